@@ -0,0 +1,797 @@
+//! DynamoDB expressions: conditions, key conditions, filters, updates and
+//! projections
+//!
+//! Attribute names and values are substituted while parsing, so the parsed
+//! forms only refer to top-level attribute names and typed values. Nested
+//! paths (`a.b`, `a[0]`) aren't supported; the handler doesn't use them.
+
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+
+/// An item, as attribute name to typed value (`{"S": "..."}`, `{"N": "1"}`, ...)
+pub type Item = Map<String, Value>;
+
+/// `ExpressionAttributeNames` and `ExpressionAttributeValues` of a request
+#[derive(Debug, Clone, Copy)]
+pub struct Placeholders<'a> {
+    pub names: Option<&'a Map<String, Value>>,
+    pub values: Option<&'a Map<String, Value>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Name(String),
+    Value(String),
+    LParen,
+    RParen,
+    Comma,
+    Dot,
+    LBracket,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Plus,
+    Minus,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let word = |start: usize| {
+        let mut end = start;
+        while end < chars.len() && (chars[end].is_ascii_alphanumeric() || chars[end] == '_') {
+            end += 1;
+        }
+        (chars[start..end].iter().collect::<String>(), end)
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '#' | ':' => {
+                let (name, end) = word(i + 1);
+                if name.is_empty() {
+                    return Err(format!("Empty placeholder at {}", i));
+                }
+                tokens.push(if c == '#' {
+                    Token::Name(format!("#{}", name))
+                } else {
+                    Token::Value(format!(":{}", name))
+                });
+                i = end;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let (ident, end) = word(i);
+                tokens.push(Token::Ident(ident));
+                i = end;
+            }
+            '(' | ')' | ',' | '.' | '[' | '=' | '+' | '-' => {
+                tokens.push(match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    ',' => Token::Comma,
+                    '.' => Token::Dot,
+                    '[' => Token::LBracket,
+                    '=' => Token::Eq,
+                    '+' => Token::Plus,
+                    _ => Token::Minus,
+                });
+                i += 1;
+            }
+            '<' | '>' => {
+                let next = chars.get(i + 1).copied();
+                let (token, len) = match (c, next) {
+                    ('<', Some('>')) => (Token::Ne, 2),
+                    ('<', Some('=')) => (Token::Le, 2),
+                    ('>', Some('=')) => (Token::Ge, 2),
+                    ('<', _) => (Token::Lt, 1),
+                    _ => (Token::Gt, 1),
+                };
+                tokens.push(token);
+                i += len;
+            }
+            other => return Err(format!("Unexpected character '{}'", other)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// A value in an expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Path(String),
+    Value(Value),
+    IfNotExists(String, Box<Operand>),
+    ListAppend(Box<Operand>, Box<Operand>),
+    Size(String),
+    Plus(Box<Operand>, Box<Operand>),
+    Minus(Box<Operand>, Box<Operand>),
+}
+
+/// A comparison between two operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A condition, key condition or filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    Compare(Operand, Comparator, Operand),
+    Between(Operand, Operand, Operand),
+    In(Operand, Vec<Operand>),
+    Exists(String),
+    NotExists(String),
+    BeginsWith(Operand, Operand),
+    Contains(Operand, Operand),
+}
+
+/// One action of an update expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Set(String, Operand),
+    Remove(String),
+    Add(String, Operand),
+    Delete(String, Operand),
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    placeholders: Placeholders<'a>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &str, placeholders: Placeholders<'a>) -> Result<Self, String> {
+        Ok(Self {
+            tokens: tokenize(input)?,
+            position: 0,
+            placeholders,
+        })
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(format!("Expected {:?}, found {:?}", expected, other)),
+        }
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("Unexpected {:?}", token)),
+        }
+    }
+
+    fn path(&mut self) -> Result<String, String> {
+        let name = match self.next() {
+            Some(Token::Ident(ident)) => ident,
+            Some(Token::Name(name)) => self
+                .placeholders
+                .names
+                .and_then(|names| names.get(&name))
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("Undefined attribute name {}", name))?
+                .to_string(),
+            other => return Err(format!("Expected an attribute, found {:?}", other)),
+        };
+        if matches!(self.peek(), Some(Token::Dot | Token::LBracket)) {
+            return Err(format!("Nested paths aren't supported ({})", name));
+        }
+        Ok(name)
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let first = self.term()?;
+        match self.peek() {
+            Some(Token::Plus) => {
+                self.next();
+                Ok(Operand::Plus(Box::new(first), Box::new(self.term()?)))
+            }
+            Some(Token::Minus) => {
+                self.next();
+                Ok(Operand::Minus(Box::new(first), Box::new(self.term()?)))
+            }
+            _ => Ok(first),
+        }
+    }
+
+    fn term(&mut self) -> Result<Operand, String> {
+        match self.peek().cloned() {
+            Some(Token::Value(name)) => {
+                self.next();
+                self.placeholders
+                    .values
+                    .and_then(|values| values.get(&name))
+                    .cloned()
+                    .map(Operand::Value)
+                    .ok_or_else(|| format!("Undefined attribute value {}", name))
+            }
+            Some(Token::Ident(function))
+                if self.tokens.get(self.position + 1) == Some(&Token::LParen) =>
+            {
+                self.next();
+                self.next();
+                let operand = match function.as_str() {
+                    "if_not_exists" => {
+                        let path = self.path()?;
+                        self.expect(Token::Comma)?;
+                        Operand::IfNotExists(path, Box::new(self.operand()?))
+                    }
+                    "list_append" => {
+                        let first = self.operand()?;
+                        self.expect(Token::Comma)?;
+                        Operand::ListAppend(Box::new(first), Box::new(self.operand()?))
+                    }
+                    "size" => Operand::Size(self.path()?),
+                    other => return Err(format!("Unknown function {}", other)),
+                };
+                self.expect(Token::RParen)?;
+                Ok(operand)
+            }
+            _ => Ok(Operand::Path(self.path()?)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let mut condition = self.conjunction()?;
+        while self.at_keyword("OR") {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.conjunction()?));
+        }
+        Ok(condition)
+    }
+
+    fn conjunction(&mut self) -> Result<Condition, String> {
+        let mut condition = self.negation()?;
+        while self.at_keyword("AND") {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.negation()?));
+        }
+        Ok(condition)
+    }
+
+    fn negation(&mut self) -> Result<Condition, String> {
+        if self.at_keyword("NOT") {
+            self.next();
+            return Ok(Condition::Not(Box::new(self.negation()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Condition, String> {
+        if self.peek() == Some(&Token::LParen) {
+            self.next();
+            let condition = self.condition()?;
+            self.expect(Token::RParen)?;
+            return Ok(condition);
+        }
+
+        if let Some(Token::Ident(function)) = self.peek().cloned()
+            && self.tokens.get(self.position + 1) == Some(&Token::LParen)
+            && function != "size"
+        {
+            self.next();
+            self.next();
+            let condition = match function.as_str() {
+                "attribute_exists" => Condition::Exists(self.path()?),
+                "attribute_not_exists" => Condition::NotExists(self.path()?),
+                "begins_with" | "contains" => {
+                    let target = self.operand()?;
+                    self.expect(Token::Comma)?;
+                    let operand = self.operand()?;
+                    if function == "begins_with" {
+                        Condition::BeginsWith(target, operand)
+                    } else {
+                        Condition::Contains(target, operand)
+                    }
+                }
+                other => return Err(format!("Unknown function {}", other)),
+            };
+            self.expect(Token::RParen)?;
+            return Ok(condition);
+        }
+
+        let left = self.operand()?;
+        if self.at_keyword("BETWEEN") {
+            self.next();
+            let low = self.operand()?;
+            if !self.at_keyword("AND") {
+                return Err("Expected AND in BETWEEN".to_string());
+            }
+            self.next();
+            return Ok(Condition::Between(left, low, self.operand()?));
+        }
+        if self.at_keyword("IN") {
+            self.next();
+            self.expect(Token::LParen)?;
+            let mut candidates = vec![self.operand()?];
+            while self.peek() == Some(&Token::Comma) {
+                self.next();
+                candidates.push(self.operand()?);
+            }
+            self.expect(Token::RParen)?;
+            return Ok(Condition::In(left, candidates));
+        }
+
+        let comparator = match self.next() {
+            Some(Token::Eq) => Comparator::Eq,
+            Some(Token::Ne) => Comparator::Ne,
+            Some(Token::Lt) => Comparator::Lt,
+            Some(Token::Le) => Comparator::Le,
+            Some(Token::Gt) => Comparator::Gt,
+            Some(Token::Ge) => Comparator::Ge,
+            other => return Err(format!("Expected a comparator, found {:?}", other)),
+        };
+        Ok(Condition::Compare(left, comparator, self.operand()?))
+    }
+
+    fn actions(&mut self) -> Result<Vec<Action>, String> {
+        let mut actions = Vec::new();
+        while let Some(token) = self.next() {
+            let Token::Ident(clause) = token else {
+                return Err(format!(
+                    "Expected SET, REMOVE, ADD or DELETE, found {:?}",
+                    token
+                ));
+            };
+            let clause = clause.to_ascii_uppercase();
+            loop {
+                let path = self.path()?;
+                actions.push(match clause.as_str() {
+                    "SET" => {
+                        self.expect(Token::Eq)?;
+                        Action::Set(path, self.operand()?)
+                    }
+                    "REMOVE" => Action::Remove(path),
+                    "ADD" => Action::Add(path, self.term()?),
+                    "DELETE" => Action::Delete(path, self.term()?),
+                    other => return Err(format!("Unknown update clause {}", other)),
+                });
+                if self.peek() != Some(&Token::Comma) {
+                    break;
+                }
+                self.next();
+            }
+        }
+        Ok(actions)
+    }
+}
+
+/// Parse a condition, key condition or filter expression
+pub fn parse_condition(input: &str, placeholders: Placeholders<'_>) -> Result<Condition, String> {
+    let mut parser = Parser::new(input, placeholders)?;
+    let condition = parser.condition()?;
+    parser.finish()?;
+    Ok(condition)
+}
+
+/// Parse an update expression
+pub fn parse_update(input: &str, placeholders: Placeholders<'_>) -> Result<Vec<Action>, String> {
+    Parser::new(input, placeholders)?.actions()
+}
+
+/// Parse a projection expression into the attributes it keeps
+pub fn parse_projection(
+    input: &str,
+    placeholders: Placeholders<'_>,
+) -> Result<Vec<String>, String> {
+    let mut parser = Parser::new(input, placeholders)?;
+    let mut paths = vec![parser.path()?];
+    while parser.peek() == Some(&Token::Comma) {
+        parser.next();
+        paths.push(parser.path()?);
+    }
+    parser.finish()?;
+    Ok(paths)
+}
+
+fn number(value: &Value) -> Option<&str> {
+    value.get("N").and_then(Value::as_str)
+}
+
+/// Order two values of the same scalar type (numbers numerically)
+pub fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    if let (Some(left), Some(right)) = (number(left), number(right)) {
+        let left: f64 = left.parse().ok()?;
+        let right: f64 = right.parse().ok()?;
+        return left.partial_cmp(&right);
+    }
+    for kind in ["S", "B"] {
+        if let (Some(left), Some(right)) = (
+            left.get(kind).and_then(Value::as_str),
+            right.get(kind).and_then(Value::as_str),
+        ) {
+            return Some(left.cmp(right));
+        }
+    }
+    None
+}
+
+fn equal(left: &Value, right: &Value) -> bool {
+    match compare(left, right) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => left == right,
+    }
+}
+
+fn arithmetic(left: &str, right: &str, subtract: bool) -> Result<String, String> {
+    if let (Ok(left), Ok(right)) = (left.parse::<i128>(), right.parse::<i128>()) {
+        return Ok(if subtract { left - right } else { left + right }.to_string());
+    }
+    let left: f64 = left
+        .parse()
+        .map_err(|_| format!("Invalid number {}", left))?;
+    let right: f64 = right
+        .parse()
+        .map_err(|_| format!("Invalid number {}", right))?;
+    Ok(if subtract { left - right } else { left + right }.to_string())
+}
+
+impl Operand {
+    /// The operand's value for an item (None for missing attributes)
+    pub fn evaluate(&self, item: &Item) -> Result<Option<Value>, String> {
+        match self {
+            Operand::Path(path) => Ok(item.get(path).cloned()),
+            Operand::Value(value) => Ok(Some(value.clone())),
+            Operand::IfNotExists(path, default) => match item.get(path) {
+                Some(value) => Ok(Some(value.clone())),
+                None => default.evaluate(item),
+            },
+            Operand::ListAppend(first, second) => {
+                let mut list = Vec::new();
+                for operand in [first, second] {
+                    let value = operand
+                        .evaluate(item)?
+                        .ok_or("list_append of a missing list")?;
+                    let items = value
+                        .get("L")
+                        .and_then(Value::as_array)
+                        .ok_or("list_append of a non-list")?;
+                    list.extend(items.iter().cloned());
+                }
+                Ok(Some(serde_json::json!({ "L": list })))
+            }
+            Operand::Size(path) => Ok(item.get(path).and_then(|value| {
+                let size = match value.as_object()?.iter().next()? {
+                    (_, Value::String(text)) => text.len(),
+                    (_, Value::Array(items)) => items.len(),
+                    (_, Value::Object(map)) => map.len(),
+                    _ => return None,
+                };
+                Some(serde_json::json!({ "N": size.to_string() }))
+            })),
+            Operand::Plus(left, right) | Operand::Minus(left, right) => {
+                let subtract = matches!(self, Operand::Minus(..));
+                let (Some(left), Some(right)) = (left.evaluate(item)?, right.evaluate(item)?)
+                else {
+                    return Err("Arithmetic on a missing attribute".to_string());
+                };
+                let (Some(left), Some(right)) = (number(&left), number(&right)) else {
+                    return Err("Arithmetic on a non-number".to_string());
+                };
+                Ok(Some(
+                    serde_json::json!({ "N": arithmetic(left, right, subtract)? }),
+                ))
+            }
+        }
+    }
+}
+
+impl Condition {
+    /// Whether an item satisfies the condition
+    pub fn matches(&self, item: &Item) -> Result<bool, String> {
+        Ok(match self {
+            Condition::And(left, right) => left.matches(item)? && right.matches(item)?,
+            Condition::Or(left, right) => left.matches(item)? || right.matches(item)?,
+            Condition::Not(condition) => !condition.matches(item)?,
+            Condition::Exists(path) => item.contains_key(path),
+            Condition::NotExists(path) => !item.contains_key(path),
+            Condition::Compare(left, comparator, right) => {
+                let (Some(left), Some(right)) = (left.evaluate(item)?, right.evaluate(item)?)
+                else {
+                    return Ok(*comparator == Comparator::Ne);
+                };
+                match comparator {
+                    Comparator::Eq => equal(&left, &right),
+                    Comparator::Ne => !equal(&left, &right),
+                    ordered => {
+                        let Some(ordering) = compare(&left, &right) else {
+                            return Ok(false);
+                        };
+                        match ordered {
+                            Comparator::Lt => ordering == Ordering::Less,
+                            Comparator::Le => ordering != Ordering::Greater,
+                            Comparator::Gt => ordering == Ordering::Greater,
+                            _ => ordering != Ordering::Less,
+                        }
+                    }
+                }
+            }
+            Condition::Between(value, low, high) => {
+                let (Some(value), Some(low), Some(high)) = (
+                    value.evaluate(item)?,
+                    low.evaluate(item)?,
+                    high.evaluate(item)?,
+                ) else {
+                    return Ok(false);
+                };
+                compare(&value, &low).is_some_and(|ordering| ordering != Ordering::Less)
+                    && compare(&value, &high).is_some_and(|ordering| ordering != Ordering::Greater)
+            }
+            Condition::In(value, candidates) => {
+                let Some(value) = value.evaluate(item)? else {
+                    return Ok(false);
+                };
+                let mut found = false;
+                for candidate in candidates {
+                    if candidate
+                        .evaluate(item)?
+                        .is_some_and(|candidate| equal(&value, &candidate))
+                    {
+                        found = true;
+                    }
+                }
+                found
+            }
+            Condition::BeginsWith(target, prefix) => {
+                match (target.evaluate(item)?, prefix.evaluate(item)?) {
+                    (Some(target), Some(prefix)) => match (target.get("S"), prefix.get("S")) {
+                        (Some(Value::String(target)), Some(Value::String(prefix))) => {
+                            target.starts_with(prefix.as_str())
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                }
+            }
+            Condition::Contains(target, element) => {
+                match (target.evaluate(item)?, element.evaluate(item)?) {
+                    (Some(target), Some(element)) => contains(&target, &element),
+                    _ => false,
+                }
+            }
+        })
+    }
+}
+
+fn contains(target: &Value, element: &Value) -> bool {
+    if let (Some(target), Some(element)) = (
+        target.get("S").and_then(Value::as_str),
+        element.get("S").and_then(Value::as_str),
+    ) {
+        return target.contains(element);
+    }
+    let scalar = element
+        .as_object()
+        .and_then(|element| element.values().next());
+    for kind in ["SS", "NS", "BS"] {
+        if let Some(set) = target.get(kind).and_then(Value::as_array) {
+            return scalar.is_some_and(|scalar| set.contains(scalar));
+        }
+    }
+    target
+        .get("L")
+        .and_then(Value::as_array)
+        .is_some_and(|list| list.contains(element))
+}
+
+/// Apply update actions to an item
+pub fn apply(actions: &[Action], item: &mut Item) -> Result<(), String> {
+    // Every operand sees the item as it was before the update
+    let original = item.clone();
+    for action in actions {
+        match action {
+            Action::Set(path, operand) => {
+                let value = operand
+                    .evaluate(&original)?
+                    .ok_or_else(|| format!("SET {} to a missing attribute", path))?;
+                item.insert(path.clone(), value);
+            }
+            Action::Remove(path) => {
+                item.remove(path);
+            }
+            Action::Add(path, operand) => {
+                let value = operand
+                    .evaluate(&original)?
+                    .ok_or_else(|| format!("ADD a missing value to {}", path))?;
+                let updated = match (original.get(path), number(&value)) {
+                    (None, _) => value,
+                    (Some(current), Some(increment)) => {
+                        let current = number(current)
+                            .ok_or_else(|| format!("ADD a number to non-number {}", path))?;
+                        serde_json::json!({ "N": arithmetic(current, increment, false)? })
+                    }
+                    (Some(current), None) => set_union(current, &value, false)?,
+                };
+                item.insert(path.clone(), updated);
+            }
+            Action::Delete(path, operand) => {
+                let value = operand
+                    .evaluate(&original)?
+                    .ok_or_else(|| format!("DELETE a missing value from {}", path))?;
+                if let Some(current) = original.get(path) {
+                    let remaining = set_union(current, &value, true)?;
+                    if remaining
+                        .as_object()
+                        .and_then(|set| set.values().next())
+                        .and_then(Value::as_array)
+                        .is_some_and(|set| set.is_empty())
+                    {
+                        item.remove(path);
+                    } else {
+                        item.insert(path.clone(), remaining);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Add the members of one set to another, or remove them
+fn set_union(current: &Value, other: &Value, remove: bool) -> Result<Value, String> {
+    for kind in ["SS", "NS", "BS"] {
+        if let (Some(current), Some(other)) = (
+            current.get(kind).and_then(Value::as_array),
+            other.get(kind).and_then(Value::as_array),
+        ) {
+            let mut members: Vec<Value> = current
+                .iter()
+                .filter(|member| !remove || !other.contains(member))
+                .cloned()
+                .collect();
+            if !remove {
+                for member in other {
+                    if !members.contains(member) {
+                        members.push(member.clone());
+                    }
+                }
+            }
+            return Ok(serde_json::json!({ kind: members }));
+        }
+    }
+    Err("ADD and DELETE need sets of the same type".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn item(value: Value) -> Item {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_conditions() {
+        let names = item(json!({"#ttl": "ttl", "#status": "status"}));
+        let values = item(json!({
+            ":now": {"N": "100"},
+            ":pending": {"S": "pending"},
+            ":since": {"N": "5"},
+            ":until": {"N": "10"},
+        }));
+        let placeholders = Placeholders {
+            names: Some(&names),
+            values: Some(&values),
+        };
+        let record = item(json!({
+            "requestId": {"S": "req_1"},
+            "status": {"S": "pending"},
+            "ttl": {"N": "99"},
+            "bucket": {"N": "7"},
+        }));
+
+        let matches = |expression: &str| {
+            parse_condition(expression, placeholders)
+                .unwrap()
+                .matches(&record)
+                .unwrap()
+        };
+        assert!(matches("attribute_exists(#ttl) AND #ttl < :now"));
+        assert!(matches("#status = :pending"));
+        assert!(!matches("attribute_not_exists(requestId)"));
+        assert!(matches(
+            "attribute_exists(requestId) AND (attribute_not_exists(pushedAt) OR pushedAt <= :now)"
+        ));
+        assert!(matches("bucket BETWEEN :since AND :until"));
+        assert!(matches("NOT #ttl >= :now"));
+        assert!(matches(
+            "begins_with(requestId, :pending) OR size(requestId) = :since"
+        ));
+        assert!(matches("missing <> :now"));
+        assert!(!matches("missing = :now"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let placeholders = Placeholders {
+            names: None,
+            values: None,
+        };
+        assert!(parse_condition("a = :undefined", placeholders).is_err());
+        assert!(parse_condition("#undefined = a", placeholders).is_err());
+        assert!(parse_condition("a.b = c", placeholders).is_err());
+        assert!(parse_condition("a = b c", placeholders).is_err());
+        assert!(parse_update("SET a", placeholders).is_err());
+    }
+
+    #[test]
+    fn test_updates() {
+        let names = item(json!({"#status": "status2xx", "#ttl": "ttl"}));
+        let values = item(json!({
+            ":one": {"N": "1"},
+            ":ttl": {"N": "500"},
+            ":tags": {"SS": ["b", "c"]},
+        }));
+        let placeholders = Placeholders {
+            names: Some(&names),
+            values: Some(&values),
+        };
+        let actions = parse_update(
+            "ADD #status :one, tags :tags SET #ttl = if_not_exists(#ttl, :ttl), n = n + :one \
+             REMOVE gone",
+            placeholders,
+        )
+        .unwrap();
+
+        let mut record = item(json!({
+            "ttl": {"N": "42"},
+            "n": {"N": "1.5"},
+            "tags": {"SS": ["a", "b"]},
+            "gone": {"BOOL": true},
+        }));
+        apply(&actions, &mut record).unwrap();
+        assert_eq!(record["status2xx"], json!({"N": "1"}));
+        assert_eq!(record["ttl"], json!({"N": "42"}));
+        assert_eq!(record["n"], json!({"N": "2.5"}));
+        assert_eq!(record["tags"], json!({"SS": ["a", "b", "c"]}));
+        assert!(!record.contains_key("gone"));
+
+        apply(&actions, &mut record).unwrap();
+        assert_eq!(record["status2xx"], json!({"N": "2"}));
+    }
+
+    #[test]
+    fn test_projection() {
+        let names = item(json!({"#data": "data"}));
+        let placeholders = Placeholders {
+            names: Some(&names),
+            values: None,
+        };
+        assert_eq!(
+            parse_projection("requestId, #data", placeholders).unwrap(),
+            vec!["requestId", "data"]
+        );
+    }
+}
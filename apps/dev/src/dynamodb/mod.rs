@@ -0,0 +1,738 @@
+//! An in-memory DynamoDB speaking the JSON wire protocol
+//!
+//! Serves the operations the handler and [`crate::tables`] use (table
+//! management, single-item reads and writes, queries, scans and batches), with
+//! condition, update, filter and projection expressions. Items never expire;
+//! the cleanup handler removes them as it does when TTL deletion lags behind.
+//!
+//! Not emulated: capacity limits, transactions, streams, and nested attribute
+//! paths in expressions.
+
+mod expr;
+
+use axum::Json;
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde_json::{Map, Value, json};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub use expr::Item;
+use expr::{Placeholders, parse_condition, parse_projection, parse_update};
+
+/// Largest page a query or scan returns without a `Limit`
+const MAX_PAGE_ITEMS: usize = 1000;
+
+/// An error returned to the client, named as DynamoDB names them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiError {
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn validation(message: impl Into<String>) -> Self {
+        Self {
+            kind: "ValidationException",
+            message: message.into(),
+        }
+    }
+
+    fn not_found(table: &str) -> Self {
+        Self {
+            kind: "ResourceNotFoundException",
+            message: format!("Requested resource not found: Table: {} not found", table),
+        }
+    }
+
+    fn conditional_check_failed() -> Self {
+        Self {
+            kind: "ConditionalCheckFailedException",
+            message: "The conditional request failed".to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::BAD_REQUEST,
+            [("content-type", "application/x-amz-json-1.0")],
+            Json(json!({
+                "__type": format!("com.amazonaws.dynamodb.v20120810#{}", self.kind),
+                "message": self.message,
+            })),
+        )
+            .into_response()
+    }
+}
+
+type ApiResult = Result<Value, ApiError>;
+
+/// Hash and optional range key attribute names of a table or index
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct KeySchema {
+    hash: String,
+    range: Option<String>,
+}
+
+impl KeySchema {
+    fn parse(schema: Option<&Value>) -> Result<Self, ApiError> {
+        let mut hash = None;
+        let mut range = None;
+        for element in schema.and_then(Value::as_array).into_iter().flatten() {
+            let name = element["AttributeName"].as_str().map(str::to_string);
+            match element["KeyType"].as_str() {
+                Some("HASH") => hash = name,
+                Some("RANGE") => range = name,
+                _ => return Err(ApiError::validation("Invalid KeySchema")),
+            }
+        }
+        Ok(Self {
+            hash: hash.ok_or_else(|| ApiError::validation("KeySchema needs a HASH key"))?,
+            range,
+        })
+    }
+
+    fn describe(&self) -> Value {
+        let mut schema = vec![json!({"AttributeName": self.hash, "KeyType": "HASH"})];
+        if let Some(range) = &self.range {
+            schema.push(json!({"AttributeName": range, "KeyType": "RANGE"}));
+        }
+        Value::Array(schema)
+    }
+
+    fn attributes(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.hash).chain(self.range.as_ref())
+    }
+
+    /// The key attributes of an item, if it has them all
+    fn key_of(&self, item: &Item) -> Option<Item> {
+        self.attributes()
+            .map(|name| Some((name.clone(), item.get(name)?.clone())))
+            .collect()
+    }
+
+    /// Compare items by range key
+    fn order(&self, left: &Item, right: &Item) -> Ordering {
+        self.range
+            .as_ref()
+            .and_then(|range| expr::compare(left.get(range)?, right.get(range)?))
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+#[derive(Debug)]
+struct Table {
+    key: KeySchema,
+    indexes: HashMap<String, KeySchema>,
+    /// Items by their encoded key (see [`Table::storage_key`])
+    items: BTreeMap<String, Item>,
+}
+
+impl Table {
+    /// Validate a `Key` parameter and encode it for storage
+    fn storage_key(&self, key: &Item) -> Result<String, ApiError> {
+        if key.len() != self.key.attributes().count() {
+            return Err(ApiError::validation(
+                "The provided key element does not match the schema",
+            ));
+        }
+        let parts: Option<Vec<&Value>> = self.key.attributes().map(|name| key.get(name)).collect();
+        let parts = parts.ok_or_else(|| {
+            ApiError::validation("The provided key element does not match the schema")
+        })?;
+        Ok(Value::from(parts.into_iter().cloned().collect::<Vec<_>>()).to_string())
+    }
+
+    fn item_key(&self, item: &Item) -> Result<String, ApiError> {
+        let key = self
+            .key
+            .key_of(item)
+            .ok_or_else(|| ApiError::validation("Missing the key attributes in the item"))?;
+        self.storage_key(&key)
+    }
+
+    fn describe(&self, name: &str) -> Value {
+        let indexes: Vec<Value> = self
+            .indexes
+            .iter()
+            .map(|(name, key)| {
+                json!({
+                    "IndexName": name,
+                    "KeySchema": key.describe(),
+                    "IndexStatus": "ACTIVE",
+                    "Projection": {"ProjectionType": "ALL"},
+                })
+            })
+            .collect();
+        let mut description = json!({
+            "TableName": name,
+            "TableStatus": "ACTIVE",
+            "KeySchema": self.key.describe(),
+            "ItemCount": self.items.len(),
+            "TableSizeBytes": 0,
+        });
+        if !indexes.is_empty() {
+            description["GlobalSecondaryIndexes"] = Value::Array(indexes);
+        }
+        description
+    }
+}
+
+/// The tables of one emulated DynamoDB endpoint
+#[derive(Debug, Default)]
+pub struct Database {
+    tables: Mutex<HashMap<String, Table>>,
+}
+
+fn string<'a>(request: &'a Value, field: &str) -> Result<&'a str, ApiError> {
+    request[field]
+        .as_str()
+        .ok_or_else(|| ApiError::validation(format!("Missing {}", field)))
+}
+
+fn object<'a>(request: &'a Value, field: &str) -> Result<&'a Item, ApiError> {
+    request[field]
+        .as_object()
+        .ok_or_else(|| ApiError::validation(format!("Missing {}", field)))
+}
+
+fn placeholders(request: &Value) -> Placeholders<'_> {
+    Placeholders {
+        names: request["ExpressionAttributeNames"].as_object(),
+        values: request["ExpressionAttributeValues"].as_object(),
+    }
+}
+
+/// Check an optional `ConditionExpression` against the current item
+fn check_condition(request: &Value, current: Option<&Item>) -> Result<(), ApiError> {
+    let Some(expression) = request["ConditionExpression"].as_str() else {
+        return Ok(());
+    };
+    let condition =
+        parse_condition(expression, placeholders(request)).map_err(ApiError::validation)?;
+    let empty = Item::new();
+    if condition
+        .matches(current.unwrap_or(&empty))
+        .map_err(ApiError::validation)?
+    {
+        Ok(())
+    } else {
+        Err(ApiError::conditional_check_failed())
+    }
+}
+
+/// Keep only the attributes named by an optional `ProjectionExpression`
+fn project(request: &Value, item: &Item) -> Result<Item, ApiError> {
+    let Some(expression) = request["ProjectionExpression"].as_str() else {
+        return Ok(item.clone());
+    };
+    let attributes =
+        parse_projection(expression, placeholders(request)).map_err(ApiError::validation)?;
+    Ok(item
+        .iter()
+        .filter(|(name, _)| attributes.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect())
+}
+
+/// Add `ConsumedCapacity` when the request asks for it
+fn with_capacity(request: &Value, mut response: Value, units: f64) -> Value {
+    if matches!(
+        request["ReturnConsumedCapacity"].as_str(),
+        Some("TOTAL" | "INDEXES")
+    ) {
+        response["ConsumedCapacity"] = json!({
+            "TableName": request["TableName"],
+            "CapacityUnits": units,
+        });
+    }
+    response
+}
+
+/// Old or new attributes, as `ReturnValues` asks
+fn return_values(request: &Value, old: Option<&Item>, new: Option<&Item>) -> Value {
+    let attributes = match request["ReturnValues"].as_str() {
+        Some("ALL_OLD" | "UPDATED_OLD") => old,
+        Some("ALL_NEW" | "UPDATED_NEW") => new,
+        _ => None,
+    };
+    match attributes {
+        Some(attributes) => json!({ "Attributes": attributes }),
+        None => json!({}),
+    }
+}
+
+impl Database {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run one operation, named as in the `X-Amz-Target` header
+    pub fn execute(&self, operation: &str, request: &Value) -> ApiResult {
+        let mut tables = self.tables.lock().unwrap_or_else(|e| e.into_inner());
+        match operation {
+            "CreateTable" => create_table(&mut tables, request),
+            "DeleteTable" => {
+                let name = string(request, "TableName")?;
+                let table = tables
+                    .remove(name)
+                    .ok_or_else(|| ApiError::not_found(name))?;
+                Ok(json!({ "TableDescription": table.describe(name) }))
+            }
+            "ListTables" => {
+                let mut names: Vec<&String> = tables.keys().collect();
+                names.sort();
+                Ok(json!({ "TableNames": names }))
+            }
+            "DescribeTable" => {
+                let name = string(request, "TableName")?;
+                let table = tables.get(name).ok_or_else(|| ApiError::not_found(name))?;
+                Ok(json!({ "Table": table.describe(name) }))
+            }
+            "UpdateTimeToLive" => {
+                let name = string(request, "TableName")?;
+                tables.get(name).ok_or_else(|| ApiError::not_found(name))?;
+                Ok(json!({
+                    "TimeToLiveSpecification": request["TimeToLiveSpecification"],
+                }))
+            }
+            "GetItem" => get_item(&tables, request),
+            "PutItem" => put_item(&mut tables, request),
+            "DeleteItem" => delete_item(&mut tables, request),
+            "UpdateItem" => update_item(&mut tables, request),
+            "Query" => query(&tables, request),
+            "Scan" => scan(&tables, request),
+            "BatchGetItem" => batch_get_item(&tables, request),
+            "BatchWriteItem" => batch_write_item(&mut tables, request),
+            other => Err(ApiError {
+                kind: "UnknownOperationException",
+                message: format!("{} is not emulated", other),
+            }),
+        }
+    }
+}
+
+fn table<'a>(tables: &'a HashMap<String, Table>, request: &Value) -> Result<&'a Table, ApiError> {
+    let name = string(request, "TableName")?;
+    tables.get(name).ok_or_else(|| ApiError::not_found(name))
+}
+
+fn table_mut<'a>(
+    tables: &'a mut HashMap<String, Table>,
+    request: &Value,
+) -> Result<&'a mut Table, ApiError> {
+    let name = string(request, "TableName")?;
+    tables
+        .get_mut(name)
+        .ok_or_else(|| ApiError::not_found(name))
+}
+
+fn create_table(tables: &mut HashMap<String, Table>, request: &Value) -> ApiResult {
+    let name = string(request, "TableName")?;
+    if tables.contains_key(name) {
+        return Err(ApiError {
+            kind: "ResourceInUseException",
+            message: format!("Table already exists: {}", name),
+        });
+    }
+
+    let mut indexes = HashMap::new();
+    for index in request["GlobalSecondaryIndexes"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        indexes.insert(
+            string(index, "IndexName")?.to_string(),
+            KeySchema::parse(index.get("KeySchema"))?,
+        );
+    }
+    let table = Table {
+        key: KeySchema::parse(request.get("KeySchema"))?,
+        indexes,
+        items: BTreeMap::new(),
+    };
+    let description = table.describe(name);
+    tables.insert(name.to_string(), table);
+    Ok(json!({ "TableDescription": description }))
+}
+
+fn get_item(tables: &HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table(tables, request)?;
+    let key = table.storage_key(object(request, "Key")?)?;
+    let units = if request["ConsistentRead"].as_bool() == Some(true) {
+        1.0
+    } else {
+        0.5
+    };
+    let response = match table.items.get(&key) {
+        Some(item) => json!({ "Item": project(request, item)? }),
+        None => json!({}),
+    };
+    Ok(with_capacity(request, response, units))
+}
+
+fn put_item(tables: &mut HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table_mut(tables, request)?;
+    let item = object(request, "Item")?.clone();
+    let key = table.item_key(&item)?;
+    check_condition(request, table.items.get(&key))?;
+    let old = table.items.insert(key, item);
+    Ok(with_capacity(
+        request,
+        return_values(request, old.as_ref(), None),
+        1.0,
+    ))
+}
+
+fn delete_item(tables: &mut HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table_mut(tables, request)?;
+    let key = table.storage_key(object(request, "Key")?)?;
+    check_condition(request, table.items.get(&key))?;
+    let old = table.items.remove(&key);
+    Ok(with_capacity(
+        request,
+        return_values(request, old.as_ref(), None),
+        1.0,
+    ))
+}
+
+fn update_item(tables: &mut HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table_mut(tables, request)?;
+    let key_attributes = object(request, "Key")?;
+    let key = table.storage_key(key_attributes)?;
+    let old = table.items.get(&key).cloned();
+    check_condition(request, old.as_ref())?;
+
+    let mut item = old.clone().unwrap_or_else(|| key_attributes.clone());
+    if let Some(expression) = request["UpdateExpression"].as_str() {
+        let actions =
+            parse_update(expression, placeholders(request)).map_err(ApiError::validation)?;
+        expr::apply(&actions, &mut item).map_err(ApiError::validation)?;
+    }
+    if table.key.key_of(&item).as_ref() != Some(key_attributes) {
+        return Err(ApiError::validation("Cannot update attribute of the key"));
+    }
+    table.items.insert(key, item.clone());
+    Ok(with_capacity(
+        request,
+        return_values(request, old.as_ref(), Some(&item)),
+        1.0,
+    ))
+}
+
+/// Items of a query or scan after filtering, projection and paging
+fn page(request: &Value, table: &Table, key: &KeySchema, candidates: Vec<&Item>) -> ApiResult {
+    let start = match request["ExclusiveStartKey"].as_object() {
+        Some(start) => Some(table.item_key(start)?),
+        None => None,
+    };
+    let mut candidates = candidates.into_iter();
+    if let Some(start) = start {
+        candidates
+            .by_ref()
+            .find(|item| table.item_key(item).ok().as_ref() == Some(&start));
+    }
+
+    let limit = request["Limit"]
+        .as_u64()
+        .map_or(MAX_PAGE_ITEMS, |limit| limit as usize);
+    let evaluated: Vec<&Item> = candidates.by_ref().take(limit).collect();
+    let more = candidates.next().is_some();
+
+    let filter = match request["FilterExpression"].as_str() {
+        Some(expression) => {
+            Some(parse_condition(expression, placeholders(request)).map_err(ApiError::validation)?)
+        }
+        None => None,
+    };
+    let mut items = Vec::new();
+    for item in &evaluated {
+        if let Some(filter) = &filter
+            && !filter.matches(item).map_err(ApiError::validation)?
+        {
+            continue;
+        }
+        items.push(Value::Object(project(request, item)?));
+    }
+
+    let mut response = json!({
+        "Count": items.len(),
+        "ScannedCount": evaluated.len(),
+    });
+    if request["Select"].as_str() != Some("COUNT") {
+        response["Items"] = Value::Array(items);
+    }
+    if more && let Some(last) = evaluated.last() {
+        let mut last_key = table.key.key_of(last).unwrap_or_default();
+        last_key.extend(key.key_of(last).unwrap_or_default());
+        response["LastEvaluatedKey"] = Value::Object(last_key);
+    }
+    Ok(with_capacity(
+        request,
+        response,
+        evaluated.len().max(1) as f64 * 0.5,
+    ))
+}
+
+fn query(tables: &HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table(tables, request)?;
+    let key = match request["IndexName"].as_str() {
+        Some(index) => table
+            .indexes
+            .get(index)
+            .ok_or_else(|| ApiError::validation(format!("Index not found: {}", index)))?,
+        None => &table.key,
+    };
+    let expression = string(request, "KeyConditionExpression")?;
+    let condition =
+        parse_condition(expression, placeholders(request)).map_err(ApiError::validation)?;
+
+    let mut matching = Vec::new();
+    for item in table.items.values() {
+        // Items without the index's key attributes aren't in the index
+        if key.key_of(item).is_some() && condition.matches(item).map_err(ApiError::validation)? {
+            matching.push(item);
+        }
+    }
+    matching.sort_by(|left, right| key.order(left, right));
+    if request["ScanIndexForward"].as_bool() == Some(false) {
+        matching.reverse();
+    }
+    page(request, table, key, matching)
+}
+
+fn scan(tables: &HashMap<String, Table>, request: &Value) -> ApiResult {
+    let table = table(tables, request)?;
+    // Every item is in the first segment
+    if request["Segment"]
+        .as_u64()
+        .is_some_and(|segment| segment > 0)
+    {
+        return Ok(json!({"Count": 0, "ScannedCount": 0, "Items": []}));
+    }
+    page(request, table, &table.key, table.items.values().collect())
+}
+
+fn batch_get_item(tables: &HashMap<String, Table>, request: &Value) -> ApiResult {
+    let mut responses = Map::new();
+    for (name, batch) in object(request, "RequestItems")? {
+        let table = tables.get(name).ok_or_else(|| ApiError::not_found(name))?;
+        let mut items = Vec::new();
+        for key in batch["Keys"].as_array().into_iter().flatten() {
+            let key = key
+                .as_object()
+                .ok_or_else(|| ApiError::validation("Invalid key"))?;
+            if let Some(item) = table.items.get(&table.storage_key(key)?) {
+                items.push(Value::Object(project(batch, item)?));
+            }
+        }
+        responses.insert(name.clone(), Value::Array(items));
+    }
+    Ok(json!({ "Responses": responses, "UnprocessedKeys": {} }))
+}
+
+fn batch_write_item(tables: &mut HashMap<String, Table>, request: &Value) -> ApiResult {
+    for (name, writes) in object(request, "RequestItems")? {
+        let table = tables
+            .get_mut(name)
+            .ok_or_else(|| ApiError::not_found(name))?;
+        for write in writes.as_array().into_iter().flatten() {
+            if let Some(item) = write["PutRequest"]["Item"].as_object() {
+                let key = table.item_key(item)?;
+                table.items.insert(key, item.clone());
+            } else if let Some(key) = write["DeleteRequest"]["Key"].as_object() {
+                let key = table.storage_key(key)?;
+                table.items.remove(&key);
+            } else {
+                return Err(ApiError::validation("Invalid write request"));
+            }
+        }
+    }
+    Ok(json!({ "UnprocessedItems": {} }))
+}
+
+/// Serve a DynamoDB API request
+pub async fn handle(
+    State(database): State<Arc<Database>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let Some(operation) = headers
+        .get("x-amz-target")
+        .and_then(|target| target.to_str().ok())
+        .and_then(|target| target.strip_prefix("DynamoDB_20120810."))
+    else {
+        return ApiError::validation("Missing X-Amz-Target").into_response();
+    };
+    let request: Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return ApiError::validation(e.to_string()).into_response(),
+    };
+
+    match database.execute(operation, &request) {
+        Ok(response) => (
+            [("content-type", "application/x-amz-json-1.0")],
+            Json(response),
+        )
+            .into_response(),
+        Err(error) => {
+            tracing::debug!("DynamoDB {} failed: {}", operation, error.message);
+            error.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database() -> Database {
+        let database = Database::new();
+        database
+            .execute(
+                "CreateTable",
+                &json!({
+                    "TableName": "stats",
+                    "KeySchema": [
+                        {"AttributeName": "tunnelId", "KeyType": "HASH"},
+                        {"AttributeName": "bucket", "KeyType": "RANGE"},
+                    ],
+                    "GlobalSecondaryIndexes": [{
+                        "IndexName": "owner-index",
+                        "KeySchema": [{"AttributeName": "owner", "KeyType": "HASH"}],
+                        "Projection": {"ProjectionType": "ALL"},
+                    }],
+                }),
+            )
+            .unwrap();
+        for bucket in [30, 10, 20] {
+            database
+                .execute(
+                    "PutItem",
+                    &json!({
+                        "TableName": "stats",
+                        "Item": {
+                            "tunnelId": {"S": "abc123def456"},
+                            "bucket": {"N": bucket.to_string()},
+                            "owner": {"S": if bucket == 20 { "bob" } else { "alice" }},
+                        },
+                    }),
+                )
+                .unwrap();
+        }
+        database
+    }
+
+    #[test]
+    fn test_conditional_writes() {
+        let database = database();
+        let put = json!({
+            "TableName": "stats",
+            "Item": {"tunnelId": {"S": "abc123def456"}, "bucket": {"N": "10"}},
+            "ConditionExpression": "attribute_not_exists(tunnelId)",
+        });
+        let error = database.execute("PutItem", &put).unwrap_err();
+        assert_eq!(error.kind, "ConditionalCheckFailedException");
+
+        let update = json!({
+            "TableName": "stats",
+            "Key": {"tunnelId": {"S": "abc123def456"}, "bucket": {"N": "10"}},
+            "UpdateExpression": "ADD #count :one",
+            "ConditionExpression": "attribute_exists(tunnelId)",
+            "ExpressionAttributeNames": {"#count": "count"},
+            "ExpressionAttributeValues": {":one": {"N": "1"}},
+            "ReturnValues": "ALL_NEW",
+        });
+        let response = database.execute("UpdateItem", &update).unwrap();
+        assert_eq!(response["Attributes"]["count"], json!({"N": "1"}));
+
+        let missing = json!({
+            "TableName": "stats",
+            "Key": {"tunnelId": {"S": "abc123def456"}},
+        });
+        let error = database.execute("GetItem", &missing).unwrap_err();
+        assert_eq!(error.kind, "ValidationException");
+    }
+
+    #[test]
+    fn test_query_orders_and_pages() {
+        let database = database();
+        let mut request = json!({
+            "TableName": "stats",
+            "KeyConditionExpression": "tunnelId = :tunnel AND #bucket >= :since",
+            "ExpressionAttributeNames": {"#bucket": "bucket"},
+            "ExpressionAttributeValues": {
+                ":tunnel": {"S": "abc123def456"},
+                ":since": {"N": "15"},
+            },
+            "Limit": 1,
+        });
+        let first = database.execute("Query", &request).unwrap();
+        assert_eq!(first["Items"][0]["bucket"], json!({"N": "20"}));
+
+        request["ExclusiveStartKey"] = first["LastEvaluatedKey"].clone();
+        let second = database.execute("Query", &request).unwrap();
+        assert_eq!(second["Items"][0]["bucket"], json!({"N": "30"}));
+        assert!(second.get("LastEvaluatedKey").is_none());
+
+        let by_owner = database
+            .execute(
+                "Query",
+                &json!({
+                    "TableName": "stats",
+                    "IndexName": "owner-index",
+                    "KeyConditionExpression": "#owner = :owner",
+                    "ExpressionAttributeNames": {"#owner": "owner"},
+                    "ExpressionAttributeValues": {":owner": {"S": "alice"}},
+                    "ProjectionExpression": "#bucket",
+                    "ExpressionAttributeNames": {"#owner": "owner", "#bucket": "bucket"},
+                }),
+            )
+            .unwrap();
+        assert_eq!(by_owner["Count"], 2);
+        assert_eq!(by_owner["Items"][0], json!({"bucket": {"N": "10"}}));
+    }
+
+    #[test]
+    fn test_scan_filter_and_batches() {
+        let database = database();
+        let scanned = database
+            .execute(
+                "Scan",
+                &json!({
+                    "TableName": "stats",
+                    "FilterExpression": "#bucket < :cutoff",
+                    "ExpressionAttributeNames": {"#bucket": "bucket"},
+                    "ExpressionAttributeValues": {":cutoff": {"N": "25"}},
+                }),
+            )
+            .unwrap();
+        assert_eq!(scanned["Count"], 2);
+        assert_eq!(scanned["ScannedCount"], 3);
+
+        database
+            .execute(
+                "BatchWriteItem",
+                &json!({"RequestItems": {"stats": [
+                    {"DeleteRequest": {"Key": {"tunnelId": {"S": "abc123def456"}, "bucket": {"N": "10"}}}},
+                ]}}),
+            )
+            .unwrap();
+        let fetched = database
+            .execute(
+                "BatchGetItem",
+                &json!({"RequestItems": {"stats": {"Keys": [
+                    {"tunnelId": {"S": "abc123def456"}, "bucket": {"N": "10"}},
+                    {"tunnelId": {"S": "abc123def456"}, "bucket": {"N": "20"}},
+                ]}}}),
+            )
+            .unwrap();
+        assert_eq!(fetched["Responses"]["stats"].as_array().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,312 @@
+//! API Gateway's WebSocket API, which agents connect to
+//!
+//! `$connect` runs before the upgrade, so the handler can refuse an agent as it
+//! would on AWS. Each text frame is handed to `$default` as its own event, and
+//! the handler answers through the management API (`/@connections/{id}`),
+//! which delivers frames to the connection it names.
+
+use aws_lambda_events::apigw::ApiGatewayWebsocketProxyRequest;
+use axum::body::Bytes;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{ConnectInfo, Path, RawQuery, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::{SinkExt, StreamExt};
+use http_tunnel_common::utils::{
+    current_timestamp_millis, generate_request_id, generate_subdomain,
+};
+use http_tunnel_handler::handlers::response::{
+    WebSocketMessageEvent, WebSocketMessageRequestContext,
+};
+use http_tunnel_handler::handlers::{handle_connect, handle_disconnect, handle_response};
+use lambda_runtime::LambdaEvent;
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{Emulator, invocation_context};
+
+/// Stage name in the events, as in the deployed `wss://.../dev`
+const STAGE: &str = "dev";
+
+/// Open agent connections by connection ID
+#[derive(Debug, Default)]
+pub struct Connections {
+    senders: Mutex<HashMap<String, mpsc::UnboundedSender<WsMessage>>>,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, mpsc::UnboundedSender<WsMessage>>> {
+        self.senders.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Queue a frame for a connection; false if it's gone
+    pub fn send(&self, connection_id: &str, message: WsMessage) -> bool {
+        self.lock()
+            .get(connection_id)
+            .is_some_and(|sender| sender.send(message).is_ok())
+    }
+}
+
+/// A `$connect` or `$disconnect` event
+fn lifecycle_event(
+    route_key: &str,
+    connection_id: &str,
+    headers: &HeaderMap,
+    query: Option<&str>,
+    peer: SocketAddr,
+) -> serde_json::Result<ApiGatewayWebsocketProxyRequest> {
+    let mut single = Map::new();
+    let mut multi: HashMap<&str, Vec<&str>> = HashMap::new();
+    for (name, value) in headers {
+        if let Ok(value) = value.to_str() {
+            single.insert(name.to_string(), json!(value));
+            multi.entry(name.as_str()).or_default().push(value);
+        }
+    }
+    let mut parameters = Map::new();
+    for pair in query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+    {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        parameters.insert(name.to_string(), json!(value));
+    }
+
+    let now = current_timestamp_millis();
+    serde_json::from_value(json!({
+        "headers": single,
+        "multiValueHeaders": multi,
+        "queryStringParameters": parameters,
+        "isBase64Encoded": false,
+        "requestContext": {
+            "routeKey": route_key,
+            "eventType": if route_key == "$connect" { "CONNECT" } else { "DISCONNECT" },
+            "connectionId": connection_id,
+            "requestId": generate_request_id(),
+            "domainName": headers.get("host").and_then(|host| host.to_str().ok()),
+            "stage": STAGE,
+            "apiId": "dev",
+            "connectedAt": now,
+            "requestTimeEpoch": now,
+            "identity": { "sourceIp": peer.ip().to_string() },
+        },
+    }))
+}
+
+/// A `$default` event for one text frame
+fn message_event(connection_id: &str, host: Option<&str>, body: String) -> WebSocketMessageEvent {
+    WebSocketMessageEvent {
+        request_context: WebSocketMessageRequestContext {
+            route_key: "$default".to_string(),
+            event_type: Some("MESSAGE".to_string()),
+            connection_id: connection_id.to_string(),
+            request_id: generate_request_id(),
+            domain_name: host.map(str::to_string),
+            stage: Some(STAGE.to_string()),
+            api_id: Some("dev".to_string()),
+            connected_at: None,
+        },
+        body: Some(body),
+        is_base64_encoded: Some(false),
+    }
+}
+
+/// Open an agent connection, if `$connect` accepts it
+pub async fn handle_agent(
+    State(emulator): State<Arc<Emulator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    // Shaped like API Gateway's IDs, which end in '='
+    let connection_id = format!("dev{}=", generate_subdomain());
+    let event = match lifecycle_event("$connect", &connection_id, &headers, query.as_deref(), peer)
+    {
+        Ok(event) => event,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let event = LambdaEvent::new(event, invocation_context());
+    match handle_connect(event, &emulator.clients).await {
+        Ok(response) if response.status_code == 200 => {}
+        Ok(response) => {
+            let status =
+                StatusCode::from_u16(response.status_code as u16).unwrap_or(StatusCode::FORBIDDEN);
+            return (status, "Forbidden").into_response();
+        }
+        Err(e) => {
+            warn!("$connect failed for {}: {}", connection_id, e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error").into_response();
+        }
+    }
+
+    upgrade.on_upgrade(move |socket| {
+        serve_agent(emulator, socket, connection_id, headers, query, peer)
+    })
+}
+
+async fn serve_agent(
+    emulator: Arc<Emulator>,
+    socket: WebSocket,
+    connection_id: String,
+    headers: HeaderMap,
+    query: Option<String>,
+    peer: SocketAddr,
+) {
+    info!("Agent connected: {}", connection_id);
+    let (mut sink, mut stream) = socket.split();
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    emulator
+        .connections
+        .lock()
+        .insert(connection_id.clone(), sender);
+
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let close = matches!(message, WsMessage::Close(_));
+            if sink.send(message).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    let host = headers
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+        .map(str::to_string);
+    while let Some(Ok(message)) = stream.next().await {
+        match message {
+            WsMessage::Text(text) => {
+                // API Gateway invokes the function for each frame concurrently
+                let event = message_event(&connection_id, host.as_deref(), text.to_string());
+                let emulator = emulator.clone();
+                tokio::spawn(async move {
+                    let connection_id = event.request_context.connection_id.clone();
+                    let event = LambdaEvent::new(event, invocation_context());
+                    if let Err(e) = handle_response(event, &emulator.clients).await {
+                        warn!("$default failed for {}: {}", connection_id, e);
+                    }
+                });
+            }
+            WsMessage::Close(_) => break,
+            // Like API Gateway, only text frames reach the function
+            _ => {}
+        }
+    }
+
+    emulator.connections.lock().remove(&connection_id);
+    writer.abort();
+    info!("Agent disconnected: {}", connection_id);
+
+    match lifecycle_event(
+        "$disconnect",
+        &connection_id,
+        &headers,
+        query.as_deref(),
+        peer,
+    ) {
+        Ok(event) => {
+            let event = LambdaEvent::new(event, invocation_context());
+            if let Err(e) = handle_disconnect(event, &emulator.clients).await {
+                warn!("$disconnect failed for {}: {}", connection_id, e);
+            }
+        }
+        Err(e) => warn!("Could not build $disconnect event: {}", e),
+    }
+}
+
+/// The management API's answer for a connection that's gone
+fn gone(connection_id: &str) -> Response {
+    debug!("Connection {} is gone", connection_id);
+    (
+        StatusCode::GONE,
+        [("x-amzn-errortype", "GoneException")],
+        axum::Json(json!({ "__type": "GoneException", "message": Value::Null })),
+    )
+        .into_response()
+}
+
+/// `POST /@connections/{id}`: send a frame to a connection
+pub async fn post_to_connection(
+    State(emulator): State<Arc<Emulator>>,
+    Path(connection_id): Path<String>,
+    body: Bytes,
+) -> Response {
+    let message = match String::from_utf8(body.to_vec()) {
+        Ok(text) => WsMessage::Text(text.into()),
+        Err(_) => WsMessage::Binary(body),
+    };
+    if emulator.connections.send(&connection_id, message) {
+        StatusCode::OK.into_response()
+    } else {
+        gone(&connection_id)
+    }
+}
+
+/// `DELETE /@connections/{id}`: close a connection
+pub async fn delete_connection(
+    State(emulator): State<Arc<Emulator>>,
+    Path(connection_id): Path<String>,
+) -> Response {
+    if emulator
+        .connections
+        .send(&connection_id, WsMessage::Close(None))
+    {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        gone(&connection_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lifecycle_event() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", "localhost:8080".parse().unwrap());
+        headers.insert("authorization", "Bearer abc".parse().unwrap());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let event = lifecycle_event(
+            "$connect",
+            "devabc=",
+            &headers,
+            Some("token=xyz&client=ttf"),
+            peer,
+        )
+        .unwrap();
+        assert_eq!(event.headers["authorization"], "Bearer abc");
+        assert_eq!(event.query_string_parameters.first("token"), Some("xyz"));
+        let context = event.request_context;
+        assert_eq!(context.connection_id.as_deref(), Some("devabc="));
+        assert_eq!(context.route_key.as_deref(), Some("$connect"));
+        assert_eq!(context.domain_name.as_deref(), Some("localhost:8080"));
+        assert_eq!(context.identity.source_ip.as_deref(), Some("127.0.0.1"));
+    }
+
+    #[test]
+    fn test_connections_send() {
+        let connections = Connections::new();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        connections.lock().insert("devabc=".to_string(), sender);
+
+        assert!(connections.send("devabc=", WsMessage::Text("hi".into())));
+        assert!(!connections.send("devxyz=", WsMessage::Text("hi".into())));
+        assert!(matches!(receiver.try_recv(), Ok(WsMessage::Text(_))));
+
+        drop(receiver);
+        assert!(!connections.send("devabc=", WsMessage::Text("hi".into())));
+    }
+}
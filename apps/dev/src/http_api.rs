@@ -0,0 +1,235 @@
+//! API Gateway's HTTP API, which public requests arrive through
+//!
+//! Requests become payload format 2.0 events for the forwarding handler, and
+//! its buffered or streamed response is returned as API Gateway would.
+
+use aws_lambda_events::apigw::ApiGatewayProxyResponse;
+use aws_lambda_events::encodings::Body as LambdaBody;
+use axum::body::{Body, to_bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use http_tunnel_common::utils::{current_timestamp_millis, generate_request_id};
+use http_tunnel_common::{decode_body, encode_body};
+use http_tunnel_handler::handlers::{ForwardingResponse, handle_forwarding};
+use http_tunnel_handler::http_event::PublicRequest;
+use lambda_runtime::LambdaEvent;
+use serde_json::{Map, Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::warn;
+
+use crate::{Emulator, invocation_context};
+
+/// Largest request body the HTTP API accepts
+const MAX_PAYLOAD_BYTES: usize = 10 * 1024 * 1024;
+
+/// The payload format 2.0 event for a request
+fn event(
+    request_id: &str,
+    method: &str,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: &[u8],
+    peer: SocketAddr,
+) -> Value {
+    // Format 2.0 joins repeated headers with commas, and moves cookies out
+    let mut joined = Map::new();
+    let mut cookies = Vec::new();
+    for (name, value) in headers {
+        let Ok(value) = value.to_str() else { continue };
+        if name == header::COOKIE {
+            cookies.extend(value.split("; ").map(str::to_string));
+            continue;
+        }
+        match joined.get_mut(name.as_str()) {
+            Some(Value::String(existing)) => {
+                existing.push(',');
+                existing.push_str(value);
+            }
+            _ => {
+                joined.insert(name.to_string(), json!(value));
+            }
+        }
+    }
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or("localhost");
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok())
+        .unwrap_or_default();
+    let now = current_timestamp_millis();
+
+    let mut event = json!({
+        "version": "2.0",
+        "routeKey": "$default",
+        "rawPath": path,
+        "rawQueryString": query.unwrap_or_default(),
+        "headers": joined,
+        "requestContext": {
+            "accountId": "000000000000",
+            "apiId": "dev",
+            "domainName": host,
+            "domainPrefix": host.split('.').next().unwrap_or(host),
+            "http": {
+                "method": method,
+                "path": path,
+                "protocol": "HTTP/1.1",
+                "sourceIp": peer.ip().to_string(),
+                "userAgent": user_agent,
+            },
+            "requestId": request_id,
+            "routeKey": "$default",
+            "stage": "$default",
+            "timeEpoch": now,
+        },
+        "isBase64Encoded": !body.is_empty(),
+    });
+    if !cookies.is_empty() {
+        event["cookies"] = json!(cookies);
+    }
+    if !body.is_empty() {
+        event["body"] = json!(encode_body(body));
+    }
+    event
+}
+
+/// Turn a buffered handler response into the HTTP one
+fn into_response(response: ApiGatewayProxyResponse) -> Response {
+    let body = match response.body {
+        Some(LambdaBody::Text(text)) if response.is_base64_encoded => match decode_body(&text) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Handler returned an undecodable body: {}", e);
+                return StatusCode::BAD_GATEWAY.into_response();
+            }
+        },
+        Some(LambdaBody::Text(text)) => text.into_bytes(),
+        Some(LambdaBody::Binary(body)) => body,
+        Some(LambdaBody::Empty) | None => Vec::new(),
+    };
+    let status = StatusCode::from_u16(response.status_code as u16)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    let mut headers = response.headers;
+    for name in response.multi_value_headers.keys() {
+        headers.remove(name);
+    }
+    for (name, value) in &response.multi_value_headers {
+        headers.append(name, value.clone());
+    }
+    (status, headers, Body::from(body)).into_response()
+}
+
+/// Forward a public request through the handler
+pub async fn handle_public(
+    State(emulator): State<Arc<Emulator>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    let (parts, body) = request.into_parts();
+    let Ok(body) = to_bytes(body, MAX_PAYLOAD_BYTES).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Request Entity Too Large").into_response();
+    };
+    let request_id = generate_request_id();
+    let payload = event(
+        &request_id,
+        parts.method.as_str(),
+        parts.uri.path(),
+        parts.uri.query(),
+        &parts.headers,
+        &body,
+        peer,
+    );
+    let event = match PublicRequest::from_payload(payload) {
+        Ok(event) => event,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let event = LambdaEvent::new(event, invocation_context());
+    match handle_forwarding(event, &emulator.clients).await {
+        Ok(ForwardingResponse::Buffered(response)) => into_response(response),
+        Ok(ForwardingResponse::Streaming(response)) => {
+            let prelude = response.metadata_prelude;
+            let mut headers = prelude.headers;
+            for cookie in prelude.cookies {
+                if let Ok(value) = HeaderValue::from_str(&cookie) {
+                    headers.append(HeaderName::from_static("set-cookie"), value);
+                }
+            }
+            (prelude.status_code, headers, Body::new(response.stream)).into_response()
+        }
+        Err(e) => {
+            warn!("Forwarding {} failed: {}", request_id, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "localhost:8080".parse().unwrap());
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "application/json".parse().unwrap());
+        headers.insert(header::COOKIE, "a=1; b=2".parse().unwrap());
+        let peer: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let payload = event(
+            "req_1",
+            "POST",
+            "/abc123def456/items",
+            Some("x=1"),
+            &headers,
+            b"{}",
+            peer,
+        );
+        assert_eq!(payload["headers"]["accept"], "text/html,application/json");
+        assert_eq!(payload["cookies"], json!(["a=1", "b=2"]));
+
+        let PublicRequest {
+            request, raw_query, ..
+        } = PublicRequest::from_payload(payload).unwrap();
+        assert_eq!(request.path.as_deref(), Some("/abc123def456/items"));
+        assert_eq!(raw_query.as_deref(), Some("x=1"));
+        assert_eq!(request.headers["cookie"], "a=1; b=2");
+        assert_eq!(
+            request.request_context.identity.source_ip.as_deref(),
+            Some("127.0.0.1")
+        );
+        assert!(request.is_base64_encoded);
+    }
+
+    #[tokio::test]
+    async fn test_into_response() {
+        let mut response = ApiGatewayProxyResponse {
+            status_code: 201,
+            body: Some(LambdaBody::Text(encode_body(b"created"))),
+            is_base64_encoded: true,
+            ..Default::default()
+        };
+        response
+            .headers
+            .insert("content-type", "text/plain".parse().unwrap());
+        response
+            .multi_value_headers
+            .append("set-cookie", "a=1".parse().unwrap());
+        response
+            .multi_value_headers
+            .append("set-cookie", "b=2".parse().unwrap());
+
+        let response = into_response(response);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.headers().get_all("set-cookie").iter().count(), 2);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"created");
+    }
+}
@@ -0,0 +1,220 @@
+//! Local emulator of the AWS tunnel stack
+//!
+//! Runs the Lambda handler's functions in-process behind stand-ins for the AWS
+//! services they're deployed with:
+//! - API Gateway's WebSocket API ([`gateway`]) at `/_agent`, with the
+//!   management API it answers through
+//! - API Gateway's HTTP API ([`http_api`]) for everything else
+//! - DynamoDB ([`dynamodb`]), in memory, with the tables of [`tables`]
+//! - the EventBridge schedule that runs the cleanup handler
+//!
+//! The management API and DynamoDB listen on a loopback port of their own,
+//! which the handler's SDK clients are pointed at (see [`handler_env`]).
+//! Nothing is persisted: restarting the emulator drops every tunnel.
+
+pub mod dynamodb;
+pub mod gateway;
+pub mod http_api;
+pub mod tables;
+
+use anyhow::{Context as _, Result};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::config::Credentials;
+use axum::Router;
+use axum::routing::{get, post};
+use http_tunnel_common::utils::{current_timestamp_millis, generate_request_id};
+use http_tunnel_handler::SharedClients;
+use http_tunnel_handler::handlers::handle_cleanup;
+use lambda_runtime::Context;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use gateway::Connections;
+use tables::TableNames;
+
+/// How long a handler invocation may take, as configured for the Lambda function
+const INVOCATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Prefix of the emulator's table names
+const TABLE_PREFIX: &str = "dev-";
+
+/// Variables naming AWS services the emulator doesn't provide, cleared so the
+/// handler falls back to polling DynamoDB rather than calling them
+pub const UNSUPPORTED_ENV: &[&str] = &[
+    "EVENT_BUS_NAME",
+    "PUBLIC_WEBSOCKET_API_ENDPOINT",
+    "REPLY_QUEUE_PREFIX",
+    "STORAGE_BACKEND",
+    "USE_EVENT_DRIVEN",
+];
+
+/// Environment the handler needs to find the emulated services
+///
+/// `services` is the address of the management API and DynamoDB.
+pub fn handler_env(services: SocketAddr) -> Vec<(&'static str, String)> {
+    let mut env: Vec<_> = TableNames::with_prefix(TABLE_PREFIX)
+        .env_vars()
+        .into_iter()
+        .map(|(name, table)| (name, table.to_string()))
+        .collect();
+    env.push(("WEBSOCKET_API_ENDPOINT", format!("http://{}", services)));
+    env
+}
+
+/// A fresh Lambda context for one invocation
+pub fn invocation_context() -> Context {
+    let mut context = Context::default();
+    context.request_id = generate_request_id();
+    context.deadline = current_timestamp_millis() as u64 + INVOCATION_TIMEOUT.as_millis() as u64;
+    context
+}
+
+/// State shared by the emulated APIs
+pub struct Emulator {
+    pub clients: SharedClients,
+    pub connections: Connections,
+}
+
+/// SDK config for the emulated DynamoDB at `services`
+async fn sdk_config(services: SocketAddr) -> aws_config::SdkConfig {
+    aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("dev", "dev", None, None, "tunnel-dev"))
+        .endpoint_url(format!("http://{}", services))
+        .load()
+        .await
+}
+
+/// Run the emulator until it fails
+///
+/// Public requests and agents are served on `public`; the handler's SDK
+/// clients reach the management API and DynamoDB on `services`. The
+/// environment must already hold [`handler_env`] for `services`.
+pub async fn serve(
+    public: TcpListener,
+    services: TcpListener,
+    cleanup_interval: Duration,
+) -> Result<()> {
+    let services_addr = services.local_addr()?;
+    let emulator = Arc::new(Emulator {
+        clients: SharedClients::new(sdk_config(services_addr).await)?,
+        connections: Connections::new(),
+    });
+
+    let database = Arc::new(dynamodb::Database::new());
+    let services_router = Router::new()
+        .route("/", post(dynamodb::handle))
+        .with_state(database)
+        .merge(
+            Router::new()
+                .route(
+                    "/@connections/{connection_id}",
+                    post(gateway::post_to_connection).delete(gateway::delete_connection),
+                )
+                .with_state(emulator.clone()),
+        );
+    tokio::spawn(async move { axum::serve(services, services_router).await });
+
+    tables::create_tables(
+        &emulator.clients.dynamodb,
+        &TableNames::with_prefix(TABLE_PREFIX),
+    )
+    .await?;
+
+    let cleanup = emulator.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(cleanup_interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let event = json!({"source": "aws.events", "detail-type": "Scheduled Event"});
+            if let Err(e) = handle_cleanup(event, &cleanup.clients).await {
+                warn!("Cleanup failed: {}", e);
+            }
+        }
+    });
+
+    let public_router = Router::new()
+        .route("/_agent", get(gateway::handle_agent))
+        .fallback(http_api::handle_public)
+        .with_state(emulator);
+    info!("Emulated AWS services on {}", services_addr);
+    axum::serve(
+        public,
+        public_router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .context("Server failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_through_emulator() {
+        use futures_util::{SinkExt, StreamExt};
+        use http_tunnel_common::{HttpResponse, Message, encode_body};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+        let public = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let services = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = public.local_addr().unwrap();
+        // SAFETY: no other test in this crate reads the environment
+        unsafe {
+            for (name, value) in handler_env(services.local_addr().unwrap()) {
+                std::env::set_var(name, value);
+            }
+            std::env::set_var("DOMAIN_NAME", addr.to_string());
+            std::env::set_var("ROUTING_MODE", "path");
+        }
+        tokio::spawn(serve(public, services, Duration::from_secs(3600)));
+
+        let url = format!("ws://{}/_agent", addr);
+        let (mut agent, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let ready = serde_json::to_string(&Message::Ready {
+            options: Default::default(),
+        })
+        .unwrap();
+        agent.send(WsMessage::Text(ready.into())).await.unwrap();
+
+        let tunnel_id = loop {
+            let Some(Ok(WsMessage::Text(text))) = agent.next().await else {
+                panic!("expected ConnectionEstablished");
+            };
+            if let Ok(Message::ConnectionEstablished { tunnel_id, .. }) =
+                serde_json::from_str(&text)
+            {
+                break tunnel_id;
+            }
+        };
+
+        // Answer requests with their URI
+        tokio::spawn(async move {
+            while let Some(Ok(WsMessage::Text(text))) = agent.next().await {
+                if let Ok(Message::HttpRequest(request)) = serde_json::from_str(&text) {
+                    let mut response = HttpResponse::new(request.request_id, 200);
+                    response.body = encode_body(request.uri.as_bytes());
+                    let reply = serde_json::to_string(&Message::HttpResponse(response)).unwrap();
+                    agent.send(WsMessage::Text(reply.into())).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /{}/hello?x=1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            tunnel_id, addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("/hello?x=1"), "{}", response);
+    }
+}
@@ -0,0 +1,79 @@
+//! Local development emulator
+//!
+//! Runs the whole tunnel stack on one machine, with the real Lambda handlers
+//! behind emulated API Gateway and DynamoDB (see the library docs):
+//! - `ws://localhost:8080/_agent` - agents connect here
+//! - `http://localhost:8080/{tunnel_id}/...` - public requests
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use http_tunnel_dev::{UNSUPPORTED_ENV, handler_env, serve};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::info;
+
+/// Local emulator of the AWS tunnel stack
+#[derive(Debug, Parser)]
+#[command(name = "tunnel-dev", version, about)]
+struct Args {
+    /// Address to serve agents and public requests on
+    #[arg(long, env = "TUNNEL_DEV_LISTEN", default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Seconds between runs of the cleanup handler
+    #[arg(long, default_value_t = 60)]
+    cleanup_interval: u64,
+}
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
+
+    let args = Args::parse();
+    let public = std::net::TcpListener::bind(args.listen)
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    let services = std::net::TcpListener::bind("127.0.0.1:0")?;
+    public.set_nonblocking(true)?;
+    services.set_nonblocking(true)?;
+
+    let mut env = handler_env(services.local_addr()?);
+    // Public URLs point at the emulator, unless configured otherwise
+    for (name, value) in [
+        ("DOMAIN_NAME", args.listen.to_string()),
+        ("ROUTING_MODE", "path".to_string()),
+    ] {
+        if std::env::var_os(name).is_none() {
+            env.push((name, value));
+        }
+    }
+    // SAFETY: no other thread has started yet; the runtime is built below
+    unsafe {
+        for name in UNSUPPORTED_ENV {
+            std::env::remove_var(name);
+        }
+        for (name, value) in env {
+            std::env::set_var(name, value);
+        }
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let public = tokio::net::TcpListener::from_std(public)?;
+            let services = tokio::net::TcpListener::from_std(services)?;
+            info!("Agents connect to ws://{}/_agent", args.listen);
+            info!("Tunnels are served at http://{}/{{tunnel_id}}/", args.listen);
+
+            tokio::select! {
+                result = serve(public, services, Duration::from_secs(args.cleanup_interval)) => result,
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Shutting down");
+                    Ok(())
+                }
+            }
+        })
+}
@@ -0,0 +1,128 @@
+//! The handler's DynamoDB tables, as the infrastructure defines them
+//!
+//! Works against any DynamoDB endpoint: the emulator's own, or DynamoDB Local.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, BillingMode, GlobalSecondaryIndex, KeySchemaElement, KeyType, Projection,
+    ProjectionType, ScalarAttributeType,
+};
+
+/// Table names, and the environment variables the handler reads them from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableNames {
+    pub connections: String,
+    pub pending_requests: String,
+    pub tunnel_stats: String,
+    pub ip_denylist: String,
+    pub ws_sessions: String,
+    pub reservations: String,
+}
+
+impl TableNames {
+    /// Names starting with `prefix`, like the deployed `http-tunnel-connections-dev`
+    pub fn with_prefix(prefix: &str) -> Self {
+        Self {
+            connections: format!("{}connections", prefix),
+            pending_requests: format!("{}pending-requests", prefix),
+            tunnel_stats: format!("{}tunnel-stats", prefix),
+            ip_denylist: format!("{}ip-denylist", prefix),
+            ws_sessions: format!("{}ws-sessions", prefix),
+            reservations: format!("{}reservations", prefix),
+        }
+    }
+
+    /// `(variable, table name)` pairs for the handler's environment
+    pub fn env_vars(&self) -> [(&'static str, &str); 6] {
+        [
+            ("CONNECTIONS_TABLE_NAME", &self.connections),
+            ("PENDING_REQUESTS_TABLE_NAME", &self.pending_requests),
+            ("TUNNEL_STATS_TABLE_NAME", &self.tunnel_stats),
+            ("IP_DENYLIST_TABLE_NAME", &self.ip_denylist),
+            ("WS_SESSIONS_TABLE_NAME", &self.ws_sessions),
+            ("RESERVATIONS_TABLE_NAME", &self.reservations),
+        ]
+    }
+}
+
+fn key(name: &str, key_type: KeyType) -> Result<KeySchemaElement> {
+    Ok(KeySchemaElement::builder()
+        .attribute_name(name)
+        .key_type(key_type)
+        .build()?)
+}
+
+fn attribute(name: &str, attribute_type: ScalarAttributeType) -> Result<AttributeDefinition> {
+    Ok(AttributeDefinition::builder()
+        .attribute_name(name)
+        .attribute_type(attribute_type)
+        .build()?)
+}
+
+/// Create one table; `range` and `index` are `(attribute, type)` and
+/// `(index name, hash attribute)`
+async fn create_table(
+    client: &DynamoDbClient,
+    name: &str,
+    hash: &str,
+    range: Option<(&str, ScalarAttributeType)>,
+    index: Option<(&str, &str)>,
+) -> Result<()> {
+    let mut request = client
+        .create_table()
+        .table_name(name)
+        .billing_mode(BillingMode::PayPerRequest)
+        .key_schema(key(hash, KeyType::Hash)?)
+        .attribute_definitions(attribute(hash, ScalarAttributeType::S)?);
+    if let Some((range, range_type)) = range {
+        request = request
+            .key_schema(key(range, KeyType::Range)?)
+            .attribute_definitions(attribute(range, range_type)?);
+    }
+    if let Some((index, index_hash)) = index {
+        request = request
+            .attribute_definitions(attribute(index_hash, ScalarAttributeType::S)?)
+            .global_secondary_indexes(
+                GlobalSecondaryIndex::builder()
+                    .index_name(index)
+                    .key_schema(key(index_hash, KeyType::Hash)?)
+                    .projection(
+                        Projection::builder()
+                            .projection_type(ProjectionType::All)
+                            .build(),
+                    )
+                    .build()?,
+            );
+    }
+    request
+        .send()
+        .await
+        .with_context(|| format!("Failed to create table {}", name))?;
+    Ok(())
+}
+
+/// Create every table the handler uses
+pub async fn create_tables(client: &DynamoDbClient, names: &TableNames) -> Result<()> {
+    create_table(
+        client,
+        &names.connections,
+        "connectionId",
+        None,
+        Some(("tunnel-id-index", "tunnelId")),
+    )
+    .await?;
+    create_table(client, &names.pending_requests, "requestId", None, None).await?;
+    create_table(
+        client,
+        &names.tunnel_stats,
+        "tunnelId",
+        Some(("bucket", ScalarAttributeType::N)),
+        None,
+    )
+    .await?;
+    create_table(client, &names.ip_denylist, "ip", None, None).await?;
+    create_table(client, &names.ws_sessions, "sessionId", None, None).await?;
+    create_table(client, &names.reservations, "tunnelId", None, None).await?;
+    Ok(())
+}
@@ -0,0 +1,325 @@
+//! Handler storage flows against DynamoDB Local
+//!
+//! Creates the tables of [`http_tunnel_dev::tables`] on a real DynamoDB wire
+//! protocol implementation and drives the handler's own functions through
+//! them, so a handler expecting a key, index or attribute type the tables
+//! don't have fails here rather than after a deploy.
+//!
+//! Built with `--features dynamodb-local`. Start DynamoDB Local first:
+//!
+//! ```bash
+//! docker run --rm -p 8000:8000 amazon/dynamodb-local
+//! cargo test -p http-tunnel-dev --features dynamodb-local
+//! ```
+//!
+//! `DYNAMODB_LOCAL_ENDPOINT` points elsewhere (default `http://localhost:8000`).
+//! When it's unset and nothing answers, the tests pass without running.
+
+#![cfg(feature = "dynamodb-local")]
+
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::config::Credentials;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::utils::{
+    calculate_ttl, current_timestamp_secs, generate_request_id, generate_subdomain,
+};
+use http_tunnel_common::{ConnectionMetadata, HttpResponse, TunnelOptions};
+use http_tunnel_dev::tables::{TableNames, create_tables};
+use http_tunnel_handler::chunks::PendingTable;
+use http_tunnel_handler::handlers::handle_cleanup;
+use http_tunnel_handler::{
+    SharedClients, connection_item, get_connection_metadata, lookup_connection_by_tunnel_id,
+    lookup_connection_metadata_by_tunnel_id, record_heartbeat, save_connection_metadata,
+    save_pending_request, save_tunnel_options, stats, update_pending_request_with_response,
+    wait_for_response,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+const DEFAULT_ENDPOINT: &str = "http://localhost:8000";
+
+/// Whether DynamoDB Local is up, once the tables have been created on it
+static TABLES: OnceCell<bool> = OnceCell::const_new();
+
+fn endpoint() -> String {
+    std::env::var("DYNAMODB_LOCAL_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string())
+}
+
+async fn sdk_config() -> aws_config::SdkConfig {
+    aws_config::defaults(BehaviorVersion::latest())
+        .region(Region::new("us-east-1"))
+        .credentials_provider(Credentials::new("local", "local", None, None, "tests"))
+        .endpoint_url(endpoint())
+        .load()
+        .await
+}
+
+/// A client for DynamoDB Local, or None to skip the test
+///
+/// The first call creates a fresh set of tables and points the handler at them.
+async fn client() -> Option<DynamoDbClient> {
+    let available = *TABLES
+        .get_or_init(|| async {
+            let client = DynamoDbClient::new(&sdk_config().await);
+            let reachable =
+                tokio::time::timeout(Duration::from_secs(3), client.list_tables().send()).await;
+            if !matches!(reachable, Ok(Ok(_))) {
+                assert!(
+                    std::env::var("DYNAMODB_LOCAL_ENDPOINT").is_err(),
+                    "DynamoDB Local is not reachable at {}",
+                    endpoint()
+                );
+                eprintln!("DynamoDB Local is not running at {DEFAULT_ENDPOINT}; skipping");
+                return false;
+            }
+
+            let names = TableNames::with_prefix(&format!("it-{}-", generate_subdomain()));
+            create_tables(&client, &names).await.unwrap();
+            // SAFETY: tests wait for this cell before they touch the environment
+            unsafe {
+                for (name, table) in names.env_vars() {
+                    std::env::set_var(name, table);
+                }
+            }
+            true
+        })
+        .await;
+    if available {
+        Some(DynamoDbClient::new(&sdk_config().await))
+    } else {
+        None
+    }
+}
+
+fn connection(tunnel_id: &str, ttl: i64) -> ConnectionMetadata {
+    ConnectionMetadata {
+        connection_id: format!("it{}=", generate_subdomain()),
+        tunnel_id: tunnel_id.to_string(),
+        public_url: format!("https://tunnel.example.com/{}", tunnel_id),
+        subdomain_url: None,
+        path_based_url: Some(format!("https://tunnel.example.com/{}", tunnel_id)),
+        created_at: current_timestamp_secs(),
+        ttl,
+        last_heartbeat_at: None,
+        client_info: None,
+        owner: Some("alice".to_string()),
+        options: TunnelOptions::default(),
+    }
+}
+
+#[tokio::test]
+async fn test_connection_lifecycle() {
+    let Some(client) = client().await else { return };
+    let tunnel_id = generate_subdomain();
+    let metadata = connection(&tunnel_id, calculate_ttl(3600));
+    save_connection_metadata(&client, &metadata).await.unwrap();
+
+    let saved = get_connection_metadata(&client, &metadata.connection_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(saved.tunnel_id, tunnel_id);
+    assert_eq!(saved.owner.as_deref(), Some("alice"));
+
+    // The tunnel-id-index finds it
+    let connections = lookup_connection_by_tunnel_id(&client, &tunnel_id)
+        .await
+        .unwrap();
+    assert_eq!(connections, vec![metadata.connection_id.clone()]);
+    let found = lookup_connection_metadata_by_tunnel_id(&client, &tunnel_id)
+        .await
+        .unwrap();
+    assert_eq!(found.connection_id, metadata.connection_id);
+
+    let options = TunnelOptions {
+        tunnel_id: Some(tunnel_id.clone()),
+        ..Default::default()
+    };
+    save_tunnel_options(&client, &metadata.connection_id, &options)
+        .await
+        .unwrap();
+    record_heartbeat(&client, &metadata.connection_id)
+        .await
+        .unwrap();
+    let updated = get_connection_metadata(&client, &metadata.connection_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(updated.options.tunnel_id, Some(tunnel_id.clone()));
+    assert!(updated.last_heartbeat_at.is_some());
+
+    // Options can't resurrect a connection that's gone
+    http_tunnel_handler::delete_connection(&client, &metadata.connection_id)
+        .await
+        .unwrap();
+    assert!(
+        get_connection_metadata(&client, &metadata.connection_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        save_tunnel_options(&client, &metadata.connection_id, &options)
+            .await
+            .is_err()
+    );
+    assert!(
+        lookup_connection_by_tunnel_id(&client, &tunnel_id)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_pending_request_roundtrip() {
+    let Some(client) = client().await else { return };
+    let tunnel_id = generate_subdomain();
+    let request_id = generate_request_id();
+    save_pending_request(
+        &client,
+        &request_id,
+        "itconn=",
+        &tunnel_id,
+        42,
+        "apigw-1",
+        None,
+    )
+    .await
+    .unwrap();
+
+    let waiter = {
+        let client = client.clone();
+        let request_id = request_id.clone();
+        tokio::spawn(async move {
+            let table = PendingTable::from_env().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            wait_for_response(&client, &table, &request_id, deadline, Duration::ZERO).await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut response = HttpResponse::new(request_id.clone(), 201);
+    response.body = http_tunnel_common::encode_body(b"created");
+    let context = update_pending_request_with_response(&client, &response)
+        .await
+        .unwrap()
+        .expect("the first response completes the request");
+    assert_eq!(context.tunnel_id, tunnel_id);
+    assert_eq!(context.request_bytes, 42);
+
+    let received = waiter.await.unwrap().unwrap();
+    assert_eq!(received.status_code, 201);
+    assert_eq!(received.body, response.body);
+
+    // A duplicate response doesn't count twice
+    assert!(
+        update_pending_request_with_response(&client, &response)
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_wait_for_response_times_out() {
+    let Some(client) = client().await else { return };
+    let request_id = generate_request_id();
+    save_pending_request(
+        &client,
+        &request_id,
+        "itconn=",
+        "abc123def456",
+        0,
+        "apigw-2",
+        None,
+    )
+    .await
+    .unwrap();
+
+    let table = PendingTable::from_env().unwrap();
+    let deadline = Instant::now() + Duration::from_millis(500);
+    assert!(
+        wait_for_response(&client, &table, &request_id, deadline, Duration::ZERO)
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_tunnel_stats() {
+    let Some(client) = client().await else { return };
+    let tunnel_id = generate_subdomain();
+    for status in [200, 200, 503] {
+        stats::record_request(&client, &tunnel_id, status, Duration::from_millis(80))
+            .await
+            .unwrap();
+    }
+
+    let windows = stats::load_tunnel_stats(&client, &tunnel_id, &[3600])
+        .await
+        .unwrap();
+    assert_eq!(windows[0].requests, 3);
+    assert_eq!(windows[0].status_classes["2xx"], 2);
+    assert_eq!(windows[0].status_classes["5xx"], 1);
+}
+
+#[tokio::test]
+async fn test_cleanup_removes_expired_items() {
+    let Some(client) = client().await else { return };
+    let connections_table = std::env::var("CONNECTIONS_TABLE_NAME").unwrap();
+    let pending_table = std::env::var("PENDING_REQUESTS_TABLE_NAME").unwrap();
+
+    let live = connection(&generate_subdomain(), calculate_ttl(3600));
+    let expired = connection(&generate_subdomain(), current_timestamp_secs() - 60);
+    for metadata in [&live, &expired] {
+        client
+            .put_item()
+            .table_name(&connections_table)
+            .set_item(Some(connection_item(metadata)))
+            .send()
+            .await
+            .unwrap();
+    }
+    let expired_request = generate_request_id();
+    client
+        .put_item()
+        .table_name(&pending_table)
+        .item("requestId", AttributeValue::S(expired_request.clone()))
+        .item("status", AttributeValue::S("pending".to_string()))
+        .item(
+            "ttl",
+            AttributeValue::N((current_timestamp_secs() - 60).to_string()),
+        )
+        .send()
+        .await
+        .unwrap();
+
+    let clients = SharedClients::new(sdk_config().await).unwrap();
+    let summary = handle_cleanup(serde_json::json!({}), &clients)
+        .await
+        .unwrap();
+    assert!(summary["connectionsDeleted"].as_u64().unwrap() >= 1);
+    assert!(summary["requestsDeleted"].as_u64().unwrap() >= 1);
+
+    assert!(
+        get_connection_metadata(&client, &expired.connection_id)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        get_connection_metadata(&client, &live.connection_id)
+            .await
+            .unwrap()
+            .is_some()
+    );
+    let request = client
+        .get_item()
+        .table_name(&pending_table)
+        .key("requestId", AttributeValue::S(expired_request))
+        .send()
+        .await
+        .unwrap();
+    assert!(request.item.is_none());
+}
@@ -0,0 +1,267 @@
+//! Load balancing across local upstreams (`--port 3000,3001,3002`)
+//!
+//! A service scaled to several local workers can share one tunnel: each
+//! request goes to one of the ports, in turn (`--balance round-robin`) or to
+//! the one with the fewest requests in flight (`--balance least-connections`).
+//!
+//! Upstreams are health-checked by opening a TCP connection every few seconds.
+//! One that refuses a request's connection is taken out right away and the
+//! request is retried on another, since nothing was sent yet; it comes back
+//! once a check succeeds. When every upstream is down, requests still go out in
+//! turn, so they fail (or wait with --retry-local) as with a single port.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// How often upstreams are checked
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a health check waits for the connection
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How requests are spread across upstreams
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BalanceStrategy {
+    /// Each upstream in turn
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight
+    LeastConnections,
+}
+
+#[derive(Debug)]
+struct Upstream {
+    address: String,
+    in_flight: AtomicUsize,
+    healthy: AtomicBool,
+}
+
+/// Picks the upstream for each request, shared by every connection of a tunnel
+#[derive(Debug)]
+pub struct Balancer {
+    upstreams: Vec<Upstream>,
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+}
+
+/// The upstream picked for a request; counts as in flight until dropped
+#[derive(Debug)]
+pub struct PickedUpstream {
+    balancer: Arc<Balancer>,
+    index: usize,
+}
+
+impl PickedUpstream {
+    /// Base URL of the upstream, e.g. `http://127.0.0.1:3001`
+    pub fn address(&self) -> &str {
+        &self.balancer.upstreams[self.index].address
+    }
+
+    /// Take the upstream out after it refused a connection, and pick another
+    ///
+    /// Returns `None` when there's no other upstream to try.
+    pub fn fail_over(&self) -> Option<PickedUpstream> {
+        self.balancer.set_healthy(self.index, false);
+        let next = self.balancer.pick_excluding(Some(self.index));
+        (next.index != self.index).then_some(next)
+    }
+}
+
+impl Drop for PickedUpstream {
+    fn drop(&mut self) {
+        self.balancer.upstreams[self.index]
+            .in_flight
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Balancer {
+    /// Balance across `addresses` (base URLs); the first one is the primary
+    pub fn new(addresses: Vec<String>, strategy: BalanceStrategy) -> Arc<Self> {
+        Arc::new(Self {
+            upstreams: addresses
+                .into_iter()
+                .map(|address| Upstream {
+                    address,
+                    in_flight: AtomicUsize::new(0),
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether requests for `local_address` are balanced; a `--config` backend
+    /// pointing elsewhere takes them out of the pool
+    pub fn serves(&self, local_address: &str) -> bool {
+        self.upstreams
+            .first()
+            .is_some_and(|primary| primary.address == local_address)
+    }
+
+    /// Pick the upstream for a request
+    pub fn pick(self: &Arc<Self>) -> PickedUpstream {
+        self.pick_excluding(None)
+    }
+
+    fn pick_excluding(self: &Arc<Self>, excluded: Option<usize>) -> PickedUpstream {
+        let count = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        // Healthy upstreams first; when none is, any of them
+        let candidates = |healthy_only: bool| {
+            (0..count)
+                .map(move |offset| (start + offset) % count)
+                .filter(move |&index| Some(index) != excluded || count == 1)
+                .filter(move |&index| {
+                    !healthy_only || self.upstreams[index].healthy.load(Ordering::Relaxed)
+                })
+        };
+        let choose = |healthy_only: bool| match self.strategy {
+            BalanceStrategy::RoundRobin => candidates(healthy_only).next(),
+            BalanceStrategy::LeastConnections => candidates(healthy_only)
+                .min_by_key(|&index| self.upstreams[index].in_flight.load(Ordering::Relaxed)),
+        };
+        let index = choose(true)
+            .or_else(|| choose(false))
+            .unwrap_or(start % count);
+
+        self.upstreams[index]
+            .in_flight
+            .fetch_add(1, Ordering::Relaxed);
+        PickedUpstream {
+            balancer: self.clone(),
+            index,
+        }
+    }
+
+    fn set_healthy(&self, index: usize, healthy: bool) {
+        let upstream = &self.upstreams[index];
+        if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            if healthy {
+                info!("Upstream {} is back", upstream.address);
+            } else {
+                warn!("Upstream {} is down", upstream.address);
+            }
+        }
+    }
+
+    /// Check the upstreams in the background for as long as the balancer is in use
+    pub fn spawn_health_checks(self: &Arc<Self>) {
+        let balancer = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(balancer) = Weak::upgrade(&balancer) else {
+                    return;
+                };
+                for (index, upstream) in balancer.upstreams.iter().enumerate() {
+                    let healthy = check(&upstream.address).await;
+                    balancer.set_healthy(index, healthy);
+                }
+            }
+        });
+    }
+}
+
+/// Whether the upstream accepts TCP connections
+async fn check(address: &str) -> bool {
+    let Some(target) = url::Url::parse(address).ok().and_then(|url| {
+        let port = url.port_or_known_default()?;
+        Some(format!("{}:{}", url.host_str()?, port))
+    }) else {
+        return true;
+    };
+    match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, TcpStream::connect(&target)).await {
+        Ok(Ok(_)) => true,
+        Ok(Err(e)) => {
+            debug!("Health check of {} failed: {}", address, e);
+            false
+        }
+        Err(_) => {
+            debug!("Health check of {} timed out", address);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(strategy: BalanceStrategy) -> Arc<Balancer> {
+        Balancer::new(
+            (3000..3003)
+                .map(|port| format!("http://127.0.0.1:{}", port))
+                .collect(),
+            strategy,
+        )
+    }
+
+    fn port(picked: &PickedUpstream) -> &str {
+        picked.address().rsplit(':').next().unwrap()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = balancer(BalanceStrategy::RoundRobin);
+        let ports: Vec<String> = (0..4).map(|_| port(&balancer.pick()).to_string()).collect();
+        assert_eq!(ports, ["3000", "3001", "3002", "3000"]);
+        assert!(balancer.serves("http://127.0.0.1:3000"));
+        assert!(!balancer.serves("http://127.0.0.1:3001"));
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let balancer = balancer(BalanceStrategy::LeastConnections);
+        let first = balancer.pick();
+        let second = balancer.pick();
+        assert_eq!((port(&first), port(&second)), ("3000", "3001"));
+
+        // 3002 is idle, and once 3000 finishes it's idle too
+        assert_eq!(port(&balancer.pick()), "3002");
+        drop(first);
+        let third = balancer.pick();
+        assert_eq!(port(&third), "3000");
+    }
+
+    #[test]
+    fn test_fail_over_skips_down_upstreams() {
+        let balancer = balancer(BalanceStrategy::RoundRobin);
+        let picked = balancer.pick();
+        let other = picked.fail_over().unwrap();
+        assert_eq!(port(&other), "3001");
+        // 3000 stays out until a health check brings it back
+        let ports: Vec<String> = (0..3).map(|_| port(&balancer.pick()).to_string()).collect();
+        assert!(!ports.contains(&"3000".to_string()), "{:?}", ports);
+        balancer.set_healthy(0, true);
+        assert!(
+            (0..3).any(|_| port(&balancer.pick()) == "3000"),
+            "3000 should be picked again"
+        );
+    }
+
+    #[test]
+    fn test_all_down_still_picks() {
+        let balancer = balancer(BalanceStrategy::LeastConnections);
+        for index in 0..3 {
+            balancer.set_healthy(index, false);
+        }
+        let picked = balancer.pick();
+        assert!(picked.fail_over().is_some());
+        assert_eq!(balancer.upstreams[0].in_flight.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        assert!(check(&address).await);
+        drop(listener);
+        assert!(!check(&address).await);
+    }
+}
@@ -0,0 +1,197 @@
+//! Bandwidth limit and transfer quota (`--max-bandwidth`, `--max-transfer`)
+//!
+//! On a metered connection, or to keep the data transfer bill in check, the
+//! forwarder can cap how fast it moves data through the tunnel and how much it
+//! moves in total. Both count the messages exchanged with the endpoint (request
+//! and response bodies, WebSocket passthrough data), in both directions and
+//! across every tunnel of the process. Messages wait for the bandwidth limit
+//! before they're sent or handled; once the quota is used up the forwarder stops
+//! with exit code 8 instead of reconnecting.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::warn;
+
+use crate::exit::QuotaExceeded;
+use crate::stats::format_bytes;
+
+/// Share of the quota after which a warning is logged
+const QUOTA_WARNING: f64 = 0.8;
+
+/// Throttles and counts the data going through the tunnel
+#[derive(Debug)]
+pub struct TransferLimit {
+    /// Bytes per second (unlimited if None)
+    rate: Option<u64>,
+    /// Bytes in total (unlimited if None)
+    quota: Option<u64>,
+    transferred: AtomicU64,
+    warned: AtomicBool,
+    bucket: Mutex<Bucket>,
+}
+
+/// Token bucket holding up to one second of bandwidth
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may go through right away; negative while messages wait
+    available: f64,
+    updated: Instant,
+}
+
+impl TransferLimit {
+    pub fn new(rate: Option<u64>, quota: Option<u64>) -> Self {
+        Self {
+            rate,
+            quota,
+            transferred: AtomicU64::new(0),
+            warned: AtomicBool::new(false),
+            bucket: Mutex::new(Bucket {
+                available: rate.unwrap_or_default() as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Bytes moved through the tunnel so far
+    pub fn transferred(&self) -> u64 {
+        self.transferred.load(Ordering::Relaxed)
+    }
+
+    /// Account for `bytes` about to go through, waiting for the bandwidth limit
+    ///
+    /// Fails once the quota doesn't leave room for them.
+    pub async fn take(&self, bytes: u64) -> Result<(), QuotaExceeded> {
+        if bytes == 0 {
+            return Ok(());
+        }
+        let transferred = self.transferred.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if let Some(quota) = self.quota {
+            if transferred > quota {
+                return Err(QuotaExceeded { quota });
+            }
+            if transferred as f64 >= quota as f64 * QUOTA_WARNING
+                && !self.warned.swap(true, Ordering::Relaxed)
+            {
+                warn!(
+                    "{} of the {} transfer quota used; the tunnel stops when it runs out",
+                    format_bytes(transferred),
+                    format_bytes(quota)
+                );
+            }
+        }
+
+        let Some(rate) = self.rate else {
+            return Ok(());
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.updated).as_secs_f64() * rate as f64;
+            bucket.available = (bucket.available + refill).min(rate as f64) - bytes as f64;
+            bucket.updated = now;
+            // Messages queue up behind the ones already waiting
+            Duration::from_secs_f64((-bucket.available).max(0.0) / rate as f64)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+/// Bytes of data a WebSocket message carries; control frames don't count
+pub fn message_len(message: &WsMessage) -> u64 {
+    match message {
+        WsMessage::Text(text) => text.len() as u64,
+        WsMessage::Binary(data) => data.len() as u64,
+        _ => 0,
+    }
+}
+
+/// Parse a byte count: `1GB`, `500KiB`, `1048576`
+///
+/// KB, MB, GB and TB are powers of 1000; KiB, MiB, GiB and TiB powers of 1024.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size `{}`, expected e.g. `1GB` or `500KiB`", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(format!("unknown unit `{}` in `{}`", unit.trim(), value)),
+    };
+    let bytes = (number * multiplier as f64).round();
+    if bytes < 1.0 {
+        return Err(format!("`{}` is less than a byte", value));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parse a bandwidth in bytes per second: `5MBps`, `5MB/s`, `500KiB`
+pub fn parse_bandwidth(value: &str) -> Result<u64, String> {
+    let trimmed = value.trim();
+    let size = trimmed
+        .strip_suffix("/s")
+        .or_else(|| trimmed.strip_suffix("ps"))
+        .unwrap_or(trimmed);
+    parse_bytes(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sizes() {
+        assert_eq!(parse_bytes("1048576"), Ok(1_048_576));
+        assert_eq!(parse_bytes("1GB"), Ok(1_000_000_000));
+        assert_eq!(parse_bytes("1.5 MiB"), Ok(1_572_864));
+        assert_eq!(parse_bandwidth("5MBps"), Ok(5_000_000));
+        assert_eq!(parse_bandwidth("512KiB/s"), Ok(524_288));
+        assert_eq!(parse_bandwidth("100kb"), Ok(100_000));
+        assert!(parse_bytes("1 parsec").is_err());
+        assert!(parse_bytes("GB").is_err());
+        assert!(parse_bytes("0").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bandwidth_limit() {
+        let limit = TransferLimit::new(Some(1000), None);
+        let started = Instant::now();
+        // A second's worth goes through at once, the rest at the limit
+        limit.take(1000).await.unwrap();
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        limit.take(500).await.unwrap();
+        limit.take(1500).await.unwrap();
+        assert_eq!(started.elapsed().as_millis(), 2000);
+        assert_eq!(limit.transferred(), 3000);
+    }
+
+    #[tokio::test]
+    async fn test_quota() {
+        let limit = TransferLimit::new(None, Some(1000));
+        limit.take(600).await.unwrap();
+        limit.take(400).await.unwrap();
+        let error = limit.take(1).await.unwrap_err();
+        assert_eq!(error.quota, 1000);
+        // Control frames don't count
+        limit
+            .take(message_len(&WsMessage::Ping(vec![0; 8].into())))
+            .await
+            .unwrap();
+    }
+}
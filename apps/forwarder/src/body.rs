@@ -0,0 +1,574 @@
+//! Response body buffering with disk spill-over
+//!
+//! Local responses are accumulated in memory until they cross a configurable
+//! threshold, at which point the buffered bytes (and everything after them) are
+//! written to an anonymous temporary file. Spilled bodies are read back one
+//! `BodyChunk` at a time as the response is sent ([`SpilledChunks`]), so
+//! tunneling a large artifact doesn't balloon the agent's memory usage.
+//!
+//! Streams that never really end, like Server-Sent Events, aren't buffered at all
+//! when the handler can relay them: each piece goes out as the local service
+//! writes it.
+//!
+//! Bodies are read frame by frame so trailers (gRPC's `grpc-status` and
+//! `grpc-message`, or those of a chunked HTTP/1.1 body) travel back too.
+
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use http_tunnel_common::constants::STREAM_CHUNK_SIZE_BYTES;
+use http_tunnel_common::protocol::BodyCompression;
+use http_tunnel_common::{Message, encode_body, headers_to_map, map_to_headers};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tracing::debug;
+
+/// Default in-memory limit before a response body is spilled to disk (8 MiB)
+///
+/// Above the AWS deployment's 2 MiB body limit, so with defaults only
+/// self-hosted servers that accept larger bodies see spilled responses.
+pub const DEFAULT_SPILL_THRESHOLD_BYTES: usize = 8 * 1024 * 1024;
+
+/// Chunk size used when reading spilled bodies back (multiple of 3 so each
+/// chunk base64-encodes without intermediate padding)
+const READ_CHUNK_SIZE: usize = 3 * 64 * 1024;
+
+/// What to do with a response larger than `--max-response-size`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizeResponse {
+    /// Reply with 502 instead of the response
+    #[default]
+    Reject,
+    /// Send the first `--max-response-size` bytes with an `x-tunnel-truncated` header
+    Truncate,
+}
+
+/// Accumulates a response body, spilling to a temporary file past the threshold
+pub struct BodyBuffer {
+    threshold: usize,
+    memory: Vec<u8>,
+    spilled: Option<(File, u64)>,
+}
+
+/// A fully buffered response body
+pub enum BufferedBody {
+    /// Body small enough to keep in memory
+    Memory(Vec<u8>),
+    /// Body written to a temporary file (removed automatically when dropped)
+    Spilled { file: File, len: u64 },
+}
+
+impl BodyBuffer {
+    /// Create a new buffer that spills to disk once `threshold` bytes are exceeded
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            memory: Vec::new(),
+            spilled: None,
+        }
+    }
+
+    /// Bytes buffered so far
+    pub fn len(&self) -> usize {
+        match &self.spilled {
+            Some((_, len)) => *len as usize,
+            None => self.memory.len(),
+        }
+    }
+
+    /// Append a chunk of body data
+    pub async fn push(&mut self, chunk: &[u8]) -> Result<()> {
+        if let Some((file, len)) = self.spilled.as_mut() {
+            file.write_all(chunk).await?;
+            *len += chunk.len() as u64;
+            return Ok(());
+        }
+
+        if self.memory.len() + chunk.len() <= self.threshold {
+            self.memory.extend_from_slice(chunk);
+            return Ok(());
+        }
+
+        debug!(
+            "Response body exceeded {} bytes, spilling to disk",
+            self.threshold
+        );
+
+        let mut file = File::from_std(tempfile::tempfile()?);
+        file.write_all(&self.memory).await?;
+        file.write_all(chunk).await?;
+        let len = (self.memory.len() + chunk.len()) as u64;
+        self.memory = Vec::new();
+        self.spilled = Some((file, len));
+
+        Ok(())
+    }
+
+    /// Finish buffering and return the body
+    pub async fn finish(self) -> Result<BufferedBody> {
+        match self.spilled {
+            Some((mut file, len)) => {
+                file.flush().await?;
+                file.rewind().await?;
+                Ok(BufferedBody::Spilled { file, len })
+            }
+            None => Ok(BufferedBody::Memory(self.memory)),
+        }
+    }
+}
+
+impl BufferedBody {
+    /// Total body length in bytes
+    pub fn len(&self) -> u64 {
+        match self {
+            BufferedBody::Memory(bytes) => bytes.len() as u64,
+            BufferedBody::Spilled { len, .. } => *len,
+        }
+    }
+
+    /// Whether the body was spilled to disk
+    pub fn is_spilled(&self) -> bool {
+        matches!(self, BufferedBody::Spilled { .. })
+    }
+
+    /// Base64-encode the whole body, reading spilled content back in chunks
+    ///
+    /// Only for responses that must be complete in memory (plugins, end-to-end
+    /// sealing); otherwise send spilled bodies with [`SpilledChunks`].
+    pub async fn encode(self) -> Result<String> {
+        match self {
+            BufferedBody::Memory(bytes) => Ok(encode_body(&bytes)),
+            BufferedBody::Spilled { mut file, len } => {
+                let mut encoded = String::with_capacity((len as usize).div_ceil(3) * 4);
+                let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+
+                loop {
+                    let filled = read_full(&mut file, &mut chunk).await?;
+                    if filled == 0 {
+                        break;
+                    }
+                    encoded.push_str(&encode_body(&chunk[..filled]));
+                    if filled < chunk.len() {
+                        break;
+                    }
+                }
+
+                Ok(encoded)
+            }
+        }
+    }
+}
+
+/// A spilled body read back as the Base64 slices of `BodyChunk` messages
+///
+/// Each slice is [`STREAM_CHUNK_SIZE_BYTES`] of the body (the last one may be
+/// shorter), which encodes to at most `BODY_CHUNK_SIZE_BYTES` without padding
+/// until the end, so the slices concatenate to the encoding of the whole body.
+pub struct SpilledChunks {
+    file: File,
+    buf: Vec<u8>,
+    done: bool,
+}
+
+impl SpilledChunks {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            buf: vec![0u8; STREAM_CHUNK_SIZE_BYTES],
+            done: false,
+        }
+    }
+
+    /// The next Base64 slice, or `None` once the body has been read
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        if self.done {
+            return Ok(None);
+        }
+        let filled = read_full(&mut self.file, &mut self.buf).await?;
+        self.done = filled < self.buf.len();
+        if filled == 0 {
+            return Ok(None);
+        }
+        Ok(Some(encode_body(&self.buf[..filled])))
+    }
+}
+
+/// Compress a spilled body into another temporary file
+///
+/// Returns the compressed file, rewound, and its length, or `None` (with `file`
+/// rewound) when compression doesn't make the body smaller, as
+/// [`HttpResponse::compress_body`] leaves such bodies alone.
+///
+/// [`HttpResponse::compress_body`]: http_tunnel_common::HttpResponse::compress_body
+pub async fn compress_spilled(
+    file: &mut File,
+    len: u64,
+    compression: BodyCompression,
+) -> Result<Option<(File, u64)>> {
+    let Some(mut compressor) = compression.compressor()? else {
+        return Ok(None);
+    };
+    let mut compressed = File::from_std(tempfile::tempfile()?);
+    let mut compressed_len = 0;
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+    let mut done = false;
+    // Give up early once the output is no smaller than the body
+    while !done && compressed_len < len {
+        let filled = read_full(file, &mut chunk).await?;
+        done = filled < chunk.len();
+        let output = compressor.write(&chunk[..filled])?;
+        compressed.write_all(&output).await?;
+        compressed_len += output.len() as u64;
+    }
+    if done {
+        let output = compressor.finish()?;
+        compressed.write_all(&output).await?;
+        compressed_len += output.len() as u64;
+    }
+    file.rewind().await?;
+    if !done || compressed_len >= len {
+        return Ok(None);
+    }
+    compressed.flush().await?;
+    compressed.rewind().await?;
+    Ok(Some((compressed, compressed_len)))
+}
+
+/// Length of a Base64-encoded body once decoded
+pub fn decoded_len(encoded: &str) -> usize {
+    let padding = encoded.bytes().rev().take_while(|b| *b == b'=').count();
+    (encoded.len() * 3 / 4).saturating_sub(padding)
+}
+
+/// Content types relayed piece by piece instead of buffered
+const STREAMED_CONTENT_TYPES: [&str; 2] = ["text/event-stream", "application/x-ndjson"];
+
+/// Whether a local response is a stream to relay as it's produced
+pub fn is_streamed(headers: &HashMap<String, Vec<String>>) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .flat_map(|(_, values)| values)
+        .any(|value| {
+            let media_type = value.split(';').next().unwrap_or_default().trim();
+            STREAMED_CONTENT_TYPES
+                .iter()
+                .any(|streamed| media_type.eq_ignore_ascii_case(streamed))
+        })
+}
+
+/// `ResponseChunk` messages carrying `data`, numbered from `next_index`
+///
+/// Data too large for one frame is split; `last` marks the final message, which
+/// is sent even without data so the handler learns the stream ended.
+pub fn stream_pieces(
+    request_id: &str,
+    next_index: &mut u32,
+    data: &[u8],
+    last: bool,
+) -> Vec<Message> {
+    let mut pieces: Vec<&[u8]> = data.chunks(STREAM_CHUNK_SIZE_BYTES).collect();
+    if pieces.is_empty() && last {
+        pieces.push(&[]);
+    }
+
+    let count = pieces.len();
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(position, piece)| {
+            let index = *next_index;
+            *next_index += 1;
+            Message::ResponseChunk {
+                request_id: request_id.to_string(),
+                index,
+                data: encode_body(piece),
+                last: last && position + 1 == count,
+            }
+        })
+        .collect()
+}
+
+/// A local response body, read piece by piece while collecting its trailers
+pub struct LocalBody {
+    body: reqwest::Body,
+    trailers: HashMap<String, Vec<String>>,
+}
+
+impl LocalBody {
+    pub fn new(response: reqwest::Response) -> Self {
+        Self {
+            body: http::Response::from(response).into_body(),
+            trailers: HashMap::new(),
+        }
+    }
+
+    /// Next piece of the body, or `None` once it (and any trailers) ended
+    pub async fn chunk(&mut self) -> reqwest::Result<Option<Bytes>> {
+        while let Some(frame) = self.body.frame().await.transpose()? {
+            match frame.into_data() {
+                Ok(data) => return Ok(Some(data)),
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        for (name, values) in headers_to_map(&trailers) {
+                            self.trailers.entry(name).or_default().extend(values);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Trailers received so far (all of them once `chunk` returned `None`)
+    pub fn into_trailers(self) -> HashMap<String, Vec<String>> {
+        self.trailers
+    }
+}
+
+/// A request body followed by trailers
+pub fn with_trailers(body: Vec<u8>, trailers: &HashMap<String, Vec<String>>) -> reqwest::Body {
+    let trailers = map_to_headers(trailers);
+    reqwest::Body::wrap(
+        Full::new(Bytes::from(body)).with_trailers(async move { Some(Ok(trailers)) }),
+    )
+}
+
+/// Content encodings the handler can decompress to rewrite a body
+const REWRITABLE_ENCODINGS: [&str; 4] = ["gzip", "deflate", "br", "identity"];
+
+/// Narrow a client's `Accept-Encoding` to the encodings the handler can rewrite,
+/// so the local service doesn't pick one (like zstd) that leaves links unrewritten
+///
+/// Returns `None` when none remain, letting the local service send it unencoded.
+pub fn rewritable_accept_encoding(value: &str) -> Option<String> {
+    let accepted: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|coding| {
+            let name = coding.split(';').next().unwrap_or_default().trim();
+            REWRITABLE_ENCODINGS
+                .iter()
+                .any(|encoding| name.eq_ignore_ascii_case(encoding))
+        })
+        .collect();
+    (!accepted.is_empty()).then(|| accepted.join(", "))
+}
+
+/// Whether a request is gRPC, which needs HTTP/2 to the local service
+pub fn is_grpc(headers: &HashMap<String, Vec<String>>) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        .flat_map(|(_, values)| values)
+        .any(|value| {
+            let media_type = value.split(';').next().unwrap_or_default().trim();
+            let media_type = media_type.to_ascii_lowercase();
+            media_type == "application/grpc" || media_type.starts_with("application/grpc+")
+        })
+}
+
+/// Read until the buffer is full or EOF, returning the number of bytes read
+async fn read_full(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_small_body_stays_in_memory() {
+        let mut buffer = BodyBuffer::new(1024);
+        buffer.push(b"hello ").await.unwrap();
+        buffer.push(b"world").await.unwrap();
+
+        let body = buffer.finish().await.unwrap();
+        assert!(!body.is_spilled());
+        assert_eq!(body.len(), 11);
+        assert_eq!(body.encode().await.unwrap(), encode_body(b"hello world"));
+    }
+
+    #[test]
+    fn test_is_streamed() {
+        let headers =
+            |value: &str| HashMap::from([("Content-Type".to_string(), vec![value.to_string()])]);
+        assert!(is_streamed(&headers("text/event-stream")));
+        assert!(is_streamed(&headers("text/event-stream; charset=utf-8")));
+        assert!(is_streamed(&headers("application/x-ndjson")));
+        assert!(!is_streamed(&headers("application/json")));
+        assert!(!is_streamed(&HashMap::new()));
+    }
+
+    #[test]
+    fn test_is_grpc() {
+        let headers =
+            |value: &str| HashMap::from([("content-type".to_string(), vec![value.to_string()])]);
+        assert!(is_grpc(&headers("application/grpc")));
+        assert!(is_grpc(&headers("application/grpc+proto")));
+        assert!(!is_grpc(&headers("application/grpc-web")));
+        assert!(!is_grpc(&headers("application/json")));
+    }
+
+    #[test]
+    fn test_rewritable_accept_encoding() {
+        assert_eq!(
+            rewritable_accept_encoding("gzip, deflate, br, zstd").as_deref(),
+            Some("gzip, deflate, br")
+        );
+        assert_eq!(
+            rewritable_accept_encoding("br;q=1.0, gzip;q=0.8, *;q=0.1").as_deref(),
+            Some("br;q=1.0, gzip;q=0.8")
+        );
+        assert_eq!(rewritable_accept_encoding("zstd"), None);
+    }
+
+    #[tokio::test]
+    async fn test_local_body_keeps_trailers() {
+        let trailers = HashMap::from([("grpc-status".to_string(), vec!["0".to_string()])]);
+        let response = reqwest::Response::from(http::Response::new(with_trailers(
+            b"abc".to_vec(),
+            &trailers,
+        )));
+
+        let mut body = LocalBody::new(response);
+        assert_eq!(body.chunk().await.unwrap().unwrap(), "abc");
+        assert!(body.chunk().await.unwrap().is_none());
+        assert_eq!(body.into_trailers(), trailers);
+    }
+
+    #[test]
+    fn test_stream_pieces() {
+        let mut index = 0;
+        let data = vec![b'x'; STREAM_CHUNK_SIZE_BYTES + 10];
+
+        let pieces = stream_pieces("req_1", &mut index, &data, false);
+        assert_eq!(pieces.len(), 2);
+        assert_eq!(index, 2);
+        assert!(
+            pieces
+                .iter()
+                .all(|piece| matches!(piece, Message::ResponseChunk { last: false, .. }))
+        );
+
+        // An empty read still ends the stream
+        assert!(stream_pieces("req_1", &mut index, &[], false).is_empty());
+        let pieces = stream_pieces("req_1", &mut index, &[], true);
+        assert!(matches!(
+            &pieces[..],
+            [Message::ResponseChunk { index: 2, data, last: true, .. }] if data.is_empty()
+        ));
+
+        let pieces = stream_pieces("req_1", &mut index, b"data: done\n\n", true);
+        assert!(matches!(
+            &pieces[..],
+            [Message::ResponseChunk {
+                index: 3,
+                last: true,
+                ..
+            }]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_large_body_spills_to_disk() {
+        let data: Vec<u8> = (0..READ_CHUNK_SIZE * 2 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect();
+
+        let mut buffer = BodyBuffer::new(1000);
+        for chunk in data.chunks(4096) {
+            buffer.push(chunk).await.unwrap();
+        }
+        assert_eq!(buffer.len(), data.len());
+
+        let body = buffer.finish().await.unwrap();
+        assert!(body.is_spilled());
+        assert_eq!(body.len(), data.len() as u64);
+        assert_eq!(body.encode().await.unwrap(), encode_body(&data));
+    }
+
+    #[test]
+    fn test_decoded_len() {
+        for len in 0..8 {
+            let data = vec![7u8; len];
+            assert_eq!(decoded_len(&encode_body(&data)), len);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spilled_chunks_concatenate_to_body() {
+        use http_tunnel_common::constants::BODY_CHUNK_SIZE_BYTES;
+
+        let data: Vec<u8> = (0..STREAM_CHUNK_SIZE_BYTES * 3 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut buffer = BodyBuffer::new(1000);
+        buffer.push(&data).await.unwrap();
+        let BufferedBody::Spilled { file, .. } = buffer.finish().await.unwrap() else {
+            panic!("expected a spilled body");
+        };
+
+        let mut chunks = SpilledChunks::new(file);
+        let mut slices = Vec::new();
+        while let Some(slice) = chunks.next().await.unwrap() {
+            assert!(slice.len() <= BODY_CHUNK_SIZE_BYTES);
+            slices.push(slice);
+        }
+        assert_eq!(slices.len(), 4);
+        assert_eq!(slices.concat(), encode_body(&data));
+    }
+
+    #[tokio::test]
+    async fn test_compress_spilled_body() {
+        let data = b"<li><a href=\"/item\">Item</a></li>".repeat(20_000);
+        let mut buffer = BodyBuffer::new(1000);
+        buffer.push(&data).await.unwrap();
+        let BufferedBody::Spilled { mut file, len } = buffer.finish().await.unwrap() else {
+            panic!("expected a spilled body");
+        };
+
+        let (compressed, compressed_len) = compress_spilled(&mut file, len, BodyCompression::Zstd)
+            .await
+            .unwrap()
+            .expect("markup shrinks");
+        assert!(compressed_len < len);
+        let encoded = BufferedBody::Spilled {
+            file: compressed,
+            len: compressed_len,
+        }
+        .encode()
+        .await
+        .unwrap();
+        let decoded = http_tunnel_common::decode_body(&encoded).unwrap();
+        assert_eq!(decoded.len() as u64, compressed_len);
+        assert_eq!(BodyCompression::Zstd.decompress(&decoded).unwrap(), data);
+
+        // Random bytes don't shrink, and the body is left to be read again
+        let noise: Vec<u8> = (0..100_000).map(|_| rand::random()).collect();
+        let mut buffer = BodyBuffer::new(1000);
+        buffer.push(&noise).await.unwrap();
+        let BufferedBody::Spilled { mut file, len } = buffer.finish().await.unwrap() else {
+            panic!("expected a spilled body");
+        };
+        assert!(
+            compress_spilled(&mut file, len, BodyCompression::Deflate)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        let encoded = BufferedBody::Spilled { file, len }.encode().await.unwrap();
+        assert_eq!(encoded, encode_body(&noise));
+    }
+}
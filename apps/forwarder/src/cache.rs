@@ -0,0 +1,380 @@
+//! In-memory cache of local responses (`--cache`)
+//!
+//! Pages reloaded during a demo fetch the same scripts, styles and images over
+//! and over. With `--cache`, responses to `GET` and `HEAD` requests are kept in
+//! memory, keyed by method, path and query, for as long as the local service's
+//! `Cache-Control: max-age` (or `s-maxage`) allows, and served again without a
+//! trip to the local service. Hits carry `X-Tunnel-Cache: hit` and an `Age`.
+//!
+//! Nothing is cached unless the local service says so: responses marked
+//! `no-store`, `no-cache` or `private`, or carrying `Vary` or `Set-Cookie`, are
+//! left alone, as are requests with credentials (`Authorization`, `Cookie`).
+//! A client sending `Cache-Control: no-cache` bypasses the cache. Once the
+//! cache holds `--cache-size` bytes, the least recently used responses go.
+
+use http_tunnel_common::{HttpRequest, HttpResponse};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bytes of responses kept by default
+pub const DEFAULT_CACHE_SIZE_BYTES: usize = 32 * 1024 * 1024;
+
+/// Header marking responses served from the cache
+const CACHE_HEADER: &str = "x-tunnel-cache";
+
+/// Statuses that may be cached when the response allows it
+const CACHEABLE_STATUSES: [u16; 6] = [200, 203, 204, 301, 404, 410];
+
+/// Keeps cacheable responses of the local service, least recently used first out
+#[derive(Debug)]
+pub struct ResponseCache {
+    max_bytes: usize,
+    inner: Mutex<Entries>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+    bytes: usize,
+    /// Bumped on every use, so the entry with the lowest `last_used` is the LRU
+    clock: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    response: HttpResponse,
+    size: usize,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            inner: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// The cached response to `request`, if there's a fresh one
+    ///
+    /// The response is addressed to `request` and marked as a cache hit.
+    pub fn get(&self, request: &HttpRequest, now: Instant) -> Option<HttpResponse> {
+        let key = cache_key(request)?;
+        if header_has_directive(&request.headers, "cache-control", "no-cache")
+            || header_has_directive(&request.headers, "pragma", "no-cache")
+        {
+            return None;
+        }
+
+        let mut inner = self.lock();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(&key)?;
+        if now >= entry.expires {
+            let size = entry.size;
+            inner.entries.remove(&key);
+            inner.bytes -= size;
+            return None;
+        }
+        entry.last_used = clock;
+
+        let mut response = entry.response.clone();
+        let age = now.saturating_duration_since(entry.stored).as_secs();
+        response.request_id = request.request_id.clone();
+        response.processing_time_ms = 0;
+        response
+            .headers
+            .insert("age".to_string(), vec![age.to_string()]);
+        response
+            .headers
+            .insert(CACHE_HEADER.to_string(), vec!["hit".to_string()]);
+        Some(response)
+    }
+
+    /// Keep the response to `request` if both allow caching it
+    pub fn store(&self, request: &HttpRequest, response: &HttpResponse, now: Instant) {
+        let Some(key) = cache_key(request) else {
+            return;
+        };
+        let Some(max_age) = freshness(response) else {
+            return;
+        };
+        if header_has_directive(&request.headers, "cache-control", "no-store") {
+            return;
+        }
+        let size = response_size(&key, response);
+        // A single response may take an eighth of the cache
+        if size > self.max_bytes / 8 {
+            return;
+        }
+
+        let mut inner = self.lock();
+        inner.clock += 1;
+        let entry = Entry {
+            response: HttpResponse {
+                request_id: String::new(),
+                ..response.clone()
+            },
+            size,
+            stored: now,
+            expires: now + max_age,
+            last_used: inner.clock,
+        };
+        if let Some(old) = inner.entries.insert(key, entry) {
+            inner.bytes -= old.size;
+        }
+        inner.bytes += size;
+
+        while inner.bytes > self.max_bytes {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.bytes -= evicted.size;
+            }
+        }
+    }
+
+    /// Number of cached responses
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Key of a request that may be answered from the cache
+fn cache_key(request: &HttpRequest) -> Option<String> {
+    let method = request.method.to_ascii_uppercase();
+    if !matches!(method.as_str(), "GET" | "HEAD")
+        || request.has_body()
+        || has_header(&request.headers, "authorization")
+        || has_header(&request.headers, "cookie")
+    {
+        return None;
+    }
+    Some(format!("{} {}", method, request.uri))
+}
+
+/// How long the response may be served from the cache, if at all
+fn freshness(response: &HttpResponse) -> Option<Duration> {
+    if !CACHEABLE_STATUSES.contains(&response.status_code)
+        || response.streaming
+        || has_header(&response.headers, "vary")
+        || has_header(&response.headers, "set-cookie")
+        || has_header(&response.headers, "x-tunnel-truncated")
+    {
+        return None;
+    }
+
+    let mut max_age = None;
+    let mut shared_max_age = None;
+    for directive in directives(&response.headers, "cache-control") {
+        let (name, value) = match directive.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (directive, None),
+        };
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" | "private" => return None,
+            "max-age" => max_age = value.and_then(|value| value.parse().ok()),
+            "s-maxage" => shared_max_age = value.and_then(|value| value.parse().ok()),
+            _ => {}
+        }
+    }
+    shared_max_age
+        .or(max_age)
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+/// Rough memory taken by a cached response
+fn response_size(key: &str, response: &HttpResponse) -> usize {
+    let headers: usize = response
+        .headers
+        .iter()
+        .chain(&response.trailers)
+        .map(|(name, values)| name.len() + values.iter().map(String::len).sum::<usize>())
+        .sum();
+    key.len() + headers + response.body.len()
+}
+
+fn has_header(headers: &HashMap<String, Vec<String>>, name: &str) -> bool {
+    headers
+        .iter()
+        .any(|(key, values)| key.eq_ignore_ascii_case(name) && !values.is_empty())
+}
+
+/// Comma-separated directives of every value of a header
+fn directives<'a>(
+    headers: &'a HashMap<String, Vec<String>>,
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> {
+    headers
+        .iter()
+        .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+        .flat_map(|(_, values)| values)
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+}
+
+fn header_has_directive(
+    headers: &HashMap<String, Vec<String>>,
+    name: &str,
+    directive: &str,
+) -> bool {
+    directives(headers, name).any(|value| value.eq_ignore_ascii_case(directive))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, uri: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        let mut request =
+            HttpRequest::new(method.to_string(), uri.to_string(), "req-1".to_string(), 0);
+        for (name, value) in headers {
+            request
+                .headers
+                .insert(name.to_string(), vec![value.to_string()]);
+        }
+        request
+    }
+
+    fn response(headers: &[(&str, &str)], body: &str) -> HttpResponse {
+        HttpResponse {
+            request_id: "req-1".to_string(),
+            status_code: 200,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), vec![value.to_string()]))
+                .collect(),
+            body: body.to_string(),
+            processing_time_ms: 12,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_serves_fresh_responses() {
+        let cache = ResponseCache::new(DEFAULT_CACHE_SIZE_BYTES);
+        let now = Instant::now();
+        let get = request("GET", "/app.js?v=1", &[]);
+        cache.store(
+            &get,
+            &response(&[("cache-control", "public, max-age=60")], "Y29kZQ=="),
+            now,
+        );
+
+        let mut again = request("GET", "/app.js?v=1", &[]);
+        again.request_id = "req-2".to_string();
+        let hit = cache.get(&again, now + Duration::from_secs(5)).unwrap();
+        assert_eq!(hit.request_id, "req-2");
+        assert_eq!(hit.body, "Y29kZQ==");
+        assert_eq!(hit.headers["age"], vec!["5"]);
+        assert_eq!(hit.headers[CACHE_HEADER], vec!["hit"]);
+
+        // Other queries and methods are separate entries
+        assert!(
+            cache
+                .get(&request("GET", "/app.js?v=2", &[]), now)
+                .is_none()
+        );
+        assert!(
+            cache
+                .get(&request("HEAD", "/app.js?v=1", &[]), now)
+                .is_none()
+        );
+
+        // Expired entries are dropped
+        assert!(cache.get(&get, now + Duration::from_secs(60)).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn test_honors_cache_control() {
+        let cache = ResponseCache::new(DEFAULT_CACHE_SIZE_BYTES);
+        let now = Instant::now();
+        let get = request("GET", "/", &[]);
+        for headers in [
+            &[][..],
+            &[("Cache-Control", "max-age=0")],
+            &[("Cache-Control", "no-store")],
+            &[("Cache-Control", "private, max-age=60")],
+            &[("cache-control", "max-age=60"), ("vary", "Accept-Encoding")],
+            &[("cache-control", "max-age=60"), ("set-cookie", "id=1")],
+        ] {
+            cache.store(&get, &response(headers, ""), now);
+        }
+        assert_eq!(cache.len(), 0);
+
+        let mut not_found = response(&[("Cache-Control", "s-maxage=30, max-age=5")], "");
+        not_found.status_code = 404;
+        cache.store(&get, &not_found, now);
+        assert!(cache.get(&get, now + Duration::from_secs(10)).is_some());
+
+        let mut error = response(&[("Cache-Control", "max-age=60")], "");
+        error.status_code = 500;
+        cache.store(&request("GET", "/error", &[]), &error, now);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_skips_uncacheable_requests() {
+        let cache = ResponseCache::new(DEFAULT_CACHE_SIZE_BYTES);
+        let now = Instant::now();
+        let cacheable = response(&[("cache-control", "max-age=60")], "");
+        for request in [
+            request("POST", "/", &[]),
+            request("GET", "/", &[("Authorization", "Bearer secret")]),
+            request("GET", "/", &[("Cookie", "session=1")]),
+        ] {
+            cache.store(&request, &cacheable, now);
+        }
+        assert_eq!(cache.len(), 0);
+
+        cache.store(&request("GET", "/", &[]), &cacheable, now);
+        let reload = request("GET", "/", &[("Cache-Control", "no-cache")]);
+        assert!(cache.get(&reload, now).is_none());
+        assert!(cache.get(&request("GET", "/", &[]), now).is_some());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let body = "x".repeat(100);
+        let cacheable = response(&[("cache-control", "max-age=60")], &body);
+        let size = response_size("GET /a", &cacheable);
+        let cache = ResponseCache::new(size * 8);
+        let now = Instant::now();
+        for path in ["/a", "/b", "/c", "/d", "/e", "/f", "/g", "/h"] {
+            cache.store(&request("GET", path, &[]), &cacheable, now);
+        }
+        assert_eq!(cache.len(), 8);
+
+        // /a was used recently, so /b goes first
+        assert!(cache.get(&request("GET", "/a", &[]), now).is_some());
+        cache.store(&request("GET", "/i", &[]), &cacheable, now);
+        assert!(cache.get(&request("GET", "/a", &[]), now).is_some());
+        assert!(cache.get(&request("GET", "/b", &[]), now).is_none());
+        assert_eq!(cache.len(), 8);
+
+        // Responses over an eighth of the cache aren't kept
+        let large = response(&[("cache-control", "max-age=60")], &body.repeat(2));
+        cache.store(&request("GET", "/large", &[]), &large, now);
+        assert!(cache.get(&request("GET", "/large", &[]), now).is_none());
+    }
+}
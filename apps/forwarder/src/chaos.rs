@@ -0,0 +1,201 @@
+//! Latency and failure injection (`--chaos latency=200ms,error-rate=0.05`)
+//!
+//! To see how webhook producers and frontends cope with a flaky backend, the
+//! forwarder can slow down every request and fail a share of them before they
+//! reach the local service. The spec is a comma-separated list of:
+//!
+//! - `latency=200ms`, or `latency=100ms..500ms` for a random delay in the range
+//! - `error-rate=0.05` (or `5%`): share of requests answered with an error
+//! - `error-status=503`: status of those errors (default 503)
+//!
+//! Injected errors carry an `x-tunnel-chaos` header. Like the other forwarding
+//! settings, `chaos` can be set in the `--config` file and changed while running.
+
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::duration::parse_duration;
+
+/// Status of injected errors unless `error-status` says otherwise
+const DEFAULT_ERROR_STATUS: u16 = 503;
+
+/// What to inject into forwarded requests
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chaos {
+    /// Delay before each request, picked between the two (inclusive)
+    pub latency: Option<(Duration, Duration)>,
+    /// Share of requests failed, from 0 to 1
+    pub error_rate: f64,
+    /// Status of the injected errors
+    pub error_status: u16,
+}
+
+impl Chaos {
+    /// Delay for the next request
+    pub fn delay(&self) -> Duration {
+        match self.latency {
+            Some((min, max)) if max > min => rand::thread_rng().gen_range(min..=max),
+            Some((min, _)) => min,
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Whether to fail the next request
+    pub fn fails(&self) -> bool {
+        self.error_rate > 0.0 && rand::thread_rng().gen_bool(self.error_rate)
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut chaos = Chaos {
+            latency: None,
+            error_rate: 0.0,
+            error_status: DEFAULT_ERROR_STATUS,
+        };
+        for part in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("expected `key=value`, got `{}`", part))?;
+            let value = value.trim();
+            match key.trim() {
+                "latency" => {
+                    let (min, max) = value.split_once("..").unwrap_or((value, value));
+                    let (min, max) = (parse_duration(min.trim())?, parse_duration(max.trim())?);
+                    if max < min {
+                        return Err(format!("latency range `{}` is backwards", value));
+                    }
+                    chaos.latency = Some((min, max));
+                }
+                "error-rate" => {
+                    chaos.error_rate = match value.strip_suffix('%') {
+                        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+                        None => value.parse(),
+                    }
+                    .ok()
+                    .filter(|rate| (0.0..=1.0).contains(rate))
+                    .ok_or_else(|| {
+                        format!(
+                            "error-rate must be between 0 and 1 (or 0% and 100%), got `{}`",
+                            value
+                        )
+                    })?;
+                }
+                "error-status" => {
+                    chaos.error_status = value
+                        .parse()
+                        .ok()
+                        .filter(|status| (400..=599).contains(status))
+                        .ok_or_else(|| {
+                            format!("error-status must be a 4xx or 5xx status, got `{}`", value)
+                        })?;
+                }
+                other => {
+                    return Err(format!(
+                        "unknown chaos setting `{}` (expected latency, error-rate or error-status)",
+                        other
+                    ));
+                }
+            }
+        }
+        if chaos.latency.is_none() && chaos.error_rate == 0.0 {
+            return Err("set latency or error-rate, e.g. `latency=200ms,error-rate=0.05`".into());
+        }
+        Ok(chaos)
+    }
+}
+
+impl fmt::Display for Chaos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        match self.latency {
+            Some((min, max)) if max > min => parts.push(format!(
+                "{}..{} latency",
+                humantime::format_duration(min),
+                humantime::format_duration(max)
+            )),
+            Some((min, _)) => parts.push(format!("{} latency", humantime::format_duration(min))),
+            None => {}
+        }
+        if self.error_rate > 0.0 {
+            parts.push(format!(
+                "{}% errors ({})",
+                self.error_rate * 100.0,
+                self.error_status
+            ));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// Parse `chaos` in the config file
+pub fn deserialize_chaos<'de, D>(deserializer: D) -> Result<Option<Chaos>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|spec| spec.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chaos() {
+        let chaos: Chaos = "latency=200ms,error-rate=0.05".parse().unwrap();
+        assert_eq!(
+            chaos,
+            Chaos {
+                latency: Some((Duration::from_millis(200), Duration::from_millis(200))),
+                error_rate: 0.05,
+                error_status: 503,
+            }
+        );
+        assert_eq!(chaos.to_string(), "200ms latency, 5% errors (503)");
+
+        let chaos: Chaos = "latency=100ms..1s, error-rate=10%, error-status=500"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            chaos.latency,
+            Some((Duration::from_millis(100), Duration::from_secs(1)))
+        );
+        assert_eq!((chaos.error_rate, chaos.error_status), (0.1, 500));
+
+        for invalid in [
+            "",
+            "latency",
+            "latency=soon",
+            "latency=1s..100ms",
+            "error-rate=1.5",
+            "error-status=200",
+            "drop-rate=0.1",
+        ] {
+            assert!(invalid.parse::<Chaos>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_injection() {
+        let chaos: Chaos = "latency=10ms..20ms,error-rate=1".parse().unwrap();
+        for _ in 0..20 {
+            let delay = chaos.delay();
+            assert!((Duration::from_millis(10)..=Duration::from_millis(20)).contains(&delay));
+            assert!(chaos.fails());
+        }
+        let chaos: Chaos = "latency=10ms".parse().unwrap();
+        assert_eq!(chaos.delay(), Duration::from_millis(10));
+        assert!(!chaos.fails());
+    }
+}
@@ -0,0 +1,507 @@
+//! Hot-reloadable forwarder settings
+//!
+//! With `--config <FILE>`, settings that only affect how requests are forwarded
+//! to the local service are read from a TOML file and re-applied whenever the
+//! file changes (or on SIGHUP), without dropping the WebSocket connection or
+//! changing the tunnel ID. Keys missing from the file fall back to the CLI flags.
+//!
+//! ```toml
+//! backend = "http://127.0.0.1:4000"
+//! request_timeout = "10s"
+//! spill_threshold = 16777216
+//! max_response_size = 1048576
+//! oversize_response = "truncate"
+//! require_headers = ["X-Demo-Key: secret"]
+//! block_headers = ["User-Agent: BadBot"]
+//! reject_status = 404
+//! retry_local = true
+//! local_insecure = true
+//! host_header = "custom:myapp.test"
+//! request_headers = ["X-Env: staging"]
+//! remove_response_headers = ["server"]
+//! mirror = "http://127.0.0.1:9000"
+//! chaos = "latency=200ms,error-rate=0.05"
+//! ```
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use crate::body::OversizeResponse;
+use crate::chaos::{Chaos, deserialize_chaos};
+use crate::duration::parse_duration;
+use crate::filter::{HeaderRule, RequestFilter};
+use crate::headers::{HeaderEdits, HostHeader, RemoveHeader, SetHeader};
+use crate::webhook::{WebhookProvider, WebhookVerifier};
+
+/// How often the config file's modification time is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Settings used when forwarding each request to the local service
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardSettings {
+    /// Local service address (e.g., "http://127.0.0.1:3000")
+    pub local_address: String,
+
+    /// Request timeout when calling the local service
+    pub request_timeout: Duration,
+
+    /// Response size above which bodies are spilled to a temporary file
+    pub spill_threshold: usize,
+
+    /// Largest response body forwarded through the tunnel
+    pub max_response_size: Option<usize>,
+
+    /// What to do with responses over `max_response_size`
+    pub oversize_response: OversizeResponse,
+
+    /// Header rules checked before forwarding
+    pub filter: RequestFilter,
+
+    /// Keep retrying a local service that refuses connections until the deadline
+    pub retry_local: bool,
+
+    /// Accept invalid (e.g. self-signed) certificates from an HTTPS local service
+    pub local_insecure: bool,
+
+    /// Server name for TLS to the local service, instead of the address's host
+    pub local_tls_name: Option<String>,
+
+    /// Unix domain socket the local service listens on, instead of a TCP port
+    pub unix_socket: Option<PathBuf>,
+
+    /// What the local service gets in the `Host` header
+    pub host_header: HostHeader,
+
+    /// Headers set and removed on requests to the local service
+    pub request_headers: HeaderEdits,
+
+    /// Headers set and removed on the local service's responses
+    pub response_headers: HeaderEdits,
+
+    /// Signature check for webhook requests
+    pub webhook: Option<WebhookVerifier>,
+
+    /// Service every request is copied to, without waiting for its response
+    pub mirror: Option<String>,
+
+    /// Latency and failures injected into requests
+    pub chaos: Option<Chaos>,
+}
+
+impl ForwardSettings {
+    /// The same settings, forwarding to another upstream of the service
+    pub fn with_local_address(&self, local_address: &str) -> Self {
+        Self {
+            local_address: local_address.to_string(),
+            ..self.clone()
+        }
+    }
+}
+
+/// Contents of the `--config` file
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// Local service base URL, overriding `--host` and `--port`
+    pub backend: Option<String>,
+
+    /// Request timeout (e.g. `10s`), overriding `--request-timeout`
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub request_timeout: Option<Duration>,
+
+    /// Spill threshold in bytes, overriding `--spill-threshold`
+    pub spill_threshold: Option<usize>,
+
+    /// Response size limit in bytes, overriding `--max-response-size`
+    pub max_response_size: Option<usize>,
+
+    /// `reject` or `truncate`, overriding `--oversize-response`
+    pub oversize_response: Option<OversizeResponse>,
+
+    /// Required headers, replacing `--require-header`
+    pub require_headers: Option<Vec<HeaderRule>>,
+
+    /// Blocked headers, replacing `--block-header`
+    pub block_headers: Option<Vec<HeaderRule>>,
+
+    /// Status for filtered requests, overriding `--reject-status`
+    pub reject_status: Option<u16>,
+
+    /// Body for filtered requests, overriding `--reject-body`
+    pub reject_body: Option<String>,
+
+    /// Retry unreachable local services, overriding `--retry-local`
+    pub retry_local: Option<bool>,
+
+    /// Accept invalid local certificates, overriding `--local-insecure`
+    pub local_insecure: Option<bool>,
+
+    /// TLS server name of the local service, overriding `--local-sni`
+    pub local_sni: Option<String>,
+
+    /// Local service socket, overriding `--unix-socket`
+    pub unix_socket: Option<PathBuf>,
+
+    /// `rewrite`, `preserve` or `custom:<value>`, overriding `--host-header`
+    pub host_header: Option<HostHeader>,
+
+    /// Headers set on requests, replacing `--request-header`
+    pub request_headers: Option<Vec<SetHeader>>,
+
+    /// Headers removed from requests, replacing `--remove-request-header`
+    pub remove_request_headers: Option<Vec<RemoveHeader>>,
+
+    /// Headers set on responses, replacing `--response-header`
+    pub response_headers: Option<Vec<SetHeader>>,
+
+    /// Headers removed from responses, replacing `--remove-response-header`
+    pub remove_response_headers: Option<Vec<RemoveHeader>>,
+
+    /// Webhook signer, overriding `--verify-webhook` (needs `webhook_secret`)
+    pub verify_webhook: Option<WebhookProvider>,
+
+    /// Signing secret for `verify_webhook`
+    pub webhook_secret: Option<String>,
+
+    /// Mirror base URL, overriding `--mirror`
+    pub mirror: Option<String>,
+
+    /// Chaos spec (e.g. `latency=200ms,error-rate=0.05`), overriding `--chaos`
+    #[serde(deserialize_with = "deserialize_chaos")]
+    pub chaos: Option<Chaos>,
+}
+
+pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_duration(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl ConfigFile {
+    /// Parse and validate config file contents
+    pub fn parse(contents: &str) -> Result<Self> {
+        let file: Self = toml::from_str(contents)?;
+
+        if let Some(backend) = &file.backend {
+            let url = url::Url::parse(backend)
+                .with_context(|| format!("invalid backend URL `{}`", backend))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                bail!("backend URL must use http or https: `{}`", backend);
+            }
+        }
+
+        if let Some(mirror) = &file.mirror {
+            crate::mirror::parse_mirror_url(mirror).map_err(anyhow::Error::msg)?;
+        }
+
+        if file.verify_webhook.is_some() != file.webhook_secret.is_some() {
+            bail!("verify_webhook and webhook_secret must be set together");
+        }
+
+        if let Some(status) = file.reject_status {
+            crate::filter::parse_reject_status(&status.to_string()).map_err(anyhow::Error::msg)?;
+        }
+
+        Ok(file)
+    }
+
+    /// Read and parse the config file at `path`
+    pub async fn load(path: &Path) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Overlay the file's settings on the ones given on the command line
+    pub fn apply(&self, base: &ForwardSettings) -> ForwardSettings {
+        ForwardSettings {
+            local_address: self
+                .backend
+                .as_deref()
+                .map(|backend| backend.trim_end_matches('/').to_string())
+                .unwrap_or_else(|| base.local_address.clone()),
+            request_timeout: self.request_timeout.unwrap_or(base.request_timeout),
+            spill_threshold: self.spill_threshold.unwrap_or(base.spill_threshold),
+            max_response_size: self.max_response_size.or(base.max_response_size),
+            oversize_response: self.oversize_response.unwrap_or(base.oversize_response),
+            filter: RequestFilter {
+                require: self
+                    .require_headers
+                    .clone()
+                    .unwrap_or_else(|| base.filter.require.clone()),
+                block: self
+                    .block_headers
+                    .clone()
+                    .unwrap_or_else(|| base.filter.block.clone()),
+                reject_status: self.reject_status.unwrap_or(base.filter.reject_status),
+                reject_body: self
+                    .reject_body
+                    .clone()
+                    .unwrap_or_else(|| base.filter.reject_body.clone()),
+            },
+            retry_local: self.retry_local.unwrap_or(base.retry_local),
+            local_insecure: self.local_insecure.unwrap_or(base.local_insecure),
+            local_tls_name: self
+                .local_sni
+                .clone()
+                .or_else(|| base.local_tls_name.clone()),
+            unix_socket: self
+                .unix_socket
+                .clone()
+                .or_else(|| base.unix_socket.clone()),
+            host_header: self
+                .host_header
+                .clone()
+                .unwrap_or_else(|| base.host_header.clone()),
+            request_headers: HeaderEdits {
+                set: self
+                    .request_headers
+                    .clone()
+                    .unwrap_or_else(|| base.request_headers.set.clone()),
+                remove: self
+                    .remove_request_headers
+                    .clone()
+                    .unwrap_or_else(|| base.request_headers.remove.clone()),
+            },
+            response_headers: HeaderEdits {
+                set: self
+                    .response_headers
+                    .clone()
+                    .unwrap_or_else(|| base.response_headers.set.clone()),
+                remove: self
+                    .remove_response_headers
+                    .clone()
+                    .unwrap_or_else(|| base.response_headers.remove.clone()),
+            },
+            webhook: self
+                .verify_webhook
+                .zip(self.webhook_secret.clone())
+                .map(|(provider, secret)| WebhookVerifier::new(provider, secret))
+                .or_else(|| base.webhook.clone()),
+            mirror: self
+                .mirror
+                .as_deref()
+                .map(|mirror| mirror.trim_end_matches('/').to_string())
+                .or_else(|| base.mirror.clone()),
+            chaos: self.chaos.clone().or_else(|| base.chaos.clone()),
+        }
+    }
+}
+
+/// Re-apply the config file whenever it changes or SIGHUP is received
+///
+/// Invalid files are reported and ignored, keeping the last good settings.
+pub async fn watch_config_file(
+    path: PathBuf,
+    base: ForwardSettings,
+    settings: watch::Sender<Arc<ForwardSettings>>,
+) {
+    let mut last_modified = modified_time(&path).await;
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let forced = tokio::select! {
+            _ = poll.tick() => false,
+            Some(()) = async {
+                match hangup.as_mut() {
+                    Some(signal) => signal.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => true,
+        };
+        #[cfg(not(unix))]
+        let forced = {
+            poll.tick().await;
+            false
+        };
+
+        let modified = modified_time(&path).await;
+        if !forced && modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        if forced {
+            info!("Received SIGHUP, reloading {}", path.display());
+        }
+
+        match ConfigFile::load(&path).await {
+            Ok(file) => {
+                let updated = file.apply(&base);
+                if **settings.borrow() == updated {
+                    debug!("Config file reloaded, no changes");
+                    continue;
+                }
+                info!(
+                    "Reloaded {}: forwarding to {} (timeout {:?}, spill threshold {} bytes)",
+                    path.display(),
+                    updated.local_address,
+                    updated.request_timeout,
+                    updated.spill_threshold
+                );
+                settings.send_replace(Arc::new(updated));
+            }
+            Err(e) => warn!("Keeping previous settings: {:#}", e),
+        }
+    }
+}
+
+async fn modified_time(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> ForwardSettings {
+        ForwardSettings {
+            local_address: "http://127.0.0.1:3000".to_string(),
+            request_timeout: Duration::from_secs(25),
+            spill_threshold: 1024,
+            max_response_size: None,
+            oversize_response: OversizeResponse::Reject,
+            filter: RequestFilter::default(),
+            retry_local: false,
+            local_insecure: false,
+            local_tls_name: None,
+            unix_socket: None,
+            host_header: HostHeader::Rewrite,
+            request_headers: HeaderEdits::default(),
+            response_headers: HeaderEdits::default(),
+            webhook: None,
+            mirror: None,
+            chaos: None,
+        }
+    }
+
+    #[test]
+    fn test_empty_file_keeps_cli_settings() {
+        let file = ConfigFile::parse("").unwrap();
+        assert_eq!(file.apply(&base()), base());
+    }
+
+    #[test]
+    fn test_file_overrides_cli_settings() {
+        let file = ConfigFile::parse(
+            r#"
+            backend = "http://localhost:4000/"
+            request_timeout = "10s"
+            max_response_size = 4096
+            oversize_response = "truncate"
+            block_headers = ["User-Agent: BadBot"]
+            reject_status = 404
+            retry_local = true
+            local_sni = "myapp.test"
+            host_header = "custom:myapp.test"
+            request_headers = ["X-Env: staging"]
+            remove_response_headers = ["server"]
+            mirror = "http://127.0.0.1:9000/"
+            chaos = "error-rate=0.5"
+            "#,
+        )
+        .unwrap();
+
+        let settings = file.apply(&base());
+        assert_eq!(settings.local_address, "http://localhost:4000");
+        assert_eq!(settings.request_timeout, Duration::from_secs(10));
+        assert_eq!(settings.spill_threshold, 1024);
+        assert_eq!(settings.max_response_size, Some(4096));
+        assert_eq!(settings.oversize_response, OversizeResponse::Truncate);
+        assert_eq!(
+            settings.filter.block,
+            vec!["User-Agent: BadBot".parse().unwrap()]
+        );
+        assert_eq!(settings.filter.reject_status, 404);
+        assert_eq!(settings.filter.reject_body, "Forbidden");
+        assert!(settings.retry_local);
+        assert!(!settings.local_insecure);
+        assert_eq!(settings.local_tls_name.as_deref(), Some("myapp.test"));
+        assert_eq!(
+            settings.host_header,
+            HostHeader::Custom("myapp.test".to_string())
+        );
+        assert_eq!(
+            settings.request_headers.set,
+            vec!["X-Env: staging".parse().unwrap()]
+        );
+        assert_eq!(
+            settings.response_headers.remove,
+            vec!["server".parse().unwrap()]
+        );
+        assert_eq!(settings.mirror.as_deref(), Some("http://127.0.0.1:9000"));
+        assert_eq!(settings.chaos.map(|chaos| chaos.error_rate), Some(0.5));
+    }
+
+    #[test]
+    fn test_invalid_files_rejected() {
+        assert!(ConfigFile::parse(r#"backend = "localhost:4000""#).is_err());
+        assert!(ConfigFile::parse(r#"backend = "ftp://localhost""#).is_err());
+        assert!(ConfigFile::parse(r#"request_timeout = "soon""#).is_err());
+        assert!(ConfigFile::parse(r#"unknown_key = 1"#).is_err());
+        assert!(ConfigFile::parse(r#"oversize_response = "drop""#).is_err());
+        assert!(ConfigFile::parse(r#"block_headers = ["bad header"]"#).is_err());
+        assert!(ConfigFile::parse(r#"reject_status = 200"#).is_err());
+        assert!(ConfigFile::parse(r#"host_header = "keep""#).is_err());
+        assert!(ConfigFile::parse(r#"request_headers = ["X-Env"]"#).is_err());
+        assert!(ConfigFile::parse(r#"verify_webhook = "github""#).is_err());
+        assert!(ConfigFile::parse(r#"mirror = "localhost:9000""#).is_err());
+        assert!(ConfigFile::parse(r#"chaos = "error-rate=2""#).is_err());
+        assert!(
+            ConfigFile::parse(
+                r#"
+                verify_webhook = "gitlab"
+                webhook_secret = "s3cret"
+                "#
+            )
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_applies_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ttf.toml");
+        tokio::fs::write(&path, "").await.unwrap();
+
+        let (tx, mut rx) = watch::channel(Arc::new(base()));
+        let handle = tokio::spawn(watch_config_file(path.clone(), base(), tx));
+
+        // Make sure the rewrite gets a different modification time
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, r#"backend = "http://localhost:4000""#).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(10), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow().local_address, "http://localhost:4000");
+
+        handle.abort();
+    }
+}
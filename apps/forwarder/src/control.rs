@@ -0,0 +1,315 @@
+//! Local control API (`ttf --control-port`)
+//!
+//! A small JSON API on localhost for scripts and process supervisors:
+//!
+//! - `GET /status` - process ID, uptime, and the connection state, public URL,
+//!   tunnel ID, request counts and recent errors of every tunnel
+//! - `POST /reconnect` - drop the WebSocket connections and connect again
+//!   (`?tunnel=NAME` for one `--map` tunnel)
+//! - `POST /shutdown` - stop the forwarder as Ctrl-C would
+//! - `POST /har` - write the `--har-out` file with the requests recorded so far
+//!
+//! It's served by [`crate::local_http`] for local clients only: requests must be
+//! addressed to `127.0.0.1:<port>` or `localhost:<port>`, and POSTs from a
+//! browser page on another origin are refused, so websites can't drive the API.
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tokio::sync::{Mutex, Notify};
+use tracing::info;
+
+use crate::ConnectionState;
+use crate::har::HarRecorder;
+use crate::local_http::{self, Access, Request, Response};
+use crate::stats::SessionStats;
+
+/// What the control API can see and do for one tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelHandle {
+    /// `--map` name, if any
+    pub(crate) name: Option<String>,
+    pub(crate) local_address: String,
+    pub(crate) state: Arc<Mutex<ConnectionState>>,
+    pub(crate) stats: Arc<SessionStats>,
+    /// Wakes the tunnel's connection manager to reconnect
+    pub(crate) reconnect: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct Control {
+    started: Instant,
+    tunnels: Vec<TunnelHandle>,
+    /// Recorder of the `--har-out` file, if enabled
+    har: Option<HarRecorder>,
+    shutdown: Arc<Notify>,
+}
+
+/// Start the control API on `port` on localhost
+///
+/// `shutdown` is notified when a client asks the forwarder to stop.
+pub async fn spawn_control_server(
+    port: u16,
+    tunnels: Vec<TunnelHandle>,
+    har: Option<HarRecorder>,
+    shutdown: Arc<Notify>,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to bind control API to port {}", port))?;
+    let control = Arc::new(Control {
+        started: Instant::now(),
+        tunnels,
+        har,
+        shutdown,
+    });
+
+    local_http::serve(listener, "Control API", Access::LocalOnly, move |request| {
+        let control = control.clone();
+        async move {
+            let (status, body) = route(&request, &control).await;
+            Response::json(status, body)
+        }
+    })
+}
+
+/// Dispatch a request to the control routes
+async fn route(request: &Request, control: &Control) -> (u16, Value) {
+    match (request.method.as_str(), request.path.trim_end_matches('/')) {
+        ("GET", "/status") => (200, status(control).await),
+        ("POST", "/reconnect") => {
+            let name = request.query.as_deref().and_then(|query| {
+                url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "tunnel")
+                    .map(|(_, value)| value.into_owned())
+            });
+            let tunnels: Vec<&TunnelHandle> = control
+                .tunnels
+                .iter()
+                .filter(|tunnel| name.is_none() || tunnel.name == name)
+                .collect();
+            if tunnels.is_empty() {
+                return (404, json!({ "error": "Tunnel not found" }));
+            }
+
+            info!("Reconnect requested through the control API");
+            for tunnel in &tunnels {
+                tunnel.reconnect.notify_waiters();
+            }
+            (202, json!({ "reconnecting": tunnels.len() }))
+        }
+        ("POST", "/shutdown") => {
+            info!("Shutdown requested through the control API");
+            control.shutdown.notify_one();
+            (202, json!({ "shutting_down": true }))
+        }
+        ("POST", "/har") => {
+            let Some(har) = &control.har else {
+                return (
+                    404,
+                    json!({ "error": "HAR recording is off (start with --har-out)" }),
+                );
+            };
+            match har.save().await {
+                Ok(count) => {
+                    info!("Saved {} requests to {}", count, har.path().display());
+                    (200, json!({ "path": har.path(), "requests": count }))
+                }
+                Err(e) => (500, json!({ "error": format!("{:#}", e) })),
+            }
+        }
+        (_, "/status" | "/reconnect" | "/shutdown" | "/har") => {
+            (405, json!({ "error": "Method not allowed" }))
+        }
+        _ => (404, json!({ "error": "Not found" })),
+    }
+}
+
+/// State of the forwarder and each of its tunnels
+async fn status(control: &Control) -> Value {
+    let mut tunnels = Vec::with_capacity(control.tunnels.len());
+    for tunnel in &control.tunnels {
+        let summary = tunnel.stats.summary();
+        let mut entry = connection_json(&*tunnel.state.lock().await);
+        entry["name"] = json!(tunnel.name);
+        entry["local_address"] = json!(tunnel.local_address);
+        entry["requests"] = json!(summary.requests);
+        entry["failures"] = json!(summary.failures);
+        entry["status_codes"] = json!(summary.statuses);
+        entry["reconnects"] = json!(summary.reconnects);
+        entry["recent_errors"] = json!(tunnel.stats.recent_errors());
+        tunnels.push(entry);
+    }
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "pid": std::process::id(),
+        "uptime_secs": control.started.elapsed().as_secs(),
+        "tunnels": tunnels,
+    })
+}
+
+fn connection_json(state: &ConnectionState) -> Value {
+    match state {
+        ConnectionState::Disconnected => json!({ "state": "disconnected" }),
+        ConnectionState::Connecting => json!({ "state": "connecting" }),
+        ConnectionState::Connected {
+            connection_id,
+            tunnel_id,
+            public_url,
+        } => json!({
+            "state": "connected",
+            "connection_id": connection_id,
+            "tunnel_id": tunnel_id,
+            "public_url": public_url,
+        }),
+        ConnectionState::Reconnecting {
+            attempt,
+            next_delay,
+        } => json!({
+            "state": "reconnecting",
+            "attempt": attempt,
+            "next_delay_ms": next_delay.as_millis() as u64,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, query: Option<&str>) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.map(str::to_string),
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn control() -> Control {
+        let stats = Arc::new(SessionStats::new());
+        stats.record_response("/", 200, 0, 2);
+        stats.record_error("Local service error: connection refused");
+        Control {
+            started: Instant::now(),
+            tunnels: vec![TunnelHandle {
+                name: Some("api".to_string()),
+                local_address: "http://127.0.0.1:3000".to_string(),
+                state: Arc::new(Mutex::new(ConnectionState::Connected {
+                    connection_id: "conn_1".to_string(),
+                    tunnel_id: "abc123def456".to_string(),
+                    public_url: "https://tunnel.example.com/abc123def456".to_string(),
+                })),
+                stats,
+                reconnect: Arc::new(Notify::new()),
+            }],
+            har: None,
+            shutdown: Arc::new(Notify::new()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status() {
+        let (status, body) = route(&request("GET", "/status", None), &control()).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["pid"], std::process::id());
+        let tunnel = &body["tunnels"][0];
+        assert_eq!(tunnel["state"], "connected");
+        assert_eq!(tunnel["tunnel_id"], "abc123def456");
+        assert_eq!(tunnel["name"], "api");
+        assert_eq!(tunnel["requests"], 1);
+        assert_eq!(tunnel["status_codes"]["200"], 1);
+        assert_eq!(
+            tunnel["recent_errors"][0]["message"],
+            "Local service error: connection refused"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_and_shutdown() {
+        let control = control();
+        let reconnect = control.tunnels[0].reconnect.clone();
+        let notified = reconnect.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let (status, _) = route(&request("POST", "/reconnect", None), &control).await;
+        assert_eq!(status, 202);
+        notified.await;
+
+        let unknown = request("POST", "/reconnect", Some("tunnel=web"));
+        assert_eq!(route(&unknown, &control).await.0, 404);
+
+        assert_eq!(
+            route(&request("GET", "/shutdown", None), &control).await.0,
+            405
+        );
+        assert_eq!(
+            route(&request("POST", "/shutdown", None), &control).await.0,
+            202
+        );
+        control.shutdown.notified().await;
+    }
+
+    #[tokio::test]
+    async fn test_rejects_other_hosts_and_origins() {
+        let control = control();
+        let shutdown = control.shutdown.clone();
+        let addr = spawn_control_server(0, control.tunnels, None, shutdown)
+            .await
+            .unwrap();
+        let client = reqwest::Client::new();
+        let url = |path: &str| format!("http://{}{}", addr, path);
+
+        let status = client.get(url("/status")).send().await.unwrap();
+        assert_eq!(status.status(), 200);
+
+        let rebound = client
+            .get(url("/status"))
+            .header("Host", format!("evil.example:{}", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rebound.status(), 403);
+
+        let forged = client
+            .post(url("/reconnect"))
+            .header("Origin", "https://evil.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(forged.status(), 403);
+
+        let same_origin = client
+            .post(url("/reconnect"))
+            .header("Origin", format!("http://localhost:{}", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(same_origin.status(), 202);
+    }
+
+    #[tokio::test]
+    async fn test_save_har() {
+        let save = request("POST", "/har", None);
+        let (status, _) = route(&save, &control()).await;
+        assert_eq!(status, 404);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.har");
+        let control = Control {
+            har: Some(HarRecorder::new(path.clone())),
+            ..control()
+        };
+        let (status, body) = route(&save, &control).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["requests"], 0);
+        assert!(path.exists());
+        assert_eq!(route(&request("GET", "/har", None), &control).await.0, 405);
+    }
+}
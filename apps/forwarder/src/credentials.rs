@@ -0,0 +1,55 @@
+//! Secrets kept in the OS keychain
+//!
+//! Entries live under the `ttf` service of the platform's credential store:
+//! the macOS Keychain, the Secret Service on Linux (GNOME Keyring, KWallet) and
+//! the Windows Credential Manager. The stores' APIs block, so every call runs
+//! on the blocking thread pool.
+
+use anyhow::{Context, Result};
+use keyring::Entry;
+
+/// Service name the entries are filed under
+const SERVICE: &str = "ttf";
+
+/// Entry holding the token saved by `ttf auth set`
+pub const TOKEN_ENTRY: &str = "token";
+
+/// Read the secret stored as `name`, if there is one
+pub async fn load(name: &'static str) -> Result<Option<String>> {
+    blocking(move || match entry(name)?.get_password() {
+        Ok(secret) => Ok(Some(secret)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read `{}` from the keychain", name)),
+    })
+    .await
+}
+
+/// Store `secret` as `name`, replacing any previous value
+pub async fn store(name: &'static str, secret: String) -> Result<()> {
+    blocking(move || {
+        entry(name)?
+            .set_password(&secret)
+            .with_context(|| format!("Failed to save `{}` to the keychain", name))
+    })
+    .await
+}
+
+/// Remove the secret stored as `name`, returning whether there was one
+pub async fn delete(name: &'static str) -> Result<bool> {
+    blocking(move || match entry(name)?.delete_credential() {
+        Ok(()) => Ok(true),
+        Err(keyring::Error::NoEntry) => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Failed to remove `{}` from the keychain", name)),
+    })
+    .await
+}
+
+fn entry(name: &str) -> Result<Entry> {
+    Entry::new(SERVICE, name).context("Failed to open the keychain")
+}
+
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("Keychain access panicked")?
+}
@@ -0,0 +1,272 @@
+//! Background mode (`ttf start --detach`, `ttf status`, `ttf stop`)
+//!
+//! `ttf start` runs the forwarder like plain `ttf`, and records its process ID
+//! and control API address in a pidfile, `$XDG_STATE_HOME/ttf/ttf.pid` (else
+//! `~/.local/state/ttf/ttf.pid`). The control API is always on for it, on a free
+//! port unless `--control-port` is given. With `--detach` it starts itself again
+//! without a terminal, logging to `ttf.log` next to the pidfile, and returns
+//! once the forwarder is up.
+//!
+//! `ttf status` and `ttf stop` find the forwarder through the pidfile and use
+//! its control API. A pidfile whose control API doesn't answer with the same
+//! process ID is left over from a forwarder that died, and is ignored.
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// How long `ttf start --detach` and `ttf stop` wait for the forwarder
+const WAIT_TIMEOUT: Duration = Duration::from_secs(15);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Contents of the pidfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pidfile {
+    pub pid: u32,
+    /// Address of the control API
+    pub control: SocketAddr,
+}
+
+/// Directory holding the pidfile and the background log
+pub fn state_dir() -> Option<PathBuf> {
+    let state_dir = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })?;
+    Some(state_dir.join("ttf"))
+}
+
+/// Default location of the pidfile
+pub fn default_pidfile_path() -> Result<PathBuf> {
+    state_dir()
+        .map(|dir| dir.join("ttf.pid"))
+        .context("Can't tell where to keep the pidfile; set HOME or XDG_STATE_HOME")
+}
+
+/// Record this process and its control API in the pidfile
+pub async fn write_pidfile(path: &Path, control: SocketAddr) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let pidfile = Pidfile {
+        pid: std::process::id(),
+        control,
+    };
+    tokio::fs::write(path, serde_json::to_vec(&pidfile)?)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// Remove the pidfile if it's still this process's
+pub async fn remove_pidfile(path: &Path) {
+    if let Ok(Some(pidfile)) = read_pidfile(path).await
+        && pidfile.pid == std::process::id()
+    {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+async fn read_pidfile(path: &Path) -> Result<Option<Pidfile>> {
+    match tokio::fs::read(path).await {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid pidfile {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// The forwarder recorded in the pidfile and its status, if it's running
+pub async fn running(path: &Path) -> Result<Option<(Pidfile, Value)>> {
+    let Some(pidfile) = read_pidfile(path).await? else {
+        return Ok(None);
+    };
+    match control_request(reqwest::Method::GET, pidfile.control, "/status").await {
+        // Another process may have the port by now
+        Ok(status) if status["pid"] == pidfile.pid => Ok(Some((pidfile, status))),
+        _ => Ok(None),
+    }
+}
+
+async fn control_request(
+    method: reqwest::Method,
+    control: SocketAddr,
+    path: &str,
+) -> Result<Value> {
+    let response = reqwest::Client::new()
+        .request(method, format!("http://{}{}", control, path))
+        .timeout(Duration::from_secs(5))
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// Start this command again in the background and wait until it's running
+pub async fn detach(pidfile_path: &Path) -> Result<()> {
+    if let Some((pidfile, _)) = running(pidfile_path).await? {
+        bail!("ttf is already running (pid {})", pidfile.pid);
+    }
+    let log_path = pidfile_path.with_file_name("ttf.log");
+    if let Some(dir) = log_path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("Failed to open {}", log_path.display()))?;
+
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1).filter(|arg| arg != "--detach"))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    // Out of the terminal's process group, so its Ctrl-C and hangup don't reach it
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    #[cfg(windows)]
+    {
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        std::os::windows::process::CommandExt::creation_flags(&mut command, DETACHED_PROCESS);
+    }
+    let mut child = command
+        .spawn()
+        .context("Failed to start ttf in the background")?;
+
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "ttf exited ({}) while starting; see {}",
+                status,
+                log_path.display()
+            );
+        }
+        if let Ok(Some(pidfile)) = read_pidfile(pidfile_path).await
+            && pidfile.pid == child.id()
+        {
+            println!(
+                "ttf is running in the background (pid {}); logs go to {}",
+                child.id(),
+                log_path.display()
+            );
+            println!("`ttf status` shows the tunnel, `ttf stop` stops it");
+            return Ok(());
+        }
+        if started.elapsed() > WAIT_TIMEOUT {
+            bail!(
+                "ttf (pid {}) is still starting; see {}",
+                child.id(),
+                log_path.display()
+            );
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Print the state of the background forwarder
+pub async fn status(pidfile_path: &Path) -> Result<()> {
+    let Some((pidfile, status)) = running(pidfile_path).await? else {
+        bail!("ttf isn't running");
+    };
+    let uptime = Duration::from_secs(status["uptime_secs"].as_u64().unwrap_or_default());
+    println!(
+        "ttf is running (pid {}, up {}, control API http://{})",
+        pidfile.pid,
+        humantime::format_duration(uptime),
+        pidfile.control
+    );
+    for tunnel in status["tunnels"].as_array().into_iter().flatten() {
+        let name = tunnel["name"]
+            .as_str()
+            .map(|name| format!("[{}] ", name))
+            .unwrap_or_default();
+        let state = tunnel["state"].as_str().unwrap_or("unknown");
+        let local = tunnel["local_address"].as_str().unwrap_or_default();
+        match tunnel["public_url"].as_str() {
+            Some(url) => println!("  {}{}: {} -> {}", name, state, url, local),
+            None => println!("  {}{}: -> {}", name, state, local),
+        }
+        println!(
+            "    {} requests, {} failed, {} reconnects",
+            tunnel["requests"], tunnel["failures"], tunnel["reconnects"]
+        );
+    }
+    Ok(())
+}
+
+/// Ask the background forwarder to stop, and wait until it has
+pub async fn stop(pidfile_path: &Path) -> Result<()> {
+    let Some((pidfile, _)) = running(pidfile_path).await? else {
+        // Don't leave a stale pidfile behind
+        let _ = tokio::fs::remove_file(pidfile_path).await;
+        bail!("ttf isn't running");
+    };
+    control_request(reqwest::Method::POST, pidfile.control, "/shutdown")
+        .await
+        .context("Failed to ask ttf to stop")?;
+
+    let started = Instant::now();
+    while running(pidfile_path).await?.is_some() {
+        if started.elapsed() > WAIT_TIMEOUT {
+            bail!("ttf (pid {}) didn't stop in time", pidfile.pid);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    println!("Stopped ttf (pid {})", pidfile.pid);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::spawn_control_server;
+    use std::sync::Arc;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_running_through_pidfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ttf.pid");
+        assert!(running(&path).await.unwrap().is_none());
+
+        let control = spawn_control_server(0, Vec::new(), None, Arc::new(Notify::new()))
+            .await
+            .unwrap();
+        write_pidfile(&path, control).await.unwrap();
+        let (pidfile, status) = running(&path).await.unwrap().unwrap();
+        assert_eq!(pidfile.pid, std::process::id());
+        assert_eq!(status["pid"], std::process::id());
+
+        remove_pidfile(&path).await;
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_stale_pidfile_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ttf.pid");
+        // Nothing listens on the recorded control port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let control = listener.local_addr().unwrap();
+        drop(listener);
+        let stale = Pidfile { pid: 1, control };
+        std::fs::write(&path, serde_json::to_vec(&stale).unwrap()).unwrap();
+
+        assert!(running(&path).await.unwrap().is_none());
+        assert!(stop(&path).await.is_err());
+        assert!(!path.exists());
+    }
+}
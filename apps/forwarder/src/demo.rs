@@ -0,0 +1,246 @@
+//! Built-in demo server for `ttf demo`
+//!
+//! A deliberately tiny HTTP/1.1 server bound to an ephemeral localhost port, so a
+//! new deployment can be checked end to end without a local service of your own:
+//!
+//! - `/` - request viewer listing the most recent requests
+//! - `/echo` - echoes the request (any method) back as JSON
+//! - `/api/items`, `/api/items/{id}` - a small sample JSON API
+//!
+//! The HTTP handling is [`crate::local_http`]'s, open to any host since requests
+//! arrive through the tunnel.
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+
+use crate::local_http::{self, Access, Request, Response};
+
+/// Requests kept for the viewer page
+const MAX_RECORDED_REQUESTS: usize = 50;
+
+/// Bytes of each request body shown in the viewer
+const MAX_RECORDED_BODY_BYTES: usize = 4096;
+
+/// Recently received requests, newest first
+#[derive(Debug, Default, Clone)]
+pub struct RequestLog {
+    requests: Arc<Mutex<VecDeque<(u64, Request)>>>,
+}
+
+impl RequestLog {
+    fn record(&self, request: &Request) {
+        let mut request = request.clone();
+        if request.body.len() > MAX_RECORDED_BODY_BYTES {
+            let mut end = MAX_RECORDED_BODY_BYTES;
+            while !request.body.is_char_boundary(end) {
+                end -= 1;
+            }
+            request.body.truncate(end);
+            request.body.push('…');
+        }
+
+        let mut requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        requests.push_front((http_tunnel_common::current_timestamp_millis(), request));
+        requests.truncate(MAX_RECORDED_REQUESTS);
+    }
+
+    fn snapshot(&self) -> Vec<(u64, Request)> {
+        let requests = self.requests.lock().unwrap_or_else(|e| e.into_inner());
+        requests.iter().cloned().collect()
+    }
+}
+
+/// Start the demo server on an ephemeral localhost port
+pub async fn spawn_demo_server() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .context("Failed to bind demo server")?;
+    let log = RequestLog::default();
+
+    local_http::serve(listener, "Demo server", Access::Anyone, move |request| {
+        if request.path != "/" {
+            log.record(&request);
+        }
+        std::future::ready(route(&request, &log))
+    })
+}
+
+/// Dispatch a request to the demo routes
+fn route(request: &Request, log: &RequestLog) -> Response {
+    let path = request.path.trim_end_matches('/');
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [""]) => Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: viewer_page(&log.snapshot()),
+        },
+        (_, ["echo", ..]) => Response::json(200, json!(request)),
+        ("GET", ["api", "items"]) => Response::json(200, json!(sample_items())),
+        ("GET", ["api", "items", id]) => sample_items()
+            .into_iter()
+            .find(|item| id.parse::<u64>().is_ok_and(|id| item["id"] == id))
+            .map(|item| Response::json(200, item))
+            .unwrap_or_else(|| Response::json(404, json!({ "error": "Item not found" }))),
+        ("POST", ["api", "items"]) => match serde_json::from_str::<Value>(&request.body) {
+            Ok(Value::Object(mut item)) => {
+                item.insert("id".to_string(), json!(sample_items().len() + 1));
+                Response::json(201, Value::Object(item))
+            }
+            _ => Response::json(400, json!({ "error": "Expected a JSON object" })),
+        },
+        _ => Response::json(404, json!({ "error": "Not found" })),
+    }
+}
+
+fn sample_items() -> Vec<Value> {
+    vec![
+        json!({ "id": 1, "name": "Keyboard", "price": 49.0 }),
+        json!({ "id": 2, "name": "Mouse", "price": 19.0 }),
+        json!({ "id": 3, "name": "Monitor", "price": 199.0 }),
+    ]
+}
+
+/// Render the request viewer (refreshes itself every few seconds)
+fn viewer_page(requests: &[(u64, Request)]) -> String {
+    let rows: String = if requests.is_empty() {
+        "<tr><td colspan=\"4\">No requests yet. Try <a href=\"echo\">echo</a> or \
+         <a href=\"api/items\">api/items</a>.</td></tr>"
+            .to_string()
+    } else {
+        requests
+            .iter()
+            .map(|(timestamp, request)| {
+                let target = match &request.query {
+                    Some(query) => format!("{}?{}", request.path, query),
+                    None => request.path.clone(),
+                };
+                let headers: String = request
+                    .headers
+                    .iter()
+                    .map(|(name, value)| format!("{}: {}\n", name, value))
+                    .collect();
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td><pre>{}\n{}</pre></td></tr>",
+                    timestamp,
+                    escape_html(&request.method),
+                    escape_html(&target),
+                    escape_html(&headers),
+                    escape_html(&request.body)
+                )
+            })
+            .collect()
+    };
+
+    format!(
+        r#"<!doctype html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="5">
+<title>ttf demo</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; width: 100%; }}
+td, th {{ border: 1px solid #ddd; padding: 4px 8px; vertical-align: top; text-align: left; }}
+pre {{ margin: 0; white-space: pre-wrap; }}
+</style>
+</head>
+<body>
+<h1>Your tunnel works</h1>
+<p>Endpoints: <a href="echo">echo</a> (any method), <a href="api/items">api/items</a>,
+<a href="api/items/1">api/items/1</a>. Recent requests are listed below.</p>
+<table>
+<tr><th>Time (ms)</th><th>Method</th><th>Path</th><th>Headers and body</th></tr>
+{}
+</table>
+</body>
+</html>
+"#,
+        rows
+    )
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: vec![("Host".to_string(), "localhost".to_string())],
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_routes() {
+        let log = RequestLog::default();
+
+        let echo = route(&request("PUT", "/echo", "hello"), &log);
+        let body: Value = serde_json::from_str(&echo.body).unwrap();
+        assert_eq!(body["method"], "PUT");
+        assert_eq!(body["body"], "hello");
+
+        let item = route(&request("GET", "/api/items/2", ""), &log);
+        assert!(item.body.contains("Mouse"));
+        assert_eq!(route(&request("GET", "/api/items/9", ""), &log).status, 404);
+
+        let created = route(&request("POST", "/api/items", r#"{"name":"Desk"}"#), &log);
+        assert_eq!(created.status, 201);
+        assert_eq!(
+            route(&request("POST", "/api/items", "nope"), &log).status,
+            400
+        );
+
+        assert_eq!(route(&request("GET", "/missing", ""), &log).status, 404);
+    }
+
+    #[test]
+    fn test_viewer_lists_recent_requests() {
+        let log = RequestLog::default();
+        for i in 0..(MAX_RECORDED_REQUESTS + 5) {
+            log.record(&request("GET", &format!("/echo/{}", i), ""));
+        }
+        log.record(&request("POST", "/echo", "<script>"));
+
+        let snapshot = log.snapshot();
+        assert_eq!(snapshot.len(), MAX_RECORDED_REQUESTS);
+        assert_eq!(snapshot[0].1.method, "POST");
+
+        let page = route(&request("GET", "/", ""), &log);
+        assert_eq!(page.content_type, "text/html; charset=utf-8");
+        assert!(page.body.contains("&lt;script&gt;"));
+        assert!(!page.body.contains("<script>"));
+    }
+
+    #[tokio::test]
+    async fn test_demo_server_round_trip() {
+        let addr = spawn_demo_server().await.unwrap();
+        let response = reqwest::Client::new()
+            .post(format!("http://{}/echo", addr))
+            .body("ping")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["body"], "ping");
+    }
+}
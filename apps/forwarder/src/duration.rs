@@ -0,0 +1,52 @@
+//! Duration parsing for CLI arguments
+//!
+//! Durations accept humantime syntax (`500ms`, `30s`, `2m`, `1h 30m`). A bare
+//! integer is still read as seconds so existing scripts keep working.
+
+use std::time::Duration;
+
+/// Parse a non-zero duration from a CLI argument
+///
+/// Errors are returned as plain strings; clap prefixes them with the offending flag.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+
+    let duration = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => humantime::parse_duration(value)
+            .map_err(|e| format!("{e} (expected e.g. `500ms`, `30s` or `2m`)"))?,
+    };
+
+    if duration.is_zero() {
+        return Err("duration must be greater than zero".to_string());
+    }
+
+    Ok(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration_humantime() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1m 30s"), Ok(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn test_parse_duration_bare_seconds() {
+        assert_eq!(parse_duration("25"), Ok(Duration::from_secs(25)));
+    }
+
+    #[test]
+    fn test_parse_duration_rejects_invalid() {
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-5s").is_err());
+    }
+}
@@ -0,0 +1,140 @@
+//! Exit codes, so supervisors (systemd, CI) can tell why `ttf` stopped
+//!
+//! | Code | Meaning                                                             |
+//! |------|---------------------------------------------------------------------|
+//! | 0    | Stopped on request (Ctrl-C, `POST /shutdown`)                       |
+//! | 1    | Any other error                                                     |
+//! | 2    | Invalid command line                                                |
+//! | 3    | Authentication failed: the token was refused and can't be refreshed |
+//! | 4    | Gave up reconnecting after the handshake timed out                  |
+//! | 5    | The local service is misconfigured                                  |
+//! | 6    | The server rejected the tunnel (revoked, tunnel ID unavailable)     |
+//! | 7    | Gave up reconnecting (`--max-reconnect-attempts`) for other reasons |
+//! | 8    | The transfer quota (`--max-transfer`) is used up                    |
+
+use http_tunnel_common::TunnelError;
+use std::fmt;
+
+pub const EXIT_ERROR: u8 = 1;
+pub const EXIT_AUTH_FAILED: u8 = 3;
+pub const EXIT_HANDSHAKE_TIMEOUT: u8 = 4;
+pub const EXIT_LOCAL_SERVICE: u8 = 5;
+pub const EXIT_REJECTED: u8 = 6;
+pub const EXIT_GAVE_UP: u8 = 7;
+pub const EXIT_QUOTA_EXCEEDED: u8 = 8;
+
+/// The local service options can't work, e.g. an unreadable `--config`
+#[derive(Debug)]
+pub struct LocalServiceError(pub String);
+
+impl fmt::Display for LocalServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LocalServiceError {}
+
+/// Reconnecting stopped after `--max-reconnect-attempts` failed attempts
+#[derive(Debug)]
+pub struct GaveUp {
+    pub attempts: usize,
+    /// Why the last attempt failed
+    pub last_error: String,
+    /// Whether the last attempt timed out waiting for the handshake
+    pub handshake_timeout: bool,
+}
+
+impl fmt::Display for GaveUp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Gave up after {} reconnect attempts: {}",
+            self.attempts, self.last_error
+        )
+    }
+}
+
+impl std::error::Error for GaveUp {}
+
+/// The tunnel moved as much data as `--max-transfer` allows
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    /// The quota in bytes
+    pub quota: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transfer quota of {} used up (--max-transfer); stopping the tunnel",
+            crate::stats::format_bytes(self.quota)
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Exit code for the error `ttf` stopped with
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    if let Some(error) = error.downcast_ref::<TunnelError>() {
+        return match error {
+            TunnelError::AuthFailed(_) => EXIT_AUTH_FAILED,
+            TunnelError::Rejected(_) => EXIT_REJECTED,
+            _ => EXIT_ERROR,
+        };
+    }
+    if let Some(gave_up) = error.downcast_ref::<GaveUp>() {
+        return if gave_up.handshake_timeout {
+            EXIT_HANDSHAKE_TIMEOUT
+        } else {
+            EXIT_GAVE_UP
+        };
+    }
+    if error.downcast_ref::<LocalServiceError>().is_some() {
+        return EXIT_LOCAL_SERVICE;
+    }
+    if error.downcast_ref::<QuotaExceeded>().is_some() {
+        return EXIT_QUOTA_EXCEEDED;
+    }
+    EXIT_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes() {
+        let code = |error: anyhow::Error| exit_code(&error);
+        assert_eq!(
+            code(TunnelError::AuthFailed("expired".to_string()).into()),
+            EXIT_AUTH_FAILED
+        );
+        assert_eq!(
+            code(TunnelError::Rejected("revoked".to_string()).into()),
+            EXIT_REJECTED
+        );
+        assert_eq!(
+            code(LocalServiceError("bad --config".to_string()).into()),
+            EXIT_LOCAL_SERVICE
+        );
+        let gave_up = |handshake_timeout| GaveUp {
+            attempts: 3,
+            last_error: "refused".to_string(),
+            handshake_timeout,
+        };
+        assert_eq!(code(gave_up(true).into()), EXIT_HANDSHAKE_TIMEOUT);
+        assert_eq!(code(gave_up(false).into()), EXIT_GAVE_UP);
+        assert_eq!(
+            code(QuotaExceeded { quota: 1 << 30 }.into()),
+            EXIT_QUOTA_EXCEEDED
+        );
+        assert_eq!(code(anyhow::anyhow!("port in use")), EXIT_ERROR);
+        assert_eq!(
+            code(TunnelError::ConnectionError("refused".to_string()).into()),
+            EXIT_ERROR
+        );
+    }
+}
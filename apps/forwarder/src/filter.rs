@@ -0,0 +1,233 @@
+//! Header-based request filtering
+//!
+//! Rules are checked before a request is forwarded to the local service. A
+//! request is rejected if it lacks a header from `--require-header` or carries
+//! one matched by `--block-header`; the rejection status and body are
+//! configurable, and the local service never sees the request.
+//!
+//! Rules are written as `Name` (header present) or `Name: value`. Required
+//! headers must match the value exactly; blocked headers match when the value
+//! appears anywhere in the header, ignoring case (e.g. `User-Agent: BadBot`).
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Default status returned for filtered requests
+pub const DEFAULT_REJECT_STATUS: u16 = 403;
+
+/// Default body returned for filtered requests
+pub const DEFAULT_REJECT_BODY: &str = "Forbidden";
+
+/// A header name with an optional value to match
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HeaderRule {
+    /// Lowercased header name
+    name: String,
+    value: Option<String>,
+}
+
+impl FromStr for HeaderRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match rule.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+            None => (rule.trim(), None),
+        };
+
+        if name.is_empty() || reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!(
+                "invalid header rule `{}` (expected `Name` or `Name: value`)",
+                rule
+            ));
+        }
+
+        Ok(Self {
+            name: name.to_ascii_lowercase(),
+            value: value.filter(|value| !value.is_empty()),
+        })
+    }
+}
+
+impl TryFrom<String> for HeaderRule {
+    type Error = String;
+
+    fn try_from(rule: String) -> Result<Self, Self::Error> {
+        rule.parse()
+    }
+}
+
+impl fmt::Display for HeaderRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}: {}", self.name, value),
+            None => f.write_str(&self.name),
+        }
+    }
+}
+
+impl HeaderRule {
+    fn values<'a>(
+        &self,
+        headers: &'a HashMap<String, Vec<String>>,
+    ) -> impl Iterator<Item = &'a String> {
+        headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.name))
+            .flat_map(|(_, values)| values)
+    }
+
+    /// Header present with exactly the expected value (if any)
+    fn is_satisfied_by(&self, headers: &HashMap<String, Vec<String>>) -> bool {
+        let mut values = self.values(headers);
+        match &self.value {
+            Some(expected) => values.any(|value| value == expected),
+            None => values.next().is_some(),
+        }
+    }
+
+    /// Header present and containing the value (if any), ignoring case
+    fn is_matched_by(&self, headers: &HashMap<String, Vec<String>>) -> bool {
+        let mut values = self.values(headers);
+        match &self.value {
+            Some(needle) => {
+                let needle = needle.to_ascii_lowercase();
+                values.any(|value| value.to_ascii_lowercase().contains(&needle))
+            }
+            None => values.next().is_some(),
+        }
+    }
+}
+
+/// Parse an HTTP status code for rejected requests (4xx or 5xx)
+pub fn parse_reject_status(value: &str) -> Result<u16, String> {
+    let status: u16 = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid status code `{}`", value))?;
+    if !(400..=599).contains(&status) {
+        return Err("status code must be between 400 and 599".to_string());
+    }
+    Ok(status)
+}
+
+/// Header rules plus the response sent when they reject a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestFilter {
+    pub require: Vec<HeaderRule>,
+    pub block: Vec<HeaderRule>,
+    pub reject_status: u16,
+    pub reject_body: String,
+}
+
+impl Default for RequestFilter {
+    fn default() -> Self {
+        Self {
+            require: Vec::new(),
+            block: Vec::new(),
+            reject_status: DEFAULT_REJECT_STATUS,
+            reject_body: DEFAULT_REJECT_BODY.to_string(),
+        }
+    }
+}
+
+impl RequestFilter {
+    /// The rule that rejects a request with these headers, if any
+    pub fn rejecting_rule(&self, headers: &HashMap<String, Vec<String>>) -> Option<String> {
+        if let Some(rule) = self
+            .require
+            .iter()
+            .find(|rule| !rule.is_satisfied_by(headers))
+        {
+            return Some(format!("missing required header {}", rule.name));
+        }
+
+        self.block
+            .iter()
+            .find(|rule| rule.is_matched_by(headers))
+            .map(|rule| format!("blocked by rule `{}`", rule))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in pairs {
+            headers
+                .entry(name.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_header_rule() {
+        let rule: HeaderRule = "X-Demo-Key: secret".parse().unwrap();
+        assert_eq!(rule.to_string(), "x-demo-key: secret");
+
+        let rule: HeaderRule = "Authorization".parse().unwrap();
+        assert_eq!(rule.to_string(), "authorization");
+
+        assert!("".parse::<HeaderRule>().is_err());
+        assert!(": value".parse::<HeaderRule>().is_err());
+        assert!("bad header: x".parse::<HeaderRule>().is_err());
+    }
+
+    #[test]
+    fn test_required_headers() {
+        let filter = RequestFilter {
+            require: vec!["X-Demo-Key: secret".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter.rejecting_rule(&headers(&[("x-demo-key", "secret")])),
+            None
+        );
+        assert!(
+            filter
+                .rejecting_rule(&headers(&[("x-demo-key", "SECRET")]))
+                .is_some()
+        );
+        assert!(filter.rejecting_rule(&headers(&[])).is_some());
+    }
+
+    #[test]
+    fn test_blocked_headers() {
+        let filter = RequestFilter {
+            block: vec![
+                "User-Agent: badbot".parse().unwrap(),
+                "X-Debug".parse().unwrap(),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            filter.rejecting_rule(&headers(&[("user-agent", "curl/8.0")])),
+            None
+        );
+        assert_eq!(
+            filter.rejecting_rule(&headers(&[("User-Agent", "Mozilla BadBot/1.0")])),
+            Some("blocked by rule `user-agent: badbot`".to_string())
+        );
+        assert!(
+            filter
+                .rejecting_rule(&headers(&[("x-debug", "1")]))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_parse_reject_status() {
+        assert_eq!(parse_reject_status("404"), Ok(404));
+        assert!(parse_reject_status("200").is_err());
+        assert!(parse_reject_status("teapot").is_err());
+    }
+}
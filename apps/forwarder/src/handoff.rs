@@ -0,0 +1,150 @@
+//! Graceful connection handoff
+//!
+//! Shortly before API Gateway's connection lifetime runs out, the handler sends
+//! `ReconnectRequested` with a handoff token. The forwarder opens a new
+//! connection that presents the token to take over the tunnel, and only then
+//! lets the old connection finish the requests it is still handling and close.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tracing::{Instrument, debug, info, warn};
+
+use crate::websocket::WsSessions;
+
+/// How long a closing connection waits for the gateway to acknowledge the close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counts requests being handled over one connection
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    inner: Arc<InFlightInner>,
+}
+
+#[derive(Debug, Default)]
+struct InFlightInner {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// Marks one request as in flight until dropped
+#[derive(Debug)]
+pub struct InFlightGuard {
+    inner: Arc<InFlightInner>,
+}
+
+impl InFlight {
+    /// Mark a request as in flight
+    pub fn start(&self) -> InFlightGuard {
+        self.inner.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Number of requests in flight
+    pub fn count(&self) -> usize {
+        self.inner.count.load(Ordering::SeqCst)
+    }
+
+    /// Wait until no request is in flight; false if `timeout` passed first
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                // Created before checking the count so a wakeup in between isn't lost
+                let idle = self.inner.idle.notified();
+                if self.count() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.inner.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.inner.idle.notify_waiters();
+        }
+    }
+}
+
+/// A connection that is being replaced and still carries in-flight requests
+pub struct DrainingConnection {
+    pub connection_id: String,
+    pub outgoing_tx: mpsc::Sender<WsMessage>,
+    pub in_flight: InFlight,
+    pub ws_sessions: Arc<WsSessions>,
+    /// Ends when the gateway closes the connection
+    pub read_task: JoinHandle<Result<()>>,
+    pub other_tasks: Vec<JoinHandle<Result<()>>>,
+}
+
+impl DrainingConnection {
+    /// Let in-flight requests finish (up to `timeout`), then close the connection
+    pub fn drain(mut self, timeout: Duration) {
+        tokio::spawn(
+            async move {
+                let pending = self.in_flight.count();
+                debug!(
+                    "Draining connection {} ({} requests in flight)",
+                    self.connection_id, pending
+                );
+                if !self.in_flight.wait_idle(timeout).await {
+                    warn!(
+                        "Closing connection {} with {} requests still in flight",
+                        self.connection_id,
+                        self.in_flight.count()
+                    );
+                }
+
+                // Passthrough sessions can't move to the new connection
+                self.ws_sessions.close_all();
+                if self.outgoing_tx.send(WsMessage::Close(None)).await.is_ok() {
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, &mut self.read_task).await;
+                }
+                self.read_task.abort();
+                for task in self.other_tasks {
+                    task.abort();
+                }
+                info!("Closed connection {} after handoff", self.connection_id);
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_idle() {
+        let in_flight = InFlight::default();
+        assert!(in_flight.wait_idle(Duration::from_millis(10)).await);
+
+        let guard = in_flight.start();
+        let second = in_flight.start();
+        assert_eq!(in_flight.count(), 2);
+        assert!(!in_flight.wait_idle(Duration::from_millis(10)).await);
+
+        let waiter = {
+            let in_flight = in_flight.clone();
+            tokio::spawn(async move { in_flight.wait_idle(Duration::from_secs(5)).await })
+        };
+        drop(guard);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+        drop(second);
+        assert!(waiter.await.unwrap());
+        assert_eq!(in_flight.count(), 0);
+    }
+}
@@ -0,0 +1,385 @@
+//! Session capture to a HAR file (`ttf --har-out session.har`)
+//!
+//! Every tunneled request and the response sent back for it are recorded with
+//! full headers and bodies, and written out as an HTTP Archive (HAR 1.2) that
+//! browser devtools and HAR viewers can open. The file is written when the
+//! forwarder stops, and on demand with `POST /har` on the control API.
+//!
+//! Text bodies are stored as they are and binary ones base64-encoded. Streamed
+//! responses only have their status and headers captured. Past
+//! `MAX_HAR_ENTRIES` exchanges the oldest are dropped.
+
+use anyhow::{Context, Result};
+use http_tunnel_common::{HttpRequest, HttpResponse, current_timestamp_millis, decode_body};
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::redact::Redactor;
+
+/// Exchanges kept for the archive
+const MAX_HAR_ENTRIES: usize = 10_000;
+
+#[derive(Debug)]
+struct Entry {
+    request_id: String,
+    /// When the request arrived (Unix milliseconds)
+    timestamp: u64,
+    started: Instant,
+    request: HttpRequest,
+    response: Option<HttpResponse>,
+    error: Option<String>,
+    time_ms: Option<u64>,
+}
+
+/// Records exchanges for a HAR file, shared by every tunnel of the process
+#[derive(Debug, Clone)]
+pub struct HarRecorder {
+    path: PathBuf,
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+    redactor: Arc<Redactor>,
+}
+
+impl HarRecorder {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            entries: Default::default(),
+            redactor: Default::default(),
+        }
+    }
+
+    /// Apply the `--redact` rules to everything recorded
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Where the archive is written
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Record a request as it arrives from the tunnel
+    pub fn record_request(&self, request: &HttpRequest) {
+        let mut entries = self.lock();
+        if entries.len() == MAX_HAR_ENTRIES {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            request_id: request.request_id.clone(),
+            timestamp: current_timestamp_millis(),
+            started: Instant::now(),
+            request: self.redactor.request(request).into_owned(),
+            response: None,
+            error: None,
+            time_ms: None,
+        });
+    }
+
+    /// Attach the response sent back for a recorded request
+    pub fn record_response(&self, response: &HttpResponse) {
+        self.complete(&response.request_id, |entry| {
+            entry.response = Some(self.redactor.response(response).into_owned());
+        });
+    }
+
+    /// Attach the error sent back instead of a response
+    pub fn record_error(&self, request_id: &str, message: &str) {
+        self.complete(request_id, |entry| {
+            entry.error = Some(self.redactor.text(message).into_owned());
+        });
+    }
+
+    fn complete(&self, request_id: &str, update: impl FnOnce(&mut Entry)) {
+        let mut entries = self.lock();
+        // Requests are answered roughly in order, so look from the newest
+        if let Some(entry) = entries
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.request_id == request_id && entry.time_ms.is_none())
+        {
+            entry.time_ms = Some(entry.started.elapsed().as_millis() as u64);
+            update(entry);
+        }
+    }
+
+    /// The recorded exchanges as a HAR document
+    pub fn to_har(&self) -> Value {
+        let entries: Vec<Value> = self.lock().iter().map(har_entry).collect();
+        json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": "ttf", "version": env!("CARGO_PKG_VERSION") },
+                "pages": [],
+                "entries": entries,
+            }
+        })
+    }
+
+    /// Write the archive, returning how many exchanges it holds
+    ///
+    /// The file is replaced in one step, so readers never see it half-written.
+    pub async fn save(&self) -> Result<usize> {
+        let har = self.to_har();
+        let count = har["log"]["entries"].as_array().map_or(0, Vec::len);
+        let json = serde_json::to_vec_pretty(&har)?;
+
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".partial");
+        tokio::fs::write(&partial, json)
+            .await
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        tokio::fs::rename(&partial, &self.path)
+            .await
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(count)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn har_entry(entry: &Entry) -> Value {
+    let request = &entry.request;
+    let started = UNIX_EPOCH + Duration::from_millis(entry.timestamp);
+    let time = entry.time_ms.unwrap_or_default();
+
+    let mut request_json = json!({
+        "method": request.method,
+        "url": request_url(request),
+        "httpVersion": "HTTP/1.1",
+        "cookies": [],
+        "headers": har_headers(&request.headers),
+        "queryString": query_string(&request.uri),
+        "headersSize": -1,
+        "bodySize": 0,
+    });
+    let body = decode_body(&request.body).unwrap_or_default();
+    if !body.is_empty() {
+        request_json["bodySize"] = json!(body.len());
+        request_json["postData"] = json!({
+            "mimeType": header(&request.headers, "content-type").unwrap_or_default(),
+            "text": String::from_utf8_lossy(&body),
+        });
+    }
+
+    let response_json = match &entry.response {
+        Some(response) => {
+            let body = decode_body(&response.body).unwrap_or_default();
+            let mut content = json!({
+                "size": body.len(),
+                "mimeType": header(&response.headers, "content-type").unwrap_or_default(),
+            });
+            match std::str::from_utf8(&body) {
+                Ok(text) => content["text"] = json!(text),
+                Err(_) => {
+                    content["text"] = json!(response.body);
+                    content["encoding"] = json!("base64");
+                }
+            }
+            json!({
+                "status": response.status_code,
+                "statusText": http::StatusCode::from_u16(response.status_code)
+                    .ok()
+                    .and_then(|status| status.canonical_reason())
+                    .unwrap_or_default(),
+                "httpVersion": "HTTP/1.1",
+                "cookies": [],
+                "headers": har_headers(&response.headers),
+                "content": content,
+                "redirectURL": header(&response.headers, "location").unwrap_or_default(),
+                "headersSize": -1,
+                "bodySize": body.len(),
+            })
+        }
+        // No response: devtools show status 0 as a failed request
+        None => json!({
+            "status": 0,
+            "statusText": "",
+            "httpVersion": "HTTP/1.1",
+            "cookies": [],
+            "headers": [],
+            "content": { "size": 0, "mimeType": "" },
+            "redirectURL": "",
+            "headersSize": -1,
+            "bodySize": -1,
+            "_error": entry.error,
+        }),
+    };
+
+    json!({
+        "startedDateTime": humantime::format_rfc3339_millis(started).to_string(),
+        "time": time,
+        "request": request_json,
+        "response": response_json,
+        "cache": {},
+        "timings": { "send": 0, "wait": time, "receive": 0 },
+    })
+}
+
+/// Absolute URL of the public request, from its `Host` header
+fn request_url(request: &HttpRequest) -> String {
+    let scheme = header(&request.headers, "x-forwarded-proto").unwrap_or("https");
+    let host = header(&request.headers, "host").unwrap_or("localhost");
+    format!("{}://{}{}", scheme, host, request.uri)
+}
+
+fn query_string(uri: &str) -> Vec<Value> {
+    let Some((_, query)) = uri.split_once('?') else {
+        return Vec::new();
+    };
+    url::form_urlencoded::parse(query.as_bytes())
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+/// Headers as HAR name/value pairs, sorted for a stable file
+fn har_headers(headers: &HashMap<String, Vec<String>>) -> Vec<Value> {
+    let mut pairs: Vec<(&String, &String)> = headers
+        .iter()
+        .flat_map(|(name, values)| values.iter().map(move |value| (name, value)))
+        .collect();
+    pairs.sort();
+    pairs
+        .into_iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+fn header<'a>(headers: &'a HashMap<String, Vec<String>>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .and_then(|(_, values)| values.first())
+        .map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_tunnel_common::encode_body;
+
+    fn request(request_id: &str, method: &str, uri: &str, body: &[u8]) -> HttpRequest {
+        let mut request = HttpRequest::new(
+            method.to_string(),
+            uri.to_string(),
+            request_id.to_string(),
+            0,
+        );
+        request.headers.insert(
+            "host".to_string(),
+            vec!["abc123def456.tunnel.example.com".to_string()],
+        );
+        request.body = encode_body(body);
+        request
+    }
+
+    #[test]
+    fn test_har_document() {
+        let recorder = HarRecorder::new(PathBuf::from("session.har"));
+        let mut post = request("req_1", "POST", "/api/items?debug=1&tag=a%20b", b"{}");
+        post.headers.insert(
+            "content-type".to_string(),
+            vec!["application/json".to_string()],
+        );
+        recorder.record_request(&post);
+        recorder.record_request(&request("req_2", "GET", "/logo.png", b""));
+        recorder.record_request(&request("req_3", "GET", "/down", b""));
+
+        let mut created = HttpResponse::new("req_1".to_string(), 201);
+        created.headers.insert(
+            "content-type".to_string(),
+            vec!["application/json".to_string()],
+        );
+        created.body = encode_body(br#"{"id":1}"#);
+        recorder.record_response(&created);
+        let mut image = HttpResponse::new("req_2".to_string(), 200);
+        image.body = encode_body(&[0x89, 0x50, 0xff]);
+        recorder.record_response(&image);
+        recorder.record_error("req_3", "Local service unavailable");
+
+        let har = recorder.to_har();
+        let log = &har["log"];
+        assert_eq!(log["version"], "1.2");
+        assert_eq!(log["creator"]["name"], "ttf");
+
+        let entries = log["entries"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        let first = &entries[0];
+        assert!(first["startedDateTime"].as_str().unwrap().ends_with('Z'));
+        assert_eq!(
+            first["request"]["url"],
+            "https://abc123def456.tunnel.example.com/api/items?debug=1&tag=a%20b"
+        );
+        assert_eq!(first["request"]["queryString"][1]["value"], "a b");
+        assert_eq!(first["request"]["postData"]["mimeType"], "application/json");
+        assert_eq!(first["request"]["postData"]["text"], "{}");
+        assert_eq!(first["response"]["status"], 201);
+        assert_eq!(first["response"]["statusText"], "Created");
+        assert_eq!(first["response"]["content"]["text"], r#"{"id":1}"#);
+
+        let binary = &entries[1]["response"]["content"];
+        assert_eq!(binary["encoding"], "base64");
+        assert_eq!(binary["size"], 3);
+        assert!(entries[1]["request"].get("postData").is_none());
+
+        assert_eq!(entries[2]["response"]["status"], 0);
+        assert_eq!(
+            entries[2]["response"]["_error"],
+            "Local service unavailable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorder = HarRecorder::new(dir.path().join("session.har"));
+        recorder.record_request(&request("req_1", "GET", "/", b""));
+        recorder.record_response(&HttpResponse::new("req_1".to_string(), 204));
+
+        assert_eq!(recorder.save().await.unwrap(), 1);
+        let saved: Value =
+            serde_json::from_slice(&std::fs::read(recorder.path()).unwrap()).unwrap();
+        assert_eq!(saved["log"]["entries"][0]["response"]["status"], 204);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let rules: Vec<crate::redact::RedactRule> = ["header:authorization", "json:password"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect();
+        let recorder = HarRecorder::new(PathBuf::from("session.har"))
+            .with_redactor(Arc::new(Redactor::new(&rules)));
+        let mut login = request("req_1", "POST", "/login", br#"{"password":"hunter2"}"#);
+        login.headers.insert(
+            "authorization".to_string(),
+            vec!["Bearer secret".to_string()],
+        );
+        recorder.record_request(&login);
+
+        let har = recorder.to_har().to_string();
+        assert!(
+            !har.contains("hunter2") && !har.contains("secret"),
+            "{}",
+            har
+        );
+        assert!(har.contains("[REDACTED]"));
+    }
+
+    #[test]
+    fn test_oldest_entries_dropped() {
+        let recorder = HarRecorder::new(PathBuf::from("session.har"));
+        for i in 0..(MAX_HAR_ENTRIES + 5) {
+            recorder.record_request(&request(&format!("req_{}", i), "GET", "/", b""));
+        }
+        let entries = recorder.lock();
+        assert_eq!(entries.len(), MAX_HAR_ENTRIES);
+        assert_eq!(entries.front().unwrap().request_id, "req_5");
+    }
+}
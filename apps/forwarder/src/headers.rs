@@ -0,0 +1,316 @@
+//! Header changes on the way to and from the local service
+//!
+//! Requests arrive with the public tunnel host in `Host`, which dev servers
+//! with host checks (vite, webpack-dev-server) and virtual-hosted apps reject.
+//! `--host-header` picks what the local service sees instead:
+//!
+//! - `rewrite` (default): the local service's own address, e.g. `127.0.0.1:3000`,
+//!   with the public host passed along in `X-Forwarded-Host`
+//! - `preserve`: the public host, as the client sent it
+//! - `custom:<value>`: a fixed virtual host, e.g. `custom:myapp.test`
+//!
+//! Other headers can be set and removed in either direction with
+//! `--request-header "X-Env: staging"` and `--remove-request-header authorization`
+//! (and their `--response-header` counterparts). Removal takes a name or a
+//! prefix ending in `*` (`x-debug-*`) and runs before headers are set, so a set
+//! header replaces whatever values the message carried.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// What the local service gets in the `Host` header
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum HostHeader {
+    /// The host of the local service's address
+    #[default]
+    Rewrite,
+    /// The public host the client asked for
+    Preserve,
+    /// A fixed value
+    Custom(String),
+}
+
+impl FromStr for HostHeader {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.split_once(':') {
+            None if mode == "rewrite" => Ok(Self::Rewrite),
+            None if mode == "preserve" => Ok(Self::Preserve),
+            Some(("custom", value))
+                if !value.trim().is_empty()
+                    && reqwest::header::HeaderValue::from_str(value.trim()).is_ok() =>
+            {
+                Ok(Self::Custom(value.trim().to_string()))
+            }
+            _ => Err(format!(
+                "invalid host header mode `{}` (expected rewrite, preserve or custom:<value>)",
+                mode
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for HostHeader {
+    type Error = String;
+
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        mode.parse()
+    }
+}
+
+impl fmt::Display for HostHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rewrite => f.write_str("rewrite"),
+            Self::Preserve => f.write_str("preserve"),
+            Self::Custom(value) => write!(f, "custom:{}", value),
+        }
+    }
+}
+
+impl HostHeader {
+    /// `Host` to send given the public one, or None to use the local address's
+    pub fn value<'a>(&'a self, public: Option<&'a str>) -> Option<&'a str> {
+        match self {
+            Self::Rewrite => None,
+            Self::Preserve => public,
+            Self::Custom(value) => Some(value),
+        }
+    }
+
+    /// Pass the public host on in `X-Forwarded-Host` if `Host` is rewritten
+    /// and the client didn't send one
+    pub fn forward_public_host(&self, headers: &mut HashMap<String, Vec<String>>) {
+        if *self != Self::Rewrite
+            || headers
+                .keys()
+                .any(|name| name.eq_ignore_ascii_case("x-forwarded-host"))
+        {
+            return;
+        }
+        let public = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+            .and_then(|(_, values)| values.first())
+            .cloned();
+        if let Some(public) = public {
+            headers.insert("x-forwarded-host".to_string(), vec![public]);
+        }
+    }
+}
+
+/// A header to set, written as `Name: value`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SetHeader {
+    /// Lowercased header name
+    name: String,
+    value: String,
+}
+
+impl FromStr for SetHeader {
+    type Err = String;
+
+    fn from_str(header: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid header `{}` (expected `Name: value`)", header);
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+        let (name, value) = (name.trim(), value.trim());
+        if reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()
+            || reqwest::header::HeaderValue::from_str(value).is_err()
+        {
+            return Err(invalid());
+        }
+        Ok(Self {
+            name: name.to_ascii_lowercase(),
+            value: value.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for SetHeader {
+    type Error = String;
+
+    fn try_from(header: String) -> Result<Self, Self::Error> {
+        header.parse()
+    }
+}
+
+/// A header to remove: a name, or a prefix ending in `*`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RemoveHeader {
+    /// Lowercased name or prefix
+    name: String,
+    prefix: bool,
+}
+
+impl FromStr for RemoveHeader {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let pattern = pattern.trim();
+        let (name, prefix) = match pattern.strip_suffix('*') {
+            Some(name) => (name, true),
+            None => (pattern, false),
+        };
+        if name.is_empty() || reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+            return Err(format!(
+                "invalid header name `{}` (expected `Name` or `prefix-*`)",
+                pattern
+            ));
+        }
+        Ok(Self {
+            name: name.to_ascii_lowercase(),
+            prefix,
+        })
+    }
+}
+
+impl TryFrom<String> for RemoveHeader {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+impl RemoveHeader {
+    fn matches(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        if self.prefix {
+            name.starts_with(&self.name)
+        } else {
+            name == self.name
+        }
+    }
+}
+
+/// Headers set and removed on messages going one way
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderEdits {
+    pub set: Vec<SetHeader>,
+    pub remove: Vec<RemoveHeader>,
+}
+
+impl HeaderEdits {
+    /// Remove, then set headers
+    pub fn apply(&self, headers: &mut HashMap<String, Vec<String>>) {
+        if self.set.is_empty() && self.remove.is_empty() {
+            return;
+        }
+        headers.retain(|name, _| {
+            !self.remove.iter().any(|rule| rule.matches(name))
+                && !self
+                    .set
+                    .iter()
+                    .any(|set| name.eq_ignore_ascii_case(&set.name))
+        });
+        for set in &self.set {
+            headers
+                .entry(set.name.clone())
+                .or_default()
+                .push(set.value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_host_header() {
+        assert_eq!("rewrite".parse(), Ok(HostHeader::Rewrite));
+        assert_eq!("preserve".parse(), Ok(HostHeader::Preserve));
+        assert_eq!(
+            "custom:myapp.test:8080".parse(),
+            Ok(HostHeader::Custom("myapp.test:8080".to_string()))
+        );
+        assert_eq!(
+            HostHeader::Custom("myapp.test".to_string()).to_string(),
+            "custom:myapp.test"
+        );
+
+        assert!("custom:".parse::<HostHeader>().is_err());
+        assert!("custom:bad\nvalue".parse::<HostHeader>().is_err());
+        assert!("keep".parse::<HostHeader>().is_err());
+    }
+
+    #[test]
+    fn test_host_value() {
+        let public = Some("abc123def456.tunnel.example.com");
+        assert_eq!(HostHeader::Rewrite.value(public), None);
+        assert_eq!(HostHeader::Preserve.value(public), public);
+        assert_eq!(
+            HostHeader::Custom("myapp.test".to_string()).value(public),
+            Some("myapp.test")
+        );
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        let mut headers: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, value) in pairs {
+            headers
+                .entry(name.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_parse_header_edits() {
+        let set: SetHeader = "X-Env:  staging ".parse().unwrap();
+        assert_eq!(
+            (set.name.as_str(), set.value.as_str()),
+            ("x-env", "staging")
+        );
+        assert!("X-Env".parse::<SetHeader>().is_err());
+        assert!("Bad Name: value".parse::<SetHeader>().is_err());
+
+        let remove: RemoveHeader = "X-Debug-*".parse().unwrap();
+        assert!(remove.matches("x-debug-token"));
+        assert!(!remove.matches("x-env"));
+        assert!("*".parse::<RemoveHeader>().is_err());
+        assert!("bad name".parse::<RemoveHeader>().is_err());
+    }
+
+    #[test]
+    fn test_apply_header_edits() {
+        let edits = HeaderEdits {
+            set: vec![
+                "X-Env: staging".parse().unwrap(),
+                "Cache-Control: no-store".parse().unwrap(),
+            ],
+            remove: vec![
+                "authorization".parse().unwrap(),
+                "x-debug-*".parse().unwrap(),
+            ],
+        };
+        let mut message = headers(&[
+            ("Authorization", "Bearer secret"),
+            ("X-Debug-Trace", "1"),
+            ("Cache-Control", "max-age=60"),
+            ("Accept", "text/html"),
+        ]);
+        edits.apply(&mut message);
+
+        assert_eq!(
+            message,
+            headers(&[
+                ("Accept", "text/html"),
+                ("cache-control", "no-store"),
+                ("x-env", "staging"),
+            ])
+        );
+
+        // No rules leave the headers alone
+        let mut untouched = headers(&[("Authorization", "Bearer secret")]);
+        HeaderEdits::default().apply(&mut untouched);
+        assert_eq!(untouched, headers(&[("Authorization", "Bearer secret")]));
+    }
+}
@@ -0,0 +1,96 @@
+//! Heartbeat round trips and dead-connection detection
+//!
+//! Heartbeats go out as WebSocket ping frames, which API Gateway answers with a
+//! pong by itself. Timing the pongs gives the round-trip time to
+//! the edge, and a run of unanswered pings shows that the connection is dead
+//! long before TCP notices, so the forwarder can reconnect right away.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Heartbeats in a row that may go unanswered before the connection is dropped
+pub const DEFAULT_MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/// Pings sent by the heartbeat task and the pongs that answered them
+#[derive(Debug, Clone, Default)]
+pub struct HeartbeatMonitor {
+    inner: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    next_seq: u64,
+    /// The ping still waiting for its pong
+    outstanding: Option<(u64, Instant)>,
+    /// Pings in a row that were never answered
+    missed: u32,
+}
+
+impl HeartbeatMonitor {
+    /// Start a new ping, returning its payload and how many pings in a row
+    /// have now gone unanswered (counting the previous one if it still is)
+    pub fn ping(&self) -> (Vec<u8>, u32) {
+        let mut state = self.lock();
+        if state.outstanding.is_some() {
+            state.missed += 1;
+        }
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.outstanding = Some((seq, Instant::now()));
+        (seq.to_be_bytes().to_vec(), state.missed)
+    }
+
+    /// Match a pong to the outstanding ping, returning the round-trip time
+    ///
+    /// Pongs for older pings (or ones we didn't send) are ignored.
+    pub fn pong(&self, payload: &[u8]) -> Option<Duration> {
+        let seq = u64::from_be_bytes(payload.try_into().ok()?);
+        let mut state = self.lock();
+        let (outstanding, sent_at) = state.outstanding?;
+        if seq != outstanding {
+            return None;
+        }
+        state.outstanding = None;
+        state.missed = 0;
+        Some(sent_at.elapsed())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_trip_time() {
+        let monitor = HeartbeatMonitor::default();
+        let (payload, missed) = monitor.ping();
+        assert_eq!(missed, 0);
+
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(monitor.pong(&payload), Some(Duration::from_millis(40)));
+
+        // A pong is only counted once
+        assert_eq!(monitor.pong(&payload), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unanswered_pings_counted() {
+        let monitor = HeartbeatMonitor::default();
+        let (first, _) = monitor.ping();
+        assert_eq!(monitor.ping().1, 1);
+        let (third, missed) = monitor.ping();
+        assert_eq!(missed, 2);
+
+        // A late pong for an old ping doesn't count
+        assert_eq!(monitor.pong(&first), None);
+        assert_eq!(monitor.pong(b"junk"), None);
+
+        assert!(monitor.pong(&third).is_some());
+        assert_eq!(monitor.ping().1, 0);
+    }
+}
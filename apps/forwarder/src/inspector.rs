@@ -0,0 +1,376 @@
+//! Local request inspector (`ttf --inspect`)
+//!
+//! Captures every tunneled request and the response sent back for it in a ring
+//! buffer, and serves them on a localhost port:
+//!
+//! - `/` - live dashboard (static page polling the JSON API)
+//! - `GET /api/requests` - captured exchanges, newest first, without bodies
+//! - `GET /api/requests/{id}` - one exchange with headers and decoded bodies
+//! - `DELETE /api/requests` - clear the buffer
+//!
+//! It's served by [`crate::local_http`] for local clients only, as the control
+//! API is: requests must be addressed to `127.0.0.1:<port>` or
+//! `localhost:<port>` so that a rebound domain can't read captured traffic.
+
+use anyhow::{Context, Result};
+use http_tunnel_common::{HttpRequest, HttpResponse, current_timestamp_millis, decode_body};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::TcpListener;
+
+use crate::local_http::{self, Access, Request, Response};
+use crate::redact::Redactor;
+
+/// Exchanges kept in the ring buffer
+const MAX_CAPTURED_EXCHANGES: usize = 200;
+
+/// Bytes of each decoded body kept for display
+const MAX_CAPTURED_BODY_BYTES: usize = 64 * 1024;
+
+/// Default port for the inspector, same as ngrok's
+pub const DEFAULT_INSPECTOR_PORT: u16 = 4040;
+
+const INSPECTOR_PAGE: &str = include_str!("inspector.html");
+
+/// A decoded request or response body
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedBody {
+    /// Size of the whole body in bytes
+    pub size: usize,
+    /// Body text (None for binary bodies)
+    pub text: Option<String>,
+    /// Whether `text` was cut at the display limit
+    pub truncated: bool,
+}
+
+impl CapturedBody {
+    fn decode(encoded: &str) -> Self {
+        let bytes = decode_body(encoded).unwrap_or_default();
+        let size = bytes.len();
+        let shown = &bytes[..size.min(MAX_CAPTURED_BODY_BYTES)];
+        // A cut may land inside a character: accept an incomplete trailing sequence
+        let text = match std::str::from_utf8(shown) {
+            Ok(text) => Some(text.to_string()),
+            Err(e) if e.error_len().is_none() => {
+                Some(String::from_utf8_lossy(&shown[..e.valid_up_to()]).into_owned())
+            }
+            Err(_) => None,
+        };
+        Self {
+            size,
+            text,
+            truncated: size > MAX_CAPTURED_BODY_BYTES,
+        }
+    }
+}
+
+/// The response half of an exchange
+#[derive(Debug, Clone, Serialize)]
+pub struct CapturedResponse {
+    pub status: u16,
+    pub headers: HashMap<String, Vec<String>>,
+    pub body: CapturedBody,
+}
+
+/// A tunneled request and what was sent back for it
+#[derive(Debug, Clone, Serialize)]
+pub struct Exchange {
+    pub id: u64,
+    pub request_id: String,
+    /// When the request arrived (Unix milliseconds)
+    pub timestamp: u64,
+    pub method: String,
+    pub uri: String,
+    pub headers: HashMap<String, Vec<String>>,
+    pub body: CapturedBody,
+    pub response: Option<CapturedResponse>,
+    /// Error reported instead of a response
+    pub error: Option<String>,
+    /// Time from receiving the request to sending the response or error
+    pub latency_ms: Option<u64>,
+    #[serde(skip)]
+    started: Instant,
+}
+
+impl Exchange {
+    /// List entry: the exchange without headers and bodies
+    fn summary(&self) -> Value {
+        json!({
+            "id": self.id,
+            "request_id": self.request_id,
+            "timestamp": self.timestamp,
+            "method": self.method,
+            "uri": self.uri,
+            "status": self.response.as_ref().map(|response| response.status),
+            "error": self.error,
+            "latency_ms": self.latency_ms,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Captures {
+    exchanges: VecDeque<Exchange>,
+    next_id: u64,
+}
+
+/// Ring buffer of recent exchanges, shared by the forwarder and the inspector server
+#[derive(Debug, Default, Clone)]
+pub struct Inspector {
+    captures: Arc<Mutex<Captures>>,
+    redactor: Arc<Redactor>,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply the `--redact` rules to everything captured
+    pub fn with_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Capture a request as it arrives from the tunnel
+    pub fn record_request(&self, request: &HttpRequest) {
+        let request = self.redactor.request(request);
+        let mut captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        captures.next_id += 1;
+        let exchange = Exchange {
+            id: captures.next_id,
+            request_id: request.request_id.clone(),
+            timestamp: current_timestamp_millis(),
+            method: request.method.clone(),
+            uri: request.uri.clone(),
+            headers: request.headers.clone(),
+            body: CapturedBody::decode(&request.body),
+            response: None,
+            error: None,
+            latency_ms: None,
+            started: Instant::now(),
+        };
+        captures.exchanges.push_front(exchange);
+        captures.exchanges.truncate(MAX_CAPTURED_EXCHANGES);
+    }
+
+    /// Attach the response sent back for a captured request
+    pub fn record_response(&self, response: &HttpResponse) {
+        let response = self.redactor.response(response);
+        self.complete(&response.request_id, |exchange| {
+            exchange.response = Some(CapturedResponse {
+                status: response.status_code,
+                headers: response.headers.clone(),
+                body: CapturedBody::decode(&response.body),
+            });
+        });
+    }
+
+    /// Attach the error sent back instead of a response
+    pub fn record_error(&self, request_id: &str, message: &str) {
+        self.complete(request_id, |exchange| {
+            exchange.error = Some(self.redactor.text(message).into_owned());
+        });
+    }
+
+    fn complete(&self, request_id: &str, update: impl FnOnce(&mut Exchange)) {
+        let mut captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(exchange) = captures
+            .exchanges
+            .iter_mut()
+            .find(|exchange| exchange.request_id == request_id)
+        {
+            exchange.latency_ms = Some(exchange.started.elapsed().as_millis() as u64);
+            update(exchange);
+        }
+    }
+
+    fn summaries(&self) -> Vec<Value> {
+        let captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        captures.exchanges.iter().map(Exchange::summary).collect()
+    }
+
+    fn get(&self, id: u64) -> Option<Exchange> {
+        let captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        captures.exchanges.iter().find(|e| e.id == id).cloned()
+    }
+
+    fn clear(&self) -> usize {
+        let mut captures = self.captures.lock().unwrap_or_else(|e| e.into_inner());
+        let cleared = captures.exchanges.len();
+        captures.exchanges.clear();
+        cleared
+    }
+}
+
+/// Start the inspector server on `port` on localhost
+pub async fn spawn_inspector_server(port: u16, inspector: Inspector) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .await
+        .with_context(|| format!("Failed to bind inspector to port {}", port))?;
+
+    local_http::serve(listener, "Inspector", Access::LocalOnly, move |request| {
+        std::future::ready(route(&request, &inspector))
+    })
+}
+
+/// Dispatch a request to the inspector routes
+fn route(request: &Request, inspector: &Inspector) -> Response {
+    let path = request.path.trim_end_matches('/');
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [""]) => Response {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: INSPECTOR_PAGE.to_string(),
+        },
+        ("GET", ["api", "requests"]) => Response::json(200, json!(inspector.summaries())),
+        ("DELETE", ["api", "requests"]) => {
+            Response::json(200, json!({ "cleared": inspector.clear() }))
+        }
+        ("GET", ["api", "requests", id]) => id
+            .parse()
+            .ok()
+            .and_then(|id| inspector.get(id))
+            .map(|exchange| Response::json(200, json!(exchange)))
+            .unwrap_or_else(|| Response::json(404, json!({ "error": "Request not found" }))),
+        _ => Response::json(404, json!({ "error": "Not found" })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_tunnel_common::encode_body;
+
+    fn get(path: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: None,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn request(request_id: &str, body: &[u8]) -> HttpRequest {
+        let mut request = HttpRequest::new(
+            "POST".to_string(),
+            "/api/items".to_string(),
+            request_id.to_string(),
+            0,
+        );
+        request.body = encode_body(body);
+        request
+    }
+
+    #[test]
+    fn test_captures_exchanges() {
+        let inspector = Inspector::new();
+        inspector.record_request(&request("req_1", b"{\"name\":\"Desk\"}"));
+        inspector.record_request(&request("req_2", &[0xff, 0xfe, 0x00]));
+
+        let mut response = HttpResponse::new("req_1".to_string(), 201);
+        response.body = encode_body(b"created");
+        inspector.record_response(&response);
+        inspector.record_error("req_2", "Local service unavailable");
+
+        let summaries = inspector.summaries();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0]["request_id"], "req_2");
+        assert_eq!(summaries[0]["error"], "Local service unavailable");
+        assert_eq!(summaries[1]["status"], 201);
+        assert!(summaries[1]["latency_ms"].is_u64());
+
+        let first = inspector.get(1).unwrap();
+        assert_eq!(first.body.text.as_deref(), Some("{\"name\":\"Desk\"}"));
+        assert_eq!(
+            first.response.unwrap().body.text.as_deref(),
+            Some("created")
+        );
+
+        let binary = inspector.get(2).unwrap();
+        assert_eq!(binary.body.size, 3);
+        assert!(binary.body.text.is_none());
+    }
+
+    #[test]
+    fn test_ring_buffer_and_truncation() {
+        let inspector = Inspector::new();
+        for i in 0..(MAX_CAPTURED_EXCHANGES + 10) {
+            inspector.record_request(&request(&format!("req_{}", i), b""));
+        }
+        assert_eq!(inspector.summaries().len(), MAX_CAPTURED_EXCHANGES);
+        assert!(inspector.get(1).is_none());
+
+        let body =
+            CapturedBody::decode(&encode_body("é".repeat(MAX_CAPTURED_BODY_BYTES).as_bytes()));
+        assert!(body.truncated);
+        assert_eq!(body.size, MAX_CAPTURED_BODY_BYTES * 2);
+        assert_eq!(body.text.unwrap().len(), MAX_CAPTURED_BODY_BYTES);
+    }
+
+    #[test]
+    fn test_routes() {
+        let inspector = Inspector::new();
+        inspector.record_request(&request("req_1", b"hello"));
+
+        let page = route(&get("/"), &inspector);
+        assert_eq!(
+            (page.status, page.content_type),
+            (200, "text/html; charset=utf-8")
+        );
+
+        let list = route(&get("/api/requests"), &inspector);
+        let list: Value = serde_json::from_str(&list.body).unwrap();
+        assert_eq!(list[0]["method"], "POST");
+        assert!(list[0].get("body").is_none());
+
+        let detail = route(&get("/api/requests/1"), &inspector);
+        assert_eq!(detail.status, 200);
+        let detail: Value = serde_json::from_str(&detail.body).unwrap();
+        assert_eq!(detail["body"]["text"], "hello");
+        assert_eq!(route(&get("/api/requests/9"), &inspector).status, 404);
+
+        let mut clear = get("/api/requests");
+        clear.method = "DELETE".to_string();
+        let cleared = route(&clear, &inspector);
+        assert_eq!(cleared.body, r#"{"cleared":1}"#);
+        assert!(inspector.summaries().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_other_hosts_and_origins() {
+        let inspector = Inspector::new();
+        inspector.record_request(&request("req_1", b"secret"));
+        let addr = spawn_inspector_server(0, inspector.clone()).await.unwrap();
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/api/requests", addr);
+
+        let list = client.get(&url).send().await.unwrap();
+        assert_eq!(list.status(), 200);
+
+        let rebound = client
+            .get(&url)
+            .header("Host", format!("evil.example:{}", addr.port()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rebound.status(), 403);
+        assert!(!rebound.text().await.unwrap().contains("secret"));
+
+        let forged = client
+            .delete(&url)
+            .header("Origin", "https://evil.example")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(forged.status(), 403);
+        assert_eq!(inspector.summaries().len(), 1);
+    }
+}
@@ -0,0 +1,127 @@
+//! Concurrency limit for requests forwarded to the local service
+//!
+//! Every tunneled request runs in its own task, so a burst of traffic can open
+//! more connections to the local service than it can take. With
+//! `--max-concurrent-requests` only that many run at once; the rest wait in a
+//! queue of `--max-queued-requests`, and requests arriving while the queue is
+//! full are answered with 503 straight away.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Requests that may wait for a slot before new ones are turned away
+pub const DEFAULT_MAX_QUEUED_REQUESTS: usize = 100;
+
+/// Limits how many requests reach the local service at once
+#[derive(Debug)]
+pub struct RequestLimiter {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+/// Outcome of asking the limiter for a slot
+#[derive(Debug)]
+pub enum Admission {
+    /// The request may run; the slot is freed when the permit is dropped
+    Admitted(OwnedSemaphorePermit),
+    /// The queue is full
+    Rejected,
+}
+
+/// Leaves the queue when dropped, so requests given up on don't hold their place
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl RequestLimiter {
+    pub fn new(max_concurrent: usize, max_queued: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a slot, queueing behind the requests already waiting
+    ///
+    /// Returns [`Admission::Rejected`] at once if the queue is full.
+    pub async fn acquire(&self) -> Admission {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Admission::Admitted(permit);
+        }
+
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Admission::Rejected;
+        }
+        let _slot = QueueSlot(&self.queued);
+
+        match self.permits.clone().acquire_owned().await {
+            Ok(permit) => Admission::Admitted(permit),
+            // The semaphore is never closed
+            Err(_) => Admission::Rejected,
+        }
+    }
+
+    /// Requests waiting for a slot
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Requests currently running
+    pub fn active(&self) -> usize {
+        self.max_concurrent - self.permits.available_permits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_queue_overflow_rejected() {
+        let limiter = Arc::new(RequestLimiter::new(1, 1));
+        let Admission::Admitted(running) = limiter.acquire().await else {
+            panic!("first request should run");
+        };
+        assert_eq!(limiter.active(), 1);
+
+        // The second request waits for the first one
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { matches!(limiter.acquire().await, Admission::Admitted(_)) }
+        });
+        while limiter.queue_depth() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // The queue is full, so the third is turned away
+        assert!(matches!(limiter.acquire().await, Admission::Rejected));
+
+        drop(running);
+        assert!(waiting.await.unwrap());
+        assert_eq!(limiter.queue_depth(), 0);
+        assert_eq!(limiter.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_wait_leaves_queue() {
+        let limiter = RequestLimiter::new(1, 1);
+        let Admission::Admitted(_running) = limiter.acquire().await else {
+            panic!("first request should run");
+        };
+
+        let wait = tokio::time::timeout(Duration::from_millis(10), limiter.acquire()).await;
+        assert!(wait.is_err());
+        assert_eq!(limiter.queue_depth(), 0);
+    }
+}
@@ -0,0 +1,361 @@
+//! Minimal HTTP/1.1 serving for the forwarder's localhost servers
+//!
+//! The demo server, the control API and the request inspector are tiny servers
+//! bound to localhost. They share the request parser and serve loop here, and
+//! every response closes the connection, which keeps the parser trivial; none
+//! of them is meant to serve anything else.
+//!
+//! Servers that expose the forwarder itself are [`Access::LocalOnly`]: requests
+//! must be addressed to `127.0.0.1:<port>` or `localhost:<port>`, so that a page
+//! on another domain that resolves to 127.0.0.1 (DNS rebinding) can't read or
+//! drive them, and requests other than GET from a browser page on another
+//! origin are refused.
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use serde_json::{Value, json};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+/// Largest request head (request line + headers) accepted
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Largest request body accepted
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// A parsed request
+#[derive(Debug, Clone, Serialize)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    /// Value of the first header called `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// A response to send back
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+}
+
+/// Who a server answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    /// Any request, e.g. the demo server reached through the tunnel
+    Anyone,
+    /// Only requests addressed to localhost, and not forged by other origins
+    LocalOnly,
+}
+
+impl Access {
+    /// The response refusing `request`, if this access doesn't allow it
+    fn refusal(self, request: &Request, port: u16) -> Option<Response> {
+        if self == Self::Anyone {
+            return None;
+        }
+        if !is_local_host(request, port) {
+            return Some(Response::json(403, json!({ "error": "Host not allowed" })));
+        }
+        if request.method != "GET" && is_cross_origin(request, port) {
+            return Some(Response::json(
+                403,
+                json!({ "error": "Cross-origin requests are not allowed" }),
+            ));
+        }
+        None
+    }
+}
+
+/// Serve `listener` in the background, answering each request with `handler`
+///
+/// `name` labels the server in logs.
+pub fn serve<H, F>(
+    listener: TcpListener,
+    name: &'static str,
+    access: Access,
+    handler: H,
+) -> Result<SocketAddr>
+where
+    H: Fn(Request) -> F + Send + Sync + 'static,
+    F: Future<Output = Response> + Send + 'static,
+{
+    let addr = listener.local_addr()?;
+    let handler = Arc::new(handler);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let handler = handler.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) =
+                            serve_connection(stream, access, addr.port(), &*handler).await
+                        {
+                            debug!("{} connection error: {:#}", name, e);
+                        }
+                    });
+                }
+                Err(e) => debug!("{} accept error: {}", name, e),
+            }
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn serve_connection<H, F>(
+    mut stream: TcpStream,
+    access: Access,
+    port: u16,
+    handler: &H,
+) -> Result<()>
+where
+    H: Fn(Request) -> F,
+    F: Future<Output = Response>,
+{
+    let response = match read_request(&mut stream).await {
+        Ok(request) => match access.refusal(&request, port) {
+            Some(refusal) => refusal,
+            None => handler(request).await,
+        },
+        Err(e) => Response::json(400, json!({ "error": e.to_string() })),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read one request from `stream`
+pub async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 8192];
+
+    let head_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        }
+        if buffer.len() > MAX_HEAD_BYTES {
+            bail!("request head too large");
+        }
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("connection closed before request was complete");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let mut request = parse_head(&String::from_utf8_lossy(&buffer[..head_end]))?;
+
+    let content_length = request
+        .header("content-length")
+        .map(|value| value.trim().parse::<usize>())
+        .transpose()
+        .context("invalid Content-Length")?
+        .unwrap_or(0);
+    if content_length > MAX_BODY_BYTES {
+        bail!("request body too large");
+    }
+
+    let mut body = buffer.split_off(head_end + 4);
+    while body.len() < content_length {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            bail!("connection closed before body was complete");
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(content_length);
+    request.body = String::from_utf8_lossy(&body).into_owned();
+
+    Ok(request)
+}
+
+/// Parse the request line and headers
+fn parse_head(head: &str) -> Result<Request> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line");
+    };
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        headers,
+        body: String::new(),
+    })
+}
+
+/// Whether the request's `Host` is `127.0.0.1:<port>` or `localhost:<port>`
+fn is_local_host(request: &Request, port: u16) -> bool {
+    request
+        .header("host")
+        .is_some_and(|host| is_local_authority(host, port))
+}
+
+/// Whether a browser sent the request from a page other than our own
+fn is_cross_origin(request: &Request, port: u16) -> bool {
+    request.header("origin").is_some_and(|origin| {
+        !origin
+            .strip_prefix("http://")
+            .is_some_and(|authority| is_local_authority(authority, port))
+    })
+}
+
+fn is_local_authority(authority: &str, port: u16) -> bool {
+    authority
+        .rsplit_once(':')
+        .is_some_and(|(host, authority_port)| {
+            (host == "127.0.0.1" || host.eq_ignore_ascii_case("localhost"))
+                && authority_port.parse() == Ok(port)
+        })
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_host(host: &str) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            query: None,
+            headers: vec![("Host".to_string(), host.to_string())],
+            body: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_parse_head() {
+        let request =
+            parse_head("POST /echo?x=1 HTTP/1.1\r\nHost: localhost\r\nX-Test:  yes").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/echo");
+        assert_eq!(request.query.as_deref(), Some("x=1"));
+        assert_eq!(
+            request.headers[1],
+            ("X-Test".to_string(), "yes".to_string())
+        );
+
+        assert!(parse_head("").is_err());
+    }
+
+    #[test]
+    fn test_local_host_and_origin() {
+        let mut request = request_with_host("127.0.0.1:4041");
+        request.method = "POST".to_string();
+        let mut set = |name: &str, value: &str| {
+            request.headers = vec![
+                ("Host".to_string(), "127.0.0.1:4041".to_string()),
+                (name.to_string(), value.to_string()),
+            ];
+            (
+                is_local_host(&request, 4041),
+                is_cross_origin(&request, 4041),
+            )
+        };
+
+        assert_eq!(set("X-Test", "yes"), (true, false));
+        assert_eq!(set("Origin", "http://localhost:4041"), (true, false));
+        assert_eq!(set("Origin", "http://127.0.0.1:4041"), (true, false));
+        assert_eq!(set("Origin", "http://127.0.0.1:8080"), (true, true));
+        assert_eq!(set("Origin", "https://evil.example"), (true, true));
+        assert_eq!(set("Origin", "null"), (true, true));
+
+        for host in ["localhost:4041", "LOCALHOST:4041", "127.0.0.1:4041"] {
+            assert!(is_local_host(&request_with_host(host), 4041), "{}", host);
+        }
+        for host in [
+            "evil.example:4041",
+            "localhost",
+            "localhost:4040",
+            "127.0.0.2:4041",
+        ] {
+            assert!(!is_local_host(&request_with_host(host), 4041), "{}", host);
+        }
+    }
+
+    #[test]
+    fn test_access_refusals() {
+        let mut request = request_with_host("evil.example:4041");
+        assert!(Access::Anyone.refusal(&request, 4041).is_none());
+        assert_eq!(
+            Access::LocalOnly.refusal(&request, 4041).map(|r| r.status),
+            Some(403)
+        );
+
+        request.headers = vec![
+            ("Host".to_string(), "localhost:4041".to_string()),
+            ("Origin".to_string(), "https://evil.example".to_string()),
+        ];
+        // Reading is fine, the browser keeps the response from the other page
+        assert!(Access::LocalOnly.refusal(&request, 4041).is_none());
+        request.method = "POST".to_string();
+        assert_eq!(
+            Access::LocalOnly.refusal(&request, 4041).map(|r| r.status),
+            Some(403)
+        );
+    }
+}
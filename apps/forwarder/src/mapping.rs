@@ -0,0 +1,81 @@
+//! `--map` tunnel mappings
+//!
+//! Each `--map PORT[:NAME]` opens its own tunnel to `--host:PORT`. The optional
+//! name labels the tunnel in logs and in the status view; it defaults to the port.
+
+use std::str::FromStr;
+
+/// One local port to tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelMapping {
+    pub port: u16,
+    pub name: Option<String>,
+}
+
+impl TunnelMapping {
+    /// Name shown for this tunnel
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.port.to_string())
+    }
+}
+
+impl FromStr for TunnelMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (port, name) = match value.split_once(':') {
+            Some((port, name)) => (port, Some(name.trim())),
+            None => (value, None),
+        };
+
+        let port = port
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .filter(|port| *port != 0)
+            .ok_or_else(|| {
+                format!(
+                    "invalid port in `{}` (expected `PORT` or `PORT:NAME`)",
+                    value
+                )
+            })?;
+
+        if let Some(name) = name
+            && (name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        {
+            return Err(format!(
+                "invalid name in `{}` (letters, digits, `-` and `_` only)",
+                value
+            ));
+        }
+
+        Ok(Self {
+            port,
+            name: name.map(str::to_string),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mapping() {
+        let mapping: TunnelMapping = "3000".parse().unwrap();
+        assert_eq!(mapping.port, 3000);
+        assert_eq!(mapping.label(), "3000");
+
+        let mapping: TunnelMapping = "5432:api".parse().unwrap();
+        assert_eq!(mapping.port, 5432);
+        assert_eq!(mapping.label(), "api");
+
+        assert!("0".parse::<TunnelMapping>().is_err());
+        assert!("http".parse::<TunnelMapping>().is_err());
+        assert!("8080:".parse::<TunnelMapping>().is_err());
+        assert!("8080:my api".parse::<TunnelMapping>().is_err());
+    }
+}
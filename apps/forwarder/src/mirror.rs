@@ -0,0 +1,78 @@
+//! Request mirroring (`--mirror`)
+//!
+//! With `--mirror http://127.0.0.1:9000`, every tunneled request is also sent
+//! to a second service, e.g. a new version running next to the current one, so
+//! it can be shadow-tested with real traffic such as webhooks. Copies are sent
+//! after header rules are applied and aren't waited for: the mirror's answers,
+//! failures and latency never reach the client or the session statistics.
+
+use http_tunnel_common::HttpRequest;
+use tracing::{Instrument, debug, warn};
+
+use crate::build_local_request;
+use crate::config_file::ForwardSettings;
+
+/// Parse a mirror base URL, which must be http or https
+pub fn parse_mirror_url(s: &str) -> Result<String, String> {
+    let url = url::Url::parse(s).map_err(|e| format!("invalid mirror URL `{}`: {}", s, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host_str().is_none() {
+        return Err(format!("mirror URL must be http(s)://host[:port]: `{}`", s));
+    }
+    Ok(s.trim_end_matches('/').to_string())
+}
+
+/// Send a copy of `request` to the mirror in the background, if one is configured
+pub fn mirror_request(request: &HttpRequest, body: Option<Vec<u8>>, settings: &ForwardSettings) {
+    let Some(mirror) = &settings.mirror else {
+        return;
+    };
+    // The mirror is reached over TCP by its own name, whatever the local service uses
+    let settings = ForwardSettings {
+        local_address: mirror.clone(),
+        local_tls_name: None,
+        unix_socket: None,
+        ..settings.clone()
+    };
+    let builder = match build_local_request(
+        request,
+        &settings,
+        request.effective_timeout(settings.request_timeout),
+        None,
+        body,
+    ) {
+        Ok(builder) => builder,
+        Err(e) => {
+            debug!("Not mirroring {} {}: {}", request.method, request.uri, e);
+            return;
+        }
+    };
+
+    let (method, uri) = (request.method.clone(), request.uri.clone());
+    tokio::spawn(
+        async move {
+            match builder.send().await {
+                Ok(response) => {
+                    debug!("Mirror answered {} {}: {}", method, uri, response.status())
+                }
+                Err(e) => warn!("Mirroring {} {} failed: {}", method, uri, e),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mirror_url() {
+        assert_eq!(
+            parse_mirror_url("http://127.0.0.1:9000/").as_deref(),
+            Ok("http://127.0.0.1:9000")
+        );
+        assert!(parse_mirror_url("https://shadow.internal").is_ok());
+        assert!(parse_mirror_url("127.0.0.1:9000").is_err());
+        assert!(parse_mirror_url("ftp://shadow.internal").is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! Notifications for tunnel lifecycle events
+//!
+//! With `--notify`, native desktop notifications; with `--notify-webhook URL`, a
+//! JSON POST to that URL for each event. The payload carries a `text` line, so a
+//! Slack (or Mattermost) incoming webhook URL works as is, plus the `event` name
+//! and its `summary` and `detail` for other receivers.
+//!
+//! Events: the tunnel connects, its public URL changes, it disconnects, the
+//! server warns it's about to close it, it sees its first request, and the
+//! local service fails. Desktop notifications are
+//! raised on a blocking thread and webhooks posted in the background, so a slow
+//! notification daemon or receiver never stalls the tunnel; failures are only
+//! logged at debug level.
+
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Minimum gap between local-service failure notifications
+const FAILURE_NOTIFY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a webhook call may take
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Raises notifications for tunnel lifecycle events
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    /// Whether to show desktop notifications
+    enabled: bool,
+    /// URL to POST events to
+    webhook: Option<String>,
+    client: reqwest::Client,
+    last_failure: Arc<Mutex<Option<Instant>>>,
+    /// Public URL of the last connection, to tell a new one apart
+    public_url: Arc<Mutex<Option<String>>>,
+    /// Set once the first request came in
+    seen_request: Arc<AtomicBool>,
+}
+
+impl Notifier {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            webhook: None,
+            client: reqwest::Client::new(),
+            last_failure: Arc::new(Mutex::new(None)),
+            public_url: Arc::new(Mutex::new(None)),
+            seen_request: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Also POST every event to `url`
+    pub fn with_webhook(mut self, url: Option<String>) -> Self {
+        self.webhook = url;
+        self
+    }
+
+    /// Tunnel connected and ready to serve traffic
+    pub fn tunnel_established(&self, public_url: &str) {
+        let previous = self
+            .public_url
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .replace(public_url.to_string());
+        match previous {
+            Some(previous) if previous != public_url => self.show(
+                "public_url_changed",
+                "Public URL changed",
+                format!("{} (was {})", public_url, previous),
+            ),
+            _ => self.show(
+                "tunnel_established",
+                "Tunnel established",
+                public_url.to_string(),
+            ),
+        }
+    }
+
+    /// Connection dropped without the user asking for it
+    pub fn disconnected(&self, reason: &str) {
+        self.show(
+            "disconnected",
+            "Tunnel disconnected",
+            format!("{}. Reconnecting...", reason),
+        );
+    }
+
+    /// The server is about to close the tunnel (idle or expiring)
+    pub fn closing(&self, warning: &str) {
+        self.show("closing", "Tunnel closing soon", warning.to_string());
+    }
+
+    /// A request came in; only the first one of the session is announced
+    pub fn request_received(&self, method: &str, uri: &str) {
+        if self.is_enabled() && !self.seen_request.swap(true, Ordering::Relaxed) {
+            self.show(
+                "first_request",
+                "First request",
+                format!("{} {}", method, uri),
+            );
+        }
+    }
+
+    /// Local service could not be reached (rate-limited to avoid a flood)
+    pub fn local_service_failed(&self, error: &str) {
+        if self.is_enabled() && self.should_notify_failure(Instant::now()) {
+            self.show(
+                "local_service_failed",
+                "Local service unavailable",
+                error.to_string(),
+            );
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled || self.webhook.is_some()
+    }
+
+    fn should_notify_failure(&self, now: Instant) -> bool {
+        let mut last_failure = self.last_failure.lock().unwrap_or_else(|e| e.into_inner());
+        match *last_failure {
+            Some(last) if now.duration_since(last) < FAILURE_NOTIFY_INTERVAL => false,
+            _ => {
+                *last_failure = Some(now);
+                true
+            }
+        }
+    }
+
+    fn show(&self, event: &'static str, summary: &'static str, body: String) {
+        if let Some(url) = &self.webhook {
+            let request = self
+                .client
+                .post(url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .json(&webhook_payload(event, summary, &body));
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {}
+                    Err(e) => debug!("Failed to call the notification webhook: {}", e),
+                }
+            });
+        }
+        if !self.enabled {
+            return;
+        }
+
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = notify_rust::Notification::new()
+                .appname("ttf")
+                .summary(summary)
+                .body(&body)
+                .show()
+            {
+                debug!("Failed to show desktop notification: {}", e);
+            }
+        });
+    }
+}
+
+/// JSON posted to `--notify-webhook`
+fn webhook_payload(event: &str, summary: &str, detail: &str) -> serde_json::Value {
+    json!({
+        "text": format!("ttf: {}: {}", summary, detail),
+        "event": event,
+        "summary": summary,
+        "detail": detail,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_failure_notifications_are_rate_limited() {
+        let notifier = Notifier::new(true);
+        let start = Instant::now();
+
+        assert!(notifier.should_notify_failure(start));
+        assert!(!notifier.should_notify_failure(start + Duration::from_secs(5)));
+        assert!(notifier.should_notify_failure(start + FAILURE_NOTIFY_INTERVAL));
+    }
+
+    /// Receiver collecting the JSON bodies posted to it
+    async fn webhook_receiver() -> (String, tokio::sync::mpsc::Receiver<serde_json::Value>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::channel(10);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                // Read up to the end of the JSON body
+                while !request.ends_with(b"}") {
+                    let n = stream.read(&mut buffer).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let (_, body) = request.split_once("\r\n\r\n").unwrap();
+                tx.send(serde_json::from_str(body).unwrap()).await.unwrap();
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+                    .await;
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn test_webhook_events() {
+        let (url, mut events) = webhook_receiver().await;
+        let notifier = Notifier::new(false).with_webhook(Some(url));
+        let mut next = async || {
+            tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+
+        notifier.tunnel_established("https://abc.example.com");
+        let event = next().await;
+        assert_eq!(event["event"], "tunnel_established");
+        assert_eq!(
+            event["text"],
+            "ttf: Tunnel established: https://abc.example.com"
+        );
+
+        notifier.request_received("GET", "/");
+        notifier.request_received("POST", "/webhook");
+        let event = next().await;
+        assert_eq!(event["event"], "first_request");
+        assert_eq!(event["detail"], "GET /");
+
+        // Reconnecting to a different tunnel ID
+        notifier.tunnel_established("https://def.example.com");
+        let event = next().await;
+        assert_eq!(event["event"], "public_url_changed");
+        assert_eq!(
+            event["detail"],
+            "https://def.example.com (was https://abc.example.com)"
+        );
+
+        // Only the first request was announced
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,448 @@
+//! OAuth 2.0 device authorization (`ttf login`)
+//!
+//! Instead of pasting a JWT into `--token`, `ttf login --issuer <URL>
+//! --client-id <ID>` signs in through the issuer's device flow (RFC 8628): it
+//! finds the issuer's endpoints in its OpenID configuration, shows a code to
+//! enter in a browser and waits for the sign-in to be approved. The refresh
+//! token it gets is saved in the OS keychain, and later runs without `--token`
+//! exchange it for an access token whenever they connect and the last one is
+//! about to expire. `ttf logout` forgets it.
+
+use anyhow::{Context, Result, bail};
+use http_tunnel_common::TunnelError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::credentials;
+
+/// Keychain entry holding the saved login
+pub const LOGIN_ENTRY: &str = "oauth-login";
+
+/// Scopes asked for by default; `offline_access` is what gets a refresh token
+pub const DEFAULT_SCOPE: &str = "openid offline_access";
+
+/// Access tokens this close to expiring are refreshed before connecting
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+
+/// Polling interval when the issuer doesn't give one (RFC 8628 §3.5)
+const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// Options of `ttf login`
+#[derive(clap::Args, Debug, Clone, PartialEq, Eq)]
+pub struct LoginArgs {
+    /// OAuth issuer URL; its endpoints are read from /.well-known/openid-configuration
+    #[arg(long, env = "TTF_OAUTH_ISSUER", value_name = "URL")]
+    pub issuer: String,
+
+    /// OAuth client ID registered for ttf with the device flow enabled
+    #[arg(long, env = "TTF_OAUTH_CLIENT_ID", value_name = "ID")]
+    pub client_id: String,
+
+    /// Scopes to ask for
+    #[arg(long, default_value = DEFAULT_SCOPE)]
+    pub scope: String,
+
+    /// API audience to ask tokens for, for issuers that need one (e.g. Auth0)
+    #[arg(long)]
+    pub audience: Option<String>,
+}
+
+/// What `ttf login` saves to the keychain
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredLogin {
+    pub issuer: String,
+    pub client_id: String,
+    pub token_endpoint: String,
+    pub refresh_token: String,
+}
+
+// Keep the refresh token out of logs
+impl fmt::Debug for StoredLogin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoredLogin")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Endpoints from the issuer's OpenID configuration
+#[derive(Debug, Deserialize)]
+struct Endpoints {
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Device authorization response (RFC 8628 §3.2)
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// Google calls it `verification_url`
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error_description {
+            Some(description) => write!(f, "{} ({})", self.error, description),
+            None => f.write_str(&self.error),
+        }
+    }
+}
+
+/// Run the device flow, printing where to sign in, until the user approves it
+pub async fn login(args: &LoginArgs) -> Result<StoredLogin> {
+    let client = reqwest::Client::new();
+    let endpoints = discover(&client, &args.issuer).await?;
+
+    let mut form = vec![
+        ("client_id", args.client_id.as_str()),
+        ("scope", args.scope.as_str()),
+    ];
+    if let Some(audience) = &args.audience {
+        form.push(("audience", audience));
+    }
+    let device: DeviceAuthorization = client
+        .post(&endpoints.device_authorization_endpoint)
+        .form(&form)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("Device authorization request failed")?
+        .json()
+        .await
+        .context("Invalid device authorization response")?;
+
+    println!(
+        "To sign in, open {} and enter the code {}",
+        device.verification_uri, device.user_code
+    );
+    if let Some(complete) = &device.verification_uri_complete {
+        println!("(or open {} directly)", complete);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(device.expires_in);
+    let mut interval = Duration::from_secs(device.interval.unwrap_or(DEFAULT_POLL_INTERVAL_SECS));
+    loop {
+        if Instant::now() >= deadline {
+            bail!("The code expired before sign-in finished; run `ttf login` again");
+        }
+        tokio::time::sleep(interval).await;
+
+        let form = [
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", &device.device_code),
+            ("client_id", &args.client_id),
+        ];
+        match token_request(&client, &endpoints.token_endpoint, &form).await? {
+            Ok(tokens) => {
+                let refresh_token = tokens.refresh_token.context(
+                    "The issuer didn't return a refresh token; add `offline_access` to --scope",
+                )?;
+                return Ok(StoredLogin {
+                    issuer: args.issuer.clone(),
+                    client_id: args.client_id.clone(),
+                    token_endpoint: endpoints.token_endpoint,
+                    refresh_token,
+                });
+            }
+            Err(e) => match e.error.as_str() {
+                "authorization_pending" => debug!("Waiting for sign-in"),
+                "slow_down" => interval += Duration::from_secs(DEFAULT_POLL_INTERVAL_SECS),
+                "access_denied" => bail!("Sign-in was denied"),
+                "expired_token" => {
+                    bail!("The code expired before sign-in finished; run `ttf login` again")
+                }
+                _ => bail!("Sign-in failed: {}", e),
+            },
+        }
+    }
+}
+
+/// Read the issuer's endpoints from its OpenID configuration
+async fn discover(client: &reqwest::Client, issuer: &str) -> Result<Endpoints> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        issuer.trim_end_matches('/')
+    );
+    client
+        .get(&url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to fetch {}", url))?
+        .json()
+        .await
+        .with_context(|| format!("{} doesn't list device flow endpoints", url))
+}
+
+/// Post to the token endpoint, telling OAuth errors apart from failed requests
+async fn token_request(
+    client: &reqwest::Client,
+    endpoint: &str,
+    form: &[(&str, &str)],
+) -> Result<Result<TokenResponse, TokenError>> {
+    let response = client
+        .post(endpoint)
+        .form(form)
+        .send()
+        .await
+        .context("Token request failed")?;
+    let status = response.status();
+    let body = response.bytes().await.context("Token request failed")?;
+    if status.is_success() {
+        let tokens = serde_json::from_slice(&body).context("Invalid token response")?;
+        return Ok(Ok(tokens));
+    }
+    match serde_json::from_slice(&body) {
+        Ok(error) => Ok(Err(error)),
+        Err(_) => bail!("Token endpoint answered {}", status),
+    }
+}
+
+/// A saved login, handing out access tokens for the tunnel connection
+pub struct OAuthSession {
+    state: Mutex<SessionState>,
+    /// Save rotated refresh tokens back to the keychain
+    persist: bool,
+}
+
+struct SessionState {
+    login: StoredLogin,
+    access_token: Option<(String, Option<Instant>)>,
+}
+
+impl fmt::Debug for OAuthSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuthSession").finish_non_exhaustive()
+    }
+}
+
+impl OAuthSession {
+    pub fn new(login: StoredLogin) -> Self {
+        Self {
+            state: Mutex::new(SessionState {
+                login,
+                access_token: None,
+            }),
+            persist: false,
+        }
+    }
+
+    /// The login saved by `ttf login`, if any
+    pub async fn load() -> Result<Option<Self>> {
+        let Some(saved) = credentials::load(LOGIN_ENTRY).await? else {
+            return Ok(None);
+        };
+        let login: StoredLogin = serde_json::from_str(&saved)
+            .context("The saved login is unreadable; run `ttf login` again")?;
+        Ok(Some(Self {
+            persist: true,
+            ..Self::new(login)
+        }))
+    }
+
+    /// Issuer the login belongs to
+    pub async fn issuer(&self) -> String {
+        self.state.lock().await.login.issuer.clone()
+    }
+
+    /// Drop the access token, e.g. after the endpoint refused it, so the next
+    /// connection refreshes it
+    pub async fn expire(&self) {
+        self.state.lock().await.access_token = None;
+    }
+
+    /// Access token for the next connection, refreshed if it's about to expire
+    pub async fn access_token(&self) -> Result<String> {
+        let mut state = self.state.lock().await;
+        if let Some((token, Some(expires))) = &state.access_token
+            && Instant::now() + EXPIRY_MARGIN < *expires
+        {
+            return Ok(token.clone());
+        }
+
+        let login = &state.login;
+        let form = [
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &login.refresh_token),
+            ("client_id", &login.client_id),
+        ];
+        let tokens = token_request(&reqwest::Client::new(), &login.token_endpoint, &form)
+            .await?
+            .map_err(|e| {
+                // The issuer answered, so trying again won't help
+                TunnelError::AuthFailed(format!(
+                    "Refreshing the login failed: {}; run `ttf login` again",
+                    e
+                ))
+            })?;
+        debug!("Refreshed the access token");
+
+        // Issuers that rotate refresh tokens invalidate the old one
+        if let Some(rotated) = tokens.refresh_token
+            && rotated != state.login.refresh_token
+        {
+            state.login.refresh_token = rotated;
+            if self.persist {
+                match serde_json::to_string(&state.login) {
+                    Ok(saved) => {
+                        if let Err(e) = credentials::store(LOGIN_ENTRY, saved).await {
+                            warn!("{:#}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to save the login: {}", e),
+                }
+            } else {
+                info!("Refresh token rotated");
+            }
+        }
+
+        // Without an expiry, the token is refreshed on every connection
+        let expires = tokens
+            .expires_in
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        state.access_token = Some((tokens.access_token.clone(), expires));
+        Ok(tokens.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use crate::local_http::read_request;
+
+    /// Run an issuer that makes the device flow wait for one poll, then approves it
+    async fn fake_issuer() -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let counter = polls.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let request = read_request(&mut stream).await.unwrap();
+                let form: Vec<(String, String)> =
+                    url::form_urlencoded::parse(request.body.as_bytes())
+                        .into_owned()
+                        .collect();
+                let param = |name: &str| {
+                    form.iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| value.as_str())
+                };
+
+                let (status, body) = match request.path.as_str() {
+                    "/.well-known/openid-configuration" => (
+                        200,
+                        format!(
+                            r#"{{"device_authorization_endpoint":"http://{addr}/device","token_endpoint":"http://{addr}/token"}}"#
+                        ),
+                    ),
+                    "/device" => {
+                        assert_eq!(param("client_id"), Some("ttf-cli"));
+                        assert_eq!(param("scope"), Some(DEFAULT_SCOPE));
+                        (
+                            200,
+                            r#"{"device_code":"dev-1","user_code":"WDJB-MJHT","verification_uri":"https://issuer.test/device","expires_in":60,"interval":0}"#
+                                .to_string(),
+                        )
+                    }
+                    "/token" => match param("grant_type") {
+                        Some(DEVICE_CODE_GRANT)
+                            if counter.fetch_add(1, Ordering::SeqCst) == 0 =>
+                        {
+                            (400, r#"{"error":"authorization_pending"}"#.to_string())
+                        }
+                        Some(DEVICE_CODE_GRANT) => (
+                            200,
+                            r#"{"access_token":"access-1","refresh_token":"refresh-1","expires_in":3600}"#
+                                .to_string(),
+                        ),
+                        _ if param("refresh_token") == Some("refresh-1") => (
+                            200,
+                            r#"{"access_token":"access-2","refresh_token":"refresh-2","expires_in":3600}"#
+                                .to_string(),
+                        ),
+                        _ => (400, r#"{"error":"invalid_grant"}"#.to_string()),
+                    },
+                    _ => (404, "{}".to_string()),
+                };
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (addr, polls)
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_and_refresh() {
+        let (addr, polls) = fake_issuer().await;
+        let args = LoginArgs {
+            issuer: format!("http://{}/", addr),
+            client_id: "ttf-cli".to_string(),
+            scope: DEFAULT_SCOPE.to_string(),
+            audience: None,
+        };
+        let login = login(&args).await.unwrap();
+        assert_eq!(polls.load(Ordering::SeqCst), 2);
+        assert_eq!(login.refresh_token, "refresh-1");
+        assert_eq!(login.token_endpoint, format!("http://{}/token", addr));
+        assert!(!format!("{:?}", login).contains("refresh-1"));
+
+        let session = OAuthSession::new(login);
+        assert_eq!(session.access_token().await.unwrap(), "access-2");
+        // Still fresh: no second refresh, which the rotated token wouldn't pass
+        assert_eq!(session.access_token().await.unwrap(), "access-2");
+        assert_eq!(session.state.lock().await.login.refresh_token, "refresh-2");
+    }
+
+    #[tokio::test]
+    async fn test_rejected_refresh() {
+        let (addr, _) = fake_issuer().await;
+        let session = OAuthSession::new(StoredLogin {
+            issuer: format!("http://{}", addr),
+            client_id: "ttf-cli".to_string(),
+            token_endpoint: format!("http://{}/token", addr),
+            refresh_token: "revoked".to_string(),
+        });
+        let error = session.access_token().await.unwrap_err().to_string();
+        assert!(error.contains("invalid_grant"), "{}", error);
+        assert!(error.contains("ttf login"), "{}", error);
+        assert!(error.starts_with("Authentication failed"), "{}", error);
+    }
+}
@@ -0,0 +1,156 @@
+//! Machine-readable output (`ttf --output json`)
+//!
+//! In JSON mode logs go to stderr, and stdout carries one JSON object per line
+//! for each lifecycle event, so CI scripts can pick up the public URL without
+//! scraping log text:
+//!
+//! ```text
+//! {"event":"tunnel_established","tunnel_id":"abc123def456","public_url":"https://...","connection_id":"...","timestamp":1700000000000}
+//! ```
+//!
+//! Events of `--map` tunnels carry the tunnel's name in `tunnel`.
+
+use clap::ValueEnum;
+use http_tunnel_common::current_timestamp_millis;
+use serde::Serialize;
+
+use crate::stats::SessionSummary;
+
+/// How the forwarder reports what it's doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable logs on stdout
+    #[default]
+    Text,
+    /// Logs on stderr, JSON lifecycle events on stdout
+    Json,
+}
+
+/// A lifecycle event reported in JSON mode
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// The tunnel is up and serving `public_url`
+    TunnelEstablished {
+        tunnel_id: &'a str,
+        public_url: &'a str,
+        connection_id: &'a str,
+    },
+    /// The connection couldn't be established
+    ConnectFailed { reason: &'a str },
+    /// An established connection was lost
+    Disconnected { reason: &'a str },
+    /// The next connection attempt is scheduled
+    Reconnecting { attempt: usize, delay_ms: u64 },
+    /// The forwarder is exiting; totals of the session
+    Stopped {
+        duration_secs: u64,
+        requests: u64,
+        failures: u64,
+        reconnects: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub fn stopped(summary: &SessionSummary) -> Self {
+        Event::Stopped {
+            duration_secs: summary.duration.as_secs(),
+            requests: summary.requests,
+            failures: summary.failures,
+            reconnects: summary.reconnects,
+            bytes_in: summary.bytes_in,
+            bytes_out: summary.bytes_out,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnel: Option<&'a str>,
+    timestamp: u64,
+}
+
+/// Writes the events of one tunnel to stdout, if JSON output is enabled
+#[derive(Debug, Clone, Default)]
+pub struct Events {
+    enabled: bool,
+    /// `--map` name of the tunnel
+    tunnel: Option<String>,
+}
+
+impl Events {
+    pub fn new(format: OutputFormat, tunnel: Option<String>) -> Self {
+        Self {
+            enabled: format == OutputFormat::Json,
+            tunnel,
+        }
+    }
+
+    pub fn emit(&self, event: Event<'_>) {
+        if let Some(line) = self.format(&event) {
+            println!("{}", line);
+        }
+    }
+
+    fn format(&self, event: &Event<'_>) -> Option<String> {
+        if !self.enabled {
+            return None;
+        }
+        let line = Line {
+            event,
+            tunnel: self.tunnel.as_deref(),
+            timestamp: current_timestamp_millis(),
+        };
+        serde_json::to_string(&line).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn test_json_lines() {
+        let events = Events::new(OutputFormat::Json, Some("api".to_string()));
+        let line = events
+            .format(&Event::TunnelEstablished {
+                tunnel_id: "abc123def456",
+                public_url: "https://tunnel.example.com/abc123def456",
+                connection_id: "conn_1",
+            })
+            .unwrap();
+        assert!(!line.contains('\n'));
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "tunnel_established");
+        assert_eq!(value["tunnel_id"], "abc123def456");
+        assert_eq!(value["tunnel"], "api");
+        assert!(value["timestamp"].is_u64());
+
+        let line = Events::new(OutputFormat::Json, None)
+            .format(&Event::Reconnecting {
+                attempt: 2,
+                delay_ms: 2000,
+            })
+            .unwrap();
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["event"], "reconnecting");
+        assert!(value.get("tunnel").is_none());
+    }
+
+    #[test]
+    fn test_text_mode_is_silent() {
+        let events = Events::new(OutputFormat::Text, None);
+        assert!(
+            events
+                .format(&Event::ConnectFailed { reason: "x" })
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,284 @@
+//! Request/response plugins (`--plugin CMD`)
+//!
+//! A plugin is a long-running command that sees every request before it's
+//! forwarded and every response before it goes back, and may change them or
+//! answer requests itself: stub out auth, scrub bodies, inject mocks. It gets
+//! one JSON object per line on stdin and answers each with one line on stdout:
+//!
+//! - `{"phase":"request","request":{...}}`: reply `{"request":{...}}` to forward
+//!   a (possibly changed) request, `{"response":{...}}` to answer it without
+//!   calling the local service, or `{}` to leave it alone.
+//! - `{"phase":"response","request":{...},"response":{...}}`: reply
+//!   `{"response":{...}}` to replace the response, or `{}` to leave it alone.
+//!
+//! Requests have `method`, `uri`, `headers` (name to list of values) and `body`
+//! (Base64); responses have `status_code`, `headers` and `body`. Other fields
+//! are informational and ignored in replies, so a plugin may echo what it got.
+//! Calls are made one at a time. A plugin that fails, exits or takes longer than
+//! 10s fails the request with a 502, and is started again for the next one.
+//!
+//! WebAssembly plugins run the same way through a WASI runtime, e.g.
+//! `--plugin "wasmtime run scrub.wasm"`. Streamed responses skip the response
+//! phase.
+
+use anyhow::{Context, Result, anyhow, bail};
+use http_tunnel_common::{HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// How long a plugin may take to answer
+const PLUGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the request phase decided
+#[derive(Debug)]
+pub enum PluginAction {
+    /// Forward this request
+    Forward(HttpRequest),
+    /// Answer with this response instead of calling the local service
+    Respond(HttpResponse),
+}
+
+/// A running plugin command, started on first use
+#[derive(Debug)]
+pub struct Plugin {
+    command: String,
+    process: Mutex<Option<PluginProcess>>,
+}
+
+#[derive(Debug)]
+struct PluginProcess {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+#[derive(Serialize)]
+struct PluginInput<'a> {
+    phase: &'static str,
+    request: &'a HttpRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<&'a HttpResponse>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PluginOutput {
+    request: Option<RequestReply>,
+    response: Option<ResponseReply>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RequestReply {
+    method: String,
+    uri: String,
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    body: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ResponseReply {
+    status_code: u16,
+    #[serde(default)]
+    headers: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    body: String,
+}
+
+impl ResponseReply {
+    /// Apply the reply to `response`, keeping what the plugin doesn't control
+    fn apply(self, response: &mut HttpResponse) {
+        response.status_code = self.status_code;
+        response.headers = self.headers;
+        response.body = self.body;
+    }
+}
+
+impl Plugin {
+    pub fn new(command: String) -> Self {
+        Self {
+            command,
+            process: Mutex::new(None),
+        }
+    }
+
+    /// Run the request phase
+    pub async fn on_request(&self, mut request: HttpRequest) -> Result<PluginAction> {
+        let output = self
+            .call(&PluginInput {
+                phase: "request",
+                request: &request,
+                response: None,
+            })
+            .await?;
+        if let Some(reply) = output.response {
+            let mut response = HttpResponse::new(request.request_id, reply.status_code);
+            reply.apply(&mut response);
+            return Ok(PluginAction::Respond(response));
+        }
+        if let Some(reply) = output.request {
+            request.method = reply.method;
+            request.uri = reply.uri;
+            request.headers = reply.headers;
+            request.body = reply.body;
+        }
+        Ok(PluginAction::Forward(request))
+    }
+
+    /// Run the response phase for the response to `request`
+    pub async fn on_response(
+        &self,
+        request: &HttpRequest,
+        mut response: HttpResponse,
+    ) -> Result<HttpResponse> {
+        let output = self
+            .call(&PluginInput {
+                phase: "response",
+                request,
+                response: Some(&response),
+            })
+            .await?;
+        if let Some(reply) = output.response {
+            reply.apply(&mut response);
+        }
+        Ok(response)
+    }
+
+    async fn call(&self, input: &PluginInput<'_>) -> Result<PluginOutput> {
+        let mut line = serde_json::to_string(input)?;
+        line.push('\n');
+
+        let mut process = self.process.lock().await;
+        if process.is_none() {
+            *process = Some(self.spawn()?);
+        }
+        let running = process.as_mut().expect("the plugin was just started");
+        let result = tokio::time::timeout(PLUGIN_TIMEOUT, running.exchange(&line))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("no answer within {:?}", PLUGIN_TIMEOUT)));
+        match result {
+            Ok(output) => Ok(output),
+            Err(e) => {
+                // Start afresh next time rather than read a late answer
+                *process = None;
+                warn!("Plugin `{}` failed: {:#}", self.command, e);
+                Err(e.context("Plugin failed"))
+            }
+        }
+    }
+
+    fn spawn(&self) -> Result<PluginProcess> {
+        debug!("Starting plugin `{}`", self.command);
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let mut child = shell
+            .arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Failed to start plugin `{}`", self.command))?;
+        let stdin = child.stdin.take().context("plugin has no stdin")?;
+        let stdout = child.stdout.take().context("plugin has no stdout")?;
+        Ok(PluginProcess {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout).lines(),
+        })
+    }
+}
+
+impl PluginProcess {
+    async fn exchange(&mut self, line: &str) -> Result<PluginOutput> {
+        self.stdin.write_all(line.as_bytes()).await?;
+        self.stdin.flush().await?;
+        let Some(reply) = self.stdout.next_line().await? else {
+            bail!("it exited");
+        };
+        let reply = reply.trim();
+        if reply.is_empty() {
+            return Ok(PluginOutput::default());
+        }
+        serde_json::from_str(reply).with_context(|| format!("invalid answer `{}`", reply))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use http_tunnel_common::{decode_body, encode_body};
+
+    fn request(uri: &str) -> HttpRequest {
+        let mut request =
+            HttpRequest::new("GET".to_string(), uri.to_string(), "req_1".to_string(), 0);
+        request.headers.insert(
+            "authorization".to_string(),
+            vec!["Bearer secret".to_string()],
+        );
+        request
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_request_and_response() {
+        // Echoes what it gets, minus the phase, with the token and the body scrubbed
+        let plugin = Plugin::new(
+            r#"sed -u -e 's/"phase":"[a-z]*",//' -e 's/Bearer secret/Bearer stub/' -e 's/"body":"[^"]*"/"body":"cmVkYWN0ZWQ="/g'"#
+                .to_string(),
+        );
+        let PluginAction::Forward(forwarded) = plugin.on_request(request("/items")).await.unwrap()
+        else {
+            panic!("expected the request to be forwarded");
+        };
+        assert_eq!(forwarded.uri, "/items");
+        assert_eq!(forwarded.request_id, "req_1");
+        assert_eq!(forwarded.headers["authorization"], ["Bearer stub"]);
+
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = encode_body(b"password=hunter2");
+        let response = plugin.on_response(&forwarded, response).await.unwrap();
+        assert_eq!(response.request_id, "req_1");
+        assert_eq!(decode_body(&response.body).unwrap(), b"redacted");
+    }
+
+    #[tokio::test]
+    async fn test_mock_response_and_empty_answer() {
+        let plugin = Plugin::new(
+            r#"while read -r line; do case "$line" in *'"uri":"/mock"'*) echo '{"response":{"status_code":418,"body":"dGVhcG90"}}';; *) echo '{}';; esac; done"#
+                .to_string(),
+        );
+        let PluginAction::Respond(response) = plugin.on_request(request("/mock")).await.unwrap()
+        else {
+            panic!("expected a mock response");
+        };
+        assert_eq!(response.request_id, "req_1");
+        assert_eq!(response.status_code, 418);
+        assert_eq!(decode_body(&response.body).unwrap(), b"teapot");
+
+        let action = plugin.on_request(request("/real")).await.unwrap();
+        assert!(matches!(action, PluginAction::Forward(request) if request.uri == "/real"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_plugin_restarts() {
+        // Answers once, then exits
+        let plugin = Plugin::new("read -r line; echo '{}'".to_string());
+        plugin.on_request(request("/")).await.unwrap();
+        let error = plugin.on_request(request("/")).await.unwrap_err();
+        assert!(error.to_string().contains("Plugin failed"), "{:#}", error);
+        // A fresh process answers the next call
+        plugin.on_request(request("/")).await.unwrap();
+    }
+}
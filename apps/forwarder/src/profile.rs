@@ -0,0 +1,343 @@
+//! Named connection profiles
+//!
+//! `~/.config/ttf/config.toml` (or `$XDG_CONFIG_HOME/ttf/config.toml`) holds
+//! named profiles so a tunnel can be started with `ttf --profile staging`
+//! instead of repeating its flags. Flags and environment variables given
+//! explicitly override the profile; the profile overrides flag defaults.
+//!
+//! ```toml
+//! default_profile = "dev"
+//!
+//! [profiles.dev]
+//! endpoint = "wss://abc123.execute-api.us-east-1.amazonaws.com/dev"
+//! token = "eyJ..."
+//! port = 3000
+//!
+//! [profiles.staging]
+//! endpoint = "wss://tunnel.example.com"
+//! host = "192.168.1.20"
+//! port = 8080
+//! request_timeout = "10s"
+//! require_headers = ["X-Demo-Key: secret"]
+//! ```
+//!
+//! Unlike `--config`, the profile is read once at startup.
+
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ArgMatches;
+use clap::parser::ValueSource;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::Args;
+use crate::config_file::deserialize_duration;
+use crate::filter::{HeaderRule, parse_reject_status};
+
+/// Settings of one named profile; every key is optional
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    /// WebSocket tunnel endpoint (`--endpoint`)
+    pub endpoint: Option<String>,
+
+    /// Authentication token (`--token`)
+    pub token: Option<String>,
+
+    /// Local service host (`--host`)
+    pub host: Option<String>,
+
+    /// Local service port (`--port`)
+    pub port: Option<u16>,
+
+    /// Connection timeout (`--connect-timeout`)
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub connect_timeout: Option<Duration>,
+
+    /// Local request timeout (`--request-timeout`)
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub request_timeout: Option<Duration>,
+
+    /// Required headers (`--require-header`)
+    pub require_headers: Option<Vec<HeaderRule>>,
+
+    /// Blocked headers (`--block-header`)
+    pub block_headers: Option<Vec<HeaderRule>>,
+
+    /// Status for filtered requests (`--reject-status`)
+    pub reject_status: Option<u16>,
+
+    /// Body for filtered requests (`--reject-body`)
+    pub reject_body: Option<String>,
+}
+
+/// Contents of the profiles file
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfilesFile {
+    /// Profile used when `--profile` isn't given
+    pub default_profile: Option<String>,
+
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl ProfilesFile {
+    /// Parse and validate profiles file contents
+    pub fn parse(contents: &str) -> Result<Self> {
+        let file: Self = toml::from_str(contents)?;
+
+        if let Some(name) = &file.default_profile
+            && !file.profiles.contains_key(name)
+        {
+            bail!("default_profile `{}` is not defined", name);
+        }
+
+        for (name, profile) in &file.profiles {
+            profile
+                .validate()
+                .with_context(|| format!("invalid profile `{}`", name))?;
+        }
+
+        Ok(file)
+    }
+
+    /// Read the profiles file at `path` (None if it doesn't exist)
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        Self::parse(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid profiles file {}", path.display()))
+    }
+
+    /// The profile named `name`, or the default profile if no name is given
+    pub fn select(&self, name: Option<&str>) -> Result<Option<(&str, &Profile)>> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(None);
+        };
+        let (name, profile) = self.profiles.get_key_value(name).ok_or_else(|| {
+            let available: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            anyhow!(
+                "profile `{}` not found (available: {})",
+                name,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            )
+        })?;
+        Ok(Some((name.as_str(), profile)))
+    }
+}
+
+impl Profile {
+    fn validate(&self) -> Result<()> {
+        if let Some(endpoint) = &self.endpoint {
+            let url = url::Url::parse(endpoint)
+                .with_context(|| format!("invalid endpoint `{}`", endpoint))?;
+            if !matches!(url.scheme(), "ws" | "wss") {
+                bail!("endpoint must use ws or wss: `{}`", endpoint);
+            }
+        }
+        if self.port == Some(0) {
+            bail!("port must be greater than zero");
+        }
+        if let Some(status) = self.reject_status {
+            parse_reject_status(&status.to_string()).map_err(anyhow::Error::msg)?;
+        }
+        Ok(())
+    }
+
+    /// Fill in every argument not given explicitly on the command line or via env
+    pub fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+        let unset = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+
+        if let Some(endpoint) = &self.endpoint
+            && unset("endpoint")
+        {
+            args.endpoint = endpoint.clone();
+        }
+        if let Some(token) = &self.token
+            && unset("token")
+        {
+            args.token = Some(token.clone());
+        }
+        if let Some(host) = &self.host
+            && unset("host")
+        {
+            args.host = host.clone();
+        }
+        if let Some(port) = self.port
+            && unset("port")
+        {
+            args.port = vec![port];
+        }
+        if let Some(timeout) = self.connect_timeout
+            && unset("connect_timeout")
+        {
+            args.connect_timeout = timeout;
+        }
+        if let Some(timeout) = self.request_timeout
+            && unset("request_timeout")
+        {
+            args.request_timeout = timeout;
+        }
+        if let Some(rules) = &self.require_headers
+            && unset("require_headers")
+        {
+            args.require_headers = rules.clone();
+        }
+        if let Some(rules) = &self.block_headers
+            && unset("block_headers")
+        {
+            args.block_headers = rules.clone();
+        }
+        if let Some(status) = self.reject_status
+            && unset("reject_status")
+        {
+            args.reject_status = status;
+        }
+        if let Some(body) = &self.reject_body
+            && unset("reject_body")
+        {
+            args.reject_body = body.clone();
+        }
+    }
+}
+
+/// Default location of the profiles file
+pub fn default_profiles_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_dir.join("ttf").join("config.toml"))
+}
+
+/// Apply the selected profile (`--profile`, else the file's default) to `args`
+///
+/// Returns the name of the applied profile. Naming a profile that doesn't
+/// exist, or that isn't in a file that doesn't exist, is an error.
+pub fn apply_profile(args: &mut Args, matches: &ArgMatches) -> Result<Option<String>> {
+    let requested = args.profile.clone();
+    let file = match default_profiles_path() {
+        Some(path) => ProfilesFile::load(&path)?,
+        None => None,
+    };
+    let Some(file) = file else {
+        if let Some(name) = requested {
+            bail!(
+                "profile `{}` requested but no profiles file found at {}",
+                name,
+                default_profiles_path()
+                    .map(|path| path.display().to_string())
+                    .unwrap_or_else(|| "~/.config/ttf/config.toml".to_string())
+            );
+        }
+        return Ok(None);
+    };
+
+    let Some((name, profile)) = file.select(requested.as_deref())? else {
+        return Ok(None);
+    };
+    profile.apply(args, matches);
+    Ok(Some(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches};
+
+    const FILE: &str = r#"
+        default_profile = "dev"
+
+        [profiles.dev]
+        endpoint = "wss://dev.example.com"
+        token = "dev-token"
+        port = 4000
+
+        [profiles.staging]
+        endpoint = "wss://staging.example.com"
+        host = "192.168.1.20"
+        request_timeout = "10s"
+        require_headers = ["X-Demo-Key: secret"]
+    "#;
+
+    fn parse_args(argv: &[&str]) -> (Args, ArgMatches) {
+        let matches = Args::command().try_get_matches_from(argv).unwrap();
+        let args = Args::from_arg_matches(&matches).unwrap();
+        (args, matches)
+    }
+
+    #[test]
+    fn test_select_profile() {
+        let file = ProfilesFile::parse(FILE).unwrap();
+        assert_eq!(file.select(None).unwrap().unwrap().0, "dev");
+        assert_eq!(file.select(Some("staging")).unwrap().unwrap().0, "staging");
+
+        let error = file.select(Some("prod")).unwrap_err().to_string();
+        assert!(error.contains("dev, staging"));
+
+        let no_default = ProfilesFile::parse("[profiles.dev]\nport = 1").unwrap();
+        assert!(no_default.select(None).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cli_args_override_profile() {
+        let file = ProfilesFile::parse(FILE).unwrap();
+        let (_, profile) = file.select(Some("staging")).unwrap().unwrap();
+
+        let (mut args, matches) = parse_args(&["ttf", "--port", "8080"]);
+        profile.apply(&mut args, &matches);
+        assert_eq!(args.endpoint, "wss://staging.example.com");
+        assert_eq!(args.host, "192.168.1.20");
+        assert_eq!(args.port, [8080]);
+        assert_eq!(args.request_timeout, Duration::from_secs(10));
+        assert_eq!(args.require_headers.len(), 1);
+
+        let (mut args, matches) = parse_args(&[
+            "ttf",
+            "--endpoint",
+            "wss://cli.example.com",
+            "--host",
+            "::1",
+        ]);
+        profile.apply(&mut args, &matches);
+        assert_eq!(args.endpoint, "wss://cli.example.com");
+        assert_eq!(args.host, "::1");
+        assert_eq!(args.port, [3000]);
+    }
+
+    #[test]
+    fn test_invalid_files_rejected() {
+        assert!(ProfilesFile::parse(r#"default_profile = "missing""#).is_err());
+        assert!(ProfilesFile::parse("[profiles.a]\nendpoint = \"https://x\"").is_err());
+        assert!(ProfilesFile::parse("[profiles.a]\nport = 0").is_err());
+        assert!(ProfilesFile::parse("[profiles.a]\nreject_status = 200").is_err());
+        assert!(ProfilesFile::parse("[profiles.a]\nunknown = 1").is_err());
+        assert!(ProfilesFile::parse("[profiles.a]\nrequest_timeout = \"soon\"").is_err());
+    }
+
+    #[test]
+    fn test_missing_file_is_not_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(
+            ProfilesFile::load(&dir.path().join("config.toml"))
+                .unwrap()
+                .is_none()
+        );
+    }
+}
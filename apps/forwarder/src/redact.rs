@@ -0,0 +1,319 @@
+//! Scrubbing of sensitive data (`--redact header:Authorization --redact json:user.email`)
+//!
+//! Redaction rules are applied to everything the forwarder keeps or prints
+//! about traffic: log lines, the `--inspect` buffer and the `--har-out` file.
+//! What reaches the local service is never changed. Rules are:
+//!
+//! - `header:NAME`: the values of this header (any case) in requests and responses
+//! - `json:PATH`: a field of JSON bodies, as dot-separated keys; `*` matches any
+//!   key, and arrays are searched element by element (`json:items.card.number`)
+//! - `regex:PATTERN`: every match in URLs, header values, text bodies and logs
+//!
+//! Whatever matches is replaced with `[REDACTED]`.
+
+use http_tunnel_common::{HttpRequest, HttpResponse, decode_body, encode_body};
+use regex::Regex;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What redacted data is replaced with
+pub const REDACTED: &str = "[REDACTED]";
+
+/// One `--redact` rule
+#[derive(Debug, Clone)]
+pub enum RedactRule {
+    Header(String),
+    JsonPath(Vec<String>),
+    Pattern(Regex),
+}
+
+impl FromStr for RedactRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (kind, rule) = value
+            .split_once(':')
+            .ok_or_else(|| format!("expected `header:`, `json:` or `regex:`, got `{}`", value))?;
+        if rule.trim().is_empty() {
+            return Err(format!("`{}` has nothing to redact", value));
+        }
+        match kind.trim() {
+            "header" => Ok(Self::Header(rule.trim().to_ascii_lowercase())),
+            "json" => Ok(Self::JsonPath(
+                rule.trim().split('.').map(str::to_string).collect(),
+            )),
+            "regex" => Regex::new(rule)
+                .map(Self::Pattern)
+                .map_err(|e| format!("invalid pattern `{}`: {}", rule, e)),
+            other => Err(format!(
+                "unknown redaction `{}` (expected header, json or regex)",
+                other
+            )),
+        }
+    }
+}
+
+/// The `--redact` rules, shared by logging, the inspector and the HAR recorder
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    headers: Vec<String>,
+    json_paths: Vec<Vec<String>>,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new(rules: &[RedactRule]) -> Self {
+        let mut redactor = Self::default();
+        for rule in rules {
+            match rule {
+                RedactRule::Header(name) => redactor.headers.push(name.clone()),
+                RedactRule::JsonPath(path) => redactor.json_paths.push(path.clone()),
+                RedactRule::Pattern(pattern) => redactor.patterns.push(pattern.clone()),
+            }
+        }
+        redactor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty() && self.json_paths.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Apply the `regex:` rules to a piece of text
+    pub fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        for pattern in &self.patterns {
+            if let Cow::Owned(replaced) = pattern.replace_all(&text, REDACTED) {
+                text = Cow::Owned(replaced);
+            }
+        }
+        text
+    }
+
+    /// A copy of `request` safe to keep
+    pub fn request<'a>(&self, request: &'a HttpRequest) -> Cow<'a, HttpRequest> {
+        if self.is_empty() {
+            return Cow::Borrowed(request);
+        }
+        let mut request = request.clone();
+        request.uri = self.text(&request.uri).into_owned();
+        self.headers(&mut request.headers);
+        request.body = self.body(&request.body);
+        Cow::Owned(request)
+    }
+
+    /// A copy of `response` safe to keep
+    pub fn response<'a>(&self, response: &'a HttpResponse) -> Cow<'a, HttpResponse> {
+        if self.is_empty() {
+            return Cow::Borrowed(response);
+        }
+        let mut response = response.clone();
+        self.headers(&mut response.headers);
+        response.body = self.body(&response.body);
+        Cow::Owned(response)
+    }
+
+    fn headers(&self, headers: &mut HashMap<String, Vec<String>>) {
+        for (name, values) in headers.iter_mut() {
+            let name = name.to_ascii_lowercase();
+            for value in values.iter_mut() {
+                if self.headers.contains(&name) {
+                    *value = REDACTED.to_string();
+                } else if let Cow::Owned(redacted) = self.text(value) {
+                    *value = redacted;
+                }
+            }
+        }
+    }
+
+    /// Redact a base64-encoded body, returning it encoded
+    fn body(&self, encoded: &str) -> String {
+        if encoded.is_empty() || (self.json_paths.is_empty() && self.patterns.is_empty()) {
+            return encoded.to_string();
+        }
+        let Ok(bytes) = decode_body(encoded) else {
+            return encoded.to_string();
+        };
+        let mut changed = false;
+        let mut bytes = Cow::Borrowed(bytes.as_slice());
+        if !self.json_paths.is_empty()
+            && let Ok(mut json) = serde_json::from_slice::<Value>(&bytes)
+        {
+            for path in &self.json_paths {
+                changed |= redact_json(&mut json, path);
+            }
+            if changed {
+                bytes = Cow::Owned(serde_json::to_vec(&json).unwrap_or_default());
+            }
+        }
+        if let Ok(text) = std::str::from_utf8(&bytes)
+            && let Cow::Owned(redacted) = self.text(text)
+        {
+            changed = true;
+            bytes = Cow::Owned(redacted.into_bytes());
+        }
+        if changed {
+            encode_body(&bytes)
+        } else {
+            encoded.to_string()
+        }
+    }
+}
+
+/// Replace the values at `path` in `json`, returning whether any was found
+fn redact_json(json: &mut Value, path: &[String]) -> bool {
+    let Some((key, rest)) = path.split_first() else {
+        *json = Value::String(REDACTED.to_string());
+        return true;
+    };
+    // Every match is redacted, so don't stop at the first
+    let mut found = false;
+    match json {
+        Value::Array(items) => {
+            for item in items {
+                found |= redact_json(item, path);
+            }
+            found
+        }
+        Value::Object(fields) if key == "*" => {
+            for field in fields.values_mut() {
+                found |= redact_json(field, rest);
+            }
+            found
+        }
+        Value::Object(fields) => fields
+            .get_mut(key)
+            .is_some_and(|field| redact_json(field, rest)),
+        _ => false,
+    }
+}
+
+/// Log writer applying the `regex:` rules to every line before it's printed
+#[derive(Debug, Clone)]
+pub struct RedactingWriter {
+    redactor: Arc<Redactor>,
+    stderr: bool,
+}
+
+impl RedactingWriter {
+    /// Write logs to stdout, or to stderr if `stderr` is set
+    pub fn new(redactor: Arc<Redactor>, stderr: bool) -> Self {
+        Self { redactor, stderr }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RedactingWriter {
+    type Writer = RedactingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Events are formatted first and written in one call, so each write is
+        // a whole line
+        let text = String::from_utf8_lossy(buf);
+        let line = self.redactor.text(&text);
+        if self.stderr {
+            io::stderr().write_all(line.as_bytes())?;
+        } else {
+            io::stdout().write_all(line.as_bytes())?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.stderr {
+            io::stderr().flush()
+        } else {
+            io::stdout().flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(rules: &[&str]) -> Redactor {
+        let rules: Vec<RedactRule> = rules.iter().map(|rule| rule.parse().unwrap()).collect();
+        Redactor::new(&rules)
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert!(matches!(
+            "header:Authorization".parse(),
+            Ok(RedactRule::Header(name)) if name == "authorization"
+        ));
+        assert!(matches!(
+            "json:user.email".parse(),
+            Ok(RedactRule::JsonPath(path)) if path == ["user", "email"]
+        ));
+        assert!("regex:\\d{16}".parse::<RedactRule>().is_ok());
+        for invalid in ["Authorization", "header:", "regex:(", "cookie:session"] {
+            assert!(invalid.parse::<RedactRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_redact_request() {
+        let redactor = redactor(&[
+            "header:authorization",
+            "json:user.email",
+            "json:cards.*",
+            "regex:tok_[a-z0-9]+",
+        ]);
+        let mut request = HttpRequest::new(
+            "POST".to_string(),
+            "/hook?token=tok_abc123".to_string(),
+            "req_1".to_string(),
+            0,
+        );
+        request.headers.insert(
+            "Authorization".to_string(),
+            vec!["Bearer secret".to_string()],
+        );
+        request
+            .headers
+            .insert("x-api-key".to_string(), vec!["tok_xyz".to_string()]);
+        request.body = encode_body(
+            br#"{"user":{"email":"a@example.com","name":"Ann"},"cards":[{"number":"4242"}],"note":"tok_q1"}"#,
+        );
+
+        let redacted = redactor.request(&request);
+        assert_eq!(redacted.uri, "/hook?token=[REDACTED]");
+        assert_eq!(redacted.headers["Authorization"], [REDACTED]);
+        assert_eq!(redacted.headers["x-api-key"], [REDACTED]);
+        let body: Value = serde_json::from_slice(&decode_body(&redacted.body).unwrap()).unwrap();
+        assert_eq!(body["user"]["email"], REDACTED);
+        assert_eq!(body["user"]["name"], "Ann");
+        assert_eq!(body["cards"][0]["number"], REDACTED);
+        assert_eq!(body["note"], REDACTED);
+        // The original is left alone
+        assert_eq!(request.headers["Authorization"], ["Bearer secret"]);
+    }
+
+    #[test]
+    fn test_untouched_without_matches() {
+        let redactor = redactor(&["json:password"]);
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = encode_body(b"{ \"ok\": true }");
+        // Formatting is kept when nothing was redacted
+        assert_eq!(redactor.response(&response).body, response.body);
+        assert!(matches!(
+            Redactor::default().response(&response),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            redactor.text("card 4242424242424242"),
+            "card 4242424242424242"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Resolution of hostname targets
+//!
+//! When the local service is addressed by hostname (a docker service name,
+//! `host.docker.internal`, a LAN device), its IP can change while the tunnel is
+//! up. The resolver pins the last known addresses for the client and, when a
+//! connection to them fails, resolves the name again so the request can be
+//! retried against the new address instead of failing until restart.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// A hostname target and the addresses it resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedTarget {
+    pub host: String,
+    pub addrs: Vec<SocketAddr>,
+}
+
+/// Caches the resolution of the local target across requests
+#[derive(Debug, Default)]
+pub struct TargetResolver {
+    cache: Mutex<Option<(String, ResolvedTarget)>>,
+}
+
+impl TargetResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Addresses for the target of `local_address`, resolving on first use
+    ///
+    /// Returns `None` for IP literals (nothing to resolve) or when resolution
+    /// fails, in which case the HTTP client resolves the name itself.
+    pub async fn resolve(&self, local_address: &str) -> Option<ResolvedTarget> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(address, _)| address == local_address)
+            .map(|(_, target)| target.clone());
+        match cached {
+            Some(target) => Some(target),
+            None => self.lookup(local_address).await,
+        }
+    }
+
+    /// Resolve the target again after a connection failure
+    ///
+    /// Returns the new addresses only if they differ from the cached ones, i.e.
+    /// when retrying is worthwhile.
+    pub async fn refresh(&self, local_address: &str) -> Option<ResolvedTarget> {
+        let previous = self
+            .cache
+            .lock()
+            .unwrap()
+            .take()
+            .filter(|(address, _)| address == local_address)
+            .map(|(_, target)| target);
+        let target = self.lookup(local_address).await?;
+
+        if previous
+            .as_ref()
+            .is_some_and(|previous| previous.addrs == target.addrs)
+        {
+            debug!("{} still resolves to {:?}", target.host, target.addrs);
+            return None;
+        }
+
+        info!(
+            "{} re-resolved to {:?} (was {:?})",
+            target.host,
+            target.addrs,
+            previous.map(|previous| previous.addrs).unwrap_or_default()
+        );
+        Some(target)
+    }
+
+    async fn lookup(&self, local_address: &str) -> Option<ResolvedTarget> {
+        let (host, port) = hostname_target(local_address)?;
+        let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host.as_str(), port)).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                warn!("Failed to resolve {}: {}", host, e);
+                return None;
+            }
+        };
+        if addrs.is_empty() {
+            return None;
+        }
+
+        let target = ResolvedTarget { host, addrs };
+        *self.cache.lock().unwrap() = Some((local_address.to_string(), target.clone()));
+        Some(target)
+    }
+}
+
+/// Host and port of `local_address` if its host is a name rather than an IP
+fn hostname_target(local_address: &str) -> Option<(String, u16)> {
+    let url = url::Url::parse(local_address).ok()?;
+    let url::Host::Domain(host) = url.host()? else {
+        return None;
+    };
+    if host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some((host.to_string(), url.port_or_known_default()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hostname_target() {
+        assert_eq!(
+            hostname_target("http://host.docker.internal:8080"),
+            Some(("host.docker.internal".to_string(), 8080))
+        );
+        assert_eq!(hostname_target("http://api"), Some(("api".to_string(), 80)));
+        assert_eq!(hostname_target("http://127.0.0.1:3000"), None);
+        assert_eq!(hostname_target("http://[::1]:3000"), None);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_caches_and_refresh_detects_changes() {
+        let resolver = TargetResolver::new();
+        assert_eq!(resolver.resolve("http://127.0.0.1:3000").await, None);
+
+        let target = resolver.resolve("http://localhost:3000").await.unwrap();
+        assert_eq!(target.host, "localhost");
+        assert!(target.addrs.iter().all(|addr| addr.port() == 3000));
+
+        // Same addresses: nothing new to retry against
+        assert_eq!(resolver.refresh("http://localhost:3000").await, None);
+
+        // Pretend the name used to point somewhere else
+        *resolver.cache.lock().unwrap() = Some((
+            "http://localhost:3000".to_string(),
+            ResolvedTarget {
+                host: "localhost".to_string(),
+                addrs: vec!["10.0.0.9:3000".parse().unwrap()],
+            },
+        ));
+        assert_eq!(
+            resolver.refresh("http://localhost:3000").await,
+            Some(target)
+        );
+    }
+}
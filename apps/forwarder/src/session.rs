@@ -0,0 +1,245 @@
+//! Recorded sessions (`ttf record session.bin`, `ttf replay session.bin`)
+//!
+//! `ttf record FILE` runs the tunnel like plain `ttf` and appends every request
+//! that comes through it to FILE, as it arrives: the `HttpRequest` protocol
+//! message, MessagePack-encoded, after a `u32` big-endian length. The file
+//! starts with [`MAGIC`]. Requests are stored before header rules and plugins
+//! are applied, so a replay goes through the settings in force at replay time.
+//!
+//! `ttf replay FILE` sends the recorded requests to the local service one after
+//! another, in their original order, and prints what each one got back. That
+//! turns captured webhook traffic into a repeatable fixture. WebSocket
+//! passthrough sessions aren't recorded.
+
+use anyhow::{Context, Result, bail};
+use http_tunnel_common::protocol::{Encoding, decode_binary};
+use http_tunnel_common::{HttpRequest, Message, decode_body};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::warn;
+
+use crate::build_local_request;
+use crate::config_file::ForwardSettings;
+use crate::exit::LocalServiceError;
+
+/// First bytes of a session file
+pub const MAGIC: &[u8; 8] = b"TTFSESS1";
+
+/// Appends tunneled requests to a session file, shared by every tunnel of the process
+#[derive(Debug, Clone)]
+pub struct SessionRecorder {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+}
+
+impl SessionRecorder {
+    /// Start a new session file at `path`, replacing any earlier one
+    pub fn create(path: PathBuf) -> Result<Self> {
+        let mut file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        file.write_all(MAGIC)?;
+        Ok(Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Append a request as it arrived from the tunnel
+    pub fn record(&self, request: &HttpRequest) {
+        let frame = match Encoding::MessagePack.encode(&Message::HttpRequest(request.clone())) {
+            Ok(frame) => frame.into_bytes(),
+            Err(e) => {
+                warn!("Failed to record request {}: {}", request.request_id, e);
+                return;
+            }
+        };
+        let mut record = Vec::with_capacity(4 + frame.len());
+        record.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        record.extend_from_slice(&frame);
+
+        // One write per request, so a crash never leaves half a record behind
+        // more than the last one
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&record) {
+            warn!("Failed to record request to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Read the requests of a session file
+pub fn read_session(path: &Path) -> Result<Vec<HttpRequest>> {
+    let contents =
+        std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let Some(mut rest) = contents.strip_prefix(MAGIC.as_slice()) else {
+        bail!(
+            "{} isn't a session recorded by `ttf record`",
+            path.display()
+        );
+    };
+
+    let mut requests = Vec::new();
+    while !rest.is_empty() {
+        let Some((length, after)) = rest.split_first_chunk::<4>() else {
+            warn!("{} ends with a partial record; ignoring it", path.display());
+            break;
+        };
+        let length = u32::from_be_bytes(*length) as usize;
+        if after.len() < length {
+            warn!("{} ends with a partial record; ignoring it", path.display());
+            break;
+        }
+        let (frame, after) = after.split_at(length);
+        match decode_binary(frame)
+            .with_context(|| format!("Invalid record in {}", path.display()))?
+        {
+            Message::HttpRequest(request) => requests.push(request),
+            other => bail!("Unexpected record in {}: {:?}", path.display(), other),
+        }
+        rest = after;
+    }
+    Ok(requests)
+}
+
+/// Send the recorded requests to the local service and print what they got back
+///
+/// Fails with `LocalServiceError` if any request couldn't be delivered.
+pub async fn replay(path: &Path, settings: &ForwardSettings) -> Result<()> {
+    let requests = read_session(path)?;
+    println!(
+        "Replaying {} requests from {} to {}",
+        requests.len(),
+        path.display(),
+        settings.local_address
+    );
+
+    let mut failed = 0;
+    for mut request in requests {
+        let body = if request.body.is_empty() {
+            None
+        } else {
+            Some(decode_body(&request.body)?)
+        };
+        settings
+            .host_header
+            .forward_public_host(&mut request.headers);
+        settings.request_headers.apply(&mut request.headers);
+
+        let started = Instant::now();
+        let result = build_local_request(&request, settings, settings.request_timeout, None, body)?
+            .send()
+            .await;
+        let elapsed = started.elapsed().as_millis();
+        match result {
+            Ok(response) => println!(
+                "{} {} -> {} ({}ms)",
+                request.method,
+                request.uri,
+                response.status().as_u16(),
+                elapsed
+            ),
+            Err(e) => {
+                failed += 1;
+                println!("{} {} -> failed: {}", request.method, request.uri, e);
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(LocalServiceError(format!(
+            "{} replayed requests couldn't reach {}",
+            failed, settings.local_address
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Config};
+    use clap::Parser;
+    use http_tunnel_common::encode_body;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn request(method: &str, uri: &str, body: &[u8]) -> HttpRequest {
+        let mut request = HttpRequest::new(
+            method.to_string(),
+            uri.to_string(),
+            format!("req_{}", uri),
+            0,
+        );
+        request.body = encode_body(body);
+        request
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.bin");
+        let recorder = SessionRecorder::create(path.clone()).unwrap();
+        recorder.record(&request("POST", "/hook", b"{\"id\":1}"));
+        recorder.record(&request("GET", "/status", b""));
+
+        let requests = read_session(&path).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].uri, "/hook");
+        assert_eq!(decode_body(&requests[0].body).unwrap(), b"{\"id\":1}");
+        assert_eq!(requests[1].method, "GET");
+
+        // A record cut short by a crash is dropped
+        let mut contents = std::fs::read(&path).unwrap();
+        contents.truncate(contents.len() - 3);
+        std::fs::write(&path, contents).unwrap();
+        assert_eq!(read_session(&path).unwrap().len(), 1);
+
+        std::fs::write(&path, b"not a session").unwrap();
+        assert!(read_session(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut seen = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 4096];
+                let n = stream.read(&mut buffer).await.unwrap();
+                let head = String::from_utf8_lossy(&buffer[..n]).to_string();
+                seen.push(head.lines().next().unwrap().to_string());
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            seen
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("session.bin");
+        let recorder = SessionRecorder::create(path.clone()).unwrap();
+        recorder.record(&request("POST", "/hook", b"payload"));
+        recorder.record(&request("DELETE", "/items/1", b""));
+
+        let port = port.to_string();
+        let settings =
+            Config::from_args(Args::parse_from(["ttf", "--port", &port])).forward_settings();
+        replay(&path, &settings).await.unwrap();
+        assert_eq!(
+            server.await.unwrap(),
+            ["POST /hook HTTP/1.1", "DELETE /items/1 HTTP/1.1"]
+        );
+
+        // Nothing listens any more
+        let error = replay(&path, &settings).await.unwrap_err();
+        assert_eq!(
+            crate::exit::exit_code(&error),
+            crate::exit::EXIT_LOCAL_SERVICE
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Session statistics and the exit summary report
+//!
+//! Every forwarded request is recorded here; when the forwarder shuts down the
+//! totals are printed so a testing session can be written up without digging
+//! through logs.
+
+use http_tunnel_common::current_timestamp_millis;
+use http_tunnel_common::models::TunnelAnalytics;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of paths listed in the summary
+const TOP_PATHS: usize = 10;
+
+/// Number of errors kept for the control API
+const RECENT_ERRORS: usize = 20;
+
+/// Counters accumulated over the lifetime of the forwarder process
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    inner: Mutex<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: u64,
+    statuses: BTreeMap<u16, u64>,
+    failures: u64,
+    paths: HashMap<String, u64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    reconnects: u64,
+    overloaded: u64,
+    peak_queue_depth: usize,
+    heartbeat_rtt_total: Duration,
+    heartbeat_pongs: u32,
+    recent_errors: VecDeque<RecentError>,
+}
+
+/// An error seen by the forwarder, kept for the control API
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecentError {
+    /// When it happened (Unix epoch milliseconds)
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// Snapshot of the session, printed on exit
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSummary {
+    pub duration: Duration,
+    pub requests: u64,
+    pub statuses: BTreeMap<u16, u64>,
+    pub failures: u64,
+    pub top_paths: Vec<(String, u64)>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub reconnects: u64,
+    /// Requests turned away because the queue was full
+    pub overloaded: u64,
+    /// Most requests ever waiting for a slot at once
+    pub peak_queue_depth: usize,
+    /// Average heartbeat round trip to the edge, if any pong arrived
+    pub heartbeat_rtt: Option<Duration>,
+}
+
+impl SessionStats {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            inner: Mutex::new(Counters::default()),
+        }
+    }
+
+    /// Record a request that the local service answered
+    pub fn record_response(&self, uri: &str, status_code: u16, bytes_in: u64, bytes_out: u64) {
+        let mut counters = self.lock();
+        counters.record_request(uri, bytes_in);
+        *counters.statuses.entry(status_code).or_default() += 1;
+        counters.bytes_out += bytes_out;
+    }
+
+    /// Record a request the local service couldn't answer (refused, timed out, ...)
+    pub fn record_failure(&self, uri: &str, bytes_in: u64) {
+        let mut counters = self.lock();
+        counters.record_request(uri, bytes_in);
+        counters.failures += 1;
+    }
+
+    /// Record a reconnect after the tunnel dropped
+    pub fn record_reconnect(&self) {
+        self.lock().reconnects += 1;
+    }
+
+    /// Record a request turned away by the concurrency limit
+    pub fn record_overload(&self) {
+        self.lock().overloaded += 1;
+    }
+
+    /// Record how many requests are waiting for a slot
+    pub fn record_queue_depth(&self, depth: usize) {
+        let mut counters = self.lock();
+        counters.peak_queue_depth = counters.peak_queue_depth.max(depth);
+    }
+
+    /// Record the round-trip time of an answered heartbeat
+    pub fn record_heartbeat_rtt(&self, rtt: Duration) {
+        let mut counters = self.lock();
+        counters.heartbeat_rtt_total += rtt;
+        counters.heartbeat_pongs += 1;
+    }
+
+    /// Remember an error, dropping the oldest beyond the last few
+    pub fn record_error(&self, message: &str) {
+        let mut counters = self.lock();
+        if counters.recent_errors.len() == RECENT_ERRORS {
+            counters.recent_errors.pop_front();
+        }
+        counters.recent_errors.push_back(RecentError {
+            timestamp: current_timestamp_millis(),
+            message: message.to_string(),
+        });
+    }
+
+    /// The last few errors, newest first
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.lock().recent_errors.iter().rev().cloned().collect()
+    }
+
+    /// Snapshot the current totals
+    pub fn summary(&self) -> SessionSummary {
+        let counters = self.lock();
+
+        let mut top_paths: Vec<(String, u64)> = counters
+            .paths
+            .iter()
+            .map(|(path, count)| (path.clone(), *count))
+            .collect();
+        top_paths.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top_paths.truncate(TOP_PATHS);
+
+        SessionSummary {
+            duration: self.started.elapsed(),
+            requests: counters.requests,
+            statuses: counters.statuses.clone(),
+            failures: counters.failures,
+            top_paths,
+            bytes_in: counters.bytes_in,
+            bytes_out: counters.bytes_out,
+            reconnects: counters.reconnects,
+            overloaded: counters.overloaded,
+            peak_queue_depth: counters.peak_queue_depth,
+            heartbeat_rtt: (counters.heartbeat_pongs > 0)
+                .then(|| counters.heartbeat_rtt_total / counters.heartbeat_pongs),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Counters {
+    fn record_request(&mut self, uri: &str, bytes_in: u64) {
+        self.requests += 1;
+        self.bytes_in += bytes_in;
+
+        // Group by path; query strings would make every webhook delivery unique
+        let path = uri.split('?').next().unwrap_or(uri);
+        *self.paths.entry(path.to_string()).or_default() += 1;
+    }
+}
+
+impl fmt::Display for SessionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duration = Duration::from_secs(self.duration.as_secs());
+
+        writeln!(f, "Session summary")?;
+        writeln!(
+            f,
+            "  Duration:     {}",
+            humantime::format_duration(duration)
+        )?;
+        writeln!(f, "  Requests:     {}", self.requests)?;
+        writeln!(
+            f,
+            "  Transferred:  {} in, {} out",
+            format_bytes(self.bytes_in),
+            format_bytes(self.bytes_out)
+        )?;
+        writeln!(f, "  Reconnects:   {}", self.reconnects)?;
+        if let Some(rtt) = self.heartbeat_rtt {
+            writeln!(
+                f,
+                "  Heartbeat:    {} ms average round trip",
+                rtt.as_millis()
+            )?;
+        }
+        if self.peak_queue_depth > 0 || self.overloaded > 0 {
+            writeln!(
+                f,
+                "  Queued:       peak {}, {} turned away",
+                self.peak_queue_depth, self.overloaded
+            )?;
+        }
+
+        if !self.statuses.is_empty() || self.failures > 0 {
+            writeln!(f, "  Status codes:")?;
+            for (status, count) in &self.statuses {
+                writeln!(f, "    {:<8}{}", status, count)?;
+            }
+            if self.failures > 0 {
+                writeln!(f, "    {:<8}{}", "failed", self.failures)?;
+            }
+        }
+
+        if !self.top_paths.is_empty() {
+            writeln!(f, "  Top paths:")?;
+            for (path, count) in &self.top_paths {
+                writeln!(f, "    {:>6}  {}", count, path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// One-line description of the tunnel's lifetime totals reported by the handler
+pub fn describe_analytics(analytics: &TunnelAnalytics) -> String {
+    let errors: u64 = analytics
+        .status_codes
+        .iter()
+        .filter(|(code, _)| code.starts_with('5'))
+        .map(|(_, n)| n)
+        .sum();
+    let latency = |ms: Option<u64>| ms.map_or_else(|| "-".to_string(), |ms| format!("{} ms", ms));
+    format!(
+        "{} requests ({} 5xx), {} in, {} out, p50 {}, p95 {}",
+        analytics.requests,
+        errors,
+        format_bytes(analytics.bytes_in),
+        format_bytes(analytics.bytes_out),
+        latency(analytics.p50_ms),
+        latency(analytics.p95_ms)
+    )
+}
+
+/// Format a byte count with a binary unit suffix
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64;
+    let mut unit = "B";
+    for next in UNITS {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary_aggregates_requests() {
+        let stats = SessionStats::new();
+        stats.record_response("/webhook?id=1", 200, 100, 20);
+        stats.record_response("/webhook?id=2", 200, 100, 20);
+        stats.record_response("/health", 404, 0, 9);
+        stats.record_failure("/webhook", 50);
+        stats.record_reconnect();
+
+        let summary = stats.summary();
+        assert_eq!(summary.requests, 4);
+        assert_eq!(summary.statuses.get(&200), Some(&2));
+        assert_eq!(summary.statuses.get(&404), Some(&1));
+        assert_eq!(summary.failures, 1);
+        assert_eq!(summary.bytes_in, 250);
+        assert_eq!(summary.bytes_out, 49);
+        assert_eq!(summary.reconnects, 1);
+        assert_eq!(
+            summary.top_paths,
+            vec![("/webhook".to_string(), 3), ("/health".to_string(), 1)]
+        );
+
+        let report = summary.to_string();
+        assert!(report.contains("Requests:     4"));
+        assert!(report.contains("failed  1"));
+        assert!(!report.contains("Queued"));
+        assert!(!report.contains("Heartbeat"));
+    }
+
+    #[test]
+    fn test_summary_averages_heartbeat_rtt() {
+        let stats = SessionStats::new();
+        stats.record_heartbeat_rtt(Duration::from_millis(30));
+        stats.record_heartbeat_rtt(Duration::from_millis(50));
+
+        let summary = stats.summary();
+        assert_eq!(summary.heartbeat_rtt, Some(Duration::from_millis(40)));
+        assert!(summary.to_string().contains("Heartbeat:    40 ms"));
+    }
+
+    #[test]
+    fn test_summary_reports_queueing() {
+        let stats = SessionStats::new();
+        stats.record_queue_depth(3);
+        stats.record_queue_depth(1);
+        stats.record_overload();
+
+        let summary = stats.summary();
+        assert_eq!(summary.peak_queue_depth, 3);
+        assert_eq!(summary.overloaded, 1);
+        assert!(
+            summary
+                .to_string()
+                .contains("Queued:       peak 3, 1 turned away")
+        );
+    }
+
+    #[test]
+    fn test_recent_errors_are_limited() {
+        let stats = SessionStats::new();
+        for i in 0..(RECENT_ERRORS + 5) {
+            stats.record_error(&format!("error {}", i));
+        }
+        let errors = stats.recent_errors();
+        assert_eq!(errors.len(), RECENT_ERRORS);
+        assert_eq!(errors[0].message, format!("error {}", RECENT_ERRORS + 4));
+    }
+
+    #[test]
+    fn test_top_paths_are_limited() {
+        let stats = SessionStats::new();
+        for i in 0..(TOP_PATHS + 5) {
+            stats.record_response(&format!("/path/{}", i), 200, 0, 0);
+        }
+        assert_eq!(stats.summary().top_paths.len(), TOP_PATHS);
+    }
+
+    #[test]
+    fn test_describe_analytics() {
+        let analytics = TunnelAnalytics {
+            tunnel_id: "abc123def456".to_string(),
+            requests: 12,
+            bytes_in: 100,
+            bytes_out: 4096,
+            status_codes: [("200", 9), ("502", 2), ("504", 1)]
+                .map(|(code, n)| (code.to_string(), n))
+                .into(),
+            p50_ms: Some(50),
+            p95_ms: None,
+        };
+        assert_eq!(
+            describe_analytics(&analytics),
+            "12 requests (3 5xx), 100 B in, 4.0 KiB out, p50 50 ms, p95 -"
+        );
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(2048), "2.0 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 + 512 * 1024), "5.5 MiB");
+    }
+}
@@ -0,0 +1,161 @@
+//! Tokens for the tunnel connection, and refreshing them when they're refused
+//!
+//! The token comes from `--token`, from `--token-command` (a shell command
+//! printing one on stdout) or from the saved `ttf login`. When the endpoint
+//! answers the WebSocket upgrade with 401 or 403, the token is dropped and a
+//! fresh one fetched: the command runs again, or the login's refresh token is
+//! exchanged for a new access token. Without a way to get another token, or
+//! when the new one is refused as well, the forwarder stops instead of
+//! retrying forever.
+
+use anyhow::{Context, Result, bail};
+use http_tunnel_common::TunnelError;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::oauth::OAuthSession;
+
+/// How long `--token-command` may take
+const TOKEN_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where fresh tokens come from
+#[derive(Debug, Clone)]
+pub enum TokenRefresh {
+    /// Shell command printing a token
+    Command(String),
+    /// Saved `ttf login`
+    OAuth(Arc<OAuthSession>),
+}
+
+/// Hands out the token for each connection attempt
+#[derive(Debug)]
+pub struct TokenSource {
+    /// Token from `--token` or the last `--token-command` run
+    current: Mutex<Option<String>>,
+    refresh: Option<TokenRefresh>,
+}
+
+impl TokenSource {
+    pub fn new(token: Option<String>, refresh: Option<TokenRefresh>) -> Self {
+        Self {
+            current: Mutex::new(token),
+            refresh,
+        }
+    }
+
+    /// Whether a refused token can be replaced
+    pub fn can_refresh(&self) -> bool {
+        self.refresh.is_some()
+    }
+
+    /// Token for the next connection, if the tunnel uses one
+    ///
+    /// Failing to get one is fatal (`TunnelError::AuthFailed`), except when the
+    /// OAuth issuer can't be reached.
+    pub async fn token(&self) -> Result<Option<String>> {
+        let mut current = self.current.lock().await;
+        if let Some(token) = &*current {
+            return Ok(Some(token.clone()));
+        }
+        match &self.refresh {
+            None => Ok(None),
+            Some(TokenRefresh::Command(command)) => {
+                let token = run_token_command(command).await.map_err(|e| {
+                    TunnelError::AuthFailed(format!("--token-command failed: {:#}", e))
+                })?;
+                *current = Some(token.clone());
+                Ok(Some(token))
+            }
+            // The session keeps the access token until it's about to expire
+            Some(TokenRefresh::OAuth(session)) => session.access_token().await.map(Some),
+        }
+    }
+
+    /// Forget the refused token, so the next connection fetches a fresh one
+    pub async fn refused(&self) {
+        *self.current.lock().await = None;
+        if let Some(TokenRefresh::OAuth(session)) = &self.refresh {
+            session.expire().await;
+        }
+    }
+}
+
+/// Run `command` through the shell and read the token from its stdout
+async fn run_token_command(command: &str) -> Result<String> {
+    debug!("Running the token command");
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let output = tokio::time::timeout(
+        TOKEN_COMMAND_TIMEOUT,
+        shell.arg(command).kill_on_drop(true).output(),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("timed out after {:?}", TOKEN_COMMAND_TIMEOUT))?
+    .context("couldn't run it")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!("{} {}", output.status, stderr.trim());
+    }
+    let token = String::from_utf8(output.stdout)
+        .context("it printed something other than text")?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        bail!("it printed no token");
+    }
+    Ok(token)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_command() {
+        let source = TokenSource::new(
+            Some("expired".to_string()),
+            Some(TokenRefresh::Command("echo ' fresh-token '".to_string())),
+        );
+        assert_eq!(source.token().await.unwrap().as_deref(), Some("expired"));
+        source.refused().await;
+        assert_eq!(
+            source.token().await.unwrap().as_deref(),
+            Some("fresh-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failing_token_command_is_fatal() {
+        for command in ["echo nope >&2; exit 3", "true"] {
+            let source = TokenSource::new(None, Some(TokenRefresh::Command(command.to_string())));
+            let error = source.token().await.unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<TunnelError>(),
+                    Some(TunnelError::AuthFailed(_))
+                ),
+                "{:#}",
+                error
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_token_cannot_refresh() {
+        let source = TokenSource::new(Some("static".to_string()), None);
+        assert!(!source.can_refresh());
+        source.refused().await;
+        assert_eq!(source.token().await.unwrap(), None);
+    }
+}
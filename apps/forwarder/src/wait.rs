@@ -0,0 +1,202 @@
+//! Startup gating on the local service (`--wait-for-local`)
+//!
+//! Started next to the app (e.g. by `docker compose up`), the forwarder would
+//! announce the public URL before the app listens, and the first visitors get
+//! 503s. With `--wait-for-local` it first waits until the local service accepts
+//! connections, or with `--wait-for-local /healthz` until that path answers
+//! with a 2xx or 3xx, and only then opens the tunnel.
+
+use anyhow::Result;
+use http_tunnel_common::HttpRequest;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::build_local_request;
+use crate::config_file::ForwardSettings;
+use crate::exit::LocalServiceError;
+
+/// Pause between probes
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long one probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How to tell the local service is up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaitForLocal {
+    /// Health path to GET; without one, accepting a connection is enough
+    pub path: Option<String>,
+    /// Give up after this long (wait forever if None)
+    pub timeout: Option<Duration>,
+}
+
+/// Parse the `--wait-for-local` value: nothing, or a path starting with `/`
+pub fn parse_health_path(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.starts_with('/') {
+        Ok(s.to_string())
+    } else {
+        Err(format!("expected a path starting with `/`, got `{}`", s))
+    }
+}
+
+/// Wait until the local service of `settings` is up
+///
+/// Fails with `LocalServiceError` once the timeout passes.
+pub async fn wait_for_local(settings: &ForwardSettings, wait: &WaitForLocal) -> Result<()> {
+    let started = Instant::now();
+    let target = match &wait.path {
+        Some(path) => format!("{}{}", settings.local_address, path),
+        None => settings.local_address.clone(),
+    };
+    info!("Waiting for the local service at {}", target);
+
+    loop {
+        match probe(settings, wait.path.as_deref()).await {
+            Ok(()) => {
+                info!(
+                    "Local service is up after {:.1}s",
+                    started.elapsed().as_secs_f64()
+                );
+                return Ok(());
+            }
+            Err(e) => debug!("Local service not up yet: {}", e),
+        }
+        if let Some(timeout) = wait.timeout
+            && started.elapsed() >= timeout
+        {
+            return Err(LocalServiceError(format!(
+                "The local service at {} wasn't up within {}",
+                target,
+                humantime::format_duration(timeout)
+            ))
+            .into());
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+/// One probe: a connection, or a GET of the health path
+async fn probe(settings: &ForwardSettings, path: Option<&str>) -> Result<(), String> {
+    let Some(path) = path else {
+        return connect(settings).await;
+    };
+    let request = HttpRequest::new(
+        "GET".to_string(),
+        path.to_string(),
+        "wait-for-local".to_string(),
+        0,
+    );
+    let response = build_local_request(&request, settings, PROBE_TIMEOUT, None, None)
+        .map_err(|e| e.to_string())?
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    if status.is_success() || status.is_redirection() {
+        Ok(())
+    } else {
+        Err(format!("{} answered {}", path, status))
+    }
+}
+
+async fn connect(settings: &ForwardSettings) -> Result<(), String> {
+    #[cfg(unix)]
+    if let Some(socket) = &settings.unix_socket {
+        return tokio::time::timeout(PROBE_TIMEOUT, tokio::net::UnixStream::connect(socket))
+            .await
+            .map_err(|_| "timed out".to_string())?
+            .map(drop)
+            .map_err(|e| e.to_string());
+    }
+    let url = url::Url::parse(&settings.local_address).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("no host")?;
+    let port = url.port_or_known_default().ok_or("no port")?;
+    // IPv6 literals keep their brackets, as connect expects
+    tokio::time::timeout(
+        PROBE_TIMEOUT,
+        tokio::net::TcpStream::connect(format!("{}:{}", host, port)),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map(drop)
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Args, Config};
+    use clap::Parser;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn settings(address: std::net::SocketAddr) -> ForwardSettings {
+        let mut settings = Config::from_args(Args::parse_from(["ttf"])).forward_settings();
+        settings.local_address = format!("http://{}", address);
+        settings
+    }
+
+    #[tokio::test]
+    async fn test_waits_until_listening() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let wait = WaitForLocal {
+            path: None,
+            timeout: Some(Duration::from_secs(5)),
+        };
+        // The app starts listening a moment after the forwarder
+        let app = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            let _ = listener.accept().await;
+        });
+
+        wait_for_local(&settings(addr), &wait).await.unwrap();
+        app.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_health_path_must_succeed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // Starting up, then healthy
+            for status in ["503 Service Unavailable", "200 OK"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let n = stream.read(&mut buffer).await.unwrap();
+                assert!(buffer[..n].starts_with(b"GET /healthz "));
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        let wait = WaitForLocal {
+            path: Some("/healthz".to_string()),
+            timeout: Some(Duration::from_secs(5)),
+        };
+        wait_for_local(&settings(addr), &wait).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_timeout() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let wait = WaitForLocal {
+            path: None,
+            timeout: Some(Duration::from_millis(200)),
+        };
+        let error = wait_for_local(&settings(addr), &wait).await.unwrap_err();
+        assert_eq!(
+            crate::exit::exit_code(&error),
+            crate::exit::EXIT_LOCAL_SERVICE
+        );
+        assert!(error.to_string().contains("wasn't up within"), "{}", error);
+    }
+}
@@ -0,0 +1,299 @@
+//! WebSocket passthrough to the local service
+//!
+//! When a public client opens a WebSocket on the tunnel the handler sends
+//! `WsOpen`; the forwarder opens a matching WebSocket to the local service and
+//! relays messages both ways as `WsFrame` until either side closes. Sessions
+//! live as long as the tunnel connection that opened them.
+
+use anyhow::{Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use http_tunnel_common::{Message, constants::BODY_CHUNK_SIZE_BYTES, decode_body, encode_body};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tracing::{debug, info, warn};
+
+/// Messages queued for the local service per session
+const SESSION_QUEUE_SIZE: usize = 32;
+
+/// Largest message relayed to the client (API Gateway frames are 32 KB including the envelope)
+const MAX_MESSAGE_BYTES: usize = BODY_CHUNK_SIZE_BYTES;
+
+/// How a session ended, as reported to the handler
+type CloseReason = Option<(u16, String)>;
+
+/// Open passthrough sessions, keyed by session ID
+#[derive(Debug, Default)]
+pub struct WsSessions {
+    sessions: Mutex<HashMap<String, mpsc::Sender<WsMessage>>>,
+}
+
+impl WsSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect to `url` on the local service and start relaying for `session_id`
+    pub fn open(
+        self: &Arc<Self>,
+        session_id: String,
+        url: String,
+        headers: HashMap<String, Vec<String>>,
+        outgoing_tx: mpsc::Sender<WsMessage>,
+    ) {
+        let (frames_tx, frames_rx) = mpsc::channel(SESSION_QUEUE_SIZE);
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(session_id.clone(), frames_tx);
+
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            info!("WebSocket session {} -> {}", session_id, url);
+            let close = match relay(&session_id, &url, &headers, &outgoing_tx, frames_rx).await {
+                Ok(close) => close,
+                Err(e) => {
+                    warn!("WebSocket session {} failed: {:#}", session_id, e);
+                    Some((1011, "Local WebSocket unavailable".to_string()))
+                }
+            };
+            sessions.sessions.lock().unwrap().remove(&session_id);
+
+            if let Some((code, reason)) = close {
+                let message = Message::WsClose {
+                    session_id: session_id.clone(),
+                    code: Some(code),
+                    reason: (!reason.is_empty()).then_some(reason),
+                };
+                if let Err(e) = send_message(&outgoing_tx, &message).await {
+                    debug!("Failed to report closed session {}: {}", session_id, e);
+                }
+            }
+            debug!("WebSocket session {} ended", session_id);
+        });
+    }
+
+    /// Deliver a message from the public client to the local service
+    pub async fn frame(&self, session_id: &str, data: String, binary: bool) -> Result<()> {
+        let Some(frames_tx) = self.sessions.lock().unwrap().get(session_id).cloned() else {
+            debug!("Dropping frame for unknown session {}", session_id);
+            return Ok(());
+        };
+        let message = if binary {
+            WsMessage::Binary(decode_body(&data)?.into())
+        } else {
+            WsMessage::Text(data.into())
+        };
+        // A full queue means the local service isn't keeping up: wait rather than drop
+        if frames_tx.send(message).await.is_err() {
+            debug!("Session {} closed before frame was delivered", session_id);
+        }
+        Ok(())
+    }
+
+    /// The public client went away: close the local WebSocket
+    pub fn close(&self, session_id: &str) {
+        // Dropping the sender ends the relay loop
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    /// Close every session (the tunnel connection dropped)
+    pub fn close_all(&self) {
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+/// URL of `path` on the local service, with the scheme switched to ws/wss
+pub fn local_websocket_url(local_address: &str, path: &str) -> String {
+    let base = local_address.trim_end_matches('/');
+    let base = if let Some(rest) = base.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = base.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else {
+        base.to_string()
+    };
+    format!("{}{}", base, path)
+}
+
+/// Relay messages until one side closes, returning the close to report to the handler
+///
+/// Returns `None` when the public client closed the session (nothing to report).
+async fn relay(
+    session_id: &str,
+    url: &str,
+    headers: &HashMap<String, Vec<String>>,
+    outgoing_tx: &mpsc::Sender<WsMessage>,
+    mut frames_rx: mpsc::Receiver<WsMessage>,
+) -> Result<CloseReason> {
+    let mut request = url
+        .into_client_request()
+        .with_context(|| format!("Invalid local WebSocket URL {}", url))?;
+    for (name, values) in headers {
+        if let (Ok(name), Some(Ok(value))) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            values.first().map(|value| HeaderValue::from_str(value)),
+        ) {
+            request.headers_mut().insert(name, value);
+        }
+    }
+
+    let (stream, _) = connect_async(request)
+        .await
+        .with_context(|| format!("Failed to connect to {}", url))?;
+    let (mut write, mut read) = stream.split();
+
+    loop {
+        tokio::select! {
+            frame = frames_rx.recv() => match frame {
+                Some(message) => write.send(message).await?,
+                None => {
+                    let _ = write.send(WsMessage::Close(None)).await;
+                    return Ok(None);
+                }
+            },
+            message = read.next() => {
+                let (data, binary) = match message {
+                    Some(Ok(WsMessage::Text(text))) => (text.to_string(), false),
+                    Some(Ok(WsMessage::Binary(bytes))) => (encode_body(&bytes), true),
+                    Some(Ok(WsMessage::Close(frame))) => {
+                        return Ok(Some(frame.map_or((1000, String::new()), |frame| {
+                            (u16::from(frame.code), frame.reason.to_string())
+                        })));
+                    }
+                    // Pings are answered by tungstenite
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(Some((1001, "Local service went away".to_string()))),
+                };
+
+                if data.len() > MAX_MESSAGE_BYTES {
+                    warn!(
+                        "Closing session {}: {} byte message exceeds the {} byte limit",
+                        session_id,
+                        data.len(),
+                        MAX_MESSAGE_BYTES
+                    );
+                    let _ = write
+                        .send(WsMessage::Close(Some(CloseFrame {
+                            code: CloseCode::Size,
+                            reason: "Message too big".into(),
+                        })))
+                        .await;
+                    return Ok(Some((1009, "Message too big".to_string())));
+                }
+
+                let frame = Message::WsFrame {
+                    session_id: session_id.to_string(),
+                    data,
+                    binary,
+                };
+                send_message(outgoing_tx, &frame).await?;
+            }
+        }
+    }
+}
+
+async fn send_message(outgoing_tx: &mpsc::Sender<WsMessage>, message: &Message) -> Result<()> {
+    let json = serde_json::to_string(message)?;
+    outgoing_tx
+        .send(WsMessage::Text(json.into()))
+        .await
+        .context("Tunnel connection closed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_local_websocket_url() {
+        assert_eq!(
+            local_websocket_url("http://127.0.0.1:3000", "/ws?room=1"),
+            "ws://127.0.0.1:3000/ws?room=1"
+        );
+        assert_eq!(
+            local_websocket_url("https://api.local/", "/socket"),
+            "wss://api.local/socket"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_relays_both_ways() {
+        // Local echo server
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(message)) = ws.next().await {
+                if message.is_text() || message.is_binary() {
+                    ws.send(message).await.unwrap();
+                }
+            }
+        });
+
+        let sessions = Arc::new(WsSessions::new());
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(10);
+        sessions.open(
+            "sess_1".to_string(),
+            format!("ws://{}/echo", addr),
+            HashMap::new(),
+            outgoing_tx,
+        );
+
+        sessions
+            .frame("sess_1", "hello".to_string(), false)
+            .await
+            .unwrap();
+        let WsMessage::Text(json) = outgoing_rx.recv().await.unwrap() else {
+            panic!("Expected text message");
+        };
+        let message: Message = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            message,
+            Message::WsFrame { session_id, data, binary: false }
+                if session_id == "sess_1" && data == "hello"
+        ));
+
+        // Closing from the client side doesn't echo a close back
+        sessions.close("sess_1");
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(200), outgoing_rx.recv())
+                .await
+                .map_or(true, |message| message.is_none())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_local_service_reports_close() {
+        let sessions = Arc::new(WsSessions::new());
+        let (outgoing_tx, mut outgoing_rx) = mpsc::channel(10);
+        sessions.open(
+            "sess_2".to_string(),
+            "ws://127.0.0.1:1/ws".to_string(),
+            HashMap::new(),
+            outgoing_tx,
+        );
+
+        let WsMessage::Text(json) = outgoing_rx.recv().await.unwrap() else {
+            panic!("Expected text message");
+        };
+        let message: Message = serde_json::from_str(&json).unwrap();
+        assert!(matches!(
+            message,
+            Message::WsClose {
+                code: Some(1011),
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+//! Activity-based TTL extension for connection items
+//!
+//! Connection items expire CONNECTION_TTL_SECS after `$connect`. Tunnels serving
+//! traffic push that back: forwarding a request to an agent, or receiving a
+//! response from it, sets the item's TTL to CONNECTION_TTL_SECS from now and its
+//! `lastActivityAt` to now (read by the idle sweep, see
+//! [`crate::handlers::idle`]). Writes are throttled to one per connection per
+//! TTL_REFRESH_INTERVAL_SECS in each container. Heartbeats don't count, so idle
+//! tunnels still expire.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::constants::{CONNECTION_TTL_SECS, TTL_REFRESH_INTERVAL_SECS};
+use http_tunnel_common::utils::{calculate_ttl, current_timestamp_secs};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::SharedClients;
+use crate::store::TunnelStore;
+
+/// Limits how often a container refreshes each connection's TTL
+#[derive(Debug, Default)]
+pub struct ActivityGate {
+    last_refreshed: Mutex<HashMap<String, Instant>>,
+}
+
+impl ActivityGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true (and starts a new interval) if `connection_id` is due for a refresh
+    pub fn should_refresh(&self, connection_id: &str, now: Instant) -> bool {
+        let interval = Duration::from_secs(TTL_REFRESH_INTERVAL_SECS);
+        let mut last_refreshed = self
+            .last_refreshed
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        match last_refreshed.get(connection_id) {
+            Some(last) if now.duration_since(*last) < interval => false,
+            _ => {
+                // Entries older than the interval carry no information; drop them
+                last_refreshed.retain(|_, last| now.duration_since(*last) < interval);
+                last_refreshed.insert(connection_id.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Note traffic on a connection, extending its TTL if it's due
+///
+/// Failures are only logged: the connection keeps working until its current TTL.
+pub async fn touch_connection(clients: &SharedClients, connection_id: &str) {
+    if !clients
+        .activity
+        .should_refresh(connection_id, Instant::now())
+    {
+        return;
+    }
+    match clients.store.touch_connection(connection_id).await {
+        Ok(()) => debug!("Extended TTL of connection {}", connection_id),
+        Err(e) => warn!(
+            "Failed to extend TTL of connection {}: {:#}",
+            connection_id, e
+        ),
+    }
+}
+
+/// Extend a connection's TTL in the connections table
+pub async fn record_activity(client: &DynamoDbClient, connection_id: &str) -> Result<()> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let update = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()));
+    set_activity(update)
+        .send()
+        .await
+        .context("Failed to update connection TTL")?;
+
+    Ok(())
+}
+
+/// Extend the TTL of the connection item `update` targets
+pub(crate) fn set_activity(update: UpdateItemFluentBuilder) -> UpdateItemFluentBuilder {
+    update
+        .update_expression("SET #ttl = :ttl, lastActivityAt = :now")
+        // Don't resurrect a connection deleted by $disconnect
+        .condition_expression("attribute_exists(connectionId)")
+        .expression_attribute_names("#ttl", "ttl")
+        .expression_attribute_values(
+            ":ttl",
+            AttributeValue::N(calculate_ttl(CONNECTION_TTL_SECS).to_string()),
+        )
+        .expression_attribute_values(
+            ":now",
+            AttributeValue::N(current_timestamp_secs().to_string()),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_gate_limits_refreshes() {
+        let gate = ActivityGate::new();
+        let start = Instant::now();
+        let interval = Duration::from_secs(TTL_REFRESH_INTERVAL_SECS);
+
+        assert!(gate.should_refresh("conn_1", start));
+        assert!(!gate.should_refresh("conn_1", start + Duration::from_secs(1)));
+        assert!(gate.should_refresh("conn_2", start + Duration::from_secs(1)));
+        assert!(gate.should_refresh("conn_1", start + interval));
+    }
+}
@@ -0,0 +1,258 @@
+//! Per-tunnel slow-request and error-rate alerting
+//!
+//! Agents opt in by sending alert thresholds in their Ready message. After a
+//! request has been metered, the forwarding path re-evaluates the tunnel's rolling
+//! statistics (at most once a minute per container) and, when a threshold is
+//! exceeded, publishes a `TunnelAlert` event to EventBridge. A conditional write on
+//! the connection item keeps containers from alerting more than once per cooldown.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::{ALERT_COOLDOWN_SECS, ALERT_EVALUATION_INTERVAL_SECS};
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::AlertBreach;
+use http_tunnel_common::utils::current_timestamp_secs;
+use serde_json::{Value, json};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::{SharedClients, stats};
+
+/// EventBridge source for tunnel alerts
+pub const ALERT_EVENT_SOURCE: &str = "http-tunnel.alerts";
+
+/// EventBridge detail type for tunnel alerts
+pub const ALERT_DETAIL_TYPE: &str = "TunnelAlert";
+
+/// Limits how often a container re-evaluates each tunnel's thresholds
+#[derive(Debug, Default)]
+pub struct AlertGate {
+    last_evaluated: Mutex<HashMap<String, Instant>>,
+}
+
+impl AlertGate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true (and starts a new interval) if `tunnel_id` is due for evaluation
+    pub fn should_evaluate(&self, tunnel_id: &str, now: Instant) -> bool {
+        let interval = Duration::from_secs(ALERT_EVALUATION_INTERVAL_SECS);
+        let mut last_evaluated = self
+            .last_evaluated
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+
+        match last_evaluated.get(tunnel_id) {
+            Some(last) if now.duration_since(*last) < interval => false,
+            _ => {
+                // Entries older than the interval carry no information; drop them
+                last_evaluated.retain(|_, last| now.duration_since(*last) < interval);
+                last_evaluated.insert(tunnel_id.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// Evaluate the tunnel's alert thresholds and publish an alert if any are breached
+///
+/// Does nothing when the agent didn't request alerts or stats are disabled.
+pub async fn evaluate_tunnel_alerts(
+    clients: &SharedClients,
+    metadata: &ConnectionMetadata,
+) -> Result<()> {
+    let Some(thresholds) = metadata.options.alerts.as_ref() else {
+        return Ok(());
+    };
+    if !thresholds.is_enabled()
+        || !stats::is_stats_enabled()
+        || !clients
+            .alerts
+            .should_evaluate(&metadata.tunnel_id, Instant::now())
+    {
+        return Ok(());
+    }
+
+    let Some(window) = stats::load_tunnel_stats(
+        &clients.dynamodb,
+        &metadata.tunnel_id,
+        &[thresholds.window_secs],
+    )
+    .await?
+    .pop() else {
+        return Ok(());
+    };
+
+    let breaches = thresholds.breaches(&window);
+    if breaches.is_empty() {
+        return Ok(());
+    }
+
+    if !claim_alert_cooldown(&clients.dynamodb, &metadata.connection_id).await? {
+        debug!(
+            "Tunnel {} breached thresholds but was alerted recently",
+            metadata.tunnel_id
+        );
+        return Ok(());
+    }
+
+    let event_bus_name =
+        std::env::var("EVENT_BUS_NAME").unwrap_or_else(|_| "http-tunnel-events-dev".to_string());
+
+    let entry = aws_sdk_eventbridge::types::PutEventsRequestEntry::builder()
+        .source(ALERT_EVENT_SOURCE)
+        .detail_type(ALERT_DETAIL_TYPE)
+        .detail(alert_detail(metadata, &window, &breaches).to_string())
+        .event_bus_name(event_bus_name)
+        .build();
+
+    clients
+        .eventbridge
+        .put_events()
+        .entries(entry)
+        .send()
+        .await
+        .context("Failed to publish tunnel alert")?;
+
+    info!(
+        "Published alert for tunnel {}: {:?}",
+        metadata.tunnel_id,
+        breaches.iter().map(|b| &b.metric).collect::<Vec<_>>()
+    );
+
+    Ok(())
+}
+
+/// Record that an alert is being sent, unless one went out within the cooldown
+///
+/// Returns false if another invocation alerted recently.
+async fn claim_alert_cooldown(client: &DynamoDbClient, connection_id: &str) -> Result<bool> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+    let now = current_timestamp_secs();
+
+    let result = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()))
+        .update_expression("SET lastAlertAt = :now")
+        .condition_expression(
+            "attribute_exists(connectionId) AND \
+             (attribute_not_exists(lastAlertAt) OR lastAlertAt < :cutoff)",
+        )
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .expression_attribute_values(
+            ":cutoff",
+            AttributeValue::N((now - ALERT_COOLDOWN_SECS).to_string()),
+        )
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).context("Failed to record alert cooldown"),
+    }
+}
+
+/// EventBridge detail payload for a tunnel alert
+fn alert_detail(
+    metadata: &ConnectionMetadata,
+    stats: &TunnelStats,
+    breaches: &[AlertBreach],
+) -> Value {
+    let summary = breaches
+        .iter()
+        .map(|breach| match breach.metric.as_str() {
+            "error_rate" => format!(
+                "error rate {:.1}% > {:.1}%",
+                breach.observed * 100.0,
+                breach.threshold * 100.0
+            ),
+            metric => format!("{} {} > {}", metric, breach.observed, breach.threshold),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    json!({
+        "tunnelId": metadata.tunnel_id,
+        "connectionId": metadata.connection_id,
+        "publicUrl": metadata.public_url,
+        "owner": metadata.owner,
+        "summary": format!(
+            "Tunnel {} over the last {}s: {}",
+            metadata.tunnel_id, stats.window_secs, summary
+        ),
+        "breaches": breaches,
+        "stats": stats,
+        "timestamp": http_tunnel_common::current_timestamp_millis(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_alert_gate_limits_evaluations() {
+        let gate = AlertGate::new();
+        let start = Instant::now();
+        let interval = Duration::from_secs(ALERT_EVALUATION_INTERVAL_SECS);
+
+        assert!(gate.should_evaluate("abc123def456", start));
+        assert!(!gate.should_evaluate("abc123def456", start + Duration::from_secs(1)));
+        assert!(gate.should_evaluate("xyz789xyz789", start + Duration::from_secs(1)));
+        assert!(gate.should_evaluate("abc123def456", start + interval));
+    }
+
+    #[test]
+    fn test_alert_detail() {
+        let metadata = ConnectionMetadata::new(
+            "conn_123".to_string(),
+            "abc123def456".to_string(),
+            "https://abc123def456.tunnel.example.com".to_string(),
+            1234567890,
+            1234574090,
+        );
+        let stats = TunnelStats {
+            tunnel_id: "abc123def456".to_string(),
+            window_secs: 300,
+            requests: 50,
+            status_classes: BTreeMap::from([("5xx".to_string(), 10)]),
+            p50_ms: Some(100),
+            p95_ms: Some(10_000),
+            p99_ms: Some(10_000),
+        };
+        let breaches = vec![
+            AlertBreach {
+                metric: "p95_ms".to_string(),
+                observed: 10_000.0,
+                threshold: 5_000.0,
+            },
+            AlertBreach {
+                metric: "error_rate".to_string(),
+                observed: 0.2,
+                threshold: 0.1,
+            },
+        ];
+
+        let detail = alert_detail(&metadata, &stats, &breaches);
+        assert_eq!(detail["tunnelId"], "abc123def456");
+        assert_eq!(detail["stats"]["p95_ms"], 10_000);
+        assert_eq!(
+            detail["summary"],
+            "Tunnel abc123def456 over the last 300s: p95_ms 10000 > 5000, error rate 20.0% > 10.0%"
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! Lifetime request analytics per tunnel
+//!
+//! Besides the per-minute buckets used for rolling windows (see [`crate::stats`]),
+//! the tunnel stats table holds one totals item per tunnel under bucket 0, which
+//! no window ever reaches. It counts every response an agent delivers: requests,
+//! request and response body bytes, a per-status-code histogram and a latency
+//! histogram for percentiles. The item's TTL is pushed back on every update, so
+//! the totals of a tunnel disappear a while after it stops being used.
+//!
+//! Totals are updated by the `$default` route when a response completes, off the
+//! public request's critical path. The pending request carries what the response
+//! doesn't know: the tunnel ID, the request body size and when it was sent.
+//!
+//! Agents that ask for `stats_updates` are sent the totals in a `TunnelStats`
+//! message at most once a minute per tunnel.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use http_tunnel_common::constants::{ANALYTICS_PUSH_INTERVAL_SECS, ANALYTICS_RETENTION_SECS};
+use http_tunnel_common::models::{LatencyHistogram, TunnelAnalytics};
+use http_tunnel_common::protocol::Message;
+use http_tunnel_common::utils::{calculate_ttl, current_timestamp_millis, current_timestamp_secs};
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, warn};
+
+use crate::store::TunnelStore;
+use crate::{SharedClients, send_message_with_encoding, stats};
+
+/// Bucket of the lifetime totals item
+const TOTALS_BUCKET: i64 = 0;
+
+/// Attribute name prefix for status code counters ("c200", "c404", ...)
+const STATUS_CODE_ATTR_PREFIX: &str = "c";
+
+/// Attribute name prefix for latency histogram bucket counters ("l0", "l1", ...)
+const LATENCY_ATTR_PREFIX: &str = "l";
+
+/// What the pending request remembers about the public request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingContext {
+    pub tunnel_id: String,
+    pub request_bytes: u64,
+    pub sent_at_ms: u64,
+}
+
+impl PendingContext {
+    /// Read the context from a pending request item (None for requests saved
+    /// before it was recorded)
+    pub fn from_item(item: &HashMap<String, AttributeValue>) -> Option<Self> {
+        Some(Self {
+            tunnel_id: item.get("tunnelId")?.as_s().ok()?.clone(),
+            request_bytes: number_attr(item.get("requestBytes")).unwrap_or(0),
+            sent_at_ms: number_attr(item.get("sentAtMs"))?,
+        })
+    }
+
+    /// The completed request, as of now
+    pub fn complete(self, status_code: u16, response_bytes: u64) -> Completion {
+        Completion {
+            latency_ms: current_timestamp_millis().saturating_sub(self.sent_at_ms),
+            tunnel_id: self.tunnel_id,
+            status_code,
+            request_bytes: self.request_bytes,
+            response_bytes,
+        }
+    }
+}
+
+/// A response delivered for a tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Completion {
+    pub tunnel_id: String,
+    pub status_code: u16,
+    pub latency_ms: u64,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+/// Add a completed request to its tunnel's totals and, when due, push the totals
+/// to the agent on `connection_id` (failures are only logged)
+pub async fn record_completion(
+    clients: &SharedClients,
+    connection_id: &str,
+    completion: Completion,
+) {
+    if !stats::is_stats_enabled() {
+        return;
+    }
+
+    let analytics = match add_completion(&clients.dynamodb, &completion).await {
+        Ok(analytics) => analytics,
+        Err(e) => {
+            warn!(
+                "Failed to record analytics for tunnel {}: {:#}",
+                completion.tunnel_id, e
+            );
+            return;
+        }
+    };
+
+    match claim_push(&clients.dynamodb, &completion.tunnel_id).await {
+        Ok(true) => {}
+        Ok(false) => return,
+        Err(e) => {
+            warn!(
+                "Failed to schedule analytics for tunnel {}: {:#}",
+                completion.tunnel_id, e
+            );
+            return;
+        }
+    }
+
+    if let Err(e) = push_analytics(clients, connection_id, analytics).await {
+        debug!(
+            "Failed to send analytics to connection {}: {:#}",
+            connection_id, e
+        );
+    }
+}
+
+/// Load a tunnel's lifetime totals (None if it has no recorded traffic)
+pub async fn load_tunnel_analytics(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+) -> Result<Option<TunnelAnalytics>> {
+    let table_name = stats_table()?;
+
+    let result = client
+        .get_item()
+        .table_name(&table_name)
+        .key("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .key("bucket", AttributeValue::N(TOTALS_BUCKET.to_string()))
+        .send()
+        .await
+        .context("Failed to load tunnel analytics")?;
+
+    Ok(result
+        .item
+        .map(|item| analytics_from_item(tunnel_id, &item)))
+}
+
+/// Increment the totals item, returning the updated totals
+async fn add_completion(
+    client: &DynamoDbClient,
+    completion: &Completion,
+) -> Result<TunnelAnalytics> {
+    let table_name = stats_table()?;
+
+    let result = client
+        .update_item()
+        .table_name(&table_name)
+        .key("tunnelId", AttributeValue::S(completion.tunnel_id.clone()))
+        .key("bucket", AttributeValue::N(TOTALS_BUCKET.to_string()))
+        .update_expression(
+            "ADD requests :one, bytesIn :in, bytesOut :out, #code :one, #latency :one \
+             SET #ttl = :ttl",
+        )
+        .expression_attribute_names(
+            "#code",
+            format!("{}{}", STATUS_CODE_ATTR_PREFIX, completion.status_code),
+        )
+        .expression_attribute_names(
+            "#latency",
+            format!(
+                "{}{}",
+                LATENCY_ATTR_PREFIX,
+                LatencyHistogram::bucket_index(completion.latency_ms)
+            ),
+        )
+        .expression_attribute_names("#ttl", "ttl")
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .expression_attribute_values(
+            ":in",
+            AttributeValue::N(completion.request_bytes.to_string()),
+        )
+        .expression_attribute_values(
+            ":out",
+            AttributeValue::N(completion.response_bytes.to_string()),
+        )
+        .expression_attribute_values(
+            ":ttl",
+            AttributeValue::N(calculate_ttl(ANALYTICS_RETENTION_SECS).to_string()),
+        )
+        .return_values(ReturnValue::AllNew)
+        .send()
+        .await
+        .context("Failed to record tunnel analytics")?;
+
+    Ok(analytics_from_item(
+        &completion.tunnel_id,
+        &result.attributes.unwrap_or_default(),
+    ))
+}
+
+/// Claim this interval's push for a tunnel; false if another invocation already did
+async fn claim_push(client: &DynamoDbClient, tunnel_id: &str) -> Result<bool> {
+    let table_name = stats_table()?;
+    let now = current_timestamp_secs();
+
+    let result = client
+        .update_item()
+        .table_name(&table_name)
+        .key("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .key("bucket", AttributeValue::N(TOTALS_BUCKET.to_string()))
+        .update_expression("SET pushedAt = :now")
+        .condition_expression("attribute_not_exists(pushedAt) OR pushedAt <= :cutoff")
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .expression_attribute_values(
+            ":cutoff",
+            AttributeValue::N((now - ANALYTICS_PUSH_INTERVAL_SECS).to_string()),
+        )
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).context("Failed to claim analytics push"),
+    }
+}
+
+/// Send the totals to an agent, if it asked for them
+async fn push_analytics(
+    clients: &SharedClients,
+    connection_id: &str,
+    analytics: TunnelAnalytics,
+) -> Result<()> {
+    let Some(apigw_management) = clients.apigw_management() else {
+        return Ok(());
+    };
+    let Some(connection) = clients.store.get_connection(connection_id).await? else {
+        return Ok(());
+    };
+    if !connection.options.stats_updates {
+        return Ok(());
+    }
+
+    send_message_with_encoding(
+        apigw_management,
+        connection_id,
+        Message::TunnelStats(analytics),
+        connection.options.encoding,
+    )
+    .await?;
+    debug!("Sent analytics to connection {}", connection_id);
+    Ok(())
+}
+
+/// Build the totals from a totals item
+fn analytics_from_item(tunnel_id: &str, item: &HashMap<String, AttributeValue>) -> TunnelAnalytics {
+    let mut status_codes = BTreeMap::new();
+    let mut latency = LatencyHistogram::new();
+
+    for (name, value) in item {
+        let Some(count) = number_attr(Some(value)) else {
+            continue;
+        };
+
+        if let Some(code) = name
+            .strip_prefix(STATUS_CODE_ATTR_PREFIX)
+            .and_then(|code| code.parse::<u16>().ok())
+        {
+            status_codes.insert(code.to_string(), count);
+        } else if let Some(index) = name
+            .strip_prefix(LATENCY_ATTR_PREFIX)
+            .and_then(|index| index.parse::<usize>().ok())
+        {
+            latency.add(index, count);
+        }
+    }
+
+    TunnelAnalytics {
+        tunnel_id: tunnel_id.to_string(),
+        requests: number_attr(item.get("requests")).unwrap_or(0),
+        bytes_in: number_attr(item.get("bytesIn")).unwrap_or(0),
+        bytes_out: number_attr(item.get("bytesOut")).unwrap_or(0),
+        status_codes,
+        p50_ms: latency.percentile(50.0),
+        p95_ms: latency.percentile(95.0),
+    }
+}
+
+fn stats_table() -> Result<String> {
+    std::env::var("TUNNEL_STATS_TABLE_NAME")
+        .context("TUNNEL_STATS_TABLE_NAME environment variable not set")
+}
+
+fn number_attr(value: Option<&AttributeValue>) -> Option<u64> {
+    value
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(attributes: &[(&str, &str)]) -> HashMap<String, AttributeValue> {
+        attributes
+            .iter()
+            .map(|(name, value)| {
+                let value = if value.parse::<u64>().is_ok() {
+                    AttributeValue::N(value.to_string())
+                } else {
+                    AttributeValue::S(value.to_string())
+                };
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_analytics_from_item() {
+        let analytics = analytics_from_item(
+            "abc123def456",
+            &item(&[
+                ("tunnelId", "abc123def456"),
+                ("bucket", "0"),
+                ("ttl", "1700000000"),
+                ("pushedAt", "1690000000"),
+                ("requests", "10"),
+                ("bytesIn", "1200"),
+                ("bytesOut", "64000"),
+                ("c200", "8"),
+                ("c502", "2"),
+                ("l2", "9"),
+                ("l8", "1"),
+            ]),
+        );
+
+        assert_eq!(analytics.tunnel_id, "abc123def456");
+        assert_eq!(analytics.requests, 10);
+        assert_eq!(analytics.bytes_in, 1200);
+        assert_eq!(analytics.bytes_out, 64000);
+        assert_eq!(
+            analytics.status_codes,
+            BTreeMap::from([("200".to_string(), 8), ("502".to_string(), 2)])
+        );
+        assert_eq!(analytics.p50_ms, Some(50));
+        assert_eq!(analytics.p95_ms, Some(5_000));
+    }
+
+    #[test]
+    fn test_pending_context() {
+        let pending = item(&[
+            ("requestId", "req_1"),
+            ("tunnelId", "abc123def456"),
+            ("requestBytes", "42"),
+            ("sentAtMs", "1000"),
+        ]);
+        let context = PendingContext::from_item(&pending).unwrap();
+        assert_eq!(
+            context,
+            PendingContext {
+                tunnel_id: "abc123def456".to_string(),
+                request_bytes: 42,
+                sent_at_ms: 1000,
+            }
+        );
+
+        let completion = context.complete(201, 7);
+        assert_eq!(completion.status_code, 201);
+        assert_eq!(completion.request_bytes, 42);
+        assert_eq!(completion.response_bytes, 7);
+        assert!(completion.latency_ms > 0);
+
+        // Requests saved by an older handler carry no context
+        assert_eq!(
+            PendingContext::from_item(&item(&[("requestId", "req_1")])),
+            None
+        );
+    }
+}
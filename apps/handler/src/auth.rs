@@ -2,13 +2,20 @@
 //!
 //! This module provides JWT-based authentication for WebSocket connections.
 //! Authentication can be enabled/disabled via the REQUIRE_AUTH environment variable.
+//!
+//! Tokens are verified against, in order of preference:
+//! - the JWKS served at `JWKS_URL`, refetched every `JWKS_CACHE_TTL_SECS`
+//!   (default 300) so signing keys can be rotated without a redeploy
+//! - the JWKS in the `JWKS` variable or the file at `JWKS_PATH`
+//! - the HMAC secret in `JWT_SECRET`
 
 use anyhow::{Context, Result, anyhow};
-use aws_lambda_events::apigw::ApiGatewayWebsocketProxyRequest;
-use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode};
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayWebsocketProxyRequest};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::sync::RwLock;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 use tracing::{debug, info, warn};
 
 /// JWT Claims structure
@@ -26,6 +33,12 @@ struct Jwks {
     keys: Vec<JwkKey>,
 }
 
+impl Jwks {
+    fn has_key(&self, kid: &str) -> bool {
+        self.keys.iter().any(|key| key.kid == kid)
+    }
+}
+
 /// Individual JWK (JSON Web Key)
 #[derive(Debug, Clone, Deserialize)]
 struct JwkKey {
@@ -83,6 +96,174 @@ fn load_jwks() -> Result<Jwks> {
     Ok(jwks)
 }
 
+/// How long keys fetched from `JWKS_URL` are used before they're refetched
+const DEFAULT_JWKS_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// Longest that fetched keys are trusted while `JWKS_URL` can't be reached
+const JWKS_MAX_STALE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Shortest time between fetches prompted by tokens signed with an unknown key
+const JWKS_MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Timeout of one JWKS fetch
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keys last fetched from a JWKS URL
+#[derive(Debug, Clone)]
+struct Fetched {
+    jwks: Jwks,
+    at: Instant,
+}
+
+/// A JWKS served over HTTP, cached for `ttl`
+///
+/// Keys older than `ttl` are still used while they're refetched in the
+/// background (stale-while-revalidate), for up to [`JWKS_MAX_STALE`] when the
+/// URL can't be reached. A token whose `kid` isn't among the cached keys
+/// prompts an immediate refetch, at most once per `min_refetch_interval`, so
+/// newly rotated-in keys are picked up before the cache expires.
+#[derive(Debug)]
+struct RemoteJwks {
+    url: String,
+    client: reqwest::Client,
+    ttl: Duration,
+    min_refetch_interval: Duration,
+    cached: RwLock<Option<Fetched>>,
+    /// When the last fetch started, successful or not
+    last_attempt: Mutex<Option<Instant>>,
+    /// Held while fetching, so concurrent callers share one fetch
+    fetching: tokio::sync::Mutex<()>,
+}
+
+impl RemoteJwks {
+    fn new(url: String, ttl: Duration, min_refetch_interval: Duration) -> Result<Self> {
+        Ok(Self {
+            url,
+            client: reqwest::Client::builder()
+                .timeout(JWKS_FETCH_TIMEOUT)
+                .build()
+                .context("Failed to build JWKS HTTP client")?,
+            ttl,
+            min_refetch_interval,
+            cached: RwLock::new(None),
+            last_attempt: Mutex::new(None),
+            fetching: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// The keys at `JWKS_URL`, if it's set
+    fn from_env() -> Option<Arc<Self>> {
+        let url = std::env::var("JWKS_URL")
+            .ok()
+            .filter(|url| !url.is_empty())?;
+        let ttl = std::env::var("JWKS_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .map_or(DEFAULT_JWKS_CACHE_TTL, Duration::from_secs);
+        match Self::new(url, ttl, JWKS_MIN_REFETCH_INTERVAL) {
+            Ok(remote) => Some(Arc::new(remote)),
+            Err(e) => {
+                warn!("JWKS_URL is set but can't be used: {:#}", e);
+                None
+            }
+        }
+    }
+
+    fn cached(&self) -> Option<Fetched> {
+        self.cached
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Whether a fetch started within `interval`
+    fn attempted_within(&self, interval: Duration) -> bool {
+        self.last_attempt
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some_and(|at| at.elapsed() < interval)
+    }
+
+    /// Fetch the keys and cache them
+    ///
+    /// Callers that arrive while a fetch is running get its result rather than
+    /// starting another.
+    async fn refetch(&self) -> Result<Jwks> {
+        let requested_at = Instant::now();
+        let _fetching = self.fetching.lock().await;
+        if let Some(fetched) = self.cached()
+            && fetched.at >= requested_at
+        {
+            return Ok(fetched.jwks);
+        }
+
+        *self.last_attempt.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        debug!("Fetching JWKS from {}", self.url);
+        let jwks: Jwks = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch JWKS from {}", self.url))?
+            .json()
+            .await
+            .context("Failed to parse JWKS JSON")?;
+
+        info!(
+            "JWKS fetched from {} with {} keys",
+            self.url,
+            jwks.keys.len()
+        );
+        *self.cached.write().unwrap_or_else(|e| e.into_inner()) = Some(Fetched {
+            jwks: jwks.clone(),
+            at: Instant::now(),
+        });
+        Ok(jwks)
+    }
+
+    /// Keys to verify a token signed with `kid` against
+    async fn keys(self: &Arc<Self>, kid: Option<&str>) -> Result<Jwks> {
+        let Some(fetched) = self.cached() else {
+            return self.refetch().await;
+        };
+
+        // The key may have been rotated in since the last fetch
+        if let Some(kid) = kid
+            && !fetched.jwks.has_key(kid)
+            && !self.attempted_within(self.min_refetch_interval)
+        {
+            match self.refetch().await {
+                Ok(jwks) => return Ok(jwks),
+                Err(e) => warn!("{:#}; using cached keys", e),
+            }
+        }
+
+        let age = fetched.at.elapsed();
+        if age >= JWKS_MAX_STALE {
+            return self
+                .refetch()
+                .await
+                .context("Cached JWKS is too old to trust");
+        }
+        if age >= self.ttl && !self.attempted_within(self.min_refetch_interval.min(self.ttl)) {
+            let remote = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = remote.refetch().await {
+                    warn!("{:#}; keeping cached keys", e);
+                }
+            });
+        }
+        Ok(fetched.jwks)
+    }
+}
+
+/// The JWKS at `JWKS_URL`, if configured (shared by every invocation)
+fn remote_jwks() -> Option<&'static Arc<RemoteJwks>> {
+    static REMOTE_JWKS: OnceLock<Option<Arc<RemoteJwks>>> = OnceLock::new();
+    REMOTE_JWKS.get_or_init(RemoteJwks::from_env).as_ref()
+}
+
 /// Check if authentication is required based on environment variable
 pub fn is_auth_required() -> bool {
     std::env::var("REQUIRE_AUTH")
@@ -91,21 +272,23 @@ pub fn is_auth_required() -> bool {
         == "true"
 }
 
+/// Extract a bearer token from the Authorization header
+fn extract_bearer_token(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get("authorization")
+        .or_else(|| headers.get("Authorization"))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.to_string())
+}
+
 /// Extract token from WebSocket request
 /// Checks (in order): Authorization header, query parameters
 fn extract_token(request: &ApiGatewayWebsocketProxyRequest) -> Option<String> {
     // First try Authorization header (preferred - works with custom domains and not logged)
-    if let Some(auth_header) = request
-        .headers
-        .get("authorization")
-        .or_else(|| request.headers.get("Authorization"))
-        && let Some(token) = auth_header
-            .to_str()
-            .ok()
-            .and_then(|s| s.strip_prefix("Bearer "))
-    {
+    if let Some(token) = extract_bearer_token(&request.headers) {
         debug!("Token extracted from Authorization header");
-        return Some(token.to_string());
+        return Some(token);
     }
 
     // Fallback to query parameter (less secure - gets logged)
@@ -117,42 +300,17 @@ fn extract_token(request: &ApiGatewayWebsocketProxyRequest) -> Option<String> {
     None
 }
 
-/// Validate JWT token using JWKS file or JWT_SECRET
-pub fn validate_token(token: &str) -> Result<Claims> {
-    // Try JWKS first if available
-    if let Ok(jwks) = load_jwks() {
-        // Try each key in JWKS
-        for key in &jwks.keys {
-            debug!(
-                "Trying key: {} (type: {}, alg: {})",
-                key.kid, key.kty, key.alg
-            );
-
-            let result = match key.kty.as_str() {
-                "RSA" => validate_with_rsa_key(token, key),
-                "oct" => validate_with_symmetric_key(token, key),
-                _ => {
-                    warn!("Unsupported key type: {} (kid: {})", key.kty, key.kid);
-                    continue;
-                }
-            };
+/// Validate JWT token using JWKS (remote, then inline or file) or JWT_SECRET
+pub async fn validate_token(token: &str) -> Result<Claims> {
+    let kid = decode_header(token).ok().and_then(|header| header.kid);
 
-            match result {
-                Ok(claims) => {
-                    info!("✅ Token validated with key: {} ({})", key.kid, key.alg);
-                    return Ok(claims);
-                }
-                Err(e) => {
-                    debug!("Key {} validation failed: {}", key.kid, e);
-                }
-            }
-        }
-
-        warn!(
-            "Token validation failed with all {} JWKS keys",
-            jwks.keys.len()
-        );
-        return Err(anyhow!("Token validation failed with all JWKS keys"));
+    // A configured JWKS_URL is the only source of keys, even while unreachable
+    if let Some(remote) = remote_jwks() {
+        let jwks = remote.keys(kid.as_deref()).await?;
+        return validate_with_jwks(token, &jwks, kid.as_deref());
+    }
+    if let Ok(jwks) = load_jwks() {
+        return validate_with_jwks(token, &jwks, kid.as_deref());
     }
 
     // Fallback to JWT_SECRET environment variable
@@ -171,6 +329,51 @@ pub fn validate_token(token: &str) -> Result<Claims> {
     Ok(token_data.claims)
 }
 
+/// Validate a token against the key its `kid` names, or every key without one
+fn validate_with_jwks(token: &str, jwks: &Jwks, kid: Option<&str>) -> Result<Claims> {
+    if let Some(kid) = kid
+        && !jwks.has_key(kid)
+    {
+        return Err(anyhow!("Token signed with unknown key: {}", kid));
+    }
+
+    let candidates = jwks
+        .keys
+        .iter()
+        .filter(|key| kid.is_none_or(|kid| key.kid == kid));
+    for key in candidates {
+        debug!(
+            "Trying key: {} (type: {}, alg: {})",
+            key.kid, key.kty, key.alg
+        );
+
+        let result = match key.kty.as_str() {
+            "RSA" => validate_with_rsa_key(token, key),
+            "oct" => validate_with_symmetric_key(token, key),
+            _ => {
+                warn!("Unsupported key type: {} (kid: {})", key.kty, key.kid);
+                continue;
+            }
+        };
+
+        match result {
+            Ok(claims) => {
+                info!("✅ Token validated with key: {} ({})", key.kid, key.alg);
+                return Ok(claims);
+            }
+            Err(e) => {
+                debug!("Key {} validation failed: {}", key.kid, e);
+            }
+        }
+    }
+
+    warn!(
+        "Token validation failed with all {} JWKS keys",
+        jwks.keys.len()
+    );
+    Err(anyhow!("Token validation failed with all JWKS keys"))
+}
+
 /// Validate token with RSA public key
 fn validate_with_rsa_key(token: &str, key: &JwkKey) -> Result<Claims> {
     let n = key
@@ -238,7 +441,9 @@ fn base64_url_decode(s: &str) -> Result<Vec<u8>> {
 /// Returns Ok(Some(claims)) if authentication is required and successful
 /// Returns Ok(None) if authentication is not required
 /// Returns Err if authentication is required but failed
-pub fn authenticate_request(request: &ApiGatewayWebsocketProxyRequest) -> Result<Option<Claims>> {
+pub async fn authenticate_request(
+    request: &ApiGatewayWebsocketProxyRequest,
+) -> Result<Option<Claims>> {
     if !is_auth_required() {
         debug!("Authentication not required");
         return Ok(None);
@@ -249,7 +454,7 @@ pub fn authenticate_request(request: &ApiGatewayWebsocketProxyRequest) -> Result
     let token =
         extract_token(request).ok_or_else(|| anyhow!("No authentication token provided"))?;
 
-    match validate_token(&token) {
+    match validate_token(&token).await {
         Ok(claims) => {
             info!("Token validated successfully for user: {}", claims.sub);
             Ok(Some(claims))
@@ -261,13 +466,30 @@ pub fn authenticate_request(request: &ApiGatewayWebsocketProxyRequest) -> Result
     }
 }
 
+/// Authenticate an HTTP API request (admin API) using its Authorization header
+///
+/// Same contract as [`authenticate_request`]: `Ok(None)` when auth is disabled.
+pub async fn authenticate_http_request(request: &ApiGatewayProxyRequest) -> Result<Option<Claims>> {
+    if !is_auth_required() {
+        return Ok(None);
+    }
+
+    let token = extract_bearer_token(&request.headers)
+        .ok_or_else(|| anyhow!("No authentication token provided"))?;
+
+    validate_token(&token).await.map(Some).map_err(|e| {
+        warn!("Token validation failed: {}", e);
+        anyhow!("Invalid or expired token")
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use jsonwebtoken::{EncodingKey, Header, encode};
 
-    #[test]
-    fn test_create_and_validate_token() {
+    #[tokio::test]
+    async fn test_create_and_validate_token() {
         let claims = Claims {
             sub: "user123".to_string(),
             exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize,
@@ -284,12 +506,12 @@ mod tests {
         )
         .unwrap();
 
-        let validated = validate_token(&token).unwrap();
+        let validated = validate_token(&token).await.unwrap();
         assert_eq!(validated.sub, "user123");
     }
 
-    #[test]
-    fn test_expired_token() {
+    #[tokio::test]
+    async fn test_expired_token() {
         let claims = Claims {
             sub: "user123".to_string(),
             exp: (chrono::Utc::now() - chrono::Duration::hours(1)).timestamp() as usize,
@@ -306,6 +528,193 @@ mod tests {
         )
         .unwrap();
 
-        assert!(validate_token(&token).is_err());
+        assert!(validate_token(&token).await.is_err());
+    }
+
+    #[test]
+    fn test_extract_bearer_token() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(extract_bearer_token(&headers), None);
+
+        headers.insert("authorization", "Basic abc".parse().unwrap());
+        assert_eq!(extract_bearer_token(&headers), None);
+
+        headers.insert("authorization", "Bearer abc.def.ghi".parse().unwrap());
+        assert_eq!(
+            extract_bearer_token(&headers).as_deref(),
+            Some("abc.def.ghi")
+        );
+    }
+
+    /// A token for `sub` signed with the HMAC `secret` under `kid`
+    fn signed(kid: &str, secret: &str) -> String {
+        let claims = Claims {
+            sub: "user123".to_string(),
+            exp: (chrono::Utc::now() + chrono::Duration::hours(1)).timestamp() as usize,
+            iat: None,
+        };
+        let header = Header {
+            kid: Some(kid.to_string()),
+            ..Header::new(Algorithm::HS256)
+        };
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    /// A JWKS of HMAC keys, as `(kid, secret)` pairs
+    fn jwks_json(keys: &[(&str, &str)]) -> String {
+        use base64::Engine;
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(kid, secret)| {
+                serde_json::json!({
+                    "kty": "oct",
+                    "kid": kid,
+                    "alg": "HS256",
+                    "k": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(secret),
+                })
+            })
+            .collect();
+        serde_json::json!({ "keys": keys }).to_string()
+    }
+
+    /// Serve the JWKS in `body` (or a 503 while it's None), counting requests
+    async fn serve_jwks(
+        body: Arc<Mutex<Option<String>>>,
+    ) -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/.well-known/jwks.json",
+            listener.local_addr().unwrap()
+        );
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let _ = stream.read(&mut request).await;
+                counter.fetch_add(1, Ordering::SeqCst);
+                let body = body.lock().unwrap().clone();
+                let response = match body {
+                    Some(body) => format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    ),
+                    None => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, hits)
+    }
+
+    async fn validate_remote(remote: &Arc<RemoteJwks>, token: &str) -> Result<Claims> {
+        let kid = decode_header(token)?.kid;
+        let jwks = remote.keys(kid.as_deref()).await?;
+        validate_with_jwks(token, &jwks, kid.as_deref())
+    }
+
+    #[tokio::test]
+    async fn test_remote_jwks_picks_up_rotated_key() {
+        use std::sync::atomic::Ordering;
+
+        let body = Arc::new(Mutex::new(Some(jwks_json(&[("k1", "secret-1")]))));
+        let (url, hits) = serve_jwks(body.clone()).await;
+        let remote =
+            Arc::new(RemoteJwks::new(url, Duration::from_secs(300), Duration::ZERO).unwrap());
+
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 1, "fresh keys are cached");
+
+        // A token signed with a key added since is verified after one refetch
+        *body.lock().unwrap() = Some(jwks_json(&[("k1", "secret-1"), ("k2", "secret-2")]));
+        assert!(
+            validate_remote(&remote, &signed("k2", "secret-2"))
+                .await
+                .is_ok()
+        );
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        // The key named by kid is used, not any key that happens to match
+        assert!(
+            validate_remote(&remote, &signed("k2", "secret-1"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remote_jwks_serves_stale_keys_while_unreachable() {
+        use std::sync::atomic::Ordering;
+
+        let body = Arc::new(Mutex::new(Some(jwks_json(&[("k1", "secret-1")]))));
+        let (url, hits) = serve_jwks(body.clone()).await;
+        let remote = Arc::new(RemoteJwks::new(url, Duration::ZERO, Duration::ZERO).unwrap());
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+
+        // Expired keys are still used while the refetch fails in the background
+        *body.lock().unwrap() = None;
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+        for _ in 0..50 {
+            if hits.load(Ordering::SeqCst) >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remote_jwks_rate_limits_unknown_kid_refetches() {
+        use std::sync::atomic::Ordering;
+
+        let body = Arc::new(Mutex::new(Some(jwks_json(&[("k1", "secret-1")]))));
+        let (url, hits) = serve_jwks(body).await;
+        let remote = Arc::new(
+            RemoteJwks::new(url, Duration::from_secs(300), Duration::from_secs(60)).unwrap(),
+        );
+        assert!(
+            validate_remote(&remote, &signed("k1", "secret-1"))
+                .await
+                .is_ok()
+        );
+
+        for _ in 0..3 {
+            let err = validate_remote(&remote, &signed("forged", "guess"))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("unknown key"), "{}", err);
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,298 @@
+//! Retries for DynamoDB calls
+//!
+//! The SDK already retries a couple of times, but a burst of traffic on one
+//! tunnel can still see throttling, which would otherwise reach its visitors as
+//! 500s. [`retry`] tries throttled calls, transient 5xx errors and timeouts a few
+//! more times with full-jitter exponential backoff, capping the total time
+//! spent waiting. Retries also draw on a budget shared by the container and
+//! refilled by successful calls, so an outage fails fast instead of multiplying
+//! the load on the table.
+//!
+//! Calls that needed retries are published as EMF metrics (`DynamoDbRetries`,
+//! and `DynamoDbRetriesExhausted` when they failed anyway) by operation.
+
+use aws_sdk_dynamodb::config::http::HttpResponse;
+use aws_sdk_dynamodb::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::metrics::{MetricLog, Unit};
+
+/// Attempts per call, including the first
+pub const MAX_ATTEMPTS: u32 = 4;
+
+/// Backoff ceiling of the first retry, doubled on each retry
+const BASE_DELAY_MS: u64 = 25;
+
+/// Highest backoff ceiling
+const MAX_DELAY_MS: u64 = 400;
+
+/// Most time a call may spend waiting between attempts
+const MAX_TOTAL_DELAY: Duration = Duration::from_millis(1000);
+
+/// Tokens in a full retry budget
+const BUDGET_CAPACITY: u32 = 100;
+
+/// Tokens taken by each retry
+const RETRY_COST: u32 = 10;
+
+/// Tokens returned by each call that succeeds first time
+const SUCCESS_REFUND: u32 = 1;
+
+/// Error codes DynamoDB returns for throttled or transiently failed calls
+const RETRYABLE_CODES: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "ThrottlingException",
+    "RequestLimitExceeded",
+    "InternalServerError",
+    "ServiceUnavailable",
+];
+
+/// Retries available to the container
+///
+/// Each retry takes [`RETRY_COST`] tokens and each first-time success returns
+/// [`SUCCESS_REFUND`], so retries stop once failures outnumber successes.
+#[derive(Debug)]
+pub struct RetryBudget {
+    tokens: AtomicU32,
+}
+
+impl RetryBudget {
+    pub const fn new() -> Self {
+        Self {
+            tokens: AtomicU32::new(BUDGET_CAPACITY),
+        }
+    }
+
+    /// Take the tokens for one retry, if there are enough left
+    pub fn try_acquire(&self) -> bool {
+        self.tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                tokens.checked_sub(RETRY_COST)
+            })
+            .is_ok()
+    }
+
+    /// Return tokens after a successful call
+    pub fn refund(&self) {
+        let _ = self
+            .tokens
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tokens| {
+                (tokens < BUDGET_CAPACITY).then(|| (tokens + SUCCESS_REFUND).min(BUDGET_CAPACITY))
+            });
+    }
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static BUDGET: RetryBudget = RetryBudget::new();
+
+/// Whether a failed call may succeed if tried again
+pub fn is_retryable<E: ProvideErrorMetadata>(err: &SdkError<E, HttpResponse>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) | SdkError::ResponseError(_) => {
+            true
+        }
+        SdkError::ServiceError(service) => {
+            service
+                .err()
+                .code()
+                .is_some_and(|code| RETRYABLE_CODES.contains(&code))
+                || service.raw().status().is_server_error()
+        }
+        _ => false,
+    }
+}
+
+/// Backoff before retry number `retry`, scaled by `jitter` in `[0, 1)`
+fn backoff(retry: u32, jitter: f64) -> Duration {
+    let ceiling = BASE_DELAY_MS
+        .saturating_mul(1 << (retry.max(1) - 1).min(16))
+        .min(MAX_DELAY_MS);
+    Duration::from_millis((ceiling as f64 * jitter.clamp(0.0, 1.0)) as u64)
+}
+
+/// Run a DynamoDB call, retrying throttling and transient failures
+///
+/// `call` sends a fresh request each time, e.g.
+/// `retry("GetItem", || request.clone().send())`.
+pub async fn retry<T, E, F, Fut>(
+    operation: &'static str,
+    mut call: F,
+) -> Result<T, SdkError<E, HttpResponse>>
+where
+    E: ProvideErrorMetadata + std::error::Error + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E, HttpResponse>>>,
+{
+    let mut attempt = 1;
+    let mut waited = Duration::ZERO;
+    loop {
+        let err = match call().await {
+            Ok(output) => {
+                if attempt == 1 {
+                    BUDGET.refund();
+                } else {
+                    report(operation, attempt - 1, false);
+                }
+                return Ok(output);
+            }
+            Err(err) => err,
+        };
+
+        if !is_retryable(&err) {
+            if attempt > 1 {
+                report(operation, attempt - 1, false);
+            }
+            return Err(err);
+        }
+
+        let delay = backoff(attempt, rand::random());
+        if attempt >= MAX_ATTEMPTS || waited + delay > MAX_TOTAL_DELAY || !BUDGET.try_acquire() {
+            report(operation, attempt - 1, true);
+            return Err(err);
+        }
+
+        warn!(
+            "DynamoDB {} failed (attempt {}), retrying in {:?}: {}",
+            operation,
+            attempt,
+            delay,
+            DisplayErrorContext(&err)
+        );
+        tokio::time::sleep(delay).await;
+        waited += delay;
+        attempt += 1;
+    }
+}
+
+fn report(operation: &'static str, retries: u32, exhausted: bool) {
+    MetricLog::new()
+        .dimension("Operation", operation)
+        .metric("DynamoDbRetries", f64::from(retries), Unit::Count)
+        .metric(
+            "DynamoDbRetriesExhausted",
+            f64::from(u8::from(exhausted)),
+            Unit::Count,
+        )
+        .emit();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_sdk_dynamodb::operation::get_item::GetItemError;
+    use aws_sdk_dynamodb::types::error::{
+        ProvisionedThroughputExceededException, ResourceNotFoundException,
+    };
+    use aws_smithy_types::body::SdkBody;
+
+    fn service_error(err: GetItemError, status: u16) -> SdkError<GetItemError, HttpResponse> {
+        let raw = HttpResponse::new(status.try_into().unwrap(), SdkBody::empty());
+        SdkError::service_error(err, raw)
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        let throttled = service_error(
+            GetItemError::ProvisionedThroughputExceededException(
+                ProvisionedThroughputExceededException::builder()
+                    .meta(
+                        aws_sdk_dynamodb::error::ErrorMetadata::builder()
+                            .code("ProvisionedThroughputExceededException")
+                            .build(),
+                    )
+                    .build(),
+            ),
+            400,
+        );
+        assert!(is_retryable(&throttled));
+
+        let missing = service_error(
+            GetItemError::ResourceNotFoundException(ResourceNotFoundException::builder().build()),
+            400,
+        );
+        assert!(!is_retryable(&missing));
+
+        let server = service_error(GetItemError::unhandled(std::io::Error::other("boom")), 500);
+        assert!(is_retryable(&server));
+
+        let timeout: SdkError<GetItemError, HttpResponse> =
+            SdkError::timeout_error(std::io::Error::other("timed out"));
+        assert!(is_retryable(&timeout));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1, 0.0), Duration::ZERO);
+        assert_eq!(backoff(1, 0.999), Duration::from_millis(24));
+        assert_eq!(backoff(3, 0.5), Duration::from_millis(50));
+        assert_eq!(backoff(10, 0.999), Duration::from_millis(399));
+        assert_eq!(backoff(u32::MAX, 1.0), Duration::from_millis(MAX_DELAY_MS));
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new();
+        for _ in 0..BUDGET_CAPACITY / RETRY_COST {
+            assert!(budget.try_acquire());
+        }
+        assert!(!budget.try_acquire());
+
+        for _ in 0..RETRY_COST {
+            budget.refund();
+        }
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+
+        // Refunds never overfill the budget
+        let budget = RetryBudget::new();
+        budget.refund();
+        assert_eq!(budget.tokens.load(Ordering::Relaxed), BUDGET_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up_on_permanent_errors() {
+        let mut calls = 0;
+        let result: Result<(), _> = retry("GetItem", || {
+            calls += 1;
+            async {
+                Err(service_error(
+                    GetItemError::ResourceNotFoundException(
+                        ResourceNotFoundException::builder().build(),
+                    ),
+                    400,
+                ))
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retry_recovers_from_timeouts() {
+        let mut calls = 0;
+        let result = retry("GetItem", || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                if attempt < 3 {
+                    Err(SdkError::<GetItemError, HttpResponse>::timeout_error(
+                        std::io::Error::other("timed out"),
+                    ))
+                } else {
+                    Ok(attempt)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+}
@@ -0,0 +1,166 @@
+//! Balancing requests between agents sharing a tunnel
+//!
+//! Several agents started with `--tunnel-id ID --shared` register under the same
+//! tunnel ID. Each Lambda container remembers when it last sent a request to
+//! each agent connection and prefers the one used least recently, so new
+//! agents are tried first and load spreads across the rest. Containers don't
+//! share this history; across containers the order is close to random.
+//!
+//! Tunnels with session affinity (`--sticky`) instead rank agents by a hash of
+//! the session key and the connection ID (rendezvous hashing). Every container
+//! ranks them the same way, and when an agent goes away only its sessions move
+//! to the next agent in their ranking.
+
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::BALANCER_MAX_TRACKED_CONNECTIONS;
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// In-memory dispatch history, shared by all invocations in a container
+#[derive(Debug, Default)]
+pub struct AgentBalancer {
+    last_used: Mutex<HashMap<String, Instant>>,
+}
+
+impl AgentBalancer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Order a tunnel's connections by preference, least recently used first
+    ///
+    /// Connections never used by this container come first, by connection ID.
+    pub fn order(&self, mut connections: Vec<ConnectionMetadata>) -> Vec<ConnectionMetadata> {
+        if connections.len() > 1 {
+            let last_used = self.last_used.lock().unwrap_or_else(|e| e.into_inner());
+            connections.sort_by(|a, b| {
+                let key = |c: &ConnectionMetadata| last_used.get(&c.connection_id).copied();
+                key(a)
+                    .cmp(&key(b))
+                    .then_with(|| a.connection_id.cmp(&b.connection_id))
+            });
+        }
+        connections
+    }
+
+    /// Order a tunnel's connections for the session `key`, preferred first
+    ///
+    /// The order only depends on the key and the connection IDs.
+    pub fn order_for_session(
+        &self,
+        mut connections: Vec<ConnectionMetadata>,
+        key: &str,
+    ) -> Vec<ConnectionMetadata> {
+        connections
+            .sort_by_cached_key(|c| std::cmp::Reverse(session_weight(key, &c.connection_id)));
+        connections
+    }
+
+    /// Record that a request was sent to `connection_id`
+    pub fn record(&self, connection_id: &str) {
+        let mut last_used = self.last_used.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Containers are short-lived, so simply start over rather than evicting
+        if !last_used.contains_key(connection_id)
+            && last_used.len() >= BALANCER_MAX_TRACKED_CONNECTIONS
+        {
+            last_used.clear();
+        }
+
+        last_used.insert(connection_id.to_string(), Instant::now());
+    }
+}
+
+/// Rendezvous weight of a connection for a session
+///
+/// `DefaultHasher::new` uses fixed keys, so every container running the same
+/// build computes the same weights.
+fn session_weight(key: &str, connection_id: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    connection_id.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connections(ids: &[&str]) -> Vec<ConnectionMetadata> {
+        ids.iter()
+            .map(|id| {
+                ConnectionMetadata::new(
+                    id.to_string(),
+                    "abc123def456".to_string(),
+                    "https://abc123def456.example.com".to_string(),
+                    0,
+                    0,
+                )
+            })
+            .collect()
+    }
+
+    fn ids(connections: &[ConnectionMetadata]) -> Vec<&str> {
+        connections
+            .iter()
+            .map(|c| c.connection_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_least_recently_used_first() {
+        let balancer = AgentBalancer::new();
+        let order = balancer.order(connections(&["b", "a", "c"]));
+        assert_eq!(ids(&order), ["a", "b", "c"]);
+
+        balancer.record("a");
+        let order = balancer.order(connections(&["a", "b", "c"]));
+        assert_eq!(ids(&order), ["b", "c", "a"]);
+
+        balancer.record("b");
+        balancer.record("c");
+        let order = balancer.order(connections(&["a", "b", "c"]));
+        assert_eq!(ids(&order), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_new_agent_preferred() {
+        let balancer = AgentBalancer::new();
+        balancer.record("a");
+        balancer.record("b");
+        let order = balancer.order(connections(&["a", "b", "z"]));
+        assert_eq!(order[0].connection_id, "z");
+    }
+
+    #[test]
+    fn test_session_order_is_stable() {
+        let balancer = AgentBalancer::new();
+        let first = balancer.order_for_session(connections(&["a", "b", "c", "d"]), "client-1");
+        let again = balancer.order_for_session(connections(&["d", "c", "b", "a"]), "client-1");
+        assert_eq!(ids(&first), ids(&again));
+
+        // Using an agent doesn't move sessions off it
+        balancer.record(&first[0].connection_id);
+        let after = balancer.order_for_session(connections(&["a", "b", "c", "d"]), "client-1");
+        assert_eq!(ids(&first), ids(&after));
+    }
+
+    #[test]
+    fn test_only_sessions_of_departed_agent_move() {
+        let balancer = AgentBalancer::new();
+        let all = ["a", "b", "c", "d"];
+        let remaining = ["a", "b", "d"];
+        for i in 0..100 {
+            let key = format!("client-{}", i);
+            let before = balancer.order_for_session(connections(&all), &key);
+            let after = balancer.order_for_session(connections(&remaining), &key);
+            if before[0].connection_id == "c" {
+                assert_eq!(after[0].connection_id, before[1].connection_id);
+            } else {
+                assert_eq!(after[0].connection_id, before[0].connection_id);
+            }
+        }
+    }
+}
@@ -0,0 +1,524 @@
+//! Storage of chunked response bodies
+//!
+//! Each piece of a chunked response (see [`split_message`]) reaches the
+//! `$default` route as its own invocation, possibly concurrently and out of
+//! order, so the pieces are collected in the pending requests table: the head
+//! as `responseHead` on the pending request, the chunk count from `BodyEnd` as
+//! `bodyChunks`, and every chunk as its own item keyed `{requestId}#chunk{index}`.
+//! After each write the invocation checks whether all pieces are present and, if
+//! so, marks the request completed; a conditional update makes sure only one
+//! invocation does. The waiter loads the chunks when it reads the response.
+//!
+//! Chunk items are left to expire with the same TTL as the pending request.
+//! The pending request also counts the decoded body bytes received so far as
+//! `responseBytes`, for the tunnel's analytics.
+//!
+//! With the single-table layout (see [`PendingTable`]) the same items live in
+//! that table instead, chunks keyed `CHUNK#{index}` under their request.
+//!
+//! [`split_message`]: http_tunnel_common::protocol::split_message
+
+use anyhow::{Context, Result, anyhow};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::update_item::{UpdateItemError, UpdateItemOutput};
+use aws_sdk_dynamodb::types::{AttributeValue, KeysAndAttributes};
+use http_tunnel_common::constants::PENDING_REQUEST_TTL_SECS;
+use http_tunnel_common::protocol::HttpResponse;
+use http_tunnel_common::utils::calculate_ttl;
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+use crate::analytics::{Completion, PendingContext};
+use crate::decoded_body_size;
+
+/// BatchGetItem accepts at most 100 keys per call
+const BATCH_GET_MAX_KEYS: usize = 100;
+
+/// Attempts at reading keys DynamoDB left unprocessed in a batch
+const BATCH_GET_MAX_ATTEMPTS: u32 = 3;
+
+/// Sort key of pending request items in the single table
+const META: &str = "META";
+
+/// Where pending requests and the pieces of their responses are kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingTable {
+    pub table_name: String,
+    single_table: bool,
+}
+
+impl PendingTable {
+    /// The pending requests table named by `PENDING_REQUESTS_TABLE_NAME`
+    pub fn from_env() -> Result<Self> {
+        let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
+            .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
+        Ok(Self {
+            table_name,
+            single_table: false,
+        })
+    }
+
+    /// The table of the single-table layout, keyed by `pk`/`sk`
+    pub fn single_table(table_name: impl Into<String>) -> Self {
+        Self {
+            table_name: table_name.into(),
+            single_table: true,
+        }
+    }
+
+    /// Key of a pending request's item
+    pub fn request_key(&self, request_id: &str) -> HashMap<String, AttributeValue> {
+        if self.single_table {
+            key_pair(request_pk(request_id), META.to_string())
+        } else {
+            HashMap::from([(
+                "requestId".to_string(),
+                AttributeValue::S(request_id.to_string()),
+            )])
+        }
+    }
+
+    /// Key of the item holding chunk `index` of a response body
+    fn chunk_key(&self, request_id: &str, index: u32) -> HashMap<String, AttributeValue> {
+        if self.single_table {
+            key_pair(request_pk(request_id), format!("CHUNK#{}", index))
+        } else {
+            HashMap::from([(
+                "requestId".to_string(),
+                AttributeValue::S(format!("{}#chunk{}", request_id, index)),
+            )])
+        }
+    }
+
+    /// Index of the chunk item with key `key`, if it belongs to `request_id`
+    fn chunk_index(&self, key: &HashMap<String, AttributeValue>, request_id: &str) -> Option<u32> {
+        let attribute = |name: &str| key.get(name).and_then(|v| v.as_s().ok());
+        let index = if self.single_table {
+            if *attribute("pk")? != request_pk(request_id) {
+                return None;
+            }
+            attribute("sk")?.strip_prefix("CHUNK#")?
+        } else {
+            attribute("requestId")?
+                .strip_prefix(request_id)?
+                .strip_prefix("#chunk")?
+        };
+        index.parse().ok()
+    }
+
+    /// The key attributes, for projections
+    fn key_attributes(&self) -> &'static str {
+        if self.single_table {
+            "pk, sk"
+        } else {
+            "requestId"
+        }
+    }
+}
+
+/// Partition key of a pending request and its chunks in the single table
+fn request_pk(request_id: &str) -> String {
+    format!("REQ#{}", request_id)
+}
+
+fn key_pair(pk: String, sk: String) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("pk".to_string(), AttributeValue::S(pk)),
+        ("sk".to_string(), AttributeValue::S(sk)),
+    ])
+}
+
+/// Store the head of a chunked response (the response without its body)
+///
+/// This and the other `save_` functions return the completed request if the
+/// piece they stored was the last one missing.
+pub async fn save_response_head(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    response: &HttpResponse,
+) -> Result<Option<Completion>> {
+    let head = serde_json::to_string(response).context("Failed to serialize response head")?;
+
+    let result = client
+        .update_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(&response.request_id)))
+        .update_expression("SET responseHead = :head")
+        .condition_expression("attribute_exists(requestId)")
+        .expression_attribute_values(":head", AttributeValue::S(head))
+        .send()
+        .await;
+    if !applied(result, "Failed to save response head")? {
+        debug!("Request {} is no longer pending", response.request_id);
+        return Ok(None);
+    }
+
+    try_complete(client, table, &response.request_id).await
+}
+
+/// Store one chunk of a response body
+pub async fn save_body_chunk(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    index: u32,
+    data: String,
+) -> Result<Option<Completion>> {
+    let result = client
+        .update_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .update_expression("ADD responseBytes :bytes")
+        .condition_expression("attribute_exists(requestId)")
+        .expression_attribute_values(
+            ":bytes",
+            AttributeValue::N(decoded_body_size(&data).to_string()),
+        )
+        .send()
+        .await;
+    if !applied(result, "Failed to count response bytes")? {
+        debug!("Request {} is no longer pending", request_id);
+        return Ok(None);
+    }
+
+    client
+        .put_item()
+        .table_name(&table.table_name)
+        .set_item(Some(table.chunk_key(request_id, index)))
+        // Not a request: ignored by the stream handler
+        .item("status", AttributeValue::S("chunk".to_string()))
+        .item("data", AttributeValue::S(data))
+        .item(
+            "ttl",
+            AttributeValue::N(calculate_ttl(PENDING_REQUEST_TTL_SECS).to_string()),
+        )
+        .send()
+        .await
+        .context("Failed to save response chunk")?;
+
+    try_complete(client, table, request_id).await
+}
+
+/// Record how many chunks a response body was split into
+pub async fn save_body_end(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    chunks: u32,
+) -> Result<Option<Completion>> {
+    let result = client
+        .update_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .update_expression("SET bodyChunks = :chunks")
+        .condition_expression("attribute_exists(requestId)")
+        .expression_attribute_values(":chunks", AttributeValue::N(chunks.to_string()))
+        .send()
+        .await;
+    if !applied(result, "Failed to save response chunk count")? {
+        debug!("Request {} is no longer pending", request_id);
+        return Ok(None);
+    }
+
+    try_complete(client, table, request_id).await
+}
+
+/// Store a piece of a streamed response body for the invocation relaying it
+///
+/// Pieces share the chunk key scheme but never complete the request: its head
+/// already did, and the relaying invocation reads them one by one.
+pub async fn save_stream_chunk(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    index: u32,
+    data: String,
+    last: bool,
+) -> Result<()> {
+    client
+        .put_item()
+        .table_name(&table.table_name)
+        .set_item(Some(table.chunk_key(request_id, index)))
+        // Not a request: ignored by the stream handler
+        .item("status", AttributeValue::S("chunk".to_string()))
+        .item("data", AttributeValue::S(data))
+        .item("last", AttributeValue::Bool(last))
+        .item(
+            "ttl",
+            AttributeValue::N(calculate_ttl(PENDING_REQUEST_TTL_SECS).to_string()),
+        )
+        .send()
+        .await
+        .context("Failed to save response stream chunk")?;
+
+    Ok(())
+}
+
+/// A piece of a streamed response body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamChunk {
+    /// Base64-encoded piece
+    pub data: String,
+    /// The body ends with this piece
+    pub last: bool,
+}
+
+/// Read piece `index` of a streamed response body, if it has arrived
+pub async fn load_stream_chunk(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    index: u32,
+) -> Result<Option<StreamChunk>> {
+    let result = client
+        .get_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.chunk_key(request_id, index)))
+        // `data` is a reserved word
+        .projection_expression("#data, #last")
+        .expression_attribute_names("#data", "data")
+        .expression_attribute_names("#last", "last")
+        .consistent_read(true)
+        .send()
+        .await
+        .context("Failed to read response stream chunk")?;
+
+    Ok(result.item.map(|item| StreamChunk {
+        data: item
+            .get("data")
+            .and_then(|v| v.as_s().ok())
+            .cloned()
+            .unwrap_or_default(),
+        last: item
+            .get("last")
+            .and_then(|v| v.as_bool().ok())
+            .copied()
+            .unwrap_or(false),
+    }))
+}
+
+/// Mark the request completed if the head, the chunk count and every chunk are stored
+///
+/// Reads are strongly consistent, so whichever invocation writes the last piece
+/// sees all the others.
+async fn try_complete(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+) -> Result<Option<Completion>> {
+    let result = client
+        .get_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .projection_expression(
+            "#status, responseHead, bodyChunks, tunnelId, requestBytes, sentAtMs, responseBytes",
+        )
+        .expression_attribute_names("#status", "status")
+        .consistent_read(true)
+        .send()
+        .await
+        .context("Failed to get pending request from DynamoDB")?;
+
+    let Some(item) = result.item else {
+        return Ok(None);
+    };
+    let status = item.get("status").and_then(|v| v.as_s().ok());
+    let head = item.get("responseHead").and_then(|v| v.as_s().ok());
+    let chunks = item
+        .get("bodyChunks")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<u32>().ok());
+    let (Some("pending"), Some(head), Some(chunks)) = (status.map(String::as_str), head, chunks)
+    else {
+        return Ok(None);
+    };
+
+    let received = load_chunks(client, table, request_id, chunks, false).await?;
+    if received.len() < chunks as usize {
+        debug!(
+            "Request {}: {} of {} chunks received",
+            request_id,
+            received.len(),
+            chunks
+        );
+        return Ok(None);
+    }
+
+    let result = client
+        .update_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .update_expression("SET #status = :completed, responseData = :data")
+        .condition_expression("#status = :pending")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":completed", AttributeValue::S("completed".to_string()))
+        .expression_attribute_values(":pending", AttributeValue::S("pending".to_string()))
+        .expression_attribute_values(":data", AttributeValue::S(head.clone()))
+        .send()
+        .await;
+    if !applied(result, "Failed to complete chunked response")? {
+        return Ok(None);
+    }
+    info!(
+        "Completed chunked response for {} ({} chunks)",
+        request_id, chunks
+    );
+
+    let (Some(context), Ok(head)) = (
+        PendingContext::from_item(&item),
+        serde_json::from_str::<HttpResponse>(head),
+    ) else {
+        return Ok(None);
+    };
+    let response_bytes = item
+        .get("responseBytes")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0);
+    Ok(Some(context.complete(head.status_code, response_bytes)))
+}
+
+/// Load and join the chunks of a completed response body
+pub async fn load_body(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    chunks: u32,
+) -> Result<String> {
+    let mut received = load_chunks(client, table, request_id, chunks, true).await?;
+    (0..chunks)
+        .map(|index| received.remove(&index))
+        .collect::<Option<String>>()
+        .ok_or_else(|| anyhow!("Missing chunks for response {}", request_id))
+}
+
+/// Read the stored chunks of a response body, keyed by index
+///
+/// Without `with_data` only the keys are read, to check which chunks have arrived.
+async fn load_chunks(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    chunks: u32,
+    with_data: bool,
+) -> Result<HashMap<u32, String>> {
+    let table_name = table.table_name.as_str();
+    let keys: Vec<HashMap<String, AttributeValue>> = (0..chunks)
+        .map(|index| table.chunk_key(request_id, index))
+        .collect();
+
+    let mut received = HashMap::new();
+    for batch in keys.chunks(BATCH_GET_MAX_KEYS) {
+        let mut request = KeysAndAttributes::builder()
+            .set_keys(Some(batch.to_vec()))
+            .consistent_read(true);
+        request = if with_data {
+            // `data` is a reserved word
+            request
+                .projection_expression(format!("{}, #data", table.key_attributes()))
+                .expression_attribute_names("#data", "data")
+        } else {
+            request.projection_expression(table.key_attributes())
+        };
+        let mut pending = Some(request.build().context("Invalid chunk key batch")?);
+
+        let mut attempts = 0;
+        while let Some(request) = pending.take()
+            && attempts < BATCH_GET_MAX_ATTEMPTS
+        {
+            attempts += 1;
+            let output = client
+                .batch_get_item()
+                .request_items(table_name, request)
+                .send()
+                .await
+                .context("Failed to read response chunks")?;
+
+            for item in output
+                .responses()
+                .and_then(|responses| responses.get(table_name))
+                .into_iter()
+                .flatten()
+            {
+                let Some(index) = table.chunk_index(item, request_id) else {
+                    continue;
+                };
+                let data = item
+                    .get("data")
+                    .and_then(|v| v.as_s().ok())
+                    .cloned()
+                    .unwrap_or_default();
+                received.insert(index, data);
+            }
+
+            pending = output
+                .unprocessed_keys()
+                .and_then(|unprocessed| unprocessed.get(table_name))
+                .filter(|request| !request.keys().is_empty())
+                .cloned();
+        }
+    }
+
+    Ok(received)
+}
+
+/// Whether a conditional update was applied (false if its condition failed)
+fn applied(
+    result: Result<UpdateItemOutput, SdkError<UpdateItemError>>,
+    context: &'static str,
+) -> Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).context(context),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn two_table() -> PendingTable {
+        PendingTable {
+            table_name: "pending".to_string(),
+            single_table: false,
+        }
+    }
+
+    #[test]
+    fn test_chunk_keys() {
+        let table = two_table();
+        let key = table.chunk_key("req_abc", 12);
+        assert_eq!(
+            key["requestId"],
+            AttributeValue::S("req_abc#chunk12".to_string())
+        );
+        assert_eq!(table.chunk_index(&key, "req_abc"), Some(12));
+        assert_eq!(table.chunk_index(&key, "req_other"), None);
+        assert_eq!(
+            table.chunk_index(&table.request_key("req_abc"), "req_abc"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_single_table_chunk_keys() {
+        let table = PendingTable::single_table("tunnel");
+        let key = table.chunk_key("req_abc", 12);
+        assert_eq!(key["pk"], AttributeValue::S("REQ#req_abc".to_string()));
+        assert_eq!(key["sk"], AttributeValue::S("CHUNK#12".to_string()));
+        assert_eq!(table.chunk_index(&key, "req_abc"), Some(12));
+        assert_eq!(table.chunk_index(&key, "req_other"), None);
+        assert_eq!(
+            table.chunk_index(&table.request_key("req_abc"), "req_abc"),
+            None
+        );
+        assert_eq!(table.key_attributes(), "pk, sk");
+    }
+}
@@ -0,0 +1,130 @@
+//! Content-Encoding support for response rewriting
+//!
+//! Compressed bodies are decoded before their links are rewritten and encoded
+//! again with the same encoding afterwards, so the client gets what the local
+//! service sent, only rewritten. Bodies in encodings the tunnel can't decode
+//! (or that decompress to more than [`MAX_DECODED_BYTES`]) are passed through
+//! unchanged; the forwarder only offers the local service the encodings below.
+
+use anyhow::{Result, bail};
+use std::io::{Read, Write};
+
+/// Largest decompressed body that gets rewritten
+pub const MAX_DECODED_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Buffer size for the Brotli encoder and decoder
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+/// A response body encoding the rewriter can decode and encode again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    /// zlib-wrapped deflate, as HTTP defines it
+    Deflate,
+    Brotli,
+}
+
+impl ContentEncoding {
+    /// Parse a `Content-Encoding` header, returning `None` for encodings the
+    /// rewriter doesn't support, including several stacked encodings
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        let Some(value) = value.map(str::trim).filter(|value| !value.is_empty()) else {
+            return Some(Self::Identity);
+        };
+        match value.to_ascii_lowercase().as_str() {
+            "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "deflate" => Some(Self::Deflate),
+            "br" => Some(Self::Brotli),
+            _ => None,
+        }
+    }
+
+    /// Decompress a body
+    pub fn decode(self, body: &[u8]) -> Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::Identity => return Ok(body.to_vec()),
+            Self::Gzip => Box::new(flate2::read::MultiGzDecoder::new(body)),
+            Self::Deflate => Box::new(flate2::read::ZlibDecoder::new(body)),
+            Self::Brotli => Box::new(brotli::Decompressor::new(body, BROTLI_BUFFER_SIZE)),
+        };
+
+        let mut decoded = Vec::new();
+        reader
+            .take(MAX_DECODED_BYTES + 1)
+            .read_to_end(&mut decoded)?;
+        if decoded.len() as u64 > MAX_DECODED_BYTES {
+            bail!("Body decompresses to more than {} bytes", MAX_DECODED_BYTES);
+        }
+        Ok(decoded)
+    }
+
+    /// Compress a body
+    pub fn encode(self, body: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Identity => body.to_vec(),
+            Self::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            Self::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), BROTLI_BUFFER_SIZE, 5, 22);
+                encoder.write_all(body)?;
+                encoder.flush()?;
+                encoder.into_inner()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_encoding() {
+        assert_eq!(
+            ContentEncoding::parse(None),
+            Some(ContentEncoding::Identity)
+        );
+        assert_eq!(
+            ContentEncoding::parse(Some("GZIP")),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(
+            ContentEncoding::parse(Some("br")),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(ContentEncoding::parse(Some("zstd")), None);
+        assert_eq!(ContentEncoding::parse(Some("gzip, br")), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let body = b"<a href=\"/api\">API</a>".repeat(10);
+        for encoding in [
+            ContentEncoding::Identity,
+            ContentEncoding::Gzip,
+            ContentEncoding::Deflate,
+            ContentEncoding::Brotli,
+        ] {
+            let encoded = encoding.encode(&body).unwrap();
+            assert_eq!(encoding.decode(&encoded).unwrap(), body, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_decode_garbage_fails() {
+        assert!(ContentEncoding::Gzip.decode(b"not gzip").is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Per-container cache of tunnel connection lookups
+//!
+//! Every forwarded request needs the connections serving its tunnel, which is a
+//! query on the tunnel ID index. Each Lambda container keeps the answer for a
+//! few seconds (`CONNECTION_CACHE_TTL_SECS`), so a busy tunnel costs one query
+//! per container every few seconds rather than one per request. The least
+//! recently used tunnel is dropped once `CONNECTION_CACHE_MAX_TUNNELS` are
+//! cached.
+//!
+//! Entries are dropped early when this container learns a connection is gone
+//! (`$disconnect`, a 410 on delivery, revocation, or a failed request seen on
+//! the pending requests stream) and when an agent connects. Other containers
+//! notice within the TTL; until then a request sent to a departed agent fails
+//! over to the tunnel's next agent as it would without the cache.
+
+use anyhow::{Result, anyhow};
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::{CONNECTION_CACHE_MAX_TUNNELS, CONNECTION_CACHE_TTL_SECS};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::store::TunnelStore;
+
+const TTL: Duration = Duration::from_secs(CONNECTION_CACHE_TTL_SECS);
+
+#[derive(Debug, Clone)]
+struct CachedConnections {
+    connections: Vec<ConnectionMetadata>,
+    fetched_at: Instant,
+    used_at: Instant,
+}
+
+/// In-memory tunnel lookups, shared by all invocations in a container
+#[derive(Debug, Default)]
+pub struct ConnectionCache {
+    tunnels: Mutex<HashMap<String, CachedConnections>>,
+}
+
+impl ConnectionCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The connections serving `tunnel_id`, from the cache while fresh
+    ///
+    /// Fails like [`lookup_connections_by_tunnel_id`](crate::lookup_connections_by_tunnel_id)
+    /// if the tunnel has none; that answer isn't cached.
+    pub async fn lookup<S: TunnelStore>(
+        &self,
+        store: &S,
+        tunnel_id: &str,
+    ) -> Result<Vec<ConnectionMetadata>> {
+        if let Some(connections) = self.get(tunnel_id, Instant::now()) {
+            debug!("Connection cache hit for tunnel {}", tunnel_id);
+            return Ok(connections);
+        }
+        let connections = store.tunnel_connections(tunnel_id).await?;
+        if connections.is_empty() {
+            return Err(anyhow!("Connection not found for tunnel ID: {}", tunnel_id));
+        }
+        self.insert(tunnel_id, connections.clone(), Instant::now());
+        Ok(connections)
+    }
+
+    /// Forget `tunnel_id`, e.g. because an agent joined it
+    pub fn invalidate_tunnel(&self, tunnel_id: &str) {
+        self.lock().remove(tunnel_id);
+    }
+
+    /// Forget every tunnel served by `connection_id`
+    pub fn invalidate_connection(&self, connection_id: &str) {
+        self.lock().retain(|_, cached| {
+            !cached
+                .connections
+                .iter()
+                .any(|connection| connection.connection_id == connection_id)
+        });
+    }
+
+    fn get(&self, tunnel_id: &str, now: Instant) -> Option<Vec<ConnectionMetadata>> {
+        let mut tunnels = self.lock();
+        let cached = tunnels.get_mut(tunnel_id)?;
+        if now.saturating_duration_since(cached.fetched_at) >= TTL {
+            tunnels.remove(tunnel_id);
+            return None;
+        }
+        cached.used_at = now;
+        Some(cached.connections.clone())
+    }
+
+    fn insert(&self, tunnel_id: &str, connections: Vec<ConnectionMetadata>, now: Instant) {
+        let mut tunnels = self.lock();
+        if !tunnels.contains_key(tunnel_id) && tunnels.len() >= CONNECTION_CACHE_MAX_TUNNELS {
+            let least_recent = tunnels
+                .iter()
+                .min_by_key(|(_, cached)| cached.used_at)
+                .map(|(tunnel_id, _)| tunnel_id.clone());
+            if let Some(least_recent) = least_recent {
+                tunnels.remove(&least_recent);
+            }
+        }
+        tunnels.insert(
+            tunnel_id.to_string(),
+            CachedConnections {
+                connections,
+                fetched_at: now,
+                used_at: now,
+            },
+        );
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, CachedConnections>> {
+        self.tunnels.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+    use std::sync::atomic::Ordering;
+
+    fn connection(connection_id: &str, tunnel_id: &str) -> ConnectionMetadata {
+        ConnectionMetadata::new(
+            connection_id.to_string(),
+            tunnel_id.to_string(),
+            format!("https://{}.tunnel.example.com", tunnel_id),
+            1_700_000_000,
+            1_700_007_200,
+        )
+    }
+
+    #[test]
+    fn test_entries_expire() {
+        let cache = ConnectionCache::new();
+        let start = Instant::now();
+        cache.insert(
+            "abc123def456",
+            vec![connection("conn_1", "abc123def456")],
+            start,
+        );
+
+        let cached = cache.get("abc123def456", start + TTL - Duration::from_millis(1));
+        assert_eq!(cached.unwrap()[0].connection_id, "conn_1");
+        assert!(cache.get("abc123def456", start + TTL).is_none());
+        // Expired entries are dropped
+        assert!(cache.get("abc123def456", start).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_queries_store_once() {
+        let cache = ConnectionCache::new();
+        let store = MemoryStore::new();
+        assert!(cache.lookup(&store, "abc123def456").await.is_err());
+
+        store
+            .put_connection(&connection("conn_1", "abc123def456"))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            let connections = cache.lookup(&store, "abc123def456").await.unwrap();
+            assert_eq!(connections[0].connection_id, "conn_1");
+        }
+        // The empty answer wasn't cached; the found one was
+        assert_eq!(store.tunnel_queries.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_invalidation() {
+        let cache = ConnectionCache::new();
+        let now = Instant::now();
+        cache.insert(
+            "tunnel_a",
+            vec![
+                connection("conn_1", "tunnel_a"),
+                connection("conn_2", "tunnel_a"),
+            ],
+            now,
+        );
+        cache.insert("tunnel_b", vec![connection("conn_3", "tunnel_b")], now);
+
+        cache.invalidate_connection("conn_2");
+        assert!(cache.get("tunnel_a", now).is_none());
+        assert!(cache.get("tunnel_b", now).is_some());
+
+        cache.invalidate_tunnel("tunnel_b");
+        assert!(cache.get("tunnel_b", now).is_none());
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let cache = ConnectionCache::new();
+        let start = Instant::now();
+        for i in 0..CONNECTION_CACHE_MAX_TUNNELS {
+            let tunnel_id = format!("tunnel_{}", i);
+            let at = start + Duration::from_millis(i as u64);
+            cache.insert(&tunnel_id, vec![connection("conn", &tunnel_id)], at);
+        }
+        // tunnel_0 is used again, so tunnel_1 is now the least recent
+        let later = start + Duration::from_millis(CONNECTION_CACHE_MAX_TUNNELS as u64);
+        assert!(cache.get("tunnel_0", later).is_some());
+
+        cache.insert("tunnel_new", vec![connection("conn", "tunnel_new")], later);
+        assert!(cache.get("tunnel_0", later).is_some());
+        assert!(cache.get("tunnel_1", later).is_none());
+        assert!(cache.get("tunnel_new", later).is_some());
+        assert_eq!(cache.lock().len(), CONNECTION_CACHE_MAX_TUNNELS);
+    }
+}
@@ -7,23 +7,28 @@
 //! For example, if a tunnel ID is "abc123" and the local service returns HTML
 //! with `href="/api/users"`, it needs to be rewritten to `href="/abc123/api/users"`
 //! so that the browser sends requests to the correct tunnel path.
+//!
+//! HTML goes through a streaming HTML rewriter (lol_html) with handlers for the
+//! URL-carrying attributes (`href`, `src`, `action`, `poster`, `data-src`,
+//! `srcset`, meta refresh), inline styles and inline scripts; CSS is rewritten
+//! with regexes and JSON by walking the parsed document.
 
 use anyhow::Result;
+use http_tunnel_common::HttpResponse;
+use lol_html::html_content::ContentType;
+use lol_html::{RewriteStrSettings, element, rewrite_str, text};
 use once_cell::sync::Lazy;
 use regex::{Captures, Regex};
+use serde_json::{Map, Value};
+use std::cell::Cell;
+use std::sync::OnceLock;
 use tracing::{debug, warn};
 
-/// Strategy for rewriting content
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
-pub enum RewriteStrategy {
-    /// No rewriting (pass through unchanged)
-    None,
-    /// HTML: inject <base> tag only
-    BaseTag,
-    /// HTML: rewrite all absolute paths
-    #[default]
-    FullRewrite,
-}
+pub use http_tunnel_common::protocol::RewriteStrategy;
+
+/// Response header a local service sets to have a response passed through
+/// unchanged; it's removed before the response reaches the client
+pub const NO_REWRITE_HEADER: &str = "x-tunnel-no-rewrite";
 
 /// Check if content type should be rewritten
 pub fn should_rewrite_content(content_type: &str) -> bool {
@@ -59,11 +64,15 @@ pub fn rewrite_response_content(
             return Ok((body.to_string(), false));
         }
         ("text/html", RewriteStrategy::BaseTag) => inject_base_tag(body, &prefix),
-        ("text/html", RewriteStrategy::FullRewrite) => rewrite_html(body, &prefix),
+        ("text/html", RewriteStrategy::FullRewrite) => rewrite_html(body, &prefix, false),
+        ("text/html", RewriteStrategy::Aggressive) => rewrite_html(body, &prefix, true),
         ("text/css", _) => rewrite_css(body, &prefix),
+        ("application/javascript" | "text/javascript", RewriteStrategy::Aggressive) => {
+            Ok(rewrite_javascript(body, &prefix))
+        }
         ("application/javascript" | "text/javascript", _) => {
-            // JavaScript rewriting is complex and risky, skip for now
-            debug!("Skipping JavaScript rewriting (not implemented)");
+            // JavaScript rewriting is risky, so it's only done when asked for
+            debug!("Skipping JavaScript rewriting (not requested)");
             return Ok((body.to_string(), false));
         }
         ("application/json", _) => rewrite_json(body, &prefix),
@@ -87,13 +96,13 @@ pub fn rewrite_response_content(
     Ok((rewritten, was_rewritten))
 }
 
-// Regex patterns (compiled once, reused many times)
-static HTML_HREF_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"href="(/[^"]*)""#).expect("Invalid regex"));
-static HTML_SRC_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"src="(/[^"]*)""#).expect("Invalid regex"));
-static HTML_ACTION_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#"action="(/[^"]*)""#).expect("Invalid regex"));
+/// Attributes holding a single URL (`data-src` is the usual lazy-loading one)
+const URL_ATTRIBUTES: [&str; 5] = ["href", "src", "action", "poster", "data-src"];
+
+/// Attributes holding a list of image candidates
+const SRCSET_ATTRIBUTES: [&str; 2] = ["srcset", "data-srcset"];
+
+// Regex patterns for CSS (compiled once, reused many times)
 
 // Match url() with various quote styles
 static CSS_URL_SINGLE_QUOTE: Lazy<Regex> =
@@ -103,44 +112,67 @@ static CSS_URL_DOUBLE_QUOTE: Lazy<Regex> =
 static CSS_URL_NO_QUOTE: Lazy<Regex> =
     Lazy::new(|| Regex::new(r#"url\((/[^)]+)\)"#).expect("Invalid regex"));
 
-static JSON_PATH_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r#""(/[a-zA-Z0-9/_-]+)""#).expect("Invalid regex"));
+/// JSON fields whose string values are rewritten as URLs by default
+const DEFAULT_JSON_URL_FIELDS: [&str; 10] = [
+    "url",
+    "href",
+    "baseUrl",
+    "base_url",
+    "$ref",
+    "authorizationUrl",
+    "tokenUrl",
+    "refreshUrl",
+    "openIdConnectUrl",
+    "operationRef",
+];
 
 /// Inject <base> tag into HTML to set base path
 /// This is a simpler approach that works for many HTML pages
 fn inject_base_tag(html: &str, prefix: &str) -> Result<String> {
-    // Look for <head> tag (case-insensitive)
-    let head_regex = Regex::new(r"(?i)<head[^>]*>")?;
-
-    if let Some(mat) = head_regex.find(html) {
-        let insert_pos = mat.end();
-        let base_tag = format!(r#"<base href="{}/""#, prefix);
-        let mut result = html.to_string();
-        result.insert_str(insert_pos, &base_tag);
-        return Ok(result);
+    let base_tag = format!(r#"<base href="{}/">"#, prefix);
+    match inject_into_head(html, &base_tag)? {
+        Some(result) => Ok(result),
+        None => {
+            warn!("Could not find <head> or <html> tag for base tag injection");
+            Ok(html.to_string())
+        }
     }
+}
 
-    // If no <head> tag found, try to inject after <html>
-    let html_regex = Regex::new(r"(?i)<html[^>]*>")?;
-    if let Some(mat) = html_regex.find(html) {
-        let insert_pos = mat.end();
-        let base_tag = format!(r#"<head><base href="{}/""></head>"#, prefix);
-        let mut result = html.to_string();
-        result.insert_str(insert_pos, &base_tag);
-        return Ok(result);
+/// Insert `content` at the start of <head>, or in a new <head> at the start of
+/// <html>; `None` if the document has neither
+fn inject_into_head(html: &str, content: &str) -> Result<Option<String>> {
+    let injected = Cell::new(false);
+    let result = rewrite_str(
+        html,
+        RewriteStrSettings::new().append_element_content_handler(element!("head", |el| {
+            if !injected.replace(true) {
+                el.prepend(content, ContentType::Html);
+            }
+            Ok(())
+        })),
+    )?;
+    if injected.get() {
+        return Ok(Some(result));
     }
 
-    warn!("Could not find <head> or <html> tag for base tag injection");
-    Ok(html.to_string())
+    let head = format!("<head>{}</head>", content);
+    let result = rewrite_str(
+        html,
+        RewriteStrSettings::new().append_element_content_handler(element!("html", |el| {
+            if !injected.replace(true) {
+                el.prepend(&head, ContentType::Html);
+            }
+            Ok(())
+        })),
+    )?;
+    Ok(injected.get().then_some(result))
 }
 
-/// Inject tunnel context script into HTML
-/// This provides a global JavaScript variable that client code can use for dynamic URL construction
-fn inject_tunnel_context(html: &str, tunnel_id: &str) -> Result<String> {
-    let head_regex = Regex::new(r"(?i)<head[^>]*>")?;
-
-    // Script that provides tunnel context to client-side JavaScript
-    let context_script = format!(
+/// Script providing a global JavaScript variable that client code can use for
+/// dynamic URL construction
+fn tunnel_context_script(tunnel_id: &str) -> String {
+    format!(
         r#"<script>
 // HTTP Tunnel Context - provides tunnel ID for dynamic URL construction
 window.__TUNNEL_CONTEXT__ = {{
@@ -162,111 +194,203 @@ window.__TUNNEL_CONTEXT__ = {{
 window.__TUNNEL_BASE_PATH__ = '{}';
 </script>"#,
         tunnel_id, tunnel_id, tunnel_id
-    );
-
-    if let Some(mat) = head_regex.find(html) {
-        let insert_pos = mat.end();
-        let mut result = html.to_string();
-        result.insert_str(insert_pos, &context_script);
-        return Ok(result);
-    }
+    )
+}
 
-    // If no <head> tag, try after <html>
-    let html_regex = Regex::new(r"(?i)<html[^>]*>")?;
-    if let Some(mat) = html_regex.find(html) {
-        let insert_pos = mat.end();
-        let script_with_head = format!("<head>{}</head>", context_script);
-        let mut result = html.to_string();
-        result.insert_str(insert_pos, &script_with_head);
+/// Rewrite absolute paths in HTML attributes and inline scripts, and inject the
+/// tunnel context
+///
+/// The document is parsed in one streaming pass (lol_html), so only real
+/// attributes and <script> contents are touched: markup-looking text inside
+/// scripts, templates or code samples is left alone. With `modules`, inline
+/// scripts also get their imports and `fetch()` URLs rewritten.
+fn rewrite_html(body: &str, prefix: &str, modules: bool) -> Result<String> {
+    let tunnel_id = prefix.trim_start_matches('/');
+    let context_script = tunnel_context_script(tunnel_id);
+    let context_injected = Cell::new(false);
+    // Scripts and style sheets arrive in chunks; each is rewritten once complete
+    let mut script = String::new();
+    let mut style = String::new();
+
+    let settings = RewriteStrSettings::new()
+        .append_element_content_handler(element!(
+            "[href], [src], [action], [poster], [data-src]",
+            |el| {
+                for name in URL_ATTRIBUTES {
+                    if let Some(value) = el.get_attribute(name)
+                        && let Some(rewritten) = rewrite_path(&value, prefix)
+                    {
+                        el.set_attribute(name, &rewritten)?;
+                    }
+                }
+                Ok(())
+            }
+        ))
+        .append_element_content_handler(element!("[srcset], [data-srcset]", |el| {
+            for name in SRCSET_ATTRIBUTES {
+                if let Some(srcset) = el.get_attribute(name)
+                    && let Some(rewritten) = rewrite_srcset(&srcset, prefix)
+                {
+                    el.set_attribute(name, &rewritten)?;
+                }
+            }
+            Ok(())
+        }))
+        .append_element_content_handler(element!("[style]", |el| {
+            if let Some(style) = el.get_attribute("style") {
+                let rewritten = rewrite_css(&style, prefix)?;
+                if rewritten != style {
+                    el.set_attribute("style", &rewritten)?;
+                }
+            }
+            Ok(())
+        }))
+        .append_element_content_handler(text!("style", |chunk| {
+            style.push_str(chunk.as_str());
+            if chunk.last_in_text_node() {
+                chunk.replace(&rewrite_css(&style, prefix)?, ContentType::Html);
+                style.clear();
+            } else {
+                chunk.remove();
+            }
+            Ok(())
+        }))
+        .append_element_content_handler(element!("meta[http-equiv][content]", |el| {
+            let is_refresh = el
+                .get_attribute("http-equiv")
+                .is_some_and(|value| value.eq_ignore_ascii_case("refresh"));
+            if is_refresh
+                && let Some(content) = el.get_attribute("content")
+                && let Some(rewritten) = rewrite_refresh(&content, prefix)
+            {
+                el.set_attribute("content", &rewritten)?;
+            }
+            Ok(())
+        }))
+        .append_element_content_handler(text!("script", |chunk| {
+            script.push_str(chunk.as_str());
+            if chunk.last_in_text_node() {
+                let mut rewritten = rewrite_inline_javascript(&script, prefix);
+                if modules {
+                    rewritten = rewrite_javascript(&rewritten, prefix);
+                }
+                chunk.replace(&rewritten, ContentType::Html);
+                script.clear();
+            } else {
+                chunk.remove();
+            }
+            Ok(())
+        }))
+        .append_element_content_handler(element!("head", |el| {
+            if !context_injected.replace(true) {
+                el.prepend(&context_script, ContentType::Html);
+            }
+            Ok(())
+        }));
+    let result = rewrite_str(body, settings)?;
+    if context_injected.get() {
         return Ok(result);
     }
 
-    // If no structure found, prepend to document
-    Ok(format!("{}{}", context_script, html))
+    // No <head>: add one, or put the script first in a fragment
+    Ok(match inject_into_head(&result, &context_script)? {
+        Some(result) => result,
+        None => format!("{}{}", context_script, result),
+    })
 }
 
-/// Rewrite absolute paths in HTML attributes and inline JavaScript
-fn rewrite_html(body: &str, prefix: &str) -> Result<String> {
-    // Helper function to check if path should be rewritten
-    let should_rewrite_path = |path: &str| -> bool {
-        // Don't rewrite if:
-        // - Already prefixed
-        // - External URL (http://, https://)
-        // - Protocol-relative URL (//)
-        // - Data URL (data:)
-        // - Anchor only (#)
-        // - Empty
-        if path.is_empty() || path.starts_with('#') {
-            return false;
-        }
-        if path.starts_with("http://")
-            || path.starts_with("https://")
-            || path.starts_with("//")
-            || path.starts_with("data:")
-        {
-            return false;
-        }
-        // Check if already prefixed
-        if path.starts_with(&format!("{}/", prefix)) || path == prefix {
-            return false;
-        }
-        true
-    };
+/// Prefix an absolute path with the tunnel prefix
+///
+/// Returns `None` for anything else: relative paths, anchors, external,
+/// protocol-relative and data URLs, and paths already prefixed.
+fn rewrite_path(path: &str, prefix: &str) -> Option<String> {
+    if !path.starts_with('/') || path.starts_with("//") {
+        return None;
+    }
+    if path.starts_with(&format!("{}/", prefix)) || path == prefix {
+        return None;
+    }
+    Some(format!("{}{}", prefix, path))
+}
 
-    // Rewrite href attributes
-    let result = HTML_HREF_REGEX.replace_all(body, |caps: &Captures| {
-        let path = &caps[1];
-        if should_rewrite_path(path) {
-            format!(r#"href="{}{}""#, prefix, path)
-        } else {
-            caps[0].to_string()
-        }
-    });
+/// Rewrite the absolute URLs among `srcset` candidates (`url [descriptor], ...`)
+fn rewrite_srcset(srcset: &str, prefix: &str) -> Option<String> {
+    let mut changed = false;
+    let candidates: Vec<String> = parse_srcset(srcset)
+        .into_iter()
+        .map(|(url, descriptor)| {
+            let url = rewrite_path(url, prefix)
+                .inspect(|_| changed = true)
+                .unwrap_or_else(|| url.to_string());
+            format!("{} {}", url, descriptor).trim_end().to_string()
+        })
+        .collect();
+    changed.then(|| candidates.join(", "))
+}
 
-    // Rewrite src attributes
-    let result = HTML_SRC_REGEX.replace_all(&result, |caps: &Captures| {
-        let path = &caps[1];
-        if should_rewrite_path(path) {
-            format!(r#"src="{}{}""#, prefix, path)
-        } else {
-            caps[0].to_string()
+/// Split a `srcset` into its `(url, descriptor)` candidates
+///
+/// Follows the HTML parsing rules: a URL runs up to the next whitespace (so data
+/// URLs and query strings may contain commas), a URL ending in a comma has no
+/// descriptor, and a descriptor ends at a comma outside parentheses.
+fn parse_srcset(srcset: &str) -> Vec<(&str, &str)> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return candidates;
         }
-    });
 
-    // Rewrite action attributes
-    let result = HTML_ACTION_REGEX.replace_all(&result, |caps: &Captures| {
-        let path = &caps[1];
-        if should_rewrite_path(path) {
-            format!(r#"action="{}{}""#, prefix, path)
-        } else {
-            caps[0].to_string()
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let url = rest[..end].trim_end_matches(',');
+        rest = &rest[url.len()..];
+        if url.len() < end {
+            candidates.push((url, ""));
+            continue;
         }
-    });
-
-    // Rewrite JavaScript string literals (for inline scripts)
-    // This is conservative and only rewrites obvious patterns
-    let result = rewrite_inline_javascript(&result, prefix)?;
 
-    // Inject tunnel context for dynamic JavaScript URL construction
-    // This enables client-side code to build URLs correctly
-    let tunnel_id = prefix.trim_start_matches('/');
-    let result = inject_tunnel_context(&result, tunnel_id)?;
+        let mut depth = 0u32;
+        let descriptor_end = rest
+            .find(|c| match c {
+                '(' => {
+                    depth += 1;
+                    false
+                }
+                ')' => {
+                    depth = depth.saturating_sub(1);
+                    false
+                }
+                ',' => depth == 0,
+                _ => false,
+            })
+            .unwrap_or(rest.len());
+        candidates.push((url, rest[..descriptor_end].trim()));
+        rest = &rest[descriptor_end..];
+    }
+}
 
-    Ok(result)
+/// Rewrite the URL of a refresh directive (`5; url=/next`)
+fn rewrite_refresh(content: &str, prefix: &str) -> Option<String> {
+    let start = content.to_ascii_lowercase().find("url=")? + "url=".len();
+    let url = content[start..]
+        .trim()
+        .trim_matches(|c| c == '\'' || c == '"');
+    let rewritten = rewrite_path(url, prefix)?;
+    Some(format!("{}{}", &content[..start], rewritten))
 }
 
 /// Rewrite JavaScript string literals in inline scripts
 /// This handles common patterns like: url: '/api/path', fetch('/api/path'), etc.
-fn rewrite_inline_javascript(html: &str, prefix: &str) -> Result<String> {
-    // Match JavaScript string literals with absolute paths
-    // Patterns: 'string', "string" with absolute paths
-    let js_single_quote = Regex::new(r#"'(/[a-zA-Z0-9/_\-\.]+)'"#)?;
-    let js_double_quote = Regex::new(r#""(/[a-zA-Z0-9/_\-\.]+)""#)?;
-
+fn rewrite_inline_javascript(script: &str, prefix: &str) -> String {
     let should_rewrite_js_path = |path: &str| -> bool {
-        // Only rewrite if it looks like an API path or common web paths
+        // Only plain path literals, e.g. not '/a b' or '/${id}'
+        let is_path = path.starts_with('/')
+            && path
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '/' | '_' | '-' | '.'));
         // Don't rewrite very short paths or paths that might be variable names
-        if path.len() < 2 {
+        if !is_path || path.len() < 2 {
             return false;
         }
         // Check if already prefixed
@@ -286,27 +410,163 @@ fn rewrite_inline_javascript(html: &str, prefix: &str) -> Result<String> {
             || path.ends_with(".yml")
     };
 
-    // Rewrite single-quoted strings
-    let result = js_single_quote.replace_all(html, |caps: &Captures| {
-        let path = &caps[1];
-        if should_rewrite_js_path(path) {
-            format!("'{}{}'", prefix, path)
-        } else {
-            caps[0].to_string()
+    let mut result = String::with_capacity(script.len());
+    let mut rest = script;
+    while let Some(open) = rest.find(['\'', '"']) {
+        let quote = rest.as_bytes()[open];
+        result.push_str(&rest[..=open]);
+        rest = &rest[open + 1..];
+
+        // Find the closing quote, skipping escaped characters
+        let mut escaped = false;
+        let Some(close) = rest.bytes().position(|byte| {
+            let closes = !escaped && byte == quote;
+            escaped = !escaped && byte == b'\\';
+            closes
+        }) else {
+            break;
+        };
+
+        let literal = &rest[..close];
+        if should_rewrite_js_path(literal) {
+            result.push_str(prefix);
         }
-    });
+        result.push_str(literal);
+        result.push(quote as char);
+        rest = &rest[close + 1..];
+    }
+    result.push_str(rest);
+    result
+}
 
-    // Rewrite double-quoted strings
-    let result = js_double_quote.replace_all(&result, |caps: &Captures| {
-        let path = &caps[1];
-        if should_rewrite_js_path(path) {
-            format!("\"{}{}\"", prefix, path)
+/// Rewrite the URLs of module imports (`import "/x.js"`, `from "/x.js"`,
+/// `import("/x.js")`) and `fetch()` calls in a script
+///
+/// Only string and template literals right after one of those are touched, and
+/// comments are skipped. A regular expression literal containing a quote can
+/// still throw the scanner off, which is why this needs the `Aggressive` strategy.
+fn rewrite_javascript(script: &str, prefix: &str) -> String {
+    let mut result = String::with_capacity(script.len());
+    let mut rest = script;
+    while let Some(start) = rest.find(['\'', '"', '`', '/']) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let skipped = if rest.starts_with("//") {
+            rest.find('\n').unwrap_or(rest.len())
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            comment.find("*/").map_or(rest.len(), |end| end + 4)
+        } else if rest.starts_with('/') {
+            1
         } else {
-            caps[0].to_string()
+            0
+        };
+        if skipped > 0 {
+            result.push_str(&rest[..skipped]);
+            rest = &rest[skipped..];
+            continue;
         }
-    });
 
-    Ok(result.into_owned())
+        // Find the closing quote, skipping escaped characters
+        let quote = rest.as_bytes()[0];
+        let mut escaped = false;
+        let Some(close) = rest[1..].bytes().position(|byte| {
+            let closes = !escaped && byte == quote;
+            escaped = !escaped && byte == b'\\';
+            closes
+        }) else {
+            break;
+        };
+
+        let literal = &rest[1..=close];
+        let rewritten = is_url_position(&result)
+            .then(|| rewrite_path(literal, prefix))
+            .flatten();
+        result.push(quote as char);
+        result.push_str(rewritten.as_deref().unwrap_or(literal));
+        result.push(quote as char);
+        rest = &rest[close + 2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Whether a literal following `code` is a module specifier or a fetched URL
+fn is_url_position(code: &str) -> bool {
+    let code = code.trim_end();
+    match code.strip_suffix('(') {
+        Some(call) => {
+            let call = call.trim_end();
+            ends_with_keyword(call, "import", false) || ends_with_keyword(call, "fetch", true)
+        }
+        None => ends_with_keyword(code, "import", false) || ends_with_keyword(code, "from", false),
+    }
+}
+
+/// Whether `code` ends with `keyword` as a whole word, optionally as a member
+/// (`window.fetch`)
+fn ends_with_keyword(code: &str, keyword: &str, member: bool) -> bool {
+    code.strip_suffix(keyword).is_some_and(|before| {
+        !before.ends_with(|c: char| {
+            c.is_alphanumeric() || c == '_' || c == '$' || (c == '.' && !member)
+        })
+    })
+}
+
+/// Response headers carrying URLs the client follows
+const URL_HEADERS: [&str; 4] = ["location", "content-location", "refresh", "link"];
+
+/// Prefix absolute paths in URL-bearing response headers (`Location`,
+/// `Content-Location`, `Refresh` and `Link`), so redirects stay in the tunnel
+///
+/// Returns whether any header changed.
+pub fn rewrite_response_headers(response: &mut HttpResponse, tunnel_id: &str) -> bool {
+    let prefix = format!("/{}", tunnel_id);
+    let mut rewritten = false;
+    for (name, values) in response.headers.iter_mut() {
+        let name = name.to_ascii_lowercase();
+        if !URL_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        for value in values.iter_mut() {
+            let new_value = match name.as_str() {
+                "refresh" => rewrite_refresh(value, &prefix),
+                "link" => rewrite_link(value, &prefix),
+                _ => rewrite_path(value.trim(), &prefix),
+            };
+            if let Some(new_value) = new_value {
+                debug!("Rewrote {} header: {} -> {}", name, value, new_value);
+                *value = new_value;
+                rewritten = true;
+            }
+        }
+    }
+    rewritten
+}
+
+/// Rewrite the absolute paths among `Link` header targets (`</a>; rel=x, </b>`)
+fn rewrite_link(link: &str, prefix: &str) -> Option<String> {
+    let mut result = String::with_capacity(link.len());
+    let mut rest = link;
+    let mut changed = false;
+    while let Some(open) = rest.find('<') {
+        let Some(close) = rest[open..].find('>').map(|close| open + close) else {
+            break;
+        };
+        result.push_str(&rest[..=open]);
+        let target = &rest[open + 1..close];
+        match rewrite_path(target, prefix) {
+            Some(rewritten) => {
+                result.push_str(&rewritten);
+                changed = true;
+            }
+            None => result.push_str(target),
+        }
+        result.push('>');
+        rest = &rest[close + 1..];
+    }
+    result.push_str(rest);
+    changed.then_some(result)
 }
 
 /// Rewrite url() references in CSS
@@ -353,66 +613,130 @@ fn rewrite_css(body: &str, prefix: &str) -> Result<String> {
 }
 
 /// Rewrite absolute paths in JSON content
-/// This is conservative and only rewrites obvious path-like strings
-/// Also handles OpenAPI spec's servers field
+///
+/// The document is parsed and only string values of URL-bearing fields
+/// ([`json_url_fields`]) are touched, wherever they're nested: `servers[].url`,
+/// HAL `_links.*.href`, `$ref` and so on. OpenAPI and Swagger documents also get
+/// their API paths prefixed, through `basePath` or, without any relative server,
+/// the `paths` keys. Bodies that aren't JSON are left alone.
 fn rewrite_json(body: &str, prefix: &str) -> Result<String> {
-    // First, handle OpenAPI servers field specially
-    // "servers": [{"url": "/api"}] or "servers": [{"url": "https://example.com"}]
-    let servers_regex = Regex::new(r#""servers"\s*:\s*\[\s*\{\s*"url"\s*:\s*"([^"]*)""#)?;
-
-    let result = servers_regex.replace_all(body, |caps: &Captures| {
-        let url = &caps[1];
-
-        // If it's a relative path (starts with /), rewrite it
-        if url.starts_with('/') && !url.starts_with(&format!("{}/", prefix)) {
-            format!(r#""servers": [{{"url": "{}{}""#, prefix, url)
-        } else if url.starts_with("http://") || url.starts_with("https://") {
-            // It's a full URL - don't rewrite
-            caps[0].to_string()
-        } else {
-            caps[0].to_string()
-        }
-    });
+    rewrite_json_fields(body, prefix, json_url_fields())
+}
 
-    // Then handle general JSON path rewriting
-    let result = JSON_PATH_REGEX.replace_all(&result, |caps: &Captures| {
-        let path = &caps[1];
+fn rewrite_json_fields(body: &str, prefix: &str, fields: &[String]) -> Result<String> {
+    let Ok(mut document) = serde_json::from_str::<Value>(body) else {
+        debug!("Skipping rewrite of invalid JSON");
+        return Ok(body.to_string());
+    };
 
-        // Don't rewrite if:
-        // - Already prefixed
-        // - Looks like a URL scheme (http:, https:, etc.)
-        // - Too short to be a meaningful path
-        if path.len() < 2 {
-            return caps[0].to_string();
-        }
-        if path.starts_with(&format!("{}/", prefix)) || path == prefix {
-            return caps[0].to_string();
-        }
-        // Check if it looks like a URL scheme
-        if path.contains("://") {
-            return caps[0].to_string();
-        }
+    let mut changed = rewrite_json_value(&mut document, prefix, fields);
+    if let Some(api) = document.as_object_mut()
+        && (api.contains_key("openapi") || api.contains_key("swagger"))
+    {
+        changed |= rewrite_openapi_paths(api, prefix);
+    }
+    if !changed {
+        return Ok(body.to_string());
+    }
 
-        // Only rewrite if it looks like an API path (starts with /api, /v1, etc.)
-        // or is in a known OpenAPI field
-        let path_lower = path.to_lowercase();
-        if path_lower.starts_with("/api")
-            || path_lower.starts_with("/v1")
-            || path_lower.starts_with("/v2")
-            || path_lower.starts_with("/v3")
-            || path_lower.starts_with("/docs")
-            || path_lower.starts_with("/openapi")
-            || path_lower.starts_with("/swagger")
-            || path_lower.starts_with("/todos")
-        // Common API path
-        {
-            format!(r#""{}{}""#, prefix, path)
-        } else {
-            caps[0].to_string()
+    // Keep pretty-printed documents readable
+    Ok(if body.trim().contains('\n') {
+        serde_json::to_string_pretty(&document)?
+    } else {
+        serde_json::to_string(&document)?
+    })
+}
+
+/// Prefix the string values of URL-bearing fields, returning whether any changed
+fn rewrite_json_value(value: &mut Value, prefix: &str, fields: &[String]) -> bool {
+    match value {
+        Value::Object(object) => {
+            let mut changed = false;
+            for (key, value) in object.iter_mut() {
+                if fields.iter().any(|field| field == key)
+                    && let Value::String(url) = value
+                {
+                    if let Some(rewritten) = rewrite_path(url, prefix) {
+                        *url = rewritten;
+                        changed = true;
+                    }
+                } else {
+                    changed |= rewrite_json_value(value, prefix, fields);
+                }
+            }
+            changed
         }
-    });
+        Value::Array(items) => items.iter_mut().fold(false, |changed, item| {
+            rewrite_json_value(item, prefix, fields) | changed
+        }),
+        _ => false,
+    }
+}
 
-    Ok(result.into_owned())
+/// Put an API description's paths under the tunnel prefix, unless a relative
+/// server URL (already rewritten) does it for them
+fn rewrite_openapi_paths(api: &mut Map<String, Value>, prefix: &str) -> bool {
+    if let Some(Value::String(base_path)) = api.get_mut("basePath") {
+        return match rewrite_path(base_path, prefix) {
+            Some(rewritten) => {
+                *base_path = rewritten;
+                true
+            }
+            None => false,
+        };
+    }
+    let has_relative_server = api
+        .get("servers")
+        .and_then(Value::as_array)
+        .is_some_and(|servers| {
+            servers.iter().any(|server| {
+                server
+                    .get("url")
+                    .and_then(Value::as_str)
+                    .is_some_and(|url| url.starts_with('/') && !url.starts_with("//"))
+            })
+        });
+    if has_relative_server {
+        return false;
+    }
+    let Some(Value::Object(paths)) = api.get_mut("paths") else {
+        return false;
+    };
+
+    let mut changed = false;
+    *paths = std::mem::take(paths)
+        .into_iter()
+        .map(|(path, item)| match rewrite_path(&path, prefix) {
+            Some(rewritten) => {
+                changed = true;
+                (rewritten, item)
+            }
+            None => (path, item),
+        })
+        .collect();
+    changed
+}
+
+/// JSON fields whose values are URLs, from `JSON_URL_FIELDS` (comma-separated)
+/// or [`DEFAULT_JSON_URL_FIELDS`]
+pub fn json_url_fields() -> &'static [String] {
+    static FIELDS: OnceLock<Vec<String>> = OnceLock::new();
+    FIELDS.get_or_init(|| parse_json_url_fields(std::env::var("JSON_URL_FIELDS").ok().as_deref()))
+}
+
+/// Parse a comma-separated list of JSON field names, falling back to the defaults
+fn parse_json_url_fields(fields: Option<&str>) -> Vec<String> {
+    let fields: Vec<String> = fields
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(str::to_string)
+        .collect();
+    if fields.is_empty() {
+        return DEFAULT_JSON_URL_FIELDS.map(str::to_string).to_vec();
+    }
+    fields
 }
 
 #[cfg(test)]
@@ -451,7 +775,7 @@ mod tests {
     #[test]
     fn test_rewrite_html_href() {
         let html = r#"<a href="/api/users">Users</a>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         assert!(result.contains(r#"<a href="/abc123/api/users">Users</a>"#));
         assert!(result.contains("window.__TUNNEL_CONTEXT__"));
     }
@@ -459,21 +783,21 @@ mod tests {
     #[test]
     fn test_rewrite_html_src() {
         let html = r#"<img src="/images/logo.png">"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         assert!(result.contains(r#"<img src="/abc123/images/logo.png">"#));
     }
 
     #[test]
     fn test_rewrite_html_action() {
         let html = r#"<form action="/submit">...</form>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         assert!(result.contains(r#"<form action="/abc123/submit">...</form>"#));
     }
 
     #[test]
     fn test_dont_rewrite_external_url() {
         let html = r#"<a href="https://example.com/page">External</a>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // External URL should be unchanged
         assert!(result.contains(r#"href="https://example.com/page""#));
     }
@@ -481,7 +805,7 @@ mod tests {
     #[test]
     fn test_dont_rewrite_protocol_relative_url() {
         let html = r#"<script src="//cdn.example.com/script.js"></script>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Protocol-relative URL should be unchanged
         assert!(result.contains(r#"src="//cdn.example.com/script.js""#));
     }
@@ -489,7 +813,7 @@ mod tests {
     #[test]
     fn test_dont_rewrite_data_url() {
         let html = r#"<img src="data:image/png;base64,iVBOR...">"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Data URL should be unchanged
         assert!(result.contains(r#"src="data:image/png;base64,iVBOR...""#));
     }
@@ -497,7 +821,7 @@ mod tests {
     #[test]
     fn test_dont_rewrite_anchor() {
         let html = "<a href=\"#section\">Jump</a>";
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Anchor should be unchanged
         assert!(result.contains("href=\"#section\""));
     }
@@ -505,12 +829,174 @@ mod tests {
     #[test]
     fn test_dont_double_prefix() {
         let html = r#"<a href="/abc123/api/users">Already prefixed</a>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Should not double-prefix
         assert!(result.contains(r#"href="/abc123/api/users""#));
         assert!(!result.contains(r#"href="/abc123/abc123/api/users""#));
     }
 
+    #[test]
+    fn test_rewrite_html_unquoted_and_single_quoted_attributes() {
+        let html = "<a href='/one'>1</a><a href=/two>2</a>";
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(r#"href="/abc123/one""#));
+        assert!(result.contains(r#"href="/abc123/two""#));
+    }
+
+    #[test]
+    fn test_rewrite_html_srcset() {
+        let html = r#"<img srcset="/img/a.png 1x, https://cdn.example.com/b.png 2x,/img/c.png">"#;
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(
+            r#"srcset="/abc123/img/a.png 1x, https://cdn.example.com/b.png 2x, /abc123/img/c.png""#
+        ));
+    }
+
+    #[test]
+    fn test_parse_srcset() {
+        assert_eq!(
+            parse_srcset("/a.png 1x,/b.png, /c.png 480w"),
+            vec![("/a.png", "1x"), ("/b.png", ""), ("/c.png", "480w")]
+        );
+        // Commas inside a URL don't split candidates
+        assert_eq!(
+            parse_srcset("data:image/png;base64,iVBOR 1x, /img?size=1,2 2x"),
+            vec![
+                ("data:image/png;base64,iVBOR", "1x"),
+                ("/img?size=1,2", "2x")
+            ]
+        );
+        assert!(parse_srcset(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_html_srcset_with_data_url() {
+        let html = r#"<img srcset="data:image/png;base64,iVBOR 1x, /img/b.png 2x">"#;
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(
+            result.contains(r#"srcset="data:image/png;base64,iVBOR 1x, /abc123/img/b.png 2x""#)
+        );
+    }
+
+    #[test]
+    fn test_rewrite_html_lazy_loading_and_poster() {
+        let html = concat!(
+            r#"<img data-src="/img/lazy.png" data-srcset="/img/lazy.png 1x, /img/lazy@2x.png 2x">"#,
+            r#"<video poster="/media/poster.jpg" src="/media/clip.mp4"></video>"#,
+        );
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(r#"data-src="/abc123/img/lazy.png""#));
+        assert!(
+            result.contains(r#"data-srcset="/abc123/img/lazy.png 1x, /abc123/img/lazy@2x.png 2x""#)
+        );
+        assert!(result.contains(r#"poster="/abc123/media/poster.jpg""#));
+        assert!(result.contains(r#"src="/abc123/media/clip.mp4""#));
+    }
+
+    #[test]
+    fn test_rewrite_html_inline_styles() {
+        let html = concat!(
+            r#"<div style="background:url(/img/bg.png); color: red"></div>"#,
+            r#"<div style="background-image: url('https://cdn.example.com/bg.png')"></div>"#,
+            "<style>.hero { background: url(\"/img/hero.png\"); }</style>",
+        );
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(r#"style="background:url(/abc123/img/bg.png); color: red""#));
+        assert!(result.contains("url('https://cdn.example.com/bg.png')"));
+        assert!(result.contains(r#".hero { background: url("/abc123/img/hero.png"); }"#));
+    }
+
+    #[test]
+    fn test_rewrite_html_meta_refresh() {
+        let html =
+            r#"<html><head><meta http-equiv="Refresh" content="5; url=/login"></head></html>"#;
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(r#"content="5; url=/abc123/login""#));
+    }
+
+    #[test]
+    fn test_dont_rewrite_markup_inside_scripts() {
+        // Templates in scripts aren't attributes, and only path literals change
+        let html = r#"<script>const row = '<a href="/items">'; fetch('/api/items');</script>"#;
+        let result = rewrite_html(html, "/abc123", false).unwrap();
+        assert!(result.contains(r#"'<a href="/items">'"#));
+        assert!(result.contains("fetch('/abc123/api/items')"));
+    }
+
+    #[test]
+    fn test_rewrite_inline_javascript_escaped_quotes() {
+        let script = r#"const s = 'it\'s'; load("/api/x");"#;
+        assert_eq!(
+            rewrite_inline_javascript(script, "/abc123"),
+            r#"const s = 'it\'s'; load("/abc123/api/x");"#
+        );
+    }
+
+    fn redirect(status_code: u16, location: &str) -> HttpResponse {
+        let mut response = HttpResponse::new("req_1".to_string(), status_code);
+        response
+            .headers
+            .insert("location".to_string(), vec![location.to_string()]);
+        response
+    }
+
+    #[test]
+    fn test_rewrite_redirect_location() {
+        for status_code in [301, 302, 307] {
+            let mut response = redirect(status_code, "/login?next=%2Fhome");
+            assert!(rewrite_response_headers(&mut response, "abc123"));
+            assert_eq!(
+                response.headers["location"],
+                vec!["/abc123/login?next=%2Fhome"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_dont_rewrite_external_or_prefixed_location() {
+        for location in [
+            "https://example.com/login",
+            "//example.com/login",
+            "/abc123/login",
+            "login",
+        ] {
+            let mut response = redirect(302, location);
+            assert!(!rewrite_response_headers(&mut response, "abc123"));
+            assert_eq!(response.headers["location"], vec![location]);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_other_url_headers() {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response
+            .headers
+            .insert("Content-Location".to_string(), vec!["/items/1".to_string()]);
+        response
+            .headers
+            .insert("refresh".to_string(), vec!["0; url=/done".to_string()]);
+        response.headers.insert(
+            "link".to_string(),
+            vec![
+                r#"</app.css>; rel=preload; as=style, <https://cdn.example.com/x.js>; rel=preload"#
+                    .to_string(),
+            ],
+        );
+
+        assert!(rewrite_response_headers(&mut response, "abc123"));
+        assert_eq!(
+            response.headers["Content-Location"],
+            vec!["/abc123/items/1"]
+        );
+        assert_eq!(response.headers["refresh"], vec!["0; url=/abc123/done"]);
+        assert_eq!(
+            response.headers["link"],
+            vec![
+                r#"</abc123/app.css>; rel=preload; as=style, <https://cdn.example.com/x.js>; rel=preload"#
+            ]
+        );
+    }
+
     #[test]
     fn test_rewrite_css_url() {
         let css = r#"background: url('/images/bg.png');"#;
@@ -543,14 +1029,14 @@ mod tests {
     fn test_rewrite_json_api_path() {
         let json = r#"{"url": "/api/users"}"#;
         let result = rewrite_json(json, "/abc123").unwrap();
-        assert_eq!(result, r#"{"url": "/abc123/api/users"}"#);
+        assert_eq!(result, r#"{"url":"/abc123/api/users"}"#);
     }
 
     #[test]
     fn test_rewrite_json_versioned_api() {
         let json = r#"{"baseUrl": "/v1/resources"}"#;
         let result = rewrite_json(json, "/abc123").unwrap();
-        assert_eq!(result, r#"{"baseUrl": "/abc123/v1/resources"}"#);
+        assert_eq!(result, r#"{"baseUrl":"/abc123/v1/resources"}"#);
     }
 
     #[test]
@@ -568,6 +1054,89 @@ mod tests {
         assert_eq!(result, json);
     }
 
+    #[test]
+    fn test_rewrite_json_nested_fields() {
+        let json = r##"{
+            "_links": {"self": {"href": "/orders/1"}, "items": [{"href": "/orders/1/items"}]},
+            "schema": {"$ref": "/schemas/order.json"},
+            "local": {"$ref": "#/components/schemas/Order"},
+            "description": "See /api/docs",
+            "endpoints": ["/api/a"]
+        }"##;
+        let result = rewrite_json(json, "/abc123").unwrap();
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["_links"]["self"]["href"], "/abc123/orders/1");
+        assert_eq!(
+            result["_links"]["items"][0]["href"],
+            "/abc123/orders/1/items"
+        );
+        assert_eq!(result["schema"]["$ref"], "/abc123/schemas/order.json");
+        assert_eq!(result["local"]["$ref"], "#/components/schemas/Order");
+        // Strings outside URL fields are never touched
+        assert_eq!(result["description"], "See /api/docs");
+        assert_eq!(result["endpoints"][0], "/api/a");
+    }
+
+    #[test]
+    fn test_rewrite_openapi_document() {
+        // Relative servers carry the prefix; paths stay relative to them
+        let json = r#"{"openapi": "3.0.0",
+            "servers": [{"url": "https://api.example.com"}, {"url": "/v1"}],
+            "paths": {"/users": {}}}"#;
+        let result: Value = serde_json::from_str(&rewrite_json(json, "/abc123").unwrap()).unwrap();
+        assert_eq!(result["servers"][0]["url"], "https://api.example.com");
+        assert_eq!(result["servers"][1]["url"], "/abc123/v1");
+        assert!(result["paths"].get("/users").is_some());
+
+        // Without a relative server the paths themselves are prefixed
+        let json = r#"{"openapi": "3.1.0", "paths": {"/users": {}, "/users/{id}": {}}}"#;
+        let result: Value = serde_json::from_str(&rewrite_json(json, "/abc123").unwrap()).unwrap();
+        assert!(result["paths"].get("/abc123/users").is_some());
+        assert!(result["paths"].get("/abc123/users/{id}").is_some());
+        assert!(result["paths"].get("/users").is_none());
+
+        // Swagger 2 documents have a basePath instead
+        let json = r#"{"swagger": "2.0", "basePath": "/api", "paths": {"/users": {}}}"#;
+        let result: Value = serde_json::from_str(&rewrite_json(json, "/abc123").unwrap()).unwrap();
+        assert_eq!(result["basePath"], "/abc123/api");
+        assert!(result["paths"].get("/users").is_some());
+
+        // Paths of anything else are just data
+        let json = r#"{"paths": {"/users": {}}}"#;
+        assert_eq!(rewrite_json(json, "/abc123").unwrap(), json);
+    }
+
+    #[test]
+    fn test_rewrite_json_keeps_unchanged_and_invalid_bodies() {
+        let json = "{\n  \"name\": \"x\"\n}";
+        assert_eq!(rewrite_json(json, "/abc123").unwrap(), json);
+        assert_eq!(rewrite_json("not json", "/abc123").unwrap(), "not json");
+
+        // Pretty-printed documents stay pretty
+        let json = "{\n  \"url\": \"/api\"\n}";
+        assert_eq!(
+            rewrite_json(json, "/abc123").unwrap(),
+            "{\n  \"url\": \"/abc123/api\"\n}"
+        );
+    }
+
+    #[test]
+    fn test_json_url_fields_allowlist() {
+        assert!(parse_json_url_fields(None).contains(&"href".to_string()));
+        assert_eq!(
+            parse_json_url_fields(Some(" , ")).len(),
+            DEFAULT_JSON_URL_FIELDS.len()
+        );
+
+        let fields = parse_json_url_fields(Some("link, next"));
+        assert_eq!(fields, vec!["link", "next"]);
+        let json = r#"{"link":"/a","url":"/b"}"#;
+        assert_eq!(
+            rewrite_json_fields(json, "/abc123", &fields).unwrap(),
+            r#"{"link":"/abc123/a","url":"/b"}"#
+        );
+    }
+
     #[test]
     fn test_rewrite_response_content_html_full() {
         let html = r#"<html><head></head><body><a href="/api">API</a></body></html>"#;
@@ -597,6 +1166,112 @@ mod tests {
         assert_eq!(result, html);
     }
 
+    #[test]
+    fn test_rewrite_javascript_modules_and_fetch() {
+        let cases = [
+            (r#"import "/a.js";"#, r#"import "/abc123/a.js";"#),
+            (
+                r#"import x from "/b.js";"#,
+                r#"import x from "/abc123/b.js";"#,
+            ),
+            (
+                "import { y } from '/c.js';",
+                "import { y } from '/abc123/c.js';",
+            ),
+            (
+                r#"export * from "/d.js";"#,
+                r#"export * from "/abc123/d.js";"#,
+            ),
+            (
+                r#"const m = await import("/e.js");"#,
+                r#"const m = await import("/abc123/e.js");"#,
+            ),
+            (
+                "import(`/pages/${name}.js`)",
+                "import(`/abc123/pages/${name}.js`)",
+            ),
+            ("fetch('/api/users')", "fetch('/abc123/api/users')"),
+            (
+                r#"window.fetch( "/api/x", { method: "POST" })"#,
+                r#"window.fetch( "/abc123/api/x", { method: "POST" })"#,
+            ),
+            (
+                "fetch(`/api/items/${id}`)",
+                "fetch(`/abc123/api/items/${id}`)",
+            ),
+            (
+                "import x from\n  '/multi/line.js'",
+                "import x from\n  '/abc123/multi/line.js'",
+            ),
+        ];
+        for (script, expected) in cases {
+            assert_eq!(rewrite_javascript(script, "/abc123"), expected);
+        }
+    }
+
+    #[test]
+    fn test_rewrite_javascript_leaves_other_literals() {
+        let untouched = [
+            r#"import "./relative.js";"#,
+            r#"import React from "react";"#,
+            r#"import "https://cdn.example.com/x.js";"#,
+            r#"import "//cdn.example.com/x.js";"#,
+            r#"import "/abc123/already.js";"#,
+            r#"const path = "/not/a/request";"#,
+            r#"Array.from("/abc");"#,
+            r#"myfetch("/api/x"); api.import("/y");"#,
+            r#"fetch(base + "/api/x");"#,
+            "// fetch('/api/commented')",
+            r#"/* import "/commented.js" */"#,
+            r#"const s = 'it\'s'; const t = "say \"hi\""; const d = a / b;"#,
+        ];
+        for script in untouched {
+            assert_eq!(rewrite_javascript(script, "/abc123"), script);
+        }
+
+        // An unterminated literal ends the scan without losing anything
+        let script = r#"fetch("/api/x"); const broken = "oops"#;
+        assert_eq!(
+            rewrite_javascript(script, "/abc123"),
+            r#"fetch("/abc123/api/x"); const broken = "oops"#
+        );
+    }
+
+    #[test]
+    fn test_rewrite_response_content_javascript() {
+        let script = r#"import { app } from "/static/app.js";"#;
+        let (result, rewritten) = rewrite_response_content(
+            script,
+            "application/javascript",
+            "abc123",
+            RewriteStrategy::FullRewrite,
+        )
+        .unwrap();
+        assert!(!rewritten);
+        assert_eq!(result, script);
+
+        let (result, rewritten) = rewrite_response_content(
+            script,
+            "text/javascript; charset=utf-8",
+            "abc123",
+            RewriteStrategy::Aggressive,
+        )
+        .unwrap();
+        assert!(rewritten);
+        assert_eq!(result, r#"import { app } from "/abc123/static/app.js";"#);
+
+        // Inline module scripts too
+        let html = r#"<script type="module">import "/static/main.js";</script>"#;
+        let (result, _) =
+            rewrite_response_content(html, "text/html", "abc123", RewriteStrategy::Aggressive)
+                .unwrap();
+        assert!(result.contains(r#"import "/abc123/static/main.js";"#));
+        let (result, _) =
+            rewrite_response_content(html, "text/html", "abc123", RewriteStrategy::FullRewrite)
+                .unwrap();
+        assert!(result.contains(r#"import "/static/main.js";"#));
+    }
+
     #[test]
     fn test_rewrite_response_content_css() {
         let css = r#"div { background: url('/img/bg.png'); }"#;
@@ -629,7 +1304,7 @@ mod tests {
     #[test]
     fn test_rewrite_inline_javascript() {
         let html = "<script>\nconst ui = { url: '/openapi.json', path: '/api/v1' };\n</script>";
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         assert!(result.contains("'/abc123/openapi.json'"));
         assert!(result.contains("'/abc123/api/v1'"));
     }
@@ -642,7 +1317,7 @@ mod tests {
         oauth2RedirectUrl: window.location.origin + '/docs/oauth2-redirect',
     })
     </script>"#;
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         assert!(result.contains("url: '/abc123/openapi.json'"));
         assert!(result.contains("+ '/abc123/docs/oauth2-redirect'"));
     }
@@ -650,7 +1325,7 @@ mod tests {
     #[test]
     fn test_dont_rewrite_short_js_paths() {
         let html = "<script>const x = '/';</script>";
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Very short paths like '/' should not be rewritten
         assert!(result.contains("const x = '/';"));
     }
@@ -658,7 +1333,7 @@ mod tests {
     #[test]
     fn test_inject_tunnel_context() {
         let html = "<html><head></head><body></body></html>";
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
         // Should inject tunnel context script
         assert!(result.contains("window.__TUNNEL_CONTEXT__"));
         assert!(result.contains("tunnelId: 'abc123'"));
@@ -670,7 +1345,7 @@ mod tests {
     fn test_complex_html_document() {
         let html = "<!DOCTYPE html>\n<html>\n<head>\n    <title>Test Page</title>\n    <link rel=\"stylesheet\" href=\"/static/style.css\">\n    <script src=\"/static/app.js\"></script>\n</head>\n<body>\n    <a href=\"/api/users\">Users</a>\n    <a href=\"https://external.com\">External</a>\n    <a href=\"#section\">Anchor</a>\n    <img src=\"/images/logo.png\">\n    <form action=\"/submit\" method=\"POST\">\n        <input type=\"submit\">\n    </form>\n</body>\n</html>";
 
-        let result = rewrite_html(html, "/abc123").unwrap();
+        let result = rewrite_html(html, "/abc123", false).unwrap();
 
         // Should rewrite local paths
         assert!(result.contains("href=\"/abc123/static/style.css\""));
@@ -0,0 +1,301 @@
+//! Error pages for visitors of a tunnel
+//!
+//! When a tunnel's agent can't be reached (502/503) or doesn't answer in time
+//! (504), browsers get an HTML page naming the tunnel and suggesting when to
+//! retry, instead of a one-line plain-text body. Clients that don't ask for HTML
+//! (no `text/html` in `Accept`, as with curl or `fetch` from scripts) keep the
+//! plain text. Pages can be branded with:
+//!
+//! - `ERROR_PAGE_BRAND`: the name shown on the page (defaults to "HTTP Tunnel")
+//! - `ERROR_PAGE_TEMPLATE`: a whole template replacing the built-in one
+//! - `ERROR_PAGE_TEMPLATE_S3`: the same, read from `s3://bucket/key` once per
+//!   container (the built-in template is used if it can't be read)
+//!
+//! Templates may use `{{status}}`, `{{title}}`, `{{message}}`, `{{retry_hint}}`,
+//! `{{tunnel_id}}`, `{{request_id}}` and `{{brand}}`; values are HTML-escaped.
+
+use anyhow::{Context, Result};
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_lambda_events::encodings::Body;
+use aws_sdk_s3::Client as S3Client;
+use http::HeaderValue;
+use http::header::{ACCEPT, CACHE_CONTROL, CONTENT_TYPE};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tracing::{info, warn};
+
+use crate::SharedClients;
+
+/// Template used unless one is configured
+pub const DEFAULT_TEMPLATE: &str = include_str!("error_page.html");
+
+/// Brand shown unless `ERROR_PAGE_BRAND` is set
+pub const DEFAULT_BRAND: &str = "HTTP Tunnel";
+
+/// How long loading a template from S3 may hold up the first error page
+const S3_LOAD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The error page template, loaded once per container
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    template: OnceCell<String>,
+}
+
+impl ErrorPages {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The configured template, loading it on first use
+    pub async fn template(&self, sdk_config: &aws_config::SdkConfig) -> &str {
+        self.template
+            .get_or_init(|| load_template(sdk_config))
+            .await
+            .as_str()
+    }
+}
+
+/// What an error page says
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorPage<'a> {
+    pub status: i64,
+    /// The plain-text explanation also sent to non-browser clients
+    pub message: &'a str,
+    pub tunnel_id: &'a str,
+    pub request_id: &'a str,
+}
+
+impl ErrorPage<'_> {
+    /// Short heading for the page
+    pub fn title(&self) -> &'static str {
+        match self.status {
+            502 => "Tunnel offline",
+            503 | 429 => "Tunnel busy",
+            504 => "Tunnel not responding",
+            _ => "Tunnel unavailable",
+        }
+    }
+
+    /// When it's worth trying again
+    pub fn retry_hint(&self) -> &'static str {
+        match self.status {
+            502 => "The service behind this tunnel isn't connected. Try again in a minute or two.",
+            503 | 429 => "Too many requests are reaching this tunnel. Try again in a few seconds.",
+            504 => "The service behind this tunnel took too long to answer. Try again shortly.",
+            _ => "Try again later.",
+        }
+    }
+
+    /// Fill in `template`, leaving unknown placeholders as they are
+    pub fn render(&self, template: &str, brand: &str) -> String {
+        let status = self.status.to_string();
+        let mut page = String::with_capacity(template.len() + 256);
+        let mut rest = template;
+        // One pass, so placeholders inside values are never expanded
+        while let Some(start) = rest.find("{{") {
+            page.push_str(&rest[..start]);
+            let after = &rest[start + 2..];
+            let Some(end) = after.find("}}") else {
+                rest = &rest[start..];
+                break;
+            };
+            let value = match after[..end].trim() {
+                "status" => status.as_str(),
+                "title" => self.title(),
+                "message" => self.message,
+                "retry_hint" => self.retry_hint(),
+                "tunnel_id" => self.tunnel_id,
+                "request_id" => self.request_id,
+                "brand" => brand,
+                _ => {
+                    page.push_str(&rest[start..start + 2 + end + 2]);
+                    rest = &after[end + 2..];
+                    continue;
+                }
+            };
+            escape_html(value, &mut page);
+            rest = &after[end + 2..];
+        }
+        page.push_str(rest);
+        page
+    }
+}
+
+/// Whether the client asked for HTML, as browsers navigating to a page do
+pub fn wants_html(request: &ApiGatewayProxyRequest) -> bool {
+    request
+        .headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|accept| accept.contains("text/html"))
+}
+
+/// Turn a tunnel error response into an error page if the client wants HTML
+///
+/// Headers such as `x-tunnel-error` and `x-request-id` are kept.
+pub async fn apply(
+    response: &mut ApiGatewayProxyResponse,
+    request: &ApiGatewayProxyRequest,
+    page: &ErrorPage<'_>,
+    clients: &SharedClients,
+) {
+    if !wants_html(request) {
+        return;
+    }
+    let template = clients.error_pages.template(&clients.sdk_config).await;
+    let html = page.render(template, &brand());
+
+    response.multi_value_headers.remove(CONTENT_TYPE);
+    response.multi_value_headers.remove(CACHE_CONTROL);
+    response.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    // The tunnel may be back any moment
+    response
+        .headers
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response.body = Some(Body::Text(html));
+    response.is_base64_encoded = false;
+}
+
+fn brand() -> String {
+    std::env::var("ERROR_PAGE_BRAND")
+        .ok()
+        .filter(|brand| !brand.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_BRAND.to_string())
+}
+
+async fn load_template(sdk_config: &aws_config::SdkConfig) -> String {
+    if let Ok(template) = std::env::var("ERROR_PAGE_TEMPLATE")
+        && !template.trim().is_empty()
+    {
+        return template;
+    }
+    if let Ok(location) = std::env::var("ERROR_PAGE_TEMPLATE_S3")
+        && !location.trim().is_empty()
+    {
+        let loaded = tokio::time::timeout(
+            S3_LOAD_TIMEOUT,
+            load_s3_template(sdk_config, location.trim()),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        match loaded {
+            Ok(template) => {
+                info!("Loaded error page template from {}", location);
+                return template;
+            }
+            Err(e) => warn!(
+                "Failed to load error page template from {}, using the built-in one: {:#}",
+                location, e
+            ),
+        }
+    }
+    DEFAULT_TEMPLATE.to_string()
+}
+
+async fn load_s3_template(sdk_config: &aws_config::SdkConfig, location: &str) -> Result<String> {
+    let (bucket, key) = parse_s3_location(location)
+        .with_context(|| format!("Expected s3://bucket/key, got {}", location))?;
+    let object = S3Client::new(sdk_config)
+        .get_object()
+        .bucket(bucket)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to get object")?;
+    let bytes = object
+        .body
+        .collect()
+        .await
+        .context("Failed to read object")?
+        .into_bytes();
+    String::from_utf8(bytes.to_vec()).context("Template isn't UTF-8")
+}
+
+/// Split `s3://bucket/key` into bucket and key
+fn parse_s3_location(location: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = location.strip_prefix("s3://")?.split_once('/')?;
+    (!bucket.is_empty() && !key.is_empty()).then_some((bucket, key))
+}
+
+fn escape_html(value: &str, out: &mut String) {
+    for c in value.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(status: i64) -> ErrorPage<'static> {
+        ErrorPage {
+            status,
+            message: "Tunnel offline: the agent is no longer connected",
+            tunnel_id: "abc123def456",
+            request_id: "req_1",
+        }
+    }
+
+    #[test]
+    fn test_render_default_template() {
+        let html = page(502).render(DEFAULT_TEMPLATE, "Acme Demos");
+        assert!(html.contains("<h1>Tunnel offline</h1>"));
+        assert!(html.contains("abc123def456"));
+        assert!(html.contains("req_1"));
+        assert!(html.contains("Acme Demos"));
+        assert!(html.contains("Try again in a minute"));
+        assert!(!html.contains("{{"));
+
+        let html = page(504).render(DEFAULT_TEMPLATE, DEFAULT_BRAND);
+        assert!(html.contains("<h1>Tunnel not responding</h1>"));
+    }
+
+    #[test]
+    fn test_render_escapes_values() {
+        let page = ErrorPage {
+            tunnel_id: "<script>{{brand}}</script>",
+            ..page(502)
+        };
+        assert_eq!(
+            page.render("{{ tunnel_id }} by {{brand}} {{unknown}} {{", "A & B"),
+            "&lt;script&gt;{{brand}}&lt;/script&gt; by A &amp; B {{unknown}} {{"
+        );
+    }
+
+    #[test]
+    fn test_wants_html() {
+        let mut request = ApiGatewayProxyRequest::default();
+        assert!(!wants_html(&request));
+        request
+            .headers
+            .insert(ACCEPT, HeaderValue::from_static("application/json"));
+        assert!(!wants_html(&request));
+        request.headers.insert(
+            ACCEPT,
+            HeaderValue::from_static("text/html,application/xhtml+xml,*/*;q=0.8"),
+        );
+        assert!(wants_html(&request));
+    }
+
+    #[test]
+    fn test_parse_s3_location() {
+        assert_eq!(
+            parse_s3_location("s3://branding/pages/error.html"),
+            Some(("branding", "pages/error.html"))
+        );
+        for invalid in ["branding/error.html", "s3://branding", "s3:///error.html"] {
+            assert_eq!(parse_s3_location(invalid), None, "{}", invalid);
+        }
+    }
+}
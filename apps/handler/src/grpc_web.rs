@@ -0,0 +1,243 @@
+//! gRPC-Web translation for gRPC services behind a tunnel
+//!
+//! gRPC ends every call with trailers (`grpc-status`, `grpc-message`), which
+//! neither API Gateway nor Lambda can send to the client. gRPC-Web clients, such
+//! as browsers, don't need them: the trailers travel at the end of the body as a
+//! frame flagged `0x80`. Requests from those clients are turned into plain gRPC
+//! for the local service, and its response (with the trailers the agent collected)
+//! back into gRPC-Web.
+//!
+//! The `-text` variants carry the body Base64-encoded.
+
+use anyhow::{Context, Result};
+use http_tunnel_common::protocol::{HttpRequest, HttpResponse};
+use http_tunnel_common::{decode_body, encode_body};
+use std::collections::HashMap;
+
+/// Flag marking a gRPC-Web frame as trailers rather than a message
+const TRAILER_FRAME_FLAG: u8 = 0x80;
+
+/// A gRPC-Web call translated to gRPC, to translate its response back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcWebCall {
+    /// The client's content type, restored on the response
+    content_type: String,
+    /// Whether bodies are Base64-encoded (`application/grpc-web-text`)
+    text: bool,
+}
+
+/// Turn a gRPC-Web request into gRPC, returning `None` for anything else
+pub fn translate_request(request: &mut HttpRequest) -> Result<Option<GrpcWebCall>> {
+    let Some(content_type) = request
+        .headers
+        .get("content-type")
+        .and_then(|values| values.first())
+    else {
+        return Ok(None);
+    };
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let (text, suffix) = if let Some(suffix) = media_type.strip_prefix("application/grpc-web-text")
+    {
+        (true, suffix)
+    } else if let Some(suffix) = media_type.strip_prefix("application/grpc-web") {
+        (false, suffix)
+    } else {
+        return Ok(None);
+    };
+    if !suffix.is_empty() && !suffix.starts_with('+') {
+        return Ok(None);
+    }
+
+    let call = GrpcWebCall {
+        content_type: content_type.clone(),
+        text,
+    };
+    if text {
+        let encoded = decode_body(&request.body).context("Invalid request body encoding")?;
+        request.body = encode_body(&decode_text(&encoded)?);
+    }
+    request.headers.insert(
+        "content-type".to_string(),
+        vec![format!("application/grpc{}", suffix)],
+    );
+    request
+        .headers
+        .insert("te".to_string(), vec!["trailers".to_string()]);
+    request.headers.remove("content-length");
+    Ok(Some(call))
+}
+
+/// Turn the local service's gRPC response back into gRPC-Web, moving its
+/// trailers into the body
+pub fn translate_response(response: &mut HttpResponse, call: &GrpcWebCall) -> Result<()> {
+    let mut body = decode_body(&response.body).context("Invalid response body encoding")?;
+    let trailers = std::mem::take(&mut response.trailers);
+    if !trailers.is_empty() {
+        body.extend(trailer_frame(&trailers));
+    }
+    if call.text {
+        body = encode_body(&body).into_bytes();
+    }
+
+    response.body = encode_body(&body);
+    response
+        .headers
+        .insert("content-type".to_string(), vec![call.content_type.clone()]);
+    response.headers.remove("content-length");
+    Ok(())
+}
+
+/// Encode trailers as a gRPC-Web trailer frame
+fn trailer_frame(trailers: &HashMap<String, Vec<String>>) -> Vec<u8> {
+    let mut names: Vec<&String> = trailers.keys().collect();
+    names.sort();
+
+    let mut block = String::new();
+    for name in names {
+        for value in &trailers[name] {
+            block.push_str(&format!("{}: {}\r\n", name.to_ascii_lowercase(), value));
+        }
+    }
+
+    let mut frame = Vec::with_capacity(5 + block.len());
+    frame.push(TRAILER_FRAME_FLAG);
+    frame.extend((block.len() as u32).to_be_bytes());
+    frame.extend(block.into_bytes());
+    frame
+}
+
+/// Decode a `-text` body, which clients may send as several padded Base64
+/// segments back to back
+fn decode_text(text: &[u8]) -> Result<Vec<u8>> {
+    let text: Vec<u8> = text
+        .iter()
+        .copied()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+
+    let mut decoded = Vec::new();
+    let mut start = 0;
+    for end in 1..=text.len() {
+        let segment_ends = end == text.len() || (text[end - 1] == b'=' && text[end] != b'=');
+        if segment_ends {
+            let segment =
+                std::str::from_utf8(&text[start..end]).context("Invalid gRPC-Web text")?;
+            decoded.extend(decode_body(segment).context("Invalid gRPC-Web text")?);
+            start = end;
+        }
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grpc_web_request(content_type: &str, body: &[u8]) -> HttpRequest {
+        let mut request = HttpRequest::new(
+            "POST".to_string(),
+            "/greeter.Greeter/SayHello".to_string(),
+            "req_1".to_string(),
+            0,
+        );
+        request
+            .headers
+            .insert("content-type".to_string(), vec![content_type.to_string()]);
+        request
+            .headers
+            .insert("content-length".to_string(), vec!["7".to_string()]);
+        request.body = encode_body(body);
+        request
+    }
+
+    #[test]
+    fn test_translate_request() {
+        let mut request = grpc_web_request("application/grpc-web+proto", b"\0\0\0\0\x02hi");
+        let call = translate_request(&mut request).unwrap().unwrap();
+        assert!(!call.text);
+        assert_eq!(
+            request.headers["content-type"],
+            vec!["application/grpc+proto"]
+        );
+        assert_eq!(request.headers["te"], vec!["trailers"]);
+        assert!(!request.headers.contains_key("content-length"));
+        assert_eq!(decode_body(&request.body).unwrap(), b"\0\0\0\0\x02hi");
+
+        // Text bodies are decoded for the local service
+        let mut request = grpc_web_request(
+            "application/grpc-web-text",
+            encode_body(b"\0\0\0\0\x02hi").as_bytes(),
+        );
+        let call = translate_request(&mut request).unwrap().unwrap();
+        assert!(call.text);
+        assert_eq!(request.headers["content-type"], vec!["application/grpc"]);
+        assert_eq!(decode_body(&request.body).unwrap(), b"\0\0\0\0\x02hi");
+
+        // Plain gRPC and everything else is forwarded untouched
+        let mut request = grpc_web_request("application/grpc", b"");
+        assert!(translate_request(&mut request).unwrap().is_none());
+        let mut request = grpc_web_request("application/grpc-webby", b"");
+        assert!(translate_request(&mut request).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_translate_response() {
+        let call = GrpcWebCall {
+            content_type: "application/grpc-web+proto".to_string(),
+            text: false,
+        };
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.headers.insert(
+            "content-type".to_string(),
+            vec!["application/grpc+proto".to_string()],
+        );
+        response.body = encode_body(b"\0\0\0\0\x01x");
+        response
+            .trailers
+            .insert("grpc-status".to_string(), vec!["0".to_string()]);
+        response
+            .trailers
+            .insert("grpc-message".to_string(), vec!["OK".to_string()]);
+
+        translate_response(&mut response, &call).unwrap();
+        assert!(response.trailers.is_empty());
+        assert_eq!(
+            response.headers["content-type"],
+            vec!["application/grpc-web+proto"]
+        );
+
+        let block = b"grpc-message: OK\r\ngrpc-status: 0\r\n";
+        let mut expected = b"\0\0\0\0\x01x".to_vec();
+        expected.push(0x80);
+        expected.extend((block.len() as u32).to_be_bytes());
+        expected.extend(block);
+        assert_eq!(decode_body(&response.body).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_translate_text_response() {
+        let call = GrpcWebCall {
+            content_type: "application/grpc-web-text".to_string(),
+            text: true,
+        };
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = encode_body(b"\0\0\0\0\x01x");
+
+        translate_response(&mut response, &call).unwrap();
+        let text = decode_body(&response.body).unwrap();
+        assert_eq!(decode_text(&text).unwrap(), b"\0\0\0\0\x01x");
+    }
+
+    #[test]
+    fn test_decode_text_segments() {
+        let text = format!("{}{}", encode_body(b"a"), encode_body(b"bc"));
+        assert_eq!(decode_text(text.as_bytes()).unwrap(), b"abc");
+        assert_eq!(decode_text(b"YWJj\r\n").unwrap(), b"abc");
+        assert!(decode_text(b"!!!").is_err());
+    }
+}
@@ -0,0 +1,579 @@
+//! AdminHandler - Handles the admin API under `/_admin` on the base domain
+//!
+//! Routes:
+//! - `GET /_admin/tunnels` - active tunnels with their connections and request
+//!   counts for the last hour
+//! - `GET /_admin/tunnels/{tunnel_id}` - one tunnel with its rolling-window stats
+//!   and lifetime analytics
+//! - `DELETE /_admin/tunnels/{tunnel_id}` - revoke a tunnel: tell its agents to
+//!   stop, close their connections and delete the connection metadata
+//! - `GET /_admin/tunnels/{tunnel_id}/stats` - rolling-window latency percentiles
+//!   and status-class counts for a tunnel
+//!
+//! When authentication is enabled, callers must present a bearer token whose
+//! subject owns the tunnel. Tunnels owned by someone else are reported as not
+//! found so their existence isn't leaked. Subjects listed in `ADMIN_SUBJECTS`
+//! (comma-separated) are operators and may manage every tunnel. Listing,
+//! inspecting and revoking tunnels are unavailable when authentication is
+//! disabled, since anyone could otherwise revoke anyone's tunnel.
+
+use anyhow::{Context, Result};
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::STATS_WINDOWS_SECS;
+use http_tunnel_common::protocol::{ErrorCode, Message};
+use lambda_runtime::Error;
+use serde_json::{Value, json};
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+use tracing::{debug, error, info, warn};
+
+use super::json_response;
+use crate::store::TunnelStore;
+use crate::{
+    SharedClients, analytics, auth, connection_metadata_from_item, send_message_with_encoding,
+    stats,
+};
+
+/// Path prefix reserved for the admin API
+pub const ADMIN_PATH_PREFIX: &str = "/_admin";
+
+/// Check whether a request targets the admin API (base domain only, so tunnel
+/// subdomains can still forward their own `/_admin` paths)
+pub fn is_admin_request(host: &str, path: &str, base_domain: &str) -> bool {
+    is_base_domain_path(host, path, base_domain, ADMIN_PATH_PREFIX)
+}
+
+/// Check whether a request on the base domain is for `prefix` or beneath it
+pub(crate) fn is_base_domain_path(host: &str, path: &str, base_domain: &str, prefix: &str) -> bool {
+    let host = host.split(':').next().unwrap_or(host);
+    host == base_domain
+        && (path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/')))
+}
+
+/// Admin API routes
+#[derive(Debug, PartialEq, Eq)]
+enum AdminRoute<'a> {
+    ListTunnels,
+    Tunnel { tunnel_id: &'a str },
+    RevokeTunnel { tunnel_id: &'a str },
+    TunnelStats { tunnel_id: &'a str },
+}
+
+impl<'a> AdminRoute<'a> {
+    fn parse(method: &http::Method, path: &'a str) -> Option<Self> {
+        let rest = path.strip_prefix(ADMIN_PATH_PREFIX)?.trim_end_matches('/');
+        let segments: Vec<&str> = rest.trim_start_matches('/').split('/').collect();
+
+        match (method, segments.as_slice()) {
+            (&http::Method::GET, ["tunnels"]) => Some(AdminRoute::ListTunnels),
+            (&http::Method::GET, ["tunnels", tunnel_id]) => Some(AdminRoute::Tunnel { tunnel_id }),
+            (&http::Method::DELETE, ["tunnels", tunnel_id]) => {
+                Some(AdminRoute::RevokeTunnel { tunnel_id })
+            }
+            (&http::Method::GET, ["tunnels", tunnel_id, "stats"]) => {
+                Some(AdminRoute::TunnelStats { tunnel_id })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Token subjects allowed to manage every tunnel (`ADMIN_SUBJECTS`)
+fn admin_subjects() -> &'static [String] {
+    static ADMIN_SUBJECTS: OnceLock<Vec<String>> = OnceLock::new();
+    ADMIN_SUBJECTS.get_or_init(|| {
+        std::env::var("ADMIN_SUBJECTS")
+            .map(|subjects| parse_subjects(&subjects))
+            .unwrap_or_default()
+    })
+}
+
+/// Parse a comma-separated list of token subjects
+fn parse_subjects(subjects: &str) -> Vec<String> {
+    subjects
+        .split(',')
+        .map(str::trim)
+        .filter(|subject| !subject.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Tunnels an authenticated caller may manage
+#[derive(Debug, PartialEq, Eq)]
+enum Scope<'a> {
+    /// Operators manage every tunnel
+    All,
+    /// Everyone else manages the tunnels they opened
+    Owner(&'a str),
+}
+
+impl<'a> Scope<'a> {
+    fn for_subject(subject: &'a str, admins: &[String]) -> Self {
+        if admins.iter().any(|admin| admin == subject) {
+            Scope::All
+        } else {
+            Scope::Owner(subject)
+        }
+    }
+
+    fn permits(&self, owner: Option<&str>) -> bool {
+        match self {
+            Scope::All => true,
+            Scope::Owner(subject) => owner == Some(*subject),
+        }
+    }
+}
+
+/// Handler for admin API requests
+pub async fn handle_admin(
+    request: &ApiGatewayProxyRequest,
+    clients: &SharedClients,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    let path = request.path.as_deref().unwrap_or("/");
+
+    let claims = match auth::authenticate_http_request(request).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            warn!("Admin API authentication failed: {}", e);
+            return Ok(json_response(401, json!({ "error": "Unauthorized" })));
+        }
+    };
+
+    let Some(route) = AdminRoute::parse(&request.http_method, path) else {
+        return Ok(json_response(404, json!({ "error": "Not found" })));
+    };
+
+    let scope = claims
+        .as_ref()
+        .map(|claims| Scope::for_subject(&claims.sub, admin_subjects()));
+
+    let response = match (route, scope) {
+        (AdminRoute::TunnelStats { tunnel_id }, _) => {
+            tunnel_stats(clients, claims.as_ref(), tunnel_id).await
+        }
+        (_, None) => json_response(
+            403,
+            json!({ "error": "Tunnel administration requires authentication" }),
+        ),
+        (AdminRoute::ListTunnels, Some(scope)) => list_tunnels(clients, &scope).await,
+        (AdminRoute::Tunnel { tunnel_id }, Some(scope)) => {
+            inspect_tunnel(clients, &scope, tunnel_id).await
+        }
+        (AdminRoute::RevokeTunnel { tunnel_id }, Some(scope)) => {
+            revoke_tunnel(clients, &scope, tunnel_id).await
+        }
+    };
+
+    Ok(response)
+}
+
+/// `GET /_admin/tunnels`
+async fn list_tunnels(clients: &SharedClients, scope: &Scope<'_>) -> ApiGatewayProxyResponse {
+    let owner = match scope {
+        Scope::All => None,
+        Scope::Owner(subject) => Some(*subject),
+    };
+    let connections = match scan_connections(&clients.dynamodb, owner).await {
+        Ok(connections) => connections,
+        Err(e) => {
+            error!("Failed to list tunnels: {:#}", e);
+            return json_response(500, json!({ "error": "Internal server error" }));
+        }
+    };
+
+    let mut tunnels = Vec::new();
+    for (tunnel_id, connections) in group_by_tunnel(connections) {
+        let mut tunnel = tunnel_json(&connections);
+        if clients.store.keeps_stats() {
+            tunnel["requests"] = match clients
+                .store
+                .tunnel_stats(&tunnel_id, &STATS_WINDOWS_SECS[1..])
+                .await
+            {
+                Ok(windows) => json!(windows.first().map(|window| window.requests)),
+                Err(e) => {
+                    warn!("Failed to load stats for tunnel {}: {:#}", tunnel_id, e);
+                    Value::Null
+                }
+            };
+        }
+        tunnels.push(tunnel);
+    }
+
+    info!("Listed {} tunnels", tunnels.len());
+    json_response(200, json!({ "tunnels": tunnels }))
+}
+
+/// `GET /_admin/tunnels/{tunnel_id}`
+async fn inspect_tunnel(
+    clients: &SharedClients,
+    scope: &Scope<'_>,
+    tunnel_id: &str,
+) -> ApiGatewayProxyResponse {
+    let connections = match find_tunnel(clients, scope, tunnel_id).await {
+        Ok(connections) => connections,
+        Err(response) => return response,
+    };
+
+    let mut tunnel = tunnel_json(&connections);
+    if clients.store.keeps_stats() {
+        match clients
+            .store
+            .tunnel_stats(tunnel_id, &STATS_WINDOWS_SECS)
+            .await
+        {
+            Ok(windows) => tunnel["windows"] = json!(windows),
+            Err(e) => warn!("Failed to load stats for tunnel {}: {:#}", tunnel_id, e),
+        }
+    }
+    // Analytics live next to the stats in the tunnel stats table
+    if stats::is_stats_enabled() {
+        match analytics::load_tunnel_analytics(&clients.dynamodb, tunnel_id).await {
+            Ok(analytics) => tunnel["analytics"] = json!(analytics),
+            Err(e) => warn!("Failed to load analytics for tunnel {}: {:#}", tunnel_id, e),
+        }
+    }
+    json_response(200, tunnel)
+}
+
+/// `DELETE /_admin/tunnels/{tunnel_id}`
+///
+/// Each agent is sent a `tunnel_revoked` error so it exits instead of
+/// reconnecting, then its connection is closed and its metadata deleted.
+/// Revocation doesn't ban the tunnel ID; a reserved ID stays reserved.
+async fn revoke_tunnel(
+    clients: &SharedClients,
+    scope: &Scope<'_>,
+    tunnel_id: &str,
+) -> ApiGatewayProxyResponse {
+    let connections = match find_tunnel(clients, scope, tunnel_id).await {
+        Ok(connections) => connections,
+        Err(response) => return response,
+    };
+
+    let mut revoked = Vec::new();
+    for connection in &connections {
+        let connection_id = connection.connection_id.as_str();
+        if let Some(apigw_management) = clients.apigw_management() {
+            let message = Message::Error {
+                request_id: None,
+                code: ErrorCode::TunnelRevoked,
+                message: "Tunnel revoked by an administrator".to_string(),
+            };
+            if let Err(e) = send_message_with_encoding(
+                apigw_management,
+                connection_id,
+                message,
+                connection.options.encoding,
+            )
+            .await
+            {
+                debug!(
+                    "Failed to notify agent {} of revocation: {:#}",
+                    connection_id, e
+                );
+            }
+            if let Err(e) = apigw_management
+                .delete_connection()
+                .connection_id(connection_id)
+                .send()
+                .await
+            {
+                debug!("Failed to close agent connection {}: {}", connection_id, e);
+            }
+        }
+
+        if let Err(e) = clients.store.delete_connection(connection_id).await {
+            error!("Failed to revoke connection {}: {:#}", connection_id, e);
+            return json_response(500, json!({ "error": "Internal server error" }));
+        }
+        clients.connections.invalidate_connection(connection_id);
+        revoked.push(connection_id);
+    }
+
+    info!(
+        "Revoked tunnel {} ({} connections)",
+        tunnel_id,
+        revoked.len()
+    );
+    json_response(
+        200,
+        json!({ "tunnel_id": tunnel_id, "revoked_connections": revoked }),
+    )
+}
+
+/// `GET /_admin/tunnels/{tunnel_id}/stats`
+async fn tunnel_stats(
+    clients: &SharedClients,
+    claims: Option<&auth::Claims>,
+    tunnel_id: &str,
+) -> ApiGatewayProxyResponse {
+    if http_tunnel_common::validation::validate_tunnel_id(tunnel_id).is_err() {
+        return json_response(400, json!({ "error": "Invalid tunnel ID" }));
+    }
+
+    if let Some(claims) = claims {
+        let owner = clients
+            .store
+            .tunnel_connections(tunnel_id)
+            .await
+            .ok()
+            .and_then(|connections| connections.into_iter().next())
+            .and_then(|metadata| metadata.owner);
+        if owner.as_deref() != Some(claims.sub.as_str()) {
+            return json_response(404, json!({ "error": "Tunnel not found" }));
+        }
+    }
+
+    if !clients.store.keeps_stats() {
+        return json_response(503, json!({ "error": "Tunnel statistics are not enabled" }));
+    }
+
+    match clients
+        .store
+        .tunnel_stats(tunnel_id, &STATS_WINDOWS_SECS)
+        .await
+    {
+        Ok(windows) => {
+            info!("Served stats for tunnel {}", tunnel_id);
+            json_response(200, json!({ "tunnel_id": tunnel_id, "windows": windows }))
+        }
+        Err(e) => {
+            error!("Failed to load stats for tunnel {}: {:#}", tunnel_id, e);
+            json_response(500, json!({ "error": "Internal server error" }))
+        }
+    }
+}
+
+/// Look up a tunnel's connections, answering 404 for tunnels outside the scope
+async fn find_tunnel(
+    clients: &SharedClients,
+    scope: &Scope<'_>,
+    tunnel_id: &str,
+) -> std::result::Result<Vec<ConnectionMetadata>, ApiGatewayProxyResponse> {
+    if http_tunnel_common::validation::validate_tunnel_id(tunnel_id).is_err() {
+        return Err(json_response(400, json!({ "error": "Invalid tunnel ID" })));
+    }
+
+    match clients.store.tunnel_connections(tunnel_id).await {
+        Ok(connections)
+            if !connections.is_empty() && scope.permits(connections[0].owner.as_deref()) =>
+        {
+            Ok(connections)
+        }
+        _ => Err(json_response(404, json!({ "error": "Tunnel not found" }))),
+    }
+}
+
+/// Scan the connections table, optionally only for tunnels opened by `owner`
+async fn scan_connections(
+    client: &DynamoDbClient,
+    owner: Option<&str>,
+) -> Result<Vec<ConnectionMetadata>> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let mut connections = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let mut scan = client
+            .scan()
+            .table_name(&table_name)
+            .set_exclusive_start_key(exclusive_start_key);
+        if let Some(owner) = owner {
+            scan = scan
+                .filter_expression("#owner = :owner")
+                .expression_attribute_names("#owner", "owner")
+                .expression_attribute_values(":owner", AttributeValue::S(owner.to_string()));
+        }
+        let result = scan.send().await.context("Failed to scan connections")?;
+
+        for item in result.items.unwrap_or_default() {
+            connections.push(connection_metadata_from_item(&item)?);
+        }
+        exclusive_start_key = result.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(connections)
+}
+
+/// Group connections by tunnel, ordered by tunnel ID
+fn group_by_tunnel(
+    connections: Vec<ConnectionMetadata>,
+) -> BTreeMap<String, Vec<ConnectionMetadata>> {
+    let mut tunnels: BTreeMap<String, Vec<ConnectionMetadata>> = BTreeMap::new();
+    for connection in connections {
+        tunnels
+            .entry(connection.tunnel_id.clone())
+            .or_default()
+            .push(connection);
+    }
+    tunnels
+}
+
+/// Describe a tunnel from its (non-empty) list of connections
+///
+/// Tunnel options aren't included: they can carry secrets such as the basic
+/// auth password hash.
+fn tunnel_json(connections: &[ConnectionMetadata]) -> Value {
+    let first = &connections[0];
+    json!({
+        "tunnel_id": first.tunnel_id,
+        "public_url": first.public_url,
+        "owner": first.owner,
+        "connections": connections
+            .iter()
+            .map(|connection| json!({
+                "connection_id": connection.connection_id,
+                "created_at": connection.created_at,
+                "expires_at": connection.ttl,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_admin_request() {
+        let domain = "tunnel.example.com";
+        assert!(is_admin_request(domain, "/_admin", domain));
+        assert!(is_admin_request(
+            "tunnel.example.com:443",
+            "/_admin/tunnels/abc123def456/stats",
+            domain
+        ));
+        assert!(!is_admin_request(domain, "/_administrator", domain));
+        assert!(!is_admin_request(domain, "/abc123def456/_admin", domain));
+        assert!(!is_admin_request(
+            "abc123def456.tunnel.example.com",
+            "/_admin/tunnels",
+            domain
+        ));
+    }
+
+    #[test]
+    fn test_parse_admin_route() {
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/tunnels/abc123def456/stats"),
+            Some(AdminRoute::TunnelStats {
+                tunnel_id: "abc123def456"
+            })
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/tunnels/abc123def456/stats/"),
+            Some(AdminRoute::TunnelStats {
+                tunnel_id: "abc123def456"
+            })
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::POST, "/_admin/tunnels/abc123def456/stats"),
+            None
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/unknown"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_tunnel_routes() {
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/tunnels"),
+            Some(AdminRoute::ListTunnels)
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/tunnels/"),
+            Some(AdminRoute::ListTunnels)
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::GET, "/_admin/tunnels/abc123def456"),
+            Some(AdminRoute::Tunnel {
+                tunnel_id: "abc123def456"
+            })
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::DELETE, "/_admin/tunnels/abc123def456"),
+            Some(AdminRoute::RevokeTunnel {
+                tunnel_id: "abc123def456"
+            })
+        );
+        assert_eq!(
+            AdminRoute::parse(&http::Method::DELETE, "/_admin/tunnels"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_scope() {
+        let admins = parse_subjects(" ops@example.com, ,root ");
+        assert_eq!(admins, ["ops@example.com", "root"]);
+
+        let operator = Scope::for_subject("root", &admins);
+        assert_eq!(operator, Scope::All);
+        assert!(operator.permits(Some("alice")));
+        assert!(operator.permits(None));
+
+        let user = Scope::for_subject("alice", &admins);
+        assert_eq!(user, Scope::Owner("alice"));
+        assert!(user.permits(Some("alice")));
+        assert!(!user.permits(Some("bob")));
+        assert!(!user.permits(None));
+    }
+
+    #[test]
+    fn test_tunnel_json() {
+        let connection = |connection_id: &str, tunnel_id: &str| {
+            ConnectionMetadata::new(
+                connection_id.to_string(),
+                tunnel_id.to_string(),
+                format!("https://{}.tunnel.example.com", tunnel_id),
+                1_000,
+                2_000,
+            )
+            .with_owner("alice".to_string())
+        };
+        let mut shared = connection("conn_2", "abc123def456");
+        shared.options.basic_auth =
+            Some(http_tunnel_common::protocol::BasicAuth::new("user", "pass"));
+
+        let tunnels = group_by_tunnel(vec![
+            connection("conn_1", "abc123def456"),
+            connection("conn_3", "aaa111bbb222"),
+            shared,
+        ]);
+        assert_eq!(
+            tunnels.keys().collect::<Vec<_>>(),
+            ["aaa111bbb222", "abc123def456"]
+        );
+
+        let tunnel = tunnel_json(&tunnels["abc123def456"]);
+        assert_eq!(tunnel["tunnel_id"], "abc123def456");
+        assert_eq!(tunnel["owner"], "alice");
+        assert_eq!(tunnel["connections"][1]["connection_id"], "conn_2");
+        assert_eq!(tunnel["connections"][1]["expires_at"], 2_000);
+        assert!(!tunnel.to_string().contains("password_hash"));
+    }
+
+    #[test]
+    fn test_json_response() {
+        let response = json_response(404, json!({ "error": "Not found" }));
+        assert_eq!(response.status_code, 404);
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+        assert!(!response.is_base64_encoded);
+    }
+}
@@ -3,16 +3,49 @@
 //! This handler runs periodically (e.g., every hour) to actively clean up expired
 //! connections from DynamoDB. While DynamoDB TTL handles eventual deletion (within 48 hours),
 //! this provides immediate cleanup for cost optimization.
+//!
+//! Expired connections are also closed on API Gateway, so agents whose record
+//! is gone don't keep a zombie socket open. API Gateway can't list its
+//! connections, so orphans (open on API Gateway, missing from DynamoDB) are
+//! found through the connection IDs that pending requests and WebSocket
+//! sessions still refer to: any that no longer has a connection record is
+//! closed if API Gateway still knows it, along with its WebSocket sessions.
 
+use aws_sdk_apigatewaymanagement::Client as ApiGatewayManagementClient;
+use aws_sdk_apigatewaymanagement::operation::delete_connection::DeleteConnectionError;
 use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::types::{AttributeValue, DeleteRequest, WriteRequest};
 use http_tunnel_common::utils::current_timestamp_secs;
 use lambda_runtime::Error;
 use serde_json::Value;
-use tracing::{error, info};
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use super::websocket::{self, WsSession};
+use crate::{SharedClients, reply};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Most items a single BatchWriteItem request may delete
+const BATCH_WRITE_LIMIT: usize = 25;
+
+/// Attempts at deleting a batch while DynamoDB leaves items unprocessed
+const BATCH_WRITE_ATTEMPTS: u32 = 5;
+
+/// Delay before the first retry of unprocessed items, doubled on each retry
+const BATCH_RETRY_DELAY_MS: u64 = 100;
+
+/// Table size each parallel scan segment covers
+const SEGMENT_SIZE_BYTES: i64 = 16 * 1024 * 1024;
+
+/// Most parallel scan segments per table
+const MAX_SCAN_SEGMENTS: i32 = 8;
 
 /// Handler for scheduled cleanup (triggered by EventBridge)
-pub async fn handle_cleanup(_event: Value, dynamodb: &DynamoDbClient) -> Result<Value, Error> {
+pub async fn handle_cleanup(_event: Value, clients: &SharedClients) -> Result<Value, Error> {
+    let dynamodb = &clients.dynamodb;
     info!("Starting TTL cleanup task");
 
     let connections_table =
@@ -23,13 +56,24 @@ pub async fn handle_cleanup(_event: Value, dynamodb: &DynamoDbClient) -> Result<
     let now = current_timestamp_secs();
 
     // Cleanup expired connections
-    let connections_deleted =
+    let expired_connections =
         cleanup_expired_items(dynamodb, &connections_table, "connectionId", now)
             .await
             .map_err(|e| {
                 error!("Failed to cleanup connections: {}", e);
                 format!("Cleanup failed: {}", e)
             })?;
+    let connections_deleted = expired_connections.len();
+
+    // Close their sockets too, in case the agent is still attached
+    let mut connections_closed = 0;
+    if let Some(apigw_management) = clients.apigw_management() {
+        for connection_id in &expired_connections {
+            if close_api_connection(apigw_management, connection_id).await {
+                connections_closed += 1;
+            }
+        }
+    }
 
     // Cleanup expired pending requests
     let requests_deleted =
@@ -38,67 +82,417 @@ pub async fn handle_cleanup(_event: Value, dynamodb: &DynamoDbClient) -> Result<
             .map_err(|e| {
                 error!("Failed to cleanup pending requests: {}", e);
                 format!("Cleanup failed: {}", e)
-            })?;
+            })?
+            .len();
+
+    let (orphans_closed, sessions_closed) =
+        close_orphans(clients, &connections_table, &pending_requests_table)
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to cleanup orphaned connections: {}", e);
+                (0, 0)
+            });
+
+    // Remove reply queues left behind by handler containers that have gone away
+    let mut queues_deleted = 0;
+    if let Some(prefix) = reply::reply_queue_prefix() {
+        match reply::delete_stale_reply_queues(&clients.sqs, &prefix).await {
+            Ok(deleted) => queues_deleted = deleted,
+            Err(e) => error!("Failed to cleanup reply queues: {:#}", e),
+        }
+    }
 
     info!(
-        "Cleanup completed: {} connections, {} pending requests deleted",
-        connections_deleted, requests_deleted
+        "Cleanup completed: {} connections ({} still open), {} pending requests, {} reply queues \
+         deleted; {} orphaned connections and {} WebSocket sessions closed",
+        connections_deleted,
+        connections_closed,
+        requests_deleted,
+        queues_deleted,
+        orphans_closed,
+        sessions_closed
     );
 
     Ok(serde_json::json!({
         "connectionsDeleted": connections_deleted,
+        "connectionsClosed": connections_closed,
+        "orphansClosed": orphans_closed,
+        "sessionsClosed": sessions_closed,
         "requestsDeleted": requests_deleted,
+        "replyQueuesDeleted": queues_deleted,
         "timestamp": now
     }))
 }
 
-/// Cleanup expired items from a DynamoDB table
+/// Close connections that API Gateway still holds but DynamoDB has lost
+///
+/// Returns the number of connections and WebSocket sessions closed.
+async fn close_orphans(
+    clients: &SharedClients,
+    connections_table: &str,
+    pending_requests_table: &str,
+) -> Result<(u32, u32), BoxError> {
+    let Some(apigw_management) = clients.apigw_management() else {
+        return Ok((0, 0));
+    };
+
+    // References are read before live connections, so an agent connecting in
+    // between can't be referenced yet and mistaken for an orphan
+    let sessions = if websocket::is_passthrough_enabled() {
+        websocket::list_sessions(&clients.dynamodb).await?
+    } else {
+        Vec::new()
+    };
+    let mut referenced =
+        scan_attribute(&clients.dynamodb, pending_requests_table, "connectionId").await?;
+    referenced.extend(
+        sessions
+            .iter()
+            .map(|session| session.agent_connection_id.clone()),
+    );
+    let live = scan_attribute(&clients.dynamodb, connections_table, "connectionId").await?;
+
+    let mut orphans_closed = 0;
+    for connection_id in orphaned_ids(&referenced, &live) {
+        if close_api_connection(apigw_management, &connection_id).await {
+            info!("Closed orphaned connection {}", connection_id);
+            orphans_closed += 1;
+        }
+    }
+
+    let mut sessions_closed = 0;
+    for session in orphaned_sessions(&sessions, &live) {
+        match websocket::close_orphaned_session(clients, session).await {
+            Ok(()) => sessions_closed += 1,
+            Err(e) => warn!("Failed to close session {}: {:#}", session.session_id, e),
+        }
+    }
+
+    Ok((orphans_closed, sessions_closed))
+}
+
+/// Referenced connection IDs that have no connection record, in a stable order
+fn orphaned_ids(referenced: &HashSet<String>, live: &HashSet<String>) -> Vec<String> {
+    let mut orphans: Vec<String> = referenced.difference(live).cloned().collect();
+    orphans.sort();
+    orphans
+}
+
+/// Sessions whose agent connection has no connection record
+fn orphaned_sessions<'a>(
+    sessions: &'a [WsSession],
+    live: &'a HashSet<String>,
+) -> impl Iterator<Item = &'a WsSession> {
+    sessions
+        .iter()
+        .filter(|session| !live.contains(&session.agent_connection_id))
+}
+
+/// Close a connection on API Gateway, returning whether it was still open
+async fn close_api_connection(client: &ApiGatewayManagementClient, connection_id: &str) -> bool {
+    match client
+        .delete_connection()
+        .connection_id(connection_id)
+        .send()
+        .await
+    {
+        Ok(_) => true,
+        Err(e) => {
+            if !matches!(
+                e.as_service_error(),
+                Some(DeleteConnectionError::GoneException(_))
+            ) {
+                warn!("Failed to close connection {}: {}", connection_id, e);
+            } else {
+                debug!("Connection {} already closed", connection_id);
+            }
+            false
+        }
+    }
+}
+
+/// Every value of a string attribute in a table
+async fn scan_attribute(
+    client: &DynamoDbClient,
+    table_name: &str,
+    attribute: &str,
+) -> Result<HashSet<String>, BoxError> {
+    let mut values = HashSet::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let result = client
+            .scan()
+            .table_name(table_name)
+            .projection_expression("#attr")
+            .expression_attribute_names("#attr", attribute)
+            // Connections written moments ago must not look orphaned
+            .consistent_read(true)
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await?;
+
+        values.extend(
+            result
+                .items
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|item| item.get(attribute)?.as_s().ok().cloned()),
+        );
+        exclusive_start_key = result.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(values)
+}
+
+/// Cleanup expired items from a DynamoDB table, returning the deleted keys
+///
+/// Large tables are scanned in parallel segments; expired items are deleted
+/// page by page with `BatchWriteItem`.
 async fn cleanup_expired_items(
     client: &DynamoDbClient,
     table_name: &str,
     key_name: &str,
     now: i64,
-) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
-    // Scan for items past TTL
-    let result = client
-        .scan()
-        .table_name(table_name)
-        .filter_expression("attribute_exists(#ttl) AND #ttl < :now")
-        .expression_attribute_names("#ttl", "ttl")
-        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
-        .send()
-        .await?;
-
-    let mut deleted = 0;
-    if let Some(items) = result.items {
-        for item in items {
-            if let Some(key_value) = item.get(key_name).and_then(|v| v.as_s().ok()) {
-                match client
-                    .delete_item()
-                    .table_name(table_name)
-                    .key(key_name, AttributeValue::S(key_value.clone()))
-                    .send()
-                    .await
-                {
-                    Ok(_) => {
-                        deleted += 1;
-                    }
-                    Err(e) => {
-                        error!(
-                            "Failed to delete item {} from {}: {}",
-                            key_value, table_name, e
-                        );
-                    }
-                }
-            }
+) -> Result<Vec<String>, BoxError> {
+    let total_segments = match client.describe_table().table_name(table_name).send().await {
+        Ok(table) => scan_segments(table.table().and_then(|table| table.table_size_bytes())),
+        Err(e) => {
+            warn!(
+                "Failed to describe {}, scanning serially: {}",
+                table_name, e
+            );
+            1
         }
+    };
+
+    let mut segments = JoinSet::new();
+    for segment in 0..total_segments {
+        let client = client.clone();
+        let table_name = table_name.to_string();
+        let key_name = key_name.to_string();
+        segments.spawn(async move {
+            cleanup_segment(
+                &client,
+                &table_name,
+                &key_name,
+                now,
+                segment,
+                total_segments,
+            )
+            .await
+        });
+    }
+
+    let mut deleted = Vec::new();
+    while let Some(result) = segments.join_next().await {
+        deleted.extend(result??);
     }
 
     Ok(deleted)
 }
 
+/// Number of parallel scan segments for a table of `table_size_bytes`
+fn scan_segments(table_size_bytes: Option<i64>) -> i32 {
+    let size = table_size_bytes.unwrap_or_default().max(0);
+    (size / SEGMENT_SIZE_BYTES + 1).min(i64::from(MAX_SCAN_SEGMENTS)) as i32
+}
+
+/// Delete the expired items of one scan segment
+async fn cleanup_segment(
+    client: &DynamoDbClient,
+    table_name: &str,
+    key_name: &str,
+    now: i64,
+    segment: i32,
+    total_segments: i32,
+) -> Result<Vec<String>, BoxError> {
+    let mut deleted = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        // Scan for items past TTL
+        let result = client
+            .scan()
+            .table_name(table_name)
+            .filter_expression("attribute_exists(#ttl) AND #ttl < :now")
+            .projection_expression("#key")
+            .expression_attribute_names("#ttl", "ttl")
+            .expression_attribute_names("#key", key_name)
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .segment(segment)
+            .total_segments(total_segments)
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await?;
+
+        let keys: Vec<String> = result
+            .items
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|item| item.get(key_name)?.as_s().ok().cloned())
+            .collect();
+        for batch in keys.chunks(BATCH_WRITE_LIMIT) {
+            deleted.extend(delete_batch(client, table_name, key_name, batch).await?);
+        }
+
+        exclusive_start_key = result.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Delete up to 25 items, retrying those DynamoDB leaves unprocessed
+///
+/// Returns the keys actually deleted; items still unprocessed after the last
+/// attempt are left for the next run.
+async fn delete_batch(
+    client: &DynamoDbClient,
+    table_name: &str,
+    key_name: &str,
+    keys: &[String],
+) -> Result<Vec<String>, BoxError> {
+    let mut requests = keys
+        .iter()
+        .map(|key| {
+            let delete = DeleteRequest::builder()
+                .key(key_name, AttributeValue::S(key.clone()))
+                .build()?;
+            Ok(WriteRequest::builder().delete_request(delete).build())
+        })
+        .collect::<Result<Vec<_>, BoxError>>()?;
+
+    let mut delay = Duration::from_millis(BATCH_RETRY_DELAY_MS);
+    for attempt in 1..=BATCH_WRITE_ATTEMPTS {
+        let result = match client
+            .batch_write_item()
+            .request_items(table_name, requests.clone())
+            .send()
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                error!(
+                    "Failed to delete {} items from {}: {}",
+                    requests.len(),
+                    table_name,
+                    e
+                );
+                break;
+            }
+        };
+        requests = result
+            .unprocessed_items
+            .and_then(|mut unprocessed| unprocessed.remove(table_name))
+            .unwrap_or_default();
+        if requests.is_empty() {
+            break;
+        }
+        if attempt < BATCH_WRITE_ATTEMPTS {
+            debug!(
+                "{} items unprocessed in {}, retrying in {:?}",
+                requests.len(),
+                table_name,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        }
+    }
+
+    let remaining = request_keys(&requests, key_name);
+    if !remaining.is_empty() {
+        warn!(
+            "{} expired items left in {} for the next cleanup",
+            remaining.len(),
+            table_name
+        );
+    }
+    Ok(keys
+        .iter()
+        .filter(|key| !remaining.contains(*key))
+        .cloned()
+        .collect())
+}
+
+/// Keys of the items targeted by delete requests
+fn request_keys(requests: &[WriteRequest], key_name: &str) -> HashSet<String> {
+    requests
+        .iter()
+        .filter_map(|request| {
+            request
+                .delete_request()?
+                .key()
+                .get(key_name)?
+                .as_s()
+                .ok()
+                .cloned()
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> HashSet<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_orphaned_ids() {
+        let referenced = ids(&["conn_c", "conn_a", "conn_b"]);
+        let live = ids(&["conn_b", "conn_d"]);
+        assert_eq!(orphaned_ids(&referenced, &live), vec!["conn_a", "conn_c"]);
+        assert!(orphaned_ids(&ids(&[]), &live).is_empty());
+    }
+
+    #[test]
+    fn test_scan_segments() {
+        assert_eq!(scan_segments(None), 1);
+        assert_eq!(scan_segments(Some(0)), 1);
+        assert_eq!(scan_segments(Some(SEGMENT_SIZE_BYTES - 1)), 1);
+        assert_eq!(scan_segments(Some(SEGMENT_SIZE_BYTES * 3)), 4);
+        assert_eq!(scan_segments(Some(i64::MAX)), MAX_SCAN_SEGMENTS);
+    }
+
+    #[test]
+    fn test_request_keys() {
+        let requests: Vec<WriteRequest> = ["req_1", "req_2"]
+            .iter()
+            .map(|key| {
+                let delete = DeleteRequest::builder()
+                    .key("requestId", AttributeValue::S(key.to_string()))
+                    .build()
+                    .unwrap();
+                WriteRequest::builder().delete_request(delete).build()
+            })
+            .collect();
+        assert_eq!(
+            request_keys(&requests, "requestId"),
+            ids(&["req_1", "req_2"])
+        );
+        assert!(request_keys(&requests, "connectionId").is_empty());
+    }
+
+    #[test]
+    fn test_orphaned_sessions() {
+        let session = |session_id: &str, agent: &str| WsSession {
+            session_id: session_id.to_string(),
+            tunnel_id: "abc123def456".to_string(),
+            agent_connection_id: agent.to_string(),
+        };
+        let sessions = [session("s1", "conn_a"), session("s2", "conn_b")];
+        let live = ids(&["conn_b"]);
+        let orphaned: Vec<_> = orphaned_sessions(&sessions, &live)
+            .map(|session| session.session_id.as_str())
+            .collect();
+        assert_eq!(orphaned, vec!["s1"]);
+    }
 
     #[test]
     fn test_cleanup_response_format() {
@@ -11,7 +11,8 @@ use http_tunnel_common::utils::{calculate_ttl, current_timestamp_secs, generate_
 use lambda_runtime::{Error, LambdaEvent};
 use tracing::{error, info};
 
-use crate::{SharedClients, auth, error_handling::sanitize_error, save_connection_metadata};
+use crate::store::TunnelStore;
+use crate::{SharedClients, TunnelUrls, auth, error_handling::sanitize_error, tunnel_urls};
 
 /// Handler for WebSocket $connect route
 pub async fn handle_connect(
@@ -19,17 +20,20 @@ pub async fn handle_connect(
     clients: &SharedClients,
 ) -> Result<ApiGatewayProxyResponse, Error> {
     // Authenticate request if auth is enabled (before extracting connection_id)
-    if let Err(e) = auth::authenticate_request(&event.payload) {
-        use aws_lambda_events::encodings::Body;
-        error!("Authentication failed: {}", e);
-        return Ok(ApiGatewayProxyResponse {
-            status_code: 401,
-            headers: Default::default(),
-            multi_value_headers: Default::default(),
-            body: Some(Body::Text("Unauthorized".to_string())),
-            is_base64_encoded: false,
-        });
-    }
+    let claims = match auth::authenticate_request(&event.payload).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            use aws_lambda_events::encodings::Body;
+            error!("Authentication failed: {}", e);
+            return Ok(ApiGatewayProxyResponse {
+                status_code: 401,
+                headers: Default::default(),
+                multi_value_headers: Default::default(),
+                body: Some(Body::Text("Unauthorized".to_string())),
+                is_base64_encoded: false,
+            });
+        }
+    };
 
     let request_context = event.payload.request_context;
     let connection_id = request_context
@@ -40,24 +44,11 @@ pub async fn handle_connect(
 
     // Generate unique tunnel ID (path segment)
     let tunnel_id = generate_subdomain(); // Reusing subdomain generator for random ID
-    let domain = std::env::var("DOMAIN_NAME").unwrap_or_else(|_| "tunnel.example.com".to_string());
-
-    // Check if subdomain routing is enabled
-    let subdomain_enabled = std::env::var("ENABLE_SUBDOMAIN_ROUTING")
-        .unwrap_or_else(|_| "true".to_string())
-        .to_lowercase()
-        == "true";
-
-    // Generate both URL formats
-    let path_based_url = format!("https://{}/{}", domain, tunnel_id);
-    let subdomain_url = if subdomain_enabled {
-        Some(format!("https://{}.{}", tunnel_id, domain))
-    } else {
-        None
-    };
-
-    // Use subdomain URL as primary if enabled, otherwise path-based
-    let public_url = subdomain_url.as_ref().unwrap_or(&path_based_url).clone();
+    let TunnelUrls {
+        public_url,
+        subdomain_url,
+        path_based_url,
+    } = tunnel_urls(&tunnel_id);
 
     // Calculate TTL (2 hours from now)
     let created_at = current_timestamp_secs();
@@ -69,13 +60,18 @@ pub async fn handle_connect(
         tunnel_id: tunnel_id.clone(),
         public_url: public_url.clone(),
         subdomain_url: subdomain_url.clone(),
-        path_based_url: Some(path_based_url.clone()),
+        path_based_url: path_based_url.clone(),
         created_at,
         ttl,
+        last_heartbeat_at: None,
         client_info: None,
+        owner: claims.map(|claims| claims.sub),
+        options: Default::default(),
     };
 
-    save_connection_metadata(&clients.dynamodb, &connection_metadata)
+    clients
+        .store
+        .put_connection(&connection_metadata)
         .await
         .map_err(|e| {
             error!(
@@ -94,7 +90,9 @@ pub async fn handle_connect(
     if let Some(ref subdomain) = subdomain_url {
         info!("🌐 Subdomain URL: {}", subdomain);
     }
-    info!("🌐 Path-based URL: {}", path_based_url);
+    if let Some(ref path_based) = path_based_url {
+        info!("🌐 Path-based URL: {}", path_based);
+    }
 
     // Return success response
     // Note: Forwarder will send Ready message to get connection info
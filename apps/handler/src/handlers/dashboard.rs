@@ -0,0 +1,109 @@
+//! DashboardHandler - Serves the operator dashboard at `/_dashboard` on the base domain
+//!
+//! The dashboard is a static page embedded in the binary. It holds no data
+//! itself: the page asks for a bearer token, keeps it in session storage and
+//! polls the admin API (`/_admin/tunnels`) with it, so it shows exactly the
+//! tunnels the token's subject may manage, with their request counts, error
+//! rates and latency percentiles from the stats table.
+
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use aws_lambda_events::encodings::Body;
+use http::header::{HeaderName, HeaderValue};
+
+use super::admin::is_base_domain_path;
+
+/// Path of the dashboard on the base domain
+pub const DASHBOARD_PATH: &str = "/_dashboard";
+
+const DASHBOARD_PAGE: &[u8] = include_bytes!("dashboard.html");
+
+/// The page only talks to the admin API on its own origin; it holds a token,
+/// so it must not be framed or load anything else
+const DASHBOARD_CSP: &str = "default-src 'none'; script-src 'unsafe-inline'; \
+                             style-src 'unsafe-inline'; connect-src 'self'; \
+                             frame-ancestors 'none'";
+
+/// Check whether a request targets the dashboard (base domain only)
+pub fn is_dashboard_request(host: &str, path: &str, base_domain: &str) -> bool {
+    is_base_domain_path(host, path, base_domain, DASHBOARD_PATH)
+}
+
+/// Serve the dashboard page
+pub fn handle_dashboard(request: &ApiGatewayProxyRequest) -> ApiGatewayProxyResponse {
+    let (status_code, content_type, body) = match request.http_method {
+        http::Method::GET | http::Method::HEAD => {
+            (200, "text/html; charset=utf-8", DASHBOARD_PAGE.to_vec())
+        }
+        _ => (405, "text/plain", b"Method Not Allowed".to_vec()),
+    };
+
+    ApiGatewayProxyResponse {
+        status_code,
+        headers: [
+            ("content-type", content_type),
+            ("cache-control", "no-cache"),
+            ("content-security-policy", DASHBOARD_CSP),
+            ("x-content-type-options", "nosniff"),
+        ]
+        .into_iter()
+        .map(|(name, value)| {
+            (
+                HeaderName::from_static(name),
+                HeaderValue::from_static(value),
+            )
+        })
+        .collect(),
+        multi_value_headers: Default::default(),
+        body: Some(Body::Binary(body)),
+        is_base64_encoded: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_dashboard_request() {
+        let domain = "tunnel.example.com";
+        assert!(is_dashboard_request(domain, "/_dashboard", domain));
+        assert!(is_dashboard_request(
+            "tunnel.example.com:443",
+            "/_dashboard/",
+            domain
+        ));
+        assert!(!is_dashboard_request(domain, "/_dashboards", domain));
+        assert!(!is_dashboard_request(
+            "abc123def456.tunnel.example.com",
+            "/_dashboard",
+            domain
+        ));
+    }
+
+    #[test]
+    fn test_handle_dashboard() {
+        let request = ApiGatewayProxyRequest {
+            http_method: http::Method::GET,
+            path: Some(DASHBOARD_PATH.to_string()),
+            ..Default::default()
+        };
+        let response = handle_dashboard(&request);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            response.headers.get("content-type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert!(response.is_base64_encoded);
+        let Some(Body::Binary(body)) = response.body else {
+            panic!("expected a binary body");
+        };
+        let page = String::from_utf8(body).unwrap();
+        assert!(page.contains("/_admin"));
+
+        let request = ApiGatewayProxyRequest {
+            http_method: http::Method::POST,
+            ..request
+        };
+        assert_eq!(handle_dashboard(&request).status_code, 405);
+    }
+}
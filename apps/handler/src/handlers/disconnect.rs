@@ -7,7 +7,8 @@ use aws_lambda_events::apigw::{ApiGatewayProxyResponse, ApiGatewayWebsocketProxy
 use lambda_runtime::{Error, LambdaEvent};
 use tracing::{info, warn};
 
-use crate::{SharedClients, delete_connection};
+use crate::SharedClients;
+use crate::store::TunnelStore;
 
 /// Handler for WebSocket $disconnect route
 pub async fn handle_disconnect(
@@ -20,9 +21,10 @@ pub async fn handle_disconnect(
         .ok_or("Missing connection ID")?;
 
     info!("WebSocket connection disconnected: {}", connection_id);
+    clients.connections.invalidate_connection(&connection_id);
 
     // Delete connection from DynamoDB
-    match delete_connection(&clients.dynamodb, &connection_id).await {
+    match clients.store.delete_connection(&connection_id).await {
         Ok(_) => {
             info!("Cleaned up connection metadata: {}", connection_id);
         }
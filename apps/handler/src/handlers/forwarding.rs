@@ -3,27 +3,65 @@
 //! This module receives public HTTP requests via API Gateway HTTP API,
 //! looks up the connection by subdomain, forwards the request to the agent via WebSocket,
 //! and polls for the response. If no response is received within the timeout,
-//! it returns a 504 Gateway Timeout.
+//! it returns a 504 Gateway Timeout. Streamed response bodies are relayed as they
+//! arrive when the request came through a Function URL (see [`crate::streaming`]).
 
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
 use http_tunnel_common::constants::MAX_BODY_SIZE_BYTES;
-use http_tunnel_common::protocol::Message;
+use http_tunnel_common::protocol::{ErrorCode, Message, PreflightOutcome};
 use http_tunnel_common::utils::generate_request_id;
+use http_tunnel_common::{ConnectionMetadata, CorsPolicy, HttpResponse};
+use lambda_runtime::streaming::{Body, Response};
 use lambda_runtime::{Error, LambdaEvent};
 use tracing::{debug, error, info, warn};
 
+use std::time::{Duration, Instant};
+
+use super::routes::{self, BaseRoute, TunnelRoute};
+use super::{admin, dashboard, status, websocket};
+use crate::compression::ContentEncoding;
+use crate::content_rewrite::{NO_REWRITE_HEADER, RewriteStrategy};
+use crate::error_pages::{self, ErrorPage};
+use crate::http_event::PublicRequest;
+use crate::store::{PendingRequest, TunnelStore};
 use crate::{
-    SharedClients, build_api_gateway_response, build_http_request, content_rewrite,
-    detect_routing_mode, lookup_connection_by_tunnel_id, save_pending_request, send_to_connection,
-    wait_for_response,
+    DeliveryFailure, RoutingConfig, SharedClients, activity, alerts, build_api_gateway_response,
+    build_error_response, build_http_request, content_rewrite, decoded_body_size,
+    detect_routing_mode, grpc_web, honeypot, metrics::RequestMetrics, remaining_budget_ms,
+    remove_stale_connection, reply, request_deadline, send_message_with_encoding, streaming,
 };
 
-/// Handler for HTTP API requests
+/// Response to a public HTTP request
+pub enum ForwardingResponse {
+    /// The whole response, returned as the invocation's result
+    Buffered(ApiGatewayProxyResponse),
+    /// A response whose body is relayed as the agent streams it
+    Streaming(Response<Body>),
+}
+
+impl From<ApiGatewayProxyResponse> for ForwardingResponse {
+    fn from(response: ApiGatewayProxyResponse) -> Self {
+        Self::Buffered(response)
+    }
+}
+
+/// Longest client-supplied `X-Request-Id` used as the correlation ID
+const MAX_CORRELATION_ID_LEN: usize = 128;
+
+/// Handler for HTTP API requests, in either payload format
 pub async fn handle_forwarding(
-    event: LambdaEvent<ApiGatewayProxyRequest>,
+    event: LambdaEvent<PublicRequest>,
     clients: &SharedClients,
-) -> Result<ApiGatewayProxyResponse, Error> {
-    let mut request = event.payload;
+) -> Result<ForwardingResponse, Error> {
+    let PublicRequest {
+        mut request,
+        raw_query,
+        ..
+    } = event.payload;
+    let deadline = request_deadline();
+    // Function URLs can stream bodies for as long as the invocation lasts
+    let streamable = streaming::is_function_url(&request);
+    let stream_deadline = streaming::stream_deadline(event.context.deadline);
     let request_id_context = request.request_context.request_id.clone();
 
     // Get domain from environment
@@ -39,20 +77,48 @@ pub async fn handle_forwarding(
 
     let original_path = request.path.as_deref().unwrap_or("/");
 
+    // Reject clients that previously tripped a honeypot path, on every tunnel
+    let source_ip = request.request_context.identity.source_ip.clone();
+    let honeypot_enabled = honeypot::is_honeypot_enabled();
+    if honeypot_enabled && let Some(ip) = source_ip.as_deref() {
+        match honeypot::is_ip_banned(&clients.dynamodb, ip).await {
+            Ok(true) => {
+                debug!("Rejecting request from banned IP {}", ip);
+                return Ok(plain_response(403, "Forbidden").into());
+            }
+            Ok(false) => {}
+            // Fail open: a denylist outage shouldn't take every tunnel down
+            Err(e) => warn!("Failed to check IP denylist for {}: {:#}", ip, e),
+        }
+    }
+
+    // Reserved paths on the base domain (the admin API and the static operator
+    // dashboard backed by it) never reach tunnel routing
+    match routes::base_route(host, original_path, &domain) {
+        Some(BaseRoute::Admin) => {
+            return admin::handle_admin(&request, clients).await.map(Into::into);
+        }
+        Some(BaseRoute::Dashboard) => return Ok(dashboard::handle_dashboard(&request).into()),
+        Some(BaseRoute::NotFound) => return Ok(plain_response(404, "Not Found").into()),
+        None => {}
+    }
+
     debug!(
         "Processing HTTP request, host: {}, path: {}",
         host, original_path
     );
 
     // Detect routing mode (subdomain vs path-based)
-    let routing_mode = detect_routing_mode(host, original_path, &domain).map_err(|e| {
-        error!(
-            "Failed to detect routing mode for host {} path {}: {}",
-            host, original_path, e
-        );
-        // Sanitized error - don't leak internal details
-        "Invalid request".to_string()
-    })?;
+    let routing_config = RoutingConfig::from_env();
+    let routing_mode =
+        detect_routing_mode(host, original_path, &domain, routing_config).map_err(|e| {
+            error!(
+                "Failed to detect routing mode for host {} path {}: {}",
+                host, original_path, e
+            );
+            // Sanitized error - don't leak internal details
+            "Invalid request".to_string()
+        })?;
 
     let tunnel_id = routing_mode.tunnel_id();
     let forwarding_path = routing_mode.forwarding_path();
@@ -62,53 +128,77 @@ pub async fn handle_forwarding(
         routing_mode, tunnel_id, forwarding_path
     );
 
+    // Reserved paths within the tunnel are answered here, without involving the agent
+    match routes::tunnel_route(forwarding_path) {
+        Some(TunnelRoute::Status) => {
+            return Ok(
+                status::handle_status(&request, tunnel_id, source_ip.as_deref(), clients)
+                    .await
+                    .into(),
+            );
+        }
+        Some(TunnelRoute::NotFound) => return Ok(plain_response(404, "Not Found").into()),
+        None => {}
+    }
+
+    // Trap paths ban the caller instead of reaching the local service
+    if honeypot_enabled
+        && let Some(ip) = source_ip.as_deref()
+        && honeypot::is_trap_path(forwarding_path, honeypot::trap_paths())
+    {
+        if let Err(e) = honeypot::ban_ip(&clients.dynamodb, ip, tunnel_id, forwarding_path).await {
+            warn!("Failed to ban {}: {:#}", ip, e);
+        }
+        return Ok(plain_response(404, "Not Found").into());
+    }
+
     // Update request path to forwarding path
     request.path = Some(forwarding_path.to_string());
 
     // Enforce request size limits
-    if let Some(body) = &request.body {
-        let body_size = if request.is_base64_encoded {
-            // Estimate decoded size (base64 is ~33% larger than binary)
-            (body.len() * 3) / 4
+    let request_bytes = request.body.as_deref().map_or(0, |body| {
+        if request.is_base64_encoded {
+            decoded_body_size(body)
         } else {
             body.len()
-        };
-
-        if body_size > MAX_BODY_SIZE_BYTES {
-            use aws_lambda_events::encodings::Body;
-            use http::header::{HeaderName, HeaderValue};
+        }
+    });
+    if request_bytes > MAX_BODY_SIZE_BYTES {
+        use aws_lambda_events::encodings::Body;
+        use http::header::{HeaderName, HeaderValue};
 
-            warn!(
-                "Request body too large: {} bytes (max: {} bytes) for tunnel {}",
-                body_size, MAX_BODY_SIZE_BYTES, tunnel_id
-            );
+        warn!(
+            "Request body too large: {} bytes (max: {} bytes) for tunnel {}",
+            request_bytes, MAX_BODY_SIZE_BYTES, tunnel_id
+        );
 
-            return Ok(ApiGatewayProxyResponse {
-                status_code: 413,
-                headers: [
-                    (
-                        HeaderName::from_static("content-type"),
-                        HeaderValue::from_static("text/plain"),
-                    ),
-                    (
-                        HeaderName::from_static("x-tunnel-error"),
-                        HeaderValue::from_static("Request Entity Too Large"),
-                    ),
-                ]
-                .into_iter()
-                .collect(),
-                multi_value_headers: Default::default(),
-                body: Some(Body::Text(format!(
-                    "Request body too large: {} bytes (maximum: {} bytes)",
-                    body_size, MAX_BODY_SIZE_BYTES
-                ))),
-                is_base64_encoded: false,
-            });
-        }
+        return Ok(ForwardingResponse::Buffered(ApiGatewayProxyResponse {
+            status_code: ErrorCode::PayloadTooLarge.http_status() as i64,
+            headers: [
+                (
+                    HeaderName::from_static("content-type"),
+                    HeaderValue::from_static("text/plain"),
+                ),
+                (
+                    HeaderName::from_static("x-tunnel-error"),
+                    HeaderValue::from_static("Request Entity Too Large"),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            multi_value_headers: Default::default(),
+            body: Some(Body::Text(format!(
+                "Request body too large: {} bytes (maximum: {} bytes)",
+                request_bytes, MAX_BODY_SIZE_BYTES
+            ))),
+            is_base64_encoded: false,
+        }));
     }
 
-    // Look up connection ID by tunnel ID
-    let connection_id = lookup_connection_by_tunnel_id(&clients.dynamodb, tunnel_id)
+    // Look up the agents serving the tunnel, least recently used first
+    let connections = clients
+        .connections
+        .lookup(&clients.store, tunnel_id)
         .await
         .map_err(|e| {
             error!(
@@ -119,68 +209,310 @@ pub async fn handle_forwarding(
             "Tunnel not found or unavailable".to_string()
         })?;
 
-    debug!("Found connection: {}", connection_id);
+    // Source address rules apply before the request is stored or sent anywhere
+    let found = !connections.is_empty();
+    let connections = permitted_connections(connections, source_ip.as_deref());
+    if found && connections.is_empty() {
+        debug!(
+            "Rejected request from {:?} to tunnel {} by IP rules",
+            source_ip, tunnel_id
+        );
+        return Ok(plain_response(403, "Forbidden").into());
+    }
+
+    // Pin sessions to one agent when the tunnel asks for affinity
+    let affinity = connections
+        .iter()
+        .find_map(|connection| connection.options.affinity.clone());
+    let session_key = affinity.as_ref().and_then(|affinity| {
+        affinity.session_key(source_ip.as_deref(), header_str(&request, "cookie"))
+    });
+    let connections = match session_key {
+        Some(key) => clients.balancer.order_for_session(connections, key),
+        None => clients.balancer.order(connections),
+    };
+    let mut candidates = connections.into_iter();
+    let mut connection = candidates.next().ok_or("Tunnel not found or unavailable")?;
+
+    debug!("Found connection: {}", connection.connection_id);
+
+    // Answer CORS preflights from the tunnel's policy without a round trip
+    let cors = connection.options.cors.clone();
+    if let Some(policy) = &cors
+        && let Some(response) = preflight_response(policy, &request)
+    {
+        debug!("Answered CORS preflight for tunnel {}", tunnel_id);
+        return Ok(response.into());
+    }
+    let origin = header_str(&request, "origin").map(str::to_string);
+
+    // Tunnels protected by basic auth only forward requests with their credentials
+    if let Some(auth) = &connection.options.basic_auth {
+        if !auth.verify(header_str(&request, "authorization")) {
+            debug!(
+                "Rejected request to tunnel {} without credentials",
+                tunnel_id
+            );
+            return Ok(unauthorized_response().into());
+        }
+        // The credentials are for the tunnel, not the local service
+        request.headers.remove(http::header::AUTHORIZATION);
+        request
+            .multi_value_headers
+            .remove(http::header::AUTHORIZATION);
+    }
+
+    // The HTTP API can't upgrade connections: send WebSocket clients to the
+    // passthrough API, which checks the tunnel's access rules again
+    if websocket::is_upgrade_request(&request.headers) {
+        let uri = build_http_request(&request, String::new(), raw_query.as_deref()).uri;
+        return Ok(websocket::upgrade_response(tunnel_id, &uri).into());
+    }
 
     // Generate request ID
     let request_id = generate_request_id();
 
     // Build HttpRequest payload
-    let http_request = build_http_request(&request, request_id.clone());
+    let mut http_request = build_http_request(&request, request_id.clone(), raw_query.as_deref());
 
-    // Store pending request in DynamoDB for response correlation
-    let api_gateway_req_id = request_id_context.as_deref().unwrap_or("unknown");
-    save_pending_request(
-        &clients.dynamodb,
-        &request_id,
-        &connection_id,
-        api_gateway_req_id,
-    )
-    .await
-    .map_err(|e| {
-        error!("Failed to save pending request {}: {}", request_id, e);
-        // Sanitized error - don't leak internal details
-        "Service temporarily unavailable".to_string()
-    })?;
+    // A client's own X-Request-Id is kept so its logs line up with the tunnel's
+    let correlation_id = client_correlation_id(&request).unwrap_or_else(|| request_id.clone());
+    http_request.correlation_id = Some(correlation_id.clone());
 
-    // Forward request to agent via WebSocket
-    let message = Message::HttpRequest(http_request);
-    let message_json = serde_json::to_string(&message).map_err(|e| {
-        error!("Failed to serialize message: {}", e);
-        // Sanitized error - don't leak internal details
-        "Service temporarily unavailable".to_string()
+    // gRPC-Web calls reach the local service as plain gRPC
+    let grpc_web_call = grpc_web::translate_request(&mut http_request).map_err(|e| {
+        debug!("Rejected gRPC-Web request {}: {:#}", request_id, e);
+        "Invalid request".to_string()
     })?;
 
+    let api_gateway_req_id = request_id_context.as_deref().unwrap_or("unknown");
+    // Stores that publish responses don't need the reply queue
+    let reply_queue = if clients.store.publishes_responses() {
+        None
+    } else {
+        clients.reply_queue.url(&clients.sqs).await
+    };
     let apigw_management = clients
-        .apigw_management
-        .as_ref()
+        .apigw_management()
         .ok_or("API Gateway Management client not initialized")?;
 
-    send_to_connection(apigw_management, &connection_id, &message_json)
+    // Agents sharing a tunnel may have gone away before their $disconnect removed
+    // them, so a request that can't be delivered moves on to the next agent
+    let connection_id = loop {
+        let connection_id = connection.connection_id.clone();
+
+        // Store pending request for response correlation, naming the queue the
+        // response should be pushed to when push delivery is enabled
+        let pending = PendingRequest {
+            request_id: &request_id,
+            connection_id: &connection_id,
+            tunnel_id,
+            request_bytes,
+            api_gateway_request_id: api_gateway_req_id,
+            reply_queue: reply_queue.as_deref(),
+        };
+        clients
+            .store
+            .put_pending_request(&pending)
+            .await
+            .map_err(|e| {
+                error!("Failed to save pending request {}: {}", request_id, e);
+                // Sanitized error - don't leak internal details
+                "Service temporarily unavailable".to_string()
+            })?;
+
+        // Forward request to agent via WebSocket, with whatever time is left so it
+        // doesn't keep working after we've given up (a streamed body may keep
+        // coming until the invocation ends)
+        http_request.timeout_ms = Some(remaining_budget_ms(if streamable {
+            stream_deadline
+        } else {
+            deadline
+        }));
+
+        let mut outgoing = http_request.clone();
+        outgoing.compress_body(connection.options.compression);
+        let Err(e) = send_message_with_encoding(
+            apigw_management,
+            &connection_id,
+            Message::HttpRequest(outgoing),
+            connection.options.encoding,
+        )
         .await
-        .map_err(|e| {
-            error!(
-                "Failed to send request {} to connection {}: {}",
-                request_id, connection_id, e
-            );
-            // Sanitized error - don't leak internal details
-            "Tunnel connection unavailable".to_string()
-        })?;
+        else {
+            clients.balancer.record(&connection_id);
+            break connection_id;
+        };
+
+        let failure = DeliveryFailure::classify(&e);
+        if failure == DeliveryFailure::Gone {
+            // Later requests shouldn't be routed to it either
+            clients.connections.invalidate_connection(&connection_id);
+            remove_stale_connection(&clients.store, &connection_id).await;
+            if let Some(next) = candidates.next() {
+                warn!(
+                    "Connection {} for tunnel_id {} is gone, trying {}",
+                    connection_id, tunnel_id, next.connection_id
+                );
+                connection = next;
+                continue;
+            }
+        }
+
+        error!(
+            "Failed to send request {} to connection {} ({:?}): {:#}",
+            request_id, connection_id, failure, e
+        );
+
+        // Fail the pending request right away instead of waiting out the poll window
+        let code = failure.error_code();
+        let error_response = build_error_response(&request_id, &code, failure.message());
+        if let Err(e) = clients
+            .store
+            .fail_pending_request(&error_response, &code)
+            .await
+        {
+            warn!("Failed to mark request {} as failed: {:#}", request_id, e);
+        }
+
+        record_stats(
+            clients,
+            &connection,
+            error_response.status_code,
+            Duration::ZERO,
+        )
+        .await;
+        RequestMetrics {
+            tunnel_id,
+            request_id: &request_id,
+            status_code: error_response.status_code,
+            request_bytes,
+            ..Default::default()
+        }
+        .emit();
+
+        let mut response = build_api_gateway_response(error_response);
+        if let Ok(value) = http::HeaderValue::from_str(failure.message()) {
+            response.headers.insert("x-tunnel-error", value);
+        }
+        if let Ok(value) = http::HeaderValue::from_str(&correlation_id) {
+            response.headers.insert("x-request-id", value);
+        }
+        let page = ErrorPage {
+            status: response.status_code,
+            message: failure.message(),
+            tunnel_id,
+            request_id: &correlation_id,
+        };
+        error_pages::apply(&mut response, &request, &page, clients).await;
+        return Ok(response.into());
+    };
 
     info!(
-        "Forwarded request {} to connection {} for tunnel_id {}",
-        request_id, connection_id, tunnel_id
+        "Forwarded request {} ({}) to connection {} for tunnel_id {}",
+        request_id, correlation_id, connection_id, tunnel_id
     );
+    // Tunnels serving traffic don't expire
+    activity::touch_connection(clients, &connection_id).await;
 
-    // Poll for response with timeout
-    match wait_for_response(&clients.dynamodb, &request_id).await {
+    // Wait for the pushed or published response, or poll for it with a head
+    // start for tunnels known to be slow
+    let sent_at = Instant::now();
+    let result = match &reply_queue {
+        Some(queue_url) => reply::wait_for_reply(clients, queue_url, &request_id, deadline).await,
+        None => {
+            let head_start = clients.latency.head_start(tunnel_id);
+            clients
+                .store
+                .wait_for_response(&request_id, deadline, head_start)
+                .await
+        }
+    };
+    match result {
         Ok(mut response) => {
+            let latency = sent_at.elapsed();
+            if let Err(e) = response.decompress_body() {
+                error!(
+                    "Undecodable response body for request {}: {}",
+                    request_id, e
+                );
+                return Err("Service temporarily unavailable".into());
+            }
+            clients.latency.record(tunnel_id, latency);
+            record_stats(clients, &connection, response.status_code, latency).await;
+
             info!(
                 "Received response for request {}: status {}",
                 request_id, response.status_code
             );
 
+            tag_response(&mut response, &correlation_id, latency);
+
+            // Streamed bodies are passed through as they arrive, without rewriting
+            if response.streaming {
+                if let (Some(policy), Some(origin)) = (&cors, &origin) {
+                    apply_cors_headers(&mut response, policy, origin);
+                }
+                RequestMetrics {
+                    tunnel_id,
+                    request_id: &request_id,
+                    status_code: response.status_code,
+                    latency,
+                    request_bytes,
+                    ..Default::default()
+                }
+                .emit();
+
+                let table = clients.store.pending_table().map_err(|e| {
+                    error!("No table for stream of {}: {:#}", request_id, e);
+                    "Service temporarily unavailable".to_string()
+                })?;
+                if streamable {
+                    let relayed = streaming::relay(
+                        clients.dynamodb.clone(),
+                        table,
+                        response,
+                        stream_deadline,
+                    )
+                    .map_err(|e| {
+                        error!("Failed to relay stream for {}: {:#}", request_id, e);
+                        "Service temporarily unavailable".to_string()
+                    })?;
+                    return Ok(ForwardingResponse::Streaming(relayed));
+                }
+                streaming::collect(&clients.dynamodb, &table, &mut response, deadline)
+                    .await
+                    .map_err(|e| {
+                        error!("Failed to collect stream for {}: {:#}", request_id, e);
+                        "Service temporarily unavailable".to_string()
+                    })?;
+                return Ok(build_api_gateway_response(response).into());
+            }
+
+            if let Some(call) = &grpc_web_call
+                && let Err(e) = grpc_web::translate_response(&mut response, call)
+            {
+                warn!(
+                    "Failed to translate gRPC-Web response {}: {:#}",
+                    request_id, e
+                );
+            }
+
+            // The tunnel picks a strategy; the local service can opt single
+            // responses out
+            let strategy = if response.headers.remove(NO_REWRITE_HEADER).is_some() {
+                RewriteStrategy::None
+            } else {
+                connection.options.rewrite
+            };
+
             // Apply content rewriting based on routing mode
-            if routing_mode.should_rewrite_content() {
+            let mut rewrite_applied = false;
+            if routing_mode.should_rewrite_content() && strategy != RewriteStrategy::None {
+                // Redirects and other URL headers must stay under the tunnel prefix
+                rewrite_applied =
+                    content_rewrite::rewrite_response_headers(&mut response, tunnel_id);
+
                 // Path-based routing: apply content rewriting
                 let content_type = response
                     .headers
@@ -189,33 +521,34 @@ pub async fn handle_forwarding(
                     .map(|s| s.as_str())
                     .unwrap_or("");
 
+                // Compressed bodies are rewritten decompressed, then compressed again
+                let encoding = ContentEncoding::parse(
+                    response
+                        .headers
+                        .get("content-encoding")
+                        .and_then(|v| v.first())
+                        .map(|s| s.as_str()),
+                );
+
                 // Only decode and rewrite if content type needs rewriting (performance optimization)
                 let should_rewrite = content_rewrite::should_rewrite_content(content_type);
 
-                let (rewritten_body, was_rewritten) = if should_rewrite {
-                    // Decode body for rewriting
-                    let body_bytes = http_tunnel_common::decode_body(&response.body)
-                        .map_err(|e| format!("Failed to decode response body: {}", e))?;
-                    let body_str = String::from_utf8_lossy(&body_bytes);
-
-                    // Rewrite content (default strategy: FullRewrite)
-                    content_rewrite::rewrite_response_content(
-                        &body_str,
-                        content_type,
-                        tunnel_id,
-                        content_rewrite::RewriteStrategy::FullRewrite,
-                    )
-                    .unwrap_or_else(|e| {
-                        warn!("Content rewrite failed: {}, returning original", e);
-                        (body_str.to_string(), false)
-                    })
-                } else {
-                    // Skip decoding for binary content (images, videos, etc.)
-                    debug!("Skipping rewrite for binary content type: {}", content_type);
-                    (String::new(), false)
+                let rewritten_body = match encoding.filter(|_| should_rewrite) {
+                    Some(encoding) => {
+                        rewrite_body(&response.body, encoding, content_type, tunnel_id, strategy)
+                    }
+                    None => {
+                        // Skip decoding for binary content (images, videos, etc.)
+                        debug!(
+                            "Skipping rewrite for content type {} ({:?})",
+                            content_type, encoding
+                        );
+                        None
+                    }
                 };
 
-                if was_rewritten {
+                if let Some(rewritten_body) = rewritten_body {
+                    rewrite_applied = true;
                     debug!(
                         "Content rewritten for request {}: {} bytes",
                         request_id,
@@ -223,7 +556,7 @@ pub async fn handle_forwarding(
                     );
 
                     // Re-encode the rewritten body
-                    response.body = http_tunnel_common::encode_body(rewritten_body.as_bytes());
+                    response.body = http_tunnel_common::encode_body(&rewritten_body);
 
                     // Update Content-Length header
                     response.headers.insert(
@@ -240,6 +573,8 @@ pub async fn handle_forwarding(
                         vec!["true".to_string()],
                     );
                 }
+            } else if routing_mode.should_rewrite_content() {
+                debug!("Content rewriting disabled for request {}", request_id);
             } else {
                 // Subdomain-based routing: skip content rewriting
                 debug!(
@@ -252,16 +587,45 @@ pub async fn handle_forwarding(
                 );
             }
 
+            if let (Some(policy), Some(origin)) = (&cors, &origin) {
+                apply_cors_headers(&mut response, policy, origin);
+            }
+
+            RequestMetrics {
+                tunnel_id,
+                request_id: &request_id,
+                status_code: response.status_code,
+                latency,
+                request_bytes,
+                response_bytes: decoded_body_size(&response.body),
+                rewrite_applied,
+                timed_out: false,
+            }
+            .emit();
+
             // Convert HttpResponse to API Gateway response
-            Ok(build_api_gateway_response(response))
+            Ok(build_api_gateway_response(response).into())
         }
         Err(e) => {
             use aws_lambda_events::encodings::Body;
             use http::header::{HeaderName, HeaderValue};
 
             error!("Request {} timeout or error: {}", request_id, e);
+            let latency = sent_at.elapsed();
+            record_stats(clients, &connection, 504, latency).await;
+            RequestMetrics {
+                tunnel_id,
+                request_id: &request_id,
+                status_code: 504,
+                latency,
+                request_bytes,
+                timed_out: true,
+                ..Default::default()
+            }
+            .emit();
             // Return 504 Gateway Timeout
-            Ok(ApiGatewayProxyResponse {
+            let message = "Gateway Timeout: No response from agent";
+            let mut response = ApiGatewayProxyResponse {
                 status_code: 504,
                 headers: [
                     (
@@ -274,17 +638,222 @@ pub async fn handle_forwarding(
                     ),
                 ]
                 .into_iter()
+                .chain(
+                    HeaderValue::from_str(&correlation_id)
+                        .ok()
+                        .map(|value| (HeaderName::from_static("x-request-id"), value)),
+                )
                 .collect(),
                 multi_value_headers: Default::default(),
-                body: Some(Body::Text(
-                    "Gateway Timeout: No response from agent".to_string(),
-                )),
+                body: Some(Body::Text(message.to_string())),
                 is_base64_encoded: false,
-            })
+            };
+            let page = ErrorPage {
+                status: 504,
+                message,
+                tunnel_id,
+                request_id: &correlation_id,
+            };
+            error_pages::apply(&mut response, &request, &page, clients).await;
+            Ok(response.into())
         }
     }
 }
 
+/// The client's `X-Request-Id`, if it's usable as a correlation ID
+fn client_correlation_id(request: &ApiGatewayProxyRequest) -> Option<String> {
+    header_str(request, "x-request-id")
+        .map(str::trim)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_CORRELATION_ID_LEN
+                && id.bytes().all(|byte| byte.is_ascii_graphic())
+        })
+        .map(str::to_string)
+}
+
+/// Echo the correlation ID in the public response, with how long the agent took
+fn tag_response(response: &mut HttpResponse, correlation_id: &str, latency: Duration) {
+    response
+        .headers
+        .insert("x-request-id".to_string(), vec![correlation_id.to_string()]);
+    response.headers.insert(
+        "x-tunnel-latency-ms".to_string(),
+        vec![latency.as_millis().to_string()],
+    );
+}
+
+/// Rewrite a path-routed response body, decompressing it first and compressing
+/// it again with the same encoding
+///
+/// Returns `None` if nothing changed or the body couldn't be rewritten, in which
+/// case it's sent as the local service produced it.
+fn rewrite_body(
+    body: &str,
+    encoding: ContentEncoding,
+    content_type: &str,
+    tunnel_id: &str,
+    strategy: RewriteStrategy,
+) -> Option<Vec<u8>> {
+    let body_bytes = http_tunnel_common::decode_body(body)
+        .inspect_err(|e| warn!("Failed to decode response body: {}", e))
+        .ok()?;
+    let body_bytes = encoding
+        .decode(&body_bytes)
+        .inspect_err(|e| warn!("Skipping rewrite of {:?} body: {:#}", encoding, e))
+        .ok()?;
+    let body_str = String::from_utf8_lossy(&body_bytes);
+
+    let (rewritten, was_rewritten) =
+        content_rewrite::rewrite_response_content(&body_str, content_type, tunnel_id, strategy)
+            .inspect_err(|e| warn!("Content rewrite failed: {}, returning original", e))
+            .ok()?;
+    if !was_rewritten {
+        return None;
+    }
+
+    encoding
+        .encode(rewritten.as_bytes())
+        .inspect_err(|e| warn!("Failed to compress rewritten body: {:#}", e))
+        .ok()
+}
+
+/// Build a plain-text response generated by the tunnel itself
+pub(super) fn plain_response(status_code: i64, message: &'static str) -> ApiGatewayProxyResponse {
+    use aws_lambda_events::encodings::Body;
+    use http::header::{HeaderName, HeaderValue};
+
+    ApiGatewayProxyResponse {
+        status_code,
+        headers: [
+            (
+                HeaderName::from_static("content-type"),
+                HeaderValue::from_static("text/plain"),
+            ),
+            (
+                HeaderName::from_static("x-tunnel-error"),
+                HeaderValue::from_static(message),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        multi_value_headers: Default::default(),
+        body: Some(Body::Text(message.to_string())),
+        is_base64_encoded: false,
+    }
+}
+
+/// 401 asking the client for the tunnel's basic auth credentials
+pub(super) fn unauthorized_response() -> ApiGatewayProxyResponse {
+    let mut response = plain_response(401, "Unauthorized");
+    response.headers.insert(
+        http::header::WWW_AUTHENTICATE,
+        http::HeaderValue::from_static("Basic realm=\"tunnel\", charset=\"UTF-8\""),
+    );
+    response
+}
+
+fn header_str<'a>(request: &'a ApiGatewayProxyRequest, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The connections whose IP rules let `source_ip` in
+///
+/// Agents sharing a tunnel each bring their own options, and a request may fail
+/// over to any of them, so every candidate's rules are checked.
+fn permitted_connections(
+    connections: Vec<ConnectionMetadata>,
+    source_ip: Option<&str>,
+) -> Vec<ConnectionMetadata> {
+    connections
+        .into_iter()
+        .filter(|connection| connection.options.ip_access.permits(source_ip))
+        .collect()
+}
+
+/// Answer a CORS preflight request (`OPTIONS` with `Origin` and
+/// `Access-Control-Request-Method`) from the tunnel's policy
+///
+/// Returns `None` for anything that isn't a preflight so it gets forwarded.
+fn preflight_response(
+    policy: &CorsPolicy,
+    request: &ApiGatewayProxyRequest,
+) -> Option<ApiGatewayProxyResponse> {
+    use aws_lambda_events::encodings::Body;
+    use http::header::{HeaderName, HeaderValue};
+
+    if request.http_method != http::Method::OPTIONS {
+        return None;
+    }
+    let origin = header_str(request, "origin")?;
+    let method = header_str(request, "access-control-request-method")?;
+    let requested_headers = header_str(request, "access-control-request-headers");
+
+    match policy.preflight(origin, method, requested_headers) {
+        PreflightOutcome::Allowed(headers) => Some(ApiGatewayProxyResponse {
+            status_code: 204,
+            headers: headers
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    Some((
+                        HeaderName::from_bytes(name.as_bytes()).ok()?,
+                        HeaderValue::from_str(&value).ok()?,
+                    ))
+                })
+                .collect(),
+            multi_value_headers: Default::default(),
+            body: Some(Body::Empty),
+            is_base64_encoded: false,
+        }),
+        PreflightOutcome::Denied => Some(plain_response(403, "CORS preflight rejected")),
+    }
+}
+
+/// Add the policy's CORS headers to a response unless the local service
+/// already set its own
+fn apply_cors_headers(response: &mut HttpResponse, policy: &CorsPolicy, origin: &str) {
+    let has_cors = response
+        .headers
+        .keys()
+        .any(|name| name.eq_ignore_ascii_case("access-control-allow-origin"));
+    if has_cors {
+        return;
+    }
+
+    for (name, value) in policy.response_headers(origin) {
+        response.headers.entry(name).or_default().push(value);
+    }
+}
+
+/// Record the request in the tunnel's traffic statistics and check its alert
+/// thresholds (failures are only logged)
+async fn record_stats(
+    clients: &SharedClients,
+    connection: &ConnectionMetadata,
+    status_code: u16,
+    latency: Duration,
+) {
+    let tunnel_id = &connection.tunnel_id;
+    if let Err(e) = clients
+        .store
+        .record_request(tunnel_id, status_code, latency)
+        .await
+    {
+        warn!("Failed to record stats for tunnel {}: {:#}", tunnel_id, e);
+        return;
+    }
+
+    if let Err(e) = alerts::evaluate_tunnel_alerts(clients, connection).await {
+        warn!(
+            "Failed to evaluate alerts for tunnel {}: {:#}",
+            tunnel_id, e
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -312,4 +881,202 @@ mod tests {
         assert!(!response.headers.is_empty());
         assert!(response.body.is_some());
     }
+    #[test]
+    fn test_client_correlation_id() {
+        let mut request = ApiGatewayProxyRequest::default();
+        assert_eq!(client_correlation_id(&request), None);
+
+        request.headers.insert(
+            "x-request-id",
+            HeaderValue::from_static(" 7f9c2ba4-e88f-4c1e "),
+        );
+        assert_eq!(
+            client_correlation_id(&request).as_deref(),
+            Some("7f9c2ba4-e88f-4c1e")
+        );
+
+        // IDs that can't be logged or echoed safely are replaced
+        request
+            .headers
+            .insert("x-request-id", HeaderValue::from_static("has space"));
+        assert_eq!(client_correlation_id(&request), None);
+        let long = "a".repeat(MAX_CORRELATION_ID_LEN + 1);
+        request
+            .headers
+            .insert("x-request-id", HeaderValue::from_str(&long).unwrap());
+        assert_eq!(client_correlation_id(&request), None);
+    }
+
+    #[test]
+    fn test_tag_response() {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response
+            .headers
+            .insert("x-request-id".to_string(), vec!["local".to_string()]);
+        tag_response(&mut response, "client-id", Duration::from_millis(42));
+        assert_eq!(response.headers["x-request-id"], vec!["client-id"]);
+        assert_eq!(response.headers["x-tunnel-latency-ms"], vec!["42"]);
+    }
+
+    #[test]
+    fn test_rewrite_compressed_body() {
+        let html = r#"<a href="/api">API</a>"#;
+        let body = http_tunnel_common::encode_body(
+            &ContentEncoding::Gzip.encode(html.as_bytes()).unwrap(),
+        );
+
+        let rewritten = rewrite_body(
+            &body,
+            ContentEncoding::Gzip,
+            "text/html",
+            "abc123",
+            RewriteStrategy::FullRewrite,
+        )
+        .unwrap();
+        let rewritten = ContentEncoding::Gzip.decode(&rewritten).unwrap();
+        assert!(
+            String::from_utf8(rewritten)
+                .unwrap()
+                .contains(r#"href="/abc123/api""#)
+        );
+
+        // Tunnels can turn rewriting off
+        assert!(
+            rewrite_body(
+                &body,
+                ContentEncoding::Gzip,
+                "text/html",
+                "abc123",
+                RewriteStrategy::None,
+            )
+            .is_none()
+        );
+
+        // A body that doesn't decompress is left alone
+        let garbage = http_tunnel_common::encode_body(b"not gzip");
+        assert!(
+            rewrite_body(
+                &garbage,
+                ContentEncoding::Gzip,
+                "text/html",
+                "abc123",
+                RewriteStrategy::FullRewrite,
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn test_plain_response() {
+        let response = plain_response(403, "Forbidden");
+        assert_eq!(response.status_code, 403);
+        assert_eq!(response.headers.get("x-tunnel-error").unwrap(), "Forbidden");
+        assert!(matches!(response.body, Some(Body::Text(ref body)) if body == "Forbidden"));
+    }
+
+    #[test]
+    fn test_unauthorized_response() {
+        let response = unauthorized_response();
+        assert_eq!(response.status_code, 401);
+        assert!(
+            response.headers[http::header::WWW_AUTHENTICATE]
+                .to_str()
+                .unwrap()
+                .starts_with("Basic realm=")
+        );
+    }
+
+    fn preflight_request(origin: &str, method: &str) -> ApiGatewayProxyRequest {
+        let mut request = ApiGatewayProxyRequest {
+            http_method: http::Method::OPTIONS,
+            ..Default::default()
+        };
+        request
+            .headers
+            .insert("origin", HeaderValue::from_str(origin).unwrap());
+        request.headers.insert(
+            "access-control-request-method",
+            HeaderValue::from_str(method).unwrap(),
+        );
+        request
+    }
+
+    #[test]
+    fn test_permitted_connections() {
+        let connection = |connection_id: &str, allow: &str| {
+            let mut connection = ConnectionMetadata::new(
+                connection_id.to_string(),
+                "abc123def456".to_string(),
+                "https://abc123def456.tunnel.example.com".to_string(),
+                1_700_000_000,
+                1_700_007_200,
+            );
+            connection.options.ip_access.allow = vec![allow.parse().unwrap()];
+            connection
+        };
+        let connections = vec![
+            connection("conn_1", "10.0.0.0/8"),
+            connection("conn_2", "192.168.0.0/16"),
+        ];
+
+        // Only agents whose rules let the client in are candidates
+        let permitted = permitted_connections(connections.clone(), Some("192.168.1.5"));
+        assert_eq!(permitted.len(), 1);
+        assert_eq!(permitted[0].connection_id, "conn_2");
+        assert!(permitted_connections(connections, Some("172.16.0.1")).is_empty());
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let policy = CorsPolicy {
+            allow_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let response = preflight_response(
+            &policy,
+            &preflight_request("https://app.example.com", "POST"),
+        )
+        .unwrap();
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            response.headers.get("access-control-allow-origin").unwrap(),
+            "https://app.example.com"
+        );
+
+        let response = preflight_response(
+            &policy,
+            &preflight_request("https://evil.example.com", "POST"),
+        )
+        .unwrap();
+        assert_eq!(response.status_code, 403);
+
+        // Plain OPTIONS requests are forwarded to the local service
+        let mut request = preflight_request("https://app.example.com", "POST");
+        request.headers.remove("access-control-request-method");
+        assert!(preflight_response(&policy, &request).is_none());
+    }
+
+    #[test]
+    fn test_apply_cors_headers_keeps_upstream_headers() {
+        let policy = CorsPolicy {
+            allow_origins: vec!["*".to_string()],
+            ..Default::default()
+        };
+
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        apply_cors_headers(&mut response, &policy, "https://a.com");
+        assert_eq!(
+            response.headers.get("access-control-allow-origin"),
+            Some(&vec!["*".to_string()])
+        );
+
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.headers.insert(
+            "Access-Control-Allow-Origin".to_string(),
+            vec!["https://b.com".to_string()],
+        );
+        apply_cors_headers(&mut response, &policy, "https://a.com");
+        assert_eq!(response.headers.len(), 1);
+    }
 }
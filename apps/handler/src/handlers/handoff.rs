@@ -0,0 +1,202 @@
+//! HandoffHandler - Asks agents to reconnect before their connection expires
+//!
+//! API Gateway closes agent connections after MAX_CONNECTION_LIFETIME_SECS,
+//! dropping whatever requests are in flight. This handler runs every minute
+//! (triggered by EventBridge with `{"task": "handoff"}`) and sends
+//! `ReconnectRequested` to every connection within HANDOFF_WINDOW_SECS of
+//! that limit. The message carries a one-time token, also stored on the
+//! connection item as `handoffToken`; the agent's new connection presents it in
+//! its Ready message to take over the tunnel (see [`take_over`]) while the old
+//! connection finishes its in-flight requests.
+//!
+//! The same sweep warns agents of idle or expiring connections (see
+//! [`super::idle`]).
+
+use anyhow::{Context, Result, anyhow};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::constants::{HANDOFF_WINDOW_SECS, MAX_CONNECTION_LIFETIME_SECS};
+use http_tunnel_common::protocol::{Handoff, Message};
+use http_tunnel_common::utils::{current_timestamp_secs, generate_request_id};
+use lambda_runtime::Error;
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+use super::idle;
+use crate::{
+    DeliveryFailure, SharedClients, TunnelUrls, delete_connection, reassign_tunnel_id,
+    remove_stale_connection, send_message_to_connection,
+};
+
+/// Handler for the scheduled handoff sweep (triggered by EventBridge)
+pub async fn handle_handoff_sweep(_event: Value, clients: &SharedClients) -> Result<Value, Error> {
+    let apigw_management = clients
+        .apigw_management()
+        .ok_or("API Gateway Management client not initialized")?;
+    let now = current_timestamp_secs();
+
+    let expiring = expiring_connections(&clients.dynamodb, now)
+        .await
+        .map_err(|e| {
+            error!("Failed to find expiring connections: {:#}", e);
+            format!("Handoff sweep failed: {}", e)
+        })?;
+
+    let mut requested = 0;
+    for (connection_id, created_at) in expiring {
+        let token = generate_request_id();
+        match save_handoff_token(&clients.dynamodb, &connection_id, &token).await {
+            Ok(true) => {}
+            // Another sweep got there first
+            Ok(false) => continue,
+            Err(e) => {
+                warn!(
+                    "Failed to save handoff token for {}: {:#}",
+                    connection_id, e
+                );
+                continue;
+            }
+        }
+
+        let message = Message::ReconnectRequested {
+            handoff_token: token,
+            expires_at: created_at + MAX_CONNECTION_LIFETIME_SECS,
+        };
+        match send_message_to_connection(apigw_management, &connection_id, message).await {
+            Ok(()) => requested += 1,
+            Err(e) if DeliveryFailure::classify(&e) == DeliveryFailure::Gone => {
+                // The agent left without $disconnect reaching us
+                remove_stale_connection(&clients.store, &connection_id).await;
+            }
+            Err(e) => warn!(
+                "Failed to request reconnect from {}: {:#}",
+                connection_id, e
+            ),
+        }
+    }
+
+    let idle = idle::sweep_idle(clients, now).await.unwrap_or_else(|e| {
+        error!("Idle sweep failed: {:#}", e);
+        Default::default()
+    });
+
+    info!(
+        "Handoff sweep completed: {} reconnects requested, {:?}",
+        requested, idle
+    );
+
+    Ok(serde_json::json!({
+        "reconnectsRequested": requested,
+        "idleWarnings": idle.idle_warnings,
+        "expiryWarnings": idle.expiry_warnings,
+        "idleClosed": idle.closed,
+        "timestamp": now
+    }))
+}
+
+/// Connections within the handoff window that haven't been asked to reconnect yet
+async fn expiring_connections(client: &DynamoDbClient, now: i64) -> Result<Vec<(String, i64)>> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+    let cutoff = now - (MAX_CONNECTION_LIFETIME_SECS - HANDOFF_WINDOW_SECS);
+
+    let result = client
+        .scan()
+        .table_name(&table_name)
+        .filter_expression("createdAt <= :cutoff AND attribute_not_exists(handoffToken)")
+        .expression_attribute_values(":cutoff", AttributeValue::N(cutoff.to_string()))
+        .projection_expression("connectionId, createdAt")
+        .send()
+        .await
+        .context("Failed to scan connections")?;
+
+    Ok(result
+        .items
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|item| {
+            let connection_id = item.get("connectionId")?.as_s().ok()?.clone();
+            let created_at = item.get("createdAt")?.as_n().ok()?.parse().ok()?;
+            Some((connection_id, created_at))
+        })
+        .collect())
+}
+
+/// Store the handoff token on a connection; false if it already has one
+async fn save_handoff_token(
+    client: &DynamoDbClient,
+    connection_id: &str,
+    token: &str,
+) -> Result<bool> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let result = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()))
+        .update_expression("SET handoffToken = :token")
+        .condition_expression(
+            "attribute_exists(connectionId) AND attribute_not_exists(handoffToken)",
+        )
+        .expression_attribute_values(":token", AttributeValue::S(token.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).context("Failed to save handoff token"),
+    }
+}
+
+/// Move the tunnel of the connection named in `handoff` to `connection_id`
+///
+/// The token must match the one sent to the old connection and both
+/// connections must belong to the same owner. The old connection's item is
+/// removed so new requests go to the new connection; responses to requests
+/// already sent still arrive over the old one.
+pub async fn take_over(
+    client: &DynamoDbClient,
+    connection_id: &str,
+    owner: Option<&String>,
+    handoff: &Handoff,
+) -> Result<(String, TunnelUrls)> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let old = client
+        .get_item()
+        .table_name(&table_name)
+        .key(
+            "connectionId",
+            AttributeValue::S(handoff.connection_id.clone()),
+        )
+        .consistent_read(true)
+        .send()
+        .await
+        .context("Failed to get handed-off connection")?
+        .item
+        .ok_or_else(|| anyhow!("connection {} not found", handoff.connection_id))?;
+
+    let string = |name: &str| old.get(name).and_then(|v| v.as_s().ok());
+    if string("handoffToken") != Some(&handoff.token) {
+        return Err(anyhow!("handoff token doesn't match"));
+    }
+    if string("owner") != owner {
+        return Err(anyhow!("connection belongs to another owner"));
+    }
+    let tunnel_id = string("tunnelId")
+        .ok_or_else(|| anyhow!("connection has no tunnel ID"))?
+        .clone();
+
+    let urls = reassign_tunnel_id(client, connection_id, &tunnel_id).await?;
+    delete_connection(client, &handoff.connection_id).await?;
+
+    Ok((tunnel_id, urls))
+}
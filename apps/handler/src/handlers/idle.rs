@@ -0,0 +1,342 @@
+//! IdleHandler - Warns agents before their connection is closed
+//!
+//! Runs with the handoff sweep, every minute. Two kinds of warning are sent,
+//! each once, CLOSE_WARNING_SECS ahead:
+//!
+//! - `ExpiryWarning` to connections nearing MAX_CONNECTION_LIFETIME_SECS that
+//!   haven't been handed off, so the CLI can say the tunnel is about to expire
+//!   rather than dropping silently
+//! - `IdleWarning` to tunnels that have gone IDLE_TIMEOUT_SECS (when set)
+//!   without traffic, going by the `lastActivityAt` that forwarding and
+//!   responses keep up to date (see [`crate::activity`])
+//!
+//! Idle connections that still see no traffic are then closed with a
+//! `TunnelIdle` error, which tells the agent not to reconnect. Traffic after a
+//! warning starts the idle period over.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::constants::{CLOSE_WARNING_SECS, MAX_CONNECTION_LIFETIME_SECS};
+use http_tunnel_common::protocol::{ErrorCode, Message};
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
+
+use crate::{
+    DeliveryFailure, SharedClients, delete_connection, remove_stale_connection,
+    send_message_to_connection,
+};
+
+/// Idle timeout from `IDLE_TIMEOUT_SECS`; tunnels are never closed for being
+/// idle without it
+pub fn idle_timeout_secs() -> Option<i64> {
+    std::env::var("IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.trim().parse().ok())
+        .filter(|secs| *secs > 0)
+}
+
+/// What the idle sweep did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdleSweep {
+    pub idle_warnings: u32,
+    pub expiry_warnings: u32,
+    pub closed: u32,
+}
+
+/// Activity of a connection, as read by the sweep
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ConnectionActivity {
+    connection_id: String,
+    created_at: i64,
+    last_activity_at: Option<i64>,
+    idle_warned_at: Option<i64>,
+    expiry_warned: bool,
+}
+
+/// What to do about a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    IdleWarning { idle_secs: i64, closes_at: i64 },
+    ExpiryWarning { expires_at: i64 },
+    Close,
+}
+
+impl ConnectionActivity {
+    fn from_item(item: &HashMap<String, AttributeValue>) -> Option<Self> {
+        let number = |name: &str| item.get(name)?.as_n().ok()?.parse().ok();
+        Some(Self {
+            connection_id: item.get("connectionId")?.as_s().ok()?.clone(),
+            created_at: number("createdAt")?,
+            last_activity_at: number("lastActivityAt"),
+            idle_warned_at: number("idleWarnedAt"),
+            expiry_warned: item.contains_key("expiryWarnedAt"),
+        })
+    }
+
+    fn next_action(&self, now: i64, idle_timeout: Option<i64>) -> Option<Action> {
+        if let Some(timeout) = idle_timeout {
+            let last = self
+                .last_activity_at
+                .map_or(self.created_at, |last| last.max(self.created_at));
+            let closes_at = last + timeout;
+            if now >= closes_at {
+                return Some(Action::Close);
+            }
+            let warned = self.idle_warned_at.is_some_and(|warned| warned >= last);
+            if !warned && now >= closes_at - CLOSE_WARNING_SECS {
+                return Some(Action::IdleWarning {
+                    idle_secs: now - last,
+                    closes_at,
+                });
+            }
+        }
+
+        let expires_at = self.created_at + MAX_CONNECTION_LIFETIME_SECS;
+        (!self.expiry_warned && now >= expires_at - CLOSE_WARNING_SECS)
+            .then_some(Action::ExpiryWarning { expires_at })
+    }
+}
+
+/// Warn agents whose connection is about to close, and close idle ones
+pub async fn sweep_idle(clients: &SharedClients, now: i64) -> Result<IdleSweep> {
+    let Some(apigw_management) = clients.apigw_management() else {
+        return Ok(IdleSweep::default());
+    };
+    let idle_timeout = idle_timeout_secs();
+
+    let mut sweep = IdleSweep::default();
+    for connection in scan_activity(&clients.dynamodb).await? {
+        let Some(action) = connection.next_action(now, idle_timeout) else {
+            continue;
+        };
+        let connection_id = connection.connection_id.as_str();
+
+        let (message, marker) = match action {
+            Action::IdleWarning {
+                idle_secs,
+                closes_at,
+            } => (
+                Message::IdleWarning {
+                    idle_secs,
+                    closes_at,
+                },
+                Some("idleWarnedAt"),
+            ),
+            Action::ExpiryWarning { expires_at } => (
+                Message::ExpiryWarning { expires_at },
+                Some("expiryWarnedAt"),
+            ),
+            Action::Close => (
+                Message::Error {
+                    request_id: None,
+                    code: ErrorCode::TunnelIdle,
+                    message: "Tunnel closed after going without traffic".to_string(),
+                },
+                None,
+            ),
+        };
+
+        match send_message_to_connection(apigw_management, connection_id, message).await {
+            Ok(()) => {}
+            Err(e) if DeliveryFailure::classify(&e) == DeliveryFailure::Gone => {
+                // The agent left without $disconnect reaching us
+                remove_stale_connection(&clients.store, connection_id).await;
+                continue;
+            }
+            Err(e) => {
+                warn!("Failed to warn connection {}: {:#}", connection_id, e);
+                continue;
+            }
+        }
+
+        match marker {
+            Some(marker) => {
+                if let Err(e) = mark_warned(&clients.dynamodb, connection_id, marker, now).await {
+                    warn!("Failed to record warning for {}: {:#}", connection_id, e);
+                }
+                if matches!(action, Action::IdleWarning { .. }) {
+                    sweep.idle_warnings += 1;
+                } else {
+                    sweep.expiry_warnings += 1;
+                }
+            }
+            None => {
+                if let Err(e) = apigw_management
+                    .delete_connection()
+                    .connection_id(connection_id)
+                    .send()
+                    .await
+                {
+                    debug!("Failed to close idle connection {}: {}", connection_id, e);
+                }
+                if let Err(e) = delete_connection(&clients.dynamodb, connection_id).await {
+                    warn!(
+                        "Failed to delete idle connection {}: {:#}",
+                        connection_id, e
+                    );
+                }
+                clients.connections.invalidate_connection(connection_id);
+                info!("Closed idle connection {}", connection_id);
+                sweep.closed += 1;
+            }
+        }
+    }
+
+    Ok(sweep)
+}
+
+async fn scan_activity(client: &DynamoDbClient) -> Result<Vec<ConnectionActivity>> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let result = client
+        .scan()
+        .table_name(&table_name)
+        .projection_expression(
+            "connectionId, createdAt, lastActivityAt, idleWarnedAt, expiryWarnedAt",
+        )
+        .send()
+        .await
+        .context("Failed to scan connections")?;
+
+    Ok(result
+        .items
+        .unwrap_or_default()
+        .iter()
+        .filter_map(ConnectionActivity::from_item)
+        .collect())
+}
+
+/// Record when a warning was sent, so it's sent once
+async fn mark_warned(
+    client: &DynamoDbClient,
+    connection_id: &str,
+    marker: &str,
+    now: i64,
+) -> Result<()> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()))
+        .update_expression("SET #marker = :now")
+        .condition_expression("attribute_exists(connectionId)")
+        .expression_attribute_names("#marker", marker)
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .send()
+        .await
+        .context("Failed to record warning")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CREATED: i64 = 1_700_000_000;
+    const HOUR: i64 = 3600;
+
+    fn connection() -> ConnectionActivity {
+        ConnectionActivity {
+            connection_id: "conn_1".to_string(),
+            created_at: CREATED,
+            last_activity_at: None,
+            idle_warned_at: None,
+            expiry_warned: false,
+        }
+    }
+
+    #[test]
+    fn test_idle_warning_then_close() {
+        let connection = connection();
+        assert_eq!(connection.next_action(CREATED + 600, Some(HOUR)), None);
+        assert_eq!(
+            connection.next_action(CREATED + HOUR - CLOSE_WARNING_SECS, Some(HOUR)),
+            Some(Action::IdleWarning {
+                idle_secs: HOUR - CLOSE_WARNING_SECS,
+                closes_at: CREATED + HOUR,
+            })
+        );
+
+        let warned = ConnectionActivity {
+            idle_warned_at: Some(CREATED + HOUR - CLOSE_WARNING_SECS),
+            ..connection.clone()
+        };
+        assert_eq!(warned.next_action(CREATED + HOUR - 60, Some(HOUR)), None);
+        assert_eq!(
+            warned.next_action(CREATED + HOUR, Some(HOUR)),
+            Some(Action::Close)
+        );
+
+        // Without an idle timeout only expiry matters
+        assert_eq!(connection.next_action(CREATED + HOUR, None), None);
+    }
+
+    #[test]
+    fn test_traffic_restarts_idle_period() {
+        let connection = ConnectionActivity {
+            last_activity_at: Some(CREATED + 3400),
+            idle_warned_at: Some(CREATED + HOUR - CLOSE_WARNING_SECS),
+            ..connection()
+        };
+        assert_eq!(connection.next_action(CREATED + HOUR, Some(HOUR)), None);
+        // A new warning is due for the new idle period
+        assert!(matches!(
+            connection.next_action(CREATED + 3400 + HOUR - 60, Some(HOUR)),
+            Some(Action::IdleWarning { closes_at, .. }) if closes_at == CREATED + 3400 + HOUR
+        ));
+    }
+
+    #[test]
+    fn test_expiry_warning() {
+        let expires_at = CREATED + MAX_CONNECTION_LIFETIME_SECS;
+        let connection = ConnectionActivity {
+            last_activity_at: Some(expires_at - 60),
+            ..connection()
+        };
+        assert_eq!(
+            connection.next_action(expires_at - CLOSE_WARNING_SECS - 1, Some(HOUR)),
+            None
+        );
+        assert_eq!(
+            connection.next_action(expires_at - CLOSE_WARNING_SECS, Some(HOUR)),
+            Some(Action::ExpiryWarning { expires_at })
+        );
+        let warned = ConnectionActivity {
+            expiry_warned: true,
+            ..connection
+        };
+        assert_eq!(warned.next_action(expires_at - 60, Some(HOUR)), None);
+    }
+
+    #[test]
+    fn test_from_item() {
+        let item = HashMap::from([
+            (
+                "connectionId".to_string(),
+                AttributeValue::S("conn_1".to_string()),
+            ),
+            (
+                "createdAt".to_string(),
+                AttributeValue::N(CREATED.to_string()),
+            ),
+            (
+                "lastActivityAt".to_string(),
+                AttributeValue::N((CREATED + 5).to_string()),
+            ),
+            (
+                "expiryWarnedAt".to_string(),
+                AttributeValue::N((CREATED + 9).to_string()),
+            ),
+        ]);
+        let activity = ConnectionActivity::from_item(&item).unwrap();
+        assert_eq!(activity.last_activity_at, Some(CREATED + 5));
+        assert_eq!(activity.idle_warned_at, None);
+        assert!(activity.expiry_warned);
+        assert!(ConnectionActivity::from_item(&HashMap::new()).is_none());
+    }
+}
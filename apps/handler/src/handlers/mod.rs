@@ -3,19 +3,56 @@
 //! This module contains all the individual handler implementations for different
 //! event types that the unified Lambda function can process.
 
+use aws_lambda_events::apigw::ApiGatewayProxyResponse;
+use aws_lambda_events::encodings::Body;
+use http::header::{HeaderName, HeaderValue};
+use serde_json::Value;
+
+pub mod admin;
 pub mod cleanup;
 pub mod connect;
+pub mod dashboard;
 pub mod disconnect;
 pub mod forwarding;
+pub mod handoff;
+pub mod idle;
 pub mod response;
+pub mod routes;
+pub mod status;
 pub mod stream;
+pub mod websocket;
 
 #[cfg(test)]
 mod tests;
 
+pub use admin::handle_admin;
 pub use cleanup::handle_cleanup;
 pub use connect::handle_connect;
 pub use disconnect::handle_disconnect;
-pub use forwarding::handle_forwarding;
+pub use forwarding::{ForwardingResponse, handle_forwarding};
+pub use handoff::handle_handoff_sweep;
 pub use response::handle_response;
 pub use stream::handle_stream;
+pub use websocket::{handle_ws_connect, handle_ws_disconnect, handle_ws_message};
+
+/// Build a JSON API Gateway response that isn't cached
+pub(crate) fn json_response(status_code: i64, body: Value) -> ApiGatewayProxyResponse {
+    ApiGatewayProxyResponse {
+        status_code,
+        headers: [
+            (
+                HeaderName::from_static("content-type"),
+                HeaderValue::from_static("application/json"),
+            ),
+            (
+                HeaderName::from_static("cache-control"),
+                HeaderValue::from_static("no-store"),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        multi_value_headers: Default::default(),
+        body: Some(Body::Text(body.to_string())),
+        is_base64_encoded: false,
+    }
+}
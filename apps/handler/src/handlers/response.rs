@@ -6,14 +6,27 @@
 
 use aws_lambda_events::apigw::ApiGatewayProxyResponse;
 use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_dynamodb::types::AttributeValue;
-use http_tunnel_common::encode_body;
-use http_tunnel_common::protocol::{ErrorCode, HttpResponse, Message};
+use http_tunnel_common::constants::{MAX_BODY_SIZE_BYTES, MAX_CONNECTION_LIFETIME_SECS};
+use http_tunnel_common::models::status_class;
+use http_tunnel_common::protocol::{
+    Capability, ErrorCode, HttpResponse, Message, TunnelInfo, TunnelOptions,
+};
+use http_tunnel_common::validation::validate_tunnel_id;
 use lambda_runtime::{Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
 
-use crate::{SharedClients, update_pending_request_with_response};
+use super::{handoff, websocket};
+use crate::activity;
+use crate::analytics::{self, Completion};
+use crate::metrics::{MetricLog, Unit};
+use crate::reservations::{self, Claim};
+use crate::store::TunnelStore;
+use crate::{
+    SharedClients, TunnelUrls, build_error_response, chunks, decoded_body_size,
+    query_tunnel_connections, reassign_tunnel_id, remove_other_connections,
+    send_message_to_connection, stats,
+};
 use aws_sdk_apigatewaymanagement::primitives::Blob;
 
 /// WebSocket $default event structure (messages from agent)
@@ -58,22 +71,122 @@ pub async fn handle_response(
 
     let connection_id = &event.payload.request_context.connection_id;
 
+    // Tunnels serving traffic don't expire
+    if carries_traffic(&message) {
+        activity::touch_connection(clients, connection_id).await;
+    }
+
     match message {
-        Message::Ready => {
+        Message::Ready { options } => {
             info!("Received Ready message from agent, sending ConnectionEstablished");
-            handle_ready_message(&clients.dynamodb, &clients.apigw_management, connection_id)
-                .await?;
+            handle_ready_message(
+                &clients.store,
+                &clients.dynamodb,
+                clients.apigw_management(),
+                connection_id,
+                &options,
+            )
+            .await?;
+            // The agent may have joined or taken over a tunnel others have cached
+            if let Some(tunnel_id) = &options.tunnel_id {
+                clients.connections.invalidate_tunnel(tunnel_id);
+            }
+            if let Some(handoff) = &options.handoff {
+                clients
+                    .connections
+                    .invalidate_connection(&handoff.connection_id);
+            }
+        }
+        Message::HttpResponse(response) if response.chunked => {
+            info!(
+                "Received chunked HTTP response for request {}: status {}",
+                response.request_id, response.status_code
+            );
+            response_metrics(&response).emit();
+            let completion = clients
+                .store
+                .save_response_head(&response)
+                .await
+                .map_err(|e| chunk_error(&response.request_id, e))?;
+            record_completion(clients, connection_id, completion).await;
         }
         Message::HttpResponse(response) => {
             info!(
                 "Received HTTP response for request {}: status {}",
                 response.request_id, response.status_code
             );
-            handle_http_response(&clients.dynamodb, response).await?;
+            response_metrics(&response).emit();
+            let completion = handle_http_response(&clients.store, response).await?;
+            record_completion(clients, connection_id, completion).await;
+        }
+        Message::BodyChunk {
+            request_id,
+            index,
+            data,
+        } => {
+            debug!("Received chunk {} for request {}", index, request_id);
+            let completion = clients
+                .store
+                .save_body_chunk(&request_id, index, data)
+                .await
+                .map_err(|e| chunk_error(&request_id, e))?;
+            record_completion(clients, connection_id, completion).await;
+        }
+        Message::BodyEnd {
+            request_id,
+            chunks: count,
+        } => {
+            debug!("Request {} body ends after {} chunks", request_id, count);
+            let completion = clients
+                .store
+                .save_body_end(&request_id, count)
+                .await
+                .map_err(|e| chunk_error(&request_id, e))?;
+            record_completion(clients, connection_id, completion).await;
+        }
+        Message::ResponseChunk {
+            request_id,
+            index,
+            data,
+            last,
+        } => {
+            debug!(
+                "Received stream chunk {} for request {}{}",
+                index,
+                request_id,
+                if last { " (last)" } else { "" }
+            );
+            let table = clients
+                .store
+                .pending_table()
+                .map_err(|e| chunk_error(&request_id, e))?;
+            chunks::save_stream_chunk(&clients.dynamodb, &table, &request_id, index, data, last)
+                .await
+                .map_err(|e| chunk_error(&request_id, e))?;
+        }
+        Message::WsFrame {
+            session_id,
+            data,
+            binary,
+        } => {
+            if let Err(e) =
+                websocket::relay_to_client(clients, connection_id, &session_id, &data, binary).await
+            {
+                warn!("Failed to relay frame to session {}: {:#}", session_id, e);
+            }
+        }
+        Message::WsClose { session_id, .. } => {
+            if let Err(e) = websocket::close_client(clients, connection_id, &session_id).await {
+                warn!("Failed to close session {}: {:#}", session_id, e);
+            }
         }
         Message::Ping => {
-            // Heartbeat received, no action needed
             debug!("Received ping from agent");
+            // Sent by agents from before ping frames, reported by the tunnel
+            // status endpoint
+            if let Err(e) = clients.store.record_heartbeat(connection_id).await {
+                warn!("Failed to record heartbeat for {}: {:#}", connection_id, e);
+            }
         }
         Message::Pong => {
             // Pong received, no action needed
@@ -89,7 +202,14 @@ pub async fn handle_response(
                     "Received error for request {}: {:?} - {}",
                     req_id, code, error_message
                 );
-                handle_error_response(&clients.dynamodb, &req_id, code, &error_message).await?;
+                MetricLog::new()
+                    .dimension("ErrorCode", format!("{:?}", code))
+                    .metric("AgentErrors", 1.0, Unit::Count)
+                    .property("RequestId", req_id.as_str())
+                    .emit();
+                let completion =
+                    handle_error_response(&clients.store, &req_id, code, &error_message).await?;
+                record_completion(clients, connection_id, completion).await;
             } else {
                 warn!("Received error without request ID: {}", error_message);
             }
@@ -109,12 +229,58 @@ pub async fn handle_response(
     })
 }
 
+/// Metrics for a response head or whole response sent by an agent
+///
+/// The body size of a chunked response isn't known until its last chunk, so
+/// only whole responses report `AgentResponseBytes`.
+fn response_metrics(response: &HttpResponse) -> MetricLog {
+    let log = MetricLog::new()
+        .dimension("StatusClass", status_class(response.status_code))
+        .metric("AgentResponses", 1.0, Unit::Count)
+        .property("RequestId", response.request_id.as_str());
+    if response.chunked {
+        log
+    } else {
+        let bytes = decoded_body_size(&response.body);
+        log.metric("AgentResponseBytes", bytes as f64, Unit::Bytes)
+    }
+}
+
+/// Add a completed request to its tunnel's analytics
+async fn record_completion(
+    clients: &SharedClients,
+    connection_id: &str,
+    completion: Option<Completion>,
+) {
+    if let Some(completion) = completion {
+        analytics::record_completion(clients, connection_id, completion).await;
+    }
+}
+
+/// Whether a message from the agent is part of serving a request, as opposed
+/// to heartbeats and control messages
+fn carries_traffic(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::HttpResponse(_)
+            | Message::BodyChunk { .. }
+            | Message::BodyEnd { .. }
+            | Message::ResponseChunk { .. }
+            | Message::WsFrame { .. }
+            | Message::Error {
+                request_id: Some(_),
+                ..
+            }
+    )
+}
+
 /// Handle HTTP response from agent
-async fn handle_http_response(
-    client: &DynamoDbClient,
+async fn handle_http_response<S: TunnelStore>(
+    store: &S,
     response: HttpResponse,
-) -> Result<(), Error> {
-    update_pending_request_with_response(client, &response)
+) -> Result<Option<Completion>, Error> {
+    let context = store
+        .complete_pending_request(&response)
         .await
         .map_err(|e| {
             error!(
@@ -129,64 +295,154 @@ async fn handle_http_response(
         response.request_id
     );
 
-    Ok(())
+    Ok(context.map(|context| {
+        context.complete(
+            response.status_code,
+            decoded_body_size(&response.body) as u64,
+        )
+    }))
+}
+
+fn chunk_error(request_id: &str, e: anyhow::Error) -> String {
+    error!("Failed to store chunked response {}: {:#}", request_id, e);
+    format!("Failed to store chunked response: {}", e)
 }
 
 /// Handle Ready message from agent - send back ConnectionEstablished with public URL
 async fn handle_ready_message(
+    store: &impl TunnelStore,
     dynamodb_client: &DynamoDbClient,
-    apigw_management: &Option<aws_sdk_apigatewaymanagement::Client>,
+    apigw_management: Option<&aws_sdk_apigatewaymanagement::Client>,
     connection_id: &str,
+    options: &TunnelOptions,
 ) -> Result<(), Error> {
-    // Look up connection metadata from DynamoDB
-    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
-        .map_err(|_| "CONNECTIONS_TABLE_NAME environment variable not set")?;
-
-    let result = dynamodb_client
-        .get_item()
-        .table_name(&table_name)
-        .key("connectionId", AttributeValue::S(connection_id.to_string()))
-        .send()
+    let metadata = store
+        .get_connection(connection_id)
         .await
         .map_err(|e| {
             error!(
-                "Failed to get connection metadata for {}: {}",
+                "Failed to get connection metadata for {}: {:#}",
                 connection_id, e
             );
             format!("Failed to get connection metadata: {}", e)
-        })?;
-
-    let item = result.item.ok_or("Connection not found")?;
-
-    let tunnel_id = item
-        .get("tunnelId")
-        .and_then(|v| v.as_s().ok())
-        .ok_or("Missing tunnelId")?;
+        })?
+        .ok_or("Connection not found")?;
+    if metadata.tunnel_id.is_empty() || metadata.public_url.is_empty() {
+        return Err("Connection has no tunnel".into());
+    }
 
-    let public_url = item
-        .get("publicUrl")
-        .and_then(|v| v.as_s().ok())
-        .ok_or("Missing publicUrl")?;
+    let mut tunnel_id = metadata.tunnel_id;
+    let mut public_url = metadata.public_url;
+    let mut subdomain_url = metadata.subdomain_url;
+    let mut path_based_url = metadata.path_based_url;
+    let created_at = Some(metadata.created_at).filter(|created_at| *created_at > 0);
+    let owner = metadata.owner.as_ref();
+
+    // Remember the agent's options so the forwarding path can apply them. This
+    // happens before the connection takes over a known tunnel ID, and a tunnel
+    // whose access rules weren't saved is never served.
+    let saved_options = TunnelOptions {
+        handoff: None,
+        ..options.clone()
+    };
+    if !saved_options.is_default()
+        && let Err(e) = store
+            .save_tunnel_options(connection_id, &saved_options)
+            .await
+    {
+        if saved_options.restricts_access() {
+            error!(
+                "Failed to save access rules for {}; closing it: {:#}",
+                connection_id, e
+            );
+            if let Some(client) = apigw_management {
+                let message = Message::Error {
+                    request_id: None,
+                    code: ErrorCode::InternalError,
+                    message: "tunnel options could not be saved, try again later".to_string(),
+                };
+                if let Err(e) = send_message_to_connection(client, connection_id, message).await {
+                    error!("Failed to send error to {}: {:#}", connection_id, e);
+                }
+                if let Err(e) = client
+                    .delete_connection()
+                    .connection_id(connection_id)
+                    .send()
+                    .await
+                {
+                    warn!("Failed to close connection {}: {}", connection_id, e);
+                }
+            }
+            return Ok(());
+        }
+        warn!(
+            "Failed to save tunnel options for {}: {:#}",
+            connection_id, e
+        );
+    }
 
-    // Get optional subdomain and path-based URLs
-    let subdomain_url = item
-        .get("subdomainUrl")
-        .and_then(|v| v.as_s().ok())
-        .map(|s| s.to_string());
+    // Take over the tunnel of the agent's expiring connection; if that fails the
+    // agent keeps the tunnel it got at $connect
+    if let Some(handoff) = &options.handoff {
+        match handoff::take_over(dynamodb_client, connection_id, owner, handoff).await {
+            Ok((handed_off, urls)) => {
+                info!(
+                    "Tunnel {} handed off from {} to {}",
+                    handed_off, handoff.connection_id, connection_id
+                );
+                tunnel_id = handed_off;
+                public_url = urls.public_url;
+                subdomain_url = urls.subdomain_url;
+                path_based_url = urls.path_based_url;
+            }
+            Err(e) => warn!(
+                "Refused handoff from {} to {}: {:#}",
+                handoff.connection_id, connection_id, e
+            ),
+        }
+    }
 
-    let path_based_url = item
-        .get("pathBasedUrl")
-        .and_then(|v| v.as_s().ok())
-        .map(|s| s.to_string());
+    // Swap the random ID assigned at $connect for the one the agent asked for
+    if let Some(requested) = &options.tunnel_id
+        && *requested != tunnel_id
+    {
+        match claim_tunnel_id(dynamodb_client, connection_id, requested, owner, options).await {
+            Ok(urls) => {
+                tunnel_id = requested.clone();
+                public_url = urls.public_url;
+                subdomain_url = urls.subdomain_url;
+                path_based_url = urls.path_based_url;
+            }
+            Err(reason) => {
+                warn!(
+                    "Refused tunnel ID {} for {}: {}",
+                    requested, connection_id, reason
+                );
+                if let Some(client) = apigw_management {
+                    let message = Message::Error {
+                        request_id: None,
+                        code: ErrorCode::TunnelIdUnavailable,
+                        message: reason,
+                    };
+                    if let Err(e) = send_message_to_connection(client, connection_id, message).await
+                    {
+                        error!("Failed to send error to {}: {:#}", connection_id, e);
+                    }
+                }
+                return Ok(());
+            }
+        }
+    }
 
     // Send ConnectionEstablished message
     if let Some(client) = apigw_management {
         let message = Message::ConnectionEstablished {
             connection_id: connection_id.to_string(),
-            tunnel_id: tunnel_id.clone(),
-            public_url: public_url.clone(),
+            tunnel_id,
+            public_url,
             subdomain_url,
             path_based_url,
+            info: Some(tunnel_info(created_at)),
         };
 
         let message_json = serde_json::to_string(&message)
@@ -245,65 +501,141 @@ async fn handle_ready_message(
     Ok(())
 }
 
-/// Handle error response from agent
-async fn handle_error_response(
+/// Move the connection to a reserved tunnel ID, or explain why it can't be used
+async fn claim_tunnel_id(
     client: &DynamoDbClient,
-    request_id: &str,
-    code: ErrorCode,
-    message: &str,
-) -> Result<(), Error> {
-    let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
-        .map_err(|_| "PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
+    connection_id: &str,
+    tunnel_id: &str,
+    owner: Option<&String>,
+    options: &TunnelOptions,
+) -> Result<TunnelUrls, String> {
+    if !reservations::is_enabled() {
+        return Err("tunnel ID reservations are not enabled on this server".to_string());
+    }
+    validate_tunnel_id(tunnel_id).map_err(|e| e.to_string())?;
 
-    // Create error response with appropriate status code
-    let status_code = match code {
-        ErrorCode::InvalidRequest => 400,
-        ErrorCode::Timeout => 504,
-        ErrorCode::LocalServiceUnavailable => 503,
-        ErrorCode::InternalError => 502,
+    let unavailable = |e: anyhow::Error| {
+        error!("Failed to claim tunnel ID {}: {:#}", tunnel_id, e);
+        "tunnel ID could not be claimed, try again later".to_string()
     };
+    let owner = owner.map(String::as_str);
 
-    let error_response = HttpResponse {
-        request_id: request_id.to_string(),
-        status_code,
-        headers: [("Content-Type".to_string(), vec!["text/plain".to_string()])]
-            .into_iter()
-            .collect(),
-        body: encode_body(message.as_bytes()),
-        processing_time_ms: 0,
-    };
+    // A live tunnel belongs to whoever is serving it, reserved or not
+    let live = query_tunnel_connections(client, tunnel_id)
+        .await
+        .map_err(unavailable)?;
+    let others = live
+        .iter()
+        .filter(|metadata| metadata.connection_id != connection_id)
+        .map(|metadata| metadata.owner.as_deref());
+    if reservations::held_by_other(others, owner) {
+        return Err(format!("tunnel ID {} is in use by another user", tunnel_id));
+    }
 
-    let response_data = serde_json::to_string(&error_response).map_err(|e| {
-        error!("Failed to serialize error response: {}", e);
-        format!("Failed to serialize error response: {}", e)
-    })?;
+    match reservations::claim(client, tunnel_id, owner, options.reserve).await {
+        Ok(Claim::Granted) => {}
+        Ok(Claim::Denied(reason)) => return Err(reason),
+        Err(e) => return Err(unavailable(e)),
+    }
 
-    client
-        .update_item()
-        .table_name(&table_name)
-        .key("requestId", AttributeValue::S(request_id.to_string()))
-        .update_expression("SET #status = :status, responseData = :data")
-        .expression_attribute_names("#status", "status")
-        .expression_attribute_values(":status", AttributeValue::S("completed".to_string()))
-        .expression_attribute_values(":data", AttributeValue::S(response_data))
-        .send()
+    // The previous connection of a reconnecting agent may not be gone yet;
+    // shared tunnels keep it along with the other agents serving the ID
+    let removed = if options.shared {
+        Vec::new()
+    } else {
+        remove_other_connections(client, tunnel_id, connection_id, owner)
+            .await
+            .map_err(unavailable)?
+    };
+    if !removed.is_empty() {
+        info!(
+            "Tunnel {} taken over by {} from {:?}",
+            tunnel_id, connection_id, removed
+        );
+    }
+
+    reassign_tunnel_id(client, connection_id, tunnel_id)
         .await
-        .map_err(|e| {
-            error!(
-                "Failed to update pending request {} with error: {}",
-                request_id, e
-            );
-            format!("Failed to update pending request: {}", e)
-        })?;
+        .map_err(unavailable)
+}
 
-    debug!("Updated pending request with error: {}", request_id);
+/// Limits and capabilities reported to the agent in ConnectionEstablished
+fn tunnel_info(created_at: Option<i64>) -> TunnelInfo {
+    TunnelInfo {
+        // API Gateway closes WebSocket connections after their maximum lifetime
+        expires_at: created_at.map(|created_at| created_at + MAX_CONNECTION_LIFETIME_SECS),
+        region: std::env::var("AWS_REGION").ok(),
+        max_body_size: Some(MAX_BODY_SIZE_BYTES),
+        capabilities: capabilities(),
+    }
+}
 
-    Ok(())
+/// Optional features enabled on this deployment
+fn capabilities() -> Vec<Capability> {
+    let mut capabilities = vec![
+        Capability::Cors,
+        Capability::MessagePack,
+        Capability::BasicAuth,
+        Capability::IpAccess,
+        Capability::Streaming,
+        Capability::Rewrite,
+        Capability::Compression,
+    ];
+    if websocket::is_passthrough_enabled() {
+        capabilities.push(Capability::WebSocket);
+    }
+    // Alerts are evaluated from the tunnel's statistics, which also hold its analytics
+    if stats::is_stats_enabled() {
+        capabilities.extend([
+            Capability::Stats,
+            Capability::Alerts,
+            Capability::TunnelStats,
+        ]);
+    }
+    // Affinity only applies to tunnels shared under a reserved ID
+    if reservations::is_enabled() {
+        capabilities.extend([Capability::Reservations, Capability::Affinity]);
+    }
+    capabilities
+}
+
+/// Handle error response from agent
+async fn handle_error_response<S: TunnelStore>(
+    store: &S,
+    request_id: &str,
+    code: ErrorCode,
+    message: &str,
+) -> Result<Option<Completion>, Error> {
+    // Create error response with appropriate status code
+    let error_response = build_error_response(request_id, &code, message);
+    handle_http_response(store, error_response).await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use http_tunnel_common::encode_body;
+
+    #[test]
+    fn test_carries_traffic() {
+        let response = HttpResponse::new("req_1".to_string(), 200);
+        assert!(carries_traffic(&Message::HttpResponse(response)));
+        assert!(carries_traffic(&Message::BodyEnd {
+            request_id: "req_1".to_string(),
+            chunks: 2,
+        }));
+        assert!(carries_traffic(&Message::Error {
+            request_id: Some("req_1".to_string()),
+            code: ErrorCode::LocalServiceUnavailable,
+            message: "refused".to_string(),
+        }));
+        // Heartbeats alone don't keep an idle tunnel alive
+        assert!(!carries_traffic(&Message::Ping));
+        assert!(!carries_traffic(&Message::Pong));
+        assert!(!carries_traffic(&Message::Ready {
+            options: Default::default(),
+        }));
+    }
 
     #[test]
     fn test_error_code_to_status_code() {
@@ -315,16 +647,24 @@ mod tests {
         ];
 
         for (error_code, expected_status) in codes {
-            let status = match error_code {
-                ErrorCode::InvalidRequest => 400,
-                ErrorCode::Timeout => 504,
-                ErrorCode::LocalServiceUnavailable => 503,
-                ErrorCode::InternalError => 502,
-            };
-            assert_eq!(status, expected_status);
+            let response = build_error_response("req_123", &error_code, "error");
+            assert_eq!(response.status_code, expected_status);
         }
     }
 
+    #[test]
+    fn test_tunnel_info() {
+        let info = tunnel_info(Some(1_700_000_000));
+        assert_eq!(
+            info.expires_at,
+            Some(1_700_000_000 + MAX_CONNECTION_LIFETIME_SECS)
+        );
+        assert_eq!(info.max_body_size, Some(MAX_BODY_SIZE_BYTES));
+        assert!(info.supports(Capability::Cors));
+
+        assert_eq!(tunnel_info(None).expires_at, None);
+    }
+
     #[test]
     fn test_error_response_format() {
         let error_response = HttpResponse {
@@ -335,6 +675,10 @@ mod tests {
                 .collect(),
             body: encode_body(b"Service error"),
             processing_time_ms: 0,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         assert_eq!(error_response.status_code, 502);
@@ -344,4 +688,20 @@ mod tests {
         );
         assert!(!error_response.body.is_empty());
     }
+
+    #[test]
+    fn test_response_metrics() {
+        let mut response = HttpResponse::new("req_123".to_string(), 404);
+        response.body = encode_body(b"Not found!!!");
+
+        let emf = response_metrics(&response).to_emf("HttpTunnel", 0);
+        assert_eq!(emf["StatusClass"], "4xx");
+        assert_eq!(emf["AgentResponses"], 1.0);
+        assert_eq!(emf["AgentResponseBytes"], 12.0);
+        assert_eq!(emf["RequestId"], "req_123");
+
+        response.chunked = true;
+        let emf = response_metrics(&response).to_emf("HttpTunnel", 0);
+        assert!(emf.get("AgentResponseBytes").is_none());
+    }
 }
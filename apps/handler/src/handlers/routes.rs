@@ -0,0 +1,106 @@
+//! Reserved paths, dispatched before tunnel routing
+//!
+//! Paths whose first segment starts with `_` belong to the Lambda. On the base
+//! domain they're never taken for a tunnel ID: `/_admin` and `/_dashboard` have
+//! their own handlers and anything else is a 404. Within a tunnel, `/_tunnel`
+//! is reserved the same way and only `/_tunnel/status` is served; other paths
+//! such as `/_admin` are forwarded to the agent like any other. Tunnel IDs are
+//! validated to stay out of this namespace (see
+//! `http_tunnel_common::validation::is_reserved_path_segment`).
+
+use http_tunnel_common::validation::is_reserved_path_segment;
+
+use super::{admin, dashboard, status};
+
+/// Reserved paths on the base domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaseRoute {
+    Admin,
+    Dashboard,
+    /// Reserved but not served
+    NotFound,
+}
+
+/// Reserved paths within a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelRoute {
+    Status,
+    /// Reserved but not served
+    NotFound,
+}
+
+/// Match a request on the base domain against the reserved paths
+///
+/// Returns `None` for requests to route to a tunnel.
+pub fn base_route(host: &str, path: &str, base_domain: &str) -> Option<BaseRoute> {
+    if admin::is_admin_request(host, path, base_domain) {
+        return Some(BaseRoute::Admin);
+    }
+    if dashboard::is_dashboard_request(host, path, base_domain) {
+        return Some(BaseRoute::Dashboard);
+    }
+    let host = host.split(':').next().unwrap_or(host);
+    (host == base_domain && is_reserved_path_segment(first_segment(path)))
+        .then_some(BaseRoute::NotFound)
+}
+
+/// Match a tunnel's forwarding path against the reserved paths
+///
+/// Returns `None` for requests to forward to the agent.
+pub fn tunnel_route(forwarding_path: &str) -> Option<TunnelRoute> {
+    if status::is_status_request(forwarding_path) {
+        return Some(TunnelRoute::Status);
+    }
+    (first_segment(forwarding_path) == status::TUNNEL_SEGMENT).then_some(TunnelRoute::NotFound)
+}
+
+fn first_segment(path: &str) -> &str {
+    path.trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOMAIN: &str = "tunnel.example.com";
+
+    #[test]
+    fn test_base_route() {
+        assert_eq!(
+            base_route(DOMAIN, "/_admin/tunnels", DOMAIN),
+            Some(BaseRoute::Admin)
+        );
+        assert_eq!(
+            base_route("tunnel.example.com:443", "/_dashboard", DOMAIN),
+            Some(BaseRoute::Dashboard)
+        );
+        for path in ["/_tunnel/status", "/_anything", "/_"] {
+            assert_eq!(
+                base_route(DOMAIN, path, DOMAIN),
+                Some(BaseRoute::NotFound),
+                "{}",
+                path
+            );
+        }
+        assert_eq!(base_route(DOMAIN, "/abc123def456/_admin", DOMAIN), None);
+        assert_eq!(base_route(DOMAIN, "/", DOMAIN), None);
+        // Tunnel subdomains keep their own paths
+        assert_eq!(
+            base_route("abc123def456.tunnel.example.com", "/_admin", DOMAIN),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tunnel_route() {
+        assert_eq!(tunnel_route("/_tunnel/status"), Some(TunnelRoute::Status));
+        assert_eq!(tunnel_route("/_tunnel"), Some(TunnelRoute::NotFound));
+        assert_eq!(tunnel_route("/_tunnel/other"), Some(TunnelRoute::NotFound));
+        assert_eq!(tunnel_route("/_admin"), None);
+        assert_eq!(tunnel_route("/api/_tunnel/status"), None);
+        assert_eq!(tunnel_route("/"), None);
+    }
+}
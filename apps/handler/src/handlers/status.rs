@@ -0,0 +1,171 @@
+//! StatusHandler - Serves `/_tunnel/status` on every tunnel
+//!
+//! `GET https://{tunnel_id}.{domain}/_tunnel/status` (or
+//! `https://{domain}/{tunnel_id}/_tunnel/status`) is answered by the Lambda
+//! itself and never reaches the agent, so uptime checks can watch a webhook
+//! endpoint without sending it traffic. It returns 200 with the tunnel's agents,
+//! when they connected and last sent a heartbeat, and request counts for the
+//! last hour while an agent is connected, and 503 otherwise.
+//!
+//! The tunnel's IP rules and basic auth credentials apply as they do to
+//! forwarded requests.
+
+use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::STATS_WINDOWS_SECS;
+use http_tunnel_common::models::TunnelStats;
+use serde_json::{Value, json};
+use tracing::{debug, warn};
+
+use super::forwarding::{plain_response, unauthorized_response};
+use super::json_response;
+use crate::SharedClients;
+use crate::store::TunnelStore;
+
+/// First path segment reserved within every tunnel
+pub const TUNNEL_SEGMENT: &str = "_tunnel";
+
+/// Path of the status endpoint within a tunnel
+pub const STATUS_PATH: &str = "/_tunnel/status";
+
+/// Check whether a tunnel's forwarding path is the status endpoint
+pub fn is_status_request(forwarding_path: &str) -> bool {
+    forwarding_path.trim_end_matches('/') == STATUS_PATH
+}
+
+/// Report whether `tunnel_id` is connected
+pub async fn handle_status(
+    request: &ApiGatewayProxyRequest,
+    tunnel_id: &str,
+    source_ip: Option<&str>,
+    clients: &SharedClients,
+) -> ApiGatewayProxyResponse {
+    if !matches!(request.http_method, http::Method::GET | http::Method::HEAD) {
+        return plain_response(405, "Method Not Allowed");
+    }
+
+    // An unknown tunnel and one whose agents have all gone look the same
+    let connections = match clients.connections.lookup(&clients.store, tunnel_id).await {
+        Ok(connections) => connections,
+        Err(e) => {
+            debug!("No connections for tunnel {}: {:#}", tunnel_id, e);
+            Vec::new()
+        }
+    };
+
+    if let Some(connection) = connections.first() {
+        if !connection.options.ip_access.permits(source_ip) {
+            return plain_response(403, "Forbidden");
+        }
+        if let Some(auth) = &connection.options.basic_auth {
+            let authorization = request
+                .headers
+                .get(http::header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok());
+            if !auth.verify(authorization) {
+                return unauthorized_response();
+            }
+        }
+    }
+
+    let last_hour = if connections.is_empty() || !clients.store.keeps_stats() {
+        None
+    } else {
+        match clients
+            .store
+            .tunnel_stats(tunnel_id, &[STATS_WINDOWS_SECS[1]])
+            .await
+        {
+            Ok(windows) => windows.into_iter().next(),
+            Err(e) => {
+                warn!("Failed to load stats for tunnel {}: {:#}", tunnel_id, e);
+                None
+            }
+        }
+    };
+
+    let status_code = if connections.is_empty() { 503 } else { 200 };
+    json_response(
+        status_code,
+        status_json(tunnel_id, &connections, last_hour.as_ref()),
+    )
+}
+
+/// Describe a tunnel's connections and recent traffic
+///
+/// Tunnel options aren't included: they can carry secrets such as the basic
+/// auth password hash.
+fn status_json(
+    tunnel_id: &str,
+    connections: &[ConnectionMetadata],
+    last_hour: Option<&TunnelStats>,
+) -> Value {
+    json!({
+        "tunnel_id": tunnel_id,
+        "connected": !connections.is_empty(),
+        "agents": connections.len(),
+        "connected_since": connections.iter().map(|c| c.created_at).min(),
+        "last_heartbeat_at": connections.iter().filter_map(|c| c.last_heartbeat_at).max(),
+        "last_hour": last_hour.map(|stats| json!({
+            "requests": stats.requests,
+            "status_classes": stats.status_classes,
+            "p50_ms": stats.p50_ms,
+            "p95_ms": stats.p95_ms,
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_is_status_request() {
+        assert!(is_status_request("/_tunnel/status"));
+        assert!(is_status_request("/_tunnel/status/"));
+        assert!(!is_status_request("/_tunnel/status/more"));
+        assert!(!is_status_request("/api/_tunnel/status"));
+        assert!(!is_status_request("/"));
+    }
+
+    #[test]
+    fn test_status_json() {
+        let mut older = ConnectionMetadata::new(
+            "conn_1".to_string(),
+            "abc123def456".to_string(),
+            "https://abc123def456.tunnel.example.com".to_string(),
+            1_700_000_000,
+            1_700_007_200,
+        );
+        older.last_heartbeat_at = Some(1_700_000_300);
+        let mut newer = older.clone();
+        newer.connection_id = "conn_2".to_string();
+        newer.created_at = 1_700_000_100;
+        newer.last_heartbeat_at = Some(1_700_000_400);
+
+        let stats = TunnelStats {
+            tunnel_id: "abc123def456".to_string(),
+            window_secs: 3600,
+            requests: 12,
+            status_classes: BTreeMap::from([("2xx".to_string(), 12)]),
+            p50_ms: Some(40),
+            p95_ms: Some(90),
+            p99_ms: None,
+        };
+        let status = status_json("abc123def456", &[older, newer], Some(&stats));
+        assert_eq!(status["connected"], true);
+        assert_eq!(status["agents"], 2);
+        assert_eq!(status["connected_since"], 1_700_000_000);
+        assert_eq!(status["last_heartbeat_at"], 1_700_000_400);
+        assert_eq!(status["last_hour"]["requests"], 12);
+        assert_eq!(status["last_hour"]["status_classes"]["2xx"], 12);
+        assert!(status.get("options").is_none());
+
+        let status = status_json("abc123def456", &[], None);
+        assert_eq!(status["connected"], false);
+        assert_eq!(status["agents"], 0);
+        assert!(status["last_heartbeat_at"].is_null());
+        assert!(status["last_hour"].is_null());
+    }
+}
@@ -1,8 +1,9 @@
 //! DynamoDB Stream Handler - Processes stream events for event-driven responses
 //!
 //! This module handles DynamoDB Stream events from the pending_requests table.
-//! When a request status changes to "completed", it publishes an event to EventBridge
-//! to notify the waiting handler.
+//! When a request status changes to "completed", it notifies the waiting handler's
+//! reply queue (when the request names one) and publishes an event to EventBridge.
+//! Failed requests drop their tunnel from this container's connection cache.
 
 use aws_lambda_events::event::dynamodb::Event as DynamoDbStreamEvent;
 use aws_lambda_events::event::dynamodb::EventRecord;
@@ -10,7 +11,7 @@ use lambda_runtime::{Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info};
 
-use crate::SharedClients;
+use crate::{SharedClients, reply};
 
 /// Minimal struct to deserialize pending request from DynamoDB Stream
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -20,6 +21,10 @@ struct StreamPendingRequest {
     status: String,
     #[serde(rename = "responseData")]
     response_data: Option<String>,
+    #[serde(rename = "replyQueue", default)]
+    reply_queue: Option<String>,
+    #[serde(rename = "tunnelId", default)]
+    tunnel_id: Option<String>,
 }
 
 /// Handler for DynamoDB Stream events
@@ -39,6 +44,19 @@ pub async fn handle_stream(
             Ok(pending_req) if pending_req.status == "completed" => {
                 // Check if this is a new completion (not already completed)
                 if is_status_change_to_completed(record) {
+                    // Wake the waiting forwarding handler first; it is on the
+                    // critical path of the public request
+                    if let Some(queue_url) = &pending_req.reply_queue
+                        && let Err(e) =
+                            reply::notify_reply(&clients.sqs, queue_url, &pending_req.request_id)
+                                .await
+                    {
+                        error!(
+                            "Failed to push response for {}: {:#}",
+                            pending_req.request_id, e
+                        );
+                    }
+
                     match publish_response_event(clients, &event_bus_name, &pending_req).await {
                         Ok(()) => {
                             info!(
@@ -58,7 +76,14 @@ pub async fn handle_stream(
                     notifications_skipped += 1;
                 }
             }
-            Ok(_) => {
+            Ok(pending_req) => {
+                // Requests fail when their agent can't be reached, which may
+                // have changed the tunnel's connections
+                if pending_req.status == "failed"
+                    && let Some(tunnel_id) = &pending_req.tunnel_id
+                {
+                    clients.connections.invalidate_tunnel(tunnel_id);
+                }
                 // Status is not completed, skip
                 notifications_skipped += 1;
             }
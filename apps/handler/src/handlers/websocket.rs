@@ -0,0 +1,614 @@
+//! WebSocketHandler - WebSocket passthrough for public clients
+//!
+//! The HTTP API can't upgrade connections, so public clients open their
+//! WebSocket on a separate public WebSocket API (`PUBLIC_WEBSOCKET_API_ENDPOINT`)
+//! with the tunnel and path in the query string, e.g.
+//! `wss://…/?tunnel={tunnel_id}&path=/ws`. Upgrade requests reaching the HTTP
+//! API are answered with `426 Upgrade Required` pointing at that URL.
+//!
+//! Each client connection is a session, stored in `WS_SESSIONS_TABLE_NAME` and
+//! announced to the agent with `WsOpen`. Messages are relayed in both directions
+//! as `WsFrame`, and either side ends the session with `WsClose`.
+
+use anyhow::{Context, Result, anyhow};
+use aws_lambda_events::apigw::{ApiGatewayProxyResponse, ApiGatewayWebsocketProxyRequest};
+use aws_sdk_apigatewaymanagement::primitives::Blob;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use base64::Engine;
+use http_tunnel_common::constants::CONNECTION_TTL_SECS;
+use http_tunnel_common::protocol::Message;
+use http_tunnel_common::utils::calculate_ttl;
+use lambda_runtime::{Error, LambdaEvent};
+use std::collections::HashMap;
+use tracing::{debug, error, info, warn};
+
+use super::forwarding::unauthorized_response;
+use super::response::WebSocketMessageEvent;
+use crate::aws_util::retry;
+use crate::{
+    DeliveryFailure, SharedClients, honeypot, lookup_connection_metadata_by_tunnel_id,
+    remove_stale_connection, send_message_to_connection,
+};
+
+/// Handshake headers that belong to the client's connection, not the local one
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "host",
+    "connection",
+    "upgrade",
+    "content-length",
+    "sec-websocket-key",
+    "sec-websocket-version",
+    "sec-websocket-extensions",
+];
+
+/// A public client's WebSocket connection to a tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WsSession {
+    /// Connection ID of the client on the public WebSocket API
+    pub session_id: String,
+    pub tunnel_id: String,
+    /// Connection ID of the agent serving the tunnel
+    pub agent_connection_id: String,
+}
+
+/// Whether the public WebSocket API and session table are deployed
+pub fn is_passthrough_enabled() -> bool {
+    std::env::var("PUBLIC_WEBSOCKET_API_ENDPOINT").is_ok()
+        && std::env::var("WS_SESSIONS_TABLE_NAME").is_ok()
+}
+
+fn sessions_table() -> Result<String> {
+    std::env::var("WS_SESSIONS_TABLE_NAME")
+        .context("WS_SESSIONS_TABLE_NAME environment variable not set")
+}
+
+/// URL public clients use to open a WebSocket to `path` on the tunnel
+///
+/// None when passthrough isn't deployed.
+pub fn websocket_url(tunnel_id: &str, path: &str) -> Option<String> {
+    if !is_passthrough_enabled() {
+        return None;
+    }
+    let endpoint = std::env::var("PUBLIC_WEBSOCKET_API_ENDPOINT").ok()?;
+    Some(format!(
+        "{}?tunnel={}&path={}",
+        endpoint,
+        encode_query_component(tunnel_id),
+        encode_query_component(path)
+    ))
+}
+
+fn encode_query_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Whether a public request asks to upgrade to a WebSocket
+pub fn is_upgrade_request(headers: &http::HeaderMap) -> bool {
+    headers
+        .get("upgrade")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// Answer an upgrade request that reached the HTTP API
+pub fn upgrade_response(tunnel_id: &str, path: &str) -> ApiGatewayProxyResponse {
+    use aws_lambda_events::encodings::Body;
+    use http::header::{HeaderName, HeaderValue};
+
+    let Some(url) = websocket_url(tunnel_id, path) else {
+        return text_response(501, "WebSocket passthrough is not enabled");
+    };
+
+    let mut response = text_response(426, "Connect to the URL in x-tunnel-websocket-url");
+    if let Ok(value) = HeaderValue::from_str(&url) {
+        response
+            .headers
+            .insert(HeaderName::from_static("x-tunnel-websocket-url"), value);
+    }
+    response.body = Some(Body::Text(format!(
+        "WebSocket connections must use {}\n",
+        url
+    )));
+    response
+}
+
+fn text_response(status_code: i64, message: &'static str) -> ApiGatewayProxyResponse {
+    use aws_lambda_events::encodings::Body;
+    use http::header::{HeaderName, HeaderValue};
+
+    ApiGatewayProxyResponse {
+        status_code,
+        headers: [(
+            HeaderName::from_static("content-type"),
+            HeaderValue::from_static("text/plain"),
+        )]
+        .into_iter()
+        .collect(),
+        multi_value_headers: Default::default(),
+        body: Some(Body::Text(message.to_string())),
+        is_base64_encoded: false,
+    }
+}
+
+/// Handler for `$connect` on the public WebSocket API
+pub async fn handle_ws_connect(
+    event: LambdaEvent<ApiGatewayWebsocketProxyRequest>,
+    clients: &SharedClients,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    let request = event.payload;
+    let session_id = request
+        .request_context
+        .connection_id
+        .clone()
+        .ok_or("Missing connection ID")?;
+
+    let Some(tunnel_id) = request.query_string_parameters.first("tunnel") else {
+        return Ok(text_response(400, "Missing tunnel parameter"));
+    };
+    let path = request.query_string_parameters.first("path").unwrap_or("/");
+    if !path.starts_with('/') {
+        return Ok(text_response(400, "Invalid path parameter"));
+    }
+
+    // Clients banned by a honeypot path are kept off WebSockets too
+    let source_ip = request.request_context.identity.source_ip.as_deref();
+    if honeypot::is_honeypot_enabled()
+        && let Some(ip) = source_ip
+    {
+        match honeypot::is_ip_banned(&clients.dynamodb, ip).await {
+            Ok(true) => {
+                debug!("Rejecting WebSocket client from banned IP {}", ip);
+                return Ok(text_response(403, "Forbidden"));
+            }
+            Ok(false) => {}
+            // Fail open, as for HTTP requests
+            Err(e) => warn!("Failed to check IP denylist for {}: {:#}", ip, e),
+        }
+    }
+
+    let connection =
+        match lookup_connection_metadata_by_tunnel_id(&clients.dynamodb, tunnel_id).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                debug!("No tunnel for WebSocket client {}: {:#}", session_id, e);
+                return Ok(text_response(404, "Tunnel not found"));
+            }
+        };
+
+    if !connection.options.ip_access.permits(source_ip) {
+        debug!(
+            "Rejected WebSocket client {:?} to tunnel {} by IP rules",
+            source_ip, tunnel_id
+        );
+        return Ok(text_response(403, "Forbidden"));
+    }
+
+    // Password-protected tunnels need their credentials on the handshake too
+    let mut headers = request.headers.clone();
+    if let Some(auth) = &connection.options.basic_auth {
+        let authorization = headers
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if !auth.verify(authorization) {
+            debug!(
+                "Rejected WebSocket client {} to tunnel {} without credentials",
+                session_id, tunnel_id
+            );
+            return Ok(unauthorized_response());
+        }
+        // The credentials are for the tunnel, not the local service
+        headers.remove(http::header::AUTHORIZATION);
+    }
+
+    let apigw_management = clients
+        .apigw_management()
+        .ok_or("API Gateway Management client not initialized")?;
+
+    let session = WsSession {
+        session_id: session_id.clone(),
+        tunnel_id: tunnel_id.to_string(),
+        agent_connection_id: connection.connection_id,
+    };
+    save_session(&clients.dynamodb, &session)
+        .await
+        .map_err(|e| {
+            error!("Failed to save WebSocket session {}: {:#}", session_id, e);
+            "Service temporarily unavailable".to_string()
+        })?;
+
+    let open = Message::WsOpen {
+        session_id: session_id.clone(),
+        path: path.to_string(),
+        headers: forwarded_headers(&headers),
+    };
+    if let Err(e) =
+        send_message_to_connection(apigw_management, &session.agent_connection_id, open).await
+    {
+        let failure = DeliveryFailure::classify(&e);
+        warn!(
+            "Failed to open WebSocket session {} on tunnel {} ({:?}): {:#}",
+            session_id, tunnel_id, failure, e
+        );
+        if let Err(e) = delete_session(&clients.dynamodb, &session_id).await {
+            warn!("Failed to delete session {}: {:#}", session_id, e);
+        }
+        return Ok(text_response(502, failure.message()));
+    }
+
+    info!(
+        "WebSocket session {} opened on tunnel {} ({})",
+        session_id, tunnel_id, path
+    );
+    Ok(text_response(200, "Connected"))
+}
+
+/// Handler for `$disconnect` on the public WebSocket API
+pub async fn handle_ws_disconnect(
+    event: LambdaEvent<ApiGatewayWebsocketProxyRequest>,
+    clients: &SharedClients,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    let session_id = event
+        .payload
+        .request_context
+        .connection_id
+        .ok_or("Missing connection ID")?;
+
+    // Gone already if the agent closed the session
+    match delete_session(&clients.dynamodb, &session_id).await {
+        Ok(Some(session)) => {
+            info!("WebSocket session {} closed by client", session_id);
+            notify_agent_closed(clients, &session, Some(1000), None).await;
+        }
+        Ok(None) => debug!("WebSocket session {} already closed", session_id),
+        Err(e) => warn!("Failed to delete session {}: {:#}", session_id, e),
+    }
+
+    Ok(text_response(200, "Disconnected"))
+}
+
+/// Handler for `$default` on the public WebSocket API (messages from the client)
+pub async fn handle_ws_message(
+    event: LambdaEvent<WebSocketMessageEvent>,
+    clients: &SharedClients,
+) -> Result<ApiGatewayProxyResponse, Error> {
+    let session_id = event.payload.request_context.connection_id;
+    let data = event.payload.body.unwrap_or_default();
+    let binary = event.payload.is_base64_encoded.unwrap_or(false);
+
+    let session = get_session(&clients.dynamodb, &session_id)
+        .await
+        .map_err(|e| {
+            error!("Failed to look up session {}: {:#}", session_id, e);
+            "Service temporarily unavailable".to_string()
+        })?;
+    let Some(session) = session else {
+        warn!("Message from unknown WebSocket session {}", session_id);
+        close_client_connection(clients, &session_id).await;
+        return Ok(text_response(200, "Closed"));
+    };
+
+    let apigw_management = clients
+        .apigw_management()
+        .ok_or("API Gateway Management client not initialized")?;
+    let frame = Message::WsFrame {
+        session_id: session_id.clone(),
+        data,
+        binary,
+    };
+    if let Err(e) =
+        send_message_to_connection(apigw_management, &session.agent_connection_id, frame).await
+    {
+        let failure = DeliveryFailure::classify(&e);
+        warn!(
+            "Failed to relay frame for session {} ({:?}): {:#}",
+            session_id, failure, e
+        );
+        if failure == DeliveryFailure::Gone {
+            // The agent is gone: end the session so the client can reconnect
+            clients
+                .connections
+                .invalidate_connection(&session.agent_connection_id);
+            remove_stale_connection(&clients.store, &session.agent_connection_id).await;
+            if let Err(e) = delete_session(&clients.dynamodb, &session_id).await {
+                warn!("Failed to delete session {}: {:#}", session_id, e);
+            }
+            close_client_connection(clients, &session_id).await;
+        }
+    }
+
+    Ok(text_response(200, "OK"))
+}
+
+/// Deliver a frame from the agent to the public client
+pub(crate) async fn relay_to_client(
+    clients: &SharedClients,
+    agent_connection_id: &str,
+    session_id: &str,
+    data: &str,
+    binary: bool,
+) -> Result<()> {
+    let Some(session) = get_session(&clients.dynamodb, session_id).await? else {
+        debug!("Dropping frame for closed session {}", session_id);
+        return Ok(());
+    };
+    // Only the agent serving the tunnel may write to its clients
+    if session.agent_connection_id != agent_connection_id {
+        return Err(anyhow!(
+            "Connection {} does not own session {}",
+            agent_connection_id,
+            session_id
+        ));
+    }
+
+    let payload = if binary {
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .context("Invalid Base64 in binary frame")?
+    } else {
+        data.as_bytes().to_vec()
+    };
+    let public = clients
+        .public_apigw_management()
+        .ok_or_else(|| anyhow!("Public WebSocket API endpoint not configured"))?;
+
+    if let Err(e) = public
+        .post_to_connection()
+        .connection_id(session_id)
+        .data(Blob::new(payload))
+        .send()
+        .await
+    {
+        let e = anyhow::Error::new(e);
+        if DeliveryFailure::classify(&e) == DeliveryFailure::Gone {
+            // The client left without a $disconnect reaching us yet
+            delete_session(&clients.dynamodb, session_id).await?;
+            notify_agent_closed(clients, &session, Some(1001), None).await;
+            return Ok(());
+        }
+        return Err(e.context("Failed to deliver frame to WebSocket client"));
+    }
+
+    Ok(())
+}
+
+/// End a session at the agent's request (the local service closed its WebSocket)
+pub(crate) async fn close_client(
+    clients: &SharedClients,
+    agent_connection_id: &str,
+    session_id: &str,
+) -> Result<()> {
+    match get_session(&clients.dynamodb, session_id).await? {
+        Some(session) if session.agent_connection_id == agent_connection_id => {}
+        Some(_) => {
+            return Err(anyhow!(
+                "Connection {} does not own session {}",
+                agent_connection_id,
+                session_id
+            ));
+        }
+        None => return Ok(()),
+    }
+
+    delete_session(&clients.dynamodb, session_id).await?;
+    close_client_connection(clients, session_id).await;
+    info!("WebSocket session {} closed by local service", session_id);
+    Ok(())
+}
+
+async fn notify_agent_closed(
+    clients: &SharedClients,
+    session: &WsSession,
+    code: Option<u16>,
+    reason: Option<String>,
+) {
+    let Some(apigw_management) = clients.apigw_management() else {
+        return;
+    };
+    let close = Message::WsClose {
+        session_id: session.session_id.clone(),
+        code,
+        reason,
+    };
+    if let Err(e) =
+        send_message_to_connection(apigw_management, &session.agent_connection_id, close).await
+    {
+        debug!(
+            "Failed to tell agent about closed session {}: {:#}",
+            session.session_id, e
+        );
+    }
+}
+
+/// All open sessions, for the scheduled cleanup to check against live agents
+pub(crate) async fn list_sessions(client: &DynamoDbClient) -> Result<Vec<WsSession>> {
+    let table_name = sessions_table()?;
+    let mut sessions = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let result = client
+            .scan()
+            .table_name(&table_name)
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await
+            .context("Failed to scan WebSocket sessions")?;
+        sessions.extend(
+            result
+                .items
+                .unwrap_or_default()
+                .iter()
+                .filter_map(session_from_item),
+        );
+        exclusive_start_key = result.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+    Ok(sessions)
+}
+
+/// End a session whose agent connection no longer exists
+pub(crate) async fn close_orphaned_session(
+    clients: &SharedClients,
+    session: &WsSession,
+) -> Result<()> {
+    delete_session(&clients.dynamodb, &session.session_id).await?;
+    close_client_connection(clients, &session.session_id).await;
+    info!(
+        "WebSocket session {} closed: connection {} is gone",
+        session.session_id, session.agent_connection_id
+    );
+    Ok(())
+}
+
+async fn close_client_connection(clients: &SharedClients, session_id: &str) {
+    let Some(public) = clients.public_apigw_management() else {
+        return;
+    };
+    if let Err(e) = public
+        .delete_connection()
+        .connection_id(session_id)
+        .send()
+        .await
+    {
+        debug!("Failed to close client connection {}: {}", session_id, e);
+    }
+}
+
+/// Client handshake headers passed on to the local service
+fn forwarded_headers(headers: &http::HeaderMap) -> HashMap<String, Vec<String>> {
+    let mut forwarded: HashMap<String, Vec<String>> = HashMap::new();
+    for (name, value) in headers {
+        if HOP_BY_HOP_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        if let Ok(value) = value.to_str() {
+            forwarded
+                .entry(name.as_str().to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+    }
+    forwarded
+}
+
+async fn save_session(client: &DynamoDbClient, session: &WsSession) -> Result<()> {
+    let request = client
+        .put_item()
+        .table_name(sessions_table()?)
+        .item("sessionId", AttributeValue::S(session.session_id.clone()))
+        .item("tunnelId", AttributeValue::S(session.tunnel_id.clone()))
+        .item(
+            "agentConnectionId",
+            AttributeValue::S(session.agent_connection_id.clone()),
+        )
+        .item(
+            "ttl",
+            AttributeValue::N(calculate_ttl(CONNECTION_TTL_SECS).to_string()),
+        );
+    retry("PutItem", || request.clone().send())
+        .await
+        .context("Failed to save WebSocket session")?;
+    Ok(())
+}
+
+async fn get_session(client: &DynamoDbClient, session_id: &str) -> Result<Option<WsSession>> {
+    let request = client
+        .get_item()
+        .table_name(sessions_table()?)
+        .key("sessionId", AttributeValue::S(session_id.to_string()))
+        // Frames can follow $connect within milliseconds
+        .consistent_read(true);
+    let result = retry("GetItem", || request.clone().send())
+        .await
+        .context("Failed to get WebSocket session")?;
+    Ok(result.item.as_ref().and_then(session_from_item))
+}
+
+/// Delete a session, returning it if it existed
+async fn delete_session(client: &DynamoDbClient, session_id: &str) -> Result<Option<WsSession>> {
+    let request = client
+        .delete_item()
+        .table_name(sessions_table()?)
+        .key("sessionId", AttributeValue::S(session_id.to_string()))
+        .return_values(ReturnValue::AllOld);
+    let result = retry("DeleteItem", || request.clone().send())
+        .await
+        .context("Failed to delete WebSocket session")?;
+    Ok(result.attributes.as_ref().and_then(session_from_item))
+}
+
+fn session_from_item(item: &HashMap<String, AttributeValue>) -> Option<WsSession> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    Some(WsSession {
+        session_id: string("sessionId")?,
+        tunnel_id: string("tunnelId")?,
+        agent_connection_id: string("agentConnectionId")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_query_component() {
+        assert_eq!(encode_query_component("/ws"), "/ws");
+        assert_eq!(
+            encode_query_component("/chat?room=a b&x=1"),
+            "/chat%3Froom%3Da%20b%26x%3D1"
+        );
+    }
+
+    #[test]
+    fn test_is_upgrade_request() {
+        let mut headers = http::HeaderMap::new();
+        assert!(!is_upgrade_request(&headers));
+        headers.insert("upgrade", "WebSocket".parse().unwrap());
+        assert!(is_upgrade_request(&headers));
+    }
+
+    #[test]
+    fn test_forwarded_headers_drop_handshake() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert("host", "abc.execute-api.amazonaws.com".parse().unwrap());
+        headers.insert("sec-websocket-key", "dGhlIHNhbXBsZQ==".parse().unwrap());
+        headers.insert("sec-websocket-protocol", "chat".parse().unwrap());
+        headers.insert("cookie", "session=1".parse().unwrap());
+
+        let forwarded = forwarded_headers(&headers);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded["sec-websocket-protocol"], vec!["chat"]);
+        assert_eq!(forwarded["cookie"], vec!["session=1"]);
+    }
+
+    #[test]
+    fn test_session_from_item() {
+        let item = HashMap::from([
+            ("sessionId".to_string(), AttributeValue::S("c1".to_string())),
+            ("tunnelId".to_string(), AttributeValue::S("t1".to_string())),
+            (
+                "agentConnectionId".to_string(),
+                AttributeValue::S("a1".to_string()),
+            ),
+        ]);
+        assert_eq!(
+            session_from_item(&item),
+            Some(WsSession {
+                session_id: "c1".to_string(),
+                tunnel_id: "t1".to_string(),
+                agent_connection_id: "a1".to_string(),
+            })
+        );
+        assert_eq!(session_from_item(&HashMap::new()), None);
+    }
+}
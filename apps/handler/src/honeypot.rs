@@ -0,0 +1,141 @@
+//! Honeypot trap paths and the shared IP denylist
+//!
+//! Paths listed in `HONEYPOT_PATHS` (e.g. `/.env`, `/phpmyadmin`) are never
+//! legitimately requested through a tunnel, so any client hitting one is treated
+//! as a scanner: its source IP is written to the denylist table and every later
+//! request from it is rejected, on every tunnel, until the entry expires.
+//!
+//! Disabled unless both `HONEYPOT_PATHS` and `IP_DENYLIST_TABLE_NAME` are set.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::constants::HONEYPOT_BAN_SECS;
+use http_tunnel_common::utils::{calculate_ttl, current_timestamp_secs};
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Trap paths configured for this deployment
+pub fn trap_paths() -> &'static [String] {
+    static TRAP_PATHS: OnceLock<Vec<String>> = OnceLock::new();
+    TRAP_PATHS.get_or_init(|| {
+        std::env::var("HONEYPOT_PATHS")
+            .map(|paths| parse_trap_paths(&paths))
+            .unwrap_or_default()
+    })
+}
+
+/// Check if the honeypot is enabled (trap paths and denylist table configured)
+pub fn is_honeypot_enabled() -> bool {
+    !trap_paths().is_empty() && std::env::var("IP_DENYLIST_TABLE_NAME").is_ok()
+}
+
+/// Parse a comma-separated list of trap paths, normalizing each to a leading `/`
+pub fn parse_trap_paths(paths: &str) -> Vec<String> {
+    paths
+        .split(',')
+        .map(|path| path.trim().trim_end_matches('/'))
+        .filter(|path| !path.is_empty())
+        .map(|path| {
+            let path = path.to_ascii_lowercase();
+            if path.starts_with('/') {
+                path
+            } else {
+                format!("/{}", path)
+            }
+        })
+        .collect()
+}
+
+/// Check whether `path` hits a trap (exact match or anything beneath it, ignoring
+/// case and query string)
+pub fn is_trap_path(path: &str, traps: &[String]) -> bool {
+    let path = path.split('?').next().unwrap_or(path).to_ascii_lowercase();
+    traps.iter().any(|trap| {
+        path.strip_prefix(trap.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/// Check whether an IP is on the denylist
+pub async fn is_ip_banned(client: &DynamoDbClient, ip: &str) -> Result<bool> {
+    let table_name = std::env::var("IP_DENYLIST_TABLE_NAME")
+        .context("IP_DENYLIST_TABLE_NAME environment variable not set")?;
+
+    let result = client
+        .get_item()
+        .table_name(&table_name)
+        .key("ip", AttributeValue::S(ip.to_string()))
+        .projection_expression("#ttl")
+        .expression_attribute_names("#ttl", "ttl")
+        .send()
+        .await
+        .context("Failed to look up IP denylist")?;
+
+    // TTL deletion lags behind expiry, so check it ourselves
+    let expires_at = result
+        .item
+        .as_ref()
+        .and_then(|item| item.get("ttl"))
+        .and_then(|ttl| ttl.as_n().ok())
+        .and_then(|ttl| ttl.parse::<i64>().ok());
+
+    Ok(expires_at.is_some_and(|expires_at| expires_at > current_timestamp_secs()))
+}
+
+/// Add an IP to the denylist for [`HONEYPOT_BAN_SECS`]
+pub async fn ban_ip(client: &DynamoDbClient, ip: &str, tunnel_id: &str, path: &str) -> Result<()> {
+    let table_name = std::env::var("IP_DENYLIST_TABLE_NAME")
+        .context("IP_DENYLIST_TABLE_NAME environment variable not set")?;
+
+    client
+        .put_item()
+        .table_name(&table_name)
+        .item("ip", AttributeValue::S(ip.to_string()))
+        .item("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .item("path", AttributeValue::S(path.to_string()))
+        .item(
+            "bannedAt",
+            AttributeValue::N(current_timestamp_secs().to_string()),
+        )
+        .item(
+            "ttl",
+            AttributeValue::N(calculate_ttl(HONEYPOT_BAN_SECS).to_string()),
+        )
+        .send()
+        .await
+        .context("Failed to add IP to denylist")?;
+
+    warn!(
+        "Banned {} for {}s after it requested trap path {} on tunnel {}",
+        ip, HONEYPOT_BAN_SECS, path, tunnel_id
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trap_paths() {
+        assert_eq!(
+            parse_trap_paths("/.env, phpmyadmin/ ,,/WP-Login.php"),
+            vec!["/.env", "/phpmyadmin", "/wp-login.php"]
+        );
+        assert!(parse_trap_paths("").is_empty());
+    }
+
+    #[test]
+    fn test_is_trap_path() {
+        let traps = parse_trap_paths("/.env,/phpmyadmin");
+        assert!(is_trap_path("/.env", &traps));
+        assert!(is_trap_path("/.ENV?x=1", &traps));
+        assert!(is_trap_path("/phpmyadmin/index.php", &traps));
+        assert!(!is_trap_path("/.environment", &traps));
+        assert!(!is_trap_path("/api/.env", &traps));
+        assert!(!is_trap_path("/", &traps));
+        assert!(!is_trap_path("/.env", &[]));
+    }
+}
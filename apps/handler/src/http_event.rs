@@ -0,0 +1,320 @@
+//! Public HTTP requests, in either API Gateway payload format
+//!
+//! REST APIs and HTTP APIs with payload format 1.0 send `ApiGatewayProxyRequest`
+//! events. HTTP APIs with payload format 2.0 and Lambda Function URLs send
+//! `ApiGatewayV2httpRequest` events instead: the method and source IP move under
+//! `requestContext.http`, cookies move out of the headers into a `cookies` array,
+//! and the query string is also given raw. Parsing those as v1 events loses most
+//! of that, so [`PublicRequest::from_payload`] reads each format natively and
+//! maps it onto the v1 shape the handlers work with, keeping the raw query string
+//! alongside.
+//!
+//! Responses go back in the format of the request: format 1.0 takes repeated
+//! headers in `multiValueHeaders`, format 2.0 takes comma-separated `headers`
+//! and Set-Cookie values in a `cookies` array (see [`PayloadFormat::response`]).
+
+use aws_lambda_events::apigw::{
+    ApiGatewayProxyRequest, ApiGatewayProxyRequestContext, ApiGatewayProxyResponse,
+    ApiGatewayRequestIdentity, ApiGatewayV2httpRequest, ApiGatewayV2httpResponse,
+};
+use http::header::{COOKIE, SET_COOKIE};
+use http::{HeaderMap, HeaderValue};
+use serde_json::Value;
+
+/// API Gateway payload format of an HTTP event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadFormat {
+    /// REST APIs, and HTTP APIs with payload format 1.0
+    #[default]
+    V1,
+    /// HTTP APIs with payload format 2.0, and Function URLs
+    V2,
+}
+
+impl PayloadFormat {
+    /// Encode a response in this format
+    pub fn response(self, response: ApiGatewayProxyResponse) -> serde_json::Result<Value> {
+        match self {
+            Self::V1 => serde_json::to_value(response),
+            Self::V2 => serde_json::to_value(v2_response(response)),
+        }
+    }
+}
+
+/// A public HTTP request to a tunnel
+#[derive(Debug, Clone, Default)]
+pub struct PublicRequest {
+    /// The request, in the payload format 1.0 shape
+    pub request: ApiGatewayProxyRequest,
+    /// Query string as the client sent it, without the `?` (format 2.0 only)
+    pub raw_query: Option<String>,
+    /// Format the event came in, and the response must go back in
+    pub format: PayloadFormat,
+}
+
+impl PublicRequest {
+    /// Parse an HTTP event in whichever payload format it came in
+    pub fn from_payload(payload: Value) -> Result<Self, serde_json::Error> {
+        if is_v2_payload(&payload) {
+            Ok(Self::from_v2(serde_json::from_value(payload)?))
+        } else {
+            Ok(serde_json::from_value::<ApiGatewayProxyRequest>(payload)?.into())
+        }
+    }
+
+    /// Map a payload format 2.0 event onto the 1.0 shape
+    pub fn from_v2(event: ApiGatewayV2httpRequest) -> Self {
+        let mut headers = event.headers;
+        // Format 2.0 takes cookies out of the headers
+        if let Some(cookies) = event.cookies.filter(|cookies| !cookies.is_empty())
+            && let Ok(value) = HeaderValue::from_str(&cookies.join("; "))
+        {
+            headers.insert(COOKIE, value);
+        }
+
+        let context = event.request_context;
+        let http = context.http;
+        let request = ApiGatewayProxyRequest {
+            resource: event.route_key,
+            path: event.raw_path.or_else(|| http.path.clone()),
+            http_method: http.method.clone(),
+            headers,
+            query_string_parameters: event.query_string_parameters,
+            path_parameters: event.path_parameters,
+            stage_variables: event.stage_variables,
+            request_context: ApiGatewayProxyRequestContext {
+                account_id: context.account_id,
+                stage: context.stage,
+                domain_name: context.domain_name,
+                domain_prefix: context.domain_prefix,
+                request_id: context.request_id,
+                protocol: http.protocol,
+                identity: ApiGatewayRequestIdentity {
+                    source_ip: http.source_ip,
+                    user_agent: http.user_agent,
+                    ..Default::default()
+                },
+                path: http.path,
+                http_method: http.method,
+                request_time: context.time,
+                request_time_epoch: context.time_epoch,
+                apiid: context.apiid,
+                ..Default::default()
+            },
+            body: event.body,
+            is_base64_encoded: event.is_base64_encoded,
+            ..Default::default()
+        };
+        Self {
+            request,
+            raw_query: event.raw_query_string,
+            format: PayloadFormat::V2,
+        }
+    }
+}
+
+impl From<ApiGatewayProxyRequest> for PublicRequest {
+    fn from(request: ApiGatewayProxyRequest) -> Self {
+        Self {
+            request,
+            raw_query: None,
+            format: PayloadFormat::V1,
+        }
+    }
+}
+
+/// Map a response onto the payload format 2.0 shape
+fn v2_response(response: ApiGatewayProxyResponse) -> ApiGatewayV2httpResponse {
+    // Every value of each header, as API Gateway would merge the two maps
+    let mut all = response.multi_value_headers;
+    for (name, value) in &response.headers {
+        if !all.contains_key(name) {
+            all.insert(name.clone(), value.clone());
+        }
+    }
+
+    let mut headers = HeaderMap::new();
+    let mut cookies = Vec::new();
+    for name in all.keys() {
+        let values = all.get_all(name).iter().filter_map(|v| v.to_str().ok());
+        if name == SET_COOKIE {
+            // Cookies can't be comma-joined: they have their own field
+            cookies.extend(values.map(str::to_string));
+        } else if let Ok(value) = HeaderValue::from_str(&values.collect::<Vec<_>>().join(", ")) {
+            headers.insert(name.clone(), value);
+        }
+    }
+
+    ApiGatewayV2httpResponse {
+        status_code: response.status_code,
+        headers,
+        body: response.body,
+        is_base64_encoded: response.is_base64_encoded,
+        cookies,
+        ..Default::default()
+    }
+}
+
+/// Whether an HTTP event uses payload format 2.0
+fn is_v2_payload(payload: &Value) -> bool {
+    payload.get("version").and_then(Value::as_str) == Some("2.0")
+        || payload
+            .get("requestContext")
+            .is_some_and(|context| context.get("http").is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_api_gateway_response, build_http_request};
+    use http::Method;
+    use http_tunnel_common::HttpResponse;
+    use serde_json::json;
+
+    /// Payload format 2.0 event, as documented by AWS
+    fn v2_event() -> Value {
+        json!({
+            "version": "2.0",
+            "routeKey": "$default",
+            "rawPath": "/api/items",
+            "rawQueryString": "tag=a&tag=b%20c&q=x%26y",
+            "cookies": ["session=abc", "theme=dark"],
+            "headers": {
+                "host": "abc123def456.tunnel.example.com",
+                "content-type": "application/json"
+            },
+            "queryStringParameters": { "tag": "a,b c", "q": "x&y" },
+            "requestContext": {
+                "accountId": "123456789012",
+                "apiId": "api-id",
+                "domainName": "abc123def456.tunnel.example.com",
+                "domainPrefix": "abc123def456",
+                "http": {
+                    "method": "POST",
+                    "path": "/api/items",
+                    "protocol": "HTTP/1.1",
+                    "sourceIp": "203.0.113.7",
+                    "userAgent": "curl/8.0"
+                },
+                "requestId": "id-1",
+                "routeKey": "$default",
+                "stage": "$default",
+                "time": "12/Mar/2024:19:03:58 +0000",
+                "timeEpoch": 1710270238000_i64
+            },
+            "body": "eyJuYW1lIjoid2lkZ2V0In0=",
+            "isBase64Encoded": true
+        })
+    }
+
+    #[test]
+    fn test_v2_payload() {
+        let PublicRequest {
+            request, raw_query, ..
+        } = PublicRequest::from_payload(v2_event()).unwrap();
+        assert_eq!(request.http_method, Method::POST);
+        assert_eq!(request.path.as_deref(), Some("/api/items"));
+        assert_eq!(raw_query.as_deref(), Some("tag=a&tag=b%20c&q=x%26y"));
+        assert_eq!(request.headers["cookie"], "session=abc; theme=dark");
+        assert_eq!(
+            request.request_context.identity.source_ip.as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(request.request_context.request_id.as_deref(), Some("id-1"));
+        assert!(request.is_base64_encoded);
+
+        let http_request = build_http_request(&request, "req_1".to_string(), raw_query.as_deref());
+        assert_eq!(http_request.method, "POST");
+        assert_eq!(http_request.uri, "/api/items?tag=a&tag=b%20c&q=x%26y");
+        assert_eq!(http_request.headers["cookie"], ["session=abc; theme=dark"]);
+    }
+
+    #[test]
+    fn test_v1_payload() {
+        let PublicRequest {
+            request, raw_query, ..
+        } = PublicRequest::from_payload(json!({
+            "httpMethod": "DELETE",
+            "path": "/api/items/1",
+            "headers": { "cookie": "session=abc" },
+            "multiValueQueryStringParameters": { "force": ["true"] },
+            "requestContext": {
+                "httpMethod": "DELETE",
+                "requestId": "id-2",
+                "identity": { "sourceIp": "198.51.100.1" }
+            }
+        }))
+        .unwrap();
+        assert_eq!(request.http_method, Method::DELETE);
+        assert_eq!(raw_query, None);
+        assert_eq!(request.headers["cookie"], "session=abc");
+        assert_eq!(
+            request.request_context.identity.source_ip.as_deref(),
+            Some("198.51.100.1")
+        );
+        let http_request = build_http_request(&request, "req_2".to_string(), raw_query.as_deref());
+        assert_eq!(http_request.uri, "/api/items/1?force=true");
+    }
+
+    fn response_with_repeated_headers() -> ApiGatewayProxyResponse {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.headers.insert(
+            "set-cookie".to_string(),
+            vec![
+                "session=abc; HttpOnly".to_string(),
+                "theme=dark; Path=/".to_string(),
+            ],
+        );
+        response.headers.insert(
+            "vary".to_string(),
+            vec!["Accept-Encoding".to_string(), "Origin".to_string()],
+        );
+        response
+            .headers
+            .insert("content-type".to_string(), vec!["text/plain".to_string()]);
+        build_api_gateway_response(response)
+    }
+
+    #[test]
+    fn test_v1_response() {
+        let payload = PayloadFormat::V1
+            .response(response_with_repeated_headers())
+            .unwrap();
+        assert_eq!(
+            payload["multiValueHeaders"]["set-cookie"],
+            json!(["session=abc; HttpOnly", "theme=dark; Path=/"])
+        );
+        assert_eq!(
+            payload["multiValueHeaders"]["vary"],
+            json!(["Accept-Encoding", "Origin"])
+        );
+        assert_eq!(payload["headers"]["content-type"], "text/plain");
+        assert!(payload.get("cookies").is_none());
+    }
+
+    #[test]
+    fn test_v2_response() {
+        let payload = PayloadFormat::V2
+            .response(response_with_repeated_headers())
+            .unwrap();
+        assert_eq!(
+            payload["cookies"],
+            json!(["session=abc; HttpOnly", "theme=dark; Path=/"])
+        );
+        assert_eq!(payload["headers"]["vary"], "Accept-Encoding, Origin");
+        assert_eq!(payload["headers"]["content-type"], "text/plain");
+        assert!(payload["headers"].get("set-cookie").is_none());
+        assert_eq!(payload["statusCode"], 200);
+
+        // Responses built with single-value headers only keep them
+        let mut response = ApiGatewayProxyResponse {
+            status_code: 404,
+            ..Default::default()
+        };
+        response
+            .headers
+            .insert(SET_COOKIE, HeaderValue::from_static("a=1"));
+        let payload = PayloadFormat::V2.response(response).unwrap();
+        assert_eq!(payload["cookies"], json!(["a=1"]));
+    }
+}
@@ -0,0 +1,131 @@
+//! Per-tunnel response latency tracking
+//!
+//! Each Lambda container remembers how long recent requests to each tunnel took
+//! to complete. When a tunnel's local service is consistently slow, the
+//! forwarding handler waits until shortly before the expected completion time
+//! before it starts polling DynamoDB, instead of burning reads from the first 50ms.
+
+use http_tunnel_common::constants::{
+    ADAPTIVE_POLL_EWMA_WEIGHT, ADAPTIVE_POLL_MAX_TRACKED_TUNNELS, ADAPTIVE_POLL_MIN_SAMPLES,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Smoothed latency estimate for a single tunnel
+#[derive(Debug, Clone, Copy, Default)]
+struct LatencyEstimate {
+    /// Exponentially weighted mean latency in milliseconds
+    mean_ms: f64,
+    /// Exponentially weighted mean absolute deviation in milliseconds
+    deviation_ms: f64,
+    /// Number of samples observed
+    samples: u32,
+}
+
+impl LatencyEstimate {
+    fn record(&mut self, latency_ms: f64) {
+        if self.samples == 0 {
+            self.mean_ms = latency_ms;
+            self.deviation_ms = latency_ms / 2.0;
+        } else {
+            let error = latency_ms - self.mean_ms;
+            self.mean_ms += ADAPTIVE_POLL_EWMA_WEIGHT * error;
+            self.deviation_ms += ADAPTIVE_POLL_EWMA_WEIGHT * (error.abs() - self.deviation_ms);
+        }
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    /// Conservative lower bound on the next response time (mean minus two deviations)
+    fn head_start(&self) -> Duration {
+        if self.samples < ADAPTIVE_POLL_MIN_SAMPLES {
+            return Duration::ZERO;
+        }
+        let ms = (self.mean_ms - 2.0 * self.deviation_ms).max(0.0);
+        Duration::from_millis(ms as u64)
+    }
+}
+
+/// In-memory latency history, shared by all invocations in a container
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    tunnels: Mutex<HashMap<String, LatencyEstimate>>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record how long a completed request to `tunnel_id` took
+    pub fn record(&self, tunnel_id: &str, latency: Duration) {
+        let mut tunnels = self.tunnels.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Containers are short-lived, so simply start over rather than tracking recency
+        if !tunnels.contains_key(tunnel_id) && tunnels.len() >= ADAPTIVE_POLL_MAX_TRACKED_TUNNELS {
+            tunnels.clear();
+        }
+
+        tunnels
+            .entry(tunnel_id.to_string())
+            .or_default()
+            .record(latency.as_secs_f64() * 1000.0);
+    }
+
+    /// How long to wait before the first poll for a request to `tunnel_id`
+    ///
+    /// Returns zero until enough samples have been observed.
+    pub fn head_start(&self, tunnel_id: &str) -> Duration {
+        self.tunnels
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(tunnel_id)
+            .map(LatencyEstimate::head_start)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_head_start_without_history() {
+        let tracker = LatencyTracker::new();
+        assert_eq!(tracker.head_start("abc123"), Duration::ZERO);
+
+        for _ in 1..ADAPTIVE_POLL_MIN_SAMPLES {
+            tracker.record("abc123", Duration::from_secs(2));
+        }
+        assert_eq!(tracker.head_start("abc123"), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_head_start_for_consistently_slow_tunnel() {
+        let tracker = LatencyTracker::new();
+        for _ in 0..20 {
+            tracker.record("slow", Duration::from_millis(2000));
+            tracker.record("fast", Duration::from_millis(40));
+        }
+
+        let slow = tracker.head_start("slow");
+        assert!(slow > Duration::from_millis(1500), "got {:?}", slow);
+        assert!(slow <= Duration::from_millis(2000), "got {:?}", slow);
+
+        assert!(tracker.head_start("fast") <= Duration::from_millis(40));
+        assert_eq!(tracker.head_start("unknown"), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_variable_latency_reduces_head_start() {
+        let steady = LatencyTracker::new();
+        let jittery = LatencyTracker::new();
+        for i in 0..20 {
+            steady.record("t", Duration::from_millis(1000));
+            let ms = if i % 2 == 0 { 200 } else { 1800 };
+            jittery.record("t", Duration::from_millis(ms));
+        }
+
+        assert!(jittery.head_start("t") < steady.head_start("t"));
+    }
+}
@@ -6,25 +6,58 @@
 use anyhow::{Context, Result, anyhow};
 use aws_lambda_events::apigw::{ApiGatewayProxyRequest, ApiGatewayProxyResponse};
 use aws_sdk_apigatewaymanagement::Client as ApiGatewayManagementClient;
+use aws_sdk_apigatewaymanagement::error::SdkError;
+use aws_sdk_apigatewaymanagement::operation::post_to_connection::PostToConnectionError;
 use aws_sdk_apigatewaymanagement::primitives::Blob;
 use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnConsumedCapacity, ReturnValue};
 use aws_sdk_eventbridge::Client as EventBridgeClient;
+use aws_sdk_sqs::Client as SqsClient;
 use http_tunnel_common::ConnectionMetadata;
 use http_tunnel_common::constants::{
     OPTIMIZED_POLL_FINAL_INTERVAL_MS, OPTIMIZED_POLL_FIRST_INTERVAL_MS,
     OPTIMIZED_POLL_SECOND_INTERVAL_MS, PENDING_REQUEST_TTL_SECS, POLL_BACKOFF_MULTIPLIER,
-    POLL_INITIAL_INTERVAL_MS, POLL_MAX_INTERVAL_MS, REQUEST_TIMEOUT_SECS,
+    POLL_CONSISTENT_READ_EVERY, POLL_INITIAL_INTERVAL_MS, POLL_MAX_INTERVAL_MS,
+    POLL_MAX_READ_UNITS_PER_REQUEST, REQUEST_DEADLINE_MARGIN_MS, REQUEST_TIMEOUT_SECS,
+};
+use http_tunnel_common::protocol::{
+    Encoding, ErrorCode, HttpRequest, HttpResponse, Message, TunnelOptions, split_message,
 };
-use http_tunnel_common::protocol::{HttpRequest, HttpResponse};
 use http_tunnel_common::utils::{calculate_ttl, current_timestamp_millis, current_timestamp_secs};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use std::collections::HashMap;
+use std::sync::OnceLock;
 use std::time::{Duration, Instant};
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
+
+use crate::analytics::PendingContext;
+use crate::aws_util::retry;
+use crate::chunks::PendingTable;
 
+pub mod activity;
+pub mod alerts;
+pub mod analytics;
 pub mod auth;
+pub mod aws_util;
+pub mod balancer;
+pub mod chunks;
+pub mod compression;
+pub mod connection_cache;
 pub mod content_rewrite;
 pub mod error_handling;
+pub mod error_pages;
+pub mod grpc_web;
 pub mod handlers;
+pub mod honeypot;
+pub mod http_event;
+pub mod latency;
+pub mod metrics;
+pub mod reply;
+pub mod reservations;
+pub mod stats;
+pub mod store;
+pub mod streaming;
 
 /// Check if event-driven response pattern is enabled
 pub fn is_event_driven_enabled() -> bool {
@@ -34,11 +67,128 @@ pub fn is_event_driven_enabled() -> bool {
         == "true"
 }
 
+/// Build an API Gateway Management client for the WebSocket API named by `endpoint_var`
+fn management_client(
+    sdk_config: &aws_config::SdkConfig,
+    endpoint_var: &str,
+) -> Option<ApiGatewayManagementClient> {
+    let websocket_endpoint = match std::env::var(endpoint_var) {
+        Ok(endpoint) => endpoint,
+        Err(_) => {
+            info!(
+                "{} not set, API Gateway Management client not initialized",
+                endpoint_var
+            );
+            return None;
+        }
+    };
+
+    // Convert wss:// to https:// for API Gateway Management API
+    let management_endpoint = websocket_endpoint.replace("wss://", "https://");
+
+    info!(
+        "Initializing API Gateway Management client with endpoint: {}",
+        management_endpoint
+    );
+
+    let config = aws_sdk_apigatewaymanagement::config::Builder::from(sdk_config)
+        .endpoint_url(management_endpoint)
+        .build();
+    Some(ApiGatewayManagementClient::from_conf(config))
+}
+
+/// Check if the DynamoDB client should be pre-warmed during cold start
+pub fn is_prewarm_enabled() -> bool {
+    std::env::var("PREWARM_DYNAMODB")
+        .unwrap_or_else(|_| "false".to_string())
+        .to_lowercase()
+        == "true"
+}
+
 /// Shared AWS clients used across all handlers
 pub struct SharedClients {
     pub dynamodb: DynamoDbClient,
-    pub apigw_management: Option<ApiGatewayManagementClient>,
     pub eventbridge: EventBridgeClient,
+    pub sqs: SqsClient,
+    /// This container's queue for push-based response delivery
+    pub reply_queue: reply::ReplyQueue,
+    /// Per-tunnel response latency history for adaptive polling
+    pub latency: latency::LatencyTracker,
+    /// Spreads requests across agents sharing a tunnel
+    pub balancer: balancer::AgentBalancer,
+    /// Connections, pending requests and stats (see [`store`])
+    pub store: store::Backend,
+    /// Recent tunnel connection lookups
+    pub connections: connection_cache::ConnectionCache,
+    /// Throttles per-tunnel alert threshold evaluation
+    pub alerts: alerts::AlertGate,
+    /// Throttles activity-based TTL extension of connection items
+    pub activity: activity::ActivityGate,
+    /// Template of the HTML pages shown for offline and timed-out tunnels
+    pub error_pages: error_pages::ErrorPages,
+    sdk_config: aws_config::SdkConfig,
+    apigw_management: OnceLock<Option<ApiGatewayManagementClient>>,
+    public_apigw_management: OnceLock<Option<ApiGatewayManagementClient>>,
+}
+
+impl SharedClients {
+    /// Create clients from the loaded SDK config
+    ///
+    /// The API Gateway Management client is built lazily on first use, since
+    /// `$connect`, `$disconnect`, cleanup and stream events never need it.
+    /// Fails if the configured storage backend isn't available.
+    pub fn new(sdk_config: aws_config::SdkConfig) -> Result<Self> {
+        let dynamodb = DynamoDbClient::new(&sdk_config);
+        Ok(Self {
+            store: store::Backend::from_env(dynamodb.clone())?,
+            dynamodb,
+            eventbridge: EventBridgeClient::new(&sdk_config),
+            sqs: SqsClient::new(&sdk_config),
+            reply_queue: reply::ReplyQueue::new(),
+            latency: latency::LatencyTracker::new(),
+            balancer: balancer::AgentBalancer::new(),
+            connections: connection_cache::ConnectionCache::new(),
+            alerts: alerts::AlertGate::new(),
+            activity: activity::ActivityGate::new(),
+            error_pages: error_pages::ErrorPages::new(),
+            sdk_config,
+            apigw_management: OnceLock::new(),
+            public_apigw_management: OnceLock::new(),
+        })
+    }
+
+    /// API Gateway Management API client (None if WEBSOCKET_API_ENDPOINT is not set)
+    pub fn apigw_management(&self) -> Option<&ApiGatewayManagementClient> {
+        self.apigw_management
+            .get_or_init(|| management_client(&self.sdk_config, "WEBSOCKET_API_ENDPOINT"))
+            .as_ref()
+    }
+
+    /// Management client for the public WebSocket API used by passthrough clients
+    /// (None if PUBLIC_WEBSOCKET_API_ENDPOINT is not set)
+    pub fn public_apigw_management(&self) -> Option<&ApiGatewayManagementClient> {
+        self.public_apigw_management
+            .get_or_init(|| management_client(&self.sdk_config, "PUBLIC_WEBSOCKET_API_ENDPOINT"))
+            .as_ref()
+    }
+
+    /// Issue a cheap DynamoDB read so connection setup and credential resolution
+    /// happen during init rather than on the first public request
+    pub async fn prewarm_dynamodb(&self) -> Result<()> {
+        let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+            .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+        self.dynamodb
+            .get_item()
+            .table_name(&table_name)
+            .key("connectionId", AttributeValue::S("__prewarm__".to_string()))
+            .projection_expression("connectionId")
+            .send()
+            .await
+            .context("Failed to pre-warm DynamoDB client")?;
+
+        Ok(())
+    }
 }
 
 /// Extract tunnel ID from request path (path-based routing)
@@ -69,6 +219,60 @@ pub fn strip_tunnel_id_from_path(path: &str) -> String {
     }
 }
 
+/// Which public URL styles a deployment serves, from `ROUTING_MODE`
+/// (`path`, `subdomain` or `both`)
+///
+/// Subdomain URLs need a wildcard DNS record and certificate. Without
+/// `ROUTING_MODE`, the older `ENABLE_SUBDOMAIN_ROUTING=false` selects path-only.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RoutingConfig {
+    /// `https://{domain}/{tunnel_id}/...` only
+    Path,
+    /// `https://{tunnel_id}.{domain}/...` only
+    Subdomain,
+    /// Either style
+    #[default]
+    Both,
+}
+
+impl RoutingConfig {
+    /// Parse a `ROUTING_MODE` value
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "path" => Some(Self::Path),
+            "subdomain" => Some(Self::Subdomain),
+            "both" => Some(Self::Both),
+            _ => None,
+        }
+    }
+
+    /// The deployment's routing configuration
+    pub fn from_env() -> Self {
+        if let Ok(value) = std::env::var("ROUTING_MODE") {
+            return Self::parse(&value).unwrap_or_else(|| {
+                warn!("Unknown ROUTING_MODE {:?}, serving both URL styles", value);
+                Self::Both
+            });
+        }
+        let subdomain_enabled = std::env::var("ENABLE_SUBDOMAIN_ROUTING")
+            .map(|value| value.eq_ignore_ascii_case("true"))
+            .unwrap_or(true);
+        if subdomain_enabled {
+            Self::Both
+        } else {
+            Self::Path
+        }
+    }
+
+    pub fn serves_path(self) -> bool {
+        self != Self::Subdomain
+    }
+
+    pub fn serves_subdomain(self) -> bool {
+        self != Self::Path
+    }
+}
+
 /// Routing mode enum - determines how tunnel ID is extracted and content is handled
 #[derive(Debug, Clone, PartialEq)]
 pub enum RoutingMode {
@@ -140,15 +344,26 @@ pub fn extract_subdomain(host: &str, base_domain: &str) -> Result<Option<String>
 }
 
 /// Detect routing mode from request
-/// Tries subdomain-based routing first, falls back to path-based routing
-pub fn detect_routing_mode(host: &str, path: &str, base_domain: &str) -> Result<RoutingMode> {
+/// Tries subdomain-based routing first, falls back to path-based routing, using
+/// only the styles `config` serves
+pub fn detect_routing_mode(
+    host: &str,
+    path: &str,
+    base_domain: &str,
+    config: RoutingConfig,
+) -> Result<RoutingMode> {
     // Try subdomain-based routing first
-    if let Some(tunnel_id) = extract_subdomain(host, base_domain)? {
+    if config.serves_subdomain()
+        && let Some(tunnel_id) = extract_subdomain(host, base_domain)?
+    {
         return Ok(RoutingMode::SubdomainBased {
             tunnel_id,
             full_path: path.to_string(),
         });
     }
+    if !config.serves_path() {
+        return Err(anyhow!("Missing tunnel ID in host"));
+    }
 
     // Fall back to path-based routing
     let tunnel_id = extract_tunnel_id_from_path(path)?;
@@ -160,6 +375,41 @@ pub fn detect_routing_mode(host: &str, path: &str, base_domain: &str) -> Result<
     })
 }
 
+/// Public URLs of a tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelUrls {
+    /// Subdomain URL if subdomain routing is enabled, otherwise the path-based URL
+    pub public_url: String,
+    pub subdomain_url: Option<String>,
+    pub path_based_url: Option<String>,
+}
+
+/// Build the public URLs for `tunnel_id` on this deployment's domain
+pub fn tunnel_urls(tunnel_id: &str) -> TunnelUrls {
+    let domain = std::env::var("DOMAIN_NAME").unwrap_or_else(|_| "tunnel.example.com".to_string());
+    tunnel_urls_for(tunnel_id, &domain, RoutingConfig::from_env())
+}
+
+/// Build the public URLs for `tunnel_id` on `domain`, in the styles `config` serves
+pub fn tunnel_urls_for(tunnel_id: &str, domain: &str, config: RoutingConfig) -> TunnelUrls {
+    let path_based_url = config
+        .serves_path()
+        .then(|| format!("https://{}/{}", domain, tunnel_id));
+    let subdomain_url = config
+        .serves_subdomain()
+        .then(|| format!("https://{}.{}", tunnel_id, domain));
+
+    TunnelUrls {
+        public_url: subdomain_url
+            .as_ref()
+            .or(path_based_url.as_ref())
+            .cloned()
+            .unwrap_or_default(),
+        subdomain_url,
+        path_based_url,
+    }
+}
+
 /// Save connection metadata to DynamoDB
 pub async fn save_connection_metadata(
     client: &DynamoDbClient,
@@ -168,70 +418,325 @@ pub async fn save_connection_metadata(
     let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
         .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
 
-    let mut put_request = client
+    let put_request = client
         .put_item()
         .table_name(&table_name)
-        .item(
-            "connectionId",
+        .set_item(Some(connection_item(metadata)));
+
+    retry("PutItem", || put_request.clone().send())
+        .await
+        .context("Failed to save connection metadata to DynamoDB")?;
+
+    Ok(())
+}
+
+/// Attributes of a new connection item (see [`connection_metadata_from_item`])
+pub fn connection_item(metadata: &ConnectionMetadata) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        (
+            "connectionId".to_string(),
             AttributeValue::S(metadata.connection_id.clone()),
-        )
-        .item("tunnelId", AttributeValue::S(metadata.tunnel_id.clone()))
-        .item("publicUrl", AttributeValue::S(metadata.public_url.clone()))
-        .item(
-            "createdAt",
+        ),
+        (
+            "tunnelId".to_string(),
+            AttributeValue::S(metadata.tunnel_id.clone()),
+        ),
+        (
+            "publicUrl".to_string(),
+            AttributeValue::S(metadata.public_url.clone()),
+        ),
+        (
+            "createdAt".to_string(),
             AttributeValue::N(metadata.created_at.to_string()),
-        )
-        .item("ttl", AttributeValue::N(metadata.ttl.to_string()));
+        ),
+        (
+            "ttl".to_string(),
+            AttributeValue::N(metadata.ttl.to_string()),
+        ),
+    ]);
 
     // Add optional fields if present
-    if let Some(ref subdomain_url) = metadata.subdomain_url {
-        put_request = put_request.item("subdomainUrl", AttributeValue::S(subdomain_url.clone()));
-    }
-    if let Some(ref path_based_url) = metadata.path_based_url {
-        put_request = put_request.item("pathBasedUrl", AttributeValue::S(path_based_url.clone()));
+    for (name, value) in [
+        ("subdomainUrl", &metadata.subdomain_url),
+        ("pathBasedUrl", &metadata.path_based_url),
+        ("owner", &metadata.owner),
+    ] {
+        if let Some(value) = value {
+            item.insert(name.to_string(), AttributeValue::S(value.clone()));
+        }
     }
 
-    put_request
-        .send()
+    item
+}
+
+/// Store the options an agent requested on its connection item
+pub async fn save_tunnel_options(
+    client: &DynamoDbClient,
+    connection_id: &str,
+    options: &TunnelOptions,
+) -> Result<()> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let update = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()));
+    let request = set_tunnel_options(update, options)?;
+    retry("UpdateItem", || request.clone().send())
         .await
-        .context("Failed to save connection metadata to DynamoDB")?;
+        .context("Failed to save tunnel options")?;
+
+    Ok(())
+}
+
+/// Set the options on the connection item `update` targets
+pub(crate) fn set_tunnel_options(
+    update: UpdateItemFluentBuilder,
+    options: &TunnelOptions,
+) -> Result<UpdateItemFluentBuilder> {
+    let options_json =
+        serde_json::to_string(options).context("Failed to serialize tunnel options")?;
+
+    Ok(update
+        .update_expression("SET #options = :options")
+        .condition_expression("attribute_exists(connectionId)")
+        .expression_attribute_names("#options", "options")
+        .expression_attribute_values(":options", AttributeValue::S(options_json)))
+}
+
+/// Note that an agent's heartbeat arrived, for the tunnel status endpoint
+pub async fn record_heartbeat(client: &DynamoDbClient, connection_id: &str) -> Result<()> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let update = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()));
+    let request = set_heartbeat(update);
+    retry("UpdateItem", || request.clone().send())
+        .await
+        .context("Failed to record heartbeat")?;
 
     Ok(())
 }
 
+/// Set the heartbeat time on the connection item `update` targets
+pub(crate) fn set_heartbeat(update: UpdateItemFluentBuilder) -> UpdateItemFluentBuilder {
+    update
+        .update_expression("SET lastHeartbeatAt = :now")
+        .condition_expression("attribute_exists(connectionId)")
+        .expression_attribute_values(
+            ":now",
+            AttributeValue::N(current_timestamp_secs().to_string()),
+        )
+}
+
+/// Move a connection to another tunnel ID, updating its public URLs
+pub async fn reassign_tunnel_id(
+    client: &DynamoDbClient,
+    connection_id: &str,
+    tunnel_id: &str,
+) -> Result<TunnelUrls> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+    let urls = tunnel_urls(tunnel_id);
+
+    let mut update = client
+        .update_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()))
+        .condition_expression("attribute_exists(connectionId)")
+        .expression_attribute_values(":tunnel_id", AttributeValue::S(tunnel_id.to_string()))
+        .expression_attribute_values(":public_url", AttributeValue::S(urls.public_url.clone()));
+
+    // Only the URL styles the deployment serves are kept
+    let mut set = vec!["tunnelId = :tunnel_id", "publicUrl = :public_url"];
+    let mut remove = Vec::new();
+    for (attribute, placeholder, assignment, url) in [
+        (
+            "pathBasedUrl",
+            ":path_based_url",
+            "pathBasedUrl = :path_based_url",
+            &urls.path_based_url,
+        ),
+        (
+            "subdomainUrl",
+            ":subdomain_url",
+            "subdomainUrl = :subdomain_url",
+            &urls.subdomain_url,
+        ),
+    ] {
+        match url {
+            Some(url) => {
+                set.push(assignment);
+                update =
+                    update.expression_attribute_values(placeholder, AttributeValue::S(url.clone()));
+            }
+            None => remove.push(attribute),
+        }
+    }
+    let mut expression = format!("SET {}", set.join(", "));
+    if !remove.is_empty() {
+        expression.push_str(&format!(" REMOVE {}", remove.join(", ")));
+    }
+    update = update.update_expression(expression);
+
+    retry("UpdateItem", || update.clone().send())
+        .await
+        .context("Failed to reassign tunnel ID")?;
+
+    Ok(urls)
+}
+
+/// Delete `owner`'s connections other than `keep_connection_id` serving `tunnel_id`
+///
+/// A reconnecting agent can claim its reserved ID before API Gateway has
+/// delivered `$disconnect` for the old connection; the old item would
+/// otherwise keep receiving the tunnel's traffic. Connections of other
+/// owners are never removed.
+pub async fn remove_other_connections(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+    keep_connection_id: &str,
+    owner: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut removed = Vec::new();
+    for metadata in query_tunnel_connections(client, tunnel_id).await? {
+        if metadata.connection_id != keep_connection_id && metadata.owner.as_deref() == owner {
+            delete_connection(client, &metadata.connection_id).await?;
+            removed.push(metadata.connection_id);
+        }
+    }
+
+    Ok(removed)
+}
+
 /// Delete connection from DynamoDB
 pub async fn delete_connection(client: &DynamoDbClient, connection_id: &str) -> Result<()> {
     let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
         .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
 
-    client
+    let request = client
         .delete_item()
         .table_name(&table_name)
-        .key("connectionId", AttributeValue::S(connection_id.to_string()))
-        .send()
+        .key("connectionId", AttributeValue::S(connection_id.to_string()));
+    retry("DeleteItem", || request.clone().send())
         .await
         .context("Failed to delete connection from DynamoDB")?;
 
     Ok(())
 }
 
-/// Look up connection ID by tunnel ID using GSI (path-based routing)
+/// Forget a connection that turned out to be gone (410 on delivery)
+///
+/// Agents that vanish without a `$disconnect` reaching us stay registered; this
+/// stops requests being routed to them. Failures are only logged.
+pub async fn remove_stale_connection(store: &impl store::TunnelStore, connection_id: &str) {
+    match store.delete_connection(connection_id).await {
+        Ok(()) => info!("Removed stale connection {}", connection_id),
+        Err(e) => warn!(
+            "Failed to delete stale connection {}: {:#}",
+            connection_id, e
+        ),
+    }
+}
+
+/// Look up a connection's metadata by connection ID (None if it's gone)
+pub async fn get_connection_metadata(
+    client: &DynamoDbClient,
+    connection_id: &str,
+) -> Result<Option<ConnectionMetadata>> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let request = client
+        .get_item()
+        .table_name(&table_name)
+        .key("connectionId", AttributeValue::S(connection_id.to_string()));
+    let result = retry("GetItem", || request.clone().send())
+        .await
+        .context("Failed to get connection metadata")?;
+
+    result
+        .item
+        .as_ref()
+        .map(connection_metadata_from_item)
+        .transpose()
+}
+
+/// Look up the IDs of every connection serving a tunnel using GSI (path-based routing)
 pub async fn lookup_connection_by_tunnel_id(
     client: &DynamoDbClient,
     tunnel_id: &str,
-) -> Result<String> {
+) -> Result<Vec<String>> {
+    let connections = lookup_connections_by_tunnel_id(client, tunnel_id).await?;
+    Ok(connections
+        .into_iter()
+        .map(|metadata| metadata.connection_id)
+        .collect())
+}
+
+/// Look up the metadata of every connection serving a tunnel using GSI
+///
+/// A tunnel has several connections when agents share it (`--shared`).
+/// Fails if the tunnel has none.
+pub async fn lookup_connections_by_tunnel_id(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+) -> Result<Vec<ConnectionMetadata>> {
+    let connections = query_tunnel_connections(client, tunnel_id).await?;
+    if connections.is_empty() {
+        return Err(anyhow!("Connection not found for tunnel ID: {}", tunnel_id));
+    }
+
+    Ok(connections)
+}
+
+/// Query the connections serving a tunnel using GSI (empty if none)
+pub async fn query_tunnel_connections(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+) -> Result<Vec<ConnectionMetadata>> {
+    let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
+        .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
+
+    let request = client
+        .query()
+        .table_name(&table_name)
+        .index_name("tunnel-id-index")
+        .key_condition_expression("tunnelId = :tunnel_id")
+        .expression_attribute_values(":tunnel_id", AttributeValue::S(tunnel_id.to_string()));
+    let result = retry("Query", || request.clone().send())
+        .await
+        .context("Failed to query connections by tunnel ID")?;
+
+    result
+        .items
+        .unwrap_or_default()
+        .iter()
+        .map(connection_metadata_from_item)
+        .collect()
+}
+
+/// Look up the full connection metadata by tunnel ID using GSI
+pub async fn lookup_connection_metadata_by_tunnel_id(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+) -> Result<ConnectionMetadata> {
     let table_name = std::env::var("CONNECTIONS_TABLE_NAME")
         .context("CONNECTIONS_TABLE_NAME environment variable not set")?;
     let index_name = "tunnel-id-index";
 
-    let result = client
+    let request = client
         .query()
         .table_name(&table_name)
         .index_name(index_name)
         .key_condition_expression("tunnelId = :tunnel_id")
         .expression_attribute_values(":tunnel_id", AttributeValue::S(tunnel_id.to_string()))
-        .limit(1)
-        .send()
+        .limit(1);
+    let result = retry("Query", || request.clone().send())
         .await
         .context("Failed to query connection by tunnel ID")?;
 
@@ -240,33 +745,104 @@ pub async fn lookup_connection_by_tunnel_id(
         .first()
         .ok_or_else(|| anyhow!("Connection not found for tunnel ID: {}", tunnel_id))?;
 
-    let connection_id = item
-        .get("connectionId")
-        .and_then(|v| v.as_s().ok())
-        .ok_or_else(|| anyhow!("Missing connectionId in DynamoDB item"))?;
+    connection_metadata_from_item(item)
+}
+
+/// Parse connection metadata from a connections table item
+pub fn connection_metadata_from_item(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<ConnectionMetadata> {
+    let string = |name: &str| item.get(name).and_then(|v| v.as_s().ok()).cloned();
+    let number = |name: &str| {
+        item.get(name)
+            .and_then(|v| v.as_n().ok())
+            .and_then(|n| n.parse::<i64>().ok())
+            .unwrap_or_default()
+    };
+
+    let connection_id =
+        string("connectionId").ok_or_else(|| anyhow!("Missing connectionId in DynamoDB item"))?;
+    let tunnel_id = string("tunnelId").unwrap_or_default();
+    let public_url = string("publicUrl").unwrap_or_default();
 
-    Ok(connection_id.clone())
+    let mut metadata = ConnectionMetadata::new(
+        connection_id,
+        tunnel_id,
+        public_url,
+        number("createdAt"),
+        number("ttl"),
+    );
+    metadata.subdomain_url = string("subdomainUrl");
+    metadata.path_based_url = string("pathBasedUrl");
+    metadata.owner = string("owner");
+    metadata.last_heartbeat_at = item
+        .get("lastHeartbeatAt")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok());
+    metadata.options = string("options")
+        .and_then(|options| serde_json::from_str(&options).ok())
+        .unwrap_or_default();
+
+    Ok(metadata)
+}
+
+/// Characters percent-encoded in rebuilt query keys and values: all but the
+/// unreserved ones (RFC 3986)
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Query string of the public request, without the `?`
+///
+/// `raw_query` is the query string as the client sent it, which HTTP API (v2)
+/// and Function URL events carry as `rawQueryString`; it's kept as is. Without
+/// it, the query is rebuilt from the decoded parameters, preferring the
+/// multi-value map so repeated keys keep every value. Keys are sorted, as the
+/// event doesn't record their order.
+pub fn query_string(request: &ApiGatewayProxyRequest, raw_query: Option<&str>) -> String {
+    if let Some(raw_query) = raw_query.filter(|raw| !raw.is_empty()) {
+        return raw_query.to_string();
+    }
+    let params = if request.multi_value_query_string_parameters.is_empty() {
+        &request.query_string_parameters
+    } else {
+        &request.multi_value_query_string_parameters
+    };
+    let mut pairs: Vec<(&str, &str)> = params.iter().collect();
+    // Stable, so the values of a key stay in order
+    pairs.sort_by_key(|(key, _)| *key);
+    pairs
+        .iter()
+        .map(|(key, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(key, QUERY_ENCODE_SET),
+                utf8_percent_encode(value, QUERY_ENCODE_SET)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
 }
 
 /// Build HttpRequest from API Gateway event
-pub fn build_http_request(request: &ApiGatewayProxyRequest, request_id: String) -> HttpRequest {
+///
+/// `raw_query` is the event's `rawQueryString`, if it has one (see [`query_string`]).
+pub fn build_http_request(
+    request: &ApiGatewayProxyRequest,
+    request_id: String,
+    raw_query: Option<&str>,
+) -> HttpRequest {
     let method = request.http_method.to_string();
 
-    let uri = format!("{}{}", request.path.as_deref().unwrap_or("/"), {
-        let params = &request.query_string_parameters;
-        if params.is_empty() {
-            String::new()
-        } else {
-            format!(
-                "?{}",
-                params
-                    .iter()
-                    .map(|(k, v)| format!("{}={}", k, v))
-                    .collect::<Vec<_>>()
-                    .join("&")
-            )
-        }
-    });
+    let path = request.path.as_deref().unwrap_or("/");
+    let query = query_string(request, raw_query);
+    let uri = if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query)
+    };
 
     let headers = request
         .headers
@@ -298,112 +874,491 @@ pub fn build_http_request(request: &ApiGatewayProxyRequest, request_id: String)
         headers,
         body,
         timestamp: current_timestamp_millis(),
+        timeout_ms: None,
+        chunked: false,
+        trailers: Default::default(),
+        correlation_id: None,
+        content_encoding: Default::default(),
     }
 }
 
 /// Save pending request to DynamoDB
+///
+/// `reply_queue` is the waiting container's queue, notified by the stream handler
+/// once the response arrives (see [`reply`]).
+///
+/// The tunnel ID, request body size and send time are kept for the tunnel's
+/// lifetime analytics, recorded when the response arrives.
 pub async fn save_pending_request(
     client: &DynamoDbClient,
     request_id: &str,
     connection_id: &str,
+    tunnel_id: &str,
+    request_bytes: usize,
     api_gateway_request_id: &str,
+    reply_queue: Option<&str>,
 ) -> Result<()> {
     let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
         .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
-    let created_at = current_timestamp_secs();
-    let ttl = calculate_ttl(PENDING_REQUEST_TTL_SECS);
+    let item = pending_request_item(&store::PendingRequest {
+        request_id,
+        connection_id,
+        tunnel_id,
+        request_bytes,
+        api_gateway_request_id,
+        reply_queue,
+    });
 
-    client
+    let put_request = client
         .put_item()
         .table_name(&table_name)
-        .item("requestId", AttributeValue::S(request_id.to_string()))
-        .item("connectionId", AttributeValue::S(connection_id.to_string()))
-        .item(
-            "apiGatewayRequestId",
-            AttributeValue::S(api_gateway_request_id.to_string()),
-        )
-        .item("createdAt", AttributeValue::N(created_at.to_string()))
-        .item("ttl", AttributeValue::N(ttl.to_string()))
-        .item("status", AttributeValue::S("pending".to_string()))
-        .send()
+        .set_item(Some(item));
+
+    retry("PutItem", || put_request.clone().send())
         .await
         .context("Failed to save pending request to DynamoDB")?;
 
     Ok(())
 }
 
+/// Attributes of a new pending request item
+pub fn pending_request_item(
+    request: &store::PendingRequest<'_>,
+) -> HashMap<String, AttributeValue> {
+    let created_at = current_timestamp_secs();
+    let ttl = calculate_ttl(PENDING_REQUEST_TTL_SECS);
+
+    let mut item = HashMap::from([
+        (
+            "requestId".to_string(),
+            AttributeValue::S(request.request_id.to_string()),
+        ),
+        (
+            "connectionId".to_string(),
+            AttributeValue::S(request.connection_id.to_string()),
+        ),
+        (
+            "tunnelId".to_string(),
+            AttributeValue::S(request.tunnel_id.to_string()),
+        ),
+        (
+            "requestBytes".to_string(),
+            AttributeValue::N(request.request_bytes.to_string()),
+        ),
+        (
+            "sentAtMs".to_string(),
+            AttributeValue::N(current_timestamp_millis().to_string()),
+        ),
+        (
+            "apiGatewayRequestId".to_string(),
+            AttributeValue::S(request.api_gateway_request_id.to_string()),
+        ),
+        (
+            "createdAt".to_string(),
+            AttributeValue::N(created_at.to_string()),
+        ),
+        ("ttl".to_string(), AttributeValue::N(ttl.to_string())),
+        (
+            "status".to_string(),
+            AttributeValue::S("pending".to_string()),
+        ),
+    ]);
+    if let Some(reply_queue) = request.reply_queue {
+        item.insert(
+            "replyQueue".to_string(),
+            AttributeValue::S(reply_queue.to_string()),
+        );
+    }
+
+    item
+}
+
+/// Maximum attempts when API Gateway throttles delivery to a connection
+const SEND_MAX_ATTEMPTS: u32 = 3;
+
+/// Initial backoff between throttled delivery attempts
+const SEND_RETRY_DELAY_MS: u64 = 50;
+
+/// Why a message could not be delivered to a WebSocket connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryFailure {
+    /// The connection no longer exists (410 Gone)
+    Gone,
+    /// API Gateway throttled the delivery (after retries)
+    Throttled,
+    /// Any other delivery error
+    Other,
+}
+
+impl DeliveryFailure {
+    /// Classify an error returned by [`send_to_connection`]
+    pub fn classify(err: &anyhow::Error) -> Self {
+        match err
+            .downcast_ref::<SdkError<PostToConnectionError>>()
+            .and_then(|e| e.as_service_error())
+        {
+            Some(PostToConnectionError::GoneException(_)) => DeliveryFailure::Gone,
+            Some(PostToConnectionError::LimitExceededException(_)) => DeliveryFailure::Throttled,
+            _ => DeliveryFailure::Other,
+        }
+    }
+
+    /// Protocol error code reported for this failure
+    pub fn error_code(&self) -> ErrorCode {
+        match self {
+            DeliveryFailure::Gone | DeliveryFailure::Other => ErrorCode::TunnelUnavailable,
+            DeliveryFailure::Throttled => ErrorCode::RateLimited,
+        }
+    }
+
+    /// Client-facing message for this failure
+    pub fn message(&self) -> &'static str {
+        match self {
+            DeliveryFailure::Gone => "Tunnel offline: the agent is no longer connected",
+            DeliveryFailure::Throttled => "Tunnel busy: please retry shortly",
+            DeliveryFailure::Other => "Tunnel connection unavailable",
+        }
+    }
+}
+
 /// Send message to WebSocket connection
+///
+/// Throttled deliveries are retried with exponential backoff; other errors are
+/// returned immediately so callers can fail fast (see [`DeliveryFailure`]).
 pub async fn send_to_connection(
     client: &ApiGatewayManagementClient,
     connection_id: &str,
-    data: &str,
+    data: &[u8],
 ) -> Result<()> {
-    client
-        .post_to_connection()
-        .connection_id(connection_id)
-        .data(Blob::new(data.as_bytes()))
-        .send()
+    let mut attempt = 1;
+    let mut delay = Duration::from_millis(SEND_RETRY_DELAY_MS);
+
+    loop {
+        let result = client
+            .post_to_connection()
+            .connection_id(connection_id)
+            .data(Blob::new(data))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e)
+                if attempt < SEND_MAX_ATTEMPTS
+                    && matches!(
+                        e.as_service_error(),
+                        Some(PostToConnectionError::LimitExceededException(_))
+                    ) =>
+            {
+                warn!(
+                    "Delivery to {} throttled (attempt {}), retrying in {:?}",
+                    connection_id, attempt, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            Err(e) => {
+                return Err(
+                    anyhow::Error::new(e).context("Failed to send message to WebSocket connection")
+                );
+            }
+        }
+    }
+}
+
+/// Send a protocol message to a WebSocket connection
+///
+/// Bodies too large for a single frame are sent as a sequence of chunks (see
+/// [`split_message`]); delivery stops at the first failed frame.
+pub async fn send_message_to_connection(
+    client: &ApiGatewayManagementClient,
+    connection_id: &str,
+    message: Message,
+) -> Result<()> {
+    send_message_with_encoding(client, connection_id, message, Encoding::Json).await
+}
+
+/// Send a protocol message in the encoding the agent asked for in its Ready message
+pub async fn send_message_with_encoding(
+    client: &ApiGatewayManagementClient,
+    connection_id: &str,
+    message: Message,
+    encoding: Encoding,
+) -> Result<()> {
+    for message in split_message(message) {
+        let frame = encoding
+            .encode(&message)
+            .context("Failed to serialize message")?;
+        send_to_connection(client, connection_id, &frame.into_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Build the response returned to the public client for a tunnel error
+pub fn build_error_response(request_id: &str, code: &ErrorCode, message: &str) -> HttpResponse {
+    HttpResponse {
+        request_id: request_id.to_string(),
+        status_code: code.http_status(),
+        headers: [("Content-Type".to_string(), vec!["text/plain".to_string()])]
+            .into_iter()
+            .collect(),
+        body: http_tunnel_common::encode_body(message.as_bytes()),
+        processing_time_ms: 0,
+        chunked: false,
+        streaming: false,
+        trailers: Default::default(),
+        content_encoding: Default::default(),
+    }
+}
+
+/// Mark a pending request as failed so any waiter stops polling immediately
+pub async fn mark_pending_request_failed(
+    client: &DynamoDbClient,
+    response: &HttpResponse,
+    code: &ErrorCode,
+) -> Result<()> {
+    let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
+        .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
+
+    let response_data =
+        serde_json::to_string(response).context("Failed to serialize response to JSON")?;
+    let error_code = serde_json::to_value(code)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default();
+
+    let request = client
+        .update_item()
+        .table_name(&table_name)
+        .key("requestId", AttributeValue::S(response.request_id.clone()))
+        .update_expression("SET #status = :status, responseData = :data, errorCode = :code")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S("failed".to_string()))
+        .expression_attribute_values(":data", AttributeValue::S(response_data))
+        .expression_attribute_values(":code", AttributeValue::S(error_code));
+    retry("UpdateItem", || request.clone().send())
         .await
-        .context("Failed to send message to WebSocket connection")?;
+        .context("Failed to mark pending request as failed")?;
+
+    debug!("Marked pending request as failed: {}", response.request_id);
 
     Ok(())
 }
 
+/// Deadline after which the edge stops waiting for a request that starts now
+pub fn request_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS)
+}
+
+/// Time budget to hand to the agent, leaving room for the response trip back
+pub fn remaining_budget_ms(deadline: Instant) -> u64 {
+    let remaining = deadline
+        .saturating_duration_since(Instant::now())
+        .as_millis() as u64;
+    remaining.saturating_sub(REQUEST_DEADLINE_MARGIN_MS)
+}
+
 /// Wait for response with event-driven or polling approach based on USE_EVENT_DRIVEN flag
-pub async fn wait_for_response(client: &DynamoDbClient, request_id: &str) -> Result<HttpResponse> {
+///
+/// `head_start` delays the first poll for tunnels known to respond slowly (see [`latency`]).
+pub async fn wait_for_response(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    deadline: Instant,
+    head_start: Duration,
+) -> Result<HttpResponse> {
+    let head_start = head_start.min(deadline.saturating_duration_since(Instant::now()));
+    if !head_start.is_zero() {
+        debug!(
+            "Delaying first poll for request {} by {}ms",
+            request_id,
+            head_start.as_millis()
+        );
+        tokio::time::sleep(head_start).await;
+    }
+
     if is_event_driven_enabled() {
-        wait_for_response_event_driven(client, request_id).await
+        wait_for_response_event_driven(client, table, request_id, deadline).await
     } else {
-        wait_for_response_polling(client, request_id).await
+        wait_for_response_polling(client, table, request_id, deadline).await
+    }
+}
+
+/// Tracks DynamoDB read cost while a single request waits for its response
+///
+/// Most polls use eventually consistent reads, which cost half as much as strongly
+/// consistent ones; every Nth read is strongly consistent to bound staleness. Once
+/// the per-request budget is spent, polling stops until a final check at the deadline.
+#[derive(Debug, Clone)]
+pub struct PollReadBudget {
+    max_units: f64,
+    consumed_units: f64,
+    reads: u32,
+}
+
+impl Default for PollReadBudget {
+    fn default() -> Self {
+        Self::new(POLL_MAX_READ_UNITS_PER_REQUEST)
+    }
+}
+
+impl PollReadBudget {
+    /// Create a budget allowing up to `max_units` read capacity units
+    pub fn new(max_units: f64) -> Self {
+        Self {
+            max_units,
+            consumed_units: 0.0,
+            reads: 0,
+        }
+    }
+
+    /// Whether the next read should be strongly consistent
+    pub fn next_read_consistent(&self) -> bool {
+        (self.reads + 1).is_multiple_of(POLL_CONSISTENT_READ_EVERY)
+    }
+
+    /// Record a completed read, using the reported capacity when available
+    pub fn record(&mut self, consumed_units: Option<f64>, consistent: bool) {
+        self.reads += 1;
+        self.consumed_units += consumed_units.unwrap_or(if consistent { 1.0 } else { 0.5 });
+    }
+
+    /// Whether the read budget has been spent
+    pub fn is_exhausted(&self) -> bool {
+        self.consumed_units >= self.max_units
+    }
+
+    /// Number of reads performed so far
+    pub fn reads(&self) -> u32 {
+        self.reads
+    }
+
+    /// Read capacity units consumed so far
+    pub fn consumed_units(&self) -> f64 {
+        self.consumed_units
     }
 }
 
 /// Helper function to check for completed response in DynamoDB
-async fn check_for_response(
+///
+/// Only `status`, `responseData` and `bodyChunks` are projected; chunked bodies
+/// are loaded separately (see [`chunks`]). Consistency is chosen by the
+/// budget unless `force_consistent` is set (used for the final check at the deadline).
+pub(crate) async fn check_for_response(
     client: &DynamoDbClient,
-    table_name: &str,
+    table: &PendingTable,
     request_id: &str,
+    budget: &mut PollReadBudget,
+    force_consistent: bool,
 ) -> Result<Option<HttpResponse>> {
-    let result = client
+    let consistent = force_consistent || budget.next_read_consistent();
+
+    let request = client
         .get_item()
-        .table_name(table_name)
-        .key("requestId", AttributeValue::S(request_id.to_string()))
-        .send()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .projection_expression("#status, responseData, bodyChunks")
+        .expression_attribute_names("#status", "status")
+        .consistent_read(consistent)
+        .return_consumed_capacity(ReturnConsumedCapacity::Total);
+    let result = retry("GetItem", || request.clone().send())
         .await
         .context("Failed to get pending request from DynamoDB")?;
 
-    if let Some(item) = result.item {
-        let status = item
-            .get("status")
-            .and_then(|v| v.as_s().ok())
-            .ok_or_else(|| anyhow!("Missing status in DynamoDB item"))?;
+    budget.record(
+        result.consumed_capacity().and_then(|c| c.capacity_units()),
+        consistent,
+    );
 
-        if status == "completed" {
-            // Extract response data
-            let response_data = item
-                .get("responseData")
-                .and_then(|v| v.as_s().ok())
-                .ok_or_else(|| anyhow!("Missing responseData in completed request"))?;
-
-            let response: HttpResponse = serde_json::from_str(response_data)
-                .context("Failed to parse response data JSON")?;
-
-            // Clean up pending request
-            if let Err(e) = client
-                .delete_item()
-                .table_name(table_name)
-                .key("requestId", AttributeValue::S(request_id.to_string()))
-                .send()
-                .await
-            {
-                error!("Failed to clean up pending request: {}", e);
-            }
+    let Some(item) = result.item else {
+        return Ok(None);
+    };
+    let Some(mut response) = response_from_item(&item)? else {
+        return Ok(None);
+    };
+    if response.chunked {
+        let body_chunks = body_chunk_count(&item)?;
+        response.body = chunks::load_body(client, table, request_id, body_chunks).await?;
+        response.chunked = false;
+    }
 
-            return Ok(Some(response));
-        }
+    debug!(
+        "Response for {} found after {} reads ({:.1} RCU)",
+        request_id,
+        budget.reads(),
+        budget.consumed_units()
+    );
+
+    // Clean up pending request
+    if let Err(e) = client
+        .delete_item()
+        .table_name(&table.table_name)
+        .set_key(Some(table.request_key(request_id)))
+        .send()
+        .await
+    {
+        error!("Failed to clean up pending request: {}", e);
+    }
+
+    Ok(Some(response))
+}
+
+/// The response recorded on a pending request item, once it completed or failed
+///
+/// Chunked bodies are left to the caller to load (see [`body_chunk_count`]).
+pub fn response_from_item(item: &HashMap<String, AttributeValue>) -> Result<Option<HttpResponse>> {
+    let status = item
+        .get("status")
+        .and_then(|v| v.as_s().ok())
+        .ok_or_else(|| anyhow!("Missing status in DynamoDB item"))?;
+
+    // Failed requests carry a pre-built error response
+    if status != "completed" && status != "failed" {
+        return Ok(None);
     }
 
-    Ok(None)
+    // Extract response data
+    let response_data = item
+        .get("responseData")
+        .and_then(|v| v.as_s().ok())
+        .ok_or_else(|| anyhow!("Missing responseData in completed request"))?;
+
+    serde_json::from_str(response_data)
+        .map(Some)
+        .context("Failed to parse response data JSON")
+}
+
+/// Number of body chunks of a chunked response
+pub fn body_chunk_count(item: &HashMap<String, AttributeValue>) -> Result<u32> {
+    item.get("bodyChunks")
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or_else(|| anyhow!("Missing bodyChunks in chunked response"))
+}
+
+/// Final strongly consistent check once the deadline is reached (or the read budget is spent)
+pub(crate) async fn finish_waiting(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    budget: &mut PollReadBudget,
+    deadline: Instant,
+) -> Result<HttpResponse> {
+    if budget.is_exhausted() {
+        warn!(
+            "Read budget exhausted for {} after {} reads, waiting for deadline",
+            request_id,
+            budget.reads()
+        );
+        tokio::time::sleep(deadline.saturating_duration_since(Instant::now())).await;
+    }
+
+    match check_for_response(client, table, request_id, budget, true).await? {
+        Some(response) => Ok(response),
+        None => Err(anyhow!("Request timeout waiting for response")),
+    }
 }
 
 /// Optimized polling approach: Sleep-based polling with strategic intervals
@@ -411,12 +1366,11 @@ async fn check_for_response(
 /// based on expected response latency distribution
 async fn wait_for_response_event_driven(
     client: &DynamoDbClient,
+    table: &PendingTable,
     request_id: &str,
+    deadline: Instant,
 ) -> Result<HttpResponse> {
-    let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
-        .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
-    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
-    let start = Instant::now();
+    let mut budget = PollReadBudget::default();
 
     // Optimized polling strategy based on expected latency:
     // - Agent processing + WebSocket round-trip: ~50-200ms
@@ -426,25 +1380,32 @@ async fn wait_for_response_event_driven(
 
     // First check after 200ms (covers fast responses)
     tokio::time::sleep(Duration::from_millis(OPTIMIZED_POLL_FIRST_INTERVAL_MS)).await;
-    if let Some(response) = check_for_response(client, &table_name, request_id).await? {
+    if let Some(response) =
+        check_for_response(client, table, request_id, &mut budget, false).await?
+    {
         return Ok(response);
     }
 
     // Second check after additional 300ms (cumulative: 500ms, covers P90+)
     tokio::time::sleep(Duration::from_millis(OPTIMIZED_POLL_SECOND_INTERVAL_MS)).await;
-    if let Some(response) = check_for_response(client, &table_name, request_id).await? {
+    if let Some(response) =
+        check_for_response(client, table, request_id, &mut budget, false).await?
+    {
         return Ok(response);
     }
 
     // Final polling loop with 400ms intervals for edge cases
     loop {
-        if start.elapsed() > timeout {
-            return Err(anyhow!("Request timeout waiting for response"));
+        let interval = Duration::from_millis(OPTIMIZED_POLL_FINAL_INTERVAL_MS);
+        if Instant::now() + interval >= deadline || budget.is_exhausted() {
+            return finish_waiting(client, table, request_id, &mut budget, deadline).await;
         }
 
-        tokio::time::sleep(Duration::from_millis(OPTIMIZED_POLL_FINAL_INTERVAL_MS)).await;
+        tokio::time::sleep(interval).await;
 
-        if let Some(response) = check_for_response(client, &table_name, request_id).await? {
+        if let Some(response) =
+            check_for_response(client, table, request_id, &mut budget, false).await?
+        {
             return Ok(response);
         }
     }
@@ -453,60 +1414,25 @@ async fn wait_for_response_event_driven(
 /// Original polling approach with exponential backoff
 async fn wait_for_response_polling(
     client: &DynamoDbClient,
+    table: &PendingTable,
     request_id: &str,
+    deadline: Instant,
 ) -> Result<HttpResponse> {
-    let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
-        .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
-    let timeout = Duration::from_secs(REQUEST_TIMEOUT_SECS);
-    let start = Instant::now();
+    let mut budget = PollReadBudget::default();
 
     // Start with initial poll interval, increase to max with backoff
     let mut poll_interval = Duration::from_millis(POLL_INITIAL_INTERVAL_MS);
     let max_poll_interval = Duration::from_millis(POLL_MAX_INTERVAL_MS);
 
     loop {
-        if start.elapsed() > timeout {
-            return Err(anyhow!("Request timeout waiting for response"));
+        if Instant::now() >= deadline || budget.is_exhausted() {
+            return finish_waiting(client, table, request_id, &mut budget, deadline).await;
         }
 
-        // Query DynamoDB for response
-        let result = client
-            .get_item()
-            .table_name(&table_name)
-            .key("requestId", AttributeValue::S(request_id.to_string()))
-            .send()
-            .await
-            .context("Failed to get pending request from DynamoDB")?;
-
-        if let Some(item) = result.item {
-            let status = item
-                .get("status")
-                .and_then(|v| v.as_s().ok())
-                .ok_or_else(|| anyhow!("Missing status in DynamoDB item"))?;
-
-            if status == "completed" {
-                // Extract response data
-                let response_data = item
-                    .get("responseData")
-                    .and_then(|v| v.as_s().ok())
-                    .ok_or_else(|| anyhow!("Missing responseData in completed request"))?;
-
-                let response: HttpResponse = serde_json::from_str(response_data)
-                    .context("Failed to parse response data JSON")?;
-
-                // Clean up pending request
-                if let Err(e) = client
-                    .delete_item()
-                    .table_name(&table_name)
-                    .key("requestId", AttributeValue::S(request_id.to_string()))
-                    .send()
-                    .await
-                {
-                    error!("Failed to clean up pending request: {}", e);
-                }
-
-                return Ok(response);
-            }
+        if let Some(response) =
+            check_for_response(client, table, request_id, &mut budget, false).await?
+        {
+            return Ok(response);
         }
 
         tokio::time::sleep(poll_interval).await;
@@ -517,20 +1443,32 @@ async fn wait_for_response_polling(
 }
 
 /// Convert HttpResponse to API Gateway response
+///
+/// API Gateway can't send trailers, so any the local service sent are passed on
+/// as headers.
 pub fn build_api_gateway_response(response: HttpResponse) -> ApiGatewayProxyResponse {
+    use http::HeaderMap;
     use http::header::{HeaderName, HeaderValue};
 
-    let headers = response
-        .headers
-        .iter()
-        .filter_map(|(k, v)| {
-            v.first().and_then(|val| {
-                HeaderName::from_bytes(k.as_bytes())
-                    .ok()
-                    .and_then(|name| HeaderValue::from_str(val).ok().map(|value| (name, value)))
-            })
-        })
-        .collect();
+    // `headers` has the first value of each header, `multi_value_headers` all of
+    // them, so repeated Set-Cookie and Vary headers survive (API Gateway merges
+    // the two)
+    let mut headers = HeaderMap::new();
+    let mut multi_value_headers = HeaderMap::new();
+    for (name, values) in response.headers.iter().chain(&response.trailers) {
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        for value in values
+            .iter()
+            .filter_map(|value| HeaderValue::from_str(value).ok())
+        {
+            if !headers.contains_key(&name) {
+                headers.insert(name.clone(), value.clone());
+            }
+            multi_value_headers.append(name.clone(), value);
+        }
+    }
 
     use aws_lambda_events::encodings::Body;
 
@@ -543,17 +1481,25 @@ pub fn build_api_gateway_response(response: HttpResponse) -> ApiGatewayProxyResp
     ApiGatewayProxyResponse {
         status_code: response.status_code as i64,
         headers,
-        multi_value_headers: Default::default(),
+        multi_value_headers,
         body,
         is_base64_encoded: true,
     }
 }
 
+/// Size of a Base64-encoded body once decoded (estimated: Base64 is ~33% larger)
+pub fn decoded_body_size(encoded: &str) -> usize {
+    (encoded.len() * 3) / 4
+}
+
 /// Update pending request with response data
+///
+/// Returns what the pending request recorded about the public request, for the
+/// tunnel's analytics, unless the request was already answered.
 pub async fn update_pending_request_with_response(
     client: &DynamoDbClient,
     response: &HttpResponse,
-) -> Result<()> {
+) -> Result<Option<PendingContext>> {
     let table_name = std::env::var("PENDING_REQUESTS_TABLE_NAME")
         .context("PENDING_REQUESTS_TABLE_NAME environment variable not set")?;
 
@@ -562,7 +1508,7 @@ pub async fn update_pending_request_with_response(
         serde_json::to_string(response).context("Failed to serialize response to JSON")?;
 
     // Update pending request with response data
-    client
+    let request = client
         .update_item()
         .table_name(&table_name)
         .key("requestId", AttributeValue::S(response.request_id.clone()))
@@ -570,13 +1516,23 @@ pub async fn update_pending_request_with_response(
         .expression_attribute_names("#status", "status")
         .expression_attribute_values(":status", AttributeValue::S("completed".to_string()))
         .expression_attribute_values(":data", AttributeValue::S(response_data))
-        .send()
+        .return_values(ReturnValue::AllOld);
+    let result = retry("UpdateItem", || request.clone().send())
         .await
         .context("Failed to update pending request with response")?;
 
     debug!("Updated pending request: {}", response.request_id);
 
-    Ok(())
+    // Only the first response for a request counts
+    Ok(result
+        .attributes
+        .filter(|item| {
+            item.get("status")
+                .and_then(|v| v.as_s().ok())
+                .is_some_and(|status| status == "pending")
+        })
+        .as_ref()
+        .and_then(PendingContext::from_item))
 }
 
 #[cfg(test)]
@@ -593,7 +1549,7 @@ mod tests {
             ..Default::default()
         };
 
-        let http_request = build_http_request(&request, "req_123".to_string());
+        let http_request = build_http_request(&request, "req_123".to_string(), None);
 
         assert_eq!(http_request.request_id, "req_123");
         assert_eq!(http_request.method, "GET");
@@ -611,7 +1567,7 @@ mod tests {
             ..Default::default()
         };
 
-        let http_request = build_http_request(&request, "req_123".to_string());
+        let http_request = build_http_request(&request, "req_123".to_string(), None);
 
         assert_eq!(http_request.request_id, "req_123");
         assert_eq!(http_request.method, "GET");
@@ -630,12 +1586,68 @@ mod tests {
             ..Default::default()
         };
 
-        let http_request = build_http_request(&request, "req_123".to_string());
+        let http_request = build_http_request(&request, "req_123".to_string(), None);
 
         assert_eq!(http_request.method, "POST");
         assert!(!http_request.body.is_empty());
     }
 
+    #[test]
+    fn test_build_http_request_query_string() {
+        use http::Method;
+
+        let multi: HashMap<String, Vec<String>> = HashMap::from([
+            ("tag".to_string(), vec!["a".to_string(), "b c".to_string()]),
+            ("q".to_string(), vec!["x&y=z".to_string()]),
+            ("name".to_string(), vec!["José".to_string()]),
+        ]);
+        let single: HashMap<String, String> = HashMap::from([
+            ("tag".to_string(), "b c".to_string()),
+            ("q".to_string(), "x&y=z".to_string()),
+        ]);
+        let request = ApiGatewayProxyRequest {
+            http_method: Method::GET,
+            path: Some("/search".to_string()),
+            query_string_parameters: single.into(),
+            multi_value_query_string_parameters: multi.into(),
+            ..Default::default()
+        };
+
+        // Every value of repeated keys, percent-encoded
+        let http_request = build_http_request(&request, "req_123".to_string(), None);
+        assert_eq!(
+            http_request.uri,
+            "/search?name=Jos%C3%A9&q=x%26y%3Dz&tag=a&tag=b%20c"
+        );
+
+        // The client's own query string wins when the event has it
+        let http_request = build_http_request(
+            &request,
+            "req_123".to_string(),
+            Some("tag=a&tag=b+c&q=x%26y"),
+        );
+        assert_eq!(http_request.uri, "/search?tag=a&tag=b+c&q=x%26y");
+
+        // Events with only the single-value map still work
+        let request = ApiGatewayProxyRequest {
+            http_method: Method::GET,
+            path: Some("/".to_string()),
+            query_string_parameters: HashMap::from([("page".to_string(), "2".to_string())]).into(),
+            ..Default::default()
+        };
+        assert_eq!(query_string(&request, Some("")), "page=2");
+    }
+
+    #[test]
+    fn test_remaining_budget_ms() {
+        let budget = remaining_budget_ms(request_deadline());
+        assert!(budget <= REQUEST_TIMEOUT_SECS * 1000 - REQUEST_DEADLINE_MARGIN_MS);
+        assert!(budget > (REQUEST_TIMEOUT_SECS - 1) * 1000 - REQUEST_DEADLINE_MARGIN_MS);
+
+        // An expired deadline leaves nothing for the agent
+        assert_eq!(remaining_budget_ms(Instant::now()), 0);
+    }
+
     #[test]
     fn test_build_api_gateway_response_success() {
         use std::collections::HashMap;
@@ -652,6 +1664,10 @@ mod tests {
             headers,
             body: "eyJ0ZXN0IjoidmFsdWUifQ==".to_string(),
             processing_time_ms: 123,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         let apigw_response = build_api_gateway_response(response);
@@ -673,6 +1689,10 @@ mod tests {
             headers: HashMap::new(),
             body: String::new(),
             processing_time_ms: 0,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         let apigw_response = build_api_gateway_response(response);
@@ -681,6 +1701,95 @@ mod tests {
         assert!(apigw_response.body.is_none());
     }
 
+    #[test]
+    fn test_poll_read_budget_consistency_schedule() {
+        let mut budget = PollReadBudget::new(100.0);
+        let mut schedule = Vec::new();
+        for _ in 0..8 {
+            let consistent = budget.next_read_consistent();
+            schedule.push(consistent);
+            budget.record(None, consistent);
+        }
+
+        // Every POLL_CONSISTENT_READ_EVERY-th read is strongly consistent
+        assert_eq!(
+            schedule,
+            vec![false, false, false, true, false, false, false, true]
+        );
+        assert_eq!(budget.reads(), 8);
+        assert_eq!(budget.consumed_units(), 6.0 * 0.5 + 2.0 * 1.0);
+    }
+
+    #[test]
+    fn test_poll_read_budget_exhaustion() {
+        let mut budget = PollReadBudget::new(2.0);
+        assert!(!budget.is_exhausted());
+
+        budget.record(Some(1.5), true);
+        assert!(!budget.is_exhausted());
+
+        // Reported capacity takes precedence over the estimate
+        budget.record(Some(0.5), false);
+        assert!(budget.is_exhausted());
+    }
+
+    fn post_to_connection_error(err: PostToConnectionError, status: u16) -> anyhow::Error {
+        use aws_sdk_apigatewaymanagement::config::http::HttpResponse as RawResponse;
+        use aws_smithy_types::body::SdkBody;
+
+        let raw = RawResponse::new(status.try_into().unwrap(), SdkBody::empty());
+        anyhow::Error::new(SdkError::service_error(err, raw)).context("send failed")
+    }
+
+    #[test]
+    fn test_delivery_failure_classification() {
+        use aws_sdk_apigatewaymanagement::types::error::{
+            ForbiddenException, GoneException, LimitExceededException,
+        };
+
+        let gone = post_to_connection_error(
+            PostToConnectionError::GoneException(GoneException::builder().build()),
+            410,
+        );
+        assert_eq!(DeliveryFailure::classify(&gone), DeliveryFailure::Gone);
+
+        let throttled = post_to_connection_error(
+            PostToConnectionError::LimitExceededException(
+                LimitExceededException::builder().build(),
+            ),
+            429,
+        );
+        assert_eq!(
+            DeliveryFailure::classify(&throttled),
+            DeliveryFailure::Throttled
+        );
+        assert_eq!(DeliveryFailure::Throttled.error_code().http_status(), 503);
+
+        let forbidden = post_to_connection_error(
+            PostToConnectionError::ForbiddenException(ForbiddenException::builder().build()),
+            403,
+        );
+        assert_eq!(
+            DeliveryFailure::classify(&forbidden),
+            DeliveryFailure::Other
+        );
+        assert_eq!(
+            DeliveryFailure::classify(&anyhow!("network down")),
+            DeliveryFailure::Other
+        );
+        assert_eq!(DeliveryFailure::Gone.error_code().http_status(), 502);
+    }
+
+    #[test]
+    fn test_build_error_response() {
+        let response = build_error_response("req_1", &ErrorCode::RateLimited, "busy");
+        assert_eq!(response.status_code, 503);
+        assert_eq!(
+            http_tunnel_common::decode_body(&response.body).unwrap(),
+            b"busy"
+        );
+    }
+
     // Subdomain extraction tests
     #[test]
     fn test_extract_subdomain_valid() {
@@ -726,6 +1835,7 @@ mod tests {
             "whsxs3svzbxw.tunnel.example.com",
             "/docs/api",
             "tunnel.example.com",
+            RoutingConfig::Both,
         )
         .unwrap();
 
@@ -747,6 +1857,7 @@ mod tests {
             "tunnel.example.com",
             "/whsxs3svzbxw/docs/api",
             "tunnel.example.com",
+            RoutingConfig::Both,
         )
         .unwrap();
 
@@ -769,6 +1880,7 @@ mod tests {
             "whsxs3svzbxw.tunnel.example.com",
             "/docs",
             "tunnel.example.com",
+            RoutingConfig::Both,
         )
         .unwrap();
 
@@ -776,6 +1888,7 @@ mod tests {
             "tunnel.example.com",
             "/whsxs3svzbxw/docs",
             "tunnel.example.com",
+            RoutingConfig::Both,
         )
         .unwrap();
 
@@ -785,4 +1898,64 @@ mod tests {
             path_mode.forwarding_path()
         );
     }
+
+    #[test]
+    fn test_detect_routing_mode_respects_config() {
+        let domain = "tunnel.example.com";
+        let subdomain_host = "whsxs3svzbxw.tunnel.example.com";
+
+        // Path-only deployments treat the host as opaque
+        let mode = detect_routing_mode(
+            subdomain_host,
+            "/abcdefghijkl/docs",
+            domain,
+            RoutingConfig::Path,
+        )
+        .unwrap();
+        assert_eq!(mode.tunnel_id(), "abcdefghijkl");
+        assert!(detect_routing_mode(subdomain_host, "/", domain, RoutingConfig::Path).is_err());
+
+        // Subdomain-only deployments never read the tunnel ID from the path
+        let mode =
+            detect_routing_mode(subdomain_host, "/docs", domain, RoutingConfig::Subdomain).unwrap();
+        assert_eq!(mode.tunnel_id(), "whsxs3svzbxw");
+        assert!(
+            detect_routing_mode(
+                domain,
+                "/whsxs3svzbxw/docs",
+                domain,
+                RoutingConfig::Subdomain
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_routing_config_parse() {
+        assert_eq!(RoutingConfig::parse("path"), Some(RoutingConfig::Path));
+        assert_eq!(
+            RoutingConfig::parse(" Subdomain "),
+            Some(RoutingConfig::Subdomain)
+        );
+        assert_eq!(RoutingConfig::parse("both"), Some(RoutingConfig::Both));
+        assert_eq!(RoutingConfig::parse("host"), None);
+    }
+
+    #[test]
+    fn test_tunnel_urls_for_routing_config() {
+        let urls = tunnel_urls_for("abc123def456", "t.example.com", RoutingConfig::Both);
+        assert_eq!(urls.public_url, "https://abc123def456.t.example.com");
+        assert_eq!(
+            urls.path_based_url.as_deref(),
+            Some("https://t.example.com/abc123def456")
+        );
+
+        let urls = tunnel_urls_for("abc123def456", "t.example.com", RoutingConfig::Path);
+        assert_eq!(urls.public_url, "https://t.example.com/abc123def456");
+        assert!(urls.subdomain_url.is_none());
+
+        let urls = tunnel_urls_for("abc123def456", "t.example.com", RoutingConfig::Subdomain);
+        assert_eq!(urls.public_url, "https://abc123def456.t.example.com");
+        assert!(urls.path_based_url.is_none());
+    }
 }
@@ -5,19 +5,26 @@
 //! - WebSocket $connect - handle_connect
 //! - WebSocket $disconnect - handle_disconnect
 //! - WebSocket $default (messages from agent) - handle_response
-//! - HTTP API requests (forwarding) - handle_forwarding
+//! - HTTP API requests (forwarding) - handle_forwarding, the only events whose
+//!   response may be streamed
+//! - Public WebSocket API (passthrough clients) - handle_ws_connect/disconnect/message
 
-use aws_sdk_apigatewaymanagement::Client as ApiGatewayManagementClient;
-use aws_sdk_dynamodb::Client as DynamoDbClient;
-use aws_sdk_eventbridge::Client as EventBridgeClient;
-use http_tunnel_handler::SharedClients;
 use http_tunnel_handler::handlers::{
-    handle_cleanup, handle_connect, handle_disconnect, handle_forwarding, handle_response,
-    handle_stream,
+    ForwardingResponse, handle_cleanup, handle_connect, handle_disconnect, handle_forwarding,
+    handle_handoff_sweep, handle_response, handle_stream, handle_ws_connect, handle_ws_disconnect,
+    handle_ws_message,
 };
-use lambda_runtime::{Error, LambdaEvent, run, service_fn};
+use http_tunnel_handler::http_event::PublicRequest;
+use http_tunnel_handler::{SharedClients, is_prewarm_enabled};
+use lambda_runtime::streaming::Body;
+use lambda_runtime::{Error, FunctionResponse, LambdaEvent, run, service_fn};
 use serde_json::Value;
-use tracing::info;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use tracing::{info, warn};
+
+/// Set once the first invocation of this container has been handled
+static WARM: AtomicBool = AtomicBool::new(false);
 
 /// Event types that the unified handler can process
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -25,8 +32,12 @@ enum EventType {
     WebSocketConnect,
     WebSocketDisconnect,
     WebSocketDefault,
+    PublicWebSocketConnect,
+    PublicWebSocketDisconnect,
+    PublicWebSocketDefault,
     HttpApi,
     ScheduledCleanup,
+    ScheduledHandoff,
     DynamoDbStream,
 }
 
@@ -41,6 +52,11 @@ fn detect_event_type(value: &Value) -> Result<EventType, Error> {
         return Ok(EventType::DynamoDbStream);
     }
 
+    // Check for the EventBridge handoff schedule, which sends a constant input
+    if value.get("task").and_then(|v| v.as_str()) == Some("handoff") {
+        return Ok(EventType::ScheduledHandoff);
+    }
+
     // Check for EventBridge scheduled event (cleanup)
     if value.get("source") == Some(&Value::String("aws.events".to_string()))
         && value.get("detail-type").is_some()
@@ -57,6 +73,14 @@ fn detect_event_type(value: &Value) -> Result<EventType, Error> {
 
         // Check for WebSocket events (they have requestContext.routeKey without http)
         if let Some(route_key) = request_context.get("routeKey").and_then(|v| v.as_str()) {
+            if is_public_websocket_api(request_context) {
+                return match route_key {
+                    "$connect" => Ok(EventType::PublicWebSocketConnect),
+                    "$disconnect" => Ok(EventType::PublicWebSocketDisconnect),
+                    "$default" => Ok(EventType::PublicWebSocketDefault),
+                    _ => Err(format!("Unknown WebSocket route: {}", route_key).into()),
+                };
+            }
             return match route_key {
                 "$connect" => Ok(EventType::WebSocketConnect),
                 "$disconnect" => Ok(EventType::WebSocketDisconnect),
@@ -74,16 +98,25 @@ fn detect_event_type(value: &Value) -> Result<EventType, Error> {
     Err("Unable to determine event type from payload".into())
 }
 
+/// Whether a WebSocket event came from the public passthrough API rather than the agent API
+fn is_public_websocket_api(request_context: &Value) -> bool {
+    let Ok(public_api_id) = std::env::var("PUBLIC_WEBSOCKET_API_ID") else {
+        return false;
+    };
+    request_context.get("apiId").and_then(|v| v.as_str()) == Some(public_api_id.as_str())
+}
+
 /// Unified handler that routes to specific handlers based on event type
 async fn function_handler(
     event: LambdaEvent<Value>,
     clients: &SharedClients,
-) -> Result<Value, Error> {
+) -> Result<FunctionResponse<Value, Body>, Error> {
     let event_type = detect_event_type(&event.payload)?;
+    let cold_start = !WARM.swap(true, Ordering::Relaxed);
 
-    info!("Processing event type: {:?}", event_type);
+    info!(cold_start, "Processing event type: {:?}", event_type);
 
-    match event_type {
+    let result = match event_type {
         EventType::WebSocketConnect => {
             // Parse as WebSocket event and handle connect
             let ws_event = serde_json::from_value(event.payload)
@@ -117,18 +150,53 @@ async fn function_handler(
             serde_json::to_value(response)
                 .map_err(|e| format!("Failed to serialize response: {}", e).into())
         }
+        EventType::PublicWebSocketConnect => {
+            let ws_event = serde_json::from_value(event.payload)
+                .map_err(|e| format!("Failed to parse WebSocket connect event: {}", e))?;
+            let lambda_event = LambdaEvent::new(ws_event, event.context);
+            let response = handle_ws_connect(lambda_event, clients).await?;
+            serde_json::to_value(response)
+                .map_err(|e| format!("Failed to serialize response: {}", e).into())
+        }
+        EventType::PublicWebSocketDisconnect => {
+            let ws_event = serde_json::from_value(event.payload)
+                .map_err(|e| format!("Failed to parse WebSocket disconnect event: {}", e))?;
+            let lambda_event = LambdaEvent::new(ws_event, event.context);
+            let response = handle_ws_disconnect(lambda_event, clients).await?;
+            serde_json::to_value(response)
+                .map_err(|e| format!("Failed to serialize response: {}", e).into())
+        }
+        EventType::PublicWebSocketDefault => {
+            let ws_event = serde_json::from_value(event.payload)
+                .map_err(|e| format!("Failed to parse WebSocket default event: {}", e))?;
+            let lambda_event = LambdaEvent::new(ws_event, event.context);
+            let response = handle_ws_message(lambda_event, clients).await?;
+            serde_json::to_value(response)
+                .map_err(|e| format!("Failed to serialize response: {}", e).into())
+        }
         EventType::HttpApi => {
-            // Parse as HTTP API event and handle forwarding
-            let http_event = serde_json::from_value(event.payload)
+            // Parse as HTTP API event (payload format 1.0 or 2.0) and handle forwarding
+            let http_event = PublicRequest::from_payload(event.payload)
                 .map_err(|e| format!("Failed to parse HTTP API event: {}", e))?;
+            // The response goes back in the request's format
+            let format = http_event.format;
             let lambda_event = LambdaEvent::new(http_event, event.context);
-            let response = handle_forwarding(lambda_event, clients).await?;
-            serde_json::to_value(response)
-                .map_err(|e| format!("Failed to serialize response: {}", e).into())
+            match handle_forwarding(lambda_event, clients).await? {
+                ForwardingResponse::Buffered(response) => format
+                    .response(response)
+                    .map_err(|e| format!("Failed to serialize response: {}", e).into()),
+                ForwardingResponse::Streaming(response) => {
+                    return Ok(FunctionResponse::StreamingResponse(response));
+                }
+            }
         }
         EventType::ScheduledCleanup => {
             // Handle scheduled cleanup from EventBridge
-            handle_cleanup(event.payload, &clients.dynamodb).await
+            handle_cleanup(event.payload, clients).await
+        }
+        EventType::ScheduledHandoff => {
+            // Ask agents nearing the connection lifetime to reconnect
+            handle_handoff_sweep(event.payload, clients).await
         }
         EventType::DynamoDbStream => {
             // Parse as DynamoDB Stream event and handle
@@ -138,7 +206,8 @@ async fn function_handler(
             handle_stream(lambda_event, clients).await?;
             Ok(json!({"statusCode": 200}))
         }
-    }
+    };
+    result.map(FunctionResponse::BufferedResponse)
 }
 
 use serde_json::json;
@@ -152,40 +221,23 @@ async fn main() -> Result<(), Error> {
         .without_time()
         .init();
 
+    let init_start = Instant::now();
     info!("Unified Lambda Handler starting");
 
-    // Initialize AWS SDK
+    // Initialize AWS SDK (API Gateway Management client is created on first use)
     let config = aws_config::load_from_env().await;
-    let dynamodb = DynamoDbClient::new(&config);
-
-    // API Gateway Management API client (optional, only for forwarding handler)
-    let apigw_management = if let Ok(websocket_endpoint) = std::env::var("WEBSOCKET_API_ENDPOINT") {
-        // Convert wss:// to https:// for API Gateway Management API
-        let management_endpoint = websocket_endpoint.replace("wss://", "https://");
-
-        info!(
-            "Initializing API Gateway Management client with endpoint: {}",
-            management_endpoint
-        );
-
-        let apigw_management_config = aws_sdk_apigatewaymanagement::config::Builder::from(&config)
-            .endpoint_url(management_endpoint)
-            .build();
-        Some(ApiGatewayManagementClient::from_conf(
-            apigw_management_config,
-        ))
-    } else {
-        info!("WEBSOCKET_API_ENDPOINT not set, API Gateway Management client not initialized");
-        None
-    };
+    let clients = SharedClients::new(config)?;
 
-    let eventbridge = EventBridgeClient::new(&config);
+    // Optionally pay for DynamoDB connection setup during init instead of on a public request
+    let prewarm = is_prewarm_enabled();
+    if prewarm && let Err(e) = clients.prewarm_dynamodb().await {
+        warn!("DynamoDB pre-warm failed: {:#}", e);
+    }
 
-    let clients = SharedClients {
-        dynamodb,
-        apigw_management,
-        eventbridge,
-    };
+    info!(
+        init_duration_ms = init_start.elapsed().as_millis() as u64,
+        prewarm, "Handler initialization complete"
+    );
 
     // Run the Lambda runtime
     run(service_fn(|event: LambdaEvent<Value>| {
@@ -0,0 +1,222 @@
+//! Per-request CloudWatch metrics in Embedded Metric Format (EMF)
+//!
+//! Each record is printed to stdout as one JSON log line; CloudWatch Logs
+//! extracts the metrics declared under `_aws` without any log scraping or
+//! `PutMetricData` calls. Every metric is published twice: once per dimension
+//! value (e.g. per status class) and once aggregated, so alarms can watch the
+//! totals directly. Tunnel and request IDs travel as plain properties, which
+//! keeps them searchable in Logs Insights without creating a metric per tunnel.
+//!
+//! Disabled unless `METRICS_NAMESPACE` is set.
+
+use http_tunnel_common::models::status_class;
+use http_tunnel_common::utils::current_timestamp_millis;
+use serde_json::{Map, Value, json};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Namespace metrics are published under, if metrics are enabled
+pub fn metrics_namespace() -> Option<&'static str> {
+    static NAMESPACE: OnceLock<Option<String>> = OnceLock::new();
+    NAMESPACE
+        .get_or_init(|| {
+            std::env::var("METRICS_NAMESPACE")
+                .ok()
+                .filter(|namespace| !namespace.is_empty())
+        })
+        .as_deref()
+}
+
+/// CloudWatch metric units used by the handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    Count,
+    Milliseconds,
+    Bytes,
+}
+
+impl Unit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Unit::Count => "Count",
+            Unit::Milliseconds => "Milliseconds",
+            Unit::Bytes => "Bytes",
+        }
+    }
+}
+
+/// One EMF log record: dimensions, metric values and extra properties
+#[derive(Debug, Default)]
+pub struct MetricLog {
+    dimensions: Vec<(&'static str, String)>,
+    metrics: Vec<(&'static str, f64, Unit)>,
+    properties: Vec<(&'static str, Value)>,
+}
+
+impl MetricLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a dimension the metrics are also broken down by
+    pub fn dimension(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.dimensions.push((name, value.into()));
+        self
+    }
+
+    /// Add a metric value
+    pub fn metric(mut self, name: &'static str, value: f64, unit: Unit) -> Self {
+        self.metrics.push((name, value, unit));
+        self
+    }
+
+    /// Add a property that is logged but not turned into a metric
+    pub fn property(mut self, name: &'static str, value: impl Into<Value>) -> Self {
+        self.properties.push((name, value.into()));
+        self
+    }
+
+    /// Serialize the record as an EMF document
+    pub fn to_emf(&self, namespace: &str, timestamp_ms: u64) -> Value {
+        let dimension_names: Vec<&str> = self.dimensions.iter().map(|(name, _)| *name).collect();
+        let metric_definitions: Vec<Value> = self
+            .metrics
+            .iter()
+            .map(|(name, _, unit)| json!({ "Name": name, "Unit": unit.as_str() }))
+            .collect();
+
+        let dimension_sets = if dimension_names.is_empty() {
+            json!([[]])
+        } else {
+            json!([dimension_names, []])
+        };
+
+        let mut root = Map::new();
+        root.insert(
+            "_aws".to_string(),
+            json!({
+                "Timestamp": timestamp_ms,
+                "CloudWatchMetrics": [{
+                    "Namespace": namespace,
+                    "Dimensions": dimension_sets,
+                    "Metrics": metric_definitions,
+                }],
+            }),
+        );
+        for (name, value) in &self.properties {
+            root.insert(name.to_string(), value.clone());
+        }
+        for (name, value) in &self.dimensions {
+            root.insert(name.to_string(), json!(value));
+        }
+        for (name, value, _) in &self.metrics {
+            root.insert(name.to_string(), json!(value));
+        }
+        Value::Object(root)
+    }
+
+    /// Print the record for CloudWatch to pick up (no-op when metrics are disabled)
+    pub fn emit(&self) {
+        if let Some(namespace) = metrics_namespace() {
+            println!("{}", self.to_emf(namespace, current_timestamp_millis()));
+        }
+    }
+}
+
+/// Outcome of one public request forwarded through a tunnel
+#[derive(Debug, Default)]
+pub struct RequestMetrics<'a> {
+    pub tunnel_id: &'a str,
+    pub request_id: &'a str,
+    pub status_code: u16,
+    /// Time spent waiting for the agent's response
+    pub latency: Duration,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    /// Whether the response body was rewritten for path-based routing
+    pub rewrite_applied: bool,
+    /// Whether the agent never answered in time
+    pub timed_out: bool,
+}
+
+impl RequestMetrics<'_> {
+    pub fn log(&self) -> MetricLog {
+        MetricLog::new()
+            .dimension("StatusClass", status_class(self.status_code))
+            .metric("Requests", 1.0, Unit::Count)
+            .metric(
+                "Latency",
+                self.latency.as_secs_f64() * 1000.0,
+                Unit::Milliseconds,
+            )
+            .metric("RequestBytes", self.request_bytes as f64, Unit::Bytes)
+            .metric("ResponseBytes", self.response_bytes as f64, Unit::Bytes)
+            .metric(
+                "RewriteApplied",
+                f64::from(u8::from(self.rewrite_applied)),
+                Unit::Count,
+            )
+            .metric("Timeouts", f64::from(u8::from(self.timed_out)), Unit::Count)
+            .property("TunnelId", self.tunnel_id)
+            .property("RequestId", self.request_id)
+            .property("StatusCode", self.status_code)
+    }
+
+    pub fn emit(&self) {
+        self.log().emit();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_metrics_emf() {
+        let metrics = RequestMetrics {
+            tunnel_id: "abc123def456",
+            request_id: "req_1",
+            status_code: 503,
+            latency: Duration::from_millis(250),
+            request_bytes: 10,
+            response_bytes: 2048,
+            rewrite_applied: true,
+            timed_out: false,
+        };
+        let emf = metrics.log().to_emf("HttpTunnel/dev", 1_700_000_000_000);
+
+        let declaration = &emf["_aws"]["CloudWatchMetrics"][0];
+        assert_eq!(emf["_aws"]["Timestamp"], 1_700_000_000_000u64);
+        assert_eq!(declaration["Namespace"], "HttpTunnel/dev");
+        assert_eq!(declaration["Dimensions"], json!([["StatusClass"], []]));
+        assert_eq!(
+            declaration["Metrics"][1],
+            json!({ "Name": "Latency", "Unit": "Milliseconds" })
+        );
+
+        // Every declared metric and dimension has a value at the root
+        for metric in declaration["Metrics"].as_array().unwrap() {
+            assert!(emf[metric["Name"].as_str().unwrap()].is_number());
+        }
+        assert_eq!(emf["StatusClass"], "5xx");
+        assert_eq!(emf["Requests"], 1.0);
+        assert_eq!(emf["Latency"], 250.0);
+        assert_eq!(emf["ResponseBytes"], 2048.0);
+        assert_eq!(emf["RewriteApplied"], 1.0);
+        assert_eq!(emf["Timeouts"], 0.0);
+        assert_eq!(emf["TunnelId"], "abc123def456");
+        assert_eq!(emf["StatusCode"], 503);
+    }
+
+    #[test]
+    fn test_metric_log_without_dimensions() {
+        let emf = MetricLog::new()
+            .metric("AgentErrors", 1.0, Unit::Count)
+            .to_emf("HttpTunnel", 0);
+        assert_eq!(
+            emf["_aws"]["CloudWatchMetrics"][0]["Dimensions"],
+            json!([[]])
+        );
+        assert_eq!(emf["AgentErrors"], 1.0);
+    }
+}
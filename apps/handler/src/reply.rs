@@ -0,0 +1,231 @@
+//! Push-based response delivery through per-container reply queues
+//!
+//! A forwarding invocation that is waiting for the agent can't receive
+//! EventBridge events, so instead of polling DynamoDB each handler container
+//! creates its own SQS queue on first use and records it on every pending request
+//! it saves (`replyQueue`). When the response lands, the DynamoDB stream handler
+//! sends the request ID to that queue; the waiter blocks on a long-poll receive
+//! and reads the response from DynamoDB exactly once.
+//!
+//! Enabled when `REPLY_QUEUE_PREFIX` is set. Containers handle one invocation at
+//! a time, so a queue only ever holds notifications for its own requests (plus
+//! stale ones for requests that already timed out, which are discarded). The
+//! cleanup job deletes queues older than [`REPLY_QUEUE_MAX_AGE_SECS`]; a container
+//! whose queue disappears falls back to polling and creates a new one.
+
+use anyhow::{Context, Result};
+use aws_sdk_sqs::Client as SqsClient;
+use aws_sdk_sqs::types::QueueAttributeName;
+use http_tunnel_common::constants::{REPLY_QUEUE_MAX_AGE_SECS, REPLY_QUEUE_MAX_WAIT_SECS};
+use http_tunnel_common::protocol::HttpResponse;
+use http_tunnel_common::utils::{current_timestamp_secs, generate_subdomain};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{PollReadBudget, SharedClients, check_for_response, finish_waiting};
+
+/// Prefix for reply queue names (None disables push delivery)
+pub fn reply_queue_prefix() -> Option<String> {
+    std::env::var("REPLY_QUEUE_PREFIX")
+        .ok()
+        .filter(|prefix| !prefix.is_empty())
+}
+
+/// This container's reply queue, created lazily
+#[derive(Debug, Default)]
+pub struct ReplyQueue {
+    url: Mutex<Option<String>>,
+}
+
+impl ReplyQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// URL of the reply queue, creating it on first use
+    ///
+    /// Returns `None` when push delivery is disabled or the queue can't be
+    /// created, in which case the caller polls as before.
+    pub async fn url(&self, sqs: &SqsClient) -> Option<String> {
+        let prefix = reply_queue_prefix()?;
+        let mut url = self.url.lock().await;
+        if url.is_none() {
+            match create_reply_queue(sqs, &prefix).await {
+                Ok(created) => *url = Some(created),
+                Err(e) => warn!("Failed to create reply queue: {:#}", e),
+            }
+        }
+        url.clone()
+    }
+
+    /// Forget a queue that has stopped working so the next request creates a new one
+    async fn reset(&self, broken: &str) {
+        let mut url = self.url.lock().await;
+        if url.as_deref() == Some(broken) {
+            *url = None;
+        }
+    }
+}
+
+async fn create_reply_queue(sqs: &SqsClient, prefix: &str) -> Result<String> {
+    let name = format!("{}{}", prefix, generate_subdomain());
+    let output = sqs
+        .create_queue()
+        .queue_name(&name)
+        // Notifications are only useful while the request is in flight
+        .attributes(QueueAttributeName::MessageRetentionPeriod, "60")
+        .send()
+        .await
+        .with_context(|| format!("Failed to create SQS queue {}", name))?;
+
+    let url = output
+        .queue_url
+        .context("CreateQueue returned no queue URL")?;
+    info!("Created reply queue {}", url);
+    Ok(url)
+}
+
+/// Tell the waiting invocation that its response is ready
+pub async fn notify_reply(sqs: &SqsClient, queue_url: &str, request_id: &str) -> Result<()> {
+    sqs.send_message()
+        .queue_url(queue_url)
+        .message_body(request_id)
+        .send()
+        .await
+        .with_context(|| format!("Failed to notify reply queue {}", queue_url))?;
+    Ok(())
+}
+
+/// Wait for the response to `request_id` by blocking on the reply queue
+///
+/// Falls back to polling if the queue stops working mid-wait.
+pub async fn wait_for_reply(
+    clients: &SharedClients,
+    queue_url: &str,
+    request_id: &str,
+    deadline: Instant,
+) -> Result<HttpResponse> {
+    let table = clients.store.pending_table()?;
+    let mut budget = PollReadBudget::default();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let wait_secs = remaining.as_secs().min(REPLY_QUEUE_MAX_WAIT_SECS);
+        if wait_secs == 0 {
+            // Less than a second left: too short for a long poll
+            tokio::time::sleep(remaining).await;
+            break;
+        }
+
+        let output = match clients
+            .sqs
+            .receive_message()
+            .queue_url(queue_url)
+            .wait_time_seconds(wait_secs as i32)
+            .max_number_of_messages(10)
+            .send()
+            .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                warn!(
+                    "Reply queue {} failed, falling back to polling: {}",
+                    queue_url, e
+                );
+                clients.reply_queue.reset(queue_url).await;
+                return clients
+                    .store
+                    .wait_for_response(request_id, deadline, Duration::ZERO)
+                    .await;
+            }
+        };
+
+        let mut ready = false;
+        for message in output.messages() {
+            if message.body() == Some(request_id) {
+                ready = true;
+            } else {
+                debug!("Discarding stale reply notification {:?}", message.body());
+            }
+            if let Some(receipt_handle) = message.receipt_handle()
+                && let Err(e) = clients
+                    .sqs
+                    .delete_message()
+                    .queue_url(queue_url)
+                    .receipt_handle(receipt_handle)
+                    .send()
+                    .await
+            {
+                warn!("Failed to delete reply notification: {}", e);
+            }
+        }
+
+        if ready
+            && let Some(response) =
+                check_for_response(&clients.dynamodb, &table, request_id, &mut budget, true).await?
+        {
+            return Ok(response);
+        }
+    }
+
+    finish_waiting(&clients.dynamodb, &table, request_id, &mut budget, deadline).await
+}
+
+/// Delete reply queues older than [`REPLY_QUEUE_MAX_AGE_SECS`]
+///
+/// Queues belong to handler containers that may be long gone; age is the only
+/// signal available, and a live container recreates its queue if needed.
+pub async fn delete_stale_reply_queues(sqs: &SqsClient, prefix: &str) -> Result<u32> {
+    let now = current_timestamp_secs();
+    let mut deleted = 0;
+
+    let mut pages = sqs
+        .list_queues()
+        .queue_name_prefix(prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.next().await {
+        let page = page.context("Failed to list reply queues")?;
+        for url in page.queue_urls() {
+            let attributes = sqs
+                .get_queue_attributes()
+                .queue_url(url)
+                .attribute_names(QueueAttributeName::CreatedTimestamp)
+                .send()
+                .await
+                .with_context(|| format!("Failed to read attributes of {}", url))?;
+            let created_at = attributes
+                .attributes()
+                .and_then(|attributes| attributes.get(&QueueAttributeName::CreatedTimestamp))
+                .and_then(|created_at| created_at.parse::<i64>().ok());
+
+            if !is_stale(created_at, now) {
+                continue;
+            }
+            match sqs.delete_queue().queue_url(url).send().await {
+                Ok(_) => deleted += 1,
+                Err(e) => warn!("Failed to delete reply queue {}: {}", url, e),
+            }
+        }
+    }
+
+    Ok(deleted)
+}
+
+fn is_stale(created_at: Option<i64>, now: i64) -> bool {
+    created_at.is_some_and(|created_at| now - created_at > REPLY_QUEUE_MAX_AGE_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_stale() {
+        let now = 1_700_000_000;
+        assert!(is_stale(Some(now - REPLY_QUEUE_MAX_AGE_SECS - 1), now));
+        assert!(!is_stale(Some(now - 60), now));
+        assert!(!is_stale(None, now));
+    }
+}
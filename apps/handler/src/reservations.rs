@@ -0,0 +1,169 @@
+//! Reserved tunnel IDs
+//!
+//! An agent started with `--tunnel-id` asks for a specific ID in its Ready
+//! message instead of the random one assigned at `$connect`, so its public URL
+//! survives reconnects. IDs are tied to the JWT `sub` of the agent that first
+//! reserved them (`--reserve`) in the reservations table, keyed by `tunnelId`.
+//! A reservation is only granted to its owner; without authentication there is
+//! no owner to tie it to, so reservations are refused. An ID served by another
+//! user's live connection can be neither reserved nor claimed, so a random ID
+//! can't be taken over by reserving it.
+//!
+//! Reservations don't expire; they live as long as the table item.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::utils::current_timestamp_secs;
+use tracing::info;
+
+/// Whether this deployment has a reservations table
+pub fn is_enabled() -> bool {
+    std::env::var("RESERVATIONS_TABLE_NAME").is_ok_and(|name| !name.is_empty())
+}
+
+fn reservations_table() -> Result<String> {
+    std::env::var("RESERVATIONS_TABLE_NAME")
+        .context("RESERVATIONS_TABLE_NAME environment variable not set")
+}
+
+/// Outcome of asking for a tunnel ID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Claim {
+    /// The ID belongs to the requester
+    Granted,
+    /// The ID can't be used; the reason is reported to the agent
+    Denied(String),
+}
+
+/// Decide a claim from the ID's current owner, if it is reserved at all
+///
+/// Returns None when the ID is free and `reserve` asks to take it.
+fn decide(tunnel_id: &str, owner: &str, current: Option<&str>, reserve: bool) -> Option<Claim> {
+    match current {
+        Some(current) if current == owner => Some(Claim::Granted),
+        Some(_) => Some(Claim::Denied(format!(
+            "tunnel ID {} is reserved by another user",
+            tunnel_id
+        ))),
+        None if reserve => None,
+        None => Some(Claim::Denied(format!(
+            "tunnel ID {} is not reserved (use --reserve to reserve it)",
+            tunnel_id
+        ))),
+    }
+}
+
+/// Whether any of the owners of a tunnel's live connections isn't `owner`
+pub fn held_by_other<'a>(
+    owners: impl IntoIterator<Item = Option<&'a str>>,
+    owner: Option<&str>,
+) -> bool {
+    owners
+        .into_iter()
+        .any(|current| owner.is_none() || current != owner)
+}
+
+/// Check that `owner` may use `tunnel_id`, reserving it first if `reserve` is set
+pub async fn claim(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+    owner: Option<&str>,
+    reserve: bool,
+) -> Result<Claim> {
+    let Some(owner) = owner else {
+        return Ok(Claim::Denied(
+            "reserved tunnel IDs require authentication".to_string(),
+        ));
+    };
+    let table_name = reservations_table()?;
+
+    let result = client
+        .get_item()
+        .table_name(&table_name)
+        .key("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .consistent_read(true)
+        .send()
+        .await
+        .context("Failed to read tunnel ID reservation")?;
+    let current = result
+        .item
+        .as_ref()
+        .and_then(|item| item.get("owner"))
+        .and_then(|v| v.as_s().ok());
+
+    if let Some(claim) = decide(tunnel_id, owner, current.map(String::as_str), reserve) {
+        return Ok(claim);
+    }
+
+    // Another agent may reserve the same ID concurrently; only one put succeeds
+    let result = client
+        .put_item()
+        .table_name(&table_name)
+        .item("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .item("owner", AttributeValue::S(owner.to_string()))
+        .item(
+            "createdAt",
+            AttributeValue::N(current_timestamp_secs().to_string()),
+        )
+        .condition_expression("attribute_not_exists(tunnelId)")
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => {
+            info!("Reserved tunnel ID {} for {}", tunnel_id, owner);
+            Ok(Claim::Granted)
+        }
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(|e| e.is_conditional_check_failed_exception()) =>
+        {
+            Ok(Claim::Denied(format!(
+                "tunnel ID {} is reserved by another user",
+                tunnel_id
+            )))
+        }
+        Err(e) => Err(e).context("Failed to reserve tunnel ID"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide_claim() {
+        let id = "abc123def456";
+        assert_eq!(
+            decide(id, "alice", Some("alice"), false),
+            Some(Claim::Granted)
+        );
+        assert_eq!(
+            decide(id, "alice", Some("alice"), true),
+            Some(Claim::Granted)
+        );
+        assert!(matches!(
+            decide(id, "alice", Some("bob"), true),
+            Some(Claim::Denied(reason)) if reason.contains("another user")
+        ));
+        assert!(matches!(
+            decide(id, "alice", None, false),
+            Some(Claim::Denied(reason)) if reason.contains("--reserve")
+        ));
+        assert_eq!(decide(id, "alice", None, true), None);
+    }
+
+    #[test]
+    fn test_held_by_other() {
+        assert!(!held_by_other([], Some("alice")));
+        assert!(!held_by_other(
+            [Some("alice"), Some("alice")],
+            Some("alice")
+        ));
+        assert!(held_by_other([Some("alice"), Some("bob")], Some("alice")));
+        // Unauthenticated connections belong to nobody in particular
+        assert!(held_by_other([None], Some("alice")));
+        assert!(held_by_other([None], None));
+    }
+}
@@ -0,0 +1,255 @@
+//! Per-tunnel traffic statistics
+//!
+//! Every forwarded request increments counters in a per-minute bucket item of the
+//! tunnel stats table (status class plus latency histogram bucket). Rolling-window
+//! statistics are produced by summing the buckets inside the window, so reads stay
+//! cheap and no per-request rows are kept. Buckets expire via DynamoDB TTL.
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::types::AttributeValue;
+use http_tunnel_common::constants::{STATS_BUCKET_SECS, STATS_RETENTION_SECS};
+use http_tunnel_common::models::{LatencyHistogram, TunnelStats, status_class};
+use http_tunnel_common::utils::{calculate_ttl, current_timestamp_secs};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tracing::debug;
+
+/// Attribute name prefix for status class counters ("s2xx", "s5xx", ...)
+const STATUS_ATTR_PREFIX: &str = "s";
+
+/// Attribute name prefix for latency histogram bucket counters ("l0", "l1", ...)
+const LATENCY_ATTR_PREFIX: &str = "l";
+
+/// Check if tunnel statistics are enabled (stats table configured)
+pub fn is_stats_enabled() -> bool {
+    std::env::var("TUNNEL_STATS_TABLE_NAME").is_ok()
+}
+
+/// Start of the aggregation bucket containing `timestamp` (Unix epoch seconds)
+pub fn bucket_start(timestamp: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(STATS_BUCKET_SECS)
+}
+
+/// Record a completed request against its tunnel's current bucket
+///
+/// No-op when the stats table isn't configured.
+pub async fn record_request(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+    status_code: u16,
+    latency: Duration,
+) -> Result<()> {
+    let Ok(table_name) = std::env::var("TUNNEL_STATS_TABLE_NAME") else {
+        return Ok(());
+    };
+
+    let bucket = bucket_start(current_timestamp_secs());
+    let latency_ms = latency.as_millis() as u64;
+
+    let update = client
+        .update_item()
+        .table_name(&table_name)
+        .key("tunnelId", AttributeValue::S(tunnel_id.to_string()))
+        .key("bucket", AttributeValue::N(bucket.to_string()));
+    count_request(update, None, status_code, latency_ms)
+        .send()
+        .await
+        .context("Failed to record tunnel stats")?;
+
+    debug!(
+        "Recorded stats for tunnel {}: status {} in {}ms",
+        tunnel_id, status_code, latency_ms
+    );
+
+    Ok(())
+}
+
+/// Add a request to the bucket item `update` targets
+///
+/// `bucket` is also stored as an attribute when it isn't part of the key.
+pub(crate) fn count_request(
+    update: UpdateItemFluentBuilder,
+    bucket: Option<i64>,
+    status_code: u16,
+    latency_ms: u64,
+) -> UpdateItemFluentBuilder {
+    let mut expression =
+        "ADD #status :one, #latency :one SET #ttl = if_not_exists(#ttl, :ttl)".to_string();
+    let mut update = update;
+    if let Some(bucket) = bucket {
+        expression.push_str(", #bucket = :bucket");
+        update = update
+            .expression_attribute_names("#bucket", "bucket")
+            .expression_attribute_values(":bucket", AttributeValue::N(bucket.to_string()));
+    }
+
+    update
+        .update_expression(expression)
+        .expression_attribute_names(
+            "#status",
+            format!("{}{}", STATUS_ATTR_PREFIX, status_class(status_code)),
+        )
+        .expression_attribute_names(
+            "#latency",
+            format!(
+                "{}{}",
+                LATENCY_ATTR_PREFIX,
+                LatencyHistogram::bucket_index(latency_ms)
+            ),
+        )
+        .expression_attribute_names("#ttl", "ttl")
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .expression_attribute_values(
+            ":ttl",
+            AttributeValue::N(calculate_ttl(STATS_RETENTION_SECS).to_string()),
+        )
+}
+
+/// Load rolling-window statistics for a tunnel, one entry per window (in seconds)
+pub async fn load_tunnel_stats(
+    client: &DynamoDbClient,
+    tunnel_id: &str,
+    windows_secs: &[i64],
+) -> Result<Vec<TunnelStats>> {
+    let table_name = std::env::var("TUNNEL_STATS_TABLE_NAME")
+        .context("TUNNEL_STATS_TABLE_NAME environment variable not set")?;
+
+    let now = current_timestamp_secs();
+    let longest = windows_secs.iter().copied().max().unwrap_or(0);
+
+    let mut items = Vec::new();
+    let mut exclusive_start_key = None;
+    loop {
+        let result = client
+            .query()
+            .table_name(&table_name)
+            .key_condition_expression("tunnelId = :tunnel_id AND #bucket >= :since")
+            .expression_attribute_names("#bucket", "bucket")
+            .expression_attribute_values(":tunnel_id", AttributeValue::S(tunnel_id.to_string()))
+            .expression_attribute_values(
+                ":since",
+                AttributeValue::N(bucket_start(now - longest).to_string()),
+            )
+            .set_exclusive_start_key(exclusive_start_key)
+            .send()
+            .await
+            .context("Failed to query tunnel stats")?;
+
+        items.extend(result.items.unwrap_or_default());
+        exclusive_start_key = result.last_evaluated_key;
+        if exclusive_start_key.is_none() {
+            break;
+        }
+    }
+
+    Ok(windows_secs
+        .iter()
+        .map(|window| aggregate_window(tunnel_id, &items, now, *window))
+        .collect())
+}
+
+/// Sum the bucket items that fall inside `window_secs` before `now`
+pub(crate) fn aggregate_window(
+    tunnel_id: &str,
+    items: &[HashMap<String, AttributeValue>],
+    now: i64,
+    window_secs: i64,
+) -> TunnelStats {
+    let since = bucket_start(now - window_secs);
+    let mut status_classes = BTreeMap::new();
+    let mut latency = LatencyHistogram::new();
+
+    for item in items {
+        let bucket = number_attr(item.get("bucket")).unwrap_or(i64::MIN);
+        if bucket < since {
+            continue;
+        }
+
+        for (name, value) in item {
+            let Some(count) = number_attr(Some(value)).map(|n| n.max(0) as u64) else {
+                continue;
+            };
+
+            if let Some(class) = name.strip_prefix(STATUS_ATTR_PREFIX) {
+                *status_classes.entry(class.to_string()).or_insert(0) += count;
+            } else if let Some(index) = name
+                .strip_prefix(LATENCY_ATTR_PREFIX)
+                .and_then(|index| index.parse::<usize>().ok())
+            {
+                latency.add(index, count);
+            }
+        }
+    }
+
+    TunnelStats::from_counts(tunnel_id.to_string(), window_secs, status_classes, &latency)
+}
+
+fn number_attr(value: Option<&AttributeValue>) -> Option<i64> {
+    value
+        .and_then(|v| v.as_n().ok())
+        .and_then(|n| n.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket_item(bucket: i64, counters: &[(&str, u64)]) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert(
+            "tunnelId".to_string(),
+            AttributeValue::S("abc123def456".to_string()),
+        );
+        item.insert("bucket".to_string(), AttributeValue::N(bucket.to_string()));
+        item.insert(
+            "ttl".to_string(),
+            AttributeValue::N("99999999999".to_string()),
+        );
+        for (name, count) in counters {
+            item.insert(name.to_string(), AttributeValue::N(count.to_string()));
+        }
+        item
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        assert_eq!(bucket_start(120), 120);
+        assert_eq!(bucket_start(179), 120);
+        assert_eq!(bucket_start(180), 180);
+    }
+
+    #[test]
+    fn test_aggregate_window() {
+        let now = 10_000;
+        let items = vec![
+            // Inside both windows
+            bucket_item(bucket_start(now), &[("s2xx", 8), ("s5xx", 2), ("l3", 10)]),
+            // Ten minutes ago: only inside the one-hour window
+            bucket_item(
+                bucket_start(now - 600),
+                &[("s2xx", 5), ("s4xx", 5), ("l8", 10)],
+            ),
+        ];
+
+        let recent = aggregate_window("abc123def456", &items, now, 300);
+        assert_eq!(recent.requests, 10);
+        assert_eq!(recent.status_classes.get("5xx"), Some(&2));
+        assert_eq!(recent.p95_ms, Some(100));
+        assert!((recent.error_rate() - 0.2).abs() < f64::EPSILON);
+
+        let hour = aggregate_window("abc123def456", &items, now, 3600);
+        assert_eq!(hour.requests, 20);
+        assert_eq!(hour.status_classes.get("4xx"), Some(&5));
+        assert_eq!(hour.p50_ms, Some(100));
+        assert_eq!(hour.p99_ms, Some(5_000));
+    }
+
+    #[test]
+    fn test_aggregate_window_empty() {
+        let stats = aggregate_window("abc123def456", &[], 10_000, 300);
+        assert_eq!(stats.requests, 0);
+        assert_eq!(stats.p50_ms, None);
+    }
+}
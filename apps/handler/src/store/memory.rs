@@ -0,0 +1,195 @@
+//! In-memory store for tests
+
+use anyhow::Result;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::{ErrorCode, HttpResponse, TunnelOptions};
+use http_tunnel_common::utils::current_timestamp_secs;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use super::{PendingRequest, TunnelStore};
+use crate::analytics::PendingContext;
+
+#[derive(Debug, Clone)]
+struct StoredRequest {
+    tunnel_id: String,
+    request_bytes: u64,
+    response: Option<HttpResponse>,
+}
+
+/// Keeps everything in maps; stats aren't kept
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    connections: Mutex<HashMap<String, ConnectionMetadata>>,
+    requests: Mutex<HashMap<String, StoredRequest>>,
+    /// Number of `tunnel_connections` calls
+    pub tunnel_queries: AtomicU32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TunnelStore for MemoryStore {
+    async fn put_connection(&self, metadata: &ConnectionMetadata) -> Result<()> {
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(metadata.connection_id.clone(), metadata.clone());
+        Ok(())
+    }
+
+    async fn get_connection(&self, connection_id: &str) -> Result<Option<ConnectionMetadata>> {
+        Ok(self.connections.lock().unwrap().get(connection_id).cloned())
+    }
+
+    async fn delete_connection(&self, connection_id: &str) -> Result<()> {
+        self.connections.lock().unwrap().remove(connection_id);
+        Ok(())
+    }
+
+    async fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> Result<()> {
+        if let Some(metadata) = self.connections.lock().unwrap().get_mut(connection_id) {
+            metadata.options = options.clone();
+        }
+        Ok(())
+    }
+
+    async fn record_heartbeat(&self, connection_id: &str) -> Result<()> {
+        if let Some(metadata) = self.connections.lock().unwrap().get_mut(connection_id) {
+            metadata.last_heartbeat_at = Some(current_timestamp_secs());
+        }
+        Ok(())
+    }
+
+    async fn touch_connection(&self, _connection_id: &str) -> Result<()> {
+        Ok(())
+    }
+
+    async fn tunnel_connections(&self, tunnel_id: &str) -> Result<Vec<ConnectionMetadata>> {
+        self.tunnel_queries.fetch_add(1, Ordering::Relaxed);
+        Ok(self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|metadata| metadata.tunnel_id == tunnel_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn put_pending_request(&self, request: &PendingRequest<'_>) -> Result<()> {
+        self.requests.lock().unwrap().insert(
+            request.request_id.to_string(),
+            StoredRequest {
+                tunnel_id: request.tunnel_id.to_string(),
+                request_bytes: request.request_bytes as u64,
+                response: None,
+            },
+        );
+        Ok(())
+    }
+
+    async fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Option<PendingContext>> {
+        let mut requests = self.requests.lock().unwrap();
+        let Some(request) = requests.get_mut(&response.request_id) else {
+            return Ok(None);
+        };
+        let first = request.response.is_none();
+        request.response = Some(response.clone());
+        Ok(first.then(|| PendingContext {
+            tunnel_id: request.tunnel_id.clone(),
+            request_bytes: request.request_bytes,
+            sent_at_ms: 0,
+        }))
+    }
+
+    async fn fail_pending_request(&self, response: &HttpResponse, _code: &ErrorCode) -> Result<()> {
+        if let Some(request) = self.requests.lock().unwrap().get_mut(&response.request_id) {
+            request.response = Some(response.clone());
+        }
+        Ok(())
+    }
+
+    async fn pending_response(&self, request_id: &str) -> Result<Option<HttpResponse>> {
+        Ok(self
+            .requests
+            .lock()
+            .unwrap()
+            .get(request_id)
+            .and_then(|request| request.response.clone()))
+    }
+
+    async fn delete_pending_request(&self, request_id: &str) -> Result<()> {
+        self.requests.lock().unwrap().remove(request_id);
+        Ok(())
+    }
+
+    async fn record_request(
+        &self,
+        _tunnel_id: &str,
+        _status_code: u16,
+        _latency: Duration,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn tunnel_stats(
+        &self,
+        _tunnel_id: &str,
+        _windows_secs: &[i64],
+    ) -> Result<Vec<TunnelStats>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pending_request_lifecycle() {
+        let store = MemoryStore::new();
+        store
+            .put_pending_request(&PendingRequest {
+                request_id: "req_1",
+                connection_id: "conn_1",
+                tunnel_id: "abc123def456",
+                request_bytes: 42,
+                api_gateway_request_id: "apigw_1",
+                reply_queue: None,
+            })
+            .await
+            .unwrap();
+        assert!(store.pending_response("req_1").await.unwrap().is_none());
+
+        let response = HttpResponse::new("req_1".to_string(), 200);
+        let context = store.complete_pending_request(&response).await.unwrap();
+        assert_eq!(context.unwrap().request_bytes, 42);
+        // Only the first response counts
+        assert!(
+            store
+                .complete_pending_request(&response)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let stored = store.pending_response("req_1").await.unwrap().unwrap();
+        assert_eq!(stored.status_code, 200);
+        store.delete_pending_request("req_1").await.unwrap();
+        assert!(store.pending_response("req_1").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,354 @@
+//! Storage of connections, pending requests and tunnel stats
+//!
+//! [`TunnelStore`] is what handlers need from storage, independent of how
+//! items are laid out in DynamoDB:
+//!
+//! - [`TwoTableStore`]: the default layout, with the connections, pending
+//!   requests and tunnel stats tables named by `CONNECTIONS_TABLE_NAME`,
+//!   `PENDING_REQUESTS_TABLE_NAME` and `TUNNEL_STATS_TABLE_NAME`
+//! - [`SingleTableStore`] (`STORAGE_BACKEND=single-table`): everything in the
+//!   table named by `TUNNEL_TABLE_NAME`, keyed by `pk`/`sk`, with a `gsi1`
+//!   index (`gsi1pk`/`gsi1sk`) for looking up a tunnel's connections
+//!
+//! Connecting, forwarding, responses (chunked and streamed), heartbeats,
+//! tunnel status and stats go through the store. Features that scan or update
+//! the tables themselves (tunnel handoff, reserved tunnel IDs, idle timeouts,
+//! alerts, analytics, the admin API's tunnel list, push delivery, and the
+//! sweeps) need the two tables, so the infrastructure only offers the single
+//! table without them. Tests can stand in their own implementation rather than
+//! a table.
+//!
+//! With the `redis` feature, `STORAGE_BACKEND=redis` keeps pending requests in
+//! the Redis server at `REDIS_URL` instead (see [`RedisStore`]), and waiting
+//! requests are told about their response rather than polling for it.
+
+use anyhow::{Result, anyhow};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::{ErrorCode, HttpResponse, TunnelOptions};
+use std::future::Future;
+use std::time::Duration;
+
+use crate::analytics::{Completion, PendingContext};
+use crate::chunks::PendingTable;
+
+mod single_table;
+mod two_table;
+
+#[cfg(feature = "redis")]
+mod redis;
+
+#[cfg(test)]
+pub(crate) mod memory;
+
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+pub use single_table::SingleTableStore;
+pub use two_table::TwoTableStore;
+
+/// A public request waiting for its agent's response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRequest<'a> {
+    pub request_id: &'a str,
+    pub connection_id: &'a str,
+    pub tunnel_id: &'a str,
+    pub request_bytes: usize,
+    pub api_gateway_request_id: &'a str,
+    /// Queue of the waiting container, for push delivery
+    pub reply_queue: Option<&'a str>,
+}
+
+/// Where handlers keep connections, pending requests and tunnel stats
+pub trait TunnelStore {
+    /// Save a new connection
+    fn put_connection(
+        &self,
+        metadata: &ConnectionMetadata,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// A connection by ID (None if it's gone)
+    fn get_connection(
+        &self,
+        connection_id: &str,
+    ) -> impl Future<Output = Result<Option<ConnectionMetadata>>> + Send;
+
+    /// Forget a connection; deleting one that's already gone succeeds
+    fn delete_connection(&self, connection_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Remember the options an agent asked for in its Ready message
+    fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Note that an agent's heartbeat arrived
+    fn record_heartbeat(&self, connection_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Push back a connection's expiry after traffic (see [`crate::activity`])
+    fn touch_connection(&self, connection_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Every connection serving a tunnel (empty if none)
+    fn tunnel_connections(
+        &self,
+        tunnel_id: &str,
+    ) -> impl Future<Output = Result<Vec<ConnectionMetadata>>> + Send;
+
+    /// Save a request sent to an agent
+    fn put_pending_request(
+        &self,
+        request: &PendingRequest<'_>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Record the agent's response to a pending request
+    ///
+    /// Returns what the pending request recorded about the public request,
+    /// unless it was already answered.
+    fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> impl Future<Output = Result<Option<PendingContext>>> + Send;
+
+    /// Record that a pending request failed, with the error response to return
+    fn fail_pending_request(
+        &self,
+        response: &HttpResponse,
+        code: &ErrorCode,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// The response to a pending request, once it has completed or failed
+    fn pending_response(
+        &self,
+        request_id: &str,
+    ) -> impl Future<Output = Result<Option<HttpResponse>>> + Send;
+
+    /// Forget a pending request
+    fn delete_pending_request(&self, request_id: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Count a completed request in its tunnel's stats
+    fn record_request(
+        &self,
+        tunnel_id: &str,
+        status_code: u16,
+        latency: Duration,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Rolling-window stats of a tunnel, one entry per window (in seconds)
+    fn tunnel_stats(
+        &self,
+        tunnel_id: &str,
+        windows_secs: &[i64],
+    ) -> impl Future<Output = Result<Vec<TunnelStats>>> + Send;
+}
+
+/// The store a deployment is configured with (`STORAGE_BACKEND`)
+#[derive(Debug)]
+pub enum Backend {
+    /// The default
+    DynamoDb(TwoTableStore),
+    SingleTable(SingleTableStore),
+    #[cfg(feature = "redis")]
+    Redis(Box<RedisStore>),
+}
+
+impl Backend {
+    /// Pick the store named by `STORAGE_BACKEND` (`dynamodb` unless set)
+    pub fn from_env(client: DynamoDbClient) -> Result<Self> {
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_default();
+        match backend.as_str() {
+            "" | "dynamodb" => Ok(Self::DynamoDb(TwoTableStore::new(client))),
+            "single-table" => {
+                let table_name = std::env::var("TUNNEL_TABLE_NAME")
+                    .map_err(|_| anyhow!("TUNNEL_TABLE_NAME environment variable not set"))?;
+                Ok(Self::SingleTable(SingleTableStore::new(client, table_name)))
+            }
+            #[cfg(feature = "redis")]
+            "redis" => {
+                let url = std::env::var("REDIS_URL")
+                    .map_err(|_| anyhow!("REDIS_URL environment variable not set"))?;
+                Ok(Self::Redis(Box::new(RedisStore::new(&url, client)?)))
+            }
+            #[cfg(not(feature = "redis"))]
+            "redis" => Err(anyhow!(
+                "STORAGE_BACKEND=redis needs a handler built with the redis feature"
+            )),
+            other => Err(anyhow!("Unknown STORAGE_BACKEND: {}", other)),
+        }
+    }
+
+    /// Whether waiting requests are told about their response (see [`Self::wait_for_response`])
+    pub fn publishes_responses(&self) -> bool {
+        match self {
+            Self::DynamoDb(_) | Self::SingleTable(_) => false,
+            #[cfg(feature = "redis")]
+            Self::Redis(_) => true,
+        }
+    }
+
+    /// Whether tunnel stats are kept (always in the single table)
+    pub fn keeps_stats(&self) -> bool {
+        match self {
+            Self::SingleTable(_) => true,
+            _ => crate::stats::is_stats_enabled(),
+        }
+    }
+
+    /// Where pending requests and the pieces of streamed bodies are kept
+    pub fn pending_table(&self) -> Result<PendingTable> {
+        match self {
+            Self::SingleTable(store) => Ok(store.pending_table().clone()),
+            _ => PendingTable::from_env(),
+        }
+    }
+
+    /// Wait for the response to a pending request, up to `deadline`
+    ///
+    /// Polling stores wait `head_start` before the first read (see
+    /// [`crate::wait_for_response`]); published responses arrive when they do.
+    pub async fn wait_for_response(
+        &self,
+        request_id: &str,
+        deadline: std::time::Instant,
+        head_start: Duration,
+    ) -> Result<HttpResponse> {
+        match self {
+            Self::DynamoDb(store) => {
+                store
+                    .wait_for_response(request_id, deadline, head_start)
+                    .await
+            }
+            Self::SingleTable(store) => {
+                store
+                    .wait_for_response(request_id, deadline, head_start)
+                    .await
+            }
+            #[cfg(feature = "redis")]
+            Self::Redis(store) => store.wait_for_response(request_id, deadline).await,
+        }
+    }
+
+    /// Store the head of a chunked response
+    ///
+    /// This and the other `save_` methods return the completed request if the
+    /// piece they stored was the last one missing.
+    pub async fn save_response_head(&self, response: &HttpResponse) -> Result<Option<Completion>> {
+        match self {
+            Self::DynamoDb(store) => store.save_response_head(response).await,
+            Self::SingleTable(store) => store.save_response_head(response).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(store) => store.save_response_head(response).await,
+        }
+    }
+
+    /// Store one chunk of a response body
+    pub async fn save_body_chunk(
+        &self,
+        request_id: &str,
+        index: u32,
+        data: String,
+    ) -> Result<Option<Completion>> {
+        match self {
+            Self::DynamoDb(store) => store.save_body_chunk(request_id, index, data).await,
+            Self::SingleTable(store) => store.save_body_chunk(request_id, index, data).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(store) => store.save_body_chunk(request_id, index, data).await,
+        }
+    }
+
+    /// Record how many chunks a response body was split into
+    pub async fn save_body_end(&self, request_id: &str, chunks: u32) -> Result<Option<Completion>> {
+        match self {
+            Self::DynamoDb(store) => store.save_body_end(request_id, chunks).await,
+            Self::SingleTable(store) => store.save_body_end(request_id, chunks).await,
+            #[cfg(feature = "redis")]
+            Self::Redis(store) => store.save_body_end(request_id, chunks).await,
+        }
+    }
+}
+
+/// Forward a [`TunnelStore`] method to the configured store
+macro_rules! delegate {
+    ($self:ident, $store:ident => $call:expr) => {
+        match $self {
+            Backend::DynamoDb($store) => $call.await,
+            Backend::SingleTable($store) => $call.await,
+            #[cfg(feature = "redis")]
+            Backend::Redis($store) => $call.await,
+        }
+    };
+}
+
+impl TunnelStore for Backend {
+    async fn put_connection(&self, metadata: &ConnectionMetadata) -> Result<()> {
+        delegate!(self, store => store.put_connection(metadata))
+    }
+
+    async fn get_connection(&self, connection_id: &str) -> Result<Option<ConnectionMetadata>> {
+        delegate!(self, store => store.get_connection(connection_id))
+    }
+
+    async fn delete_connection(&self, connection_id: &str) -> Result<()> {
+        delegate!(self, store => store.delete_connection(connection_id))
+    }
+
+    async fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> Result<()> {
+        delegate!(self, store => store.save_tunnel_options(connection_id, options))
+    }
+
+    async fn record_heartbeat(&self, connection_id: &str) -> Result<()> {
+        delegate!(self, store => store.record_heartbeat(connection_id))
+    }
+
+    async fn touch_connection(&self, connection_id: &str) -> Result<()> {
+        delegate!(self, store => store.touch_connection(connection_id))
+    }
+
+    async fn tunnel_connections(&self, tunnel_id: &str) -> Result<Vec<ConnectionMetadata>> {
+        delegate!(self, store => store.tunnel_connections(tunnel_id))
+    }
+
+    async fn put_pending_request(&self, request: &PendingRequest<'_>) -> Result<()> {
+        delegate!(self, store => store.put_pending_request(request))
+    }
+
+    async fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Option<PendingContext>> {
+        delegate!(self, store => store.complete_pending_request(response))
+    }
+
+    async fn fail_pending_request(&self, response: &HttpResponse, code: &ErrorCode) -> Result<()> {
+        delegate!(self, store => store.fail_pending_request(response, code))
+    }
+
+    async fn pending_response(&self, request_id: &str) -> Result<Option<HttpResponse>> {
+        delegate!(self, store => store.pending_response(request_id))
+    }
+
+    async fn delete_pending_request(&self, request_id: &str) -> Result<()> {
+        delegate!(self, store => store.delete_pending_request(request_id))
+    }
+
+    async fn record_request(
+        &self,
+        tunnel_id: &str,
+        status_code: u16,
+        latency: Duration,
+    ) -> Result<()> {
+        delegate!(self, store => store.record_request(tunnel_id, status_code, latency))
+    }
+
+    async fn tunnel_stats(
+        &self,
+        tunnel_id: &str,
+        windows_secs: &[i64],
+    ) -> Result<Vec<TunnelStats>> {
+        delegate!(self, store => store.tunnel_stats(tunnel_id, windows_secs))
+    }
+}
@@ -0,0 +1,462 @@
+//! Pending requests in Redis, with responses published to the waiter
+//!
+//! Each pending request is a hash `req:{id}` (fields named as in the pending
+//! requests table) expiring after `PENDING_REQUEST_TTL_SECS`. The first
+//! response recorded for a request is published on `response:{id}`, which the
+//! waiting invocation subscribes to, so it hears about the response as soon as
+//! it's stored instead of polling for it.
+//!
+//! Chunked bodies are collected in the hash `req:{id}:chunks`, keyed by chunk
+//! index, and the invocation that stores the last missing piece assembles and
+//! publishes the whole response. Pieces of streamed bodies still go to the
+//! pending requests table, where the relaying invocation reads them.
+//!
+//! Connections and tunnel stats stay in DynamoDB: tunnel options, heartbeats,
+//! handoffs, sweeps, alerts and the admin API all read or update them there.
+
+use anyhow::{Context, Result, anyhow};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use futures_util::StreamExt;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::constants::PENDING_REQUEST_TTL_SECS;
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::{ErrorCode, HttpResponse, TunnelOptions};
+use http_tunnel_common::utils::current_timestamp_millis;
+use once_cell::sync::Lazy;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncCommands, Script};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use super::{PendingRequest, TunnelStore, TwoTableStore};
+use crate::analytics::{Completion, PendingContext};
+use crate::decoded_body_size;
+
+/// Record the first response to a pending request and publish it
+///
+/// Returns the request's `tunnelId`, `requestBytes` and `sentAtMs`, or nil if
+/// the request is unknown or was already answered.
+static FINISH: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then return nil end
+        if redis.call('HSETNX', KEYS[1], 'responseData', ARGV[1]) == 0 then return nil end
+        redis.call('HSET', KEYS[1], 'status', ARGV[2])
+        redis.call('PUBLISH', ARGV[3], ARGV[1])
+        return redis.call('HMGET', KEYS[1], 'tunnelId', 'requestBytes', 'sentAtMs')
+        ",
+    )
+});
+
+/// Set a field of a pending request unless it's already set or the request has expired
+static SAVE_FIELD: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+        if redis.call('EXISTS', KEYS[1]) == 0 then return 0 end
+        return redis.call('HSETNX', KEYS[1], ARGV[1], ARGV[2])
+        ",
+    )
+});
+
+fn request_key(request_id: &str) -> String {
+    format!("req:{}", request_id)
+}
+
+fn chunks_key(request_id: &str) -> String {
+    format!("req:{}:chunks", request_id)
+}
+
+fn response_channel(request_id: &str) -> String {
+    format!("response:{}", request_id)
+}
+
+/// Join the chunks of a body, if all `count` of them have arrived
+fn join_chunks(mut chunks: HashMap<u32, String>, count: u32) -> Option<String> {
+    (0..count).map(|index| chunks.remove(&index)).collect()
+}
+
+/// What the pending request recorded about the public request
+fn pending_context(fields: &[Option<String>]) -> Option<PendingContext> {
+    let [tunnel_id, request_bytes, sent_at_ms] = fields else {
+        return None;
+    };
+    Some(PendingContext {
+        tunnel_id: tunnel_id.clone()?,
+        request_bytes: request_bytes
+            .as_deref()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0),
+        sent_at_ms: sent_at_ms.as_deref()?.parse().ok()?,
+    })
+}
+
+/// Pending requests in Redis; connections and stats in DynamoDB
+#[derive(Debug)]
+pub struct RedisStore {
+    client: redis::Client,
+    connection: OnceCell<MultiplexedConnection>,
+    tables: TwoTableStore,
+}
+
+impl RedisStore {
+    /// Store pending requests on the server at `url` (`redis://` or `rediss://`)
+    pub fn new(url: &str, client: DynamoDbClient) -> Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url).context("Invalid REDIS_URL")?,
+            connection: OnceCell::new(),
+            tables: TwoTableStore::new(client),
+        })
+    }
+
+    /// The container's connection, opened on first use
+    async fn connection(&self) -> Result<MultiplexedConnection> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| self.client.get_multiplexed_async_connection())
+            .await
+            .context("Failed to connect to Redis")?;
+        Ok(connection.clone())
+    }
+
+    /// Record the first response to a request and publish it to the waiter
+    async fn finish(
+        &self,
+        request_id: &str,
+        response_data: &str,
+        status: &str,
+    ) -> Result<Option<PendingContext>> {
+        let mut connection = self.connection().await?;
+        let fields: Option<Vec<Option<String>>> = FINISH
+            .key(request_key(request_id))
+            .arg(response_data)
+            .arg(status)
+            .arg(response_channel(request_id))
+            .invoke_async(&mut connection)
+            .await
+            .context("Failed to record response in Redis")?;
+
+        match fields {
+            Some(fields) => {
+                debug!("Published response for {}", request_id);
+                Ok(pending_context(&fields))
+            }
+            None => {
+                debug!("Request {} is no longer pending", request_id);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Store the head of a chunked response (the response without its body)
+    ///
+    /// This and the other `save_` methods return the completed request if the
+    /// piece they stored was the last one missing, like their counterparts in
+    /// [`chunks`](crate::chunks).
+    pub async fn save_response_head(&self, response: &HttpResponse) -> Result<Option<Completion>> {
+        let head = serde_json::to_string(response).context("Failed to serialize response head")?;
+        self.save_field(&response.request_id, "responseHead", head)
+            .await?;
+        self.try_complete(&response.request_id).await
+    }
+
+    /// Store one chunk of a response body
+    pub async fn save_body_chunk(
+        &self,
+        request_id: &str,
+        index: u32,
+        data: String,
+    ) -> Result<Option<Completion>> {
+        let key = chunks_key(request_id);
+        let mut connection = self.connection().await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .hset(&key, index, data)
+            .ignore()
+            .expire(&key, PENDING_REQUEST_TTL_SECS)
+            .ignore()
+            .query_async(&mut connection)
+            .await
+            .context("Failed to save response chunk")?;
+
+        self.try_complete(request_id).await
+    }
+
+    /// Record how many chunks a response body was split into
+    pub async fn save_body_end(&self, request_id: &str, chunks: u32) -> Result<Option<Completion>> {
+        self.save_field(request_id, "bodyChunks", chunks.to_string())
+            .await?;
+        self.try_complete(request_id).await
+    }
+
+    /// Set a field of a pending request
+    async fn save_field(&self, request_id: &str, field: &str, value: String) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let saved: bool = SAVE_FIELD
+            .key(request_key(request_id))
+            .arg(field)
+            .arg(value)
+            .invoke_async(&mut connection)
+            .await
+            .context("Failed to save chunked response")?;
+        if !saved {
+            debug!("Request {} is no longer pending", request_id);
+        }
+        Ok(())
+    }
+
+    /// Publish the response once the head, the chunk count and every chunk are stored
+    async fn try_complete(&self, request_id: &str) -> Result<Option<Completion>> {
+        let mut connection = self.connection().await?;
+        let (status, head, count): (Option<String>, Option<String>, Option<u32>) = connection
+            .hget(
+                request_key(request_id),
+                &["status", "responseHead", "bodyChunks"],
+            )
+            .await
+            .context("Failed to read pending request from Redis")?;
+        let (Some("pending"), Some(head), Some(count)) = (status.as_deref(), head, count) else {
+            return Ok(None);
+        };
+
+        let chunks: HashMap<u32, String> = connection
+            .hgetall(chunks_key(request_id))
+            .await
+            .context("Failed to read response chunks from Redis")?;
+        let received = chunks.len();
+        let Some(body) = join_chunks(chunks, count) else {
+            debug!(
+                "Request {}: {} of {} chunks received",
+                request_id, received, count
+            );
+            return Ok(None);
+        };
+
+        let mut response: HttpResponse =
+            serde_json::from_str(&head).context("Failed to parse response head")?;
+        let response_bytes = decoded_body_size(&body) as u64;
+        response.body = body;
+        response.chunked = false;
+        let response_data =
+            serde_json::to_string(&response).context("Failed to serialize response to JSON")?;
+
+        let context = self.finish(request_id, &response_data, "completed").await?;
+        if context.is_some() {
+            info!(
+                "Completed chunked response for {} ({} chunks)",
+                request_id, count
+            );
+        }
+        Ok(context.map(|context| context.complete(response.status_code, response_bytes)))
+    }
+
+    /// Wait until the response to `request_id` is published, up to `deadline`
+    pub async fn wait_for_response(
+        &self,
+        request_id: &str,
+        deadline: std::time::Instant,
+    ) -> Result<HttpResponse> {
+        let mut pubsub = self
+            .client
+            .get_async_pubsub()
+            .await
+            .context("Failed to connect to Redis")?;
+        pubsub
+            .subscribe(response_channel(request_id))
+            .await
+            .context("Failed to subscribe to response channel")?;
+
+        // The response may have been published before we subscribed
+        let response = match self.pending_response(request_id).await? {
+            Some(response) => response,
+            None => {
+                let mut messages = pubsub.on_message();
+                let message = tokio::time::timeout_at(Instant::from_std(deadline), messages.next())
+                    .await
+                    .map_err(|_| anyhow!("Request timeout waiting for response"))?
+                    .ok_or_else(|| anyhow!("Redis closed the response channel"))?;
+                let payload: String = message
+                    .get_payload()
+                    .context("Invalid response notification")?;
+                serde_json::from_str(&payload).context("Failed to parse response data JSON")?
+            }
+        };
+
+        if let Err(e) = self.delete_pending_request(request_id).await {
+            warn!("Failed to clean up pending request: {:#}", e);
+        }
+        Ok(response)
+    }
+}
+
+impl TunnelStore for RedisStore {
+    async fn put_connection(&self, metadata: &ConnectionMetadata) -> Result<()> {
+        self.tables.put_connection(metadata).await
+    }
+
+    async fn get_connection(&self, connection_id: &str) -> Result<Option<ConnectionMetadata>> {
+        self.tables.get_connection(connection_id).await
+    }
+
+    async fn delete_connection(&self, connection_id: &str) -> Result<()> {
+        self.tables.delete_connection(connection_id).await
+    }
+
+    async fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> Result<()> {
+        self.tables
+            .save_tunnel_options(connection_id, options)
+            .await
+    }
+
+    async fn record_heartbeat(&self, connection_id: &str) -> Result<()> {
+        self.tables.record_heartbeat(connection_id).await
+    }
+
+    async fn touch_connection(&self, connection_id: &str) -> Result<()> {
+        self.tables.touch_connection(connection_id).await
+    }
+
+    async fn tunnel_connections(&self, tunnel_id: &str) -> Result<Vec<ConnectionMetadata>> {
+        self.tables.tunnel_connections(tunnel_id).await
+    }
+
+    async fn put_pending_request(&self, request: &PendingRequest<'_>) -> Result<()> {
+        let key = request_key(request.request_id);
+        let mut fields = vec![
+            ("requestId", request.request_id.to_string()),
+            ("connectionId", request.connection_id.to_string()),
+            ("tunnelId", request.tunnel_id.to_string()),
+            ("requestBytes", request.request_bytes.to_string()),
+            ("sentAtMs", current_timestamp_millis().to_string()),
+            (
+                "apiGatewayRequestId",
+                request.api_gateway_request_id.to_string(),
+            ),
+            ("status", "pending".to_string()),
+        ];
+        if let Some(reply_queue) = request.reply_queue {
+            fields.push(("replyQueue", reply_queue.to_string()));
+        }
+
+        let mut connection = self.connection().await?;
+        let _: () = redis::pipe()
+            .atomic()
+            .hset_multiple(&key, &fields)
+            .ignore()
+            .expire(&key, PENDING_REQUEST_TTL_SECS)
+            .ignore()
+            .query_async(&mut connection)
+            .await
+            .context("Failed to save pending request to Redis")?;
+
+        Ok(())
+    }
+
+    async fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Option<PendingContext>> {
+        if response.chunked {
+            return Err(anyhow!(
+                "Chunked responses are stored with save_response_head"
+            ));
+        }
+        let response_data =
+            serde_json::to_string(response).context("Failed to serialize response to JSON")?;
+        self.finish(&response.request_id, &response_data, "completed")
+            .await
+    }
+
+    async fn fail_pending_request(&self, response: &HttpResponse, _code: &ErrorCode) -> Result<()> {
+        let response_data =
+            serde_json::to_string(response).context("Failed to serialize response to JSON")?;
+        self.finish(&response.request_id, &response_data, "failed")
+            .await?;
+        Ok(())
+    }
+
+    async fn pending_response(&self, request_id: &str) -> Result<Option<HttpResponse>> {
+        let mut connection = self.connection().await?;
+        let response_data: Option<String> = connection
+            .hget(request_key(request_id), "responseData")
+            .await
+            .context("Failed to get pending request from Redis")?;
+
+        response_data
+            .map(|data| serde_json::from_str(&data).context("Failed to parse response data JSON"))
+            .transpose()
+    }
+
+    async fn delete_pending_request(&self, request_id: &str) -> Result<()> {
+        let mut connection = self.connection().await?;
+        let _: () = connection
+            .del(&[request_key(request_id), chunks_key(request_id)])
+            .await
+            .context("Failed to delete pending request from Redis")?;
+        Ok(())
+    }
+
+    async fn record_request(
+        &self,
+        tunnel_id: &str,
+        status_code: u16,
+        latency: Duration,
+    ) -> Result<()> {
+        self.tables
+            .record_request(tunnel_id, status_code, latency)
+            .await
+    }
+
+    async fn tunnel_stats(
+        &self,
+        tunnel_id: &str,
+        windows_secs: &[i64],
+    ) -> Result<Vec<TunnelStats>> {
+        self.tables.tunnel_stats(tunnel_id, windows_secs).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        assert_eq!(request_key("req_1"), "req:req_1");
+        assert_eq!(chunks_key("req_1"), "req:req_1:chunks");
+        assert_eq!(response_channel("req_1"), "response:req_1");
+    }
+
+    #[test]
+    fn test_join_chunks() {
+        let chunks = HashMap::from([
+            (1, "world".to_string()),
+            (0, "hello ".to_string()),
+            (2, "!".to_string()),
+        ]);
+        assert_eq!(join_chunks(chunks.clone(), 3).unwrap(), "hello world!");
+        assert!(join_chunks(chunks, 4).is_none());
+    }
+
+    #[test]
+    fn test_pending_context() {
+        let fields = [
+            Some("abc123def456".to_string()),
+            Some("42".to_string()),
+            Some("1700000000000".to_string()),
+        ];
+        let context = pending_context(&fields).unwrap();
+        assert_eq!(context.tunnel_id, "abc123def456");
+        assert_eq!(context.request_bytes, 42);
+        assert_eq!(context.sent_at_ms, 1_700_000_000_000);
+
+        // Unknown requests have no context
+        assert!(pending_context(&[None, None, None]).is_none());
+    }
+}
@@ -0,0 +1,500 @@
+//! Everything in one table
+//!
+//! | Item               | `pk`            | `sk`             | `gsi1pk`        | `gsi1sk`   |
+//! |--------------------|-----------------|------------------|-----------------|------------|
+//! | Connection         | `CONN#<id>`     | `META`           | `TUNNEL#<id>`   | `CONN#<id>`|
+//! | Pending request    | `REQ#<id>`      | `META`           |                 |            |
+//! | Body chunk         | `REQ#<id>`      | `CHUNK#<index>`  |                 |            |
+//! | Tunnel stats bucket| `STATS#<id>`    | `BUCKET#<start>` |                 |            |
+//!
+//! Other attributes are named as in the two-table layout, so items parse the
+//! same way, and pending requests and their chunks are read and written by the
+//! same code (see [`PendingTable`]). Bucket starts are zero-padded so sort keys
+//! order by time.
+
+use anyhow::{Context, Result, anyhow};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use aws_sdk_dynamodb::operation::update_item::builders::UpdateItemFluentBuilder;
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::{ErrorCode, HttpResponse, TunnelOptions};
+use http_tunnel_common::utils::current_timestamp_secs;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::{PendingRequest, TunnelStore};
+use crate::analytics::{Completion, PendingContext};
+use crate::aws_util::retry;
+use crate::chunks::{self, PendingTable};
+use crate::stats::{self, bucket_start};
+use crate::{
+    activity, body_chunk_count, connection_item, connection_metadata_from_item,
+    pending_request_item, response_from_item, set_heartbeat, set_tunnel_options,
+};
+
+/// Sort key of connection items
+const META: &str = "META";
+
+/// Index for looking up a tunnel's connections
+const GSI1: &str = "gsi1";
+
+fn connection_pk(connection_id: &str) -> String {
+    format!("CONN#{}", connection_id)
+}
+
+fn tunnel_pk(tunnel_id: &str) -> String {
+    format!("TUNNEL#{}", tunnel_id)
+}
+
+fn stats_pk(tunnel_id: &str) -> String {
+    format!("STATS#{}", tunnel_id)
+}
+
+fn bucket_sk(bucket: i64) -> String {
+    format!("BUCKET#{:012}", bucket.max(0))
+}
+
+fn key(pk: String, sk: String) -> HashMap<String, AttributeValue> {
+    HashMap::from([
+        ("pk".to_string(), AttributeValue::S(pk)),
+        ("sk".to_string(), AttributeValue::S(sk)),
+    ])
+}
+
+/// A connection item with its keys
+fn single_table_connection_item(metadata: &ConnectionMetadata) -> HashMap<String, AttributeValue> {
+    let mut item = connection_item(metadata);
+    item.extend(key(
+        connection_pk(&metadata.connection_id),
+        META.to_string(),
+    ));
+    item.insert(
+        "gsi1pk".to_string(),
+        AttributeValue::S(tunnel_pk(&metadata.tunnel_id)),
+    );
+    item.insert(
+        "gsi1sk".to_string(),
+        AttributeValue::S(connection_pk(&metadata.connection_id)),
+    );
+    item
+}
+
+/// A pending request item with its keys
+fn single_table_pending_item(
+    table: &PendingTable,
+    request: &PendingRequest<'_>,
+) -> HashMap<String, AttributeValue> {
+    let mut item = pending_request_item(request);
+    item.extend(table.request_key(request.request_id));
+    item
+}
+
+/// Connections, pending requests and stats in a single table
+#[derive(Debug, Clone)]
+pub struct SingleTableStore {
+    client: DynamoDbClient,
+    table_name: String,
+    pending: PendingTable,
+}
+
+impl SingleTableStore {
+    pub fn new(client: DynamoDbClient, table_name: impl Into<String>) -> Self {
+        let table_name = table_name.into();
+        Self {
+            client,
+            pending: PendingTable::single_table(&table_name),
+            table_name,
+        }
+    }
+
+    /// How pending requests and their chunks are keyed in the table
+    pub fn pending_table(&self) -> &PendingTable {
+        &self.pending
+    }
+
+    /// Poll for the response to a pending request (see [`crate::wait_for_response`])
+    pub async fn wait_for_response(
+        &self,
+        request_id: &str,
+        deadline: std::time::Instant,
+        head_start: Duration,
+    ) -> Result<HttpResponse> {
+        crate::wait_for_response(
+            &self.client,
+            &self.pending,
+            request_id,
+            deadline,
+            head_start,
+        )
+        .await
+    }
+
+    /// See [`chunks::save_response_head`]
+    pub async fn save_response_head(&self, response: &HttpResponse) -> Result<Option<Completion>> {
+        chunks::save_response_head(&self.client, &self.pending, response).await
+    }
+
+    /// See [`chunks::save_body_chunk`]
+    pub async fn save_body_chunk(
+        &self,
+        request_id: &str,
+        index: u32,
+        data: String,
+    ) -> Result<Option<Completion>> {
+        chunks::save_body_chunk(&self.client, &self.pending, request_id, index, data).await
+    }
+
+    /// See [`chunks::save_body_end`]
+    pub async fn save_body_end(&self, request_id: &str, count: u32) -> Result<Option<Completion>> {
+        chunks::save_body_end(&self.client, &self.pending, request_id, count).await
+    }
+
+    /// An update of a connection item
+    fn update_connection(&self, connection_id: &str) -> UpdateItemFluentBuilder {
+        self.client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(key(connection_pk(connection_id), META.to_string())))
+    }
+}
+
+impl TunnelStore for SingleTableStore {
+    async fn put_connection(&self, metadata: &ConnectionMetadata) -> Result<()> {
+        let request = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(single_table_connection_item(metadata)));
+        retry("PutItem", || request.clone().send())
+            .await
+            .context("Failed to save connection metadata to DynamoDB")?;
+
+        Ok(())
+    }
+
+    async fn get_connection(&self, connection_id: &str) -> Result<Option<ConnectionMetadata>> {
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .set_key(Some(key(connection_pk(connection_id), META.to_string())));
+        let result = retry("GetItem", || request.clone().send())
+            .await
+            .context("Failed to get connection metadata")?;
+
+        result
+            .item
+            .as_ref()
+            .map(connection_metadata_from_item)
+            .transpose()
+    }
+
+    async fn delete_connection(&self, connection_id: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .set_key(Some(key(connection_pk(connection_id), META.to_string())));
+        retry("DeleteItem", || request.clone().send())
+            .await
+            .context("Failed to delete connection from DynamoDB")?;
+
+        Ok(())
+    }
+
+    async fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> Result<()> {
+        let request = set_tunnel_options(self.update_connection(connection_id), options)?;
+        retry("UpdateItem", || request.clone().send())
+            .await
+            .context("Failed to save tunnel options")?;
+
+        Ok(())
+    }
+
+    async fn record_heartbeat(&self, connection_id: &str) -> Result<()> {
+        let request = set_heartbeat(self.update_connection(connection_id));
+        retry("UpdateItem", || request.clone().send())
+            .await
+            .context("Failed to record heartbeat")?;
+
+        Ok(())
+    }
+
+    async fn touch_connection(&self, connection_id: &str) -> Result<()> {
+        activity::set_activity(self.update_connection(connection_id))
+            .send()
+            .await
+            .context("Failed to update connection TTL")?;
+
+        Ok(())
+    }
+
+    async fn tunnel_connections(&self, tunnel_id: &str) -> Result<Vec<ConnectionMetadata>> {
+        let mut connections = Vec::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let request = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .index_name(GSI1)
+                .key_condition_expression("gsi1pk = :tunnel")
+                .expression_attribute_values(":tunnel", AttributeValue::S(tunnel_pk(tunnel_id)))
+                .set_exclusive_start_key(exclusive_start_key);
+            let result = retry("Query", || request.clone().send())
+                .await
+                .context("Failed to query connections by tunnel ID")?;
+
+            for item in result.items() {
+                connections.push(connection_metadata_from_item(item)?);
+            }
+            exclusive_start_key = result.last_evaluated_key;
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(connections)
+    }
+
+    async fn put_pending_request(&self, request: &PendingRequest<'_>) -> Result<()> {
+        let put_request = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(single_table_pending_item(&self.pending, request)));
+        retry("PutItem", || put_request.clone().send())
+            .await
+            .context("Failed to save pending request to DynamoDB")?;
+
+        Ok(())
+    }
+
+    async fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Option<PendingContext>> {
+        if response.chunked {
+            return Err(anyhow!("Chunked responses are saved piece by piece"));
+        }
+        let response_data =
+            serde_json::to_string(response).context("Failed to serialize response to JSON")?;
+
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.pending.request_key(&response.request_id)))
+            .update_expression("SET #status = :status, responseData = :data")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":status", AttributeValue::S("completed".to_string()))
+            .expression_attribute_values(":data", AttributeValue::S(response_data))
+            .return_values(ReturnValue::AllOld);
+        let result = retry("UpdateItem", || request.clone().send())
+            .await
+            .context("Failed to update pending request with response")?;
+
+        // Only the first response for a request counts
+        Ok(result
+            .attributes
+            .filter(|item| {
+                item.get("status")
+                    .and_then(|v| v.as_s().ok())
+                    .is_some_and(|status| status == "pending")
+            })
+            .as_ref()
+            .and_then(PendingContext::from_item))
+    }
+
+    async fn fail_pending_request(&self, response: &HttpResponse, code: &ErrorCode) -> Result<()> {
+        let response_data =
+            serde_json::to_string(response).context("Failed to serialize response to JSON")?;
+        let error_code = serde_json::to_value(code)
+            .ok()
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default();
+
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.pending.request_key(&response.request_id)))
+            .update_expression("SET #status = :status, responseData = :data, errorCode = :code")
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":status", AttributeValue::S("failed".to_string()))
+            .expression_attribute_values(":data", AttributeValue::S(response_data))
+            .expression_attribute_values(":code", AttributeValue::S(error_code));
+        retry("UpdateItem", || request.clone().send())
+            .await
+            .context("Failed to mark pending request as failed")?;
+
+        Ok(())
+    }
+
+    async fn pending_response(&self, request_id: &str) -> Result<Option<HttpResponse>> {
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.pending.request_key(request_id)))
+            .consistent_read(true);
+        let result = retry("GetItem", || request.clone().send())
+            .await
+            .context("Failed to get pending request from DynamoDB")?;
+
+        let Some(item) = result.item else {
+            return Ok(None);
+        };
+        let Some(mut response) = response_from_item(&item)? else {
+            return Ok(None);
+        };
+        if response.chunked {
+            let body_chunks = body_chunk_count(&item)?;
+            response.body =
+                chunks::load_body(&self.client, &self.pending, request_id, body_chunks).await?;
+            response.chunked = false;
+        }
+
+        Ok(Some(response))
+    }
+
+    async fn delete_pending_request(&self, request_id: &str) -> Result<()> {
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .set_key(Some(self.pending.request_key(request_id)));
+        retry("DeleteItem", || request.clone().send())
+            .await
+            .context("Failed to delete pending request from DynamoDB")?;
+
+        Ok(())
+    }
+
+    async fn record_request(
+        &self,
+        tunnel_id: &str,
+        status_code: u16,
+        latency: Duration,
+    ) -> Result<()> {
+        let bucket = bucket_start(current_timestamp_secs());
+        let update = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .set_key(Some(key(stats_pk(tunnel_id), bucket_sk(bucket))));
+        stats::count_request(
+            update,
+            Some(bucket),
+            status_code,
+            latency.as_millis() as u64,
+        )
+        .send()
+        .await
+        .context("Failed to record tunnel stats")?;
+
+        Ok(())
+    }
+
+    async fn tunnel_stats(
+        &self,
+        tunnel_id: &str,
+        windows_secs: &[i64],
+    ) -> Result<Vec<TunnelStats>> {
+        let now = current_timestamp_secs();
+        let longest = windows_secs.iter().copied().max().unwrap_or(0);
+
+        let mut items = Vec::new();
+        let mut exclusive_start_key = None;
+        loop {
+            let request = self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .key_condition_expression("pk = :pk AND sk BETWEEN :since AND :until")
+                .expression_attribute_values(":pk", AttributeValue::S(stats_pk(tunnel_id)))
+                .expression_attribute_values(
+                    ":since",
+                    AttributeValue::S(bucket_sk(bucket_start(now - longest))),
+                )
+                .expression_attribute_values(":until", AttributeValue::S(bucket_sk(now)))
+                .set_exclusive_start_key(exclusive_start_key);
+            let result = retry("Query", || request.clone().send())
+                .await
+                .context("Failed to query tunnel stats")?;
+
+            items.extend(result.items.unwrap_or_default());
+            exclusive_start_key = result.last_evaluated_key;
+            if exclusive_start_key.is_none() {
+                break;
+            }
+        }
+
+        Ok(windows_secs
+            .iter()
+            .map(|window| stats::aggregate_window(tunnel_id, &items, now, *window))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string_attr<'a>(item: &'a HashMap<String, AttributeValue>, name: &str) -> &'a str {
+        item.get(name).unwrap().as_s().unwrap()
+    }
+
+    #[test]
+    fn test_connection_item_keys() {
+        let mut metadata = ConnectionMetadata::new(
+            "conn_1".to_string(),
+            "abc123def456".to_string(),
+            "https://abc123def456.tunnel.example.com".to_string(),
+            1_700_000_000,
+            1_700_007_200,
+        );
+        metadata.owner = Some("alice".to_string());
+
+        let item = single_table_connection_item(&metadata);
+        assert_eq!(string_attr(&item, "pk"), "CONN#conn_1");
+        assert_eq!(string_attr(&item, "sk"), "META");
+        assert_eq!(string_attr(&item, "gsi1pk"), "TUNNEL#abc123def456");
+        assert_eq!(string_attr(&item, "gsi1sk"), "CONN#conn_1");
+
+        // Items read back like connections table items
+        let parsed = connection_metadata_from_item(&item).unwrap();
+        assert_eq!(parsed.connection_id, "conn_1");
+        assert_eq!(parsed.tunnel_id, "abc123def456");
+        assert_eq!(parsed.owner.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn test_pending_item_keys() {
+        let request = PendingRequest {
+            request_id: "req_1",
+            connection_id: "conn_1",
+            tunnel_id: "abc123def456",
+            request_bytes: 42,
+            api_gateway_request_id: "apigw_1",
+            reply_queue: None,
+        };
+
+        let item = single_table_pending_item(&PendingTable::single_table("tunnel"), &request);
+        assert_eq!(string_attr(&item, "pk"), "REQ#req_1");
+        assert_eq!(string_attr(&item, "sk"), "META");
+        assert_eq!(string_attr(&item, "requestId"), "req_1");
+        assert_eq!(string_attr(&item, "status"), "pending");
+    }
+
+    #[test]
+    fn test_bucket_sort_keys_order_by_time() {
+        assert_eq!(bucket_sk(1_700_000_040), "BUCKET#001700000040");
+        assert!(bucket_sk(999_999_960) < bucket_sk(1_000_000_020));
+    }
+}
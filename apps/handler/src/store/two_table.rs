@@ -0,0 +1,179 @@
+//! The deployed layout: a table per kind of item
+
+use anyhow::{Context, Result};
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use http_tunnel_common::ConnectionMetadata;
+use http_tunnel_common::models::TunnelStats;
+use http_tunnel_common::protocol::{ErrorCode, HttpResponse, TunnelOptions};
+use std::time::Duration;
+
+use super::{PendingRequest, TunnelStore};
+use crate::analytics::{Completion, PendingContext};
+use crate::aws_util::retry;
+use crate::chunks::{self, PendingTable};
+use crate::{body_chunk_count, response_from_item, stats};
+
+/// Connections, pending requests and stats in their own tables
+///
+/// Table names come from the environment, as for the rest of the handler.
+#[derive(Debug, Clone)]
+pub struct TwoTableStore {
+    client: DynamoDbClient,
+}
+
+impl TwoTableStore {
+    pub fn new(client: DynamoDbClient) -> Self {
+        Self { client }
+    }
+
+    /// Poll for the response to a pending request (see [`crate::wait_for_response`])
+    pub async fn wait_for_response(
+        &self,
+        request_id: &str,
+        deadline: std::time::Instant,
+        head_start: Duration,
+    ) -> Result<HttpResponse> {
+        let table = PendingTable::from_env()?;
+        crate::wait_for_response(&self.client, &table, request_id, deadline, head_start).await
+    }
+
+    /// See [`chunks::save_response_head`]
+    pub async fn save_response_head(&self, response: &HttpResponse) -> Result<Option<Completion>> {
+        chunks::save_response_head(&self.client, &PendingTable::from_env()?, response).await
+    }
+
+    /// See [`chunks::save_body_chunk`]
+    pub async fn save_body_chunk(
+        &self,
+        request_id: &str,
+        index: u32,
+        data: String,
+    ) -> Result<Option<Completion>> {
+        let table = PendingTable::from_env()?;
+        chunks::save_body_chunk(&self.client, &table, request_id, index, data).await
+    }
+
+    /// See [`chunks::save_body_end`]
+    pub async fn save_body_end(&self, request_id: &str, count: u32) -> Result<Option<Completion>> {
+        chunks::save_body_end(&self.client, &PendingTable::from_env()?, request_id, count).await
+    }
+}
+
+impl TunnelStore for TwoTableStore {
+    async fn put_connection(&self, metadata: &ConnectionMetadata) -> Result<()> {
+        crate::save_connection_metadata(&self.client, metadata).await
+    }
+
+    async fn get_connection(&self, connection_id: &str) -> Result<Option<ConnectionMetadata>> {
+        crate::get_connection_metadata(&self.client, connection_id).await
+    }
+
+    async fn delete_connection(&self, connection_id: &str) -> Result<()> {
+        crate::delete_connection(&self.client, connection_id).await
+    }
+
+    async fn save_tunnel_options(
+        &self,
+        connection_id: &str,
+        options: &TunnelOptions,
+    ) -> Result<()> {
+        crate::save_tunnel_options(&self.client, connection_id, options).await
+    }
+
+    async fn record_heartbeat(&self, connection_id: &str) -> Result<()> {
+        crate::record_heartbeat(&self.client, connection_id).await
+    }
+
+    async fn touch_connection(&self, connection_id: &str) -> Result<()> {
+        crate::activity::record_activity(&self.client, connection_id).await
+    }
+
+    async fn tunnel_connections(&self, tunnel_id: &str) -> Result<Vec<ConnectionMetadata>> {
+        crate::query_tunnel_connections(&self.client, tunnel_id).await
+    }
+
+    async fn put_pending_request(&self, request: &PendingRequest<'_>) -> Result<()> {
+        crate::save_pending_request(
+            &self.client,
+            request.request_id,
+            request.connection_id,
+            request.tunnel_id,
+            request.request_bytes,
+            request.api_gateway_request_id,
+            request.reply_queue,
+        )
+        .await
+    }
+
+    async fn complete_pending_request(
+        &self,
+        response: &HttpResponse,
+    ) -> Result<Option<PendingContext>> {
+        crate::update_pending_request_with_response(&self.client, response).await
+    }
+
+    async fn fail_pending_request(&self, response: &HttpResponse, code: &ErrorCode) -> Result<()> {
+        crate::mark_pending_request_failed(&self.client, response, code).await
+    }
+
+    async fn pending_response(&self, request_id: &str) -> Result<Option<HttpResponse>> {
+        let table = PendingTable::from_env()?;
+
+        let request = self
+            .client
+            .get_item()
+            .table_name(&table.table_name)
+            .set_key(Some(table.request_key(request_id)))
+            .consistent_read(true);
+        let result = retry("GetItem", || request.clone().send())
+            .await
+            .context("Failed to get pending request from DynamoDB")?;
+
+        let Some(item) = result.item else {
+            return Ok(None);
+        };
+        let Some(mut response) = response_from_item(&item)? else {
+            return Ok(None);
+        };
+        if response.chunked {
+            let body_chunks = body_chunk_count(&item)?;
+            response.body =
+                chunks::load_body(&self.client, &table, request_id, body_chunks).await?;
+            response.chunked = false;
+        }
+
+        Ok(Some(response))
+    }
+
+    async fn delete_pending_request(&self, request_id: &str) -> Result<()> {
+        let table = PendingTable::from_env()?;
+
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&table.table_name)
+            .set_key(Some(table.request_key(request_id)));
+        retry("DeleteItem", || request.clone().send())
+            .await
+            .context("Failed to delete pending request from DynamoDB")?;
+
+        Ok(())
+    }
+
+    async fn record_request(
+        &self,
+        tunnel_id: &str,
+        status_code: u16,
+        latency: Duration,
+    ) -> Result<()> {
+        stats::record_request(&self.client, tunnel_id, status_code, latency).await
+    }
+
+    async fn tunnel_stats(
+        &self,
+        tunnel_id: &str,
+        windows_secs: &[i64],
+    ) -> Result<Vec<TunnelStats>> {
+        stats::load_tunnel_stats(&self.client, tunnel_id, windows_secs).await
+    }
+}
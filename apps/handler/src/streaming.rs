@@ -0,0 +1,238 @@
+//! Relaying streamed response bodies
+//!
+//! Agents stream bodies that never really end, such as Server-Sent Events, by
+//! sending the response head with `streaming` set and then the body piece by piece
+//! in `ResponseChunk` messages. The `$default` route stores each piece like a
+//! chunk of a chunked body; the head completes the pending request as usual, so
+//! the forwarding invocation picks it up through the normal wait.
+//!
+//! Requests that arrive through a Lambda Function URL (whose invoke mode must be
+//! `RESPONSE_STREAM`) get the body relayed as it arrives, until the agent marks
+//! the last piece or the invocation is about to time out. The HTTP API can't
+//! stream, so there the pieces are collected until the last one or the request
+//! deadline and returned as one body.
+
+use anyhow::Result;
+use aws_lambda_events::apigw::ApiGatewayProxyRequest;
+use aws_sdk_dynamodb::Client as DynamoDbClient;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use http_tunnel_common::constants::{
+    STREAM_DEADLINE_MARGIN_MS, STREAM_POLL_INITIAL_INTERVAL_MS, STREAM_POLL_MAX_INTERVAL_MS,
+};
+use http_tunnel_common::protocol::HttpResponse;
+use http_tunnel_common::utils::current_timestamp_millis;
+use http_tunnel_common::{decode_body, encode_body};
+use lambda_runtime::MetadataPrelude;
+use lambda_runtime::streaming::{Body, Response, Sender, channel};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::chunks::{self, PendingTable, StreamChunk};
+
+/// Whether a request came through a Lambda Function URL, which can stream responses
+pub fn is_function_url(request: &ApiGatewayProxyRequest) -> bool {
+    request
+        .request_context
+        .domain_name
+        .as_deref()
+        .is_some_and(|domain| domain.contains(".lambda-url."))
+}
+
+/// When a relayed stream must end: shortly before the invocation times out
+///
+/// `invocation_deadline_ms` is the Lambda deadline (Unix epoch milliseconds).
+pub fn stream_deadline(invocation_deadline_ms: u64) -> Instant {
+    let remaining = invocation_deadline_ms
+        .saturating_sub(current_timestamp_millis())
+        .saturating_sub(STREAM_DEADLINE_MARGIN_MS);
+    Instant::now() + Duration::from_millis(remaining)
+}
+
+/// Start relaying a streamed body, returning the response to stream back
+pub fn relay(
+    client: DynamoDbClient,
+    table: PendingTable,
+    response: HttpResponse,
+    deadline: Instant,
+) -> Result<Response<Body>> {
+    let (mut sender, body) = channel();
+    let metadata_prelude = metadata_prelude(&response);
+
+    tokio::spawn(async move {
+        let request_id = response.request_id;
+        match relay_chunks(&client, &table, &request_id, deadline, &mut sender).await {
+            Ok(pieces) => debug!("Relayed {} pieces of request {}", pieces, request_id),
+            Err(e) => {
+                warn!("Stream for request {} broken: {:#}", request_id, e);
+                sender.abort();
+            }
+        }
+    });
+
+    Ok(Response {
+        metadata_prelude,
+        stream: body,
+    })
+}
+
+/// Collect a streamed body into the response, for clients that can't be streamed to
+///
+/// Stops at the last piece or at `deadline`, whichever comes first, so a stream
+/// that never ends returns what it produced in time.
+pub async fn collect(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    response: &mut HttpResponse,
+    deadline: Instant,
+) -> Result<()> {
+    let mut body = Vec::new();
+    let mut index = 0;
+
+    while let Some(chunk) = next_chunk(client, table, &response.request_id, index, deadline).await?
+    {
+        body.extend(decode_body(&chunk.data)?);
+        index += 1;
+        if chunk.last {
+            break;
+        }
+    }
+    debug!(
+        "Collected {} pieces ({} bytes) of request {}",
+        index,
+        body.len(),
+        response.request_id
+    );
+
+    response.body = encode_body(&body);
+    response.streaming = false;
+    Ok(())
+}
+
+/// Send each piece to the client as soon as it's stored, returning how many were sent
+async fn relay_chunks(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    deadline: Instant,
+    sender: &mut Sender,
+) -> Result<u32> {
+    let mut index = 0;
+    while let Some(chunk) = next_chunk(client, table, request_id, index, deadline).await? {
+        let data = decode_body(&chunk.data)?;
+        if !data.is_empty() {
+            sender.send_data(Bytes::from(data)).await?;
+        }
+        index += 1;
+        if chunk.last {
+            break;
+        }
+    }
+    Ok(index)
+}
+
+/// Wait for piece `index` to be stored, checking more slowly the longer it takes
+///
+/// Returns `None` once `deadline` passes.
+async fn next_chunk(
+    client: &DynamoDbClient,
+    table: &PendingTable,
+    request_id: &str,
+    index: u32,
+    deadline: Instant,
+) -> Result<Option<StreamChunk>> {
+    let mut interval = Duration::from_millis(STREAM_POLL_INITIAL_INTERVAL_MS);
+    loop {
+        if let Some(chunk) = chunks::load_stream_chunk(client, table, request_id, index).await? {
+            return Ok(Some(chunk));
+        }
+        if Instant::now() + interval >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(interval).await;
+        interval = (interval * 2).min(Duration::from_millis(STREAM_POLL_MAX_INTERVAL_MS));
+    }
+}
+
+/// Status and headers of a streamed response
+fn metadata_prelude(response: &HttpResponse) -> MetadataPrelude {
+    let mut headers = HeaderMap::new();
+    let mut cookies = Vec::new();
+    for (name, values) in &response.headers {
+        // Function URLs take cookies separately from the headers
+        if name.eq_ignore_ascii_case("set-cookie") {
+            cookies.extend(values.iter().cloned());
+            continue;
+        }
+        let Ok(name) = HeaderName::from_bytes(name.as_bytes()) else {
+            continue;
+        };
+        for value in values {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.append(name.clone(), value);
+            }
+        }
+    }
+    // The length isn't known up front; the body ends when the stream does
+    headers.remove(http::header::CONTENT_LENGTH);
+
+    MetadataPrelude {
+        status_code: StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::BAD_GATEWAY),
+        headers,
+        cookies,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_metadata_prelude() {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.streaming = true;
+        response.headers = HashMap::from([
+            (
+                "content-type".to_string(),
+                vec!["text/event-stream".to_string()],
+            ),
+            ("content-length".to_string(), vec!["0".to_string()]),
+            (
+                "set-cookie".to_string(),
+                vec!["a=1".to_string(), "b=2".to_string()],
+            ),
+        ]);
+
+        let prelude = metadata_prelude(&response);
+        assert_eq!(prelude.status_code, StatusCode::OK);
+        assert_eq!(prelude.headers["content-type"], "text/event-stream");
+        assert!(!prelude.headers.contains_key("content-length"));
+        assert!(!prelude.headers.contains_key("set-cookie"));
+        assert_eq!(prelude.cookies, vec!["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn test_is_function_url() {
+        let mut request = ApiGatewayProxyRequest::default();
+        assert!(!is_function_url(&request));
+
+        request.request_context.domain_name =
+            Some("abc123.lambda-url.us-east-1.on.aws".to_string());
+        assert!(is_function_url(&request));
+
+        request.request_context.domain_name = Some("tunnel.example.com".to_string());
+        assert!(!is_function_url(&request));
+    }
+
+    #[test]
+    fn test_stream_deadline_leaves_margin() {
+        let deadline = stream_deadline(current_timestamp_millis() + 10_000);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        assert!(remaining <= Duration::from_millis(10_000 - STREAM_DEADLINE_MARGIN_MS));
+        assert!(remaining > Duration::from_millis(8_000 - STREAM_DEADLINE_MARGIN_MS));
+
+        // An invocation already past its deadline doesn't stream at all
+        assert!(stream_deadline(0) <= Instant::now());
+    }
+}
@@ -0,0 +1,291 @@
+//! Agent connections: handshake, then responses back to waiting requests
+
+use axum::extract::State;
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use futures_util::{SinkExt, StreamExt};
+use http_tunnel_common::constants::MAX_BODY_SIZE_BYTES;
+use http_tunnel_common::protocol::{
+    Capability, ChunkAssembler, ErrorCode, TunnelInfo, TunnelOptions, decode_binary, decode_text,
+};
+use http_tunnel_common::validation::validate_tunnel_id;
+use http_tunnel_common::{Message, generate_subdomain};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::proxy::error_response;
+use crate::registry::Agent;
+use crate::{AppState, ServerConfig};
+
+/// How long a new connection has to send its Ready message
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Features this server offers agents
+///
+/// Reserved tunnel IDs are first come, first served while the agent stays
+/// connected.
+const CAPABILITIES: [Capability; 3] = [
+    Capability::Reservations,
+    Capability::BasicAuth,
+    Capability::IpAccess,
+];
+
+/// Upgrade an agent's connection, if it presents the server's token
+pub async fn handle_agent(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    if !authorized(state.config.token.as_deref(), authorization) {
+        return (StatusCode::UNAUTHORIZED, "Invalid or missing token").into_response();
+    }
+    upgrade.on_upgrade(move |socket| serve_agent(state, socket))
+}
+
+/// Whether an `Authorization` header carries the server's token (if it has one)
+fn authorized(token: Option<&str>, authorization: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| presented.trim() == token)
+}
+
+/// Decode a WebSocket message from an agent; None for control frames
+fn decode(message: &WsMessage) -> Option<http_tunnel_common::Result<Message>> {
+    match message {
+        WsMessage::Text(text) => Some(decode_text(text)),
+        WsMessage::Binary(data) => Some(decode_binary(data)),
+        _ => None,
+    }
+}
+
+/// The tunnel a Ready message asks for: its reserved ID, or a fresh one
+fn requested_tunnel_id(options: &TunnelOptions) -> Result<String, String> {
+    match &options.tunnel_id {
+        Some(tunnel_id) => validate_tunnel_id(tunnel_id)
+            .map(|_| tunnel_id.clone())
+            .map_err(|e| e.to_string()),
+        None => Ok(generate_subdomain()),
+    }
+}
+
+/// The ConnectionEstablished message for a tunnel
+fn connection_established(config: &ServerConfig, connection_id: &str, tunnel_id: &str) -> Message {
+    let path_based_url = format!("{}/{}", config.public_url, tunnel_id);
+    let subdomain_url = config.domain.as_ref().map(|domain| {
+        let scheme = if config.public_url.starts_with("https://") {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{}://{}.{}", scheme, tunnel_id, domain)
+    });
+
+    Message::ConnectionEstablished {
+        connection_id: connection_id.to_string(),
+        tunnel_id: tunnel_id.to_string(),
+        public_url: subdomain_url
+            .clone()
+            .unwrap_or_else(|| path_based_url.clone()),
+        subdomain_url,
+        path_based_url: Some(path_based_url),
+        info: Some(TunnelInfo {
+            expires_at: None,
+            region: None,
+            max_body_size: Some(MAX_BODY_SIZE_BYTES),
+            capabilities: CAPABILITIES.to_vec(),
+        }),
+    }
+}
+
+/// Wait for the agent's Ready message
+async fn receive_ready(socket: &mut WebSocket) -> Option<TunnelOptions> {
+    let ready = async {
+        while let Some(Ok(message)) = socket.recv().await {
+            match decode(&message) {
+                Some(Ok(Message::Ready { options })) => return Some(options),
+                Some(Ok(_)) | None => continue,
+                Some(Err(e)) => {
+                    warn!("Invalid message during handshake: {}", e);
+                    return None;
+                }
+            }
+        }
+        None
+    };
+    tokio::time::timeout(READY_TIMEOUT, ready).await.ok()?
+}
+
+async fn serve_agent(state: Arc<AppState>, mut socket: WebSocket) {
+    let Some(options) = receive_ready(&mut socket).await else {
+        debug!("Agent left before sending Ready");
+        return;
+    };
+
+    let connection_id = format!("conn_{}", generate_subdomain());
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    let registered = requested_tunnel_id(&options).and_then(|tunnel_id| {
+        let agent = Agent::new(connection_id.clone(), options, sender.clone());
+        state
+            .registry
+            .register(&tunnel_id, agent)
+            .map(|_| tunnel_id.clone())
+            .ok_or_else(|| format!("Tunnel ID {} is in use", tunnel_id))
+    });
+    let tunnel_id = match registered {
+        Ok(tunnel_id) => tunnel_id,
+        Err(message) => {
+            warn!("Refused agent: {}", message);
+            let refusal = Message::Error {
+                request_id: None,
+                code: ErrorCode::TunnelIdUnavailable,
+                message,
+            };
+            if let Ok(json) = serde_json::to_string(&refusal) {
+                let _ = socket.send(WsMessage::Text(json.into())).await;
+            }
+            let _ = socket.close().await;
+            return;
+        }
+    };
+    info!("Tunnel {} opened by {}", tunnel_id, connection_id);
+
+    let _ = sender.send(connection_established(
+        &state.config,
+        &connection_id,
+        &tunnel_id,
+    ));
+
+    let (mut write, mut read) = socket.split();
+    let writer = tokio::spawn(async move {
+        while let Some(message) = outgoing.recv().await {
+            let json = match serde_json::to_string(&message) {
+                Ok(json) => json,
+                Err(e) => {
+                    warn!("Failed to serialize message: {}", e);
+                    continue;
+                }
+            };
+            if write.send(WsMessage::Text(json.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut assembler = ChunkAssembler::new();
+    while let Some(Ok(message)) = read.next().await {
+        let message = match decode(&message) {
+            Some(Ok(message)) => message,
+            Some(Err(e)) => {
+                warn!("Invalid message from {}: {}", connection_id, e);
+                continue;
+            }
+            None if matches!(message, WsMessage::Close(_)) => break,
+            None => continue,
+        };
+        let Some(message) = assembler.push(message) else {
+            continue;
+        };
+        match message {
+            Message::HttpResponse(response) => {
+                if !state.registry.complete(&connection_id, response) {
+                    debug!("Late or unknown response from {}", connection_id);
+                }
+            }
+            Message::Error {
+                request_id: Some(request_id),
+                code,
+                message,
+            } => {
+                let response = error_response(&request_id, &code, &message);
+                state.registry.complete(&connection_id, response);
+            }
+            Message::Ping => {
+                let _ = sender.send(Message::Pong);
+            }
+            other => debug!("Ignoring message from {}: {:?}", connection_id, other),
+        }
+    }
+
+    state.registry.unregister(&tunnel_id, &connection_id);
+    drop(sender);
+    writer.abort();
+    info!("Tunnel {} closed by {}", tunnel_id, connection_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(None, None));
+        assert!(authorized(Some("secret"), Some("Bearer secret")));
+        assert!(!authorized(Some("secret"), Some("Bearer other")));
+        assert!(!authorized(Some("secret"), Some("secret")));
+        assert!(!authorized(Some("secret"), None));
+    }
+
+    #[test]
+    fn test_requested_tunnel_id() {
+        let options = TunnelOptions {
+            tunnel_id: Some("abc123def456".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(requested_tunnel_id(&options).unwrap(), "abc123def456");
+
+        let options = TunnelOptions {
+            tunnel_id: Some("_admin".to_string()),
+            ..Default::default()
+        };
+        assert!(requested_tunnel_id(&options).is_err());
+
+        let generated = requested_tunnel_id(&TunnelOptions::default()).unwrap();
+        assert!(validate_tunnel_id(&generated).is_ok());
+    }
+
+    #[test]
+    fn test_connection_established_urls() {
+        let mut config = ServerConfig {
+            public_url: "https://tunnel.example.com".to_string(),
+            domain: None,
+            token: None,
+            request_timeout: Duration::from_secs(25),
+        };
+        let Message::ConnectionEstablished {
+            public_url,
+            subdomain_url,
+            info,
+            ..
+        } = connection_established(&config, "conn_1", "abc123def456")
+        else {
+            panic!("expected ConnectionEstablished");
+        };
+        assert_eq!(public_url, "https://tunnel.example.com/abc123def456");
+        assert!(subdomain_url.is_none());
+        assert!(info.unwrap().supports(Capability::BasicAuth));
+
+        config.domain = Some("tunnel.example.com".to_string());
+        let Message::ConnectionEstablished {
+            public_url,
+            path_based_url,
+            ..
+        } = connection_established(&config, "conn_1", "abc123def456")
+        else {
+            panic!("expected ConnectionEstablished");
+        };
+        assert_eq!(public_url, "https://abc123def456.tunnel.example.com");
+        assert_eq!(
+            path_based_url.as_deref(),
+            Some("https://tunnel.example.com/abc123def456")
+        );
+    }
+}
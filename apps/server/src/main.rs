@@ -0,0 +1,223 @@
+//! Self-hosted tunnel server
+//!
+//! Serves the same WebSocket protocol as the Lambda handler, from a single
+//! process without AWS:
+//! - `/_agent` - agents (`ttf --endpoint ws://host/_agent`) open tunnels here
+//! - `/_health` - liveness check
+//! - everything else - public requests, routed by subdomain (with `--domain`)
+//!   or by the first path segment, and forwarded to the tunnel's agent
+//!
+//! Tunnels and pending requests are kept in memory (see [`registry`]).
+
+mod agent;
+mod proxy;
+mod registry;
+mod routing;
+
+use anyhow::{Context, Result};
+use axum::Router;
+use axum::routing::get;
+use clap::Parser;
+use http_tunnel_common::constants::REQUEST_TIMEOUT_SECS;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
+
+use registry::Registry;
+
+/// Self-hosted HTTP tunnel server
+#[derive(Debug, Parser)]
+#[command(name = "tunnel-server", version, about)]
+struct Args {
+    /// Address to listen on
+    #[arg(long, env = "TUNNEL_LISTEN", default_value = "0.0.0.0:8080")]
+    listen: SocketAddr,
+
+    /// Base URL the server is reached at, used in the URLs given to agents
+    /// (default: http://localhost:{port})
+    #[arg(long, env = "TUNNEL_PUBLIC_URL")]
+    public_url: Option<String>,
+
+    /// Route `{tunnel_id}.{domain}` hosts to tunnels (needs a wildcard DNS record)
+    #[arg(long, env = "TUNNEL_DOMAIN")]
+    domain: Option<String>,
+
+    /// Token agents must present as `Authorization: Bearer` (default: anyone may
+    /// open a tunnel)
+    #[arg(long, env = "TUNNEL_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Seconds to wait for an agent's response
+    #[arg(long, env = "TUNNEL_REQUEST_TIMEOUT", default_value_t = REQUEST_TIMEOUT_SECS)]
+    request_timeout: u64,
+}
+
+/// How the server was configured
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Base URL of path-routed tunnels, without a trailing slash
+    pub public_url: String,
+    pub domain: Option<String>,
+    pub token: Option<String>,
+    pub request_timeout: Duration,
+}
+
+impl ServerConfig {
+    fn from_args(args: &Args) -> Self {
+        let public_url = args
+            .public_url
+            .clone()
+            .unwrap_or_else(|| format!("http://localhost:{}", args.listen.port()));
+        Self {
+            public_url: public_url.trim_end_matches('/').to_string(),
+            domain: args.domain.clone(),
+            token: args.token.clone(),
+            request_timeout: Duration::from_secs(args.request_timeout),
+        }
+    }
+}
+
+/// State shared by every request
+#[derive(Debug)]
+pub struct AppState {
+    pub config: ServerConfig,
+    pub registry: Registry,
+}
+
+/// Routes of the server
+pub fn router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/_agent", get(agent::handle_agent))
+        .route("/_health", get(|| async { "ok" }))
+        .fallback(proxy::handle_public)
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
+
+    let args = Args::parse();
+    let state = Arc::new(AppState {
+        config: ServerConfig::from_args(&args),
+        registry: Registry::new(),
+    });
+    if state.config.token.is_none() {
+        tracing::warn!("No --token set: anyone can open tunnels on this server");
+    }
+
+    let listener = tokio::net::TcpListener::bind(args.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    info!("Listening on {}", args.listen);
+    info!("Agents connect to {}/_agent", state.config.public_url);
+
+    axum::serve(
+        listener,
+        router(state).into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("Shutting down");
+    })
+    .await
+    .context("Server failed")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_defaults() {
+        let args = Args::parse_from(["tunnel-server"]);
+        let config = ServerConfig::from_args(&args);
+        assert_eq!(config.public_url, "http://localhost:8080");
+        assert_eq!(
+            config.request_timeout,
+            Duration::from_secs(REQUEST_TIMEOUT_SECS)
+        );
+
+        let args = Args::parse_from([
+            "tunnel-server",
+            "--public-url",
+            "https://tunnel.example.com/",
+        ]);
+        let config = ServerConfig::from_args(&args);
+        assert_eq!(config.public_url, "https://tunnel.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_request_through_tunnel() {
+        use futures_util::{SinkExt, StreamExt};
+        use http_tunnel_common::{HttpResponse, Message, encode_body};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::tungstenite::Message as WsMessage;
+        use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(AppState {
+            config: ServerConfig {
+                public_url: format!("http://{}", addr),
+                domain: None,
+                token: Some("secret".to_string()),
+                request_timeout: Duration::from_secs(5),
+            },
+            registry: Registry::new(),
+        });
+        let app = router(state).into_make_service_with_connect_info::<SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        // Agents without the token are turned away
+        let url = format!("ws://{}/_agent", addr);
+        assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+
+        let mut request = url.into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert("Authorization", "Bearer secret".parse().unwrap());
+        let (mut agent, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+        let ready = serde_json::to_string(&Message::Ready {
+            options: Default::default(),
+        })
+        .unwrap();
+        agent.send(WsMessage::Text(ready.into())).await.unwrap();
+
+        let Some(Ok(WsMessage::Text(text))) = agent.next().await else {
+            panic!("expected ConnectionEstablished");
+        };
+        let Message::ConnectionEstablished { tunnel_id, .. } = serde_json::from_str(&text).unwrap()
+        else {
+            panic!("expected ConnectionEstablished");
+        };
+
+        // Answer one request with its URI
+        tokio::spawn(async move {
+            while let Some(Ok(WsMessage::Text(text))) = agent.next().await {
+                if let Ok(Message::HttpRequest(request)) = serde_json::from_str(&text) {
+                    let mut response = HttpResponse::new(request.request_id, 200);
+                    response.body = encode_body(request.uri.as_bytes());
+                    let reply = serde_json::to_string(&Message::HttpResponse(response)).unwrap();
+                    agent.send(WsMessage::Text(reply.into())).await.unwrap();
+                }
+            }
+        });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /{}/hello?x=1 HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            tunnel_id, addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("/hello?x=1"), "{}", response);
+    }
+}
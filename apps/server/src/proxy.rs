@@ -0,0 +1,186 @@
+//! Public requests: forwarded to the tunnel's agent, answered with its response
+
+use axum::body::{Body, to_bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use http_tunnel_common::constants::MAX_BODY_SIZE_BYTES;
+use http_tunnel_common::{
+    ErrorCode, HttpRequest, HttpResponse, Message, current_timestamp_millis, decode_body,
+    encode_body, generate_request_id, headers_to_map, map_to_headers,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::AppState;
+use crate::routing;
+
+/// Headers of the agent's response that belong to its own connection
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "transfer-encoding",
+    "content-length",
+    "upgrade",
+];
+
+/// An error response, as the agent would send for `code`
+pub fn error_response(request_id: &str, code: &ErrorCode, message: &str) -> HttpResponse {
+    let mut response = HttpResponse::new(request_id.to_string(), code.http_status());
+    response
+        .headers
+        .insert("Content-Type".to_string(), vec!["text/plain".to_string()]);
+    response.body = encode_body(message.as_bytes());
+    response
+}
+
+fn error(code: ErrorCode, message: &str) -> Response {
+    into_response(error_response("", &code, message))
+}
+
+/// Turn an agent's response into the public one
+fn into_response(mut response: HttpResponse) -> Response {
+    let body = response
+        .decompress_body()
+        .map_err(|e| e.to_string())
+        .and_then(|_| decode_body(&response.body).map_err(|e| e.to_string()));
+    let body = match body {
+        Ok(body) => body,
+        Err(e) => {
+            debug!("Undecodable body in {}: {}", response.request_id, e);
+            return (StatusCode::BAD_GATEWAY, "Invalid response from agent").into_response();
+        }
+    };
+    let status = StatusCode::from_u16(response.status_code).unwrap_or(StatusCode::BAD_GATEWAY);
+
+    let mut headers = map_to_headers(&response.headers);
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+    (status, headers, Body::from(body)).into_response()
+}
+
+/// Forward a public request to its tunnel
+pub async fn handle_public(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+) -> Response {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok());
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let Some(route) = routing::resolve(host, state.config.domain.as_deref(), path_and_query) else {
+        return (StatusCode::NOT_FOUND, "Tunnel not found").into_response();
+    };
+    let Some(agent) = state.registry.agent(&route.tunnel_id) else {
+        return (StatusCode::NOT_FOUND, "Tunnel not found").into_response();
+    };
+
+    if !agent
+        .options
+        .ip_access
+        .permits(Some(&peer.ip().to_string()))
+    {
+        return (StatusCode::FORBIDDEN, "Forbidden").into_response();
+    }
+    let (parts, body) = request.into_parts();
+    let mut headers = parts.headers;
+    if let Some(basic_auth) = &agent.options.basic_auth {
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if !basic_auth.verify(authorization) {
+            return (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Basic realm=\"tunnel\"")],
+                "Unauthorized",
+            )
+                .into_response();
+        }
+        // The credentials are for the tunnel, not the local service
+        headers.remove(header::AUTHORIZATION);
+    }
+
+    let Ok(body) = to_bytes(body, MAX_BODY_SIZE_BYTES).await else {
+        return error(ErrorCode::PayloadTooLarge, "Request body too large");
+    };
+
+    let request_id = generate_request_id();
+    let mut forwarded = HttpRequest::new(
+        parts.method.to_string(),
+        route.uri,
+        request_id.clone(),
+        current_timestamp_millis(),
+    );
+    forwarded.correlation_id = Some(
+        headers
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or(&request_id)
+            .to_string(),
+    );
+    forwarded.headers = headers_to_map(&headers);
+    forwarded.body = encode_body(&body);
+    forwarded.timeout_ms = Some(state.config.request_timeout.as_millis() as u64);
+
+    let reply = state.registry.expect(&request_id, &agent.connection_id);
+    if !agent.send(Message::HttpRequest(forwarded)) {
+        state.registry.forget(&request_id);
+        return error(ErrorCode::TunnelUnavailable, "Tunnel is not connected");
+    }
+
+    match tokio::time::timeout(state.config.request_timeout, reply).await {
+        Ok(Ok(response)) => into_response(response),
+        Ok(Err(_)) => error(ErrorCode::TunnelUnavailable, "Tunnel closed"),
+        Err(_) => {
+            state.registry.forget(&request_id);
+            error(ErrorCode::Timeout, "Request timed out")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_into_response() {
+        let mut response = HttpResponse::new("req_1".to_string(), 201);
+        response.headers.insert(
+            "content-type".to_string(),
+            vec!["application/json".to_string()],
+        );
+        response
+            .headers
+            .insert("transfer-encoding".to_string(), vec!["chunked".to_string()]);
+        response.body = encode_body(b"{\"ok\":true}");
+
+        let response = into_response(response);
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert!(response.headers().get("transfer-encoding").is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"{\"ok\":true}");
+    }
+
+    #[tokio::test]
+    async fn test_into_response_rejects_bad_body() {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = "not base64!".to_string();
+        assert_eq!(into_response(response).status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response("req_1", &ErrorCode::Timeout, "Request timed out");
+        assert_eq!(response.status_code, 504);
+        assert_eq!(decode_body(&response.body).unwrap(), b"Request timed out");
+    }
+}
@@ -0,0 +1,214 @@
+//! Connected agents and the requests waiting on them
+//!
+//! Everything lives in this process: restarting the server drops every tunnel,
+//! and agents reconnect (keeping a reserved `tunnel_id` if they asked for one).
+
+use http_tunnel_common::{HttpResponse, Message, TunnelOptions};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// An agent's WebSocket connection, serving one tunnel
+#[derive(Debug)]
+pub struct Agent {
+    pub connection_id: String,
+    /// Options from the agent's Ready message
+    pub options: TunnelOptions,
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Agent {
+    pub fn new(
+        connection_id: String,
+        options: TunnelOptions,
+        sender: mpsc::UnboundedSender<Message>,
+    ) -> Self {
+        Self {
+            connection_id,
+            options,
+            sender,
+        }
+    }
+
+    /// Queue a message for the agent; false if its connection is gone
+    pub fn send(&self, message: Message) -> bool {
+        self.sender.send(message).is_ok()
+    }
+
+    fn is_connected(&self) -> bool {
+        !self.sender.is_closed()
+    }
+}
+
+#[derive(Debug)]
+struct Waiting {
+    connection_id: String,
+    reply: oneshot::Sender<HttpResponse>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    tunnels: HashMap<String, Arc<Agent>>,
+    pending: HashMap<String, Waiting>,
+}
+
+/// Tunnels by ID, and pending requests by request ID
+#[derive(Debug, Default)]
+pub struct Registry {
+    inner: Mutex<Inner>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Serve `tunnel_id` with `agent`, unless a connected agent already does
+    pub fn register(&self, tunnel_id: &str, agent: Agent) -> Option<Arc<Agent>> {
+        let mut inner = self.lock();
+        if inner
+            .tunnels
+            .get(tunnel_id)
+            .is_some_and(|current| current.is_connected())
+        {
+            return None;
+        }
+        let agent = Arc::new(agent);
+        inner.tunnels.insert(tunnel_id.to_string(), agent.clone());
+        Some(agent)
+    }
+
+    /// Drop a connection's tunnel; its waiting requests fail right away
+    pub fn unregister(&self, tunnel_id: &str, connection_id: &str) {
+        let mut inner = self.lock();
+        if inner
+            .tunnels
+            .get(tunnel_id)
+            .is_some_and(|agent| agent.connection_id == connection_id)
+        {
+            inner.tunnels.remove(tunnel_id);
+        }
+        inner
+            .pending
+            .retain(|_, waiting| waiting.connection_id != connection_id);
+    }
+
+    /// The agent serving a tunnel
+    pub fn agent(&self, tunnel_id: &str) -> Option<Arc<Agent>> {
+        self.lock().tunnels.get(tunnel_id).cloned()
+    }
+
+    /// Wait for a connection's response to a request
+    ///
+    /// The receiver fails if the connection closes before answering.
+    pub fn expect(&self, request_id: &str, connection_id: &str) -> oneshot::Receiver<HttpResponse> {
+        let (reply, receiver) = oneshot::channel();
+        self.lock().pending.insert(
+            request_id.to_string(),
+            Waiting {
+                connection_id: connection_id.to_string(),
+                reply,
+            },
+        );
+        receiver
+    }
+
+    /// Hand a connection's response to the waiting request
+    ///
+    /// Returns false if nobody is waiting, or the request was sent to another
+    /// connection.
+    pub fn complete(&self, connection_id: &str, response: HttpResponse) -> bool {
+        let mut inner = self.lock();
+        let Some(waiting) = inner.pending.remove(&response.request_id) else {
+            return false;
+        };
+        if waiting.connection_id != connection_id {
+            inner.pending.insert(response.request_id.clone(), waiting);
+            return false;
+        }
+        waiting.reply.send(response).is_ok()
+    }
+
+    /// Stop waiting for a request (after a timeout)
+    pub fn forget(&self, request_id: &str) {
+        self.lock().pending.remove(request_id);
+    }
+
+    /// Number of requests waiting for a response
+    pub fn pending(&self) -> usize {
+        self.lock().pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn agent(connection_id: &str) -> (Agent, mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            Agent::new(connection_id.to_string(), TunnelOptions::default(), sender),
+            receiver,
+        )
+    }
+
+    #[test]
+    fn test_register_keeps_connected_agent() {
+        let registry = Registry::new();
+        let (first, first_rx) = agent("conn_1");
+        assert!(registry.register("abc123def456", first).is_some());
+
+        let (second, _second_rx) = agent("conn_2");
+        assert!(registry.register("abc123def456", second).is_none());
+
+        // Once the first agent is gone, the ID can be taken over
+        drop(first_rx);
+        let (third, _third_rx) = agent("conn_3");
+        assert!(registry.register("abc123def456", third).is_some());
+        assert_eq!(
+            registry.agent("abc123def456").unwrap().connection_id,
+            "conn_3"
+        );
+
+        // A stale connection doesn't remove its successor
+        registry.unregister("abc123def456", "conn_1");
+        assert!(registry.agent("abc123def456").is_some());
+        registry.unregister("abc123def456", "conn_3");
+        assert!(registry.agent("abc123def456").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_complete_delivers_response() {
+        let registry = Registry::new();
+        let receiver = registry.expect("req_1", "conn_1");
+
+        // Only the connection the request was sent to may answer it
+        let response = HttpResponse::new("req_1".to_string(), 200);
+        assert!(!registry.complete("conn_2", response.clone()));
+        assert!(registry.complete("conn_1", response.clone()));
+        assert!(!registry.complete("conn_1", response));
+
+        assert_eq!(receiver.await.unwrap().status_code, 200);
+        assert_eq!(registry.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unregister_fails_waiting_requests() {
+        let registry = Registry::new();
+        let (first, _rx) = agent("conn_1");
+        registry.register("abc123def456", first);
+        let receiver = registry.expect("req_1", "conn_1");
+        let other = registry.expect("req_2", "conn_2");
+
+        registry.unregister("abc123def456", "conn_1");
+        assert!(receiver.await.is_err());
+        assert_eq!(registry.pending(), 1);
+
+        registry.forget("req_2");
+        assert!(other.await.is_err());
+    }
+}
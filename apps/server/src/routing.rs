@@ -0,0 +1,108 @@
+//! Which tunnel a public request is for
+
+use http_tunnel_common::validation::validate_tunnel_id;
+
+/// A public request resolved to a tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub tunnel_id: String,
+    /// Path and query to request from the local service
+    pub uri: String,
+}
+
+/// Resolve a request from its `Host` header and path (with query)
+///
+/// With a `domain`, `{tunnel_id}.{domain}` hosts route by subdomain and keep
+/// the whole path. Otherwise the first path segment names the tunnel and is
+/// stripped: `/abc123def456/api?x=1` becomes `/api?x=1`.
+pub fn resolve(host: Option<&str>, domain: Option<&str>, path_and_query: &str) -> Option<Route> {
+    if let Some(tunnel_id) = host
+        .zip(domain)
+        .and_then(|(host, domain)| subdomain(host, domain))
+    {
+        return Some(Route {
+            tunnel_id,
+            uri: path_and_query.to_string(),
+        });
+    }
+
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+    let (tunnel_id, rest) = match path.trim_start_matches('/').split_once('/') {
+        Some((tunnel_id, rest)) => (tunnel_id, rest),
+        None => (path.trim_start_matches('/'), ""),
+    };
+    validate_tunnel_id(tunnel_id).ok()?;
+
+    let mut uri = format!("/{}", rest);
+    if let Some(query) = query {
+        uri.push('?');
+        uri.push_str(query);
+    }
+    Some(Route {
+        tunnel_id: tunnel_id.to_string(),
+        uri,
+    })
+}
+
+/// The tunnel ID of a `{tunnel_id}.{domain}` host, ignoring any port
+fn subdomain(host: &str, domain: &str) -> Option<String> {
+    let host = host.split(':').next().unwrap_or(host);
+    let label = host.strip_suffix(domain)?.strip_suffix('.')?;
+    validate_tunnel_id(label).ok()?;
+    Some(label.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_by_path() {
+        let route = resolve(
+            Some("example.com"),
+            None,
+            "/abc123def456/api/users?limit=10",
+        )
+        .unwrap();
+        assert_eq!(route.tunnel_id, "abc123def456");
+        assert_eq!(route.uri, "/api/users?limit=10");
+
+        let route = resolve(None, None, "/abc123def456").unwrap();
+        assert_eq!(route.uri, "/");
+        let route = resolve(None, None, "/abc123def456?x=1").unwrap();
+        assert_eq!(route.uri, "/?x=1");
+    }
+
+    #[test]
+    fn test_resolve_by_subdomain() {
+        let route = resolve(
+            Some("abc123def456.tunnel.example.com:8080"),
+            Some("tunnel.example.com"),
+            "/api?x=1",
+        )
+        .unwrap();
+        assert_eq!(route.tunnel_id, "abc123def456");
+        assert_eq!(route.uri, "/api?x=1");
+
+        // The bare domain falls back to path routing
+        let route = resolve(
+            Some("tunnel.example.com"),
+            Some("tunnel.example.com"),
+            "/abc123def456/api",
+        )
+        .unwrap();
+        assert_eq!(route.uri, "/api");
+    }
+
+    #[test]
+    fn test_resolve_rejects_invalid_tunnel_ids() {
+        assert!(resolve(None, None, "/").is_none());
+        assert!(resolve(None, None, "/_agent").is_none());
+        assert!(resolve(None, None, "/ABC/api").is_none());
+        assert!(resolve(Some("x.y.example.com"), Some("example.com"), "/").is_none());
+        assert!(resolve(Some("evilexample.com"), Some("example.com"), "/").is_none());
+    }
+}
@@ -0,0 +1,80 @@
+//! Cost and payoff of compressing message bodies
+//!
+//! Run with `cargo bench -p http-tunnel-common`. Each payload is compressed and
+//! decompressed with every [`BodyCompression`]; the sizes are printed once so
+//! the time can be weighed against the bytes saved on the WebSocket.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use http_tunnel_common::protocol::BodyCompression;
+use std::hint::black_box;
+
+const ALGORITHMS: [BodyCompression; 2] = [BodyCompression::Zstd, BodyCompression::Deflate];
+
+fn json_payload() -> Vec<u8> {
+    let items: Vec<String> = (0..2000)
+        .map(|i| {
+            format!(
+                r#"{{"id":{},"name":"Item {}","price":{}.99,"tags":["sale","new"],"href":"/api/items/{}"}}"#,
+                i,
+                i,
+                i % 100,
+                i
+            )
+        })
+        .collect();
+    format!(r#"{{"items":[{}],"total":2000}}"#, items.join(",")).into_bytes()
+}
+
+fn html_payload() -> Vec<u8> {
+    let rows: String = (0..2000)
+        .map(|i| {
+            format!(
+                r#"<tr class="row"><td>{}</td><td><a href="/items/{}">Item {}</a></td></tr>"#,
+                i, i, i
+            )
+        })
+        .collect();
+    format!(
+        "<!DOCTYPE html><html><head><title>Items</title></head><body><table>{}</table></body></html>",
+        rows
+    )
+    .into_bytes()
+}
+
+fn bench_compression(c: &mut Criterion) {
+    for (name, payload) in [("json", json_payload()), ("html", html_payload())] {
+        let mut group = c.benchmark_group(format!("compress_{}", name));
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        for algorithm in ALGORITHMS {
+            let compressed = algorithm.compress(&payload).unwrap();
+            println!(
+                "{} {:?}: {} -> {} bytes",
+                name,
+                algorithm,
+                payload.len(),
+                compressed.len()
+            );
+            group.bench_with_input(
+                BenchmarkId::from_parameter(format!("{:?}", algorithm)),
+                &payload,
+                |b, payload| b.iter(|| algorithm.compress(black_box(payload)).unwrap()),
+            );
+        }
+        group.finish();
+
+        let mut group = c.benchmark_group(format!("decompress_{}", name));
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        for algorithm in ALGORITHMS {
+            let compressed = algorithm.compress(&payload).unwrap();
+            group.bench_with_input(
+                BenchmarkId::from_parameter(format!("{:?}", algorithm)),
+                &compressed,
+                |b, compressed| b.iter(|| algorithm.decompress(black_box(compressed)).unwrap()),
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
@@ -1,9 +1,21 @@
 /// Maximum connection lifetime before requiring reconnection (2 hours)
 pub const MAX_CONNECTION_LIFETIME_SECS: i64 = 7200;
 
+/// How long before the connection lifetime ends the agent is asked to hand off (10 minutes)
+pub const HANDOFF_WINDOW_SECS: i64 = 600;
+
+/// How long before an idle or expiring connection is closed the agent is warned (5 minutes)
+pub const CLOSE_WARNING_SECS: i64 = 300;
+
+/// How long a handing-off agent waits for in-flight requests before closing (30 seconds)
+pub const HANDOFF_DRAIN_TIMEOUT_SECS: u64 = 30;
+
 /// DynamoDB TTL buffer for cleanup of old connections (2 hours)
 pub const CONNECTION_TTL_SECS: i64 = 7200;
 
+/// Minimum time between activity-based TTL refreshes per connection (1 minute)
+pub const TTL_REFRESH_INTERVAL_SECS: u64 = 60;
+
 /// Heartbeat interval to keep WebSocket connection alive (5 minutes)
 pub const HEARTBEAT_INTERVAL_SECS: u64 = 300;
 
@@ -13,12 +25,21 @@ pub const WEBSOCKET_IDLE_TIMEOUT_SECS: u64 = 600;
 /// Request timeout waiting for response from agent (under API Gateway's 29s limit)
 pub const REQUEST_TIMEOUT_SECS: u64 = 25;
 
+/// Time reserved for the response trip back to the edge when computing the agent's deadline
+pub const REQUEST_DEADLINE_MARGIN_MS: u64 = 500;
+
 /// Pending request TTL in DynamoDB (30 seconds)
 pub const PENDING_REQUEST_TTL_SECS: i64 = 30;
 
 /// Maximum request/response body size (2 MB per API Gateway limit)
 pub const MAX_BODY_SIZE_BYTES: usize = 2 * 1024 * 1024;
 
+/// Smallest (Base64-encoded) body worth compressing inside a message (1 KB)
+pub const COMPRESSION_MIN_BODY_BYTES: usize = 1024;
+
+/// Largest body a compressed message body may expand to (64 MB)
+pub const MAX_DECOMPRESSED_BODY_BYTES: usize = 64 * 1024 * 1024;
+
 /// Minimum delay for exponential backoff reconnection (1 second)
 pub const RECONNECT_MIN_DELAY_MS: u64 = 1000;
 
@@ -28,6 +49,12 @@ pub const RECONNECT_MAX_DELAY_MS: u64 = 60000;
 /// Multiplier for exponential backoff reconnection
 pub const RECONNECT_MULTIPLIER: f64 = 2.0;
 
+/// First delay before retrying a local service that refused the connection (100ms)
+pub const LOCAL_RETRY_MIN_DELAY_MS: u64 = 100;
+
+/// Longest delay between retries of the local service (2 seconds)
+pub const LOCAL_RETRY_MAX_DELAY_MS: u64 = 2000;
+
 /// Initial polling interval when waiting for response (50ms)
 pub const POLL_INITIAL_INTERVAL_MS: u64 = 50;
 
@@ -37,6 +64,12 @@ pub const POLL_MAX_INTERVAL_MS: u64 = 500;
 /// Polling backoff multiplier
 pub const POLL_BACKOFF_MULTIPLIER: u32 = 2;
 
+/// Maximum DynamoDB read capacity units a single request may spend polling for its response
+pub const POLL_MAX_READ_UNITS_PER_REQUEST: f64 = 25.0;
+
+/// Every Nth poll uses a strongly consistent read; the rest are eventually consistent (half cost)
+pub const POLL_CONSISTENT_READ_EVERY: u32 = 4;
+
 /// Optimized polling: first check interval (200ms) - covers fast responses
 pub const OPTIMIZED_POLL_FIRST_INTERVAL_MS: u64 = 200;
 
@@ -46,6 +79,70 @@ pub const OPTIMIZED_POLL_SECOND_INTERVAL_MS: u64 = 300;
 /// Optimized polling: final polling interval (400ms) - for edge cases
 pub const OPTIMIZED_POLL_FINAL_INTERVAL_MS: u64 = 400;
 
+/// Adaptive polling: samples required before a tunnel's latency history is trusted
+pub const ADAPTIVE_POLL_MIN_SAMPLES: u32 = 3;
+
+/// Adaptive polling: weight given to each new latency sample (0.0 - 1.0)
+pub const ADAPTIVE_POLL_EWMA_WEIGHT: f64 = 0.2;
+
+/// Adaptive polling: maximum tunnels tracked per handler container
+pub const ADAPTIVE_POLL_MAX_TRACKED_TUNNELS: usize = 1024;
+
+/// Agent balancing: maximum agent connections tracked per handler container
+pub const BALANCER_MAX_TRACKED_CONNECTIONS: usize = 4096;
+
+/// Connection cache: how long a tunnel's connections are reused per handler container
+pub const CONNECTION_CACHE_TTL_SECS: u64 = 5;
+
+/// Connection cache: maximum tunnels cached per handler container
+pub const CONNECTION_CACHE_MAX_TUNNELS: usize = 1024;
+
+/// Tunnel stats: width of each aggregation bucket (1 minute)
+pub const STATS_BUCKET_SECS: i64 = 60;
+
+/// Tunnel stats: how long aggregation buckets are kept (2 hours)
+pub const STATS_RETENTION_SECS: i64 = 7200;
+
+/// Tunnel stats: rolling windows reported by the admin API (5 minutes, 1 hour)
+pub const STATS_WINDOWS_SECS: [i64; 2] = [300, 3600];
+
+/// Tunnel analytics: lifetime totals expire after this long without traffic (30 days)
+pub const ANALYTICS_RETENTION_SECS: i64 = 2_592_000;
+
+/// Tunnel analytics: minimum time between `TunnelStats` messages per tunnel (1 minute)
+pub const ANALYTICS_PUSH_INTERVAL_SECS: i64 = 60;
+
+/// Tunnel alerts: minimum time between threshold evaluations per tunnel (1 minute)
+pub const ALERT_EVALUATION_INTERVAL_SECS: u64 = 60;
+
+/// Tunnel alerts: minimum time between alerts for the same connection (15 minutes)
+pub const ALERT_COOLDOWN_SECS: i64 = 900;
+
+/// Push delivery: longest single SQS long-poll on a reply queue (SQS maximum, 20 seconds)
+pub const REPLY_QUEUE_MAX_WAIT_SECS: u64 = 20;
+
+/// Push delivery: reply queues older than this are deleted by the cleanup job (6 hours)
+pub const REPLY_QUEUE_MAX_AGE_SECS: i64 = 21600;
+
+/// API Gateway WebSocket frame size limit (32 KB)
+pub const WEBSOCKET_FRAME_LIMIT_BYTES: usize = 32 * 1024;
+
+/// Base64 body bytes carried by each `BodyChunk`, leaving room for the envelope and headers
+pub const BODY_CHUNK_SIZE_BYTES: usize = 24 * 1024;
+
+/// Streaming: body bytes carried by each `ResponseChunk` (Base64-encodes to `BODY_CHUNK_SIZE_BYTES`)
+pub const STREAM_CHUNK_SIZE_BYTES: usize = BODY_CHUNK_SIZE_BYTES / 4 * 3;
+
+/// Streaming: first and longest interval between checks for the next piece of a streamed body
+pub const STREAM_POLL_INITIAL_INTERVAL_MS: u64 = 20;
+pub const STREAM_POLL_MAX_INTERVAL_MS: u64 = 250;
+
+/// Streaming: time reserved before the invocation times out to close a relayed stream
+pub const STREAM_DEADLINE_MARGIN_MS: u64 = 1000;
+
+/// Honeypot: how long an IP that hit a trap path stays on the denylist (24 hours)
+pub const HONEYPOT_BAN_SECS: i64 = 86400;
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -59,6 +156,15 @@ mod tests {
         const _: () = assert!(PENDING_REQUEST_TTL_SECS < MAX_CONNECTION_LIFETIME_SECS);
         const _: () = assert!(RECONNECT_MIN_DELAY_MS < RECONNECT_MAX_DELAY_MS);
         const _: () = assert!(RECONNECT_MULTIPLIER > 1.0);
+        const _: () = assert!(LOCAL_RETRY_MIN_DELAY_MS < LOCAL_RETRY_MAX_DELAY_MS);
+        const _: () = assert!(REQUEST_DEADLINE_MARGIN_MS < REQUEST_TIMEOUT_SECS * 1000);
+        const _: () = assert!(STATS_WINDOWS_SECS[1] <= STATS_RETENTION_SECS);
+        const _: () = assert!(BODY_CHUNK_SIZE_BYTES < WEBSOCKET_FRAME_LIMIT_BYTES);
+        const _: () = assert!(ADAPTIVE_POLL_EWMA_WEIGHT > 0.0 && ADAPTIVE_POLL_EWMA_WEIGHT <= 1.0);
+        const _: () = assert!(HANDOFF_WINDOW_SECS < MAX_CONNECTION_LIFETIME_SECS);
+        const _: () = assert!((HANDOFF_DRAIN_TIMEOUT_SECS as i64) < HANDOFF_WINDOW_SECS);
+        // Agents are asked to hand off before they're warned the connection ends
+        const _: () = assert!(CLOSE_WARNING_SECS < HANDOFF_WINDOW_SECS);
 
         // Verify size limits
         assert_eq!(MAX_BODY_SIZE_BYTES, 2 * 1024 * 1024);
@@ -0,0 +1,253 @@
+//! End-to-end encryption of bodies between clients and the forwarder
+//!
+//! A tunnel started with `--e2e-key` only accepts requests sealed with that
+//! key, and seals its responses with it, so bodies are opaque to the handler
+//! and everything it stores. The key is shared out of band with the clients.
+//!
+//! Sealed messages carry `x-tunnel-e2e: aes-256-gcm`. A sealed body is a
+//! random 12-byte nonce followed by the AES-256-GCM ciphertext and tag.
+//! Sensitive headers ([`SEALED_HEADERS`]) are removed and travel as a JSON
+//! object of header names to value lists, sealed the same way and
+//! Base64-encoded into `x-tunnel-sealed-headers`. Other headers, the method
+//! and the path stay in clear so requests can still be routed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+use crate::error::{Result, TunnelError};
+
+/// Marks a sealed request or response; the value names the cipher
+pub const E2E_HEADER: &str = "x-tunnel-e2e";
+
+/// Cipher named in [`E2E_HEADER`]
+pub const E2E_CIPHER: &str = "aes-256-gcm";
+
+/// Carries the sealed sensitive headers
+pub const SEALED_HEADERS_HEADER: &str = "x-tunnel-sealed-headers";
+
+/// Headers sealed along with the body (lowercase)
+///
+/// `content-type` is included so the handler doesn't rewrite sealed bodies;
+/// sealed messages are sent as `application/octet-stream`.
+pub const SEALED_HEADERS: [&str; 7] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "content-type",
+    "content-encoding",
+];
+
+const NONCE_LEN: usize = 12;
+const KEY_LEN: usize = 32;
+
+/// A 256-bit key shared between a tunnel and its clients
+#[derive(Clone)]
+pub struct E2eKey {
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for E2eKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("E2eKey(..)")
+    }
+}
+
+impl FromStr for E2eKey {
+    type Err = TunnelError;
+
+    /// Parse a Base64-encoded 32-byte key (e.g. from `openssl rand -base64 32`)
+    fn from_str(value: &str) -> Result<Self> {
+        let bytes = STANDARD.decode(value.trim())?;
+        if bytes.len() != KEY_LEN {
+            return Err(TunnelError::InvalidMessage(format!(
+                "expected a {}-byte key, got {} bytes",
+                KEY_LEN,
+                bytes.len()
+            )));
+        }
+        let cipher = Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|e| TunnelError::InvalidMessage(e.to_string()))?;
+        Ok(Self { cipher })
+    }
+}
+
+impl E2eKey {
+    /// Encrypt `plaintext` under a fresh random nonce
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("AES-GCM encryption of an in-memory buffer can't fail");
+
+        let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt data produced by [`seal`](Self::seal)
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return Err(TunnelError::DecryptionError(
+                "sealed data too short".to_string(),
+            ));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("split at the nonce length");
+        self.cipher
+            .decrypt(&Nonce::from(nonce), ciphertext)
+            .map_err(|_| TunnelError::DecryptionError("wrong key or corrupted data".to_string()))
+    }
+
+    /// Move the sensitive headers into a sealed header and mark the message sealed
+    pub fn seal_headers(&self, headers: &mut HashMap<String, Vec<String>>) {
+        let sealed: HashMap<String, Vec<String>> = SEALED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let key = headers
+                    .keys()
+                    .find(|k| k.eq_ignore_ascii_case(name))?
+                    .clone();
+                headers.remove_entry(&key)
+            })
+            .collect();
+
+        if !sealed.is_empty() {
+            let json = serde_json::to_vec(&sealed).expect("headers serialize to JSON");
+            headers.insert(
+                SEALED_HEADERS_HEADER.to_string(),
+                vec![STANDARD.encode(self.seal(&json))],
+            );
+        }
+        headers.insert(
+            "content-type".to_string(),
+            vec!["application/octet-stream".to_string()],
+        );
+        headers.insert(E2E_HEADER.to_string(), vec![E2E_CIPHER.to_string()]);
+    }
+
+    /// Restore the headers sealed by [`seal_headers`](Self::seal_headers)
+    ///
+    /// Fails if the message isn't marked sealed with [`E2E_CIPHER`].
+    pub fn open_headers(&self, headers: &mut HashMap<String, Vec<String>>) -> Result<()> {
+        let marker = take_header(headers, E2E_HEADER)
+            .ok_or_else(|| TunnelError::DecryptionError("message isn't sealed".to_string()))?;
+        if !marker.iter().any(|v| v.eq_ignore_ascii_case(E2E_CIPHER)) {
+            return Err(TunnelError::DecryptionError(format!(
+                "unsupported cipher {}",
+                marker.join(", ")
+            )));
+        }
+
+        // Clear values of sealed headers are dropped; only the sealed ones count
+        for name in SEALED_HEADERS {
+            take_header(headers, name);
+        }
+        if let Some(values) = take_header(headers, SEALED_HEADERS_HEADER) {
+            let sealed = STANDARD.decode(values.concat())?;
+            let sealed: HashMap<String, Vec<String>> =
+                serde_json::from_slice(&self.open(&sealed)?)?;
+            headers.extend(sealed);
+        }
+        Ok(())
+    }
+}
+
+/// Remove a header by case-insensitive name
+fn take_header(headers: &mut HashMap<String, Vec<String>>, name: &str) -> Option<Vec<String>> {
+    let key = headers
+        .keys()
+        .find(|k| k.eq_ignore_ascii_case(name))?
+        .clone();
+    headers.remove(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    fn headers(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), vec![v.to_string()]))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(KEY.parse::<E2eKey>().is_ok());
+        assert!("c2hvcnQ=".parse::<E2eKey>().is_err());
+        assert!("not base64!".parse::<E2eKey>().is_err());
+        assert_eq!(
+            format!("{:?}", KEY.parse::<E2eKey>().unwrap()),
+            "E2eKey(..)"
+        );
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let key: E2eKey = KEY.parse().unwrap();
+        let sealed = key.seal(b"secret body");
+        assert_eq!(sealed.len(), NONCE_LEN + b"secret body".len() + 16);
+        assert_ne!(key.seal(b"secret body"), sealed);
+        assert_eq!(key.open(&sealed).unwrap(), b"secret body");
+
+        let mut tampered = sealed.clone();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(key.open(&tampered).is_err());
+        assert!(key.open(b"short").is_err());
+
+        let other: E2eKey = STANDARD.encode([7u8; KEY_LEN]).parse().unwrap();
+        assert!(other.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_seal_and_open_headers() {
+        let key: E2eKey = KEY.parse().unwrap();
+        let original = headers(&[
+            ("Authorization", "Bearer secret"),
+            ("Content-Type", "application/json"),
+            ("Accept", "*/*"),
+        ]);
+
+        let mut sealed = original.clone();
+        key.seal_headers(&mut sealed);
+        assert!(!sealed.contains_key("Authorization"));
+        assert!(!sealed.contains_key("Content-Type"));
+        assert_eq!(sealed["content-type"], ["application/octet-stream"]);
+        assert_eq!(sealed[E2E_HEADER], [E2E_CIPHER]);
+        assert_eq!(sealed["Accept"], ["*/*"]);
+
+        key.open_headers(&mut sealed).unwrap();
+        assert_eq!(sealed, original);
+    }
+
+    #[test]
+    fn test_open_headers_requires_seal() {
+        let key: E2eKey = KEY.parse().unwrap();
+        assert!(
+            key.open_headers(&mut headers(&[("Accept", "*/*")]))
+                .is_err()
+        );
+        assert!(
+            key.open_headers(&mut headers(&[(E2E_HEADER, "rot13")]))
+                .is_err()
+        );
+
+        // Nothing sensitive to seal; clear copies of sealed headers are dropped
+        let mut sealed = headers(&[(E2E_HEADER, E2E_CIPHER), ("Cookie", "injected")]);
+        key.open_headers(&mut sealed).unwrap();
+        assert!(sealed.is_empty());
+    }
+}
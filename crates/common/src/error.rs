@@ -9,6 +9,18 @@ pub enum TunnelError {
     #[error("Connection error: {0}")]
     ConnectionError(String),
 
+    /// The server refused the tunnel; retrying won't help
+    #[error("Tunnel rejected: {0}")]
+    Rejected(String),
+
+    /// The server refused the credentials (HTTP 401/403 on the upgrade)
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    /// No usable credentials could be had; retrying won't help
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+
     #[error("Timeout waiting for response")]
     Timeout,
 
@@ -27,6 +39,13 @@ pub enum TunnelError {
     #[error("Base64 decode error: {0}")]
     Base64Error(#[from] base64::DecodeError),
 
+    /// A sealed body or header couldn't be opened with the tunnel's key
+    #[error("Decryption failed: {0}")]
+    DecryptionError(String),
+
+    #[error("Invalid multipart body: {0}")]
+    InvalidMultipart(String),
+
     #[error("HTTP error: {0}")]
     HttpError(String),
 
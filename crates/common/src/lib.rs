@@ -4,6 +4,7 @@
 //! the forwarder (client agent) and handler (Lambda functions).
 
 pub mod constants;
+pub mod e2e;
 pub mod error;
 pub mod models;
 pub mod protocol;
@@ -12,8 +13,11 @@ pub mod validation;
 
 // Re-export commonly used types for convenience
 pub use error::{Result, TunnelError};
-pub use models::{ClientInfo, ConnectionMetadata, PendingRequest};
-pub use protocol::{ErrorCode, HttpRequest, HttpResponse, Message};
+pub use models::{ClientInfo, ConnectionMetadata, PendingRequest, TunnelStats};
+pub use protocol::{
+    AlertThresholds, CorsPolicy, ErrorCode, HttpRequest, HttpResponse, Message, TunnelInfo,
+    TunnelOptions,
+};
 pub use utils::{
     calculate_ttl, current_timestamp_millis, current_timestamp_secs, decode_body, encode_body,
     generate_request_id, generate_subdomain, headers_to_map, map_to_headers,
@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::protocol::TunnelOptions;
+
 /// Connection metadata tracked in DynamoDB for active WebSocket connections
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionMetadata {
@@ -26,9 +28,21 @@ pub struct ConnectionMetadata {
     /// TTL timestamp for DynamoDB auto-deletion (Unix epoch seconds)
     pub ttl: i64,
 
+    /// When the agent last sent a heartbeat (Unix epoch seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_heartbeat_at: Option<i64>,
+
     /// Optional metadata about the client
     #[serde(default)]
     pub client_info: Option<ClientInfo>,
+
+    /// Subject (`sub` claim) of the token used to open the tunnel, when auth is enabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+
+    /// Options requested by the agent in its Ready message
+    #[serde(default, skip_serializing_if = "TunnelOptions::is_default")]
+    pub options: TunnelOptions,
 }
 
 impl ConnectionMetadata {
@@ -48,7 +62,10 @@ impl ConnectionMetadata {
             path_based_url: None,
             created_at,
             ttl,
+            last_heartbeat_at: None,
             client_info: None,
+            owner: None,
+            options: TunnelOptions::default(),
         }
     }
 
@@ -57,6 +74,12 @@ impl ConnectionMetadata {
         self.client_info = Some(client_info);
         self
     }
+
+    /// Create a connection owned by the given token subject
+    pub fn with_owner(mut self, owner: String) -> Self {
+        self.owner = Some(owner);
+        self
+    }
 }
 
 /// Information about the client agent
@@ -95,6 +118,26 @@ mod tests {
         assert_eq!(metadata.created_at, 1234567890);
         assert_eq!(metadata.ttl, 1234574090);
         assert!(metadata.client_info.is_none());
+        assert!(metadata.owner.is_none());
+        assert!(metadata.last_heartbeat_at.is_none());
+    }
+
+    #[test]
+    fn test_connection_metadata_with_owner() {
+        let metadata = ConnectionMetadata::new(
+            "conn_123".to_string(),
+            "abc123def456".to_string(),
+            "https://abc123def456.tunnel.example.com".to_string(),
+            1234567890,
+            1234574090,
+        )
+        .with_owner("user-42".to_string());
+
+        let json = serde_json::to_string(&metadata).unwrap();
+        assert!(json.contains(r#""owner":"user-42""#));
+
+        let parsed: ConnectionMetadata = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.owner.as_deref(), Some("user-42"));
     }
 
     #[test]
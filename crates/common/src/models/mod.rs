@@ -1,5 +1,10 @@
 mod connection;
 mod pending;
+mod stats;
 
 pub use connection::{ClientInfo, ConnectionMetadata};
 pub use pending::PendingRequest;
+pub use stats::{
+    LATENCY_BUCKET_BOUNDS_MS, LATENCY_BUCKET_COUNT, LatencyHistogram, TunnelAnalytics, TunnelStats,
+    status_class,
+};
@@ -0,0 +1,250 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Upper bounds (inclusive, milliseconds) of the latency histogram buckets
+///
+/// A final overflow bucket catches everything slower than the last bound.
+pub const LATENCY_BUCKET_BOUNDS_MS: [u64; 14] = [
+    10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 15_000, 20_000, 25_000, 30_000,
+];
+
+/// Number of histogram buckets, including the overflow bucket
+pub const LATENCY_BUCKET_COUNT: usize = LATENCY_BUCKET_BOUNDS_MS.len() + 1;
+
+/// Fixed-bucket latency histogram
+///
+/// Fixed buckets let counts from many time slices be summed before percentiles are
+/// computed, which is what makes rolling windows cheap to aggregate server-side.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; LATENCY_BUCKET_COUNT],
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Index of the bucket a latency falls into
+    pub fn bucket_index(latency_ms: u64) -> usize {
+        LATENCY_BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_MS.len())
+    }
+
+    /// Record a single observation
+    pub fn record(&mut self, latency_ms: u64) {
+        self.add(Self::bucket_index(latency_ms), 1);
+    }
+
+    /// Add `count` observations to a bucket (out-of-range indices are ignored)
+    pub fn add(&mut self, index: usize, count: u64) {
+        if let Some(bucket) = self.counts.get_mut(index) {
+            *bucket += count;
+        }
+    }
+
+    /// Total number of observations
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimate a percentile (0.0 - 100.0) as the upper bound of the bucket containing it
+    ///
+    /// Observations in the overflow bucket report the last bound. Returns `None` when empty.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = ((percentile / 100.0) * total as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = LATENCY_BUCKET_BOUNDS_MS
+                    .get(index)
+                    .or(LATENCY_BUCKET_BOUNDS_MS.last())
+                    .copied();
+                return bound;
+            }
+        }
+
+        LATENCY_BUCKET_BOUNDS_MS.last().copied()
+    }
+}
+
+/// Status class label ("2xx", "4xx", ...) for an HTTP status code
+pub fn status_class(status_code: u16) -> &'static str {
+    match status_code {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Aggregated traffic statistics for a tunnel over a rolling window
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TunnelStats {
+    /// Tunnel the statistics belong to
+    pub tunnel_id: String,
+
+    /// Length of the rolling window in seconds
+    pub window_secs: i64,
+
+    /// Total requests observed in the window
+    pub requests: u64,
+
+    /// Request counts keyed by status class ("2xx", "5xx", ...)
+    pub status_classes: BTreeMap<String, u64>,
+
+    /// Median end-to-end latency in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p50_ms: Option<u64>,
+
+    /// 95th percentile latency in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p95_ms: Option<u64>,
+
+    /// 99th percentile latency in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p99_ms: Option<u64>,
+}
+
+impl TunnelStats {
+    /// Build statistics from aggregated counts
+    pub fn from_counts(
+        tunnel_id: String,
+        window_secs: i64,
+        status_classes: BTreeMap<String, u64>,
+        latency: &LatencyHistogram,
+    ) -> Self {
+        Self {
+            tunnel_id,
+            window_secs,
+            requests: status_classes.values().sum(),
+            status_classes,
+            p50_ms: latency.percentile(50.0),
+            p95_ms: latency.percentile(95.0),
+            p99_ms: latency.percentile(99.0),
+        }
+    }
+
+    /// Fraction of requests answered with a 5xx status (0.0 when idle)
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.status_classes.get("5xx").copied().unwrap_or(0) as f64 / self.requests as f64
+    }
+}
+
+/// Lifetime traffic totals for a tunnel
+///
+/// Counts responses delivered by the tunnel's agents since the tunnel was first
+/// used (the totals expire after a period of inactivity).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TunnelAnalytics {
+    /// Tunnel the totals belong to
+    pub tunnel_id: String,
+
+    /// Total responses delivered
+    pub requests: u64,
+
+    /// Request body bytes received from public clients
+    pub bytes_in: u64,
+
+    /// Response body bytes returned to public clients
+    pub bytes_out: u64,
+
+    /// Response counts keyed by status code ("200", "404", ...)
+    ///
+    /// String keys, since maps inside tagged messages can't have integer keys.
+    pub status_codes: BTreeMap<String, u64>,
+
+    /// Median end-to-end latency in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p50_ms: Option<u64>,
+
+    /// 95th percentile latency in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p95_ms: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_index() {
+        assert_eq!(LatencyHistogram::bucket_index(0), 0);
+        assert_eq!(LatencyHistogram::bucket_index(10), 0);
+        assert_eq!(LatencyHistogram::bucket_index(11), 1);
+        assert_eq!(LatencyHistogram::bucket_index(2_000), 7);
+        assert_eq!(
+            LatencyHistogram::bucket_index(60_000),
+            LATENCY_BUCKET_COUNT - 1
+        );
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+
+        for _ in 0..90 {
+            histogram.record(40);
+        }
+        for _ in 0..9 {
+            histogram.record(2_000);
+        }
+        histogram.record(60_000);
+
+        assert_eq!(histogram.total(), 100);
+        assert_eq!(histogram.percentile(50.0), Some(50));
+        assert_eq!(histogram.percentile(95.0), Some(2_500));
+        assert_eq!(histogram.percentile(99.0), Some(2_500));
+        assert_eq!(histogram.percentile(100.0), Some(30_000));
+    }
+
+    #[test]
+    fn test_status_class() {
+        assert_eq!(status_class(101), "1xx");
+        assert_eq!(status_class(204), "2xx");
+        assert_eq!(status_class(302), "3xx");
+        assert_eq!(status_class(404), "4xx");
+        assert_eq!(status_class(504), "5xx");
+    }
+
+    #[test]
+    fn test_tunnel_stats_from_counts() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(100);
+        histogram.record(100);
+
+        let mut classes = BTreeMap::new();
+        classes.insert("2xx".to_string(), 3);
+        classes.insert("5xx".to_string(), 1);
+
+        let stats = TunnelStats::from_counts("abc123def456".to_string(), 300, classes, &histogram);
+        assert_eq!(stats.requests, 4);
+        assert_eq!(stats.p50_ms, Some(100));
+        assert!((stats.error_rate() - 0.25).abs() < f64::EPSILON);
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert!(json.contains(r#""status_classes":{"2xx":3,"5xx":1}"#));
+    }
+}
@@ -0,0 +1,281 @@
+//! Chunked transfer of large bodies
+//!
+//! API Gateway rejects WebSocket frames over [`WEBSOCKET_FRAME_LIMIT_BYTES`], so
+//! a request or response whose Base64 body is longer than [`BODY_CHUNK_SIZE_BYTES`]
+//! is sent as a head (the message with `chunked` set and an empty body), followed
+//! by `BodyChunk` messages carrying consecutive slices of the body and a closing
+//! `BodyEnd`. The receiver concatenates the slices in index order.
+//!
+//! [`WEBSOCKET_FRAME_LIMIT_BYTES`]: crate::constants::WEBSOCKET_FRAME_LIMIT_BYTES
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::Message;
+use crate::constants::{BODY_CHUNK_SIZE_BYTES, REQUEST_TIMEOUT_SECS};
+
+/// Partially received bodies older than this are dropped (the request has timed out)
+const MAX_ASSEMBLY_AGE: Duration = Duration::from_secs(REQUEST_TIMEOUT_SECS);
+
+/// Split a message whose body doesn't fit in one frame into head, chunks and end marker
+///
+/// Messages without a large body are returned unchanged.
+pub fn split_message(message: Message) -> Vec<Message> {
+    match message {
+        Message::HttpRequest(mut request) if request.body.len() > BODY_CHUNK_SIZE_BYTES => {
+            let body = std::mem::take(&mut request.body);
+            request.chunked = true;
+            let request_id = request.request_id.clone();
+            with_chunks(Message::HttpRequest(request), request_id, &body)
+        }
+        Message::HttpResponse(mut response) if response.body.len() > BODY_CHUNK_SIZE_BYTES => {
+            let body = std::mem::take(&mut response.body);
+            response.chunked = true;
+            let request_id = response.request_id.clone();
+            with_chunks(Message::HttpResponse(response), request_id, &body)
+        }
+        message => vec![message],
+    }
+}
+
+fn with_chunks(head: Message, request_id: String, body: &str) -> Vec<Message> {
+    let mut messages = vec![head];
+    let mut index = 0;
+    let mut start = 0;
+    while start < body.len() {
+        // Base64 is ASCII, but never split a character should anything else slip in
+        let mut end = (start + BODY_CHUNK_SIZE_BYTES).min(body.len());
+        while !body.is_char_boundary(end) {
+            end -= 1;
+        }
+        messages.push(Message::BodyChunk {
+            request_id: request_id.clone(),
+            index,
+            data: body[start..end].to_string(),
+        });
+        index += 1;
+        start = end;
+    }
+    messages.push(Message::BodyEnd {
+        request_id,
+        chunks: index,
+    });
+    messages
+}
+
+/// Reassembles chunked messages received over a WebSocket
+///
+/// Pieces may arrive in any order; the head is released once its end marker and
+/// every chunk it announces have been received.
+#[derive(Debug, Default)]
+pub struct ChunkAssembler {
+    pending: HashMap<String, PendingBody>,
+}
+
+#[derive(Debug)]
+struct PendingBody {
+    head: Option<Message>,
+    chunks: HashMap<u32, String>,
+    total: Option<u32>,
+    started: Instant,
+}
+
+impl PendingBody {
+    fn new() -> Self {
+        Self {
+            head: None,
+            chunks: HashMap::new(),
+            total: None,
+            started: Instant::now(),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.head.is_some()
+            && self
+                .total
+                .is_some_and(|total| (0..total).all(|index| self.chunks.contains_key(&index)))
+    }
+
+    fn into_message(mut self) -> Option<Message> {
+        let body: String = (0..self.total?)
+            .map(|index| self.chunks.remove(&index))
+            .collect::<Option<_>>()?;
+        match self.head? {
+            Message::HttpRequest(mut request) => {
+                request.body = body;
+                request.chunked = false;
+                Some(Message::HttpRequest(request))
+            }
+            Message::HttpResponse(mut response) => {
+                response.body = body;
+                response.chunked = false;
+                Some(Message::HttpResponse(response))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl ChunkAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a received message, returning the message to handle if there is one
+    ///
+    /// Unchunked messages are returned as is. Heads, chunks and end markers are
+    /// buffered, and the head is returned with its body filled in once complete.
+    pub fn push(&mut self, message: Message) -> Option<Message> {
+        let request_id = match &message {
+            Message::HttpRequest(request) if request.chunked => request.request_id.clone(),
+            Message::HttpResponse(response) if response.chunked => response.request_id.clone(),
+            Message::BodyChunk { request_id, .. } | Message::BodyEnd { request_id, .. } => {
+                request_id.clone()
+            }
+            _ => return Some(message),
+        };
+
+        self.pending
+            .retain(|_, pending| pending.started.elapsed() < MAX_ASSEMBLY_AGE);
+
+        let pending = self
+            .pending
+            .entry(request_id.clone())
+            .or_insert_with(PendingBody::new);
+        match message {
+            Message::BodyChunk { index, data, .. } => {
+                pending.chunks.insert(index, data);
+            }
+            Message::BodyEnd { chunks, .. } => pending.total = Some(chunks),
+            head => pending.head = Some(head),
+        }
+
+        if !pending.is_complete() {
+            return None;
+        }
+        self.pending.remove(&request_id)?.into_message()
+    }
+
+    /// Number of bodies still being received
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HttpRequest, HttpResponse};
+
+    fn large_response(len: usize) -> HttpResponse {
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = "QUJD".repeat(len / 4);
+        response
+    }
+
+    #[test]
+    fn test_small_messages_are_not_split() {
+        let messages = split_message(Message::HttpResponse(large_response(1024)));
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0], Message::HttpResponse(r) if !r.chunked));
+
+        assert!(matches!(split_message(Message::Ping)[..], [Message::Ping]));
+    }
+
+    #[test]
+    fn test_split_fits_frame_limit() {
+        let response = large_response(100 * 1024);
+        let messages = split_message(Message::HttpResponse(response));
+
+        // Head, five chunks, end marker
+        assert_eq!(messages.len(), 7);
+        assert!(matches!(&messages[0], Message::HttpResponse(r) if r.chunked && r.body.is_empty()));
+        assert!(matches!(
+            messages.last(),
+            Some(Message::BodyEnd { chunks: 5, .. })
+        ));
+        for message in &messages {
+            let json = serde_json::to_string(message).unwrap();
+            assert!(json.len() <= crate::constants::WEBSOCKET_FRAME_LIMIT_BYTES);
+        }
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order() {
+        let response = large_response(100 * 1024);
+        let body = response.body.clone();
+        let mut messages = split_message(Message::HttpResponse(response));
+        messages.reverse();
+
+        let mut assembler = ChunkAssembler::new();
+        let mut assembled = None;
+        for message in messages {
+            // Round-trip through JSON like the real transport
+            let json = serde_json::to_string(&message).unwrap();
+            if let Some(message) = assembler.push(serde_json::from_str(&json).unwrap()) {
+                assert!(assembled.is_none());
+                assembled = Some(message);
+            }
+        }
+
+        match assembled {
+            Some(Message::HttpResponse(response)) => {
+                assert_eq!(response.body, body);
+                assert!(!response.chunked);
+            }
+            other => panic!("Expected assembled response, got {:?}", other),
+        }
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_requests_are_tracked_separately() {
+        let mut first = HttpRequest::new(
+            "POST".to_string(),
+            "/upload".to_string(),
+            "req_a".to_string(),
+            0,
+        );
+        first.body = "a".repeat(BODY_CHUNK_SIZE_BYTES + 1);
+        let mut second = first.clone();
+        second.request_id = "req_b".to_string();
+        second.body = "b".repeat(BODY_CHUNK_SIZE_BYTES * 2);
+
+        let mut assembler = ChunkAssembler::new();
+        let first = split_message(Message::HttpRequest(first));
+        let second = split_message(Message::HttpRequest(second));
+        let mut completed = Vec::new();
+        for message in first.into_iter().zip(second).flat_map(|(a, b)| [a, b]) {
+            completed.extend(assembler.push(message));
+        }
+
+        assert_eq!(completed.len(), 2);
+        for message in completed {
+            let Message::HttpRequest(request) = message else {
+                panic!("Expected request");
+            };
+            let expected = if request.request_id == "req_a" {
+                BODY_CHUNK_SIZE_BYTES + 1
+            } else {
+                BODY_CHUNK_SIZE_BYTES * 2
+            };
+            assert_eq!(request.body.len(), expected);
+        }
+    }
+
+    #[test]
+    fn test_unchunked_messages_pass_through() {
+        let mut assembler = ChunkAssembler::new();
+        assert!(matches!(assembler.push(Message::Pong), Some(Message::Pong)));
+        assert!(
+            assembler
+                .push(Message::BodyEnd {
+                    request_id: "req_1".to_string(),
+                    chunks: 1,
+                })
+                .is_none()
+        );
+        assert_eq!(assembler.pending(), 1);
+    }
+}
@@ -0,0 +1,275 @@
+//! Compression of bodies inside protocol messages
+//!
+//! An agent that asks for a [`BodyCompression`] in its Ready message gets
+//! request bodies compressed with it, and compresses its responses the same way
+//! when the handler advertises [`Capability::Compression`]. A body is compressed
+//! before it's Base64-encoded, only when it's large enough and actually shrinks,
+//! and the message's `content_encoding` says how to undo it. This is separate
+//! from the HTTP `Content-Encoding` the body may already have.
+//!
+//! [`Capability::Compression`]: super::Capability::Compression
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+use crate::constants::{COMPRESSION_MIN_BODY_BYTES, MAX_DECOMPRESSED_BODY_BYTES};
+use crate::error::{Result, TunnelError};
+use crate::utils::{decode_body, encode_body};
+
+/// zstd level: fast, and still well ahead of deflate on text
+const ZSTD_LEVEL: i32 = 3;
+
+/// How a message body is compressed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BodyCompression {
+    /// Not compressed
+    #[default]
+    None,
+    Zstd,
+    /// zlib-wrapped deflate
+    Deflate,
+}
+
+impl BodyCompression {
+    /// Whether this is the default (no compression)
+    pub fn is_none(&self) -> bool {
+        *self == Self::None
+    }
+
+    /// Compress raw bytes
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Zstd => zstd::encode_all(data, ZSTD_LEVEL).map_err(compression_error),
+            Self::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data).map_err(compression_error)?;
+                encoder.finish().map_err(compression_error)
+            }
+        }
+    }
+
+    /// Decompress raw bytes, refusing output over [`MAX_DECOMPRESSED_BODY_BYTES`]
+    pub fn decompress(self, data: &[u8]) -> Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self {
+            Self::None => return Ok(data.to_vec()),
+            Self::Zstd => Box::new(zstd::Decoder::new(data).map_err(compression_error)?),
+            Self::Deflate => Box::new(flate2::read::ZlibDecoder::new(data)),
+        };
+
+        let mut decompressed = Vec::new();
+        reader
+            .take(MAX_DECOMPRESSED_BODY_BYTES as u64 + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(compression_error)?;
+        if decompressed.len() > MAX_DECOMPRESSED_BODY_BYTES {
+            return Err(TunnelError::InvalidMessage(format!(
+                "Body decompresses to more than {} bytes",
+                MAX_DECOMPRESSED_BODY_BYTES
+            )));
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Compresses a body fed in pieces, for bodies too large to hold in memory
+///
+/// The pieces returned by [`Self::write`] and [`Self::finish`] concatenate to
+/// what [`BodyCompression::compress`] produces for the whole body.
+pub struct Compressor(Encoder);
+
+enum Encoder {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(flate2::write::ZlibEncoder<Vec<u8>>),
+}
+
+impl BodyCompression {
+    /// A [`Compressor`] for this compression, or `None` for no compression
+    pub fn compressor(self) -> Result<Option<Compressor>> {
+        let encoder = match self {
+            Self::None => return Ok(None),
+            Self::Zstd => Encoder::Zstd(
+                zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)
+                    .map_err(compression_error)?,
+            ),
+            Self::Deflate => Encoder::Deflate(flate2::write::ZlibEncoder::new(
+                Vec::new(),
+                flate2::Compression::fast(),
+            )),
+        };
+        Ok(Some(Compressor(encoder)))
+    }
+}
+
+impl Compressor {
+    /// Compress the next piece of the body, returning the output produced so far
+    pub fn write(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        match &mut self.0 {
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(data).map_err(compression_error)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            Encoder::Deflate(encoder) => {
+                encoder.write_all(data).map_err(compression_error)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    /// End the body, returning the rest of the output
+    pub fn finish(self) -> Result<Vec<u8>> {
+        match self.0 {
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+        .map_err(compression_error)
+    }
+}
+
+impl std::str::FromStr for BodyCompression {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Self::None),
+            "zstd" => Ok(Self::Zstd),
+            "deflate" => Ok(Self::Deflate),
+            _ => Err(format!(
+                "invalid compression `{}` (expected `zstd`, `deflate` or `none`)",
+                value
+            )),
+        }
+    }
+}
+
+fn compression_error(e: std::io::Error) -> TunnelError {
+    TunnelError::InvalidMessage(format!("Body compression failed: {}", e))
+}
+
+/// Compress a Base64 body in place, if that's worth it
+///
+/// Leaves small bodies, bodies that don't shrink and already compressed ones alone.
+pub(super) fn compress_body(
+    body: &mut String,
+    content_encoding: &mut BodyCompression,
+    compression: BodyCompression,
+) {
+    // Base64 inflates by a third, so this is a lower bound of the raw size
+    if compression.is_none()
+        || !content_encoding.is_none()
+        || body.len() < COMPRESSION_MIN_BODY_BYTES
+    {
+        return;
+    }
+    let Ok(raw) = decode_body(body) else {
+        return;
+    };
+    let Ok(compressed) = compression.compress(&raw) else {
+        return;
+    };
+    if compressed.len() < raw.len() {
+        *body = encode_body(&compressed);
+        *content_encoding = compression;
+    }
+}
+
+/// Undo [`compress_body`]
+pub(super) fn decompress_body(
+    body: &mut String,
+    content_encoding: &mut BodyCompression,
+) -> Result<()> {
+    if content_encoding.is_none() {
+        return Ok(());
+    }
+    let raw = content_encoding.decompress(&decode_body(body)?)?;
+    *body = encode_body(&raw);
+    *content_encoding = BodyCompression::None;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HttpRequest, HttpResponse};
+
+    #[test]
+    fn test_round_trip() {
+        let data = br#"{"items":[{"id":1,"name":"item"}]}"#.repeat(100);
+        for compression in [
+            BodyCompression::None,
+            BodyCompression::Zstd,
+            BodyCompression::Deflate,
+        ] {
+            let compressed = compression.compress(&data).unwrap();
+            assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        }
+        assert!(BodyCompression::Zstd.decompress(b"not zstd").is_err());
+    }
+
+    #[test]
+    fn test_compressor_output_decompresses_to_body() {
+        let data = br#"{"items":[{"id":1,"name":"item"}]}"#.repeat(1000);
+        for compression in [BodyCompression::Zstd, BodyCompression::Deflate] {
+            let mut compressor = compression.compressor().unwrap().unwrap();
+            let mut compressed = Vec::new();
+            for piece in data.chunks(777) {
+                compressed.extend(compressor.write(piece).unwrap());
+            }
+            compressed.extend(compressor.finish().unwrap());
+            assert!(compressed.len() < data.len());
+            assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        }
+        assert!(BodyCompression::None.compressor().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_compress_message_bodies() {
+        let html = b"<li><a href=\"/item\">Item</a></li>".repeat(100);
+        let mut response = HttpResponse::new("req_1".to_string(), 200);
+        response.body = encode_body(&html);
+
+        response.compress_body(BodyCompression::Zstd);
+        assert_eq!(response.content_encoding, BodyCompression::Zstd);
+        assert!(response.body.len() < encode_body(&html).len());
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.contains(r#""content_encoding":"zstd""#));
+
+        // Compressing again is a no-op
+        let compressed = response.body.clone();
+        response.compress_body(BodyCompression::Deflate);
+        assert_eq!(response.body, compressed);
+
+        response.decompress_body().unwrap();
+        assert_eq!(response.content_encoding, BodyCompression::None);
+        assert_eq!(decode_body(&response.body).unwrap(), html);
+    }
+
+    #[test]
+    fn test_small_and_incompressible_bodies_left_alone() {
+        let mut request =
+            HttpRequest::new("POST".to_string(), "/".to_string(), "req_1".to_string(), 0);
+        request.body = encode_body(b"small");
+        request.compress_body(BodyCompression::Zstd);
+        assert!(request.content_encoding.is_none());
+
+        // Random bytes don't shrink
+        let noise: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        request.body = encode_body(&noise);
+        request.compress_body(BodyCompression::Deflate);
+        assert!(request.content_encoding.is_none());
+        assert_eq!(decode_body(&request.body).unwrap(), noise);
+
+        let json = serde_json::to_string(&request).unwrap();
+        assert!(!json.contains("content_encoding"));
+    }
+
+    #[test]
+    fn test_parse_body_compression() {
+        assert_eq!("zstd".parse(), Ok(BodyCompression::Zstd));
+        assert_eq!("deflate".parse(), Ok(BodyCompression::Deflate));
+        assert_eq!("none".parse(), Ok(BodyCompression::None));
+        assert!("gzip".parse::<BodyCompression>().is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Wire encodings for protocol messages
+//!
+//! Messages travel as JSON text frames by default, with bodies Base64-encoded.
+//! An agent that asks for [`Encoding::MessagePack`] in its Ready message (and
+//! talks to a handler advertising [`Capability::MessagePack`]) receives binary
+//! MessagePack frames instead, in which bodies are raw bytes. API Gateway
+//! rejects binary frames sent by clients, so agents always send JSON.
+//!
+//! Bodies stay Base64 strings in memory; only the MessagePack form carries
+//! them as bytes (see [`body`]).
+//!
+//! [`Capability::MessagePack`]: super::Capability::MessagePack
+
+use serde::{Deserialize, Serialize};
+
+use super::Message;
+use crate::error::{Result, TunnelError};
+
+/// Encoding of the messages sent to an agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// JSON text frames
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    /// MessagePack binary frames
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+/// An encoded message, ready to be sent as a WebSocket frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// Payload size in bytes
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Text(text) => text.len(),
+            Self::Binary(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Text(text) => text.into_bytes(),
+            Self::Binary(data) => data,
+        }
+    }
+}
+
+impl Encoding {
+    /// Whether this is the default encoding
+    pub fn is_json(&self) -> bool {
+        *self == Self::Json
+    }
+
+    /// Encode a message as a frame of this encoding
+    pub fn encode(self, message: &Message) -> Result<Frame> {
+        match self {
+            Self::Json => Ok(Frame::Text(serde_json::to_string(message)?)),
+            // Structs as maps, so optional fields can be left out
+            Self::MessagePack => rmp_serde::to_vec_named(message)
+                .map(Frame::Binary)
+                .map_err(|e| TunnelError::InvalidMessage(e.to_string())),
+        }
+    }
+}
+
+/// Decode a message received in a text frame
+pub fn decode_text(text: &str) -> Result<Message> {
+    Ok(serde_json::from_str(text)?)
+}
+
+/// Decode a message received in a binary frame
+pub fn decode_binary(data: &[u8]) -> Result<Message> {
+    rmp_serde::from_slice(data).map_err(|e| TunnelError::InvalidMessage(e.to_string()))
+}
+
+/// Serde helpers for Base64 body fields
+///
+/// Human-readable formats keep the Base64 string. Binary formats carry the
+/// decoded bytes, which are Base64-encoded again when read back; a string that
+/// isn't valid Base64 is kept as a string.
+pub(crate) mod body {
+    use std::fmt;
+
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return serializer.serialize_str(body);
+        }
+        match STANDARD.decode(body) {
+            Ok(bytes) => serializer.serialize_bytes(&bytes),
+            Err(_) => serializer.serialize_str(body),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        // Tagged enums buffer their content and lose the format's
+        // human-readable flag, so accept either form regardless
+        deserializer.deserialize_any(BodyVisitor)
+    }
+
+    struct BodyVisitor;
+
+    impl<'de> Visitor<'de> for BodyVisitor {
+        type Value = String;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a Base64 string or bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<String, E> {
+            Ok(value.to_string())
+        }
+
+        fn visit_string<E: de::Error>(self, value: String) -> Result<String, E> {
+            Ok(value)
+        }
+
+        fn visit_bytes<E: de::Error>(self, value: &[u8]) -> Result<String, E> {
+            Ok(STANDARD.encode(value))
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<String, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element::<u8>()? {
+                bytes.push(byte);
+            }
+            Ok(STANDARD.encode(bytes))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{HttpRequest, HttpResponse, split_message};
+    use crate::utils::encode_body;
+
+    fn request(body: &[u8]) -> Message {
+        let mut request = HttpRequest::new(
+            "POST".to_string(),
+            "/upload".to_string(),
+            "req_1".to_string(),
+            0,
+        );
+        request.body = encode_body(body);
+        Message::HttpRequest(request)
+    }
+
+    fn same(a: &Message, b: &Message) -> bool {
+        serde_json::to_value(a).unwrap() == serde_json::to_value(b).unwrap()
+    }
+
+    #[test]
+    fn test_json_unchanged() {
+        let message = request(b"hello");
+        let Frame::Text(text) = Encoding::Json.encode(&message).unwrap() else {
+            panic!("expected a text frame");
+        };
+        assert_eq!(text, serde_json::to_string(&message).unwrap());
+        assert!(same(&decode_text(&text).unwrap(), &message));
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let body: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let messages = [
+            request(&body),
+            request(b""),
+            Message::HttpResponse(HttpResponse {
+                body: "not base64!".to_string(),
+                ..HttpResponse::new("req_1".to_string(), 200)
+            }),
+            Message::Ping,
+        ];
+        for message in messages {
+            let Frame::Binary(data) = Encoding::MessagePack.encode(&message).unwrap() else {
+                panic!("expected a binary frame");
+            };
+            assert!(same(&decode_binary(&data).unwrap(), &message));
+        }
+    }
+
+    #[test]
+    fn test_msgpack_carries_raw_bodies() {
+        let body: Vec<u8> = (0..=255).cycle().take(16 * 1024).collect();
+        let message = request(&body);
+        let json = Encoding::Json.encode(&message).unwrap();
+        let msgpack = Encoding::MessagePack.encode(&message).unwrap();
+        assert!(msgpack.len() < body.len() + 256);
+        assert!(json.len() > body.len() * 4 / 3);
+    }
+
+    #[test]
+    fn test_msgpack_chunks() {
+        let body: Vec<u8> = (0..=255).cycle().take(64 * 1024).collect();
+        for chunk in split_message(request(&body)) {
+            let frame = Encoding::MessagePack.encode(&chunk).unwrap();
+            assert!(same(&decode_binary(&frame.into_bytes()).unwrap(), &chunk));
+        }
+    }
+
+    #[test]
+    fn test_encoding_serialization() {
+        assert_eq!(
+            serde_json::to_string(&Encoding::MessagePack).unwrap(),
+            r#""msgpack""#
+        );
+        assert_eq!(
+            serde_json::from_str::<Encoding>(r#""json""#).unwrap(),
+            Encoding::Json
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-use super::{HttpRequest, HttpResponse};
+use super::{HttpRequest, HttpResponse, TunnelInfo, TunnelOptions};
+use crate::models::TunnelAnalytics;
 
 /// All WebSocket messages are wrapped in this typed envelope
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -9,7 +11,11 @@ pub enum Message {
     /// Control plane messages
     Ping,
     Pong,
-    Ready, // Sent by forwarder after connection to request connection info
+    /// Sent by forwarder after connection to request connection info
+    Ready {
+        #[serde(default, skip_serializing_if = "TunnelOptions::is_default")]
+        options: TunnelOptions,
+    },
 
     /// Connection lifecycle
     ConnectionEstablished {
@@ -20,11 +26,92 @@ pub enum Message {
         subdomain_url: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         path_based_url: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        info: Option<TunnelInfo>,
+    },
+    /// Sent by the handler shortly before the gateway closes the connection;
+    /// the agent should open a new connection that takes over the tunnel
+    ReconnectRequested {
+        /// Presented in the new connection's Ready message (see [`super::Handoff`])
+        handoff_token: String,
+        /// When the current connection will be closed (Unix epoch seconds)
+        expires_at: i64,
+    },
+    /// Sent by the handler when the tunnel has had no traffic for a while; it
+    /// is closed at `closes_at` unless a request comes in first
+    IdleWarning {
+        /// How long the tunnel has been idle, in seconds
+        idle_secs: i64,
+        /// When the tunnel will be closed (Unix epoch seconds)
+        closes_at: i64,
+    },
+    /// Sent by the handler shortly before the gateway closes a connection that
+    /// hasn't been handed off (see `ReconnectRequested`)
+    ExpiryWarning {
+        /// When the connection will be closed (Unix epoch seconds)
+        expires_at: i64,
     },
 
+    /// Sent by the handler now and then to agents that asked for `stats_updates`
+    TunnelStats(TunnelAnalytics),
+
     /// Data plane messages
     HttpRequest(HttpRequest),
     HttpResponse(HttpResponse),
+    /// A slice of a chunked request or response body (see [`super::split_message`])
+    BodyChunk {
+        request_id: String,
+        /// Position of this slice, starting at 0
+        index: u32,
+        /// Slice of the Base64-encoded body
+        #[serde(with = "super::encoding::body")]
+        data: String,
+    },
+    /// Marks the end of a chunked body
+    BodyEnd {
+        request_id: String,
+        /// Number of chunks sent
+        chunks: u32,
+    },
+    /// A piece of a streamed response body, sent as soon as the local service
+    /// produces it (see [`HttpResponse::streaming`])
+    ResponseChunk {
+        request_id: String,
+        /// Position of this piece, starting at 0
+        index: u32,
+        /// The piece, Base64-encoded
+        #[serde(with = "super::encoding::body")]
+        data: String,
+        /// The body ends with this piece
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        last: bool,
+    },
+
+    /// WebSocket passthrough: a public client connected to the tunnel
+    WsOpen {
+        /// Identifies the client connection in later frames
+        session_id: String,
+        /// Path and query to open on the local service
+        path: String,
+        #[serde(default)]
+        headers: HashMap<String, Vec<String>>,
+    },
+    /// A WebSocket message, in either direction
+    WsFrame {
+        session_id: String,
+        /// Message text, or Base64-encoded bytes for binary messages
+        data: String,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        binary: bool,
+    },
+    /// Either side closed the WebSocket
+    WsClose {
+        session_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        code: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
 
     /// Error handling
     Error {
@@ -42,12 +129,44 @@ pub enum ErrorCode {
     Timeout,
     LocalServiceUnavailable,
     InternalError,
+    /// The agent's WebSocket connection is gone or could not be reached
+    TunnelUnavailable,
+    /// Delivery to the agent was throttled by API Gateway
+    RateLimited,
+    /// The local service's response exceeded the tunnel's size limit
+    ResponseTooLarge,
+    /// The request body exceeded the tunnel's size limit
+    PayloadTooLarge,
+    /// The requested tunnel ID is reserved by someone else or not reserved at all
+    TunnelIdUnavailable,
+    /// An operator revoked the tunnel; the agent should not reconnect
+    TunnelRevoked,
+    /// The tunnel was closed after going without traffic; the agent should not reconnect
+    TunnelIdle,
+}
+
+impl ErrorCode {
+    /// HTTP status code returned to the public client for this error
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 400,
+            ErrorCode::Timeout => 504,
+            ErrorCode::LocalServiceUnavailable => 503,
+            ErrorCode::InternalError => 502,
+            ErrorCode::TunnelUnavailable => 502,
+            ErrorCode::RateLimited => 503,
+            ErrorCode::ResponseTooLarge => 502,
+            ErrorCode::PayloadTooLarge => 413,
+            ErrorCode::TunnelIdUnavailable => 409,
+            ErrorCode::TunnelRevoked => 410,
+            ErrorCode::TunnelIdle => 410,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
 
     #[test]
     fn test_ping_pong_serialization() {
@@ -63,6 +182,39 @@ mod tests {
         assert!(matches!(parsed, Message::Pong));
     }
 
+    #[test]
+    fn test_ready_serialization() {
+        // Agents without options keep sending the bare message
+        let ready = Message::Ready {
+            options: TunnelOptions::default(),
+        };
+        let json = serde_json::to_string(&ready).unwrap();
+        assert_eq!(json, r#"{"type":"ready"}"#);
+
+        let parsed: Message = serde_json::from_str(r#"{"type":"ready"}"#).unwrap();
+        assert!(matches!(parsed, Message::Ready { options } if options.is_default()));
+
+        let ready = Message::Ready {
+            options: TunnelOptions {
+                alerts: Some(crate::protocol::AlertThresholds {
+                    p95_ms: Some(5000),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        };
+        let json = serde_json::to_string(&ready).unwrap();
+        assert!(json.contains(r#""options":{"alerts":{"p95_ms":5000"#));
+
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        match parsed {
+            Message::Ready { options } => {
+                assert_eq!(options.alerts.unwrap().p95_ms, Some(5000));
+            }
+            _ => panic!("Expected Ready message"),
+        }
+    }
+
     #[test]
     fn test_connection_established_serialization() {
         let msg = Message::ConnectionEstablished {
@@ -71,6 +223,12 @@ mod tests {
             public_url: "https://abc123def456.tunnel.example.com".to_string(),
             subdomain_url: Some("https://abc123def456.tunnel.example.com".to_string()),
             path_based_url: Some("https://tunnel.example.com/abc123def456".to_string()),
+            info: Some(TunnelInfo {
+                expires_at: Some(1_700_007_200),
+                region: Some("us-east-1".to_string()),
+                max_body_size: Some(2 * 1024 * 1024),
+                capabilities: vec![crate::protocol::Capability::Stats],
+            }),
         };
 
         let json = serde_json::to_string(&msg).unwrap();
@@ -79,8 +237,15 @@ mod tests {
 
         let parsed: Message = serde_json::from_str(&json).unwrap();
         match parsed {
-            Message::ConnectionEstablished { connection_id, .. } => {
+            Message::ConnectionEstablished {
+                connection_id,
+                info,
+                ..
+            } => {
                 assert_eq!(connection_id, "conn_123");
+                let info = info.unwrap();
+                assert_eq!(info.expires_at, Some(1_700_007_200));
+                assert_eq!(info.region.as_deref(), Some("us-east-1"));
             }
             _ => panic!("Expected ConnectionEstablished"),
         }
@@ -97,16 +262,80 @@ mod tests {
                 connection_id,
                 subdomain_url,
                 path_based_url,
+                info,
                 ..
             } => {
                 assert_eq!(connection_id, "conn_123");
                 assert!(subdomain_url.is_none());
                 assert!(path_based_url.is_none());
+                assert!(info.is_none());
             }
             _ => panic!("Expected ConnectionEstablished"),
         }
     }
 
+    #[test]
+    fn test_handoff_serialization() {
+        let msg = Message::ReconnectRequested {
+            handoff_token: "token123".to_string(),
+            expires_at: 1_700_007_200,
+        };
+        let json = serde_json::to_string(&msg).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"reconnect_requested","handoff_token":"token123","expires_at":1700007200}"#
+        );
+
+        let msg = Message::IdleWarning {
+            idle_secs: 3300,
+            closes_at: 1_700_003_600,
+        };
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            r#"{"type":"idle_warning","idle_secs":3300,"closes_at":1700003600}"#
+        );
+        let msg = Message::ExpiryWarning {
+            expires_at: 1_700_007_200,
+        };
+        assert_eq!(
+            serde_json::to_string(&msg).unwrap(),
+            r#"{"type":"expiry_warning","expires_at":1700007200}"#
+        );
+
+        let ready = Message::Ready {
+            options: TunnelOptions {
+                handoff: Some(crate::protocol::Handoff {
+                    connection_id: "conn_123".to_string(),
+                    token: "token123".to_string(),
+                }),
+                ..Default::default()
+            },
+        };
+        let json = serde_json::to_string(&ready).unwrap();
+        assert!(json.contains(r#""handoff":{"connection_id":"conn_123","token":"token123"}"#));
+    }
+
+    #[test]
+    fn test_tunnel_stats_serialization() {
+        let msg = Message::TunnelStats(TunnelAnalytics {
+            tunnel_id: "abc123def456".to_string(),
+            requests: 3,
+            bytes_in: 10,
+            bytes_out: 2048,
+            status_codes: [("200".to_string(), 2), ("404".to_string(), 1)].into(),
+            p50_ms: Some(50),
+            p95_ms: None,
+        });
+        let json = serde_json::to_string(&msg).unwrap();
+        assert!(json.starts_with(r#"{"type":"tunnel_stats","tunnel_id":"abc123def456""#));
+        assert!(json.contains(r#""status_codes":{"200":2,"404":1}"#));
+        assert!(!json.contains("p95_ms"));
+
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        assert!(matches!(parsed, Message::TunnelStats(analytics)
+            if analytics.status_codes["404"] == 1 && analytics.p50_ms == Some(50)));
+    }
+
     #[test]
     fn test_http_request_serialization() {
         let request = HttpRequest {
@@ -116,6 +345,11 @@ mod tests {
             headers: HashMap::new(),
             body: String::new(),
             timestamp: 1234567890,
+            timeout_ms: None,
+            chunked: false,
+            trailers: Default::default(),
+            correlation_id: None,
+            content_encoding: Default::default(),
         };
 
         let msg = Message::HttpRequest(request);
@@ -159,6 +393,10 @@ mod tests {
                 "local_service_unavailable",
             ),
             (ErrorCode::InternalError, "internal_error"),
+            (ErrorCode::TunnelUnavailable, "tunnel_unavailable"),
+            (ErrorCode::RateLimited, "rate_limited"),
+            (ErrorCode::ResponseTooLarge, "response_too_large"),
+            (ErrorCode::PayloadTooLarge, "payload_too_large"),
         ];
 
         for (code, expected_json) in codes {
@@ -175,7 +413,59 @@ mod tests {
                         ErrorCode::LocalServiceUnavailable
                     )
                     | (ErrorCode::InternalError, ErrorCode::InternalError)
+                    | (ErrorCode::TunnelUnavailable, ErrorCode::TunnelUnavailable)
+                    | (ErrorCode::RateLimited, ErrorCode::RateLimited)
+                    | (ErrorCode::ResponseTooLarge, ErrorCode::ResponseTooLarge)
+                    | (ErrorCode::PayloadTooLarge, ErrorCode::PayloadTooLarge)
             ));
         }
     }
+
+    #[test]
+    fn test_error_code_http_status() {
+        assert_eq!(ErrorCode::InvalidRequest.http_status(), 400);
+        assert_eq!(ErrorCode::Timeout.http_status(), 504);
+        assert_eq!(ErrorCode::LocalServiceUnavailable.http_status(), 503);
+        assert_eq!(ErrorCode::InternalError.http_status(), 502);
+        assert_eq!(ErrorCode::TunnelUnavailable.http_status(), 502);
+        assert_eq!(ErrorCode::RateLimited.http_status(), 503);
+        assert_eq!(ErrorCode::ResponseTooLarge.http_status(), 502);
+        assert_eq!(ErrorCode::PayloadTooLarge.http_status(), 413);
+        assert_eq!(ErrorCode::TunnelRevoked.http_status(), 410);
+        assert_eq!(ErrorCode::TunnelIdle.http_status(), 410);
+    }
+
+    #[test]
+    fn test_websocket_messages_serialization() {
+        let open = Message::WsOpen {
+            session_id: "sess_1".to_string(),
+            path: "/ws?room=1".to_string(),
+            headers: HashMap::new(),
+        };
+        let json = serde_json::to_string(&open).unwrap();
+        assert!(json.contains(r#""type":"ws_open"#));
+
+        let frame = Message::WsFrame {
+            session_id: "sess_1".to_string(),
+            data: "hello".to_string(),
+            binary: false,
+        };
+        let json = serde_json::to_string(&frame).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"ws_frame","session_id":"sess_1","data":"hello"}"#
+        );
+
+        let parsed: Message =
+            serde_json::from_str(r#"{"type":"ws_close","session_id":"sess_1","code":1000}"#)
+                .unwrap();
+        assert!(matches!(
+            parsed,
+            Message::WsClose {
+                code: Some(1000),
+                reason: None,
+                ..
+            }
+        ));
+    }
 }
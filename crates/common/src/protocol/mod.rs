@@ -1,7 +1,19 @@
+mod chunk;
+mod compression;
+mod encoding;
 mod message;
+mod options;
 mod request;
 mod response;
 
+pub use chunk::{ChunkAssembler, split_message};
+pub use compression::{BodyCompression, Compressor};
+pub use encoding::{Encoding, Frame, decode_binary, decode_text};
 pub use message::{ErrorCode, Message};
+pub use options::{
+    AlertBreach, AlertThresholds, BasicAuth, Capability, CorsPolicy, DEFAULT_ALERT_MIN_REQUESTS,
+    DEFAULT_ALERT_WINDOW_SECS, DEFAULT_CORS_METHODS, Handoff, IpAccess, IpNet, PreflightOutcome,
+    RewriteStrategy, SessionAffinity, TunnelInfo, TunnelOptions,
+};
 pub use request::HttpRequest;
 pub use response::HttpResponse;
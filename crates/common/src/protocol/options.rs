@@ -0,0 +1,1039 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+use super::{BodyCompression, Encoding};
+use crate::models::TunnelStats;
+
+/// Default evaluation window for alert thresholds (5 minutes)
+pub const DEFAULT_ALERT_WINDOW_SECS: i64 = 300;
+
+/// Default minimum number of requests in the window before alerts fire
+pub const DEFAULT_ALERT_MIN_REQUESTS: u64 = 10;
+
+/// Methods allowed by a CORS policy that doesn't list its own
+pub const DEFAULT_CORS_METHODS: [&str; 7] =
+    ["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS"];
+
+/// Per-tunnel options requested by the agent in its Ready message
+///
+/// Every field is optional so agents and handlers of different versions can
+/// talk to each other; an empty set of options serializes to nothing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TunnelOptions {
+    /// Thresholds that raise an alert when the tunnel misbehaves
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<AlertThresholds>,
+
+    /// CORS policy used to answer preflight requests at the edge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cors: Option<CorsPolicy>,
+
+    /// Reserved tunnel ID to use instead of a random one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel_id: Option<String>,
+
+    /// Reserve `tunnel_id` for the token's subject if nobody holds it yet
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub reserve: bool,
+
+    /// Serve `tunnel_id` alongside other agents of the same owner instead of
+    /// taking it over; requests are balanced between them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared: bool,
+
+    /// Route each client of a shared tunnel to the same agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub affinity: Option<SessionAffinity>,
+
+    /// Take over the tunnel of a connection that is about to expire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handoff: Option<Handoff>,
+
+    /// Source addresses allowed or denied access to the tunnel
+    #[serde(default, skip_serializing_if = "IpAccess::is_empty")]
+    pub ip_access: IpAccess,
+
+    /// Credentials public clients must present (HTTP basic auth)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub basic_auth: Option<BasicAuth>,
+
+    /// Encoding of the messages the handler sends to this agent
+    #[serde(default, skip_serializing_if = "Encoding::is_json")]
+    pub encoding: Encoding,
+
+    /// Send this agent `TunnelStats` messages with the tunnel's lifetime totals
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub stats_updates: bool,
+
+    /// How path-routed responses are rewritten to stay under the tunnel prefix
+    #[serde(default, skip_serializing_if = "RewriteStrategy::is_full")]
+    pub rewrite: RewriteStrategy,
+
+    /// Compression of the bodies of requests the handler sends to this agent
+    #[serde(default, skip_serializing_if = "BodyCompression::is_none")]
+    pub compression: BodyCompression,
+}
+
+impl TunnelOptions {
+    /// Whether no options were requested
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether the options keep some public clients out (basic auth or IP rules)
+    pub fn restricts_access(&self) -> bool {
+        self.basic_auth.is_some() || !self.ip_access.is_empty()
+    }
+
+    /// Capabilities these options rely on
+    pub fn required_capabilities(&self) -> Vec<Capability> {
+        let mut required = Vec::new();
+        if self.alerts.is_some() {
+            required.push(Capability::Alerts);
+        }
+        if self.cors.is_some() {
+            required.push(Capability::Cors);
+        }
+        if self.tunnel_id.is_some() {
+            required.push(Capability::Reservations);
+        }
+        if self.affinity.is_some() {
+            required.push(Capability::Affinity);
+        }
+        if self.basic_auth.is_some() {
+            required.push(Capability::BasicAuth);
+        }
+        if !self.ip_access.is_empty() {
+            required.push(Capability::IpAccess);
+        }
+        if !self.rewrite.is_full() {
+            required.push(Capability::Rewrite);
+        }
+        required
+    }
+}
+
+/// Identifies the connection a new connection takes over from
+///
+/// The token comes from the old connection's `ReconnectRequested` message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Handoff {
+    pub connection_id: String,
+    pub token: String,
+}
+
+/// Optional features a handler deployment may support
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// Per-tunnel traffic statistics
+    Stats,
+    /// Slow-request and error-rate alerts
+    Alerts,
+    /// CORS preflight answered at the edge
+    Cors,
+    /// WebSocket passthrough for public clients
+    #[serde(rename = "websocket")]
+    WebSocket,
+    /// Reserved tunnel IDs kept across reconnects
+    Reservations,
+    /// Session affinity between agents sharing a tunnel
+    Affinity,
+    /// MessagePack frames for agents that ask for them
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    /// HTTP basic auth enforced at the edge
+    BasicAuth,
+    /// Source address allow and deny lists enforced at the edge
+    IpAccess,
+    /// Lifetime totals pushed to agents in `TunnelStats` messages
+    TunnelStats,
+    /// Response bodies relayed as they arrive in `ResponseChunk` messages
+    Streaming,
+    /// Content rewriting strategy chosen per tunnel
+    Rewrite,
+    /// Message bodies compressed as the agent asked in its Ready message
+    Compression,
+    /// Capability added by a newer handler
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stats => "stats",
+            Self::Alerts => "alerts",
+            Self::Cors => "cors",
+            Self::WebSocket => "websocket",
+            Self::Reservations => "reservations",
+            Self::Affinity => "affinity",
+            Self::MessagePack => "msgpack",
+            Self::BasicAuth => "basic_auth",
+            Self::IpAccess => "ip_access",
+            Self::TunnelStats => "tunnel_stats",
+            Self::Streaming => "streaming",
+            Self::Rewrite => "rewrite",
+            Self::Compression => "compression",
+            Self::Unknown => "unknown",
+        }
+    }
+
+    /// Whether options relying on this capability keep clients out of the tunnel
+    pub fn guards_access(&self) -> bool {
+        matches!(self, Self::BasicAuth | Self::IpAccess)
+    }
+}
+
+/// Limits and features of an established tunnel, reported by the handler
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TunnelInfo {
+    /// When the connection will be closed by the gateway (Unix epoch seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+
+    /// AWS region serving the tunnel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+
+    /// Largest request or response body the edge accepts, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<usize>,
+
+    /// Optional features enabled on this deployment
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+}
+
+impl TunnelInfo {
+    /// Check if the deployment supports a capability
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Capabilities the options rely on that this deployment lacks
+    pub fn unsupported(&self, options: &TunnelOptions) -> Vec<Capability> {
+        options
+            .required_capabilities()
+            .into_iter()
+            .filter(|capability| !self.supports(*capability))
+            .collect()
+    }
+}
+
+/// Slow-request and error-rate alert thresholds for a tunnel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertThresholds {
+    /// Alert when p95 latency exceeds this many milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p95_ms: Option<u64>,
+
+    /// Alert when the fraction of 5xx responses exceeds this value (0.0 - 1.0)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_rate: Option<f64>,
+
+    /// Window the thresholds are evaluated over, in seconds
+    #[serde(default = "default_alert_window_secs")]
+    pub window_secs: i64,
+
+    /// Requests required in the window before any threshold is considered
+    #[serde(default = "default_alert_min_requests")]
+    pub min_requests: u64,
+}
+
+fn default_alert_window_secs() -> i64 {
+    DEFAULT_ALERT_WINDOW_SECS
+}
+
+fn default_alert_min_requests() -> u64 {
+    DEFAULT_ALERT_MIN_REQUESTS
+}
+
+impl Default for AlertThresholds {
+    fn default() -> Self {
+        Self {
+            p95_ms: None,
+            error_rate: None,
+            window_secs: DEFAULT_ALERT_WINDOW_SECS,
+            min_requests: DEFAULT_ALERT_MIN_REQUESTS,
+        }
+    }
+}
+
+/// A threshold exceeded by a tunnel's recent traffic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlertBreach {
+    /// Metric that crossed its threshold ("p95_ms" or "error_rate")
+    pub metric: String,
+    /// Observed value over the window
+    pub observed: f64,
+    /// Configured threshold
+    pub threshold: f64,
+}
+
+impl AlertThresholds {
+    /// Whether any threshold is configured
+    pub fn is_enabled(&self) -> bool {
+        self.p95_ms.is_some() || self.error_rate.is_some()
+    }
+
+    /// Thresholds exceeded by `stats` (empty when healthy or below `min_requests`)
+    pub fn breaches(&self, stats: &TunnelStats) -> Vec<AlertBreach> {
+        let mut breaches = Vec::new();
+        if stats.requests < self.min_requests {
+            return breaches;
+        }
+
+        if let (Some(threshold), Some(observed)) = (self.p95_ms, stats.p95_ms)
+            && observed > threshold
+        {
+            breaches.push(AlertBreach {
+                metric: "p95_ms".to_string(),
+                observed: observed as f64,
+                threshold: threshold as f64,
+            });
+        }
+
+        if let Some(threshold) = self.error_rate {
+            let observed = stats.error_rate();
+            if observed > threshold {
+                breaches.push(AlertBreach {
+                    metric: "error_rate".to_string(),
+                    observed,
+                    threshold,
+                });
+            }
+        }
+
+        breaches
+    }
+}
+
+/// CORS policy for a tunnel
+///
+/// When set, the handler answers preflight `OPTIONS` requests itself instead of
+/// forwarding them, and adds `Access-Control-Allow-Origin` to responses that
+/// don't already carry it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CorsPolicy {
+    /// Allowed origins (`*` allows any origin)
+    pub allow_origins: Vec<String>,
+
+    /// Allowed methods (defaults to [`DEFAULT_CORS_METHODS`] when empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_methods: Vec<String>,
+
+    /// Allowed request headers (echoes the requested headers when empty)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow_headers: Vec<String>,
+
+    /// Whether credentials (cookies, authorization) may be sent
+    #[serde(default)]
+    pub allow_credentials: bool,
+
+    /// How long browsers may cache the preflight result, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<u64>,
+}
+
+/// Outcome of evaluating a CORS preflight request against a policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PreflightOutcome {
+    /// Allowed; respond with these headers
+    Allowed(Vec<(String, String)>),
+    /// The origin or method isn't allowed by the policy
+    Denied,
+}
+
+impl CorsPolicy {
+    /// Value for `Access-Control-Allow-Origin` if `origin` is allowed
+    ///
+    /// Wildcard policies echo the origin when credentials are allowed, since
+    /// browsers reject `*` on credentialed requests.
+    pub fn allowed_origin(&self, origin: &str) -> Option<String> {
+        let wildcard = self.allow_origins.iter().any(|allowed| allowed == "*");
+        if wildcard && !self.allow_credentials {
+            return Some("*".to_string());
+        }
+
+        (wildcard
+            || self
+                .allow_origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin)))
+        .then(|| origin.to_string())
+    }
+
+    fn allows_method(&self, method: &str) -> bool {
+        if self.allow_methods.is_empty() {
+            DEFAULT_CORS_METHODS
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method))
+        } else {
+            self.allow_methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method))
+        }
+    }
+
+    /// Evaluate a preflight request (`Origin`, `Access-Control-Request-Method`
+    /// and optional `Access-Control-Request-Headers`)
+    pub fn preflight(
+        &self,
+        origin: &str,
+        request_method: &str,
+        request_headers: Option<&str>,
+    ) -> PreflightOutcome {
+        let Some(allow_origin) = self.allowed_origin(origin) else {
+            return PreflightOutcome::Denied;
+        };
+        if !self.allows_method(request_method) {
+            return PreflightOutcome::Denied;
+        }
+
+        let allow_methods = if self.allow_methods.is_empty() {
+            DEFAULT_CORS_METHODS.join(", ")
+        } else {
+            self.allow_methods.join(", ")
+        };
+
+        let mut headers = self.origin_headers(allow_origin);
+        headers.push(("access-control-allow-methods".to_string(), allow_methods));
+
+        let allow_headers = if self.allow_headers.is_empty() {
+            request_headers.map(str::to_string)
+        } else {
+            Some(self.allow_headers.join(", "))
+        };
+        if let Some(allow_headers) = allow_headers.filter(|h| !h.is_empty()) {
+            headers.push(("access-control-allow-headers".to_string(), allow_headers));
+        }
+
+        if let Some(max_age) = self.max_age_secs {
+            headers.push(("access-control-max-age".to_string(), max_age.to_string()));
+        }
+
+        PreflightOutcome::Allowed(headers)
+    }
+
+    /// Headers to add to an actual (non-preflight) response for `origin`
+    pub fn response_headers(&self, origin: &str) -> Vec<(String, String)> {
+        self.allowed_origin(origin)
+            .map(|allow_origin| self.origin_headers(allow_origin))
+            .unwrap_or_default()
+    }
+
+    fn origin_headers(&self, allow_origin: String) -> Vec<(String, String)> {
+        let echoes_origin = allow_origin != "*";
+        let mut headers = vec![("access-control-allow-origin".to_string(), allow_origin)];
+        if self.allow_credentials {
+            headers.push((
+                "access-control-allow-credentials".to_string(),
+                "true".to_string(),
+            ));
+        }
+        if echoes_origin {
+            headers.push(("vary".to_string(), "Origin".to_string()));
+        }
+        headers
+    }
+}
+
+/// What identifies a client for session affinity
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionAffinity {
+    /// The client's source IP address
+    ClientIp,
+    /// The value of the named cookie; clients without it aren't pinned
+    Cookie(String),
+}
+
+impl SessionAffinity {
+    /// The session key of a request, if it has one
+    pub fn session_key<'a>(
+        &self,
+        client_ip: Option<&'a str>,
+        cookie_header: Option<&'a str>,
+    ) -> Option<&'a str> {
+        match self {
+            Self::ClientIp => client_ip,
+            Self::Cookie(name) => cookie_header?
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .filter(|value| !value.is_empty()),
+        }
+    }
+}
+
+impl std::str::FromStr for SessionAffinity {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            None if value == "ip" => Ok(Self::ClientIp),
+            Some(("cookie", name)) if !name.is_empty() => Ok(Self::Cookie(name.to_string())),
+            _ => Err(format!(
+                "invalid affinity `{}` (expected `ip` or `cookie:NAME`)",
+                value
+            )),
+        }
+    }
+}
+
+/// How responses of a path-routed tunnel are rewritten to stay under its prefix
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteStrategy {
+    /// No rewriting (pass through unchanged)
+    None,
+    /// HTML: inject <base> tag only
+    BaseTag,
+    /// HTML: rewrite all absolute paths
+    #[default]
+    FullRewrite,
+    /// Like `FullRewrite`, and JavaScript module imports and `fetch()` URLs too;
+    /// opt-in since scripts can't be rewritten reliably without running them
+    Aggressive,
+}
+
+impl RewriteStrategy {
+    /// Whether this is the default strategy
+    pub fn is_full(&self) -> bool {
+        *self == Self::FullRewrite
+    }
+}
+
+impl std::str::FromStr for RewriteStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(Self::None),
+            "base-tag" => Ok(Self::BaseTag),
+            "full" => Ok(Self::FullRewrite),
+            "aggressive" => Ok(Self::Aggressive),
+            _ => Err(format!(
+                "invalid rewrite strategy `{}` (expected `none`, `base-tag`, `full` or `aggressive`)",
+                value
+            )),
+        }
+    }
+}
+
+/// HTTP basic auth credentials a tunnel requires from public clients
+///
+/// Only a salted SHA-256 hash of the password leaves the forwarder; the
+/// handler checks the `Authorization` header of each request against it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    /// Random salt, Base64-encoded
+    pub salt: String,
+    /// SHA-256 of the salt followed by the password, Base64-encoded
+    pub password_hash: String,
+}
+
+impl BasicAuth {
+    /// Hash `password` under a fresh random salt
+    pub fn new(username: &str, password: &str) -> Self {
+        let salt: [u8; 16] = rand::random();
+        Self {
+            username: username.to_string(),
+            salt: STANDARD.encode(salt),
+            password_hash: STANDARD.encode(hash_password(&salt, password)),
+        }
+    }
+
+    /// Whether an `Authorization` header carries these credentials
+    pub fn verify(&self, authorization: Option<&str>) -> bool {
+        let Some((scheme, encoded)) = authorization.and_then(|value| value.trim().split_once(' '))
+        else {
+            return false;
+        };
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return false;
+        }
+        let Some(credentials) = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+        else {
+            return false;
+        };
+        let Some((username, password)) = credentials.split_once(':') else {
+            return false;
+        };
+        let (Ok(salt), Ok(expected)) = (
+            STANDARD.decode(&self.salt),
+            STANDARD.decode(&self.password_hash),
+        ) else {
+            return false;
+        };
+
+        // Compare every byte so the time taken doesn't reveal how much matched
+        let hash = hash_password(&salt, password);
+        let hash_matches = hash.len() == expected.len()
+            && hash
+                .iter()
+                .zip(&expected)
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0;
+        hash_matches && username == self.username
+    }
+}
+
+fn hash_password(salt: &[u8], password: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(password.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+impl std::str::FromStr for BasicAuth {
+    type Err = String;
+
+    /// Parse `user:pass`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some((username, password)) if !username.is_empty() && !password.is_empty() => {
+                Ok(Self::new(username, password))
+            }
+            _ => Err("expected `user:password`".to_string()),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network in CIDR notation, e.g. `10.0.0.0/8`
+///
+/// A bare address is a network of one host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Whether `ip` is inside this network (IPv4-mapped IPv6 addresses count as IPv4)
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::str::FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network `{}` (expected e.g. `10.0.0.0/8`)", value);
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for IpNet {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl TryFrom<String> for IpNet {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<IpNet> for String {
+    fn from(net: IpNet) -> Self {
+        net.to_string()
+    }
+}
+
+/// Source address rules of a tunnel
+///
+/// The most specific network matching a client decides (deny on a tie).
+/// Clients matching no rule are let in unless there are allow rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IpAccess {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<IpNet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<IpNet>,
+}
+
+impl IpAccess {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether a client may use the tunnel; unknown or unparsable addresses
+    /// are only let in when there are no rules
+    pub fn permits(&self, source_ip: Option<&str>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(ip) = source_ip.and_then(|ip| ip.trim().parse::<IpAddr>().ok()) else {
+            return false;
+        };
+
+        let longest = |nets: &[IpNet]| {
+            nets.iter()
+                .filter(|net| net.contains(ip))
+                .map(IpNet::prefix)
+                .max()
+        };
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::LatencyHistogram;
+    use std::collections::BTreeMap;
+
+    fn stats(ok: u64, errors: u64, latency_ms: u64) -> TunnelStats {
+        let mut histogram = LatencyHistogram::new();
+        for _ in 0..(ok + errors) {
+            histogram.record(latency_ms);
+        }
+        let mut classes = BTreeMap::new();
+        classes.insert("2xx".to_string(), ok);
+        classes.insert("5xx".to_string(), errors);
+        TunnelStats::from_counts("abc123def456".to_string(), 300, classes, &histogram)
+    }
+
+    #[test]
+    fn test_default_options_serialize_empty() {
+        let options = TunnelOptions::default();
+        assert!(options.is_default());
+        assert_eq!(serde_json::to_string(&options).unwrap(), "{}");
+
+        let parsed: TunnelOptions = serde_json::from_str("{}").unwrap();
+        assert!(parsed.is_default());
+
+        let reserved: TunnelOptions =
+            serde_json::from_str(r#"{"tunnel_id":"mywebhook001","reserve":true}"#).unwrap();
+        assert_eq!(reserved.tunnel_id.as_deref(), Some("mywebhook001"));
+        assert!(reserved.reserve);
+        assert!(!reserved.shared);
+        assert!(reserved.affinity.is_none());
+    }
+
+    #[test]
+    fn test_alert_thresholds_defaults() {
+        let parsed: AlertThresholds = serde_json::from_str(r#"{"p95_ms":5000}"#).unwrap();
+        assert_eq!(parsed.p95_ms, Some(5000));
+        assert_eq!(parsed.window_secs, DEFAULT_ALERT_WINDOW_SECS);
+        assert_eq!(parsed.min_requests, DEFAULT_ALERT_MIN_REQUESTS);
+        assert!(parsed.is_enabled());
+        assert!(!AlertThresholds::default().is_enabled());
+    }
+
+    #[test]
+    fn test_breaches() {
+        let thresholds = AlertThresholds {
+            p95_ms: Some(5_000),
+            error_rate: Some(0.1),
+            ..Default::default()
+        };
+
+        assert!(thresholds.breaches(&stats(100, 0, 200)).is_empty());
+
+        let slow = thresholds.breaches(&stats(100, 0, 8_000));
+        assert_eq!(slow.len(), 1);
+        assert_eq!(slow[0].metric, "p95_ms");
+
+        let failing = thresholds.breaches(&stats(80, 20, 200));
+        assert_eq!(failing.len(), 1);
+        assert_eq!(failing[0].metric, "error_rate");
+        assert!((failing[0].observed - 0.2).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_tunnel_info_unsupported_options() {
+        let info: TunnelInfo = serde_json::from_str(
+            r#"{"max_body_size":2097152,"capabilities":["stats","teleport"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            info.capabilities,
+            vec![Capability::Stats, Capability::Unknown]
+        );
+        assert_eq!(info.max_body_size, Some(2097152));
+
+        let options = TunnelOptions {
+            alerts: Some(AlertThresholds {
+                p95_ms: Some(1000),
+                ..Default::default()
+            }),
+            cors: Some(CorsPolicy::default()),
+            tunnel_id: Some("mywebhook001".to_string()),
+            reserve: true,
+            shared: false,
+            affinity: None,
+            handoff: None,
+            ip_access: IpAccess::default(),
+            basic_auth: None,
+            // Falls back to JSON, so never reported as unsupported
+            encoding: Encoding::MessagePack,
+            // Optional: without it the agent just gets no updates
+            stats_updates: true,
+            rewrite: RewriteStrategy::FullRewrite,
+            // Without it bodies just travel uncompressed
+            compression: BodyCompression::Zstd,
+        };
+        assert_eq!(
+            info.unsupported(&options),
+            vec![
+                Capability::Alerts,
+                Capability::Cors,
+                Capability::Reservations
+            ]
+        );
+        assert!(info.unsupported(&TunnelOptions::default()).is_empty());
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_cors_preflight() {
+        let policy = CorsPolicy {
+            allow_origins: vec!["https://app.example.com".to_string()],
+            max_age_secs: Some(600),
+            ..Default::default()
+        };
+
+        let PreflightOutcome::Allowed(headers) = policy.preflight(
+            "https://app.example.com",
+            "PUT",
+            Some("content-type, x-token"),
+        ) else {
+            panic!("preflight should be allowed");
+        };
+        assert_eq!(
+            header(&headers, "access-control-allow-origin"),
+            Some("https://app.example.com")
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-headers"),
+            Some("content-type, x-token")
+        );
+        assert_eq!(header(&headers, "access-control-max-age"), Some("600"));
+        assert_eq!(header(&headers, "vary"), Some("Origin"));
+
+        assert_eq!(
+            policy.preflight("https://evil.example.com", "GET", None),
+            PreflightOutcome::Denied
+        );
+        assert_eq!(
+            policy.preflight("https://app.example.com", "TRACE", None),
+            PreflightOutcome::Denied
+        );
+    }
+
+    #[test]
+    fn test_cors_wildcard_origin() {
+        let policy = CorsPolicy {
+            allow_origins: vec!["*".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(policy.allowed_origin("https://a.com").as_deref(), Some("*"));
+        assert_eq!(
+            header(&policy.response_headers("https://a.com"), "vary"),
+            None
+        );
+
+        // Credentialed requests can't use `*`, so the origin is echoed
+        let policy = CorsPolicy {
+            allow_credentials: true,
+            ..policy
+        };
+        let headers = policy.response_headers("https://a.com");
+        assert_eq!(
+            header(&headers, "access-control-allow-origin"),
+            Some("https://a.com")
+        );
+        assert_eq!(
+            header(&headers, "access-control-allow-credentials"),
+            Some("true")
+        );
+    }
+
+    #[test]
+    fn test_breaches_require_min_requests() {
+        let thresholds = AlertThresholds {
+            error_rate: Some(0.1),
+            ..Default::default()
+        };
+        assert!(thresholds.breaches(&stats(1, 2, 200)).is_empty());
+    }
+
+    #[test]
+    fn test_ip_net() {
+        let net: IpNet = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.1.2.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+        let host: IpNet = "2001:db8::1".parse().unwrap();
+        assert_eq!(host.to_string(), "2001:db8::1/128");
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+        assert_eq!(serde_json::to_string(&net).unwrap(), r#""10.0.0.0/8""#);
+        assert!(serde_json::from_str::<IpNet>(r#""bogus""#).is_err());
+    }
+
+    #[test]
+    fn test_ip_access() {
+        let nets = |list: &[&str]| list.iter().map(|n| n.parse().unwrap()).collect();
+
+        assert!(IpAccess::default().permits(None));
+
+        // Allow a range, deny everything else
+        let access = IpAccess {
+            allow: nets(&["10.0.0.0/8"]),
+            deny: nets(&["0.0.0.0/0"]),
+        };
+        assert!(access.permits(Some("10.1.2.3")));
+        assert!(!access.permits(Some("8.8.8.8")));
+        assert!(!access.permits(None));
+        assert!(!access.permits(Some("not an ip")));
+
+        // The more specific rule wins
+        let access = IpAccess {
+            allow: nets(&["10.0.0.0/8"]),
+            deny: nets(&["10.6.0.0/16"]),
+        };
+        assert!(access.permits(Some("10.1.2.3")));
+        assert!(!access.permits(Some("10.6.0.1")));
+        assert!(!access.permits(Some("192.0.2.1")));
+
+        let access = IpAccess {
+            allow: vec![],
+            deny: nets(&["192.0.2.0/24"]),
+        };
+        assert!(access.permits(Some("198.51.100.1")));
+        assert!(!access.permits(Some("192.0.2.1")));
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let auth: BasicAuth = "admin:s3cret:with-colon".parse().unwrap();
+        assert_eq!(auth.username, "admin");
+        assert!(!auth.password_hash.contains("s3cret"));
+        assert_ne!(auth, "admin:s3cret:with-colon".parse().unwrap());
+
+        let header = |credentials: &str| format!("Basic {}", STANDARD.encode(credentials));
+        assert!(auth.verify(Some(&header("admin:s3cret:with-colon"))));
+        assert!(auth.verify(Some(&format!(
+            "basic  {}",
+            STANDARD.encode("admin:s3cret:with-colon")
+        ))));
+        assert!(!auth.verify(Some(&header("admin:s3cret"))));
+        assert!(!auth.verify(Some(&header("root:s3cret:with-colon"))));
+        assert!(!auth.verify(Some("Bearer token")));
+        assert!(!auth.verify(Some("Basic !!!")));
+        assert!(!auth.verify(None));
+
+        assert!("admin".parse::<BasicAuth>().is_err());
+        assert!(":pass".parse::<BasicAuth>().is_err());
+        assert!("admin:".parse::<BasicAuth>().is_err());
+    }
+
+    #[test]
+    fn test_session_affinity() {
+        assert_eq!("ip".parse(), Ok(SessionAffinity::ClientIp));
+        let cookie: SessionAffinity = "cookie:SESSION".parse().unwrap();
+        assert_eq!(cookie, SessionAffinity::Cookie("SESSION".to_string()));
+        assert!("cookie:".parse::<SessionAffinity>().is_err());
+        assert!("header:x".parse::<SessionAffinity>().is_err());
+
+        assert_eq!(
+            serde_json::to_string(&cookie).unwrap(),
+            r#"{"cookie":"SESSION"}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&SessionAffinity::ClientIp).unwrap(),
+            r#""client_ip""#
+        );
+
+        let header = Some("theme=dark; SESSION=abc123; other=1");
+        assert_eq!(cookie.session_key(Some("1.2.3.4"), header), Some("abc123"));
+        assert_eq!(cookie.session_key(Some("1.2.3.4"), Some("SESSION=")), None);
+        assert_eq!(cookie.session_key(Some("1.2.3.4"), None), None);
+        assert_eq!(
+            SessionAffinity::ClientIp.session_key(Some("1.2.3.4"), header),
+            Some("1.2.3.4")
+        );
+    }
+    #[test]
+    fn test_rewrite_strategy() {
+        assert_eq!("none".parse(), Ok(RewriteStrategy::None));
+        assert_eq!("base-tag".parse(), Ok(RewriteStrategy::BaseTag));
+        assert_eq!("full".parse(), Ok(RewriteStrategy::FullRewrite));
+        assert_eq!("aggressive".parse(), Ok(RewriteStrategy::Aggressive));
+        assert!("base_tag".parse::<RewriteStrategy>().is_err());
+
+        let options = TunnelOptions {
+            rewrite: RewriteStrategy::BaseTag,
+            ..Default::default()
+        };
+        assert_eq!(
+            serde_json::to_string(&options).unwrap(),
+            r#"{"rewrite":"base_tag"}"#
+        );
+        assert_eq!(options.required_capabilities(), vec![Capability::Rewrite]);
+        assert!(TunnelOptions::default().required_capabilities().is_empty());
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
+
+use super::BodyCompression;
 
 /// Represents an HTTP request forwarded from the public endpoint to the agent
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -20,11 +23,33 @@ pub struct HttpRequest {
 
     /// Request body encoded in Base64
     /// Empty string for requests without body
-    #[serde(default)]
+    #[serde(default, with = "super::encoding::body")]
     pub body: String,
 
     /// Timestamp when request was received (Unix epoch in milliseconds)
     pub timestamp: u64,
+
+    /// Remaining time budget in milliseconds before the edge gives up on this request
+    /// Absent when sent by older handlers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+
+    /// The body follows in `BodyChunk` messages terminated by `BodyEnd`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub chunked: bool,
+
+    /// Trailers sent after the body (HTTP/2 or chunked HTTP/1.1), same shape as `headers`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trailers: HashMap<String, Vec<String>>,
+
+    /// Stable ID of the public request (the client's `X-Request-Id`, or one made up
+    /// at the edge), passed to the local service so logs on every hop line up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+
+    /// How `body` is compressed inside the message (see [`BodyCompression`])
+    #[serde(default, skip_serializing_if = "BodyCompression::is_none")]
+    pub content_encoding: BodyCompression,
 }
 
 impl HttpRequest {
@@ -37,6 +62,11 @@ impl HttpRequest {
             headers: HashMap::new(),
             body: String::new(),
             timestamp,
+            timeout_ms: None,
+            chunked: false,
+            trailers: HashMap::new(),
+            correlation_id: None,
+            content_encoding: BodyCompression::None,
         }
     }
 
@@ -44,11 +74,29 @@ impl HttpRequest {
     pub fn has_body(&self) -> bool {
         !self.body.is_empty()
     }
+
+    /// Compress the body for the trip to the agent, if that's worth it
+    pub fn compress_body(&mut self, compression: BodyCompression) {
+        super::compression::compress_body(&mut self.body, &mut self.content_encoding, compression);
+    }
+
+    /// Restore a body compressed with [`Self::compress_body`]
+    pub fn decompress_body(&mut self) -> crate::Result<()> {
+        super::compression::decompress_body(&mut self.body, &mut self.content_encoding)
+    }
+
+    /// Timeout to use for the local request: the edge's remaining budget, capped by `local_timeout`
+    pub fn effective_timeout(&self, local_timeout: Duration) -> Duration {
+        self.timeout_ms
+            .map(Duration::from_millis)
+            .map_or(local_timeout, |budget| budget.min(local_timeout))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::{Encoding, Frame, Message, decode_binary, decode_text};
 
     #[test]
     fn test_http_request_creation() {
@@ -86,6 +134,11 @@ mod tests {
             headers,
             body: "eyJ0ZXN0IjoidmFsdWUifQ==".to_string(), // {"test":"value"}
             timestamp: 1234567890,
+            timeout_ms: None,
+            chunked: false,
+            trailers: Default::default(),
+            correlation_id: None,
+            content_encoding: Default::default(),
         };
 
         assert_eq!(req.headers.len(), 2);
@@ -104,6 +157,11 @@ mod tests {
             headers,
             body: String::new(),
             timestamp: 1234567890000,
+            timeout_ms: None,
+            chunked: false,
+            trailers: Default::default(),
+            correlation_id: None,
+            content_encoding: Default::default(),
         };
 
         let json = serde_json::to_string(&req).unwrap();
@@ -118,6 +176,31 @@ mod tests {
         assert_eq!(parsed.timestamp, req.timestamp);
     }
 
+    #[test]
+    fn test_http_request_custom_method() {
+        // Methods are carried as they are, whatever the encoding
+        for method in ["PROPFIND", "MKCOL", "PURGE", "X-CUSTOM"] {
+            let req = HttpRequest::new(
+                method.to_string(),
+                "/dav/".to_string(),
+                "req_abc123".to_string(),
+                0,
+            );
+            let message = Message::HttpRequest(req);
+            for encoding in [Encoding::Json, Encoding::MessagePack] {
+                let decoded = match encoding.encode(&message).unwrap() {
+                    Frame::Text(text) => decode_text(&text),
+                    Frame::Binary(data) => decode_binary(&data),
+                }
+                .unwrap();
+                let Message::HttpRequest(decoded) = decoded else {
+                    panic!("expected an HttpRequest");
+                };
+                assert_eq!(decoded.method, method);
+            }
+        }
+    }
+
     #[test]
     fn test_http_request_multiple_header_values() {
         let mut headers = HashMap::new();
@@ -133,6 +216,11 @@ mod tests {
             headers,
             body: String::new(),
             timestamp: 1234567890,
+            timeout_ms: None,
+            chunked: false,
+            trailers: Default::default(),
+            correlation_id: None,
+            content_encoding: Default::default(),
         };
 
         assert_eq!(req.headers.get("cookie").unwrap().len(), 2);
@@ -155,5 +243,31 @@ mod tests {
         let parsed: HttpRequest = serde_json::from_str(json).unwrap();
         assert_eq!(parsed.body, "");
         assert!(!parsed.has_body());
+        assert!(parsed.timeout_ms.is_none());
+    }
+
+    #[test]
+    fn test_http_request_effective_timeout() {
+        let mut req = HttpRequest::new(
+            "GET".to_string(),
+            "/".to_string(),
+            "req_123".to_string(),
+            1234567890,
+        );
+        let local = Duration::from_secs(25);
+
+        // No budget from the edge: use local timeout
+        assert_eq!(req.effective_timeout(local), local);
+
+        // Edge budget shorter than local timeout wins
+        req.timeout_ms = Some(3_000);
+        assert_eq!(req.effective_timeout(local), Duration::from_secs(3));
+
+        // Local timeout caps a generous edge budget
+        req.timeout_ms = Some(60_000);
+        assert_eq!(req.effective_timeout(local), local);
+
+        let json = serde_json::to_string(&req).unwrap();
+        assert!(json.contains(r#""timeout_ms":60000"#));
     }
 }
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use super::BodyCompression;
+
 /// Represents the response from the local service, sent back through the tunnel
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HttpResponse {
@@ -14,12 +16,29 @@ pub struct HttpResponse {
     pub headers: HashMap<String, Vec<String>>,
 
     /// Response body encoded in Base64
-    #[serde(default)]
+    #[serde(default, with = "super::encoding::body")]
     pub body: String,
 
     /// Processing time in milliseconds (local service response time)
     #[serde(default)]
     pub processing_time_ms: u64,
+
+    /// The body follows in `BodyChunk` messages terminated by `BodyEnd`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub chunked: bool,
+
+    /// The body follows as the local service produces it, in `ResponseChunk`
+    /// messages ending with one marked `last`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streaming: bool,
+
+    /// Trailers the local service sent after the body, such as gRPC's `grpc-status`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trailers: HashMap<String, Vec<String>>,
+
+    /// How `body` is compressed inside the message (see [`BodyCompression`])
+    #[serde(default, skip_serializing_if = "BodyCompression::is_none")]
+    pub content_encoding: BodyCompression,
 }
 
 impl HttpResponse {
@@ -31,6 +50,10 @@ impl HttpResponse {
             headers: HashMap::new(),
             body: String::new(),
             processing_time_ms: 0,
+            chunked: false,
+            streaming: false,
+            trailers: HashMap::new(),
+            content_encoding: BodyCompression::None,
         }
     }
 
@@ -39,6 +62,16 @@ impl HttpResponse {
         !self.body.is_empty()
     }
 
+    /// Compress the body for the trip to the handler, if that's worth it
+    pub fn compress_body(&mut self, compression: BodyCompression) {
+        super::compression::compress_body(&mut self.body, &mut self.content_encoding, compression);
+    }
+
+    /// Restore a body compressed with [`Self::compress_body`]
+    pub fn decompress_body(&mut self) -> crate::Result<()> {
+        super::compression::decompress_body(&mut self.body, &mut self.content_encoding)
+    }
+
     /// Check if the response is successful (2xx status code)
     pub fn is_success(&self) -> bool {
         (200..300).contains(&self.status_code)
@@ -103,6 +136,10 @@ mod tests {
             headers,
             body: "eyJ0ZXN0IjoidmFsdWUifQ==".to_string(),
             processing_time_ms: 123,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         assert_eq!(res.headers.len(), 2);
@@ -121,6 +158,10 @@ mod tests {
             headers,
             body: "dGVzdCBkYXRh".to_string(), // "test data"
             processing_time_ms: 456,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         let json = serde_json::to_string(&res).unwrap();
@@ -135,6 +176,19 @@ mod tests {
         assert_eq!(parsed.processing_time_ms, res.processing_time_ms);
     }
 
+    #[test]
+    fn test_http_response_trailers() {
+        let mut res = HttpResponse::new("req_1".to_string(), 200);
+        let json = serde_json::to_string(&res).unwrap();
+        assert!(!json.contains("trailers"));
+
+        res.trailers
+            .insert("grpc-status".to_string(), vec!["0".to_string()]);
+        let json = serde_json::to_string(&res).unwrap();
+        let parsed: HttpResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.trailers, res.trailers);
+    }
+
     #[test]
     fn test_http_response_multiple_header_values() {
         let mut headers = HashMap::new();
@@ -149,6 +203,10 @@ mod tests {
             headers,
             body: String::new(),
             processing_time_ms: 0,
+            chunked: false,
+            streaming: false,
+            trailers: Default::default(),
+            content_encoding: Default::default(),
         };
 
         assert_eq!(res.headers.get("set-cookie").unwrap().len(), 2);
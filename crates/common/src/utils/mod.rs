@@ -1,9 +1,11 @@
 mod encoding;
 mod headers;
 mod id;
+mod multipart;
 mod time;
 
 pub use encoding::{decode_body, encode_body};
 pub use headers::{headers_to_map, map_to_headers};
 pub use id::{generate_request_id, generate_subdomain};
+pub use multipart::{Multipart, MultipartPart, boundary_from_content_type, is_multipart};
 pub use time::{calculate_ttl, current_timestamp_millis, current_timestamp_secs};
@@ -0,0 +1,443 @@
+//! `multipart/form-data` parsing and reconstruction
+//!
+//! Bodies travel through the tunnel as opaque bytes, but a few features need to
+//! look inside uploads (which fields were sent, how large each file is) or
+//! rebuild a body after changing it. Parsing keeps every part's raw headers, so
+//! [`Multipart::to_bytes`] reproduces a body equivalent to the original.
+
+use crate::error::{Result, TunnelError};
+
+/// A single part of a multipart body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartPart {
+    /// Part headers in their original order and casing
+    pub headers: Vec<(String, String)>,
+
+    /// Part content, without the trailing line break before the next boundary
+    pub body: Vec<u8>,
+}
+
+impl MultipartPart {
+    /// Create a form field part
+    pub fn field(name: &str, value: impl Into<Vec<u8>>) -> Self {
+        Self {
+            headers: vec![(
+                "Content-Disposition".to_string(),
+                format!("form-data; name=\"{}\"", name),
+            )],
+            body: value.into(),
+        }
+    }
+
+    /// Create a file upload part
+    pub fn file(name: &str, filename: &str, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            headers: vec![
+                (
+                    "Content-Disposition".to_string(),
+                    format!("form-data; name=\"{}\"; filename=\"{}\"", name, filename),
+                ),
+                ("Content-Type".to_string(), content_type.to_string()),
+            ],
+            body: body.into(),
+        }
+    }
+
+    /// First value of a header, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Form field name from `Content-Disposition`
+    pub fn name(&self) -> Option<String> {
+        self.disposition_param("name")
+    }
+
+    /// Uploaded file name from `Content-Disposition`, if this part is a file
+    pub fn filename(&self) -> Option<String> {
+        self.disposition_param("filename")
+    }
+
+    /// Declared content type of the part
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("content-type")
+    }
+
+    /// Check if this part is a file upload
+    pub fn is_file(&self) -> bool {
+        self.filename().is_some()
+    }
+
+    /// Size of the part content in bytes
+    pub fn len(&self) -> usize {
+        self.body.len()
+    }
+
+    /// Check if the part content is empty
+    pub fn is_empty(&self) -> bool {
+        self.body.is_empty()
+    }
+
+    fn disposition_param(&self, param: &str) -> Option<String> {
+        header_param(self.header("content-disposition")?, param)
+    }
+}
+
+/// A parsed multipart body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Multipart {
+    pub boundary: String,
+    pub parts: Vec<MultipartPart>,
+}
+
+impl Multipart {
+    /// Create an empty multipart body with the given boundary
+    pub fn new(boundary: impl Into<String>) -> Self {
+        Self {
+            boundary: boundary.into(),
+            parts: Vec::new(),
+        }
+    }
+
+    /// Parse a body using the boundary from its `Content-Type` header
+    pub fn parse(content_type: &str, body: &[u8]) -> Result<Self> {
+        let boundary = boundary_from_content_type(content_type).ok_or_else(|| {
+            TunnelError::InvalidMultipart(format!(
+                "no multipart boundary in content type `{}`",
+                content_type
+            ))
+        })?;
+        Self::parse_with_boundary(&boundary, body)
+    }
+
+    /// Parse a body delimited by `boundary`
+    pub fn parse_with_boundary(boundary: &str, body: &[u8]) -> Result<Self> {
+        let delimiter = format!("--{}", boundary).into_bytes();
+        let mut parts = Vec::new();
+
+        // Anything before the first delimiter is preamble and ignored
+        let mut pos = find(body, &delimiter, 0)
+            .ok_or_else(|| invalid("missing opening boundary"))?
+            + delimiter.len();
+
+        loop {
+            if body[pos..].starts_with(b"--") {
+                break;
+            }
+            pos = skip_line_break(body, pos).ok_or_else(|| invalid("malformed boundary line"))?;
+
+            let (headers, body_start) = parse_part_headers(body, pos)?;
+
+            // The part ends at the line break preceding the next delimiter
+            let next = find_delimiter(body, &delimiter, body_start)
+                .ok_or_else(|| invalid("missing closing boundary"))?;
+            let body_end = if next >= 2 && &body[next - 2..next] == b"\r\n" {
+                next - 2
+            } else {
+                next - 1
+            };
+
+            parts.push(MultipartPart {
+                headers,
+                body: body[body_start..body_end.max(body_start)].to_vec(),
+            });
+            pos = next + delimiter.len();
+        }
+
+        Ok(Self {
+            boundary: boundary.to_string(),
+            parts,
+        })
+    }
+
+    /// `Content-Type` header value for this body
+    pub fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    /// Serialize back into a multipart body
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.encoded_len());
+        for part in &self.parts {
+            out.extend_from_slice(b"--");
+            out.extend_from_slice(self.boundary.as_bytes());
+            out.extend_from_slice(b"\r\n");
+            for (name, value) in &part.headers {
+                out.extend_from_slice(name.as_bytes());
+                out.extend_from_slice(b": ");
+                out.extend_from_slice(value.as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(&part.body);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"--");
+        out.extend_from_slice(self.boundary.as_bytes());
+        out.extend_from_slice(b"--\r\n");
+        out
+    }
+
+    /// Size in bytes of the body produced by [`Multipart::to_bytes`]
+    pub fn encoded_len(&self) -> usize {
+        let delimiter = self.boundary.len() + 4; // "--" boundary CRLF
+        let parts: usize = self
+            .parts
+            .iter()
+            .map(|part| {
+                let headers: usize = part
+                    .headers
+                    .iter()
+                    .map(|(name, value)| name.len() + value.len() + 4)
+                    .sum();
+                delimiter + headers + 2 + part.len() + 2
+            })
+            .sum();
+        parts + delimiter + 2
+    }
+
+    /// Total size of the part contents, excluding boundaries and headers
+    pub fn content_len(&self) -> usize {
+        self.parts.iter().map(MultipartPart::len).sum()
+    }
+
+    /// The largest file upload, if any
+    pub fn largest_file(&self) -> Option<&MultipartPart> {
+        self.parts
+            .iter()
+            .filter(|part| part.is_file())
+            .max_by_key(|part| part.len())
+    }
+
+    /// Find a part by form field name
+    pub fn part(&self, name: &str) -> Option<&MultipartPart> {
+        self.parts
+            .iter()
+            .find(|part| part.name().as_deref() == Some(name))
+    }
+}
+
+/// Check if a `Content-Type` is multipart (any subtype)
+pub fn is_multipart(content_type: &str) -> bool {
+    content_type
+        .trim_start()
+        .get(..10)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case("multipart/"))
+}
+
+/// Extract the boundary parameter from a multipart `Content-Type`
+pub fn boundary_from_content_type(content_type: &str) -> Option<String> {
+    if !is_multipart(content_type) {
+        return None;
+    }
+    header_param(content_type, "boundary").filter(|boundary| !boundary.is_empty())
+}
+
+/// Read a `key=value` parameter from a header value such as
+/// `form-data; name="file"; filename="a;b.txt"`, honoring quotes
+fn header_param(value: &str, param: &str) -> Option<String> {
+    split_params(value).into_iter().skip(1).find_map(|segment| {
+        let (key, val) = segment.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(param) {
+            return None;
+        }
+        let val = val.trim();
+        Some(
+            match val.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => val.to_string(),
+            },
+        )
+    })
+}
+
+/// Split a header value on `;` outside quoted strings
+fn split_params(value: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => {
+                segments.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&value[start..]);
+    segments
+}
+
+fn invalid(message: &str) -> TunnelError {
+    TunnelError::InvalidMultipart(message.to_string())
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| i + from)
+}
+
+/// Find a delimiter line (content may contain the boundary text elsewhere, so
+/// it must start a line and be followed by `--`, padding or a line break)
+fn find_delimiter(body: &[u8], delimiter: &[u8], from: usize) -> Option<usize> {
+    let mut pos = from;
+    while let Some(found) = find(body, delimiter, pos) {
+        let starts_line = found > 0 && body[found - 1] == b'\n';
+        let rest = &body[found + delimiter.len()..];
+        let ends_delimiter = rest.starts_with(b"--")
+            || matches!(rest.first(), None | Some(b'\r' | b'\n' | b' ' | b'\t'));
+        if starts_line && ends_delimiter {
+            return Some(found);
+        }
+        pos = found + 1;
+    }
+    None
+}
+
+fn skip_line_break(body: &[u8], pos: usize) -> Option<usize> {
+    // Transport padding (whitespace) is allowed after the boundary
+    let mut pos = pos;
+    while matches!(body.get(pos), Some(b' ' | b'\t')) {
+        pos += 1;
+    }
+    match body.get(pos..)? {
+        [b'\r', b'\n', ..] => Some(pos + 2),
+        [b'\n', ..] => Some(pos + 1),
+        _ => None,
+    }
+}
+
+/// Parse part headers starting at `pos`, returning them and the content offset
+fn parse_part_headers(body: &[u8], mut pos: usize) -> Result<(Vec<(String, String)>, usize)> {
+    let mut headers = Vec::new();
+
+    loop {
+        let line_end =
+            find(body, b"\n", pos).ok_or_else(|| invalid("unterminated part headers"))?;
+        let line = &body[pos..line_end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        pos = line_end + 1;
+
+        if line.is_empty() {
+            return Ok((headers, pos));
+        }
+
+        let line = std::str::from_utf8(line).map_err(|_| invalid("part header is not UTF-8"))?;
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed part header"))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+hello\r\n\
+--XyZ\r\n\
+Content-Disposition: form-data; name=\"upload\"; filename=\"a;b.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+line one\r\n--XyZ-not-a-boundary\r\n\
+--XyZ--\r\n";
+
+    #[test]
+    fn test_boundary_from_content_type() {
+        assert_eq!(
+            boundary_from_content_type("multipart/form-data; boundary=XyZ").as_deref(),
+            Some("XyZ")
+        );
+        assert_eq!(
+            boundary_from_content_type("Multipart/Form-Data; charset=utf-8; boundary=\"a b\"")
+                .as_deref(),
+            Some("a b")
+        );
+        assert_eq!(boundary_from_content_type("application/json"), None);
+        assert_eq!(boundary_from_content_type("multipart/form-data"), None);
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let multipart = Multipart::parse("multipart/form-data; boundary=XyZ", BODY).unwrap();
+        assert_eq!(multipart.parts.len(), 2);
+
+        let title = multipart.part("title").unwrap();
+        assert_eq!(title.body, b"hello");
+        assert!(!title.is_file());
+
+        let upload = multipart.part("upload").unwrap();
+        assert_eq!(upload.filename().as_deref(), Some("a;b.txt"));
+        assert_eq!(upload.content_type(), Some("text/plain"));
+        assert_eq!(upload.body, b"line one\r\n--XyZ-not-a-boundary");
+
+        assert_eq!(multipart.content_len(), 5 + upload.len());
+        assert_eq!(multipart.largest_file(), Some(upload));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let multipart = Multipart::parse_with_boundary("XyZ", BODY).unwrap();
+        let bytes = multipart.to_bytes();
+        assert_eq!(bytes.len(), multipart.encoded_len());
+        assert_eq!(
+            Multipart::parse_with_boundary("XyZ", &bytes).unwrap(),
+            multipart
+        );
+    }
+
+    #[test]
+    fn test_build_multipart() {
+        let mut multipart = Multipart::new("b0undary");
+        multipart.parts.push(MultipartPart::field("name", "ttf"));
+        multipart.parts.push(MultipartPart::file(
+            "file",
+            "x.bin",
+            "application/octet-stream",
+            vec![0u8, 1, 2],
+        ));
+
+        let parsed = Multipart::parse(&multipart.content_type(), &multipart.to_bytes()).unwrap();
+        assert_eq!(parsed, multipart);
+        assert_eq!(parsed.part("file").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_lf_only_and_empty() {
+        let body = b"--b\nContent-Disposition: form-data; name=\"x\"\n\n1\n--b--";
+        let multipart = Multipart::parse_with_boundary("b", body).unwrap();
+        assert_eq!(multipart.part("x").unwrap().body, b"1");
+
+        let multipart = Multipart::parse_with_boundary("b", b"--b--\r\n").unwrap();
+        assert!(multipart.parts.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Multipart::parse("text/plain", BODY).is_err());
+        assert!(Multipart::parse_with_boundary("other", BODY).is_err());
+        assert!(
+            Multipart::parse_with_boundary("b", b"--b\r\nContent-Disposition: x\r\n\r\nno end")
+                .is_err()
+        );
+        assert!(
+            Multipart::parse_with_boundary("b", b"--b\r\nbroken header\r\n\r\n\r\n--b--").is_err()
+        );
+    }
+}
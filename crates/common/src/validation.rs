@@ -19,6 +19,10 @@ static REQUEST_ID_REGEX: Lazy<Regex> = Lazy::new(|| {
 static CONNECTION_ID_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"^[A-Za-z0-9_=-]{1,128}$").unwrap());
 
+/// First path segments served by the Lambda itself (on the base domain, or
+/// within a tunnel for `_tunnel`)
+pub const RESERVED_PATH_SEGMENTS: [&str; 3] = ["_admin", "_dashboard", "_tunnel"];
+
 /// Maximum length for HTTP header values
 pub const MAX_HEADER_VALUE_LENGTH: usize = 8192;
 
@@ -49,7 +53,8 @@ pub enum ValidationError {
 
 /// Validate tunnel ID format
 ///
-/// Tunnel IDs must be exactly 12 lowercase alphanumeric characters.
+/// Tunnel IDs must be exactly 12 lowercase alphanumeric characters, which keeps
+/// them out of the reserved path namespace (see [`is_reserved_path_segment`]).
 ///
 /// # Examples
 ///
@@ -61,7 +66,7 @@ pub enum ValidationError {
 /// assert!(validate_tunnel_id("abc123").is_err()); // too short
 /// ```
 pub fn validate_tunnel_id(id: &str) -> Result<(), ValidationError> {
-    if !TUNNEL_ID_REGEX.is_match(id) {
+    if is_reserved_path_segment(id) || !TUNNEL_ID_REGEX.is_match(id) {
         return Err(ValidationError::InvalidTunnelId(
             id.chars().take(50).collect::<String>(), // Limit error message
         ));
@@ -69,6 +74,15 @@ pub fn validate_tunnel_id(id: &str) -> Result<(), ValidationError> {
     Ok(())
 }
 
+/// Check whether a path segment is in the reserved namespace
+///
+/// Every segment starting with `_` is reserved, not only the
+/// [`RESERVED_PATH_SEGMENTS`] in use, so new internal paths never shadow a
+/// tunnel.
+pub fn is_reserved_path_segment(segment: &str) -> bool {
+    segment.starts_with('_')
+}
+
 /// Validate request ID format
 ///
 /// Request IDs must start with "req_" followed by a UUID.
@@ -191,6 +205,17 @@ mod tests {
         assert!(validate_tunnel_id("").is_err()); // empty
     }
 
+    #[test]
+    fn test_reserved_path_segments_are_never_tunnel_ids() {
+        for segment in RESERVED_PATH_SEGMENTS {
+            assert!(is_reserved_path_segment(segment));
+            assert!(validate_tunnel_id(segment).is_err());
+        }
+        assert!(is_reserved_path_segment("_abc123def45"));
+        assert!(validate_tunnel_id("_abc123def45").is_err());
+        assert!(!is_reserved_path_segment("abc123def456"));
+    }
+
     #[test]
     fn test_validate_request_id_valid() {
         assert!(validate_request_id("req_550e8400-e29b-41d4-a716-446655440000").is_ok());
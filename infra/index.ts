@@ -5,7 +5,7 @@ import { createLambdaRole } from "./src/iam";
 import { createLambdaHandler } from "./src/lambda";
 import { createCustomDomains } from "./src/domain";
 import { createMonitoringDashboard, createAlarms, createBudget } from "./src/monitoring";
-import { createEventBus } from "./src/eventbridge";
+import { createEventBus, createTunnelAlerts } from "./src/eventbridge";
 import { createStreamMapping } from "./src/streaming";
 import { appConfig, tags } from "./src/config";
 
@@ -18,15 +18,26 @@ const awsProvider = new aws.Provider("aws-provider", {
 });
 
 // Step 1: Create DynamoDB tables
-const { connectionsTable, pendingRequestsTable } = createDynamoDBTables();
+const {
+  connectionsTable,
+  pendingRequestsTable,
+  tunnelStatsTable,
+  ipDenylistTable,
+  wsSessionsTable,
+  reservationsTable,
+  tunnelTable,
+} = createDynamoDBTables();
 
 // Step 1b: Create EventBridge event bus for event-driven responses
 const eventBus = createEventBus();
+const tunnelAlertsTopic = createTunnelAlerts(eventBus);
 
 // Step 2: Create IAM role (without WebSocket API ARN policy initially)
 const handlerRole = createLambdaRole(
   connectionsTable.arn,
   pendingRequestsTable.arn,
+  tunnelStatsTable.arn,
+  ipDenylistTable.arn,
   eventBus.arn
 );
 
@@ -53,13 +64,54 @@ const preliminaryWebsocketStage = new aws.apigatewayv2.Stage("websocket-stage",
 
 const websocketEndpoint = pulumi.interpolate`wss://${preliminaryWebsocketApi.id}.execute-api.${appConfig.awsRegion}.amazonaws.com/${preliminaryWebsocketStage.name}`;
 
+// Step 3b: Create the public WebSocket API for WebSocket passthrough (optional)
+// HTTP APIs can't upgrade connections, so public clients connect here instead
+const publicWebsocketApi = wsSessionsTable
+  ? new aws.apigatewayv2.Api("public-websocket-api", {
+      name: pulumi.interpolate`http-tunnel-public-ws-${appConfig.environment}`,
+      protocolType: "WEBSOCKET",
+      routeSelectionExpression: "$request.body.action",
+      tags: {
+        ...tags,
+        Name: "HTTP Tunnel Public WebSocket API",
+      },
+    })
+  : undefined;
+
+const publicWebsocketStage = publicWebsocketApi
+  ? new aws.apigatewayv2.Stage("public-websocket-stage", {
+      apiId: publicWebsocketApi.id,
+      name: appConfig.environment,
+      autoDeploy: true,
+      tags: {
+        ...tags,
+        Name: "HTTP Tunnel Public WebSocket Stage",
+      },
+    })
+  : undefined;
+
+const publicWebsocketEndpoint = publicWebsocketApi && publicWebsocketStage
+  ? pulumi.interpolate`wss://${publicWebsocketApi.id}.execute-api.${appConfig.awsRegion}.amazonaws.com/${publicWebsocketStage.name}`
+  : undefined;
+
 // Step 4: Create Lambda handler with the WebSocket endpoint
 const handler = createLambdaHandler(
   handlerRole,
   connectionsTable.name,
   pendingRequestsTable.name,
+  tunnelStatsTable.name,
+  ipDenylistTable.name,
   websocketEndpoint,
-  eventBus.name
+  eventBus.name,
+  publicWebsocketApi && publicWebsocketEndpoint && wsSessionsTable
+    ? {
+        apiId: publicWebsocketApi.id,
+        endpoint: publicWebsocketEndpoint,
+        sessionsTableName: wsSessionsTable.name,
+      }
+    : undefined,
+  reservationsTable?.name,
+  tunnelTable?.name
 );
 
 // Step 5: Add WebSocket API permissions to the IAM role
@@ -80,6 +132,59 @@ new aws.iam.RolePolicy("handler-websocket-policy", {
   ),
 });
 
+// Step 5b: Allow the handler to read and create tunnel ID reservations (optional)
+if (reservationsTable) {
+  new aws.iam.RolePolicy("handler-reservations-policy", {
+    role: handlerRole,
+    policy: reservationsTable.arn.apply((reservationsTableArn: string) =>
+      JSON.stringify({
+        Version: "2012-10-17",
+        Statement: [
+          {
+            Sid: "DynamoDBReservationsTable",
+            Effect: "Allow",
+            Action: ["dynamodb:GetItem", "dynamodb:PutItem"],
+            Resource: reservationsTableArn,
+          },
+        ],
+      })
+    ),
+  });
+}
+
+// Step 5c: Allow the handler to use the single table (optional)
+if (tunnelTable) {
+  new aws.iam.RolePolicy("handler-tunnel-table-policy", {
+    role: handlerRole,
+    policy: tunnelTable.arn.apply((tunnelTableArn: string) =>
+      JSON.stringify({
+        Version: "2012-10-17",
+        Statement: [
+          {
+            Sid: "DynamoDBTunnelTable",
+            Effect: "Allow",
+            Action: [
+              "dynamodb:PutItem",
+              "dynamodb:GetItem",
+              "dynamodb:UpdateItem",
+              "dynamodb:DeleteItem",
+              "dynamodb:Query", // tunnel stats buckets
+              "dynamodb:BatchGetItem", // chunked response bodies
+            ],
+            Resource: tunnelTableArn,
+          },
+          {
+            Sid: "DynamoDBTunnelTableGSI",
+            Effect: "Allow",
+            Action: ["dynamodb:Query"],
+            Resource: `${tunnelTableArn}/index/gsi1`,
+          },
+        ],
+      })
+    ),
+  });
+}
+
 // Step 6: Create WebSocket API routes
 // $connect route
 const connectIntegration = new aws.apigatewayv2.Integration(
@@ -150,6 +255,64 @@ new aws.lambda.Permission("response-lambda-permission", {
   sourceArn: pulumi.interpolate`${preliminaryWebsocketApi.executionArn}/*/$default`,
 });
 
+// Step 6b: Wire the public WebSocket API to the same handler (optional)
+if (publicWebsocketApi && wsSessionsTable) {
+  new aws.iam.RolePolicy("handler-public-websocket-policy", {
+    role: handlerRole,
+    policy: pulumi
+      .all([publicWebsocketApi.executionArn, wsSessionsTable.arn])
+      .apply(([publicApiExecArn, sessionsTableArn]) =>
+        JSON.stringify({
+          Version: "2012-10-17",
+          Statement: [
+            {
+              Sid: "ApiGatewayPublicWebSocketManagement",
+              Effect: "Allow",
+              Action: ["execute-api:ManageConnections"],
+              Resource: `${publicApiExecArn}/*/*/@connections/*`,
+            },
+            {
+              Sid: "DynamoDBWsSessionsTable",
+              Effect: "Allow",
+              Action: [
+                "dynamodb:PutItem",
+                "dynamodb:GetItem",
+                "dynamodb:DeleteItem",
+                "dynamodb:Scan", // cleanup of orphaned sessions
+              ],
+              Resource: sessionsTableArn,
+            },
+          ],
+        })
+      ),
+  });
+
+  for (const [name, routeKey] of [
+    ["public-connect", "$connect"],
+    ["public-disconnect", "$disconnect"],
+    ["public-default", "$default"],
+  ]) {
+    const integration = new aws.apigatewayv2.Integration(`${name}-integration`, {
+      apiId: publicWebsocketApi.id,
+      integrationType: "AWS_PROXY",
+      integrationUri: handler.invokeArn,
+    });
+
+    new aws.apigatewayv2.Route(`${name}-route`, {
+      apiId: publicWebsocketApi.id,
+      routeKey,
+      target: pulumi.interpolate`integrations/${integration.id}`,
+    });
+
+    new aws.lambda.Permission(`${name}-lambda-permission`, {
+      action: "lambda:InvokeFunction",
+      function: handler.name,
+      principal: "apigateway.amazonaws.com",
+      sourceArn: pulumi.interpolate`${publicWebsocketApi.executionArn}/*/${routeKey}`,
+    });
+  }
+}
+
 // Step 7: Create HTTP API
 const httpApi = new aws.apigatewayv2.Api("http-api", {
   name: pulumi.interpolate`http-tunnel-http-${appConfig.environment}`,
@@ -226,6 +389,39 @@ new aws.lambda.Permission("cleanup-permission", {
   sourceArn: cleanupRule.arn,
 });
 
+// Step 9b: Ask agents to reconnect shortly before API Gateway's 2-hour connection limit
+const handoffRule = new aws.cloudwatch.EventRule("handoff-schedule", {
+  name: pulumi.interpolate`http-tunnel-handoff-${appConfig.environment}`,
+  description: "Triggers Lambda to request a graceful reconnect from agents nearing the connection lifetime",
+  scheduleExpression: "rate(1 minute)",
+  tags: {
+    ...tags,
+    Name: "HTTP Tunnel Handoff Schedule",
+  },
+});
+
+new aws.cloudwatch.EventTarget("handoff-target", {
+  rule: handoffRule.name,
+  arn: handler.arn,
+  input: JSON.stringify({ task: "handoff" }),
+});
+
+new aws.lambda.Permission("handoff-permission", {
+  action: "lambda:InvokeFunction",
+  function: handler.name,
+  principal: "events.amazonaws.com",
+  sourceArn: handoffRule.arn,
+});
+
+// Step 9c: Function URL that streams responses (Server-Sent Events) as they arrive
+const streamingUrl = appConfig.streamingUrl
+  ? new aws.lambda.FunctionUrl("streaming-url", {
+      functionName: handler.name,
+      authorizationType: "NONE",
+      invokeMode: "RESPONSE_STREAM",
+    })
+  : undefined;
+
 // Step 9: Create custom domains (optional)
 const customDomains = createCustomDomains(
   httpApi.id,
@@ -262,12 +458,18 @@ if (appConfig.enableMonitoring) {
 // Exports
 export const connectionsTableName = connectionsTable.name;
 export const pendingRequestsTableName = pendingRequestsTable.name;
+export const tunnelStatsTableName = tunnelStatsTable.name;
+export const ipDenylistTableName = ipDenylistTable.name;
+export const tunnelTableName = tunnelTable?.name;
+export const tunnelAlertsTopicArn = tunnelAlertsTopic.arn;
 export const websocketApiEndpoint = websocketEndpoint;
 export const httpApiEndpoint = httpEndpoint;
 export const websocketApiId = preliminaryWebsocketApi.id;
+export const publicWebsocketApiEndpoint = publicWebsocketEndpoint;
 export const httpApiId = httpApi.id;
 export const lambdaFunctionName = handler.name;
 export const lambdaFunctionArn = handler.arn;
+export const streamingFunctionUrl = streamingUrl?.functionUrl;
 
 // Export custom domain info if enabled
 export const httpCustomDomain = customDomains?.httpCustomEndpoint;
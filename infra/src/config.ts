@@ -13,6 +13,8 @@ export interface AppConfig {
   websocketDomainName?: string;
   enableCustomDomain: boolean;
   enableSubdomainRouting?: boolean;
+  // Public URL styles served: path (/{tunnel_id}), subdomain ({tunnel_id}.domain) or both
+  routingMode: "path" | "subdomain" | "both";
   certificateArn?: string;
   hostedZoneId?: string;
   lambdaArchitecture: "x86_64" | "arm64";
@@ -25,12 +27,30 @@ export interface AppConfig {
   monthlyBudget?: number;
   // Security settings
   requireAuth?: boolean;
+  honeypotPaths?: string[];
+  adminSubjects?: string[];
   // Rate limiting
   rateLimitPerSecond?: number;
   rateLimitBurst?: number;
   perTunnelRateLimit?: number;
+  // Storage layout: the connections/pending/stats tables, or one table for all three
+  storageBackend: "dynamodb" | "single-table";
   // Performance
   useEventDriven?: boolean;
+  pushResponses?: boolean;
+  prewarmDynamoDb?: boolean;
+  // Features
+  websocketPassthrough?: boolean;
+  tunnelReservations?: boolean;
+  streamingUrl?: boolean;
+  jsonUrlFields?: string[];
+  // Branding of the HTML pages for offline and timed-out tunnels
+  errorPageBrand?: string;
+  errorPageTemplateS3?: string;
+  // Close tunnels that go this long without traffic (never when unset)
+  idleTimeoutSecs?: number;
+  // JWKS endpoint of the identity provider; takes precedence over jwks
+  jwksUrl?: string;
 }
 
 export const appConfig: AppConfig = {
@@ -40,6 +60,9 @@ export const appConfig: AppConfig = {
   websocketDomainName: process.env.TUNNEL_WEBSOCKET_DOMAIN_NAME || config.get("websocketDomainName"),
   enableCustomDomain: config.getBoolean("enableCustomDomain") ?? false,
   enableSubdomainRouting: config.getBoolean("enableSubdomainRouting") ?? true,
+  routingMode:
+    (config.get("routingMode") as "path" | "subdomain" | "both" | undefined) ??
+    ((config.getBoolean("enableSubdomainRouting") ?? true) ? "both" : "path"),
   certificateArn: process.env.TUNNEL_CERTIFICATE_ARN || config.get("certificateArn"),
   hostedZoneId: process.env.ROUTE53_HOSTED_ZONE_ID || config.get("hostedZoneId"),
   lambdaArchitecture: (config.get("lambdaArchitecture") as "x86_64" | "arm64") ?? "x86_64",
@@ -52,20 +75,54 @@ export const appConfig: AppConfig = {
   monthlyBudget: config.getNumber("monthlyBudget") ?? 50,
   // Security settings
   requireAuth: config.getBoolean("requireAuth") ?? false,
+  honeypotPaths: config.getObject<string[]>("honeypotPaths") ?? [],
+  adminSubjects: config.getObject<string[]>("adminSubjects") ?? [],
   // Rate limiting (defaults aligned with improvement plan)
   rateLimitPerSecond: config.getNumber("rateLimitPerSecond") ?? 50,
   rateLimitBurst: config.getNumber("rateLimitBurst") ?? 100,
   perTunnelRateLimit: config.getNumber("perTunnelRateLimit") ?? 1000,
+  storageBackend: (config.get("storageBackend") as "dynamodb" | "single-table" | undefined) ?? "dynamodb",
   // Performance
   useEventDriven: config.getBoolean("useEventDriven") ?? false,
+  pushResponses: config.getBoolean("pushResponses") ?? false,
+  prewarmDynamoDb: config.getBoolean("prewarmDynamoDb") ?? false,
+  // Features
+  websocketPassthrough: config.getBoolean("websocketPassthrough") ?? false,
+  tunnelReservations: config.getBoolean("tunnelReservations") ?? false,
+  streamingUrl: config.getBoolean("streamingUrl") ?? false,
+  jsonUrlFields: config.getObject<string[]>("jsonUrlFields") ?? [],
+  errorPageBrand: config.get("errorPageBrand"),
+  errorPageTemplateS3: config.get("errorPageTemplateS3"),
+  idleTimeoutSecs: config.getNumber("idleTimeoutSecs"),
+  jwksUrl: config.get("jwksUrl"),
 };
 
+// Features that scan or update the connections and pending requests tables
+// directly, so they don't work with the single table
+if (appConfig.storageBackend === "single-table") {
+  const twoTableOnly = [
+    ["useEventDriven", appConfig.useEventDriven],
+    ["pushResponses", appConfig.pushResponses],
+    ["websocketPassthrough", appConfig.websocketPassthrough],
+    ["tunnelReservations", appConfig.tunnelReservations],
+    ["idleTimeoutSecs", appConfig.idleTimeoutSecs],
+  ].filter(([, enabled]) => enabled).map(([name]) => name);
+  if (twoTableOnly.length > 0) {
+    throw new Error(
+      `storageBackend "single-table" can't be combined with ${twoTableOnly.join(", ")}`
+    );
+  }
+}
+
 // JWT Secret is handled separately as it can be a Pulumi secret
 export const jwtSecret = config.getSecret("jwtSecret");
 
 // JWKS can also be stored as a Pulumi secret (entire JSON content)
 export const jwksSecret = config.getSecret("jwks");
 
+// CloudWatch namespace for the handler's per-request EMF metrics
+export const metricsNamespace = `HttpTunnel/${appConfig.environment}`;
+
 export const tags = {
   Environment: appConfig.environment,
   Project: "http-tunnel",
@@ -128,11 +128,11 @@ export function createCustomDomains(
   }
 
   // Wildcard subdomain for subdomain-based routing (optional)
-  // Only create if subdomain routing is enabled and certificate supports wildcards
+  // Only create if subdomain URLs are served and the certificate supports wildcards
   let wildcardDomainName: aws.apigatewayv2.DomainName | undefined;
   let wildcardApiMapping: aws.apigatewayv2.ApiMapping | undefined;
 
-  if (appConfig.enableSubdomainRouting) {
+  if (appConfig.routingMode !== "path") {
     const wildcardDomain = `*.${httpDomain}`; // e.g., *.tunnel.example.com
 
     wildcardDomainName = new aws.apigatewayv2.DomainName("wildcard-custom-domain", {
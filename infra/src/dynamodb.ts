@@ -1,10 +1,15 @@
 import * as aws from "@pulumi/aws";
 import * as pulumi from "@pulumi/pulumi";
-import { tags } from "./config";
+import { appConfig, tags } from "./config";
 
 export interface DynamoDBTables {
   connectionsTable: aws.dynamodb.Table;
   pendingRequestsTable: aws.dynamodb.Table;
+  tunnelStatsTable: aws.dynamodb.Table;
+  ipDenylistTable: aws.dynamodb.Table;
+  wsSessionsTable?: aws.dynamodb.Table;
+  reservationsTable?: aws.dynamodb.Table;
+  tunnelTable?: aws.dynamodb.Table;
 }
 
 export function createDynamoDBTables(): DynamoDBTables {
@@ -54,8 +59,119 @@ export function createDynamoDBTables(): DynamoDBTables {
     },
   });
 
+  // Per-tunnel traffic statistics, one item per tunnel per minute
+  const tunnelStatsTable = new aws.dynamodb.Table("tunnel-stats-table", {
+    name: pulumi.interpolate`http-tunnel-tunnel-stats-${tags.Environment}`,
+    billingMode: "PAY_PER_REQUEST",
+    hashKey: "tunnelId",
+    rangeKey: "bucket",
+    attributes: [
+      { name: "tunnelId", type: "S" },
+      { name: "bucket", type: "N" },
+    ],
+    ttl: {
+      attributeName: "ttl",
+      enabled: true,
+    },
+    tags: {
+      ...tags,
+      Name: "HTTP Tunnel Stats",
+    },
+  });
+
+  // IPs banned for requesting honeypot trap paths, expired via TTL
+  const ipDenylistTable = new aws.dynamodb.Table("ip-denylist-table", {
+    name: pulumi.interpolate`http-tunnel-ip-denylist-${tags.Environment}`,
+    billingMode: "PAY_PER_REQUEST",
+    hashKey: "ip",
+    attributes: [
+      { name: "ip", type: "S" },
+    ],
+    ttl: {
+      attributeName: "ttl",
+      enabled: true,
+    },
+    tags: {
+      ...tags,
+      Name: "HTTP Tunnel IP Denylist",
+    },
+  });
+
+  // Public WebSocket passthrough sessions (client connection -> agent connection)
+  const wsSessionsTable = appConfig.websocketPassthrough
+    ? new aws.dynamodb.Table("ws-sessions-table", {
+        name: pulumi.interpolate`http-tunnel-ws-sessions-${tags.Environment}`,
+        billingMode: "PAY_PER_REQUEST",
+        hashKey: "sessionId",
+        attributes: [
+          { name: "sessionId", type: "S" },
+        ],
+        ttl: {
+          attributeName: "ttl",
+          enabled: true,
+        },
+        tags: {
+          ...tags,
+          Name: "HTTP Tunnel WebSocket Sessions",
+        },
+      })
+    : undefined;
+
+  // Tunnel IDs reserved by an owner (JWT sub) for reuse across reconnects
+  const reservationsTable = appConfig.tunnelReservations
+    ? new aws.dynamodb.Table("reservations-table", {
+        name: pulumi.interpolate`http-tunnel-reservations-${tags.Environment}`,
+        billingMode: "PAY_PER_REQUEST",
+        hashKey: "tunnelId",
+        attributes: [
+          { name: "tunnelId", type: "S" },
+        ],
+        tags: {
+          ...tags,
+          Name: "HTTP Tunnel Reservations",
+        },
+      })
+    : undefined;
+
+  // Connections, pending requests and stats in one table (storageBackend "single-table")
+  const tunnelTable = appConfig.storageBackend === "single-table"
+    ? new aws.dynamodb.Table("tunnel-table", {
+        name: pulumi.interpolate`http-tunnel-${tags.Environment}`,
+        billingMode: "PAY_PER_REQUEST",
+        hashKey: "pk",
+        rangeKey: "sk",
+        attributes: [
+          { name: "pk", type: "S" },
+          { name: "sk", type: "S" },
+          { name: "gsi1pk", type: "S" },
+          { name: "gsi1sk", type: "S" },
+        ],
+        globalSecondaryIndexes: [
+          {
+            name: "gsi1",  // A tunnel's connections
+            hashKey: "gsi1pk",
+            rangeKey: "gsi1sk",
+            projectionType: "ALL",
+          },
+        ],
+        ttl: {
+          attributeName: "ttl",
+          enabled: true,
+        },
+        tags: {
+          ...tags,
+          Name: "HTTP Tunnel",
+        },
+      })
+    : undefined;
+
   return {
     connectionsTable,
     pendingRequestsTable,
+    tunnelStatsTable,
+    ipDenylistTable,
+    wsSessionsTable,
+    reservationsTable,
+    tunnelTable,
   };
 }
@@ -1,3 +1,4 @@
+import * as pulumi from "@pulumi/pulumi";
 import * as aws from "@pulumi/aws";
 import { appConfig, tags } from "./config";
 
@@ -13,3 +14,70 @@ export function createEventBus(): aws.cloudwatch.EventBus {
     },
   });
 }
+
+/**
+ * Route per-tunnel alerts published by the handler to an SNS topic
+ *
+ * Agents opt in with --alert-p95 / --alert-error-rate; the handler publishes a
+ * `TunnelAlert` event when a threshold is breached. Subscribe to the topic (the
+ * optional alertEmail config does so by email) to be notified.
+ */
+export function createTunnelAlerts(eventBus: aws.cloudwatch.EventBus): aws.sns.Topic {
+  const topic = new aws.sns.Topic("tunnel-alerts", {
+    name: `http-tunnel-alerts-${appConfig.environment}`,
+    tags: {
+      ...tags,
+      Name: "HTTP Tunnel Alerts",
+    },
+  });
+
+  const rule = new aws.cloudwatch.EventRule("tunnel-alerts-rule", {
+    name: pulumi.interpolate`http-tunnel-alerts-${appConfig.environment}`,
+    description: "Forwards tunnel slow-request and error-rate alerts to SNS",
+    eventBusName: eventBus.name,
+    eventPattern: JSON.stringify({
+      source: ["http-tunnel.alerts"],
+      "detail-type": ["TunnelAlert"],
+    }),
+    tags: {
+      ...tags,
+      Name: "HTTP Tunnel Alerts Rule",
+    },
+  });
+
+  new aws.sns.TopicPolicy("tunnel-alerts-policy", {
+    arn: topic.arn,
+    policy: pulumi.all([topic.arn, rule.arn]).apply(([topicArn, ruleArn]) =>
+      JSON.stringify({
+        Version: "2012-10-17",
+        Statement: [
+          {
+            Sid: "AllowEventBridgePublish",
+            Effect: "Allow",
+            Principal: { Service: "events.amazonaws.com" },
+            Action: "sns:Publish",
+            Resource: topicArn,
+            Condition: { ArnEquals: { "aws:SourceArn": ruleArn } },
+          },
+        ],
+      })
+    ),
+  });
+
+  new aws.cloudwatch.EventTarget("tunnel-alerts-target", {
+    rule: rule.name,
+    eventBusName: eventBus.name,
+    arn: topic.arn,
+    inputPath: "$.detail",
+  });
+
+  if (appConfig.alertEmail) {
+    new aws.sns.TopicSubscription("tunnel-alerts-email", {
+      topic: topic.arn,
+      protocol: "email",
+      endpoint: appConfig.alertEmail,
+    });
+  }
+
+  return topic;
+}
@@ -1,6 +1,6 @@
 import * as aws from "@pulumi/aws";
 import * as pulumi from "@pulumi/pulumi";
-import { tags } from "./config";
+import { appConfig, tags } from "./config";
 
 const lambdaAssumeRolePolicy = aws.iam.assumeRolePolicyForPrincipal({
   Service: "lambda.amazonaws.com",
@@ -14,6 +14,8 @@ const lambdaAssumeRolePolicy = aws.iam.assumeRolePolicyForPrincipal({
 export function createLambdaRole(
   connectionsTableArn: pulumi.Output<string>,
   pendingRequestsTableArn: pulumi.Output<string>,
+  tunnelStatsTableArn: pulumi.Output<string>,
+  ipDenylistTableArn: pulumi.Output<string>,
   eventBusArn?: pulumi.Output<string>
 ): aws.iam.Role {
   // Unified handler role with all permissions
@@ -37,7 +39,9 @@ export function createLambdaRole(
     policy: pulumi.all([
       connectionsTableArn,
       pendingRequestsTableArn,
-    ]).apply(([connTableArn, pendingTableArn]) =>
+      tunnelStatsTableArn,
+      ipDenylistTableArn,
+    ]).apply(([connTableArn, pendingTableArn, statsTableArn, denylistTableArn]) =>
       JSON.stringify({
         Version: "2012-10-17",
         Statement: [
@@ -47,7 +51,11 @@ export function createLambdaRole(
             Action: [
               "dynamodb:PutItem",
               "dynamodb:GetItem",
+              "dynamodb:UpdateItem",
               "dynamodb:DeleteItem",
+              "dynamodb:Scan", // cleanup and handoff sweeps
+              "dynamodb:BatchWriteItem", // cleanup
+              "dynamodb:DescribeTable", // cleanup scan segments
             ],
             Resource: connTableArn,
           },
@@ -65,9 +73,25 @@ export function createLambdaRole(
               "dynamodb:GetItem",
               "dynamodb:UpdateItem",
               "dynamodb:DeleteItem",
+              "dynamodb:BatchGetItem", // chunked response bodies
+              "dynamodb:Scan", // cleanup
+              "dynamodb:BatchWriteItem", // cleanup
+              "dynamodb:DescribeTable", // cleanup scan segments
             ],
             Resource: pendingTableArn,
           },
+          {
+            Sid: "DynamoDBTunnelStatsTable",
+            Effect: "Allow",
+            Action: ["dynamodb:GetItem", "dynamodb:UpdateItem", "dynamodb:Query"],
+            Resource: statsTableArn,
+          },
+          {
+            Sid: "DynamoDBIpDenylistTable",
+            Effect: "Allow",
+            Action: ["dynamodb:GetItem", "dynamodb:PutItem"],
+            Resource: denylistTableArn,
+          },
           {
             Sid: "DynamoDBStreamRead",
             Effect: "Allow",
@@ -84,6 +108,55 @@ export function createLambdaRole(
     ),
   });
 
+  // SQS permissions for per-container reply queues (push response delivery)
+  if (appConfig.pushResponses) {
+    new aws.iam.RolePolicy("handler-reply-queues-policy", {
+      role: handlerRole,
+      policy: JSON.stringify({
+        Version: "2012-10-17",
+        Statement: [
+          {
+            Sid: "SqsReplyQueues",
+            Effect: "Allow",
+            Action: [
+              "sqs:CreateQueue",
+              "sqs:DeleteQueue",
+              "sqs:GetQueueAttributes",
+              "sqs:SendMessage",
+              "sqs:ReceiveMessage",
+              "sqs:DeleteMessage",
+            ],
+            Resource: `arn:aws:sqs:*:*:http-tunnel-replies-${appConfig.environment}-*`,
+          },
+          {
+            Sid: "SqsListReplyQueues",
+            Effect: "Allow",
+            Action: ["sqs:ListQueues"],
+            Resource: "*",
+          },
+        ],
+      }),
+    });
+  }
+
+  // Reading a custom error page template (s3://bucket/key)
+  if (appConfig.errorPageTemplateS3?.startsWith("s3://")) {
+    new aws.iam.RolePolicy("handler-error-page-policy", {
+      role: handlerRole,
+      policy: JSON.stringify({
+        Version: "2012-10-17",
+        Statement: [
+          {
+            Sid: "S3ErrorPageTemplate",
+            Effect: "Allow",
+            Action: ["s3:GetObject"],
+            Resource: `arn:aws:s3:::${appConfig.errorPageTemplateS3.slice("s3://".length)}`,
+          },
+        ],
+      }),
+    });
+  }
+
   // EventBridge permissions (if event bus provided)
   if (eventBusArn) {
     new aws.iam.RolePolicy("handler-eventbridge-policy", {
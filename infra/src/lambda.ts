@@ -2,7 +2,7 @@ import * as aws from "@pulumi/aws";
 import * as pulumi from "@pulumi/pulumi";
 import * as path from "path";
 import * as fs from "fs";
-import { appConfig, jwtSecret, jwksSecret, tags } from "./config";
+import { appConfig, jwtSecret, jwksSecret, metricsNamespace, tags } from "./config";
 
 // Use infra/lambda directory for Lambda code
 const lambdaCodePath = process.env.LAMBDA_CODE_PATH ||
@@ -24,6 +24,15 @@ if (fs.existsSync(jwksPath)) {
   console.log("✓ JWKS file found, will be included in Lambda environment");
 }
 
+/**
+ * Public WebSocket API used for WebSocket passthrough
+ */
+export interface PublicWebsocketConfig {
+  apiId: pulumi.Output<string>;
+  endpoint: pulumi.Output<string>;
+  sessionsTableName: pulumi.Output<string>;
+}
+
 /**
  * Create the unified Lambda handler that handles all routes
  */
@@ -31,8 +40,13 @@ export function createLambdaHandler(
   role: aws.iam.Role,
   connectionsTableName: pulumi.Output<string>,
   pendingRequestsTableName: pulumi.Output<string>,
+  tunnelStatsTableName: pulumi.Output<string>,
+  ipDenylistTableName: pulumi.Output<string>,
   websocketApiEndpoint: pulumi.Output<string>,
-  eventBusName?: pulumi.Output<string>
+  eventBusName?: pulumi.Output<string>,
+  publicWebsocket?: PublicWebsocketConfig,
+  reservationsTableName?: pulumi.Output<string>,
+  tunnelTableName?: pulumi.Output<string>
 ): aws.lambda.Function {
   const architecture = appConfig.lambdaArchitecture === "arm64" ? "arm64" : "x86_64";
 
@@ -49,21 +63,31 @@ export function createLambdaHandler(
       variables: pulumi.all([
         connectionsTableName,
         pendingRequestsTableName,
+        tunnelStatsTableName,
+        ipDenylistTableName,
         websocketApiEndpoint,
         eventBusName,
         jwtSecret,
-        jwksSecret
-      ]).apply(([connTable, reqTable, wsEndpoint, busName, secret, jwks]) => {
+        jwksSecret,
+        publicWebsocket?.apiId,
+        publicWebsocket?.endpoint,
+        publicWebsocket?.sessionsTableName,
+        reservationsTableName,
+        tunnelTableName,
+      ]).apply(([connTable, reqTable, statsTable, denylistTable, wsEndpoint, busName, secret, jwks, publicApiId, publicEndpoint, sessionsTable, reservationsTable, tunnelTable]) => {
         const vars: Record<string, string> = {
           RUST_LOG: "info",
           CONNECTIONS_TABLE_NAME: connTable,
           PENDING_REQUESTS_TABLE_NAME: reqTable,
+          TUNNEL_STATS_TABLE_NAME: statsTable,
+          IP_DENYLIST_TABLE_NAME: denylistTable,
           DOMAIN_NAME: appConfig.domainName,
           WEBSOCKET_API_ENDPOINT: wsEndpoint,
           EVENT_BUS_NAME: busName || `http-tunnel-events-${appConfig.environment}`,
           USE_EVENT_DRIVEN: appConfig.useEventDriven ? "true" : "false",
-          // Subdomain routing
-          ENABLE_SUBDOMAIN_ROUTING: appConfig.enableSubdomainRouting ? "true" : "false",
+          PREWARM_DYNAMODB: appConfig.prewarmDynamoDb ? "true" : "false",
+          // Public URL styles (path, subdomain or both)
+          ROUTING_MODE: appConfig.routingMode,
           // Authentication
           REQUIRE_AUTH: appConfig.requireAuth ? "true" : "false",
           JWT_SECRET: secret || process.env.JWT_SECRET || "default-secret-change-in-production",
@@ -71,12 +95,72 @@ export function createLambdaHandler(
           PER_TUNNEL_RATE_LIMIT: String(appConfig.perTunnelRateLimit || 1000),
         };
 
+        // Push responses to per-container SQS reply queues instead of polling
+        if (appConfig.pushResponses) {
+          vars.REPLY_QUEUE_PREFIX = `http-tunnel-replies-${appConfig.environment}-`;
+        }
+
+        // WebSocket passthrough for public clients
+        if (publicApiId && publicEndpoint && sessionsTable) {
+          vars.PUBLIC_WEBSOCKET_API_ID = publicApiId;
+          vars.PUBLIC_WEBSOCKET_API_ENDPOINT = publicEndpoint;
+          vars.WS_SESSIONS_TABLE_NAME = sessionsTable;
+        }
+
+        // Reserved tunnel IDs (--tunnel-id)
+        if (reservationsTable) {
+          vars.RESERVATIONS_TABLE_NAME = reservationsTable;
+        }
+
+        // Connections, pending requests and stats in one table
+        if (tunnelTable) {
+          vars.STORAGE_BACKEND = "single-table";
+          vars.TUNNEL_TABLE_NAME = tunnelTable;
+        }
+
+        // Honeypot trap paths (disabled when none are configured)
+        if (appConfig.honeypotPaths && appConfig.honeypotPaths.length > 0) {
+          vars.HONEYPOT_PATHS = appConfig.honeypotPaths.join(",");
+        }
+
+        // JSON fields rewritten as URLs on path-routed tunnels (built-in list otherwise)
+        if (appConfig.jsonUrlFields && appConfig.jsonUrlFields.length > 0) {
+          vars.JSON_URL_FIELDS = appConfig.jsonUrlFields.join(",");
+        }
+
+        // Branded error pages for offline and timed-out tunnels
+        if (appConfig.errorPageBrand) {
+          vars.ERROR_PAGE_BRAND = appConfig.errorPageBrand;
+        }
+        if (appConfig.errorPageTemplateS3) {
+          vars.ERROR_PAGE_TEMPLATE_S3 = appConfig.errorPageTemplateS3;
+        }
+
+        // Idle tunnels are warned, then closed by the handoff sweep
+        if (appConfig.idleTimeoutSecs) {
+          vars.IDLE_TIMEOUT_SECS = String(appConfig.idleTimeoutSecs);
+        }
+
+        // Per-request EMF metrics feed the monitoring dashboard and alarms
+        if (appConfig.enableMonitoring) {
+          vars.METRICS_NAMESPACE = metricsNamespace;
+        }
+
+        // Token subjects allowed to manage every tunnel via /_admin/tunnels
+        if (appConfig.adminSubjects && appConfig.adminSubjects.length > 0) {
+          vars.ADMIN_SUBJECTS = appConfig.adminSubjects.join(",");
+        }
+
         // Add JWKS - priority: Pulumi secret > file content > not set
         if (jwks) {
           vars.JWKS = jwks;
         } else if (jwksContent) {
           vars.JWKS = jwksContent;
         }
+        // Keys fetched (and refetched on rotation) from the identity provider
+        if (appConfig.jwksUrl) {
+          vars.JWKS_URL = appConfig.jwksUrl;
+        }
 
         return vars;
       }),
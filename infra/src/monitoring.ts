@@ -1,6 +1,6 @@
 import * as aws from "@pulumi/aws";
 import * as pulumi from "@pulumi/pulumi";
-import { appConfig, tags } from "./config";
+import { appConfig, metricsNamespace, tags } from "./config";
 
 /**
  * Create CloudWatch Dashboard for HTTP Tunnel monitoring
@@ -96,6 +96,43 @@ export function createMonitoringDashboard(
                 dimensions: { ApiId: wsApi },
               },
             },
+            // Tunnel requests (EMF metrics from the handler)
+            {
+              type: "metric",
+              width: 12,
+              height: 6,
+              properties: {
+                metrics: [
+                  [metricsNamespace, "Requests", { stat: "Sum", label: "Requests" }],
+                  [".", "Timeouts", { stat: "Sum", label: "Timeouts", color: "#d62728" }],
+                  [".", "RewriteApplied", { stat: "Sum", label: "Rewritten", color: "#2ca02c" }],
+                ],
+                view: "timeSeries",
+                stacked: false,
+                region: appConfig.awsRegion,
+                title: "Tunnel Requests & Timeouts",
+                period: 300,
+              },
+            },
+            // Tunnel latency (EMF metrics from the handler)
+            {
+              type: "metric",
+              width: 12,
+              height: 6,
+              properties: {
+                metrics: [
+                  [metricsNamespace, "Latency", { stat: "p50", label: "p50 Latency" }],
+                  ["...", { stat: "p95", label: "p95 Latency", color: "#ff7f0e" }],
+                  ["...", { stat: "p99", label: "p99 Latency", color: "#d62728" }],
+                ],
+                view: "timeSeries",
+                stacked: false,
+                region: appConfig.awsRegion,
+                title: "Tunnel Latency (ms)",
+                period: 300,
+                yAxis: { left: { min: 0 } },
+              },
+            },
             // DynamoDB connections table
             {
               type: "metric",
@@ -246,6 +283,26 @@ export function createAlarms(
     });
   });
 
+  // Agents not answering in time
+  new aws.cloudwatch.MetricAlarm("tunnel-timeouts", {
+    name: `http-tunnel-timeouts-${appConfig.environment}`,
+    comparisonOperator: "GreaterThanThreshold",
+    evaluationPeriods: 2,
+    metricName: "Timeouts",
+    namespace: metricsNamespace,
+    period: 300,
+    statistic: "Sum",
+    threshold: 20,
+    datapointsToAlarm: 2,
+    treatMissingData: "notBreaching",
+    alarmDescription: "Alert when tunnels time out >20 requests in 10 minutes",
+    alarmActions,
+    tags: {
+      ...tags,
+      Name: "HTTP Tunnel Request Timeouts",
+    },
+  });
+
   // WebSocket disconnect rate
   pulumi.all([websocketApiId]).apply(([apiId]) => {
     new aws.cloudwatch.MetricAlarm("websocket-disconnects", {